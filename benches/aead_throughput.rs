@@ -0,0 +1,122 @@
+//! Compares seal and open throughput of every AEAD this crate provides. Each AEAD is run over a
+//! base-mode context at a few message sizes, and Criterion reports the result in bytes per
+//! second. The KEM and KDF are fixed, since they only affect setup. Run with
+//!
+//! ```text
+//! cargo bench --features x25519 --bench aead_throughput
+//! ```
+//!
+//! `--features p256` works too. Without either KEM, there's nothing to bench with.
+
+use hpke::{
+    aead::{Aead as AeadTrait, AeadCtxR, AeadCtxS},
+    kdf::Kdf as KdfTrait,
+    kem::Kem as KemTrait,
+    setup_receiver, setup_sender, OpModeR, OpModeS,
+};
+
+use criterion::{black_box, criterion_main, Criterion, Throughput};
+use rand::{rngs::StdRng, RngCore, SeedableRng};
+use std::time::{Duration, Instant};
+
+// Plaintext lengths to bench at: a small record, a typical packet, and a large chunk
+const MSG_LENS: [usize; 3] = [64, 1024, 64 * 1024];
+// Number of ciphertexts sealed ahead of time for each timed run of the open benchmark. This
+// bounds the memory used at the largest message size.
+const OPEN_CHUNK_LEN: usize = 256;
+
+// Makes a sender and receiver context that can talk to each other
+fn make_ctx_pair<Aead, Kdf, Kem>() -> (AeadCtxS<Aead, Kdf, Kem>, AeadCtxR<Aead, Kdf, Kem>)
+where
+    Aead: AeadTrait,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    let mut csprng = StdRng::from_entropy();
+    let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+    let (encapped_key, sender_ctx) =
+        setup_sender::<Aead, Kdf, Kem, _>(&OpModeS::Base, &pk_recip, b"bench", &mut csprng)
+            .unwrap();
+    let receiver_ctx =
+        setup_receiver::<Aead, Kdf, Kem>(&OpModeR::Base, &sk_recip, &encapped_key, b"bench")
+            .unwrap();
+
+    (sender_ctx, receiver_ctx)
+}
+
+// Benches seal() and open() with the given AEAD at every length in MSG_LENS
+fn bench_aead_throughput<Aead, Kdf, Kem>(aead_name: &str, c: &mut Criterion)
+where
+    Aead: AeadTrait,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    let mut csprng = StdRng::from_entropy();
+    let mut group = c.benchmark_group(format!("throughput[aead={}]", aead_name));
+
+    for &msg_len in MSG_LENS.iter() {
+        group.throughput(Throughput::Bytes(msg_len as u64));
+
+        // Bench seal(). The plaintext is encrypted in place over and over, which is fine since
+        // its contents don't affect the running time
+        let (mut sender_ctx, _) = make_ctx_pair::<Aead, Kdf, Kem>();
+        let mut plaintext = vec![0u8; msg_len];
+        csprng.fill_bytes(&mut plaintext);
+        group.bench_function(format!("seal[msglen={}]", msg_len), |b| {
+            b.iter(|| sender_ctx.seal(&mut plaintext, b"").unwrap())
+        });
+
+        // Bench open(). Ciphertexts have to be opened in the order they were sealed, so seal them
+        // OPEN_CHUNK_LEN at a time, off the clock, and time only opening them
+        let (mut sender_ctx, mut receiver_ctx) = make_ctx_pair::<Aead, Kdf, Kem>();
+        group.bench_function(format!("open[msglen={}]", msg_len), |b| {
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::ZERO;
+                let mut remaining = iters as usize;
+                while remaining > 0 {
+                    let chunk_len = remaining.min(OPEN_CHUNK_LEN);
+                    let mut ciphertext_tags: Vec<_> = (0..chunk_len)
+                        .map(|_| {
+                            let mut ciphertext = plaintext.clone();
+                            let tag = sender_ctx.seal(&mut ciphertext, b"").unwrap();
+                            (ciphertext, tag)
+                        })
+                        .collect();
+
+                    let start = Instant::now();
+                    for (ciphertext, tag) in ciphertext_tags.iter_mut() {
+                        receiver_ctx.open(ciphertext, b"", tag).unwrap();
+                        black_box(&ciphertext);
+                    }
+                    elapsed += start.elapsed();
+                    remaining -= chunk_len;
+                }
+                elapsed
+            })
+        });
+    }
+}
+
+// Runs bench_aead_throughput once for each AEAD this crate provides, with the given KDF and KEM
+macro_rules! bench_all_aeads {
+    ($kdf_ty:ty, $kem_ty:ty, $c:expr) => {
+        bench_aead_throughput::<hpke::aead::AesGcm128, $kdf_ty, $kem_ty>("AES-GCM-128", $c);
+        bench_aead_throughput::<hpke::aead::AesGcm256, $kdf_ty, $kem_ty>("AES-GCM-256", $c);
+        bench_aead_throughput::<hpke::aead::ChaCha20Poly1305, $kdf_ty, $kem_ty>(
+            "ChaCha20Poly1305",
+            $c,
+        );
+    };
+}
+
+pub fn benches() {
+    let mut c = Criterion::default().configure_from_args();
+
+    // Any KEM will do. Prefer X25519 if it's there
+    #[cfg(feature = "x25519")]
+    bench_all_aeads!(hpke::kdf::HkdfSha256, hpke::kem::X25519HkdfSha256, &mut c);
+    #[cfg(all(feature = "p256", not(feature = "x25519")))]
+    bench_all_aeads!(hpke::kdf::HkdfSha256, hpke::kem::DhP256HkdfSha256, &mut c);
+}
+
+criterion_main!(benches);
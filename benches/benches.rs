@@ -1,8 +1,11 @@
+#![allow(clippy::needless_borrow, clippy::unit_arg, clippy::useless_vec)]
 use hpke::{
-    aead::{Aead as AeadTrait, AeadCtxR, AeadTag},
+    aead::{Aead as AeadTrait, AeadCtxR, AeadTag, ExportOnlyAead},
     kdf::Kdf as KdfTrait,
     kem::Kem as KemTrait,
-    setup_receiver, setup_sender, OpModeR, OpModeS, PskBundle,
+    kex::KeyExchange,
+    setup_receiver, setup_sender, AuthSendCache, Deserializable, OpModeR, OpModeS, PskBundle,
+    Serializable,
 };
 
 use criterion::{black_box, criterion_main, Criterion};
@@ -13,6 +16,12 @@ use std::time::Instant;
 const AAD_LEN: usize = 64;
 // Length of plaintext and ciphertext for all seal/open benchmarks
 const MSG_LEN: usize = 64;
+// Length of plaintext for the seal_fixed benchmark, e.g., a token
+const FIXED_MSG_LEN: usize = 32;
+// Number of secrets exported in the multi-export benchmark
+const NUM_EXPORTS: usize = 100;
+// Length of each exported secret in the multi-export benchmark
+const EXPORT_LEN: usize = 32;
 // Length of PSK. Since we're only testing the 128-bit security level, make it 128 bits
 const PSK_LEN: usize = 16;
 
@@ -52,7 +61,7 @@ where
         OpModeS::Base,
         OpModeS::Auth((sk_sender.clone(), pk_sender.clone())),
         OpModeS::Psk(psk_bundle),
-        OpModeS::AuthPsk((sk_sender, pk_sender.clone()), psk_bundle),
+        OpModeS::AuthPsk((sk_sender.clone(), pk_sender.clone()), psk_bundle),
     ];
     let opmodes_r = vec![
         OpModeR::Base,
@@ -76,6 +85,13 @@ where
         });
     }
 
+    // Bench setup_sender() in auth mode when the static DH is cached. Compare this to
+    // setup_sender[mode=auth]
+    let auth_cache = AuthSendCache::<Kem>::new(&sk_sender, &pk_recip).unwrap();
+    group.bench_function("setup_sender_cached[mode=auth]", |b| {
+        b.iter(|| auth_cache.setup_sender::<Aead, Kdf, _>(None, b"bench setup sender", &mut csprng))
+    });
+
     // Collect the encapsulated keys from each setup_sender under each opmode. We will pass these
     // to setup_receiver in a moment
     let encapped_keys = opmodes_s.iter().map(|opmode_s| {
@@ -105,6 +121,27 @@ where
         });
     }
 
+    // Bench setup_receiver() in base mode with the export-only AEAD, which skips deriving an AEAD
+    // key and nonce. Compare this to setup_receiver[mode=base]
+    let (encapped_key, _) = setup_sender::<ExportOnlyAead, Kdf, Kem, _>(
+        &OpModeS::Base,
+        &pk_recip,
+        b"bench setup receiver",
+        &mut csprng,
+    )
+    .unwrap();
+    group.bench_function("setup_receiver_export_only[mode=base]", |b| {
+        b.iter(|| {
+            setup_receiver::<ExportOnlyAead, Kdf, Kem>(
+                &OpModeR::Base,
+                &sk_recip,
+                &encapped_key,
+                b"bench setup receiver",
+            )
+            .unwrap()
+        })
+    });
+
     // Make the encryption context so we can benchmark seal()
     let (_, mut encryption_ctx) =
         setup_sender::<Aead, Kdf, Kem, _>(&OpModeS::Base, &pk_recip, b"bench seal", &mut csprng)
@@ -122,6 +159,44 @@ where
         b.iter(|| encryption_ctx.seal(&mut plaintext, &aad).unwrap())
     });
 
+    // Bench seal_fixed() on a FIXED_MSG_LEN-byte plaintext, and compare it to copying the same
+    // plaintext into a Vec and calling seal() on that. The difference is the cost of allocating.
+    let bench_name = format!("seal_fixed[msglen={},aadlen={}]", FIXED_MSG_LEN, AAD_LEN);
+    group.bench_function(bench_name, |b| {
+        let mut plaintext = [0u8; FIXED_MSG_LEN];
+        let mut aad = [0u8; AAD_LEN];
+        csprng.fill_bytes(&mut plaintext);
+        csprng.fill_bytes(&mut aad);
+
+        b.iter(|| encryption_ctx.seal_fixed(&plaintext, &aad).unwrap())
+    });
+    let bench_name = format!("seal_vec[msglen={},aadlen={}]", FIXED_MSG_LEN, AAD_LEN);
+    group.bench_function(bench_name, |b| {
+        let mut plaintext = [0u8; FIXED_MSG_LEN];
+        let mut aad = [0u8; AAD_LEN];
+        csprng.fill_bytes(&mut plaintext);
+        csprng.fill_bytes(&mut aad);
+
+        b.iter(|| {
+            let mut ciphertext = plaintext.to_vec();
+            let tag = encryption_ctx.seal(&mut ciphertext, &aad).unwrap();
+            (ciphertext, tag)
+        })
+    });
+
+    // Bench deriving NUM_EXPORTS many EXPORT_LEN-byte sub-keys from one context, each with its own
+    // exporter context
+    let bench_name = format!("export[n={},len={}]", NUM_EXPORTS, EXPORT_LEN);
+    group.bench_function(bench_name, |b| {
+        let mut out = [[0u8; EXPORT_LEN]; NUM_EXPORTS];
+        b.iter(|| {
+            for (i, buf) in out.iter_mut().enumerate() {
+                encryption_ctx.export(&i.to_be_bytes(), buf).unwrap();
+            }
+            black_box(&out);
+        })
+    });
+
     // Bench open() on MSG_LEN-bytes ciphertexts with AAD_LEN-byte AADs. This is more complicated
     // than the other benchmarks because we need to first construct and store a ton of ciphertexts
     // that we can open() in sequence.
@@ -191,6 +266,29 @@ where
     (decryption_ctx, ciphertext_aad_tags)
 }
 
+// Benches pubkey deserialization with and without validation. The trusted constructors are
+// inherent methods on the concrete pubkey types, so this takes both functions as arguments. Not
+// every pubkey type has a trusted constructor.
+#[cfg(any(feature = "p256", feature = "x25519"))]
+fn bench_pubkey_from_bytes<Pk>(
+    group_name: &str,
+    pk_bytes: &[u8],
+    from_bytes: fn(&[u8]) -> Pk,
+    from_trusted_bytes: Option<fn(&[u8]) -> Pk>,
+    c: &mut Criterion,
+) {
+    let mut group = c.benchmark_group(group_name);
+
+    group.bench_function("pubkey_from_bytes", |b| {
+        b.iter(|| from_bytes(black_box(pk_bytes)))
+    });
+    if let Some(from_trusted_bytes) = from_trusted_bytes {
+        group.bench_function("pubkey_from_trusted_bytes", |b| {
+            b.iter(|| from_trusted_bytes(black_box(pk_bytes)))
+        });
+    }
+}
+
 pub fn benches() {
     let mut c = Criterion::default().configure_from_args();
 
@@ -208,6 +306,34 @@ pub fn benches() {
         hpke::kdf::HkdfSha256,
         hpke::kem::X25519HkdfSha256,
     >("Non-NIST[seclevel=128]", &mut c);
+
+    #[cfg(feature = "p256")]
+    {
+        type Kem = hpke::kem::DhP256HkdfSha256;
+        type Pk = <<Kem as KemTrait>::Kex as KeyExchange>::PublicKey;
+        let (_, pk) = Kem::gen_keypair(&mut StdRng::from_entropy());
+        bench_pubkey_from_bytes::<Pk>(
+            "NIST[seclevel=128]",
+            &pk.to_bytes(),
+            |bytes| Pk::from_bytes(bytes).unwrap(),
+            None,
+            &mut c,
+        );
+    }
+
+    #[cfg(feature = "x25519")]
+    {
+        type Kem = hpke::kem::X25519HkdfSha256;
+        type Pk = <<Kem as KemTrait>::Kex as KeyExchange>::PublicKey;
+        let (_, pk) = Kem::gen_keypair(&mut StdRng::from_entropy());
+        bench_pubkey_from_bytes::<Pk>(
+            "Non-NIST[seclevel=128]",
+            &pk.to_bytes(),
+            |bytes| Pk::from_bytes(bytes).unwrap(),
+            Some(Pk::from_trusted_bytes),
+            &mut c,
+        );
+    }
 }
 
 criterion_main!(benches);
@@ -0,0 +1,166 @@
+//! A timing harness for auditing how constant-time decapsulation is. Run it with
+//!
+//! ```text
+//! cargo bench --all-features --bench timing -- ITERATIONS
+//! ```
+//!
+//! where `ITERATIONS` is the number of samples taken per class of encapsulated key (the default is
+//! 10000). This only reports numbers. It does not pass or fail anything, since the outcome
+//! depends heavily on the machine it's run on.
+//!
+//! Methodology
+//! ===========
+//! For each KEM, we make a recipient keypair and build a pool of encapsulated keys in each of the
+//! following classes:
+//!
+//! * `valid`: encapsulated to the recipient by `setup_sender`
+//! * `foreign`: well-formed, but encapsulated to some other recipient, i.e., what an attacker
+//!   would send when it doesn't know the recipient's public key
+//! * `low-order`: the all-zero encoding, if the KEM accepts it as a public key. For X25519, this
+//!   is a low-order point, and decap fails with `HpkeError::DecapError`. P-256 rejects it at
+//!   deserialization time, so this class is skipped there.
+//!
+//! Decap is driven through `setup_receiver` in `Base` mode, since that's the public entry point
+//! that an attacker can reach. Every sample times a single call with `Instant`. The samples of
+//! all the classes are shuffled together before any are taken, so that drift in clock speed or
+//! system load over the course of the run hits every class equally.
+//!
+//! For each class, we report the median and the interquartile range (IQR), i.e., the distance
+//! between the 25th and 75th percentiles. Both are robust to the long tail of outliers that
+//! preemption and cache misses produce, unlike the mean and standard deviation. The last column
+//! is the difference between the class's median and the `valid` median, in units of the `valid`
+//! IQR. A value well within ±1 means the difference is lost in the noise.
+//!
+//! Note that a failed decap returns early, skipping the key schedule, so `low-order` is expected
+//! to be faster. That difference reveals nothing that the returned error doesn't already reveal.
+//! What matters is that `valid` and `foreign` are indistinguishable, and that the DH itself takes
+//! the same time for every class.
+
+use hpke::{
+    aead::ChaCha20Poly1305, kdf::HkdfSha256, kem::Kem as KemTrait, kex::KeyExchange,
+    setup_receiver, setup_sender, Deserializable, OpModeR, OpModeS, Serializable,
+};
+
+use criterion::black_box;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use std::{env, time::Instant};
+
+// Number of samples per class, if none is given on the command line
+const DEFAULT_ITERATIONS: usize = 10_000;
+// Number of distinct encapsulated keys in each class. Samples cycle through the pool.
+const POOL_LEN: usize = 64;
+
+/// Summary statistics of one class of samples, in nanoseconds
+struct ClassReport {
+    name: &'static str,
+    median: f64,
+    iqr: f64,
+}
+
+/// Returns the `p`-th quantile of the sorted slice `xs`, interpolating linearly between the two
+/// closest ranks
+fn quantile(xs: &[u64], p: f64) -> f64 {
+    let pos = p * (xs.len() - 1) as f64;
+    let (lo, hi) = (pos.floor() as usize, pos.ceil() as usize);
+    let frac = pos - lo as f64;
+    xs[lo] as f64 * (1.0 - frac) + xs[hi] as f64 * frac
+}
+
+/// Times `setup_receiver` over `iterations` samples of each class of encapsulated key (see the
+/// module documentation) and prints the median and IQR of each class
+fn decap_timing_report<Kem: KemTrait>(kem_name: &str, iterations: usize) {
+    type A = ChaCha20Poly1305;
+    type Kdf = HkdfSha256;
+
+    let mut csprng = StdRng::from_entropy();
+    let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+    let (_, pk_foreign) = Kem::gen_keypair(&mut csprng);
+
+    let encap_pool = |pk: &<Kem::Kex as KeyExchange>::PublicKey, csprng: &mut StdRng| {
+        (0..POOL_LEN)
+            .map(|_| {
+                setup_sender::<A, Kdf, Kem, _>(&OpModeS::Base, pk, b"", csprng)
+                    .unwrap()
+                    .0
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut classes = vec![
+        ("valid", encap_pool(&pk_recip, &mut csprng)),
+        ("foreign", encap_pool(&pk_foreign, &mut csprng)),
+    ];
+    let zero_bytes = vec![0u8; <Kem::Kex as KeyExchange>::PublicKey::size()];
+    if hpke::kem::EncappedKey::<Kem::Kex>::from_bytes(&zero_bytes).is_ok() {
+        let pool = (0..POOL_LEN)
+            .map(|_| hpke::kem::EncappedKey::<Kem::Kex>::from_bytes(&zero_bytes).unwrap())
+            .collect();
+        classes.push(("low-order", pool));
+    }
+
+    // Shuffle the schedule so that every class sees the same conditions over the run
+    let mut schedule: Vec<(usize, usize)> = (0..classes.len())
+        .flat_map(|class| (0..iterations).map(move |i| (class, i % POOL_LEN)))
+        .collect();
+    schedule.shuffle(&mut csprng);
+
+    let mut samples = vec![Vec::with_capacity(iterations); classes.len()];
+    for (class, i) in schedule {
+        let encapped_key = &classes[class].1[i];
+        let start = Instant::now();
+        let res = setup_receiver::<A, Kdf, Kem>(
+            &OpModeR::Base,
+            black_box(&sk_recip),
+            black_box(encapped_key),
+            b"",
+        );
+        let elapsed = start.elapsed();
+        black_box(res).ok();
+        samples[class].push(elapsed.as_nanos() as u64);
+    }
+
+    let reports: Vec<ClassReport> = classes
+        .iter()
+        .zip(samples.iter_mut())
+        .map(|((name, _), xs)| {
+            xs.sort_unstable();
+            ClassReport {
+                name,
+                median: quantile(xs, 0.5),
+                iqr: quantile(xs, 0.75) - quantile(xs, 0.25),
+            }
+        })
+        .collect();
+
+    // Compare everything to the valid class, which is always first
+    let baseline = &reports[0];
+    println!("{} ({} samples per class)", kem_name, iterations);
+    println!(
+        "  {:<10} {:>12} {:>12} {:>18}",
+        "class", "median (ns)", "IQR (ns)", "Δmedian / IQR"
+    );
+    for report in reports.iter() {
+        println!(
+            "  {:<10} {:>12.0} {:>12.0} {:>+18.2}",
+            report.name,
+            report.median,
+            report.iqr,
+            (report.median - baseline.median) / baseline.iqr.max(1.0),
+        );
+    }
+}
+
+fn main() {
+    // cargo bench passes flags like --bench to us. The iteration count is the first argument that
+    // parses as a number.
+    let iterations = env::args()
+        .skip(1)
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or(DEFAULT_ITERATIONS);
+
+    #[cfg(feature = "x25519-dalek")]
+    decap_timing_report::<hpke::kem::X25519HkdfSha256>("X25519HkdfSha256", iterations);
+
+    #[cfg(feature = "p256")]
+    decap_timing_report::<hpke::kem::DhP256HkdfSha256>("DhP256HkdfSha256", iterations);
+}
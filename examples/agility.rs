@@ -1,4 +1,11 @@
-#![allow(dead_code)]
+#![allow(
+    dead_code,
+    clippy::clone_on_copy,
+    clippy::collapsible_match,
+    clippy::extra_unused_type_parameters,
+    clippy::redundant_field_names,
+    clippy::wrong_self_convention
+)]
 //! Here's the gist of this file: Instead of doing things at the type level, you can use zero-sized
 //! types and runtime validity checks to do all of HPKE. This file is a rough idea of how one would
 //! go about implementing that. There isn't too much repetition. The main part where you have to
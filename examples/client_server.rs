@@ -15,6 +15,8 @@
 //  * Proper error handling. Everything here just panics when an error is encountered. It is up to
 //    the user of this library to do the appropriate thing when a function returns an error.
 
+#![allow(clippy::let_and_return, clippy::redundant_static_lifetimes)]
+
 use hpke::{
     aead::{AeadTag, ChaCha20Poly1305},
     kdf::HkdfSha384,
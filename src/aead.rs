@@ -1,18 +1,32 @@
 use crate::{
     kdf::{Kdf as KdfTrait, LabeledExpand},
-    kem::Kem as KemTrait,
-    kex::{Deserializable, Serializable},
-    setup::ExporterSecret,
-    util::{enforce_equal_len, full_suite_id, FullSuiteId},
+    kem::{self, EncappedKey, Kem as KemTrait},
+    kex::{Deserializable, KeyExchange, Serializable},
+    labels::LabelConfig,
+    op_mode::{OpModeR, OpModeS, PskBundle},
+    setup::{derive_enc_ctx_with_labels, ExporterSecret},
+    trace,
+    util::{enforce_equal_len, replace_and_zeroize, FullSuiteId},
     HpkeError,
 };
 
-use core::{default::Default, marker::PhantomData, u8};
+use core::{default::Default, marker::PhantomData, sync::atomic::Ordering};
+
+#[cfg(feature = "test-internals")]
+use alloc::collections::VecDeque;
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+#[cfg(feature = "alloc")]
+use subtle::{Choice, ConditionallySelectable};
 
 use aead::{AeadCore as BaseAeadCore, AeadInPlace as BaseAeadInPlace, NewAead as BaseNewAead};
 use byteorder::{BigEndian, ByteOrder};
+use digest::FixedOutput;
 use generic_array::GenericArray;
 use hkdf::Hkdf;
+use hmac::{Hmac, Mac, NewMac};
+use rand_core::{CryptoRng, RngCore};
+use subtle::ConstantTimeEq;
 use zeroize::Zeroize;
 
 /// Represents authenticated encryption functionality
@@ -123,7 +137,26 @@ fn mix_nonce<A: Aead>(base_nonce: &AeadNonce<A>, seq: &Seq) -> AeadNonce<A> {
     AeadNonce(GenericArray::from_exact_iter(new_nonce_iter).unwrap())
 }
 
-/// An authenticated encryption tag
+/// Strips the padding added by `AeadCtxS::seal_padded` from `buf`. The padding is everything
+/// from the last `0x80` byte on, and everything after that byte must be `0x00`.
+#[cfg(feature = "alloc")]
+fn unpad(buf: &mut Vec<u8>) -> Result<(), HpkeError> {
+    let marker_idx = buf
+        .iter()
+        .rposition(|&b| b != 0x00)
+        .ok_or(HpkeError::InvalidPadding)?;
+    if buf[marker_idx] != 0x80 {
+        return Err(HpkeError::InvalidPadding);
+    }
+
+    buf.truncate(marker_idx);
+    Ok(())
+}
+
+/// An authenticated encryption tag. To embed it in a wire format, convert it to bytes with
+/// `Serializable::to_bytes` and back with `Deserializable::from_bytes`, which checks the length.
+/// Both traits are re-exported at the crate root. `into_array` and `from_array` do the same with
+/// fixed-size arrays.
 pub struct AeadTag<A: Aead>(GenericArray<u8, <A::AeadImpl as BaseAeadCore>::TagSize>);
 
 impl<A: Aead> Serializable for AeadTag<A> {
@@ -145,6 +178,47 @@ impl<A: Aead> Deserializable for AeadTag<A> {
     }
 }
 
+impl<A: Aead> AeadTag<A> {
+    /// Converts this tag into a fixed-size array, without going through a `GenericArray`
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(arr)` on success. If `N` is not the tag size of `A`, returns
+    /// `Err(HpkeError::IncorrectInputLength { expected: tag_size, got: N })`.
+    pub fn into_array<const N: usize>(self) -> Result<[u8; N], HpkeError> {
+        enforce_equal_len(Self::size(), N)?;
+
+        let mut arr = [0u8; N];
+        arr.copy_from_slice(&self.0);
+        Ok(arr)
+    }
+
+    /// Makes a tag out of a fixed-size array. This is the inverse of `into_array`.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(tag)` on success. If `N` is not the tag size of `A`, returns
+    /// `Err(HpkeError::IncorrectInputLength { expected: tag_size, got: N })`.
+    pub fn from_array<const N: usize>(arr: [u8; N]) -> Result<AeadTag<A>, HpkeError> {
+        Self::from_bytes(&arr)
+    }
+}
+
+// Returns the same bytes as to_bytes()
+impl<A: Aead> AsRef<[u8]> for AeadTag<A> {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_slice()
+    }
+}
+
+/// Compares two ciphertexts, or any other byte strings, in constant time. This is meant for
+/// checking a ciphertext made by `setup_sender_idempotent` against a stored one, to decide whether
+/// a retry is a duplicate. Only the lengths of `a` and `b` leak. If they differ, this returns
+/// `false` without looking at the contents.
+pub fn ct_ciphertext_eq(a: &[u8], b: &[u8]) -> bool {
+    bool::from(a.ct_eq(b))
+}
+
 /// The HPKE encryption context. This is what you use to `seal` plaintexts and `open` ciphertexts.
 pub(crate) struct AeadCtx<A: Aead, Kdf: KdfTrait, Kem: KemTrait> {
     /// Records whether the nonce sequence counter has overflowed
@@ -153,14 +227,98 @@ pub(crate) struct AeadCtx<A: Aead, Kdf: KdfTrait, Kem: KemTrait> {
     encryptor: A::AeadImpl,
     /// The base nonce which we XOR with sequence numbers
     base_nonce: AeadNonce<A>,
-    /// The exporter secret, used in the `export()` method
+    /// The exporter secret. This is what `export()` and the other exporter-derived values expand
+    /// from. It's zeroized on drop.
     exporter_secret: ExporterSecret<Kdf>,
     /// The running sequence number
     seq: Seq,
     /// This binds the `AeadCtx` to the KEM that made it. Used to generate `suite_id`.
     src_kem: PhantomData<Kem>,
-    /// The full ID of the ciphersuite that created this `AeadCtx`. Used for context binding.
+    /// The full ID of the ciphersuite that created this `AeadCtx`. Used for context binding. This
+    /// is computed once, at setup, so that `export` and the other labeled operations don't
+    /// rebuild it on every call.
     suite_id: FullSuiteId,
+    /// The labels this context was set up with. `suite_id` is already made with these. This is
+    /// kept for the version label, and in case the context has to be set up again.
+    labels: LabelConfig,
+    /// The serialized encapsulated key this context was set up with, if there was one. Used by
+    /// `seal_binding_enc` and `open_binding_enc`.
+    enc: Option<EncappedKeyBytes<Kem>>,
+    /// The sender identity key this context was set up to authenticate, if it's a receiver
+    /// context in an authenticated mode. Used by `AeadCtxR::sender_public_key`.
+    pk_sender_id: Option<<Kem::Kex as KeyExchange>::PublicKey>,
+    /// The last `NONCE_LOG_LEN` nonces that `seal` has used, in order
+    #[cfg(feature = "test-internals")]
+    nonce_log: VecDeque<GenericArray<u8, <A::AeadImpl as BaseAeadCore>::NonceSize>>,
+    /// The number of successful seals. Unlike `seq`, this is only for `stats()`.
+    seals: u64,
+    /// The number of successful opens
+    opens: u64,
+    /// The total length of the plaintexts that were successfully sealed
+    bytes_sealed: u64,
+    /// The most messages this context may seal, if `AeadCtxS::with_seal_limit` set a limit
+    seal_limit: Option<u64>,
+    /// How far ahead of `seq` the sequence number given to `AeadCtxR::open_at_seq` can be
+    seq_window: u64,
+    /// The number of successful exports
+    exports: ExportCounter,
+}
+
+/// How many operations a context has done, as returned by `AeadCtxS::stats` and
+/// `AeadCtxR::stats`. Only successful operations are counted. None of this is secret, and it has
+/// no effect on the crypto.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ContextStats {
+    /// The number of messages sealed, by any of the `seal` methods
+    pub seals: u64,
+    /// The number of messages opened, by any of the `open` methods
+    pub opens: u64,
+    /// The number of secrets exported with `export`. This saturates at `usize::MAX` on targets
+    /// without 64-bit atomics, and is always 0 on targets without atomic read-modify-write
+    /// operations.
+    pub exports: u64,
+}
+
+// Counts exports. `export` takes `&self`, so this has to be atomic. Not every target has 64-bit
+// atomics, so where they're missing, this is a `usize` that saturates. Targets without any atomic
+// read-modify-write operations don't count exports at all, and always report 0.
+#[cfg(target_has_atomic = "64")]
+struct ExportCounter(core::sync::atomic::AtomicU64);
+#[cfg(not(target_has_atomic = "64"))]
+struct ExportCounter(core::sync::atomic::AtomicUsize);
+
+#[cfg(target_has_atomic = "64")]
+impl ExportCounter {
+    fn new(n: u64) -> ExportCounter {
+        ExportCounter(core::sync::atomic::AtomicU64::new(n))
+    }
+
+    fn increment(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(not(target_has_atomic = "64"))]
+impl ExportCounter {
+    fn new(n: u64) -> ExportCounter {
+        let n = core::convert::TryFrom::try_from(n).unwrap_or(usize::MAX);
+        ExportCounter(core::sync::atomic::AtomicUsize::new(n))
+    }
+
+    fn increment(&self) {
+        #[cfg(target_has_atomic = "ptr")]
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_add(1));
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed) as u64
+    }
 }
 
 // Necessary for test_setup_soundness
@@ -174,19 +332,76 @@ impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> Clone for AeadCtx<A, Kdf, Kem> {
             exporter_secret: self.exporter_secret.clone(),
             seq: self.seq.clone(),
             src_kem: PhantomData,
-            suite_id: self.suite_id.clone(),
+            suite_id: self.suite_id,
+            labels: self.labels,
+            enc: self.enc.clone(),
+            pk_sender_id: self.pk_sender_id.clone(),
+            #[cfg(feature = "test-internals")]
+            nonce_log: self.nonce_log.clone(),
+            seals: self.seals,
+            opens: self.opens,
+            bytes_sealed: self.bytes_sealed,
+            seal_limit: self.seal_limit,
+            seq_window: self.seq_window,
+            exports: ExportCounter::new(self.exports.get()),
         }
     }
 }
 
+/// The length of a `Salt`, in bytes
+pub const SALT_LEN: usize = 16;
+
+/// A random per-message salt, used by `AeadCtxS::seal_salted` and `AeadCtxR::open_salted`
+pub type Salt = [u8; SALT_LEN];
+
+/// How many nonces `AeadCtxS::nonce_log` remembers. Older ones are forgotten, so a long-lived
+/// context doesn't grow without bound.
+#[cfg(feature = "test-internals")]
+const NONCE_LOG_LEN: usize = 4096;
+
+/// The length of the timestamp that `AeadCtxS::seal_timestamped` prepends, in bytes
+#[cfg(feature = "alloc")]
+const TIMESTAMP_LEN: usize = 8;
+
+/// Computes the AAD of a timestamped message, i.e., `I2OSP(timestamp, 8) || aad`
+#[cfg(feature = "alloc")]
+fn timestamped_aad(timestamp_bytes: &[u8], aad: &[u8]) -> Vec<u8> {
+    let mut full_aad = Vec::with_capacity(timestamp_bytes.len() + aad.len());
+    full_aad.extend_from_slice(timestamp_bytes);
+    full_aad.extend_from_slice(aad);
+    full_aad
+}
+
+/// A serialized encapsulated key of the given KEM
+type EncappedKeyBytes<Kem> = GenericArray<
+    u8,
+    <<<Kem as KemTrait>::Kex as KeyExchange>::PublicKey as Serializable>::OutputSize,
+>;
+
+/// An HMAC key or HMAC tag. Both are `Nh` bytes long, where `Nh` is the digest size of `Kdf`.
+pub type HmacOutput<Kdf> =
+    GenericArray<u8, <<Kdf as KdfTrait>::HashImpl as FixedOutput>::OutputSize>;
+
 impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AeadCtx<A, Kdf, Kem> {
     /// Makes an AeadCtx from a raw key and nonce
+    #[cfg(any(test, feature = "alloc"))]
     pub(crate) fn new(
         key: &AeadKey<A>,
         base_nonce: AeadNonce<A>,
         exporter_secret: ExporterSecret<Kdf>,
     ) -> AeadCtx<A, Kdf, Kem> {
-        let suite_id = full_suite_id::<A, Kdf, Kem>();
+        AeadCtx::new_with_labels(key, base_nonce, exporter_secret, &LabelConfig::HPKE)
+    }
+
+    /// Makes an AeadCtx from a raw key and nonce, which does its own labeled expansions under
+    /// `labels`
+    pub(crate) fn new_with_labels(
+        key: &AeadKey<A>,
+        base_nonce: AeadNonce<A>,
+        exporter_secret: ExporterSecret<Kdf>,
+        labels: &LabelConfig,
+    ) -> AeadCtx<A, Kdf, Kem> {
+        let suite_id = labels.full_suite_id::<A, Kdf, Kem>();
         AeadCtx {
             overflowed: false,
             encryptor: <A::AeadImpl as aead::NewAead>::new(&key.0),
@@ -195,9 +410,64 @@ impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AeadCtx<A, Kdf, Kem> {
             seq: <Seq as Default>::default(),
             src_kem: PhantomData,
             suite_id,
+            labels: *labels,
+            enc: None,
+            pk_sender_id: None,
+            #[cfg(feature = "test-internals")]
+            nonce_log: VecDeque::new(),
+            seals: 0,
+            opens: 0,
+            bytes_sealed: 0,
+            seal_limit: None,
+            seq_window: 0,
+            exports: ExportCounter::new(0),
         }
     }
 
+    /// Overwrites the key material in this context with zeros, and marks it as used up. The AEAD
+    /// is replaced with one keyed with all zeros, and the memory of the old one is zeroed after
+    /// it's dropped, so its key schedule is wiped even if its implementation doesn't zeroize on
+    /// drop.
+    pub(crate) fn wipe(&mut self) {
+        self.base_nonce.0.zeroize();
+        replace_and_zeroize(
+            &mut self.encryptor,
+            <A::AeadImpl as aead::NewAead>::new(&AeadKey::<A>::default().0),
+        );
+        self.exporter_secret.0.zeroize();
+        self.overflowed = true;
+    }
+
+    /// Records `encapped_key` as the encapsulated key this context was set up with
+    pub(crate) fn bind_enc(&mut self, encapped_key: &EncappedKey<Kem::Kex>) {
+        self.enc = Some(encapped_key.to_bytes());
+    }
+
+    /// Records `pk_sender_id` as the sender identity key this context authenticates
+    pub(crate) fn bind_sender_id(
+        &mut self,
+        pk_sender_id: Option<&<Kem::Kex as KeyExchange>::PublicKey>,
+    ) {
+        self.pk_sender_id = pk_sender_id.cloned();
+    }
+
+    /// Returns `enc || extra_aad`, where `enc` is the encapsulated key this context was set up
+    /// with. `enc` has a fixed length for a given KEM, so this encoding is unambiguous.
+    #[cfg(feature = "alloc")]
+    fn enc_aad(&self, extra_aad: &[u8]) -> Result<Vec<u8>, HpkeError> {
+        let enc = self.enc.as_ref().ok_or(HpkeError::NoEncappedKey)?;
+        let mut aad = Vec::with_capacity(enc.len() + extra_aad.len());
+        aad.extend_from_slice(enc);
+        aad.extend_from_slice(extra_aad);
+        Ok(aad)
+    }
+
+    /// Returns an HKDF-Expand context keyed with the exporter secret
+    fn exporter(&self) -> Hkdf<Kdf::HashImpl> {
+        // The exporter secret is exactly the digest size, so this can't fail
+        Hkdf::<Kdf::HashImpl>::from_prk(self.exporter_secret.0.as_slice()).unwrap()
+    }
+
     // draft11 §5.3
     // def Context.Export(exporter_context, L):
     //   return LabeledExpand(self.exporter_secret, "sec",
@@ -212,19 +482,281 @@ impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AeadCtx<A, Kdf, Kem> {
     /// underlying hash function, returns an `Err(HpkeError::KdfOutputTooLong)`. Just don't use to
     /// fill massive buffers and you'll be fine.
     pub fn export(&self, exporter_ctx: &[u8], out_buf: &mut [u8]) -> Result<(), HpkeError> {
-        // Use our exporter secret as the PRK for an HKDF-Expand op. The only time this fails is
-        // when the length of the PRK is not the the underlying hash function's digest size. But
-        // that's guaranteed by the type system, so we can unwrap().
-        let hkdf_ctx = Hkdf::<Kdf::HashImpl>::from_prk(self.exporter_secret.0.as_slice()).unwrap();
-
-        // This call either succeeds or returns hkdf::InvalidLength (iff the buffer length is more
-        // than 255x the digest size of the underlying hash function)
-        hkdf_ctx
-            .labeled_expand(&self.suite_id, b"sec", exporter_ctx, out_buf)
+        self.export_uncounted(exporter_ctx, out_buf)?;
+        self.exports.increment();
+        Ok(())
+    }
+
+    /// Does `export` without counting it in `stats()`. This is for secrets the library exports
+    /// for its own use.
+    fn export_uncounted(&self, exporter_ctx: &[u8], out_buf: &mut [u8]) -> Result<(), HpkeError> {
+        // Do an HKDF-Expand op with our exporter secret as the PRK. This call either succeeds or
+        // returns hkdf::InvalidLength (iff the buffer length is more than 255x the digest size of
+        // the underlying hash function)
+        self.exporter()
+            .labeled_expand_with_version(
+                self.labels.version_label,
+                &self.suite_id,
+                b"sec",
+                exporter_ctx,
+                out_buf,
+            )
             .map_err(|_| HpkeError::KdfOutputTooLong)
     }
+
+    /// Exports `len` bytes for the current sequence number `seq`. This uses the label `"seq sec"`
+    /// instead of the `"sec"` that `export` uses, with the exporter context `I2OSP(seq, 8) ||
+    /// label`, so it never coincides with an exported secret. Returns `seq` along with the secret.
+    #[cfg(feature = "alloc")]
+    fn export_at_seq(&self, label: &[u8], len: usize) -> Result<(u64, Vec<u8>), HpkeError> {
+        let seq = self.seq.0;
+        let mut exporter_ctx = Vec::with_capacity(8 + label.len());
+        exporter_ctx.extend_from_slice(&seq.to_be_bytes());
+        exporter_ctx.extend_from_slice(label);
+
+        let mut secret = vec![0u8; len];
+        self.exporter()
+            .labeled_expand_with_version(
+                self.labels.version_label,
+                &self.suite_id,
+                b"seq sec",
+                &exporter_ctx,
+                &mut secret,
+            )
+            .map_err(|_| HpkeError::KdfOutputTooLong)?;
+        self.exports.increment();
+        Ok((seq, secret))
+    }
+
+    /// Exports `len` bytes that also depend on `salt`. An `Nh`-byte secret is expanded from the
+    /// exporter secret with the label `"salted exporter"`. Then `LabeledExtract(salt,
+    /// "salted_sec", secret)` is expanded with the label `"sec"` and the exporter context `label`.
+    #[cfg(feature = "alloc")]
+    fn export_with_salt(
+        &self,
+        salt: &[u8],
+        label: &[u8],
+        len: usize,
+    ) -> Result<Vec<u8>, HpkeError> {
+        // Don't let the length assert in LabeledExpand get hit. Anything this long is too long for
+        // HKDF anyway.
+        if len > u16::MAX as usize {
+            return Err(HpkeError::KdfOutputTooLong);
+        }
+
+        // This only fails if the output is more than 255x the digest size. It's exactly 1x.
+        let mut ikm = <ExporterSecret<Kdf> as Default>::default();
+        self.exporter()
+            .labeled_expand_with_version(
+                self.labels.version_label,
+                &self.suite_id,
+                b"salted exporter",
+                &[],
+                ikm.0.as_mut_slice(),
+            )
+            .expect("salted exporter secret is way too big");
+        let (_, salted_exporter) = crate::kdf::labeled_extract_with_version::<Kdf>(
+            self.labels.version_label,
+            salt,
+            &self.suite_id,
+            b"salted_sec",
+            &ikm.0,
+        );
+
+        let mut secret = vec![0u8; len];
+        salted_exporter
+            .labeled_expand_with_version(
+                self.labels.version_label,
+                &self.suite_id,
+                b"sec",
+                label,
+                &mut secret,
+            )
+            .map_err(|_| HpkeError::KdfOutputTooLong)?;
+        self.exports.increment();
+        Ok(secret)
+    }
+
+    /// Returns how many operations this context has done
+    pub(crate) fn stats(&self) -> ContextStats {
+        ContextStats {
+            seals: self.seals,
+            opens: self.opens,
+            exports: self.exports.get(),
+        }
+    }
+
+    /// Returns a fingerprint of the exporter secret. This uses the label `"fingerprint"` instead
+    /// of the `"sec"` that `export` uses, so it never coincides with an exported secret.
+    fn fingerprint(&self) -> [u8; 32] {
+        // This only fails if the output is more than 255x the digest size. It's at most 1x.
+        let mut out = [0u8; 32];
+        self.exporter()
+            .labeled_expand_with_version(
+                self.labels.version_label,
+                &self.suite_id,
+                b"fingerprint",
+                &[],
+                &mut out,
+            )
+            .expect("fingerprint is way too big");
+        out
+    }
+
+    /// Derives a message ID for the sequence number `seq`. This uses the label `"message id"`
+    /// instead of the `"sec"` that `export` uses, with the exporter context `I2OSP(seq, 8)`.
+    fn message_id(&self, seq: u64) -> [u8; 16] {
+        // This only fails if the output is more than 255x the digest size. It's less than 1x.
+        let mut id = [0u8; 16];
+        self.exporter()
+            .labeled_expand_with_version(
+                self.labels.version_label,
+                &self.suite_id,
+                b"message id",
+                &seq.to_be_bytes(),
+                &mut id,
+            )
+            .expect("message ID is way too big");
+        id
+    }
+
+    /// Derives an HMAC key from the exporter secret. This uses the label `"hmac key"` instead of
+    /// the `"sec"` that `export` uses, so it never coincides with an exported secret. It also
+    /// never coincides with the AEAD key, which isn't derived from the exporter secret at all.
+    fn hmac_key(&self, label: &[u8]) -> HmacOutput<Kdf> {
+        // This only fails if the output is more than 255x the digest size. It's exactly 1x.
+        let mut key = HmacOutput::<Kdf>::default();
+        self.exporter()
+            .labeled_expand_with_version(
+                self.labels.version_label,
+                &self.suite_id,
+                b"hmac key",
+                label,
+                &mut key,
+            )
+            .expect("HMAC key is way too big");
+        key
+    }
+
+    /// Returns an HMAC instance keyed with `hmac_key(label)`
+    fn keyed_hmac(&self, label: &[u8]) -> Hmac<Kdf::HashImpl> {
+        let mut key = self.hmac_key(label);
+        // HMAC takes keys of any length, so this can't fail
+        let mac = Hmac::<Kdf::HashImpl>::new_from_slice(&key).unwrap();
+        key.zeroize();
+        mac
+    }
+
+    /// Computes a key-confirmation tag over `transcript`. The MAC key is derived from the exporter
+    /// secret with the label `"confirmation"`, so it never coincides with an exported secret or
+    /// an HMAC key. The tag is HMAC with the KDF's hash function, truncated to 32 bytes.
+    fn confirmation_tag(&self, transcript: &[u8]) -> [u8; 32] {
+        // This only fails if the output is more than 255x the digest size. It's exactly 1x.
+        let mut key = HmacOutput::<Kdf>::default();
+        self.exporter()
+            .labeled_expand_with_version(
+                self.labels.version_label,
+                &self.suite_id,
+                b"confirmation",
+                &[],
+                &mut key,
+            )
+            .expect("confirmation key is way too big");
+        // HMAC takes keys of any length, so this can't fail
+        let mut mac = Hmac::<Kdf::HashImpl>::new_from_slice(&key).unwrap();
+        key.zeroize();
+        mac.update(transcript);
+
+        // Every supported hash function has an output of at least 32 bytes
+        let mut tag = [0u8; 32];
+        tag.copy_from_slice(&mac.finalize().into_bytes()[..32]);
+        tag
+    }
+
+    /// Checks `tag` against `confirmation_tag(transcript)` in constant time
+    fn verify_confirmation(&self, transcript: &[u8], tag: &[u8; 32]) -> Result<(), HpkeError> {
+        if bool::from(self.confirmation_tag(transcript).ct_eq(tag)) {
+            Ok(())
+        } else {
+            Err(HpkeError::ValidationError)
+        }
+    }
+
+    /// Returns the `(KEM ID, KDF ID, AEAD ID)` of the ciphersuite that made this context
+    pub(crate) fn suite_ids(&self) -> (u16, u16, u16) {
+        (Kem::KEM_ID, Kdf::KDF_ID, A::AEAD_ID)
+    }
+
+    /// Returns the nonce for the current sequence number. If `salt` is given, the nonce is also
+    /// XORed with a mask derived from the exporter secret and the salt (see
+    /// `AeadCtxS::seal_salted`). The label `"salted nonce"` keeps the mask separate from exported
+    /// secrets.
+    fn current_nonce(&self, salt: Option<&Salt>) -> AeadNonce<A> {
+        let mut nonce = mix_nonce::<A>(&self.base_nonce, &self.seq);
+        if let Some(salt) = salt {
+            // This only fails if the output is more than 255x the digest size. It's at most 1x.
+            let mut mask = AeadNonce::<A>::default();
+            self.exporter()
+                .labeled_expand_with_version(
+                    self.labels.version_label,
+                    &self.suite_id,
+                    b"salted nonce",
+                    salt,
+                    mask.0.as_mut_slice(),
+                )
+                .expect("nonce mask is way too big");
+            for (nonce_byte, mask_byte) in nonce.0.iter_mut().zip(mask.0.iter()) {
+                *nonce_byte ^= mask_byte;
+            }
+        }
+        nonce
+    }
+
+    /// Exports a secret of length `Nh` which is used as the PSK of a re-encapsulated context. This
+    /// is how a re-encapsulated context stays bound to the mode, PSK, and info string of this one.
+    fn reencap_psk(&self) -> ExporterSecret<Kdf> {
+        let mut psk = <ExporterSecret<Kdf> as Default>::default();
+        // This only fails if the output is more than 255x the digest size. It's 1x the digest size
+        self.export_uncounted(REENCAP_LABEL, psk.0.as_mut_slice())
+            .expect("reencap psk is way too big");
+        psk
+    }
+}
+
+/// Lets the `*_in_place_buffer` methods work on a `BytesMut`. aead 0.4 doesn't implement
+/// `aead::Buffer` for it.
+#[cfg(feature = "bytes")]
+struct BytesMutBuffer<'a>(&'a mut bytes::BytesMut);
+
+#[cfg(feature = "bytes")]
+impl<'a> AsRef<[u8]> for BytesMutBuffer<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl<'a> AsMut<[u8]> for BytesMutBuffer<'a> {
+    fn as_mut(&mut self) -> &mut [u8] {
+        self.0.as_mut()
+    }
+}
+
+#[cfg(feature = "bytes")]
+impl<'a> aead::Buffer for BytesMutBuffer<'a> {
+    fn extend_from_slice(&mut self, other: &[u8]) -> aead::Result<()> {
+        self.0.extend_from_slice(other);
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.0.truncate(len);
+    }
 }
 
+/// The exporter context and PSK ID used when re-encapsulating a context
+const REENCAP_LABEL: &[u8] = b"reencapsulate";
+
 /// The HPKE receiver's context. This is what you use to `open` ciphertexts and `export` secrets.
 pub struct AeadCtxR<A: Aead, Kdf: KdfTrait, Kem: KemTrait>(AeadCtx<A, Kdf, Kem>);
 
@@ -260,19 +792,35 @@ impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AeadCtxR<A, Kdf, Kem> {
     /// Returns `Ok(())` on success. If this context has been used for so many encryptions that the
     /// sequence number overflowed, returns `Err(HpkeError::MessageLimitReached)`. If this happens,
     /// `ciphertext` will be unmodified. If the tag fails to validate, returns
-    /// `Err(HpkeError::OpenError)`. If this happens, `ciphertext` is in an undefined state.
+    /// `Err(HpkeError::OpenError)`. If this happens, `ciphertext` is in an undefined state, and
+    /// the sequence number is unchanged. If `A` is `ExportOnlyAead`, returns
+    /// `Err(HpkeError::ExportOnly)`, and `ciphertext` will be unmodified.
     pub fn open(
         &mut self,
         ciphertext: &mut [u8],
         aad: &[u8],
         tag: &AeadTag<A>,
     ) -> Result<(), HpkeError> {
-        if self.0.overflowed {
+        self.open_inner(ciphertext, aad, tag, None)
+    }
+
+    // The body of open and open_salted
+    fn open_inner(
+        &mut self,
+        ciphertext: &mut [u8],
+        aad: &[u8],
+        tag: &AeadTag<A>,
+        salt: Option<&Salt>,
+    ) -> Result<(), HpkeError> {
+        if A::AEAD_ID == ExportOnlyAead::AEAD_ID {
+            // There's nothing to decrypt with
+            Err(HpkeError::ExportOnly)
+        } else if self.0.overflowed {
             // If the sequence counter overflowed, we've been used for too long. Shut down.
             Err(HpkeError::MessageLimitReached)
         } else {
             // Compute the nonce and do the encryption in place
-            let nonce = mix_nonce::<A>(&self.0.base_nonce, &self.0.seq);
+            let nonce = self.0.current_nonce(salt);
             let decrypt_res = self
                 .0
                 .encryptor
@@ -280,330 +828,3111 @@ impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AeadCtxR<A, Kdf, Kem> {
 
             if decrypt_res.is_err() {
                 // Opening failed due to a bad tag
+                trace::open_failure(self.0.suite_ids());
                 return Err(HpkeError::OpenError);
             }
 
+            self.0.opens += 1;
+
             // Opening was a success. Try to increment the sequence counter. If it fails, this was
             // our last decryption.
             match increment_seq(&self.0.seq) {
                 Some(new_seq) => self.0.seq = new_seq,
-                None => self.0.overflowed = true,
+                None => {
+                    trace::seq_overflow("receiver", self.0.suite_ids(), self.0.seq.0);
+                    self.0.overflowed = true;
+                }
             }
 
             Ok(())
         }
     }
 
-    /// Fills a given buffer with secret bytes derived from this encryption context. This value
-    /// does not depend on sequence number, so it is constant for the lifetime of this context.
+    /// Sets how far ahead of the expected sequence number `open_at_seq` will look. The default is
+    /// 0, which only accepts messages in order. This is for lossy transports, where the sender's
+    /// sequence number can get ahead of the receiver's when messages are dropped.
+    pub fn with_seq_window(mut self, window: u64) -> AeadCtxR<A, Kdf, Kem> {
+        self.0.seq_window = window;
+        self
+    }
+
+    /// Does `open` on the message with the sequence number `seq`, which the sender transmitted
+    /// along with the ciphertext. `seq` can be ahead of the sequence number this context expects
+    /// by up to the window set by `with_seq_window`. On success, the context skips to `seq + 1`,
+    /// so the skipped messages can't be opened anymore. Sequence numbers that were already used
+    /// or skipped are rejected, so replays don't open.
     ///
     /// Return Value
     /// ============
-    /// Returns `Ok(())` on success. If the buffer length is more than about 255x the digest size
-    /// of the underlying hash function, returns an `Err(HpkeError::KdfOutputTooLong)`. The exact
-    /// number is given in the "Input Length Restrictions" section of the spec. Just don't use to
-    /// fill massive buffers and you'll be fine.
-    pub fn export(&self, info: &[u8], out_buf: &mut [u8]) -> Result<(), HpkeError> {
-        // Pass to AeadCtx
-        self.0.export(info, out_buf)
+    /// Returns `Ok(())` on success. If `seq` is behind the expected sequence number, or ahead of
+    /// it by more than the window, returns `Err(HpkeError::SeqOutOfWindow)`, and `ciphertext` is
+    /// unmodified. Otherwise, this returns the same errors as `open`. On any error, the sequence
+    /// number is unchanged.
+    pub fn open_at_seq(
+        &mut self,
+        seq: u64,
+        ciphertext: &mut [u8],
+        aad: &[u8],
+        tag: &AeadTag<A>,
+    ) -> Result<(), HpkeError> {
+        let expected_seq = self.0.seq.0;
+        if self.0.overflowed || A::AEAD_ID == ExportOnlyAead::AEAD_ID {
+            // Let open_inner return the right error
+        } else if seq < expected_seq || seq - expected_seq > self.0.seq_window {
+            return Err(HpkeError::SeqOutOfWindow);
+        }
+
+        // Open at the given sequence number, and go back to the old one if that fails
+        self.0.seq = Seq(seq);
+        let res = self.open_inner(ciphertext, aad, tag, None);
+        if res.is_err() {
+            self.0.seq = Seq(expected_seq);
+        }
+        res
     }
-}
 
-/// The HPKE senders's context. This is what you use to `seal` plaintexts and `export` secrets.
-pub struct AeadCtxS<A: Aead, Kdf: KdfTrait, Kem: KemTrait>(AeadCtx<A, Kdf, Kem>);
+    /// Opens `ciphertext`, which is a ciphertext with its tag appended, and writes the plaintext
+    /// to the start of `out`. This mirrors `AeadCtxS::seal_to_slice`.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(n)` on success, where `n` is the length of the plaintext. If `ciphertext` is
+    /// shorter than a tag, returns `Err(HpkeError::OpenError)`. If `out` is shorter than the
+    /// plaintext, returns
+    /// `Err(HpkeError::IncorrectInputLength { expected: plaintext_len, got: out.len() })`. In both
+    /// cases, neither `out` nor the sequence number is modified. On any error from `open`, the
+    /// first `n` bytes of `out` are zeroed.
+    pub fn open_to_slice(
+        &mut self,
+        ciphertext: &[u8],
+        aad: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, HpkeError> {
+        let tag_len = AeadTag::<A>::size();
+        let pt_len = ciphertext
+            .len()
+            .checked_sub(tag_len)
+            .ok_or(HpkeError::OpenError)?;
+        if out.len() < pt_len {
+            return Err(HpkeError::IncorrectInputLength {
+                expected: pt_len,
+                got: out.len(),
+            });
+        }
 
-// AeadCtx -> AeadCtxS via wrapping
-impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> From<AeadCtx<A, Kdf, Kem>> for AeadCtxS<A, Kdf, Kem> {
-    fn from(ctx: AeadCtx<A, Kdf, Kem>) -> AeadCtxS<A, Kdf, Kem> {
-        AeadCtxS(ctx)
-    }
-}
+        let (body, tag_bytes) = ciphertext.split_at(pt_len);
+        let tag = AeadTag::<A>::from_bytes(tag_bytes)?;
+        let pt_out = &mut out[..pt_len];
+        pt_out.copy_from_slice(body);
+        if let Err(e) = self.open(pt_out, aad, &tag) {
+            // Don't leave unauthenticated data lying around
+            pt_out.zeroize();
+            return Err(e);
+        }
 
-// Necessary for test_setup_soundness
-#[cfg(test)]
-impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> Clone for AeadCtxS<A, Kdf, Kem> {
-    fn clone(&self) -> AeadCtxS<A, Kdf, Kem> {
-        self.0.clone().into()
+        Ok(pt_len)
     }
-}
-
-impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AeadCtxS<A, Kdf, Kem> {
-    // draft11 §5.2
-    // def ContextS.Seal(aad, pt):
-    //   ct = Seal(self.key, self.ComputeNonce(self.seq), aad, pt)
-    //   self.IncrementSeq()
-    //   return ct
 
-    /// Does a "detached seal in place", meaning it overwrites `plaintext` with the resulting
-    /// ciphertext, and returns the resulting authentication tag
+    /// Opens `ciphertext`, which is a ciphertext with its tag appended, and returns either the
+    /// plaintext or `dummy`, along with a `Choice` that is 1 iff the message opened. `dummy` must
+    /// be as long as the plaintext, i.e., `ciphertext.len() - AeadTag::<A>::size()` bytes.
+    ///
+    /// This is for callers that would rather not branch on whether a message authenticated. The
+    /// output is always a buffer of the expected length, and it's picked from the plaintext and
+    /// `dummy` byte by byte with constant-time selects, so the caller can carry the `Choice` along
+    /// and only look at it at the end. This does not make the whole call constant-time: `open`
+    /// itself branches on the result of the tag check, and a successful open increments the
+    /// sequence number while a failed one doesn't.
     ///
     /// Return Value
     /// ============
-    /// Returns `Ok(tag)` on success.  If this context has been used for so many encryptions that
-    /// the sequence number overflowed, returns `Err(HpkeError::MessageLimitReached)`. If this
-    /// happens, `plaintext` will be unmodified. If an error happened during encryption, returns
-    /// `Err(HpkeError::SealError)`. If this happens, the contents of `plaintext` is undefined.
-    pub fn seal(&mut self, plaintext: &mut [u8], aad: &[u8]) -> Result<AeadTag<A>, HpkeError> {
-        if self.0.overflowed {
-            // If the sequence counter overflowed, we've been used for far too long. Shut down.
-            Err(HpkeError::MessageLimitReached)
-        } else {
-            // Compute the nonce and do the encryption in place
-            let nonce = mix_nonce::<A>(&self.0.base_nonce, &self.0.seq);
-            let tag = self
-                .0
-                .encryptor
-                .encrypt_in_place_detached(&nonce.0, aad, plaintext)
-                .map_err(|_| HpkeError::SealError)?;
+    /// Returns `(output, choice)`. If `ciphertext` is shorter than a tag, or `dummy` isn't the
+    /// length of the plaintext, returns a copy of `dummy` and `Choice(0)`. These lengths aren't
+    /// secret, so this is decided by a regular branch. If this context has been used for so many
+    /// encryptions that the sequence number overflowed, or if `A` is `ExportOnlyAead`, the output
+    /// is also `dummy` with `Choice(0)`.
+    #[cfg(feature = "alloc")]
+    pub fn open_ct(&mut self, ciphertext: &[u8], aad: &[u8], dummy: &[u8]) -> (Vec<u8>, Choice) {
+        let tag_len = AeadTag::<A>::size();
+        let pt_len = match ciphertext.len().checked_sub(tag_len) {
+            Some(pt_len) if pt_len == dummy.len() => pt_len,
+            _ => return (dummy.to_vec(), Choice::from(0)),
+        };
 
-            // Try to increment the sequence counter. If it fails, this was our last encryption.
-            match increment_seq(&self.0.seq) {
-                Some(new_seq) => self.0.seq = new_seq,
-                None => self.0.overflowed = true,
-            }
+        let (body, tag_bytes) = ciphertext.split_at(pt_len);
+        // The tag is exactly tag_len bytes, so this can't fail
+        let tag = AeadTag::<A>::from_bytes(tag_bytes).unwrap();
+        let mut buf = body.to_vec();
+        let opened = Choice::from(self.open(&mut buf, aad, &tag).is_ok() as u8);
 
-            // Return the tag
-            Ok(AeadTag(tag))
+        // Pick the plaintext if the open worked, and the dummy otherwise, without branching
+        for (out_byte, dummy_byte) in buf.iter_mut().zip(dummy.iter()) {
+            *out_byte = u8::conditional_select(dummy_byte, out_byte, opened);
         }
+        (buf, opened)
     }
 
-    /// Fills a given buffer with secret bytes derived from this encryption context. This value
-    /// does not depend on sequence number, so it is constant for the lifetime of this context.
+    /// Opens the ciphertext in `buf`, which has its tag appended, in place, and truncates the tag
+    /// off. This mirrors `AeadCtxS::seal_in_place_buffer`, and works with any `aead::Buffer`, so
+    /// growable buffers can be opened without copying. For `bytes::BytesMut`, use
+    /// `open_in_place_bytes`.
     ///
     /// Return Value
     /// ============
-    /// Returns `Ok(())` on success. If the buffer length is more than 255x the digest size of the
-    /// underlying hash function, returns an `Err(HpkeError::KdfOutputTooLong)`. Just don't use to
-    /// fill massive buffers and you'll be fine.
-    pub fn export(&self, info: &[u8], out_buf: &mut [u8]) -> Result<(), HpkeError> {
-        // Pass to AeadCtx
-        self.0.export(info, out_buf)
-    }
-}
+    /// Returns `Ok(())` on success, and `buf` then holds the plaintext. If `buf` is shorter than a
+    /// tag, returns `Err(HpkeError::OpenError)`. Otherwise, errors are the same as in `open`. With
+    /// the AEADs in this crate, which check the tag before decrypting anything, `buf` is unchanged
+    /// on any error.
+    pub fn open_in_place_buffer<B: aead::Buffer>(
+        &mut self,
+        buf: &mut B,
+        aad: &[u8],
+    ) -> Result<(), HpkeError> {
+        let tag_len = AeadTag::<A>::size();
+        let pt_len = buf.len().checked_sub(tag_len).ok_or(HpkeError::OpenError)?;
 
-// Export all the AEAD implementations
-pub mod aes_gcm;
-pub mod chacha20_poly1305;
-pub mod export_only;
-#[doc(inline)]
-pub use crate::aead::{aes_gcm::*, chacha20_poly1305::*, export_only::*};
+        let (ciphertext, tag_bytes) = buf.as_mut().split_at_mut(pt_len);
+        let tag = AeadTag::<A>::from_bytes(tag_bytes)?;
+        self.open(ciphertext, aad, &tag)?;
+        buf.truncate(pt_len);
 
-#[cfg(test)]
-mod test {
-    use super::{Aead, AeadTag, AesGcm128, AesGcm256, ChaCha20Poly1305, ExportOnlyAead, Seq};
-    use crate::{
-        kdf::HkdfSha256,
-        kex::{Deserializable, Serializable},
-        test_util::gen_ctx_simple_pair,
-        HpkeError,
-    };
+        Ok(())
+    }
 
-    use aead::AeadCore as BaseAeadCore;
-    use generic_array::GenericArray;
+    /// Does `open_in_place_buffer` on a `bytes::BytesMut`. This is gated under the `bytes`
+    /// feature.
+    ///
+    /// Return Value
+    /// ============
+    /// Same as `open_in_place_buffer`.
+    #[cfg(feature = "bytes")]
+    pub fn open_in_place_bytes(
+        &mut self,
+        buf: &mut bytes::BytesMut,
+        aad: &[u8],
+    ) -> Result<(), HpkeError> {
+        self.open_in_place_buffer(&mut BytesMutBuffer(buf), aad)
+    }
 
-    /// Tests that AeadKey::from_bytes fails on inputs of incorrect length
-    macro_rules! test_invalid_nonce {
-        ($test_name:ident, $aead_ty:ty) => {
-            #[test]
-            fn $test_name() {
-                type A = $aead_ty;
+    /// Opens a fixed-size ciphertext sealed with `AeadCtxS::seal_fixed`, returning the plaintext in
+    /// a stack array. Nothing is allocated.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(plaintext)` on success. Errors are the same as in `open`.
+    pub fn open_fixed<const N: usize>(
+        &mut self,
+        ciphertext: &[u8; N],
+        aad: &[u8],
+        tag: &AeadTag<A>,
+    ) -> Result<[u8; N], HpkeError> {
+        let mut plaintext = *ciphertext;
+        if let Err(e) = self.open(&mut plaintext, aad, tag) {
+            // Don't leave unauthenticated data lying around
+            plaintext.zeroize();
+            return Err(e);
+        }
 
-                // No AEAD tag is 5 bytes long. This should give an IncorrectInputLength error
-                let tag_res = AeadTag::<A>::from_bytes(&[0; 5]);
-                if let Err(e) = tag_res {
-                    assert_eq!(e, HpkeError::IncorrectInputLength(AeadTag::<A>::size(), 5));
-                } else {
-                    panic!("AeadTag was unexpectedly valid");
-                }
-            }
-        };
+        Ok(plaintext)
     }
 
-    /// Tests that encryption context secret export does not change behavior based on the
-    /// underlying sequence number This logic is cipher-agnostic, so we don't make the test generic
-    /// over ciphers.
-    macro_rules! test_export_idempotence {
+    /// Does `open`, where the AAD is computed by `aad_fn` from the sequence number that this
+    /// message is opened with. This is useful for binding the AAD to the exact position of the
+    /// message in the session, without tracking the sequence number separately.
+    ///
+    /// Return Value
+    /// ============
+    /// Same as `open`. If `A` is `ExportOnlyAead`, or this context has been used for so many
+    /// encryptions that the sequence number overflowed, `aad_fn` is not called.
+    #[cfg(feature = "alloc")]
+    pub fn open_with_aad_fn<F: FnOnce(u64) -> Vec<u8>>(
+        &mut self,
+        ciphertext: &mut [u8],
+        aad_fn: F,
+        tag: &AeadTag<A>,
+    ) -> Result<(), HpkeError> {
+        if A::AEAD_ID == ExportOnlyAead::AEAD_ID {
+            return Err(HpkeError::ExportOnly);
+        } else if self.0.overflowed {
+            return Err(HpkeError::MessageLimitReached);
+        }
+
+        let aad = aad_fn(self.0.seq.0);
+        self.open(ciphertext, &aad, tag)
+    }
+
+    /// Opens a message sealed with `AeadCtxS::seal_padded`, and strips its padding
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(plaintext)` on success. Errors are the same as in `open`. If the message was
+    /// authentic but its padding is malformed, returns `Err(HpkeError::InvalidPadding)`. The
+    /// sequence number is incremented in this case, since the message was still opened.
+    #[cfg(feature = "alloc")]
+    pub fn open_padded(
+        &mut self,
+        ciphertext: &[u8],
+        aad: &[u8],
+        tag: &AeadTag<A>,
+    ) -> Result<Vec<u8>, HpkeError> {
+        let mut buf = ciphertext.to_vec();
+        self.open(&mut buf, aad, tag)?;
+        if let Err(e) = unpad(&mut buf) {
+            // Don't leave the plaintext lying around in freed memory
+            buf.zeroize();
+            return Err(e);
+        }
+        Ok(buf)
+    }
+
+    /// Opens a message sealed with `AeadCtxS::seal_salted`. `ciphertext` is the ciphertext
+    /// followed by the tag, and `salt` is the salt that was sent with it. This is not standard
+    /// HPKE. See `seal_salted` for details.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(plaintext)` on success. If `ciphertext` is shorter than a tag, returns
+    /// `Err(HpkeError::OpenError)`. Otherwise, errors are the same as in `open`.
+    #[cfg(feature = "alloc")]
+    pub fn open_salted(
+        &mut self,
+        salt: &Salt,
+        ciphertext: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, HpkeError> {
+        let tag_len = AeadTag::<A>::size();
+        if ciphertext.len() < tag_len {
+            return Err(HpkeError::OpenError);
+        }
+        let (ct, tag_bytes) = ciphertext.split_at(ciphertext.len() - tag_len);
+        let tag = AeadTag::<A>::from_bytes(tag_bytes)?;
+
+        let mut buf = ct.to_vec();
+        if let Err(e) = self.open_inner(&mut buf, aad, &tag, Some(salt)) {
+            // Don't hand back a half-decrypted buffer
+            buf.zeroize();
+            return Err(e);
+        }
+        Ok(buf)
+    }
+
+    /// Opens a message sealed with `AeadCtxS::seal_timestamped`, and checks that it's fresh.
+    /// `buf` is the timestamp, ciphertext, and tag, as output by `seal_timestamped`, and `now` is
+    /// the current time, in the same units as the sender's. The message is fresh if
+    /// `now - timestamp <= max_age`. A timestamp from the future, e.g., because of clock skew, has
+    /// age 0. This only limits how old a message can be. It doesn't stop a fresh message from
+    /// being replayed into a different context.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok((timestamp, plaintext))` on success. If `buf` is shorter than a timestamp and a
+    /// tag, returns `Err(HpkeError::OpenError)`. If the message is authentic but not fresh,
+    /// returns `Err(HpkeError::StaleMessage)`. The sequence number is incremented in this case,
+    /// since the message was still opened. Otherwise, errors are the same as in `open`.
+    #[cfg(feature = "alloc")]
+    pub fn open_timestamped(
+        &mut self,
+        buf: &[u8],
+        aad: &[u8],
+        now: u64,
+        max_age: u64,
+    ) -> Result<(u64, Vec<u8>), HpkeError> {
+        let tag_len = AeadTag::<A>::size();
+        if buf.len() < TIMESTAMP_LEN + tag_len {
+            return Err(HpkeError::OpenError);
+        }
+        let (timestamp_bytes, rest) = buf.split_at(TIMESTAMP_LEN);
+        let (ct, tag_bytes) = rest.split_at(rest.len() - tag_len);
+        let tag = AeadTag::<A>::from_bytes(tag_bytes)?;
+
+        let mut plaintext = ct.to_vec();
+        self.open(&mut plaintext, &timestamped_aad(timestamp_bytes, aad), &tag)?;
+
+        // Only trust the timestamp once it's been authenticated
+        let timestamp = BigEndian::read_u64(timestamp_bytes);
+        if now.saturating_sub(timestamp) > max_age {
+            plaintext.zeroize();
+            return Err(HpkeError::StaleMessage);
+        }
+        Ok((timestamp, plaintext))
+    }
+
+    /// Opens a message sealed with `AeadCtxS::seal_binding_enc`. The AAD is the encapsulated key
+    /// this context was set up with, followed by `extra_aad`, so the message only opens if the
+    /// sender's context was set up with the same encapsulated key.
+    ///
+    /// Return Value
+    /// ============
+    /// Same as `open`. If this context wasn't set up from an encapsulated key, returns
+    /// `Err(HpkeError::NoEncappedKey)`, and `ciphertext` will be unmodified.
+    #[cfg(feature = "alloc")]
+    pub fn open_binding_enc(
+        &mut self,
+        ciphertext: &mut [u8],
+        extra_aad: &[u8],
+        tag: &AeadTag<A>,
+    ) -> Result<(), HpkeError> {
+        let aad = self.0.enc_aad(extra_aad)?;
+        self.open(ciphertext, &aad, tag)
+    }
+
+    /// Derives an HMAC key from this context, for computing an HMAC that is independent of the
+    /// AEAD. This is like `export`, but with a different label, so the key never coincides with
+    /// an exported secret. It also never coincides with the AEAD key. Different `label`s give
+    /// independent keys. This value is constant for the lifetime of this context, and it equals
+    /// the sender's `AeadCtxS::derive_hmac_key(label)`.
+    pub fn derive_hmac_key(&self, label: &[u8]) -> HmacOutput<Kdf> {
+        self.0.hmac_key(label)
+    }
+
+    /// Opens a message sealed with `AeadCtxS::seal_with_hmac`, and checks the HMAC over the
+    /// plaintext in constant time
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. Errors are the same as in `open`. If the message was authentic
+    /// but the HMAC doesn't match, returns `Err(HpkeError::OpenError)`, and `ciphertext` is zeroed.
+    /// The sequence number is incremented in this case, since the message was still opened.
+    pub fn open_with_hmac(
+        &mut self,
+        ciphertext: &mut [u8],
+        aad: &[u8],
+        tag: &AeadTag<A>,
+        label: &[u8],
+        hmac_tag: &HmacOutput<Kdf>,
+    ) -> Result<(), HpkeError> {
+        self.open(ciphertext, aad, tag)?;
+
+        let mut mac = self.0.keyed_hmac(label);
+        mac.update(ciphertext);
+
+        // Don't hand out plaintext whose HMAC didn't check out
+        mac.verify(hmac_tag).map_err(|_| {
+            ciphertext.zeroize();
+            HpkeError::OpenError
+        })
+    }
+
+    /// Fills a given buffer with secret bytes derived from this encryption context. This value
+    /// does not depend on sequence number, so it is constant for the lifetime of this context.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If the buffer length is more than about 255x the digest size
+    /// of the underlying hash function, returns an `Err(HpkeError::KdfOutputTooLong)`. The exact
+    /// number is given in the "Input Length Restrictions" section of the spec. Just don't use to
+    /// fill massive buffers and you'll be fine.
+    pub fn export(&self, info: &[u8], out_buf: &mut [u8]) -> Result<(), HpkeError> {
+        // Pass to AeadCtx
+        self.0.export(info, out_buf)
+    }
+
+    /// Exports a secret tied to this context's current sequence number, i.e., to the position of
+    /// the message it opens next. A sender at the same position derives the same secret. This is
+    /// for deriving per-message auxiliary keys. The secret is expanded from the exporter secret
+    /// with its own label, so it never equals an `export` output.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok((seq, secret))` on success, where `seq` is the sequence number that was used and
+    /// `secret` is `len` bytes long. If `len` is more than 255x the digest size of the underlying
+    /// hash function, returns `Err(HpkeError::KdfOutputTooLong)`.
+    #[cfg(feature = "alloc")]
+    pub fn export_at_seq(&self, label: &[u8], len: usize) -> Result<(u64, Vec<u8>), HpkeError> {
+        self.0.export_at_seq(label, len)
+    }
+
+    /// Exports a secret that depends on `salt` as well as this context. Where `export` only does
+    /// HKDF-Expand on the exporter secret, this first runs a fresh `LabeledExtract` with `salt`, so
+    /// the caller can mix in, e.g., a nonce for freshness. Both sides get the same secret for the
+    /// same `salt` and `label`. This is an extension layered on top of HPKE's exporter, and not
+    /// part of the RFC. The output never coincides with an `export` output.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(secret)` on success, where `secret` is `len` bytes long. If `len` is more than
+    /// 255x the digest size of the underlying hash function, returns
+    /// `Err(HpkeError::KdfOutputTooLong)`.
+    #[cfg(feature = "alloc")]
+    pub fn export_with_salt(
+        &self,
+        salt: &[u8],
+        label: &[u8],
+        len: usize,
+    ) -> Result<Vec<u8>, HpkeError> {
+        self.0.export_with_salt(salt, label, len)
+    }
+
+    /// Returns the `(KEM ID, KDF ID, AEAD ID)` of the ciphersuite this context belongs to
+    pub fn suite_ids(&self) -> (u16, u16, u16) {
+        self.0.suite_ids()
+    }
+
+    /// Returns how many messages this context has sealed and opened, and how many secrets it has
+    /// exported. This is for metrics. Reading it doesn't affect the context.
+    pub fn stats(&self) -> ContextStats {
+        self.0.stats()
+    }
+
+    /// Returns the sender identity key that this context was set up to authenticate, i.e., the
+    /// key that was given in `OpModeR::Auth` or `OpModeR::AuthPsk`. This is for audit logging.
+    /// Returns `None` if this context was set up in `Base` or `Psk` mode.
+    pub fn sender_public_key(&self) -> Option<&<Kem::Kex as KeyExchange>::PublicKey> {
+        self.0.pk_sender_id.as_ref()
+    }
+
+    /// Wipes this context's AEAD key, base nonce, and exporter secret, and consumes it. This is
+    /// for ending a session without waiting for the context to be dropped, and since the context
+    /// is moved, using it afterwards doesn't compile.
+    ///
+    /// The base nonce and exporter secret are overwritten in place, and the memory of the AEAD
+    /// instance is zeroed after it's dropped, which wipes the AES-GCM round keys too. Copies that
+    /// the compiler made while moving the context around can't be reached, and aren't wiped.
+    pub fn close(mut self) {
+        self.0.wipe();
+    }
+
+    /// Returns a 32-byte fingerprint of this context's key schedule. The sender's and receiver's
+    /// fingerprints are equal iff they derived the same secrets, so peers can compare them to
+    /// check that they're linked. The fingerprint reveals nothing about the keys, and is constant
+    /// for the lifetime of this context.
+    pub fn context_fingerprint(&self) -> [u8; 32] {
+        self.0.fingerprint()
+    }
+
+    /// Returns a 16-byte ID for the message at sequence number `seq`. The sender and receiver
+    /// compute the same ID for the same `seq` iff their contexts are linked, so it can be used to
+    /// deduplicate retried messages. The ID reveals nothing about the keys. It doesn't depend on
+    /// the current sequence number, or on the message itself.
+    pub fn message_id(&self, seq: u64) -> [u8; 16] {
+        self.0.message_id(seq)
+    }
+
+    /// Computes a key-confirmation tag over `transcript`. After setup, each side can send the
+    /// other its tag, to confirm that they derived the same secrets before anything else is sent.
+    /// The tag equals the sender's `confirmation_tag(transcript)` iff the two contexts are
+    /// linked. Since both sides compute the same tag for the same transcript, each direction
+    /// should use its own transcript, e.g., one that starts with the role of the side sending the
+    /// tag. Otherwise, a tag can be reflected back to its sender.
+    pub fn confirmation_tag(&self, transcript: &[u8]) -> [u8; 32] {
+        self.0.confirmation_tag(transcript)
+    }
+
+    /// Checks, in constant time, a confirmation tag that the peer computed with
+    /// `AeadCtxS::confirmation_tag`
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` if `tag` is `confirmation_tag(transcript)`. Otherwise, returns
+    /// `Err(HpkeError::ValidationError)`.
+    pub fn verify_confirmation(&self, transcript: &[u8], tag: &[u8; 32]) -> Result<(), HpkeError> {
+        self.0.verify_confirmation(transcript, tag)
+    }
+
+    /// Replaces this context with one derived from the fresh encapsulation `encapped_key`, which
+    /// the sender produced with `AeadCtxS::reencapsulate`. The new context is bound to this one,
+    /// so it inherits its mode, PSK, sender identity, and info string. It also keeps this
+    /// context's `sender_public_key()` and the window set by `with_seq_window`. This resets the
+    /// sequence counter to 0.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If an error happened during key decapsulation, returns
+    /// `Err(HpkeError::DecapError)`. If this happens, the context is unmodified.
+    pub fn reencapsulate(
+        &mut self,
+        sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
+        encapped_key: &EncappedKey<Kem::Kex>,
+    ) -> Result<(), HpkeError> {
+        // Decap the new shared secret
+        let shared_secret = kem::decap::<Kem>(sk_recip, None, encapped_key)?;
+
+        // Bind the new context to this one by using this context's reencap PSK
+        let psk = self.0.reencap_psk();
+        let mode = OpModeR::Psk(PskBundle {
+            psk: psk.0.as_slice(),
+            psk_id: REENCAP_LABEL,
+        });
+        let labels = self.0.labels;
+        let pk_sender_id = self.0.pk_sender_id.take();
+        let seq_window = self.0.seq_window;
+        self.0 = derive_enc_ctx_with_labels::<A, Kdf, Kem, _>(&mode, shared_secret, &[], &labels);
+        self.0.bind_enc(encapped_key);
+        self.0.pk_sender_id = pk_sender_id;
+        self.0.seq_window = seq_window;
+
+        Ok(())
+    }
+}
+
+/// The HPKE senders's context. This is what you use to `seal` plaintexts and `export` secrets.
+pub struct AeadCtxS<A: Aead, Kdf: KdfTrait, Kem: KemTrait>(AeadCtx<A, Kdf, Kem>);
+
+// AeadCtx -> AeadCtxS via wrapping
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> From<AeadCtx<A, Kdf, Kem>> for AeadCtxS<A, Kdf, Kem> {
+    fn from(ctx: AeadCtx<A, Kdf, Kem>) -> AeadCtxS<A, Kdf, Kem> {
+        AeadCtxS(ctx)
+    }
+}
+
+// Necessary for test_setup_soundness
+#[cfg(test)]
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> Clone for AeadCtxS<A, Kdf, Kem> {
+    fn clone(&self) -> AeadCtxS<A, Kdf, Kem> {
+        self.0.clone().into()
+    }
+}
+
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AeadCtxS<A, Kdf, Kem> {
+    // draft11 §5.2
+    // def ContextS.Seal(aad, pt):
+    //   ct = Seal(self.key, self.ComputeNonce(self.seq), aad, pt)
+    //   self.IncrementSeq()
+    //   return ct
+
+    /// Does a "detached seal in place", meaning it overwrites `plaintext` with the resulting
+    /// ciphertext, and returns the resulting authentication tag
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(tag)` on success.  If this context has been used for so many encryptions that
+    /// the sequence number overflowed, returns `Err(HpkeError::MessageLimitReached)`. If this
+    /// happens, `plaintext` will be unmodified. If this context has reached the limit set by
+    /// `with_seal_limit`, returns `Err(HpkeError::PolicyLimitReached)`, and `plaintext` will be
+    /// unmodified. If an error happened during encryption, returns
+    /// `Err(HpkeError::SealError)`. If this happens, the contents of `plaintext` is undefined, but
+    /// the sequence number is unchanged, so a retry uses the same nonce. If `A` is
+    /// `ExportOnlyAead`, returns `Err(HpkeError::ExportOnly)`, and `plaintext` will be unmodified.
+    pub fn seal(&mut self, plaintext: &mut [u8], aad: &[u8]) -> Result<AeadTag<A>, HpkeError> {
+        self.seal_inner(plaintext, aad, None)
+    }
+
+    // The body of seal and seal_salted
+    fn seal_inner(
+        &mut self,
+        plaintext: &mut [u8],
+        aad: &[u8],
+        salt: Option<&Salt>,
+    ) -> Result<AeadTag<A>, HpkeError> {
+        if A::AEAD_ID == ExportOnlyAead::AEAD_ID {
+            // There's nothing to encrypt with
+            Err(HpkeError::ExportOnly)
+        } else if self.0.overflowed {
+            // If the sequence counter overflowed, we've been used for far too long. Shut down.
+            Err(HpkeError::MessageLimitReached)
+        } else if matches!(self.0.seal_limit, Some(limit) if self.0.seals >= limit) {
+            // This isn't a cryptographic limit, but the caller asked us to stop here
+            Err(HpkeError::PolicyLimitReached)
+        } else {
+            // Compute the nonce and do the encryption in place
+            let nonce = self.0.current_nonce(salt);
+            let tag = self
+                .0
+                .encryptor
+                .encrypt_in_place_detached(&nonce.0, aad, plaintext)
+                .map_err(|_| HpkeError::SealError)?;
+            #[cfg(feature = "test-internals")]
+            {
+                if self.0.nonce_log.len() == NONCE_LOG_LEN {
+                    self.0.nonce_log.pop_front();
+                }
+                self.0.nonce_log.push_back(nonce.0.clone());
+            }
+            self.0.seals += 1;
+            self.0.bytes_sealed = self.0.bytes_sealed.saturating_add(plaintext.len() as u64);
+            trace::seal(self.0.suite_ids());
+
+            // Nothing before this point touches the sequence counter, so a failed seal can be
+            // retried with the same nonce. Try to increment the sequence counter. If it fails, this
+            // was our last encryption.
+            match increment_seq(&self.0.seq) {
+                Some(new_seq) => self.0.seq = new_seq,
+                None => {
+                    trace::seq_overflow("sender", self.0.suite_ids(), self.0.seq.0);
+                    self.0.overflowed = true;
+                }
+            }
+
+            // Return the tag
+            Ok(AeadTag(tag))
+        }
+    }
+
+    /// Caps the number of messages this context will seal at `n`. This is for enforcing a
+    /// rotation policy, and is separate from the sequence number limit of the AEAD. Messages this
+    /// context already sealed count towards `n`. Once the limit is reached, every `seal` method
+    /// returns `Err(HpkeError::PolicyLimitReached)`, and a fresh context has to be set up, either
+    /// from scratch or with `reencapsulate`. Exports keep working.
+    pub fn with_seal_limit(mut self, n: u64) -> AeadCtxS<A, Kdf, Kem> {
+        self.0.seal_limit = Some(n);
+        self
+    }
+
+    /// Seals `plaintext` and writes the ciphertext, followed by the tag, to the start of `out`.
+    /// This is for when the plaintext and ciphertext are in separate buffers. `out` must be at
+    /// least `plaintext.len() + AeadTag::<A>::size()` bytes long.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(n)` on success, where `n` is the number of bytes written. If `out` is too short,
+    /// returns `Err(HpkeError::IncorrectInputLength { expected: required_len, got: out.len() })`.
+    /// If this happens, neither `out` nor the sequence number is modified. Otherwise, errors are
+    /// the same as in `seal`.
+    pub fn seal_to_slice(
+        &mut self,
+        plaintext: &[u8],
+        aad: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, HpkeError> {
+        let tag_len = AeadTag::<A>::size();
+        let required_len = plaintext.len() + tag_len;
+        if out.len() < required_len {
+            return Err(HpkeError::IncorrectInputLength {
+                expected: required_len,
+                got: out.len(),
+            });
+        }
+
+        let (ct_out, tag_out) = out[..required_len].split_at_mut(plaintext.len());
+        ct_out.copy_from_slice(plaintext);
+        let tag = self.seal(ct_out, aad)?;
+        tag_out.copy_from_slice(&tag.to_bytes());
+
+        Ok(required_len)
+    }
+
+    /// Seals the plaintext in `buf` in place, and appends the tag to it. This works with any
+    /// `aead::Buffer`, so a growable buffer can be sealed without copying. For `bytes::BytesMut`,
+    /// use `seal_in_place_bytes`. Open with `AeadCtxR::open_in_place_buffer`.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success, and `buf` then holds `ciphertext || tag`. If `buf` can't be
+    /// extended by a tag, returns `Err(HpkeError::SealError)`. In this case, neither `buf` nor the
+    /// sequence number is modified. Otherwise, errors are the same as in `seal`, and `buf` is
+    /// truncated back to its original length.
+    pub fn seal_in_place_buffer<B: aead::Buffer>(
+        &mut self,
+        buf: &mut B,
+        aad: &[u8],
+    ) -> Result<(), HpkeError> {
+        let pt_len = buf.len();
+        // Make room for the tag first, so that a buffer which can't grow fails before anything is
+        // encrypted
+        let blank_tag = GenericArray::<u8, <A::AeadImpl as BaseAeadCore>::TagSize>::default();
+        buf.extend_from_slice(&blank_tag)
+            .map_err(|_| HpkeError::SealError)?;
+
+        let (plaintext, tag_out) = buf.as_mut().split_at_mut(pt_len);
+        match self.seal(plaintext, aad) {
+            Ok(tag) => {
+                tag_out.copy_from_slice(&tag.to_bytes());
+                Ok(())
+            }
+            Err(e) => {
+                buf.truncate(pt_len);
+                Err(e)
+            }
+        }
+    }
+
+    /// Does `seal_in_place_buffer` on a `bytes::BytesMut`, which grows to fit the tag. Open with
+    /// `AeadCtxR::open_in_place_bytes`. This is gated under the `bytes` feature.
+    ///
+    /// Return Value
+    /// ============
+    /// Same as `seal_in_place_buffer`.
+    #[cfg(feature = "bytes")]
+    pub fn seal_in_place_bytes(
+        &mut self,
+        buf: &mut bytes::BytesMut,
+        aad: &[u8],
+    ) -> Result<(), HpkeError> {
+        self.seal_in_place_buffer(&mut BytesMutBuffer(buf), aad)
+    }
+
+    /// Seals a fixed-size plaintext, returning the ciphertext in a stack array along with the tag.
+    /// Nothing is allocated, and `plaintext` is left as is. Open with `AeadCtxR::open_fixed`.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok((ciphertext, tag))` on success. Errors are the same as in `seal`.
+    pub fn seal_fixed<const N: usize>(
+        &mut self,
+        plaintext: &[u8; N],
+        aad: &[u8],
+    ) -> Result<([u8; N], AeadTag<A>), HpkeError> {
+        let mut ciphertext = *plaintext;
+        let tag = self.seal(&mut ciphertext, aad)?;
+        Ok((ciphertext, tag))
+    }
+
+    /// Does `seal`, where the AAD is computed by `aad_fn` from the sequence number that this
+    /// message is sealed with. This is the sender's side of `AeadCtxR::open_with_aad_fn`.
+    ///
+    /// Return Value
+    /// ============
+    /// Same as `seal`. If `A` is `ExportOnlyAead`, or this context has been used for so many
+    /// encryptions that the sequence number overflowed, `aad_fn` is not called.
+    #[cfg(feature = "alloc")]
+    pub fn seal_with_aad_fn<F: FnOnce(u64) -> Vec<u8>>(
+        &mut self,
+        plaintext: &mut [u8],
+        aad_fn: F,
+    ) -> Result<AeadTag<A>, HpkeError> {
+        if A::AEAD_ID == ExportOnlyAead::AEAD_ID {
+            return Err(HpkeError::ExportOnly);
+        } else if self.0.overflowed {
+            return Err(HpkeError::MessageLimitReached);
+        }
+
+        let aad = aad_fn(self.0.seq.0);
+        self.seal(plaintext, &aad)
+    }
+
+    /// Pads `plaintext` to a multiple of `block_size` bytes and seals it, so that the ciphertext
+    /// length only reveals the plaintext length rounded up to a block. The padding is a `0x80`
+    /// byte followed by as many `0x00` bytes as are needed to fill the block (ISO/IEC 7816-4). It
+    /// is encrypted along with the plaintext, so it is authenticated. A plaintext that is already
+    /// a multiple of `block_size` bytes gets a whole block of padding. Open with
+    /// `AeadCtxR::open_padded`.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok((ciphertext, tag))` on success. If `block_size` is 0, or the padded length
+    /// doesn't fit in a `usize`, returns `Err(HpkeError::InvalidPadding)`. Otherwise, errors are
+    /// the same as in `seal`.
+    #[cfg(feature = "alloc")]
+    pub fn seal_padded(
+        &mut self,
+        plaintext: &[u8],
+        aad: &[u8],
+        block_size: usize,
+    ) -> Result<(Vec<u8>, AeadTag<A>), HpkeError> {
+        if block_size == 0 {
+            return Err(HpkeError::InvalidPadding);
+        }
+
+        // Always add at least the 0x80 byte, then fill up the rest of the block
+        let padded_len = (plaintext.len() / block_size + 1)
+            .checked_mul(block_size)
+            .ok_or(HpkeError::InvalidPadding)?;
+        let mut buf = Vec::with_capacity(padded_len);
+        buf.extend_from_slice(plaintext);
+        buf.push(0x80);
+        buf.resize(padded_len, 0x00);
+
+        let tag = self.seal(&mut buf, aad)?;
+        Ok((buf, tag))
+    }
+
+    /// Seals `plaintext` with a nonce that also depends on a fresh random salt. The nonce is
+    /// `base_nonce XOR seq XOR mask`, where the mask is derived from the exporter secret and the
+    /// salt. The salt is not secret, and must be sent along with the ciphertext. Open with
+    /// `AeadCtxR::open_salted`.
+    ///
+    /// This is for deployments that can't rule out the sequence counter being reset, e.g., by
+    /// restoring a context from a stale snapshot. A counter reset makes `seal` reuse nonces,
+    /// which is catastrophic. With random salts, two messages sealed at the same sequence number
+    /// still get different nonces, unless their salts collide. The sequence number advances as
+    /// usual, so a receiver still has to open messages in order.
+    ///
+    /// **This is not standard HPKE.** Salted messages can only be opened with `open_salted`, and
+    /// no other HPKE implementation can open them.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok((salt, ciphertext))` on success, where `ciphertext` is the ciphertext followed
+    /// by the tag. Errors are the same as in `seal`.
+    #[cfg(feature = "alloc")]
+    pub fn seal_salted<R: CryptoRng + RngCore>(
+        &mut self,
+        plaintext: &[u8],
+        aad: &[u8],
+        csprng: &mut R,
+    ) -> Result<(Salt, Vec<u8>), HpkeError> {
+        let mut salt = [0u8; SALT_LEN];
+        csprng.fill_bytes(&mut salt);
+
+        let mut buf = plaintext.to_vec();
+        let tag = self.seal_inner(&mut buf, aad, Some(&salt))?;
+        buf.extend_from_slice(&tag.0);
+        Ok((salt, buf))
+    }
+
+    /// Seals `plaintext` along with the timestamp `now`, so that the receiver can reject stale
+    /// messages with `AeadCtxR::open_timestamped`. The timestamp is authenticated as part of the
+    /// AAD, so it can't be altered. The units of `now` are up to the application, e.g., seconds
+    /// since the Unix epoch, but both sides must agree on them.
+    ///
+    /// **This is not standard HPKE.** Timestamped messages can only be opened with
+    /// `open_timestamped`.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(buf)` on success, where `buf` is `I2OSP(now, 8) || ciphertext || tag`. Errors
+    /// are the same as in `seal`.
+    #[cfg(feature = "alloc")]
+    pub fn seal_timestamped(
+        &mut self,
+        plaintext: &[u8],
+        aad: &[u8],
+        now: u64,
+    ) -> Result<Vec<u8>, HpkeError> {
+        let timestamp_bytes = now.to_be_bytes();
+        let mut buf = Vec::with_capacity(TIMESTAMP_LEN + plaintext.len() + AeadTag::<A>::size());
+        buf.extend_from_slice(&timestamp_bytes);
+        buf.extend_from_slice(plaintext);
+        let tag = self.seal(
+            &mut buf[TIMESTAMP_LEN..],
+            &timestamped_aad(&timestamp_bytes, aad),
+        )?;
+        buf.extend_from_slice(&tag.0);
+        Ok(buf)
+    }
+
+    /// Seals `plaintext` with the encapsulated key this context was set up with, followed by
+    /// `extra_aad`, as the AAD. This authenticates the encapsulated key, so it can't be swapped in
+    /// transit. Open with `AeadCtxR::open_binding_enc`.
+    ///
+    /// Return Value
+    /// ============
+    /// Same as `seal`. If this context wasn't set up from an encapsulated key, returns
+    /// `Err(HpkeError::NoEncappedKey)`, and `plaintext` will be unmodified.
+    #[cfg(feature = "alloc")]
+    pub fn seal_binding_enc(
+        &mut self,
+        plaintext: &mut [u8],
+        extra_aad: &[u8],
+    ) -> Result<AeadTag<A>, HpkeError> {
+        let aad = self.0.enc_aad(extra_aad)?;
+        self.seal(plaintext, &aad)
+    }
+
+    /// Derives an HMAC key from this context, for computing an HMAC that is independent of the
+    /// AEAD. This is like `export`, but with a different label, so the key never coincides with
+    /// an exported secret. It also never coincides with the AEAD key. Different `label`s give
+    /// independent keys. This value is constant for the lifetime of this context, and it equals
+    /// the receiver's `AeadCtxR::derive_hmac_key(label)`.
+    pub fn derive_hmac_key(&self, label: &[u8]) -> HmacOutput<Kdf> {
+        self.0.hmac_key(label)
+    }
+
+    /// Computes an HMAC over `plaintext` with `derive_hmac_key(label)`, then seals `plaintext`.
+    /// Open with `AeadCtxR::open_with_hmac`.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok((tag, hmac_tag))` on success. Errors are the same as in `seal`.
+    pub fn seal_with_hmac(
+        &mut self,
+        plaintext: &mut [u8],
+        aad: &[u8],
+        label: &[u8],
+    ) -> Result<(AeadTag<A>, HmacOutput<Kdf>), HpkeError> {
+        // The HMAC is over the plaintext, so compute it before it gets encrypted in place
+        let mut mac = self.0.keyed_hmac(label);
+        mac.update(plaintext);
+        let hmac_tag = mac.finalize().into_bytes();
+        let tag = self.seal(plaintext, aad)?;
+        Ok((tag, hmac_tag))
+    }
+
+    /// Fills a given buffer with secret bytes derived from this encryption context. This value
+    /// does not depend on sequence number, so it is constant for the lifetime of this context.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If the buffer length is more than 255x the digest size of the
+    /// underlying hash function, returns an `Err(HpkeError::KdfOutputTooLong)`. Just don't use to
+    /// fill massive buffers and you'll be fine.
+    pub fn export(&self, info: &[u8], out_buf: &mut [u8]) -> Result<(), HpkeError> {
+        // Pass to AeadCtx
+        self.0.export(info, out_buf)
+    }
+
+    /// Exports a secret tied to this context's current sequence number, i.e., to the position of
+    /// the message it seals next. A receiver at the same position derives the same secret. This is
+    /// for deriving per-message auxiliary keys. The secret is expanded from the exporter secret
+    /// with its own label, so it never equals an `export` output.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok((seq, secret))` on success, where `seq` is the sequence number that was used and
+    /// `secret` is `len` bytes long. If `len` is more than 255x the digest size of the underlying
+    /// hash function, returns `Err(HpkeError::KdfOutputTooLong)`.
+    #[cfg(feature = "alloc")]
+    pub fn export_at_seq(&self, label: &[u8], len: usize) -> Result<(u64, Vec<u8>), HpkeError> {
+        self.0.export_at_seq(label, len)
+    }
+
+    /// Exports a secret that depends on `salt` as well as this context. Where `export` only does
+    /// HKDF-Expand on the exporter secret, this first runs a fresh `LabeledExtract` with `salt`, so
+    /// the caller can mix in, e.g., a nonce for freshness. Both sides get the same secret for the
+    /// same `salt` and `label`. This is an extension layered on top of HPKE's exporter, and not
+    /// part of the RFC. The output never coincides with an `export` output.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(secret)` on success, where `secret` is `len` bytes long. If `len` is more than
+    /// 255x the digest size of the underlying hash function, returns
+    /// `Err(HpkeError::KdfOutputTooLong)`.
+    #[cfg(feature = "alloc")]
+    pub fn export_with_salt(
+        &self,
+        salt: &[u8],
+        label: &[u8],
+        len: usize,
+    ) -> Result<Vec<u8>, HpkeError> {
+        self.0.export_with_salt(salt, label, len)
+    }
+
+    /// Returns the `(KEM ID, KDF ID, AEAD ID)` of the ciphersuite this context belongs to
+    pub fn suite_ids(&self) -> (u16, u16, u16) {
+        self.0.suite_ids()
+    }
+
+    /// Returns how many messages this context has sealed and opened, and how many secrets it has
+    /// exported. This is for metrics. Reading it doesn't affect the context.
+    pub fn stats(&self) -> ContextStats {
+        self.0.stats()
+    }
+
+    /// Returns the total number of plaintext bytes this context has successfully sealed. This
+    /// counts what was passed to the AEAD, so it includes the padding that `seal_padded` adds. It
+    /// doesn't include anything a `seal_*` variant sends in the clear or only authenticates, like
+    /// the salt of `seal_salted` or the timestamp of `seal_timestamped`.
+    pub fn bytes_sealed(&self) -> u64 {
+        self.0.bytes_sealed
+    }
+
+    /// Returns whether this context has sealed at least `byte_limit` bytes of plaintext. AEAD
+    /// security bounds depend on the total amount of data encrypted under one key, not just on the
+    /// number of messages, so a sender can use this to set up a fresh context before reaching the
+    /// data limit of its AEAD.
+    pub fn should_rekey(&self, byte_limit: u64) -> bool {
+        self.0.bytes_sealed >= byte_limit
+    }
+
+    /// Wipes this context's AEAD key, base nonce, and exporter secret, and consumes it. This is
+    /// for ending a session without waiting for the context to be dropped, and since the context
+    /// is moved, using it afterwards doesn't compile.
+    ///
+    /// The base nonce and exporter secret are overwritten in place, and the memory of the AEAD
+    /// instance is zeroed after it's dropped, which wipes the AES-GCM round keys too. Copies that
+    /// the compiler made while moving the context around can't be reached, and aren't wiped.
+    pub fn close(mut self) {
+        self.0.wipe();
+    }
+
+    /// Returns a 32-byte fingerprint of this context's key schedule. The sender's and receiver's
+    /// fingerprints are equal iff they derived the same secrets, so peers can compare them to
+    /// check that they're linked. The fingerprint reveals nothing about the keys, and is constant
+    /// for the lifetime of this context.
+    pub fn context_fingerprint(&self) -> [u8; 32] {
+        self.0.fingerprint()
+    }
+
+    /// Returns a 16-byte ID for the message at sequence number `seq`. The sender and receiver
+    /// compute the same ID for the same `seq` iff their contexts are linked, so it can be used to
+    /// deduplicate retried messages. The ID reveals nothing about the keys. It doesn't depend on
+    /// the current sequence number, or on the message itself.
+    pub fn message_id(&self, seq: u64) -> [u8; 16] {
+        self.0.message_id(seq)
+    }
+
+    /// Computes a key-confirmation tag over `transcript`. After setup, each side can send the
+    /// other its tag, to confirm that they derived the same secrets before anything else is sent.
+    /// The tag equals the receiver's `confirmation_tag(transcript)` iff the two contexts are
+    /// linked. Since both sides compute the same tag for the same transcript, each direction
+    /// should use its own transcript, e.g., one that starts with the role of the side sending the
+    /// tag. Otherwise, a tag can be reflected back to its sender.
+    pub fn confirmation_tag(&self, transcript: &[u8]) -> [u8; 32] {
+        self.0.confirmation_tag(transcript)
+    }
+
+    /// Checks, in constant time, a confirmation tag that the peer computed with
+    /// `AeadCtxR::confirmation_tag`
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` if `tag` is `confirmation_tag(transcript)`. Otherwise, returns
+    /// `Err(HpkeError::ValidationError)`.
+    pub fn verify_confirmation(&self, transcript: &[u8], tag: &[u8; 32]) -> Result<(), HpkeError> {
+        self.0.verify_confirmation(transcript, tag)
+    }
+
+    /// Returns the last 4096 nonces that `seal` has used on this context, in order. This is only
+    /// for testing that nonces are never reused.
+    #[cfg(feature = "test-internals")]
+    #[doc(hidden)]
+    pub fn nonce_log(&self) -> Vec<GenericArray<u8, <A::AeadImpl as BaseAeadCore>::NonceSize>> {
+        self.0.nonce_log.iter().cloned().collect()
+    }
+
+    /// Does a fresh key encapsulation to `pk_recip` and replaces this context with one derived
+    /// from the new shared secret. The new context is bound to this one, so it inherits its mode,
+    /// PSK, sender identity, and info string. This resets the sequence counter to 0. A seal limit
+    /// set by `with_seal_limit` carries over, and the new context can seal that many messages
+    /// before it, too, is refused. The returned encapsulated key must be sent to the recipient,
+    /// who passes it to `AeadCtxR::reencapsulate`.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(encapped_key)` on success. If an error happened during key encapsulation,
+    /// returns `Err(HpkeError::EncapError)`. If this happens, the context is unmodified.
+    pub fn reencapsulate<R: CryptoRng + RngCore>(
+        &mut self,
+        pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
+        csprng: &mut R,
+    ) -> Result<EncappedKey<Kem::Kex>, HpkeError> {
+        // Encap a new shared secret
+        let (shared_secret, encapped_key) = kem::encap::<Kem, _>(pk_recip, None, csprng)?;
+
+        // Bind the new context to this one by using this context's reencap PSK
+        let psk = self.0.reencap_psk();
+        let mode = OpModeS::Psk(PskBundle {
+            psk: psk.0.as_slice(),
+            psk_id: REENCAP_LABEL,
+        });
+        let labels = self.0.labels;
+        let seal_limit = self.0.seal_limit;
+        self.0 = derive_enc_ctx_with_labels::<A, Kdf, Kem, _>(&mode, shared_secret, &[], &labels);
+        self.0.bind_enc(&encapped_key);
+        self.0.seal_limit = seal_limit;
+
+        Ok(encapped_key)
+    }
+}
+
+/// Checks at compile time that the given AEAD's nonce is big enough to hold a sequence number.
+/// If key, nonce, and tag lengths are also given, checks that they match the `AeadImpl`'s
+/// `KeySize`, `NonceSize`, and `TagSize`, respectively.
+macro_rules! assert_aead_sizes {
+    ($aead:ty) => {
+        // mix_nonce writes the sequence number into the last bytes of the nonce
+        const_assert!(
+            <<<$aead as crate::aead::Aead>::AeadImpl as ::aead::AeadCore>::NonceSize as
+                generic_array::typenum::Unsigned>::USIZE
+                > core::mem::size_of::<crate::aead::Seq>()
+        );
+    };
+    ($aead:ty, nk = $nk:expr, nn = $nn:expr, nt = $nt:expr) => {
+        assert_aead_sizes!($aead);
+        const_assert!(
+            <<<$aead as crate::aead::Aead>::AeadImpl as ::aead::NewAead>::KeySize as
+                generic_array::typenum::Unsigned>::USIZE
+                == $nk
+        );
+        const_assert!(
+            <<<$aead as crate::aead::Aead>::AeadImpl as ::aead::AeadCore>::NonceSize as
+                generic_array::typenum::Unsigned>::USIZE
+                == $nn
+        );
+        const_assert!(
+            <<<$aead as crate::aead::Aead>::AeadImpl as ::aead::AeadCore>::TagSize as
+                generic_array::typenum::Unsigned>::USIZE
+                == $nt
+        );
+    };
+}
+
+// Export all the AEAD implementations
+pub mod aes_gcm;
+pub mod chacha20_poly1305;
+pub mod export_only;
+#[doc(inline)]
+pub use crate::aead::{aes_gcm::*, chacha20_poly1305::*, export_only::*};
+
+#[cfg(test)]
+mod test {
+    use super::{
+        mix_nonce, Aead, AeadNonce, AeadTag, AesGcm128, AesGcm256, ChaCha20Poly1305,
+        ExportOnlyAead, Seq,
+    };
+    use crate::{
+        kdf::HkdfSha256,
+        kem::Kem as KemTrait,
+        kex::{Deserializable, Serializable},
+        op_mode::{OpModeR, OpModeS},
+        setup::{setup_receiver, setup_sender},
+        test_util::{aead_ctx_eq, gen_ctx_simple_pair, gen_rand_buf},
+        HpkeError,
+    };
+
+    use aead::AeadCore as BaseAeadCore;
+    use generic_array::GenericArray;
+    use rand::{rngs::StdRng, SeedableRng};
+    use sha2::{Digest, Sha256};
+
+    #[cfg(feature = "alloc")]
+    use super::{AeadKey, ContextStats};
+    #[cfg(feature = "alloc")]
+    use alloc::vec::Vec;
+
+    /// Tests that AeadKey::from_bytes fails on inputs of incorrect length
+    macro_rules! test_invalid_nonce {
+        ($test_name:ident, $aead_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = $aead_ty;
+
+                // No AEAD tag is 5 bytes long. This should give an IncorrectInputLength error
+                let tag_res = AeadTag::<A>::from_bytes(&[0; 5]);
+                if let Err(e) = tag_res {
+                    assert_eq!(
+                        e,
+                        HpkeError::IncorrectInputLength {
+                            expected: AeadTag::<A>::size(),
+                            got: 5
+                        }
+                    );
+                } else {
+                    panic!("AeadTag was unexpectedly valid");
+                }
+            }
+        };
+    }
+
+    /// Tests that `AeadTag::as_ref()` gives the same bytes as `to_bytes()`
+    macro_rules! test_tag_as_ref {
+        ($test_name:ident, $aead_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = $aead_ty;
+
+                // Every tag is at most 32 bytes, so this is big enough
+                let tag_bytes = gen_rand_buf();
+                let tag_size = AeadTag::<A>::size();
+                let tag = AeadTag::<A>::from_bytes(&tag_bytes[..tag_size]).unwrap();
+
+                assert_eq!(
+                    Sha256::digest(tag.as_ref()),
+                    Sha256::digest(&tag.to_bytes())
+                );
+            }
+        };
+    }
+
+    /// Tests that a tag can be written into a fixed-offset header field, read back out, and used
+    /// in a detached open, and that a field of the wrong length is rejected
+    macro_rules! test_tag_embedding {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+
+                // The header is a 3-byte prefix, then the tag, then a 1-byte suffix
+                const TAG_OFFSET: usize = 3;
+                let tag_len = AeadTag::<A>::size();
+
+                let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let mut msg = *b"in the header";
+                let tag = sender_ctx.seal(&mut msg, b"").unwrap();
+
+                let mut header = [0xffu8; 32];
+                header[TAG_OFFSET..TAG_OFFSET + tag_len].copy_from_slice(&tag.to_bytes());
+
+                // Read it back out and open with it
+                let parsed_tag =
+                    AeadTag::<A>::from_bytes(&header[TAG_OFFSET..TAG_OFFSET + tag_len]).unwrap();
+                assert_eq!(parsed_tag.to_bytes(), tag.to_bytes());
+                receiver_ctx.open(&mut msg, b"", &parsed_tag).unwrap();
+                assert_eq!(&msg, b"in the header");
+
+                // Off-by-one field lengths are rejected
+                for len in [tag_len - 1, tag_len + 1].iter() {
+                    assert_eq!(
+                        AeadTag::<A>::from_bytes(&header[TAG_OFFSET..TAG_OFFSET + len]).err(),
+                        Some(HpkeError::IncorrectInputLength {
+                            expected: tag_len,
+                            got: *len
+                        })
+                    );
+                }
+            }
+        };
+    }
+
+    /// Tests that `AeadTag::into_array` and `AeadTag::from_array` round-trip at the correct
+    /// length, and error at an incorrect one
+    macro_rules! test_tag_array {
+        ($test_name:ident, $aead_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = $aead_ty;
+
+                let mut tag_bytes = [0u8; 16];
+                tag_bytes.copy_from_slice(&gen_rand_buf()[..16]);
+
+                // The GCM tag size is 16
+                let tag = AeadTag::<A>::from_array(tag_bytes).unwrap();
+                assert_eq!(tag.into_array::<16>(), Ok(tag_bytes));
+
+                let tag = AeadTag::<A>::from_array(tag_bytes).unwrap();
+                assert_eq!(
+                    tag.into_array::<12>(),
+                    Err(HpkeError::IncorrectInputLength {
+                        expected: 16,
+                        got: 12
+                    })
+                );
+                assert_eq!(
+                    AeadTag::<A>::from_array([0u8; 12]).err(),
+                    Some(HpkeError::IncorrectInputLength {
+                        expected: 16,
+                        got: 12
+                    })
+                );
+            }
+        };
+    }
+
+    /// Tests that encryption context secret export does not change behavior based on the
+    /// underlying sequence number This logic is cipher-agnostic, so we don't make the test generic
+    /// over ciphers.
+    macro_rules! test_export_idempotence {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kdf = HkdfSha256;
+                // Again, this test is cipher-agnostic
+                type A = ChaCha20Poly1305;
+
+                // Set up a context. Logic is algorithm-independent, so we don't care about the
+                // types here
+                let (mut sender_ctx, _) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+
+                // Get an initial export secret
+                let mut secret1 = [0u8; 16];
+                sender_ctx
+                    .export(b"test_export_idempotence", &mut secret1)
+                    .unwrap();
+
+                // Modify the context by encrypting something
+                let mut plaintext = *b"back hand";
+                sender_ctx
+                    .seal(&mut plaintext[..], b"")
+                    .expect("seal() failed");
+
+                // Get a second export secret
+                let mut secret2 = [0u8; 16];
+                sender_ctx
+                    .export(b"test_export_idempotence", &mut secret2)
+                    .unwrap();
+
+                assert_eq!(secret1, secret2);
+            }
+        };
+    }
+
+    /// Tests that exports are byte-identical to expanding directly from an HKDF state keyed with
+    /// the exporter secret, over many exports in a row
+    macro_rules! test_export_matches_hkdf {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kdf = HkdfSha256;
+                type A = ChaCha20Poly1305;
+                use crate::kdf::LabeledExpand;
+
+                let mut exporter_secret = crate::setup::ExporterSecret::<Kdf>::default();
+                exporter_secret.0.copy_from_slice(&gen_rand_buf());
+                let ctx = crate::aead::AeadCtx::<A, Kdf, Kem>::new(
+                    &crate::aead::AeadKey::default(),
+                    crate::aead::AeadNonce::default(),
+                    exporter_secret.clone(),
+                );
+                let suite_id = crate::util::full_suite_id::<A, Kdf, Kem>();
+
+                for i in 0u8..100 {
+                    let exporter_ctx = [i; 4];
+                    let mut got = [0u8; 32];
+                    ctx.export(&exporter_ctx, &mut got).unwrap();
+
+                    let mut expected = [0u8; 32];
+                    hkdf::Hkdf::<<Kdf as crate::kdf::Kdf>::HashImpl>::from_prk(&exporter_secret.0)
+                        .unwrap()
+                        .labeled_expand(&suite_id, b"sec", &exporter_ctx, &mut expected)
+                        .unwrap();
+                    assert_eq!(got, expected);
+                }
+            }
+        };
+    }
+
+    /// Tests that seal and open on an export-only context return ExportOnly without touching their
+    /// input, and that export works as many times as you like
+    macro_rules! test_exportonly_errors {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kdf = HkdfSha256;
+                type A = ExportOnlyAead;
+
+                let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+
+                let mut plaintext = *b"back hand";
+                assert_eq!(
+                    sender_ctx.seal(&mut plaintext[..], b"").err(),
+                    Some(HpkeError::ExportOnly)
+                );
+                assert_eq!(&plaintext, b"back hand");
+
+                // The contents of the tag doesn't matter. It's never read.
+                let mut ciphertext = *b"back hand";
+                let aad = b"with my prayers";
+                type TagSize = <<A as Aead>::AeadImpl as BaseAeadCore>::TagSize;
+                let tag = AeadTag(GenericArray::<u8, TagSize>::default());
+                assert_eq!(
+                    receiver_ctx.open(&mut ciphertext[..], aad, &tag),
+                    Err(HpkeError::ExportOnly)
+                );
+                assert_eq!(&ciphertext, b"back hand");
+
+                // The wrappers around seal and open give the same error
+                let mut out = [0u8; 16];
+                assert_eq!(
+                    sender_ctx.seal_to_slice(b"hi", b"", &mut out),
+                    Err(HpkeError::ExportOnly)
+                );
+                assert_eq!(
+                    receiver_ctx.open_to_slice(b"hi", b"", &mut out),
+                    Err(HpkeError::ExportOnly)
+                );
+                // The AAD callbacks aren't even called
+                #[cfg(feature = "alloc")]
+                {
+                    assert_eq!(
+                        sender_ctx
+                            .seal_with_aad_fn(&mut plaintext[..], |_| panic!("aad_fn was called"))
+                            .err(),
+                        Some(HpkeError::ExportOnly)
+                    );
+                    assert_eq!(
+                        receiver_ctx.open_with_aad_fn(
+                            &mut ciphertext[..],
+                            |_| panic!("aad_fn was called"),
+                            &tag
+                        ),
+                        Err(HpkeError::ExportOnly)
+                    );
+                }
+
+                // Exports are unaffected by the failed calls, and don't change over many calls
+                let mut first_sender = [0u8; 32];
+                let mut first_receiver = [0u8; 32];
+                sender_ctx.export(b"ctx", &mut first_sender).unwrap();
+                receiver_ctx.export(b"ctx", &mut first_receiver).unwrap();
+                assert_eq!(first_sender, first_receiver);
+                for _ in 0..1000 {
+                    let mut secret = [0u8; 32];
+                    sender_ctx.export(b"ctx", &mut secret).unwrap();
+                    assert_eq!(secret, first_sender);
+                    receiver_ctx.export(b"ctx", &mut secret).unwrap();
+                    assert_eq!(secret, first_sender);
+                }
+            }
+        };
+    }
+
+    /// Tests that the export-only AEAD's 128-byte nonce has well-defined arithmetic. The sequence
+    /// number lands in the last 8 bytes, even at its maximum.
+    #[test]
+    fn test_exportonly_nonce_arithmetic() {
+        let base_nonce = AeadNonce::<ExportOnlyAead>::default();
+        let nonce = mix_nonce(&base_nonce, &Seq(u64::MAX));
+        assert_eq!(nonce.0.len(), 128);
+        assert!(nonce.0[..120].iter().all(|&b| b == 0));
+        assert!(nonce.0[120..].iter().all(|&b| b == 0xff));
+    }
+
+    /// Tests that sequence overflowing causes an error. This logic is cipher-agnostic, so we don't
+    /// make the test generic over ciphers.
+    macro_rules! test_overflow {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kdf = HkdfSha256;
+                // Again, this test is cipher-agnostic
+                type A = ChaCha20Poly1305;
+
+                // Make a sequence number that's at the max
+                let big_seq = {
+                    let mut seq = <Seq as Default>::default();
+                    seq.0 = u64::MAX;
+                    seq
+                };
+
+                let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                sender_ctx.0.seq = big_seq.clone();
+                receiver_ctx.0.seq = big_seq.clone();
+
+                // These should support precisely one more encryption before it registers an
+                // overflow
+
+                let msg = b"draxx them sklounst";
+                let aad = b"with my prayers";
+
+                // Do one round trip and ensure it works
+                {
+                    let mut plaintext = *msg;
+                    // Encrypt the plaintext
+                    let tag = sender_ctx
+                        .seal(&mut plaintext[..], aad)
+                        .expect("seal() failed");
+                    // Rename for clarity
+                    let mut ciphertext = plaintext;
+
+                    // Now to decrypt on the other side
+                    receiver_ctx
+                        .open(&mut ciphertext[..], aad, &tag)
+                        .expect("open() failed");
+                    // Rename for clarity
+                    let roundtrip_plaintext = ciphertext;
+
+                    // Make sure the output message was the same as the input message
+                    assert_eq!(msg, &roundtrip_plaintext);
+                }
+
+                // Try another round trip and ensure that we've overflowed
+                {
+                    let mut plaintext = *msg;
+                    // Try to encrypt the plaintext
+                    match sender_ctx.seal(&mut plaintext[..], aad) {
+                        Err(HpkeError::MessageLimitReached) => {
+                            // Good, this should have overflowed
+                        }
+                        Err(e) => panic!("seal() should have overflowed. Instead got {}", e),
+                        _ => panic!("seal() should have overflowed. Instead it succeeded"),
+                    }
+
+                    // Now try to decrypt something. This isn't a valid ciphertext or tag, but the
+                    // overflow should fail before the tag check fails.
+                    let mut placeholder_ciphertext = [0u8; 32];
+                    let placeholder_tag = AeadTag::from_bytes(&[0; 16]).unwrap();
+
+                    match receiver_ctx.open(&mut placeholder_ciphertext[..], aad, &placeholder_tag)
+                    {
+                        Err(HpkeError::MessageLimitReached) => {
+                            // Good, this should have overflowed
+                        }
+                        Err(e) => panic!("open() should have overflowed. Instead got {}", e),
+                        _ => panic!("open() should have overflowed. Instead it succeeded"),
+                    }
+                }
+            }
+        };
+    }
+
+    /// An AEAD with 8-byte nonces, the smallest that can hold a sequence number. Every bit of the
+    /// nonce is determined by the sequence number, so this is the best case for catching a bug in
+    /// the nonce derivation. It doesn't actually encrypt anything.
+    #[cfg(feature = "test-internals")]
+    #[derive(Clone)]
+    struct SmallNonceAeadImpl;
+
+    #[cfg(feature = "test-internals")]
+    impl BaseAeadCore for SmallNonceAeadImpl {
+        type NonceSize = generic_array::typenum::U8;
+        type TagSize = generic_array::typenum::U16;
+        type CiphertextOverhead = generic_array::typenum::U0;
+    }
+
+    #[cfg(feature = "test-internals")]
+    impl aead::AeadInPlace for SmallNonceAeadImpl {
+        fn encrypt_in_place_detached(
+            &self,
+            _: &aead::Nonce<Self>,
+            _: &[u8],
+            _: &mut [u8],
+        ) -> Result<aead::Tag<Self>, aead::Error> {
+            Ok(Default::default())
+        }
+
+        fn decrypt_in_place_detached(
+            &self,
+            _: &aead::Nonce<Self>,
+            _: &[u8],
+            _: &mut [u8],
+            _: &aead::Tag<Self>,
+        ) -> Result<(), aead::Error> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "test-internals")]
+    impl aead::NewAead for SmallNonceAeadImpl {
+        type KeySize = generic_array::typenum::U16;
+
+        fn new(_: &aead::Key<Self>) -> Self {
+            SmallNonceAeadImpl
+        }
+    }
+
+    #[cfg(feature = "test-internals")]
+    struct SmallNonceAead;
+
+    #[cfg(feature = "test-internals")]
+    impl Aead for SmallNonceAead {
+        type AeadImpl = SmallNonceAeadImpl;
+        // Not a real algorithm ID
+        const AEAD_ID: u16 = 0xFFFE;
+    }
+
+    /// Tests that `seal()` never reuses a nonce, from the start of a context's life up to the
+    /// point where the sequence counter overflows, and that each nonce is the base nonce XORed
+    /// with the sequence number
+    #[cfg(feature = "test-internals")]
+    macro_rules! test_nonce_log {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kdf = HkdfSha256;
+                type A = SmallNonceAead;
+
+                let (mut sender_ctx, _) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let mut msg = *b"nonce sense";
+
+                // Seal some messages at the start, then skip to right before rollover and seal
+                // until the context refuses to
+                let mut expected_seqs = Vec::new();
+                for seq in 0..1000u64 {
+                    sender_ctx.seal(&mut msg, b"").unwrap();
+                    expected_seqs.push(seq);
+                }
+                sender_ctx.0.seq.0 = u64::MAX - 1000;
+                for seq in u64::MAX - 1000..=u64::MAX {
+                    sender_ctx.seal(&mut msg, b"").unwrap();
+                    expected_seqs.push(seq);
+                }
+                assert_eq!(
+                    sender_ctx.seal(&mut msg, b"").err(),
+                    Some(HpkeError::MessageLimitReached)
+                );
+
+                // The failed seal didn't use a nonce
+                let log = sender_ctx.nonce_log();
+                assert_eq!(log.len(), expected_seqs.len());
+
+                // Each nonce is base_nonce XOR seq
+                let base_nonce = u64::from_be_bytes(sender_ctx.0.base_nonce.0.into());
+                for (nonce, seq) in log.iter().zip(expected_seqs.iter()) {
+                    assert_eq!(u64::from_be_bytes((*nonce).into()), base_nonce ^ seq);
+                }
+
+                // And no nonce appears twice
+                let mut sorted_log = log.to_vec();
+                sorted_log.sort();
+                sorted_log.dedup();
+                assert_eq!(sorted_log.len(), log.len());
+
+                // The log only keeps the most recent NONCE_LOG_LEN nonces
+                let (mut sender_ctx, _) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                for _ in 0..crate::aead::NONCE_LOG_LEN + 10 {
+                    sender_ctx.seal(&mut msg, b"").unwrap();
+                }
+                let log = sender_ctx.nonce_log();
+                let base_nonce = u64::from_be_bytes(sender_ctx.0.base_nonce.0.into());
+                assert_eq!(log.len(), crate::aead::NONCE_LOG_LEN);
+                assert_eq!(u64::from_be_bytes(log[0].into()), base_nonce ^ 10);
+            }
+        };
+    }
+
+    /// An AEAD that's ChaCha20Poly1305, except the first encryption each instance does fails
+    #[derive(Clone)]
+    struct FlakyAeadImpl {
+        inner: chacha20poly1305::ChaCha20Poly1305,
+        failed: core::cell::Cell<bool>,
+    }
+
+    impl BaseAeadCore for FlakyAeadImpl {
+        type NonceSize = generic_array::typenum::U12;
+        type TagSize = generic_array::typenum::U16;
+        type CiphertextOverhead = generic_array::typenum::U0;
+    }
+
+    impl aead::AeadInPlace for FlakyAeadImpl {
+        fn encrypt_in_place_detached(
+            &self,
+            nonce: &aead::Nonce<Self>,
+            aad: &[u8],
+            buffer: &mut [u8],
+        ) -> Result<aead::Tag<Self>, aead::Error> {
+            if !self.failed.replace(true) {
+                return Err(aead::Error);
+            }
+            self.inner.encrypt_in_place_detached(nonce, aad, buffer)
+        }
+
+        fn decrypt_in_place_detached(
+            &self,
+            nonce: &aead::Nonce<Self>,
+            aad: &[u8],
+            buffer: &mut [u8],
+            tag: &aead::Tag<Self>,
+        ) -> Result<(), aead::Error> {
+            self.inner
+                .decrypt_in_place_detached(nonce, aad, buffer, tag)
+        }
+    }
+
+    impl aead::NewAead for FlakyAeadImpl {
+        type KeySize = generic_array::typenum::U32;
+
+        fn new(key: &aead::Key<Self>) -> Self {
+            FlakyAeadImpl {
+                inner: chacha20poly1305::ChaCha20Poly1305::new(key),
+                failed: core::cell::Cell::new(false),
+            }
+        }
+    }
+
+    struct FlakyAead;
+
+    impl Aead for FlakyAead {
+        type AeadImpl = FlakyAeadImpl;
+        // Not a real algorithm ID
+        const AEAD_ID: u16 = 0xFFFD;
+    }
+
+    /// Tests that a failed `seal()` or `open()` leaves the sequence counter where it was, so the
+    /// retry uses the nonce the failed call would have
+    macro_rules! test_failure_keeps_seq {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kdf = HkdfSha256;
+                type A = FlakyAead;
+
+                let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let msg = b"try, try again";
+
+                // The first seal fails and doesn't advance the counter
+                let mut ciphertext = *msg;
+                assert_eq!(
+                    sender_ctx.seal(&mut ciphertext, b"").err(),
+                    Some(HpkeError::SealError)
+                );
+                assert_eq!(sender_ctx.0.seq.0, 0);
+
+                // The retry succeeds with seq 0, and only then advances the counter
+                let mut ciphertext = *msg;
+                let tag = sender_ctx.seal(&mut ciphertext, b"").unwrap();
+                assert_eq!(sender_ctx.0.seq.0, 1);
+
+                // Likewise, a failed open doesn't advance the receiver's counter
+                let mut buf = ciphertext;
+                assert_eq!(
+                    receiver_ctx.open(&mut buf, b"wrong aad", &tag),
+                    Err(HpkeError::OpenError)
+                );
+                assert_eq!(receiver_ctx.0.seq.0, 0);
+
+                // So the receiver still opens the retried message at seq 0
+                let mut buf = ciphertext;
+                receiver_ctx.open(&mut buf, b"", &tag).unwrap();
+                assert_eq!(&buf, msg);
+                assert_eq!(receiver_ctx.0.seq.0, 1);
+            }
+        };
+    }
+
+    /// Tests that `stats()` counts exactly the successful seals, opens, and exports
+    #[cfg(feature = "alloc")]
+    macro_rules! test_stats {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kdf = HkdfSha256;
+                type A = ChaCha20Poly1305;
+
+                let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                assert_eq!(sender_ctx.stats(), ContextStats::default());
+                assert_eq!(receiver_ctx.stats(), ContextStats::default());
+
+                let mut sealed = Vec::new();
+                for i in 0..3u8 {
+                    let mut msg = [i; 10];
+                    let tag = sender_ctx.seal(&mut msg, b"").unwrap();
+                    sealed.push((msg, tag));
+                }
+                let mut out = [0u8; 64];
+                sender_ctx.seal_to_slice(b"fourth", b"", &mut out).unwrap();
+
+                // A failed open isn't counted
+                let (mut ciphertext, tag) = (sealed[0].0, &sealed[0].1);
+                assert!(receiver_ctx.open(&mut ciphertext, b"wrong", tag).is_err());
+                for (mut ciphertext, tag) in sealed.into_iter().take(2) {
+                    receiver_ctx.open(&mut ciphertext, b"", &tag).unwrap();
+                }
+
+                // Neither is a failed export
+                let mut exported = [0u8; 32];
+                for _ in 0..5 {
+                    sender_ctx.export(b"metrics", &mut exported).unwrap();
+                }
+                let mut too_long = vec![0u8; 255 * <Kdf as crate::kdf::Kdf>::N_H + 1];
+                assert!(sender_ctx.export(b"", &mut too_long).is_err());
+                receiver_ctx.export(b"metrics", &mut exported).unwrap();
+
+                // Nor is the PSK that re-encapsulation exports for itself
+                let _ = sender_ctx.0.reencap_psk();
+
+                assert_eq!(
+                    sender_ctx.stats(),
+                    ContextStats {
+                        seals: 4,
+                        opens: 0,
+                        exports: 5,
+                    }
+                );
+                assert_eq!(
+                    receiver_ctx.stats(),
+                    ContextStats {
+                        seals: 0,
+                        opens: 2,
+                        exports: 1,
+                    }
+                );
+            }
+        };
+    }
+
+    /// Tests that `bytes_sealed()` adds up the plaintext lengths of successful seals, and that
+    /// `should_rekey` flips exactly at the limit
+    macro_rules! test_bytes_sealed {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kdf = HkdfSha256;
+                type A = ChaCha20Poly1305;
+
+                let byte_limit = 100;
+                let (mut sender_ctx, _) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                assert_eq!(sender_ctx.bytes_sealed(), 0);
+                assert!(!sender_ctx.should_rekey(byte_limit));
+
+                for i in 1..=3 {
+                    let mut msg = [0u8; 30];
+                    sender_ctx.seal(&mut msg, b"").unwrap();
+                    assert_eq!(sender_ctx.bytes_sealed(), 30 * i);
+                    assert!(!sender_ctx.should_rekey(byte_limit));
+                }
+                let mut out = [0u8; 64];
+                sender_ctx.seal_to_slice(&[0u8; 9], b"", &mut out).unwrap();
+                assert_eq!(sender_ctx.bytes_sealed(), 99);
+                assert!(!sender_ctx.should_rekey(byte_limit));
+
+                // A failed seal isn't counted
+                let mut too_short = [0u8; 4];
+                assert!(sender_ctx
+                    .seal_to_slice(&[0u8; 9], b"", &mut too_short)
+                    .is_err());
+                assert_eq!(sender_ctx.bytes_sealed(), 99);
+
+                sender_ctx.seal(&mut [0u8; 1], b"").unwrap();
+                assert_eq!(sender_ctx.bytes_sealed(), 100);
+                assert!(sender_ctx.should_rekey(byte_limit));
+            }
+        };
+    }
+
+    /// Tests that a sender and receiver at the same sequence number derive the same
+    /// `export_at_seq` secret, and that other sequence numbers and labels give other secrets
+    #[cfg(feature = "alloc")]
+    macro_rules! test_export_at_seq {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kdf = HkdfSha256;
+                type A = ChaCha20Poly1305;
+
+                let label = b"per-message key";
+                let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+
+                let mut secrets = Vec::new();
+                for i in 0..3u64 {
+                    let (sender_seq, sender_secret) = sender_ctx.export_at_seq(label, 32).unwrap();
+                    let (receiver_seq, receiver_secret) =
+                        receiver_ctx.export_at_seq(label, 32).unwrap();
+                    assert_eq!(sender_seq, i);
+                    assert_eq!(receiver_seq, i);
+                    assert_eq!(sender_secret, receiver_secret);
+                    assert_eq!(sender_secret.len(), 32);
+
+                    // It's not a plain export, even with the sequence number in the exporter
+                    // context, and it depends on the label
+                    let mut plain_export = [0u8; 32];
+                    sender_ctx.export(label, &mut plain_export).unwrap();
+                    assert_ne!(sender_secret, plain_export.to_vec());
+                    let seq_ctx = [&i.to_be_bytes()[..], &label[..]].concat();
+                    sender_ctx.export(&seq_ctx, &mut plain_export).unwrap();
+                    assert_ne!(sender_secret, plain_export.to_vec());
+                    let (_, other_label_secret) = sender_ctx.export_at_seq(b"other", 32).unwrap();
+                    assert_ne!(sender_secret, other_label_secret);
+
+                    assert!(!secrets.contains(&sender_secret));
+                    secrets.push(sender_secret);
+
+                    let mut msg = *b"next";
+                    let tag = sender_ctx.seal(&mut msg, b"").unwrap();
+                    receiver_ctx.open(&mut msg, b"", &tag).unwrap();
+                }
+
+                let too_long = 255 * <Kdf as crate::kdf::Kdf>::N_H + 1;
+                assert_eq!(
+                    sender_ctx.export_at_seq(label, too_long),
+                    Err(HpkeError::KdfOutputTooLong)
+                );
+            }
+        };
+    }
+
+    /// Tests that both sides derive the same `export_with_salt` secret for the same salt and label,
+    /// and that other salts and labels give other secrets
+    #[cfg(feature = "alloc")]
+    macro_rules! test_export_with_salt {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kdf = HkdfSha256;
+                type A = ChaCha20Poly1305;
+
+                let label = b"downstream key";
+                let (sender_ctx, receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let (other_sender_ctx, _) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+
+                let secret = sender_ctx.export_with_salt(b"salt 1", label, 32).unwrap();
+                assert_eq!(secret.len(), 32);
+                assert_eq!(
+                    secret,
+                    sender_ctx.export_with_salt(b"salt 1", label, 32).unwrap()
+                );
+                assert_eq!(
+                    secret,
+                    receiver_ctx.export_with_salt(b"salt 1", label, 32).unwrap()
+                );
+
+                assert_ne!(
+                    secret,
+                    sender_ctx.export_with_salt(b"salt 2", label, 32).unwrap()
+                );
+                assert_ne!(secret, sender_ctx.export_with_salt(b"", label, 32).unwrap());
+                assert_ne!(
+                    secret,
+                    sender_ctx
+                        .export_with_salt(b"salt 1", b"other", 32)
+                        .unwrap()
+                );
+                assert_ne!(
+                    secret,
+                    other_sender_ctx
+                        .export_with_salt(b"salt 1", label, 32)
+                        .unwrap()
+                );
+
+                // It's not the plain export, even with an empty salt
+                let mut plain_export = [0u8; 32];
+                sender_ctx.export(label, &mut plain_export).unwrap();
+                assert_ne!(
+                    sender_ctx.export_with_salt(b"", label, 32).unwrap(),
+                    plain_export.to_vec()
+                );
+
+                let too_long = 255 * <Kdf as crate::kdf::Kdf>::N_H + 1;
+                assert_eq!(
+                    sender_ctx.export_with_salt(b"salt 1", label, too_long),
+                    Err(HpkeError::KdfOutputTooLong)
+                );
+            }
+        };
+    }
+
+    /// A buffer that can't grow past a fixed capacity, like a preallocated `BytesMut` would
+    #[cfg(feature = "alloc")]
+    struct BoundedBuffer {
+        buf: Vec<u8>,
+        capacity: usize,
+    }
+
+    #[cfg(feature = "alloc")]
+    impl AsRef<[u8]> for BoundedBuffer {
+        fn as_ref(&self) -> &[u8] {
+            &self.buf
+        }
+    }
+
+    #[cfg(feature = "alloc")]
+    impl AsMut<[u8]> for BoundedBuffer {
+        fn as_mut(&mut self) -> &mut [u8] {
+            &mut self.buf
+        }
+    }
+
+    #[cfg(feature = "alloc")]
+    impl aead::Buffer for BoundedBuffer {
+        fn extend_from_slice(&mut self, other: &[u8]) -> aead::Result<()> {
+            if self.buf.len() + other.len() > self.capacity {
+                return Err(aead::Error);
+            }
+            self.buf.extend_from_slice(other);
+            Ok(())
+        }
+
+        fn truncate(&mut self, len: usize) {
+            self.buf.truncate(len);
+        }
+    }
+
+    /// Tests that `seal_in_place_buffer` and `open_in_place_buffer` round-trip, and that failures
+    /// leave the buffer and the sequence number unchanged
+    #[cfg(feature = "alloc")]
+    macro_rules! test_in_place_buffer {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kdf = HkdfSha256;
+                type A = ChaCha20Poly1305;
+
+                let msg = b"zero-copy";
+                let aad = b"framing";
+                let tag_len = AeadTag::<A>::size();
+                let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+
+                // A buffer with no room for the tag can't be sealed
+                let mut full = BoundedBuffer {
+                    buf: msg.to_vec(),
+                    capacity: msg.len() + tag_len - 1,
+                };
+                assert_eq!(
+                    sender_ctx.seal_in_place_buffer(&mut full, aad),
+                    Err(HpkeError::SealError)
+                );
+                assert_eq!(&full.buf, msg);
+                assert_eq!(sender_ctx.0.seq.0, 0);
+
+                let mut buf = BoundedBuffer {
+                    buf: msg.to_vec(),
+                    capacity: msg.len() + tag_len,
+                };
+                sender_ctx.seal_in_place_buffer(&mut buf, aad).unwrap();
+                assert_eq!(buf.buf.len(), msg.len() + tag_len);
+                let sealed = buf.buf.clone();
+
+                // A failed open leaves everything as it was
+                let mut tampered = sealed.clone();
+                tampered[0] ^= 1;
+                let tampered_copy = tampered.clone();
+                assert_eq!(
+                    receiver_ctx.open_in_place_buffer(&mut tampered, aad),
+                    Err(HpkeError::OpenError)
+                );
+                assert_eq!(tampered, tampered_copy);
+                let mut wrong_aad = sealed.clone();
+                assert!(receiver_ctx
+                    .open_in_place_buffer(&mut wrong_aad, b"")
+                    .is_err());
+                assert_eq!(wrong_aad, sealed);
+                let mut too_short = vec![0u8; tag_len - 1];
+                assert_eq!(
+                    receiver_ctx.open_in_place_buffer(&mut too_short, aad),
+                    Err(HpkeError::OpenError)
+                );
+                assert_eq!(receiver_ctx.0.seq.0, 0);
+
+                receiver_ctx.open_in_place_buffer(&mut buf, aad).unwrap();
+                assert_eq!(&buf.buf, msg);
+            }
+        };
+    }
+
+    /// Tests that a `BytesMut` round-trips through `seal_in_place_bytes` and `open_in_place_bytes`,
+    /// and that a failed open leaves it unchanged
+    #[cfg(feature = "bytes")]
+    macro_rules! test_in_place_bytes {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kdf = HkdfSha256;
+                type A = ChaCha20Poly1305;
+
+                let msg = b"zero-copy";
+                let aad = b"framing";
+                let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+
+                let mut buf = bytes::BytesMut::from(&msg[..]);
+                sender_ctx.seal_in_place_bytes(&mut buf, aad).unwrap();
+                assert_eq!(buf.len(), msg.len() + AeadTag::<A>::size());
+                assert_ne!(&buf[..msg.len()], msg);
+
+                // A failed open leaves everything as it was
+                let mut tampered = buf.clone();
+                tampered[0] ^= 1;
+                let tampered_copy = tampered.clone();
+                assert_eq!(
+                    receiver_ctx.open_in_place_bytes(&mut tampered, aad),
+                    Err(HpkeError::OpenError)
+                );
+                assert_eq!(tampered, tampered_copy);
+                assert_eq!(receiver_ctx.0.seq.0, 0);
+
+                receiver_ctx.open_in_place_bytes(&mut buf, aad).unwrap();
+                assert_eq!(&buf[..], msg);
+            }
+        };
+    }
+
+    /// Tests that `open_ct` returns the plaintext and 1 for a good message, and the dummy and 0
+    /// for a bad one
+    #[cfg(feature = "alloc")]
+    macro_rules! test_open_ct {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kdf = HkdfSha256;
+                type A = ChaCha20Poly1305;
+
+                let msg = b"constant time";
+                let dummy = [0xffu8; 13];
+                let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+
+                let mut sealed = [0u8; 64];
+                let n = sender_ctx.seal_to_slice(msg, b"", &mut sealed).unwrap();
+                let sealed = &sealed[..n];
+
+                // A tampered message gives the dummy
+                let mut tampered = sealed.to_vec();
+                tampered[0] ^= 1;
+                let (out, opened) = receiver_ctx.open_ct(&tampered, b"", &dummy);
+                assert!(!bool::from(opened));
+                assert_eq!(out, dummy.to_vec());
+
+                // So does a dummy of the wrong length, or a message that's too short
+                let (out, opened) = receiver_ctx.open_ct(sealed, b"", &dummy[1..]);
+                assert!(!bool::from(opened));
+                assert_eq!(out, dummy[1..].to_vec());
+                let (out, opened) = receiver_ctx.open_ct(&sealed[..4], b"", &[]);
+                assert!(!bool::from(opened));
+                assert!(out.is_empty());
+
+                // None of that advanced the receiver, so the real message still opens
+                let (out, opened) = receiver_ctx.open_ct(sealed, b"", &dummy);
+                assert!(bool::from(opened));
+                assert_eq!(out, msg.to_vec());
+            }
+        };
+    }
+
+    /// Tests that a context with a seal limit of N refuses the (N+1)th seal, that the receiver
+    /// keeps opening, and that exports keep working
+    macro_rules! test_seal_limit {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kdf = HkdfSha256;
+                type A = ChaCha20Poly1305;
+
+                let (sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let mut sender_ctx = sender_ctx.with_seal_limit(3);
+
+                for _ in 0..3 {
+                    let mut msg = *b"within policy";
+                    let tag = sender_ctx.seal(&mut msg, b"").unwrap();
+                    receiver_ctx.open(&mut msg, b"", &tag).unwrap();
+                }
+
+                let mut msg = *b"over the limit";
+                assert_eq!(
+                    sender_ctx.seal(&mut msg, b"").err(),
+                    Some(HpkeError::PolicyLimitReached)
+                );
+                assert_eq!(&msg, b"over the limit");
+                let mut out = [0u8; 64];
+                assert_eq!(
+                    sender_ctx.seal_to_slice(b"nor this", b"", &mut out),
+                    Err(HpkeError::PolicyLimitReached)
+                );
+                assert_eq!(sender_ctx.0.seq.0, 3);
+
+                let mut exported = [0u8; 32];
+                sender_ctx.export(b"still fine", &mut exported).unwrap();
+
+                // Re-encapsulating keeps the limit, but starts counting towards it again
+                let (_, pk_recip) = Kem::gen_keypair(&mut StdRng::from_entropy());
+                sender_ctx
+                    .reencapsulate(&pk_recip, &mut StdRng::from_entropy())
+                    .unwrap();
+                for _ in 0..3 {
+                    sender_ctx.seal(&mut [], b"").unwrap();
+                }
+                assert_eq!(
+                    sender_ctx.seal(&mut [], b"").err(),
+                    Some(HpkeError::PolicyLimitReached)
+                );
+
+                // Seals before the limit was set count towards it
+                let (mut sender_ctx, _) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                sender_ctx.seal(&mut [], b"").unwrap();
+                let mut sender_ctx = sender_ctx.with_seal_limit(1);
+                assert_eq!(
+                    sender_ctx.seal(&mut [], b"").err(),
+                    Some(HpkeError::PolicyLimitReached)
+                );
+            }
+        };
+    }
+
+    /// Tests that open_at_seq opens messages that skip ahead within the window, and rejects
+    /// replays, skipped messages, and messages too far ahead
+    macro_rules! test_open_at_seq {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kdf = HkdfSha256;
+                type A = ChaCha20Poly1305;
+
+                let (mut sender_ctx, receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let mut receiver_ctx = receiver_ctx.with_seq_window(2);
+
+                // Seal messages 0 through 7. ChaCha20Poly1305 tags are 16 bytes.
+                let mut msgs = [[0u8; 9]; 8];
+                let mut tags = [[0u8; 16]; 8];
+                for (i, (msg, tag)) in msgs.iter_mut().zip(tags.iter_mut()).enumerate() {
+                    *msg = *b"message 0";
+                    msg[8] += i as u8;
+                    tag.copy_from_slice(&sender_ctx.seal(msg, b"").unwrap().to_bytes());
+                }
+                // Opens message i at sequence number seq
+                let open =
+                    |receiver_ctx: &mut crate::aead::AeadCtxR<A, Kdf, Kem>, i: usize, seq: u64| {
+                        let mut buf = msgs[i];
+                        let tag = AeadTag::<A>::from_bytes(&tags[i]).unwrap();
+                        receiver_ctx
+                            .open_at_seq(seq, &mut buf, b"", &tag)
+                            .map(|()| buf)
+                    };
+
+                assert_eq!(&open(&mut receiver_ctx, 0, 0).unwrap(), b"message 0");
+                assert_eq!(&open(&mut receiver_ctx, 1, 1).unwrap(), b"message 1");
+                // 2 and 3 were dropped. 4 is 2 ahead, which is within the window
+                assert_eq!(&open(&mut receiver_ctx, 4, 4).unwrap(), b"message 4");
+                assert_eq!(receiver_ctx.0.seq.0, 5);
+
+                // Replays and late arrivals are rejected
+                assert_eq!(
+                    open(&mut receiver_ctx, 1, 1),
+                    Err(HpkeError::SeqOutOfWindow)
+                );
+                assert_eq!(
+                    open(&mut receiver_ctx, 3, 3),
+                    Err(HpkeError::SeqOutOfWindow)
+                );
+                // 8 is 3 ahead, which is too far
+                assert_eq!(
+                    open(&mut receiver_ctx, 7, 8),
+                    Err(HpkeError::SeqOutOfWindow)
+                );
+                // A sequence number in the window that doesn't match the message doesn't open, and
+                // gets the context nowhere
+                assert_eq!(open(&mut receiver_ctx, 6, 7), Err(HpkeError::OpenError));
+                assert_eq!(receiver_ctx.0.seq.0, 5);
+
+                // The regular open carries on from where open_at_seq left off
+                let mut buf = msgs[5];
+                let tag = AeadTag::<A>::from_bytes(&tags[5]).unwrap();
+                receiver_ctx.open(&mut buf, b"", &tag).unwrap();
+                assert_eq!(&buf, b"message 5");
+
+                // Without a window, only the expected sequence number is accepted
+                let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let tag0 = sender_ctx.seal(&mut [], b"").unwrap();
+                let tag1 = sender_ctx.seal(&mut [], b"").unwrap();
+                assert_eq!(
+                    receiver_ctx.open_at_seq(1, &mut [], b"", &tag1),
+                    Err(HpkeError::SeqOutOfWindow)
+                );
+                receiver_ctx.open_at_seq(0, &mut [], b"", &tag0).unwrap();
+            }
+        };
+    }
+
+    /// Tests that `ct_ciphertext_eq` accepts equal inputs, and rejects inputs that differ in
+    /// contents or in length
+    #[test]
+    fn test_ct_ciphertext_eq() {
+        use super::ct_ciphertext_eq;
+
+        let ciphertext = *b"ciphertext and tag";
+        assert!(ct_ciphertext_eq(&ciphertext, &ciphertext.clone()));
+        assert!(ct_ciphertext_eq(b"", b""));
+
+        let mut different = ciphertext;
+        different[17] ^= 1;
+        assert!(!ct_ciphertext_eq(&ciphertext, &different));
+
+        assert!(!ct_ciphertext_eq(&ciphertext, &ciphertext[..17]));
+        assert!(!ct_ciphertext_eq(&ciphertext[..17], &ciphertext));
+        assert!(!ct_ciphertext_eq(&ciphertext, b""));
+    }
+
+    /// Tests that `close()` leaves a context keyed with all zeros and unable to seal or open
+    #[cfg(feature = "alloc")]
+    macro_rules! test_close {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                use crate::{aead::AeadCtx, setup::ExporterSecret};
+                use aead::AeadInPlace;
+
+                type Kem = $kem_ty;
+                type Kdf = HkdfSha256;
+                type A = ChaCha20Poly1305;
+
+                let (sender_ctx, receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let mut msg = *b"last words";
+                // What the real key makes of this message, to check that the wiped key differs
+                let real_tag = receiver_ctx.0.encryptor.encrypt_in_place_detached(
+                    &Default::default(),
+                    b"",
+                    &mut msg,
+                );
+
+                // close() is just wipe() followed by a drop. Wipe a boxed context so it can be
+                // inspected before it's dropped.
+                let mut sender_ctx = alloc::boxed::Box::new(sender_ctx);
+                sender_ctx.0.wipe();
+                assert!(sender_ctx.0.base_nonce.0.iter().all(|&b| b == 0));
+
+                // The AEAD and exporter are the ones from an all-zero key and exporter secret
+                let zero_ctx = AeadCtx::<A, Kdf, Kem>::new(
+                    &AeadKey::default(),
+                    AeadNonce::default(),
+                    ExporterSecret::default(),
+                );
+                let mut wiped_ct = *b"last words";
+                let mut zero_ct = *b"last words";
+                let wiped_tag = sender_ctx
+                    .0
+                    .encryptor
+                    .encrypt_in_place_detached(&Default::default(), b"", &mut wiped_ct)
+                    .unwrap();
+                let zero_tag = zero_ctx
+                    .encryptor
+                    .encrypt_in_place_detached(&Default::default(), b"", &mut zero_ct)
+                    .unwrap();
+                assert_eq!((wiped_ct, wiped_tag), (zero_ct, zero_tag));
+                assert_ne!(Ok(wiped_tag), real_tag);
+
+                let (mut wiped_export, mut zero_export) = ([0u8; 32], [0u8; 32]);
+                sender_ctx.0.export(b"", &mut wiped_export).unwrap();
+                zero_ctx.export(b"", &mut zero_export).unwrap();
+                assert_eq!(wiped_export, zero_export);
+
+                // And it refuses to seal
+                assert_eq!(
+                    sender_ctx.seal(&mut msg, b"").err(),
+                    Some(HpkeError::MessageLimitReached)
+                );
+
+                sender_ctx.close();
+                receiver_ctx.close();
+            }
+        };
+    }
+
+    /// Tests that `open()` can decrypt things properly encrypted with `seal()`
+    macro_rules! test_ctx_correctness {
+        ($test_name:ident, $aead_ty:ty, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = $aead_ty;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+
+                let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+
+                let msg = b"Love it or leave it, you better gain way";
+                let aad = b"You better hit bull's eye, the kid don't play";
+
+                // Encrypt with the sender context
+                let mut ciphertext = msg.clone();
+                let tag = sender_ctx
+                    .seal(&mut ciphertext[..], aad)
+                    .expect("seal() failed");
+
+                // Make sure seal() isn't a no-op
+                assert!(&ciphertext[..] != &msg[..]);
+
+                // Decrypt with the receiver context
+                receiver_ctx
+                    .open(&mut ciphertext[..], aad, &tag)
+                    .expect("open() failed");
+                // Change name for clarity
+                let decrypted = ciphertext;
+                assert_eq!(&decrypted[..], &msg[..]);
+            }
+        };
+    }
+
+    /// Tests that padded messages of different lengths have the same ciphertext length when they
+    /// fit in the same block, that they round-trip, and that malformed padding is rejected
+    #[cfg(feature = "alloc")]
+    macro_rules! test_padded {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+
+                let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let block_size = 32;
+                assert_eq!(
+                    sender_ctx.seal_padded(b"", b"", 0).err(),
+                    Some(HpkeError::InvalidPadding)
+                );
+
+                // Everything shorter than a block pads to one block. A full block gets a second
+                // block of padding. Include trailing 0x80 and 0x00 bytes to make sure they survive.
+                let msgs: [&[u8]; 6] = [
+                    b"",
+                    b"y",
+                    b"yes\x80",
+                    b"no\x00\x00",
+                    &[0x80; 31],
+                    &[0xaa; 32],
+                ];
+                for msg in msgs.iter() {
+                    let (ciphertext, tag) =
+                        sender_ctx.seal_padded(msg, b"aad", block_size).unwrap();
+                    let expected_len = if msg.len() < block_size { 32 } else { 64 };
+                    assert_eq!(ciphertext.len(), expected_len);
+
+                    let plaintext = receiver_ctx.open_padded(&ciphertext, b"aad", &tag).unwrap();
+                    assert_eq!(&plaintext[..], *msg);
+                }
+
+                // Seal some bad padding without going through seal_padded. All zeros has no 0x80
+                // byte, and the other one has a nonzero byte after the 0x80.
+                let bad_paddings: [&[u8]; 2] = [&[0x00; 32], b"hello\x80\x00\x01"];
+                for bad in bad_paddings.iter() {
+                    let mut ciphertext = bad.to_vec();
+                    let tag = sender_ctx.seal(&mut ciphertext, b"").unwrap();
+                    assert_eq!(
+                        receiver_ctx.open_padded(&ciphertext, b"", &tag).err(),
+                        Some(HpkeError::InvalidPadding)
+                    );
+                }
+            }
+        };
+    }
+
+    /// Tests that the sender and receiver derive the same HMAC key, that it's separate from
+    /// exported secrets, and that a bad HMAC is rejected even when the AEAD tag is good
+    macro_rules! test_hmac {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+
+                let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+
+                // Both sides agree on the key, and it's not the same as exporting the label
+                let sender_key = sender_ctx.derive_hmac_key(b"integrity");
+                assert_eq!(sender_key, receiver_ctx.derive_hmac_key(b"integrity"));
+                assert_ne!(sender_key, sender_ctx.derive_hmac_key(b"other label"));
+                let mut exported = [0u8; 32];
+                sender_ctx.export(b"integrity", &mut exported).unwrap();
+                assert_ne!(sender_key.as_slice(), &exported[..]);
+
+                // A round trip works
+                let mut msg = *b"check twice, cut once";
+                let (tag, hmac_tag) = sender_ctx
+                    .seal_with_hmac(&mut msg, b"aad", b"integrity")
+                    .unwrap();
+                receiver_ctx
+                    .open_with_hmac(&mut msg, b"aad", &tag, b"integrity", &hmac_tag)
+                    .unwrap();
+                assert_eq!(&msg, b"check twice, cut once");
+
+                // A flipped HMAC bit is rejected, and the plaintext is wiped
+                let mut msg = *b"check twice, cut once";
+                let (tag, mut hmac_tag) = sender_ctx
+                    .seal_with_hmac(&mut msg, b"aad", b"integrity")
+                    .unwrap();
+                hmac_tag[0] ^= 1;
+                assert_eq!(
+                    receiver_ctx.open_with_hmac(&mut msg, b"aad", &tag, b"integrity", &hmac_tag),
+                    Err(HpkeError::OpenError)
+                );
+                assert_eq!(msg, [0u8; 21]);
+
+                // So is an HMAC under the wrong label
+                let mut msg = *b"check twice, cut once";
+                let (tag, hmac_tag) = sender_ctx
+                    .seal_with_hmac(&mut msg, b"aad", b"integrity")
+                    .unwrap();
+                assert_eq!(
+                    receiver_ctx.open_with_hmac(&mut msg, b"aad", &tag, b"wrong", &hmac_tag),
+                    Err(HpkeError::OpenError)
+                );
+            }
+        };
+    }
+
+    /// Tests that a 32-byte payload round-trips through seal_fixed and open_fixed, that the result
+    /// matches a regular seal, and that a bad tag is rejected without handing out the plaintext
+    macro_rules! test_fixed {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+
+                let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+
+                let token = [0x42u8; 32];
+                // A copy of the sender that seals the same message the regular way
+                let mut regular_sender_ctx = sender_ctx.clone();
+                let (ciphertext, tag) = sender_ctx.seal_fixed(&token, b"aad").unwrap();
+                assert_ne!(ciphertext, token);
+                let mut regular_ciphertext = token;
+                let regular_tag = regular_sender_ctx
+                    .seal(&mut regular_ciphertext, b"aad")
+                    .unwrap();
+                assert_eq!(ciphertext, regular_ciphertext);
+                assert_eq!(tag.to_bytes(), regular_tag.to_bytes());
+                assert_eq!(
+                    receiver_ctx.open_fixed(&ciphertext, b"aad", &tag).unwrap(),
+                    token
+                );
+
+                // Tampering is detected
+                let (mut ciphertext, tag) = sender_ctx.seal_fixed(&token, b"aad").unwrap();
+                ciphertext[0] ^= 1;
+                assert_eq!(
+                    receiver_ctx.open_fixed(&ciphertext, b"aad", &tag),
+                    Err(HpkeError::OpenError)
+                );
+            }
+        };
+    }
+
+    /// Tests that seal_binding_enc and open_binding_enc round-trip, that the message doesn't open
+    /// if the receiver was set up with a swapped encapsulated key, and that contexts without an
+    /// encapsulated key refuse to bind
+    #[cfg(feature = "alloc")]
+    macro_rules! test_binding_enc {
         ($test_name:ident, $kem_ty:ty) => {
             #[test]
             fn $test_name() {
-                type Kem = $kem_ty;
-                type Kdf = HkdfSha256;
-                // Again, this test is cipher-agnostic
                 type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
 
-                // Set up a context. Logic is algorithm-independent, so we don't care about the
-                // types here
-                let (mut sender_ctx, _) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let mut csprng = StdRng::from_entropy();
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let info = b"bound";
 
-                // Get an initial export secret
-                let mut secret1 = [0u8; 16];
-                sender_ctx
-                    .export(b"test_export_idempotence", &mut secret1)
+                let (encapped_key, mut sender_ctx) =
+                    setup_sender::<A, Kdf, Kem, _>(&OpModeS::Base, &pk_recip, info, &mut csprng)
+                        .unwrap();
+                let mut receiver_ctx =
+                    setup_receiver::<A, Kdf, Kem>(&OpModeR::Base, &sk_recip, &encapped_key, info)
+                        .unwrap();
+
+                // A round trip works
+                let mut msg = *b"pinned";
+                let tag = sender_ctx.seal_binding_enc(&mut msg, b"extra").unwrap();
+                receiver_ctx
+                    .open_binding_enc(&mut msg, b"extra", &tag)
                     .unwrap();
+                assert_eq!(&msg, b"pinned");
 
-                // Modify the context by encrypting something
-                let mut plaintext = *b"back hand";
-                sender_ctx
-                    .seal(&mut plaintext[..], b"")
-                    .expect("seal() failed");
+                // The encapsulated key really is in the AAD. A plain open with just the extra AAD
+                // fails, and one with enc || extra succeeds.
+                let mut msg = *b"pinned";
+                let tag = sender_ctx.seal_binding_enc(&mut msg, b"extra").unwrap();
+                assert_eq!(
+                    receiver_ctx.clone().open(&mut msg.clone(), b"extra", &tag),
+                    Err(HpkeError::OpenError)
+                );
+                let mut aad = encapped_key.to_bytes().to_vec();
+                aad.extend_from_slice(b"extra");
+                receiver_ctx.open(&mut msg, &aad, &tag).unwrap();
+                assert_eq!(&msg, b"pinned");
 
-                // Get a second export secret
-                let mut secret2 = [0u8; 16];
-                sender_ctx
-                    .export(b"test_export_idempotence", &mut secret2)
+                // Swap the transmitted encapsulated key for a different valid one. The receiver
+                // sets up with the swapped one, and the message doesn't open.
+                let (swapped_encapped_key, _) =
+                    setup_sender::<A, Kdf, Kem, _>(&OpModeS::Base, &pk_recip, info, &mut csprng)
+                        .unwrap();
+                let mut swapped_receiver_ctx = setup_receiver::<A, Kdf, Kem>(
+                    &OpModeR::Base,
+                    &sk_recip,
+                    &swapped_encapped_key,
+                    info,
+                )
+                .unwrap();
+                let mut msg = *b"pinned";
+                let tag = sender_ctx.seal_binding_enc(&mut msg, b"extra").unwrap();
+                assert_eq!(
+                    swapped_receiver_ctx.open_binding_enc(&mut msg, b"extra", &tag),
+                    Err(HpkeError::OpenError)
+                );
+
+                // Re-encapsulating binds to the new encapsulated key
+                let new_encapped_key = sender_ctx.reencapsulate(&pk_recip, &mut csprng).unwrap();
+                receiver_ctx
+                    .reencapsulate(&sk_recip, &new_encapped_key)
                     .unwrap();
+                let mut msg = *b"pinned";
+                let tag = sender_ctx.seal_binding_enc(&mut msg, b"").unwrap();
+                receiver_ctx.open_binding_enc(&mut msg, b"", &tag).unwrap();
+                assert_eq!(&msg, b"pinned");
 
-                assert_eq!(secret1, secret2);
+                // Contexts that weren't set up from an encapsulated key can't bind to one
+                let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let mut msg = *b"pinned";
+                assert_eq!(
+                    sender_ctx.seal_binding_enc(&mut msg, b"").err(),
+                    Some(HpkeError::NoEncappedKey)
+                );
+                assert_eq!(&msg, b"pinned");
+                let tag = sender_ctx.seal(&mut msg, b"").unwrap();
+                assert_eq!(
+                    receiver_ctx.open_binding_enc(&mut msg, b"", &tag),
+                    Err(HpkeError::NoEncappedKey)
+                );
             }
         };
     }
 
-    /// Tests that anything other than `export()` called on an `ExportOnly` context results in a
-    /// panic
-    macro_rules! test_exportonly_panics {
-        ($test_name1:ident, $test_name2:ident, $kem_ty:ty) => {
-            #[should_panic]
+    /// Tests that two messages salted at the same sequence number, as after a counter reset, get
+    /// different nonces and both open, and that the salt is authenticated
+    #[cfg(feature = "alloc")]
+    macro_rules! test_salted {
+        ($test_name:ident, $kem_ty:ty) => {
             #[test]
-            fn $test_name1() {
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
                 type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let (sender_ctx, receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+
+                // Simulate a counter reset by sealing from two copies of the same context
+                let (mut sender_a, mut sender_b) = (sender_ctx.clone(), sender_ctx);
+                let msg = b"same position, different nonce";
+                let (salt_a, ct_a) = sender_a.seal_salted(msg, b"aad", &mut csprng).unwrap();
+                let (salt_b, ct_b) = sender_b.seal_salted(msg, b"aad", &mut csprng).unwrap();
+                assert_ne!(salt_a, salt_b);
+                assert_ne!(ct_a, ct_b);
+
+                // The nonces differ from each other and from the unsalted nonce
+                let unsalted = receiver_ctx.0.current_nonce(None);
+                let nonce_a = receiver_ctx.0.current_nonce(Some(&salt_a));
+                let nonce_b = receiver_ctx.0.current_nonce(Some(&salt_b));
+                assert_ne!(nonce_a.0, nonce_b.0);
+                assert_ne!(nonce_a.0, unsalted.0);
+
+                // Both open at sequence number 0
+                let pt_a = receiver_ctx
+                    .clone()
+                    .open_salted(&salt_a, &ct_a, b"aad")
+                    .unwrap();
+                let pt_b = receiver_ctx
+                    .clone()
+                    .open_salted(&salt_b, &ct_b, b"aad")
+                    .unwrap();
+                assert_eq!(&pt_a, msg);
+                assert_eq!(&pt_b, msg);
+
+                // The wrong salt, a plain open, and a truncated ciphertext all fail
+                assert_eq!(
+                    receiver_ctx.clone().open_salted(&salt_b, &ct_a, b"aad"),
+                    Err(HpkeError::OpenError)
+                );
+                let tag_len = AeadTag::<A>::size();
+                let (ct, tag) = ct_a.split_at(ct_a.len() - tag_len);
+                let tag = AeadTag::<A>::from_bytes(tag).unwrap();
+                assert_eq!(
+                    receiver_ctx.clone().open(&mut ct.to_vec(), b"aad", &tag),
+                    Err(HpkeError::OpenError)
+                );
+                assert_eq!(
+                    receiver_ctx
+                        .clone()
+                        .open_salted(&salt_a, &ct_a[..tag_len - 1], b"aad"),
+                    Err(HpkeError::OpenError)
+                );
+
+                // The sequence number still advances, so a second message opens in order
+                let mut receiver_ctx = receiver_ctx;
+                receiver_ctx.open_salted(&salt_a, &ct_a, b"aad").unwrap();
+                let (salt, ct) = sender_a.seal_salted(b"next", b"", &mut csprng).unwrap();
+                assert_eq!(receiver_ctx.open_salted(&salt, &ct, b"").unwrap(), b"next");
+            }
+        };
+    }
+
+    /// Tests that a fresh timestamped message opens, that a stale one is rejected after being
+    /// authenticated, and that the timestamp can't be altered
+    #[cfg(feature = "alloc")]
+    macro_rules! test_timestamped {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
                 type Kdf = HkdfSha256;
-                type A = ExportOnlyAead;
+                type Kem = $kem_ty;
 
-                // Set up a context and try encrypting
-                let (mut sender_ctx, _) = gen_ctx_simple_pair::<A, Kdf, Kem>();
-                let mut plaintext = *b"back hand";
-                let _ = sender_ctx.seal(&mut plaintext[..], b"");
+                let (mut sender_ctx, receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let msg = b"read me soon";
+                let sent_at = 1_600_000_000u64;
+                let max_age = 300;
+                let buf = sender_ctx.seal_timestamped(msg, b"aad", sent_at).unwrap();
+                assert_eq!(&buf[..8], &sent_at.to_be_bytes());
+
+                // Fresh, including right at the edge of the window, and from slightly in the future
+                for &now in &[sent_at, sent_at + max_age, sent_at - 10] {
+                    let (timestamp, plaintext) = receiver_ctx
+                        .clone()
+                        .open_timestamped(&buf, b"aad", now, max_age)
+                        .unwrap();
+                    assert_eq!(timestamp, sent_at);
+                    assert_eq!(&plaintext, msg);
+                }
+
+                // Stale
+                let mut stale_receiver = receiver_ctx.clone();
+                assert_eq!(
+                    stale_receiver.open_timestamped(&buf, b"aad", sent_at + max_age + 1, max_age),
+                    Err(HpkeError::StaleMessage)
+                );
+                // The stale message was still opened, so the next message opens in order
+                let next = sender_ctx.seal_timestamped(b"next", b"", sent_at).unwrap();
+                assert!(stale_receiver
+                    .open_timestamped(&next, b"", sent_at, max_age)
+                    .is_ok());
+
+                // Making the message look newer breaks the tag
+                let mut tampered = buf.clone();
+                tampered[..8].copy_from_slice(&(sent_at + 1000).to_be_bytes());
+                assert_eq!(
+                    receiver_ctx.clone().open_timestamped(
+                        &tampered,
+                        b"aad",
+                        sent_at + 1000,
+                        max_age
+                    ),
+                    Err(HpkeError::OpenError)
+                );
+                // Too short to hold a timestamp and a tag
+                assert_eq!(
+                    receiver_ctx
+                        .clone()
+                        .open_timestamped(&buf[..8 + 15], b"aad", sent_at, max_age),
+                    Err(HpkeError::OpenError)
+                );
             }
+        };
+    }
 
-            #[should_panic]
+    /// Tests seal_to_slice and open_to_slice with output buffers that are exactly big enough,
+    /// too big, and one byte too small
+    macro_rules! test_to_slice {
+        ($test_name:ident, $kem_ty:ty) => {
             #[test]
-            fn $test_name2() {
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
                 type Kem = $kem_ty;
+
+                let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let tag_len = AeadTag::<A>::size();
+
+                let msg = b"separate buffers";
+                let ct_len = msg.len() + tag_len;
+
+                // One byte short fails without burning a sequence number
+                let mut short = [0u8; 16 + 16 - 1];
+                assert_eq!(
+                    sender_ctx.seal_to_slice(msg, b"aad", &mut short),
+                    Err(HpkeError::IncorrectInputLength {
+                        expected: ct_len,
+                        got: ct_len - 1
+                    })
+                );
+                assert_eq!(short, [0u8; 31]);
+
+                // Exactly the right size. This is also the first message, so the receiver can
+                // open it.
+                let mut exact = [0u8; 16 + 16];
+                assert_eq!(
+                    sender_ctx.seal_to_slice(msg, b"aad", &mut exact),
+                    Ok(ct_len)
+                );
+
+                // Opening into a buffer one byte short fails, also without burning a sequence
+                // number
+                let mut pt_short = [0u8; 16 - 1];
+                assert_eq!(
+                    receiver_ctx.open_to_slice(&exact, b"aad", &mut pt_short),
+                    Err(HpkeError::IncorrectInputLength {
+                        expected: msg.len(),
+                        got: msg.len() - 1
+                    })
+                );
+                let mut pt_exact = [0u8; 16];
+                assert_eq!(
+                    receiver_ctx.open_to_slice(&exact, b"aad", &mut pt_exact),
+                    Ok(msg.len())
+                );
+                assert_eq!(&pt_exact, msg);
+
+                // A bigger buffer only gets written at the start
+                let mut big = [0xffu8; 64];
+                assert_eq!(sender_ctx.seal_to_slice(msg, b"", &mut big), Ok(ct_len));
+                assert!(big[ct_len..].iter().all(|&b| b == 0xff));
+                let mut pt_big = [0xffu8; 64];
+                assert_eq!(
+                    receiver_ctx.open_to_slice(&big[..ct_len], b"", &mut pt_big),
+                    Ok(msg.len())
+                );
+                assert_eq!(&pt_big[..msg.len()], msg);
+
+                // Tampering is detected and the output is zeroed. Too-short ciphertexts are
+                // rejected.
+                sender_ctx.seal_to_slice(msg, b"", &mut exact).unwrap();
+                exact[0] ^= 1;
+                let mut pt = [0xffu8; 16];
+                assert_eq!(
+                    receiver_ctx.open_to_slice(&exact, b"", &mut pt),
+                    Err(HpkeError::OpenError)
+                );
+                assert_eq!(pt, [0u8; 16]);
+                assert_eq!(
+                    receiver_ctx.open_to_slice(&exact[..tag_len - 1], b"", &mut pt),
+                    Err(HpkeError::OpenError)
+                );
+            }
+        };
+    }
+
+    /// Tests that seal and open agree when both compute the AAD from the sequence number, and that
+    /// the closures see the sequence numbers in order
+    #[cfg(feature = "alloc")]
+    macro_rules! test_aad_fn {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
                 type Kdf = HkdfSha256;
-                type A = ExportOnlyAead;
+                type Kem = $kem_ty;
 
-                // Set up a context and try encrypting
-                let (_, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
-                let mut ciphertext = *b"back hand";
-                let aad = b"with my prayers";
-                // The contents of the tag doesn't matter. This will panic before the take is read
-                type TagSize = <<A as Aead>::AeadImpl as BaseAeadCore>::TagSize;
-                let tag = AeadTag(GenericArray::<u8, TagSize>::default());
+                let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+
+                // The AAD is epoch || seq
+                let epoch = 7u32;
+                let make_aad = |epoch: u32, seq: u64| {
+                    let mut aad = epoch.to_be_bytes().to_vec();
+                    aad.extend_from_slice(&seq.to_be_bytes());
+                    aad
+                };
 
-                let _ = receiver_ctx.open(&mut ciphertext[..], aad, &tag);
+                for expected_seq in 0..3u64 {
+                    let msg = b"tick tock";
+                    let mut ciphertext = *msg;
+                    let tag = sender_ctx
+                        .seal_with_aad_fn(&mut ciphertext, |seq| {
+                            assert_eq!(seq, expected_seq);
+                            make_aad(epoch, seq)
+                        })
+                        .unwrap();
+                    receiver_ctx
+                        .open_with_aad_fn(
+                            &mut ciphertext,
+                            |seq| {
+                                assert_eq!(seq, expected_seq);
+                                make_aad(epoch, seq)
+                            },
+                            &tag,
+                        )
+                        .unwrap();
+                    assert_eq!(&ciphertext, msg);
+                }
+
+                // A receiver in the wrong epoch can't open
+                let mut ciphertext = *b"tick tock";
+                let tag = sender_ctx
+                    .seal_with_aad_fn(&mut ciphertext, |seq| make_aad(epoch, seq))
+                    .unwrap();
+                assert_eq!(
+                    receiver_ctx.open_with_aad_fn(
+                        &mut ciphertext,
+                        |seq| make_aad(epoch + 1, seq),
+                        &tag
+                    ),
+                    Err(HpkeError::OpenError)
+                );
             }
         };
     }
 
-    /// Tests that sequence overflowing causes an error. This logic is cipher-agnostic, so we don't
-    /// make the test generic over ciphers.
-    macro_rules! test_overflow {
+    /// Tests that linked contexts compute the same confirmation tag for the same transcript, and
+    /// that a different transcript or an unlinked context gives a different tag
+    macro_rules! test_confirmation_tag {
         ($test_name:ident, $kem_ty:ty) => {
             #[test]
             fn $test_name() {
                 type Kem = $kem_ty;
                 type Kdf = HkdfSha256;
-                // Again, this test is cipher-agnostic
                 type A = ChaCha20Poly1305;
 
-                // Make a sequence number that's at the max
-                let big_seq = {
-                    let mut seq = <Seq as Default>::default();
-                    seq.0 = u64::MAX;
-                    seq
-                };
+                let (sender_ctx, receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let (_, other_receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let transcript = b"client hello || server hello";
 
-                let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
-                sender_ctx.0.seq = big_seq.clone();
-                receiver_ctx.0.seq = big_seq.clone();
+                let tag = sender_ctx.confirmation_tag(transcript);
+                assert_eq!(tag, receiver_ctx.confirmation_tag(transcript));
+                assert!(receiver_ctx.verify_confirmation(transcript, &tag).is_ok());
+                assert!(sender_ctx
+                    .verify_confirmation(transcript, &receiver_ctx.confirmation_tag(transcript))
+                    .is_ok());
 
-                // These should support precisely one more encryption before it registers an
-                // overflow
+                // Mismatched contexts and transcripts fail
+                assert_ne!(tag, other_receiver_ctx.confirmation_tag(transcript));
+                assert_eq!(
+                    other_receiver_ctx.verify_confirmation(transcript, &tag),
+                    Err(HpkeError::ValidationError)
+                );
+                assert_ne!(tag, sender_ctx.confirmation_tag(b"another transcript"));
+                let mut bad_tag = tag;
+                bad_tag[31] ^= 1;
+                assert_eq!(
+                    receiver_ctx.verify_confirmation(transcript, &bad_tag),
+                    Err(HpkeError::ValidationError)
+                );
 
-                let msg = b"draxx them sklounst";
-                let aad = b"with my prayers";
+                // The tag isn't any exported secret or the fingerprint
+                let mut exported = [0u8; 32];
+                sender_ctx.export(b"confirmation", &mut exported).unwrap();
+                assert_ne!(tag, exported);
+                assert_ne!(tag, sender_ctx.context_fingerprint());
+            }
+        };
+    }
 
-                // Do one round trip and ensure it works
-                {
-                    let mut plaintext = *msg;
-                    // Encrypt the plaintext
-                    let tag = sender_ctx
-                        .seal(&mut plaintext[..], aad)
-                        .expect("seal() failed");
-                    // Rename for clarity
-                    let mut ciphertext = plaintext;
+    /// Tests that linked sender and receiver contexts have the same fingerprint, and unlinked ones
+    /// don't
+    macro_rules! test_context_fingerprint {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kdf = HkdfSha256;
+                type A = ChaCha20Poly1305;
 
-                    // Now to decrypt on the other side
-                    receiver_ctx
-                        .open(&mut ciphertext[..], aad, &tag)
-                        .expect("open() failed");
-                    // Rename for clarity
-                    let roundtrip_plaintext = ciphertext;
+                let (sender_ctx, receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let (other_sender_ctx, other_receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
 
-                    // Make sure the output message was the same as the input message
-                    assert_eq!(msg, &roundtrip_plaintext);
-                }
+                assert_eq!(
+                    sender_ctx.context_fingerprint(),
+                    receiver_ctx.context_fingerprint()
+                );
+                assert_ne!(
+                    sender_ctx.context_fingerprint(),
+                    other_receiver_ctx.context_fingerprint()
+                );
+                assert_ne!(
+                    other_sender_ctx.context_fingerprint(),
+                    receiver_ctx.context_fingerprint()
+                );
 
-                // Try another round trip and ensure that we've overflowed
-                {
-                    let mut plaintext = *msg;
-                    // Try to encrypt the plaintext
-                    match sender_ctx.seal(&mut plaintext[..], aad) {
-                        Err(HpkeError::MessageLimitReached) => {
-                            // Good, this should have overflowed
-                        }
-                        Err(e) => panic!("seal() should have overflowed. Instead got {}", e),
-                        _ => panic!("seal() should have overflowed. Instead it succeeded"),
-                    }
+                // The fingerprint isn't any exported secret of the same length
+                let mut exported = [0u8; 32];
+                sender_ctx.export(b"fingerprint", &mut exported).unwrap();
+                assert_ne!(sender_ctx.context_fingerprint(), exported);
+                sender_ctx.export(b"", &mut exported).unwrap();
+                assert_ne!(sender_ctx.context_fingerprint(), exported);
+            }
+        };
+    }
 
-                    // Now try to decrypt something. This isn't a valid ciphertext or tag, but the
-                    // overflow should fail before the tag check fails.
-                    let mut placeholder_ciphertext = [0u8; 32];
-                    let placeholder_tag = AeadTag::from_bytes(&[0; 16]).unwrap();
+    /// Tests that linked sender and receiver contexts compute the same message ID for the same
+    /// sequence number, and that different sequence numbers and unlinked contexts give different
+    /// IDs
+    macro_rules! test_message_id {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kdf = HkdfSha256;
+                type A = ChaCha20Poly1305;
 
-                    match receiver_ctx.open(&mut placeholder_ciphertext[..], aad, &placeholder_tag)
-                    {
-                        Err(HpkeError::MessageLimitReached) => {
-                            // Good, this should have overflowed
-                        }
-                        Err(e) => panic!("open() should have overflowed. Instead got {}", e),
-                        _ => panic!("open() should have overflowed. Instead it succeeded"),
-                    }
+                let (mut sender_ctx, receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let (other_sender_ctx, _) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+
+                for &seq in &[0, 1, 2, u64::MAX] {
+                    assert_eq!(sender_ctx.message_id(seq), receiver_ctx.message_id(seq));
+                    assert_ne!(sender_ctx.message_id(seq), other_sender_ctx.message_id(seq));
                 }
+                assert_ne!(sender_ctx.message_id(0), sender_ctx.message_id(1));
+                assert_ne!(sender_ctx.message_id(1), sender_ctx.message_id(2));
+
+                // Sealing doesn't change the ID of any sequence number
+                let id = sender_ctx.message_id(0);
+                sender_ctx.seal(&mut [], b"").unwrap();
+                assert_eq!(sender_ctx.message_id(0), id);
+
+                // The ID isn't a prefix of the fingerprint or of an exported secret
+                assert_ne!(id[..], sender_ctx.context_fingerprint()[..16]);
+                let mut exported = [0u8; 16];
+                sender_ctx
+                    .export(&0u64.to_be_bytes(), &mut exported)
+                    .unwrap();
+                assert_ne!(id, exported);
             }
         };
     }
 
-    /// Tests that `open()` can decrypt things properly encrypted with `seal()`
-    macro_rules! test_ctx_correctness {
-        ($test_name:ident, $aead_ty:ty, $kem_ty:ty) => {
+    /// Tests that contexts still agree after both sides re-encapsulate, and that re-encapsulating
+    /// resets the sequence counter
+    macro_rules! test_reencapsulate {
+        ($test_name:ident, $kem_ty:ty) => {
             #[test]
             fn $test_name() {
-                type A = $aead_ty;
-                type Kdf = HkdfSha256;
                 type Kem = $kem_ty;
+                type Kdf = HkdfSha256;
+                type A = ChaCha20Poly1305;
 
-                let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let mut csprng = StdRng::from_entropy();
+                let info = b"the same info string every time";
 
-                let msg = b"Love it or leave it, you better gain way";
-                let aad = b"You better hit bull's eye, the kid don't play";
+                // Do an ordinary setup
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let (encapped_key, mut sender_ctx) =
+                    setup_sender::<A, Kdf, Kem, _>(&OpModeS::Base, &pk_recip, info, &mut csprng)
+                        .unwrap();
+                let mut receiver_ctx =
+                    setup_receiver::<A, Kdf, Kem>(&OpModeR::Base, &sk_recip, &encapped_key, info)
+                        .unwrap();
+                assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
 
-                // Encrypt with the sender context
-                let mut ciphertext = msg.clone();
-                let tag = sender_ctx
-                    .seal(&mut ciphertext[..], aad)
-                    .expect("seal() failed");
+                // Re-encapsulate on both sides. aead_ctx_eq advanced the sequence counters, so
+                // this should reset them.
+                let new_encapped_key = sender_ctx.reencapsulate(&pk_recip, &mut csprng).unwrap();
+                receiver_ctx
+                    .reencapsulate(&sk_recip, &new_encapped_key)
+                    .unwrap();
+                assert_eq!(sender_ctx.0.seq.0, 0);
+                assert_eq!(receiver_ctx.0.seq.0, 0);
 
-                // Make sure seal() isn't a no-op
-                assert!(&ciphertext[..] != &msg[..]);
+                // The two sides should still interoperate
+                assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
 
-                // Decrypt with the receiver context
+                // Re-encapsulating with the wrong encapped key should break interop
+                let _ = sender_ctx.reencapsulate(&pk_recip, &mut csprng).unwrap();
                 receiver_ctx
-                    .open(&mut ciphertext[..], aad, &tag)
-                    .expect("open() failed");
-                // Change name for clarity
-                let decrypted = ciphertext;
-                assert_eq!(&decrypted[..], &msg[..]);
+                    .reencapsulate(&sk_recip, &new_encapped_key)
+                    .unwrap();
+                assert!(!aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+            }
+        };
+    }
+
+    /// Tests that re-encapsulating a receiver context keeps its sender identity key and its
+    /// sequence number window
+    macro_rules! test_reencapsulate_keeps_receiver_state {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kdf = HkdfSha256;
+                type A = ChaCha20Poly1305;
+
+                let mut csprng = StdRng::from_entropy();
+                let info = b"who sent this";
+
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let (sender_mode, receiver_mode) = crate::test_util::new_op_mode_pair::<
+                    <Kem as KemTrait>::Kex,
+                    Kdf,
+                >(
+                    crate::test_util::OpModeKind::Auth, &[], &[]
+                );
+                let (encapped_key, mut sender_ctx) =
+                    setup_sender::<A, Kdf, Kem, _>(&sender_mode, &pk_recip, info, &mut csprng)
+                        .unwrap();
+                let mut receiver_ctx =
+                    setup_receiver::<A, Kdf, Kem>(&receiver_mode, &sk_recip, &encapped_key, info)
+                        .unwrap()
+                        .with_seq_window(2);
+                let pk_sender_bytes = receiver_ctx.sender_public_key().unwrap().to_bytes();
+
+                let new_encapped_key = sender_ctx.reencapsulate(&pk_recip, &mut csprng).unwrap();
+                receiver_ctx
+                    .reencapsulate(&sk_recip, &new_encapped_key)
+                    .unwrap();
+                assert_eq!(
+                    receiver_ctx.sender_public_key().map(|pk| pk.to_bytes()),
+                    Some(pk_sender_bytes)
+                );
+
+                // Drop two messages. The third still opens, since it's within the window.
+                sender_ctx.seal(&mut [], b"").unwrap();
+                sender_ctx.seal(&mut [], b"").unwrap();
+                let mut msg = *b"the third message";
+                let tag = sender_ctx.seal(&mut msg, b"").unwrap();
+                receiver_ctx.open_at_seq(2, &mut msg, b"", &tag).unwrap();
+                assert_eq!(&msg, b"the third message");
+            }
+        };
+    }
+
+    /// Tests that `suite_ids()` reports the algorithm IDs of the context's ciphersuite, and that
+    /// the `suite_id` cached at setup is the one computed from scratch
+    macro_rules! test_suite_ids {
+        ($test_name:ident, $kem_ty:ty, $kem_id:expr) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kdf = crate::kdf::HkdfSha384;
+                type A = AesGcm256;
+
+                let (sender_ctx, receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+
+                // draft11 §7.2: HKDF-SHA384 is 0x0002. draft11 §7.3: AES-256-GCM is 0x0002.
+                assert_eq!(sender_ctx.suite_ids(), ($kem_id, 0x0002, 0x0002));
+                assert_eq!(receiver_ctx.suite_ids(), ($kem_id, 0x0002, 0x0002));
+
+                let fresh_suite_id = crate::util::full_suite_id::<A, Kdf, Kem>();
+                assert_eq!(sender_ctx.0.suite_id, fresh_suite_id);
+                assert_eq!(receiver_ctx.0.suite_id, fresh_suite_id);
             }
         };
     }
@@ -612,17 +3941,71 @@ mod test {
     test_invalid_nonce!(test_invalid_nonce_aes256, AesGcm128);
     test_invalid_nonce!(test_invalid_nonce_chacha, ChaCha20Poly1305);
 
+    test_tag_as_ref!(test_tag_as_ref_aes128, AesGcm128);
+    test_tag_as_ref!(test_tag_as_ref_aes256, AesGcm256);
+    test_tag_as_ref!(test_tag_as_ref_chacha, ChaCha20Poly1305);
+
+    test_tag_array!(test_tag_array_aes128, AesGcm128);
+    test_tag_array!(test_tag_array_aes256, AesGcm256);
+
     #[cfg(feature = "x25519-dalek")]
     mod x25519_tests {
         use super::*;
 
         test_export_idempotence!(test_export_idempotence_x25519, crate::kem::X25519HkdfSha256);
-        test_exportonly_panics!(
-            test_exportonly_panics_x25519_seal,
-            test_exportonly_panics_x25519_open,
+        test_export_matches_hkdf!(
+            test_export_matches_hkdf_x25519,
             crate::kem::X25519HkdfSha256
         );
+        test_exportonly_errors!(test_exportonly_errors_x25519, crate::kem::X25519HkdfSha256);
         test_overflow!(test_overflow_x25519, crate::kem::X25519HkdfSha256);
+        test_failure_keeps_seq!(test_failure_keeps_seq_x25519, crate::kem::X25519HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_close!(test_close_x25519, crate::kem::X25519HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_stats!(test_stats_x25519, crate::kem::X25519HkdfSha256);
+        test_seal_limit!(test_seal_limit_x25519, crate::kem::X25519HkdfSha256);
+        test_open_at_seq!(test_open_at_seq_x25519, crate::kem::X25519HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_open_ct!(test_open_ct_x25519, crate::kem::X25519HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_in_place_buffer!(test_in_place_buffer_x25519, crate::kem::X25519HkdfSha256);
+        #[cfg(feature = "bytes")]
+        test_in_place_bytes!(test_in_place_bytes_x25519, crate::kem::X25519HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_export_at_seq!(test_export_at_seq_x25519, crate::kem::X25519HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_export_with_salt!(test_export_with_salt_x25519, crate::kem::X25519HkdfSha256);
+        test_bytes_sealed!(test_bytes_sealed_x25519, crate::kem::X25519HkdfSha256);
+        #[cfg(feature = "test-internals")]
+        test_nonce_log!(test_nonce_log_x25519, crate::kem::X25519HkdfSha256);
+        test_reencapsulate!(test_reencapsulate_x25519, crate::kem::X25519HkdfSha256);
+        test_reencapsulate_keeps_receiver_state!(
+            test_reencapsulate_keeps_receiver_state_x25519,
+            crate::kem::X25519HkdfSha256
+        );
+        test_to_slice!(test_to_slice_x25519, crate::kem::X25519HkdfSha256);
+        test_fixed!(test_fixed_x25519, crate::kem::X25519HkdfSha256);
+        test_context_fingerprint!(
+            test_context_fingerprint_x25519,
+            crate::kem::X25519HkdfSha256
+        );
+        test_message_id!(test_message_id_x25519, crate::kem::X25519HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_aad_fn!(test_aad_fn_x25519, crate::kem::X25519HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_padded!(test_padded_x25519, crate::kem::X25519HkdfSha256);
+        test_hmac!(test_hmac_x25519, crate::kem::X25519HkdfSha256);
+        test_confirmation_tag!(test_confirmation_tag_x25519, crate::kem::X25519HkdfSha256);
+        test_tag_embedding!(test_tag_embedding_x25519, crate::kem::X25519HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_salted!(test_salted_x25519, crate::kem::X25519HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_timestamped!(test_timestamped_x25519, crate::kem::X25519HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_binding_enc!(test_binding_enc_x25519, crate::kem::X25519HkdfSha256);
+        // draft11 §7.1: DHKEM(X25519, HKDF-SHA256) is 0x0020
+        test_suite_ids!(test_suite_ids_x25519, crate::kem::X25519HkdfSha256, 0x0020);
 
         test_ctx_correctness!(
             test_ctx_correctness_aes128_x25519,
@@ -641,17 +4024,75 @@ mod test {
         );
     }
 
+    // Make sure the software AES backend is actually exercised when it's forced
+    #[cfg(all(feature = "aes-force-soft", feature = "x25519-dalek"))]
+    mod aes_force_soft_tests {
+        use super::*;
+
+        test_ctx_correctness!(
+            test_ctx_correctness_aes128_force_soft,
+            AesGcm128,
+            crate::kem::X25519HkdfSha256
+        );
+        test_ctx_correctness!(
+            test_ctx_correctness_aes256_force_soft,
+            AesGcm256,
+            crate::kem::X25519HkdfSha256
+        );
+    }
+
     #[cfg(feature = "p256")]
     mod p256_tests {
         use super::*;
 
         test_export_idempotence!(test_export_idempotence_p256, crate::kem::DhP256HkdfSha256);
-        test_exportonly_panics!(
-            test_exportonly_panics_p256_seal,
-            test_exportonly_panics_p256_open,
+        test_export_matches_hkdf!(test_export_matches_hkdf_p256, crate::kem::DhP256HkdfSha256);
+        test_exportonly_errors!(test_exportonly_errors_p256, crate::kem::DhP256HkdfSha256);
+        test_overflow!(test_overflow_p256, crate::kem::DhP256HkdfSha256);
+        test_failure_keeps_seq!(test_failure_keeps_seq_p256, crate::kem::DhP256HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_close!(test_close_p256, crate::kem::DhP256HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_stats!(test_stats_p256, crate::kem::DhP256HkdfSha256);
+        test_seal_limit!(test_seal_limit_p256, crate::kem::DhP256HkdfSha256);
+        test_open_at_seq!(test_open_at_seq_p256, crate::kem::DhP256HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_open_ct!(test_open_ct_p256, crate::kem::DhP256HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_in_place_buffer!(test_in_place_buffer_p256, crate::kem::DhP256HkdfSha256);
+        #[cfg(feature = "bytes")]
+        test_in_place_bytes!(test_in_place_bytes_p256, crate::kem::DhP256HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_export_at_seq!(test_export_at_seq_p256, crate::kem::DhP256HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_export_with_salt!(test_export_with_salt_p256, crate::kem::DhP256HkdfSha256);
+        test_bytes_sealed!(test_bytes_sealed_p256, crate::kem::DhP256HkdfSha256);
+        #[cfg(feature = "test-internals")]
+        test_nonce_log!(test_nonce_log_p256, crate::kem::DhP256HkdfSha256);
+        test_reencapsulate!(test_reencapsulate_p256, crate::kem::DhP256HkdfSha256);
+        test_reencapsulate_keeps_receiver_state!(
+            test_reencapsulate_keeps_receiver_state_p256,
             crate::kem::DhP256HkdfSha256
         );
-        test_overflow!(test_overflow_p256, crate::kem::DhP256HkdfSha256);
+        test_to_slice!(test_to_slice_p256, crate::kem::DhP256HkdfSha256);
+        test_fixed!(test_fixed_p256, crate::kem::DhP256HkdfSha256);
+        test_context_fingerprint!(test_context_fingerprint_p256, crate::kem::DhP256HkdfSha256);
+        test_message_id!(test_message_id_p256, crate::kem::DhP256HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_aad_fn!(test_aad_fn_p256, crate::kem::DhP256HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_padded!(test_padded_p256, crate::kem::DhP256HkdfSha256);
+        test_hmac!(test_hmac_p256, crate::kem::DhP256HkdfSha256);
+        test_confirmation_tag!(test_confirmation_tag_p256, crate::kem::DhP256HkdfSha256);
+        test_tag_embedding!(test_tag_embedding_p256, crate::kem::DhP256HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_salted!(test_salted_p256, crate::kem::DhP256HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_timestamped!(test_timestamped_p256, crate::kem::DhP256HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_binding_enc!(test_binding_enc_p256, crate::kem::DhP256HkdfSha256);
+        // draft11 §7.1: DHKEM(P-256, HKDF-SHA256) is 0x0010
+        test_suite_ids!(test_suite_ids_p256, crate::kem::DhP256HkdfSha256, 0x0010);
 
         test_ctx_correctness!(
             test_ctx_correctness_aes128_p256,
@@ -10,6 +10,9 @@ impl Aead for AesGcm128 {
     const AEAD_ID: u16 = 0x0001;
 }
 
+// draft11 §7.3: Nk, Nn, and Nt of AES-128-GCM are 16, 12, and 16
+assert_aead_sizes!(AesGcm128, nk = 16, nn = 12, nt = 16);
+
 /// The implementation of AES-256-GCM
 pub struct AesGcm256 {}
 
@@ -19,3 +22,6 @@ impl Aead for AesGcm256 {
     // draft11 §7.3: AES-256-GCM
     const AEAD_ID: u16 = 0x0002;
 }
+
+// draft11 §7.3: Nk, Nn, and Nt of AES-256-GCM are 32, 12, and 16
+assert_aead_sizes!(AesGcm256, nk = 32, nn = 12, nt = 16);
@@ -9,3 +9,6 @@ impl Aead for ChaCha20Poly1305 {
     // draft11 §7.3: ChaCha20Poly1305
     const AEAD_ID: u16 = 0x0003;
 }
+
+// draft11 §7.3: Nk, Nn, and Nt of ChaCha20Poly1305 are 32, 12, and 16
+assert_aead_sizes!(ChaCha20Poly1305, nk = 32, nn = 12, nt = 16);
@@ -3,15 +3,17 @@ use crate::aead::Aead;
 use aead::{AeadCore as BaseAeadCore, AeadInPlace as BaseAeadInPlace, NewAead as BaseNewAead};
 use generic_array::typenum;
 
-/// An inert underlying Aead implementation. The open/seal routines panic. The `new()` function
-/// returns an `EmptyAeadImpl`, and that is all of the functionality this struct has.
+/// An inert underlying Aead implementation. The open/seal routines panic, but they are never
+/// called, since `AeadCtxS::seal` and `AeadCtxR::open` return `HpkeError::ExportOnly` first. The
+/// `new()` function returns an `EmptyAeadImpl`, and that is all of the functionality this struct
+/// has.
 #[doc(hidden)]
 #[derive(Clone)]
 pub struct EmptyAeadImpl;
 
 impl BaseAeadCore for EmptyAeadImpl {
-    // The nonce size has to be bigger than the sequence size (currently u64), otherwise we get an
-    // underflow error on seal()/open() before we can even panic
+    // The nonce size has to be at least the sequence size (currently u64), otherwise computing a
+    // nonce would underflow. This is checked by assert_aead_sizes below.
     type NonceSize = typenum::U128;
     type TagSize = typenum::U0;
     type CiphertextOverhead = typenum::U0;
@@ -48,8 +50,8 @@ impl BaseNewAead for EmptyAeadImpl {
 }
 
 /// An AEAD which can **only** be used for its `export()` function. The `open()` and `seal()`
-/// methods on an `AeadCtxR` or `AeadCtxS` which uses this AEAD underlyingly **will panic** if you
-/// call them
+/// methods on an `AeadCtxR` or `AeadCtxS` which uses this AEAD underlyingly return
+/// `Err(HpkeError::ExportOnly)`
 pub struct ExportOnlyAead;
 
 impl Aead for ExportOnlyAead {
@@ -58,3 +60,7 @@ impl Aead for ExportOnlyAead {
     // draft11 §7.3: Export-only
     const AEAD_ID: u16 = 0xFFFF;
 }
+
+// draft11 §7.3 doesn't define Nk, Nn, or Nt for export-only. But we still need the nonce to hold a
+// sequence number, so that the nonce arithmetic in AeadCtx is well-defined for every AEAD.
+assert_aead_sizes!(ExportOnlyAead);
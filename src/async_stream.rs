@@ -0,0 +1,340 @@
+//! Chunked encryption of `futures::Stream`s. This is gated under the `async` feature.
+//!
+//! `AeadCtxS::seal_async_stream` turns a stream of plaintext chunks into a stream of sealed
+//! records, one record per chunk, and `AeadCtxR::open_async_stream` turns the records back into
+//! the chunks. Each record has the same format as a record of `AeadCtxS::seal_stream` (see the
+//! `stream` module), so the concatenation of the records is a valid sealed stream. Unlike
+//! `seal_stream`, chunks can be any size. The crypto is still synchronous, and is done inside
+//! `poll_next`. So this is for plugging HPKE into async pipelines, not for offloading the work.
+//!
+//! Both streams end after they yield their first error.
+
+use crate::{
+    aead::{Aead, AeadCtxR, AeadCtxS, AeadTag},
+    kdf::Kdf as KdfTrait,
+    kem::Kem as KemTrait,
+    kex::{Deserializable, Serializable},
+    stream::{header, record_aad, HEADER_LEN},
+    HpkeError,
+};
+
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use futures_core::stream::Stream;
+use std::vec::Vec;
+
+/// A stream of sealed records, made by `AeadCtxS::seal_async_stream`
+pub struct SealStream<A: Aead, Kdf: KdfTrait, Kem: KemTrait, S> {
+    ctx: AeadCtxS<A, Kdf, Kem>,
+    inner: S,
+    aad: Vec<u8>,
+    /// The last chunk we got. We can't tell if it's the final chunk until the inner stream says
+    /// what comes after it.
+    pending: Option<Vec<u8>>,
+    done: bool,
+}
+
+/// A stream of opened chunks, made by `AeadCtxR::open_async_stream`
+pub struct OpenStream<A: Aead, Kdf: KdfTrait, Kem: KemTrait, S> {
+    ctx: AeadCtxR<A, Kdf, Kem>,
+    inner: S,
+    aad: Vec<u8>,
+    /// Whether we've opened the final record
+    saw_last: bool,
+    done: bool,
+}
+
+// Nothing is ever pinned structurally. The inner stream is Unpin, and the context is only ever
+// used through &mut. So these are Unpin even if the AEAD or KEM types aren't.
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait, S: Unpin> Unpin for SealStream<A, Kdf, Kem, S> {}
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait, S: Unpin> Unpin for OpenStream<A, Kdf, Kem, S> {}
+
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AeadCtxS<A, Kdf, Kem> {
+    /// Returns a stream which seals every chunk of `chunks` with this context, and yields the
+    /// resulting records, in order. See the module documentation for the record format. An empty
+    /// input still yields one record, so that the receiver can tell it wasn't truncated.
+    ///
+    /// Return Value
+    /// ============
+    /// The returned stream yields `Ok(record)` for every chunk. If a chunk is at least 2^32 bytes
+    /// long, it yields `Err(HpkeError::IncorrectInputLength { expected: u32::MAX, got: len })`. If
+    /// sealing fails, it yields the error that `AeadCtxS::seal` returned.
+    pub fn seal_async_stream<S>(self, chunks: S, aad: &[u8]) -> SealStream<A, Kdf, Kem, S>
+    where
+        S: Stream<Item = Vec<u8>> + Unpin,
+    {
+        SealStream {
+            ctx: self,
+            inner: chunks,
+            aad: aad.to_vec(),
+            pending: None,
+            done: false,
+        }
+    }
+}
+
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AeadCtxR<A, Kdf, Kem> {
+    /// Returns a stream which opens every record of `records` with this context, and yields the
+    /// resulting chunks, in order. Every item of `records` must be exactly one record, as yielded
+    /// by `AeadCtxS::seal_async_stream`.
+    ///
+    /// Each chunk is authenticated before it's yielded, but the stream as a whole is not
+    /// authenticated until the returned stream ends without an error. If it yields an error, the
+    /// chunks so far MUST be discarded.
+    ///
+    /// Return Value
+    /// ============
+    /// The returned stream yields `Ok(chunk)` for every record. If a record is malformed or comes
+    /// after the final record, it yields `Err(HpkeError::ValidationError)`. If a record fails to
+    /// open, it yields `Err(HpkeError::OpenError)`. If `records` ends before the final record,
+    /// i.e., it was truncated, it yields `Err(HpkeError::OpenError)`.
+    pub fn open_async_stream<S>(self, records: S, aad: &[u8]) -> OpenStream<A, Kdf, Kem, S>
+    where
+        S: Stream<Item = Vec<u8>> + Unpin,
+    {
+        OpenStream {
+            ctx: self,
+            inner: records,
+            aad: aad.to_vec(),
+            saw_last: false,
+            done: false,
+        }
+    }
+}
+
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait, S> SealStream<A, Kdf, Kem, S> {
+    /// Seals `chunk` into a record
+    fn seal_record(&mut self, mut chunk: Vec<u8>, is_last: bool) -> Result<Vec<u8>, HpkeError> {
+        if chunk.len() > u32::MAX as usize {
+            return Err(HpkeError::IncorrectInputLength {
+                expected: u32::MAX as usize,
+                got: chunk.len(),
+            });
+        }
+
+        let header = header(is_last, chunk.len() as u32);
+        let tag = self.ctx.seal(&mut chunk, &record_aad(&header, &self.aad))?;
+
+        let mut record = Vec::with_capacity(HEADER_LEN + chunk.len() + AeadTag::<A>::size());
+        record.extend_from_slice(&header);
+        record.extend_from_slice(&chunk);
+        record.extend_from_slice(&tag.to_bytes());
+        Ok(record)
+    }
+}
+
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait, S> OpenStream<A, Kdf, Kem, S> {
+    /// Opens `record` into a chunk
+    fn open_record(&mut self, mut record: Vec<u8>) -> Result<Vec<u8>, HpkeError> {
+        let tag_size = AeadTag::<A>::size();
+        if self.saw_last || record.len() < HEADER_LEN + tag_size {
+            return Err(HpkeError::ValidationError);
+        }
+
+        // Check the header against the record we actually got
+        let mut header = [0u8; HEADER_LEN];
+        header.copy_from_slice(&record[..HEADER_LEN]);
+        let is_last = match header[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(HpkeError::ValidationError),
+        };
+        let mut len_bytes = [0u8; 4];
+        len_bytes.copy_from_slice(&header[1..]);
+        let len = u32::from_be_bytes(len_bytes) as usize;
+        if len != record.len() - HEADER_LEN - tag_size {
+            return Err(HpkeError::ValidationError);
+        }
+
+        let tag = AeadTag::<A>::from_bytes(&record[HEADER_LEN + len..])?;
+        let ciphertext = &mut record[HEADER_LEN..HEADER_LEN + len];
+        self.ctx
+            .open(ciphertext, &record_aad(&header, &self.aad), &tag)?;
+        self.saw_last = is_last;
+
+        // Strip the header and tag off, leaving the plaintext
+        record.truncate(HEADER_LEN + len);
+        record.drain(..HEADER_LEN);
+        Ok(record)
+    }
+}
+
+impl<A, Kdf, Kem, S> Stream for SealStream<A, Kdf, Kem, S>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    S: Stream<Item = Vec<u8>> + Unpin,
+{
+    type Item = Result<Vec<u8>, HpkeError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+
+        loop {
+            let res = match Pin::new(&mut this.inner).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                // A new chunk means the pending one isn't the last, so it can be sealed. If there
+                // was no pending one, this is the first chunk, and we need to look ahead again.
+                Poll::Ready(Some(chunk)) => match this.pending.replace(chunk) {
+                    Some(prev) => this.seal_record(prev, false),
+                    None => continue,
+                },
+                Poll::Ready(None) => {
+                    this.done = true;
+                    let last = this.pending.take().unwrap_or_default();
+                    this.seal_record(last, true)
+                }
+            };
+
+            this.done |= res.is_err();
+            return Poll::Ready(Some(res));
+        }
+    }
+}
+
+impl<A, Kdf, Kem, S> Stream for OpenStream<A, Kdf, Kem, S>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    S: Stream<Item = Vec<u8>> + Unpin,
+{
+    type Item = Result<Vec<u8>, HpkeError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+
+        let res = match Pin::new(&mut this.inner).poll_next(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Some(record)) => this.open_record(record),
+            Poll::Ready(None) => {
+                this.done = true;
+                if this.saw_last {
+                    return Poll::Ready(None);
+                }
+                Err(HpkeError::OpenError)
+            }
+        };
+
+        this.done |= res.is_err();
+        Poll::Ready(Some(res))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        aead::ChaCha20Poly1305, kdf::HkdfSha256, test_util::gen_ctx_simple_pair, HpkeError,
+    };
+
+    use futures_util::{stream, FutureExt, StreamExt};
+    use std::vec::Vec;
+
+    /// Tests that chunks round-trip in order, that the records concatenate to a valid
+    /// `seal_stream` stream, and that reordered and truncated records are rejected
+    macro_rules! test_async_stream_roundtrip {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+
+                let aad = b"response body";
+                let chunks: Vec<Vec<u8>> = vec![
+                    b"first".to_vec(),
+                    b"".to_vec(),
+                    b"third, and longer".to_vec(),
+                    vec![0xab; 1000],
+                ];
+
+                // Seal and open everything. The streams are always ready, so polling them once
+                // is enough to run them to completion.
+                let seal_all = |chunks: Vec<Vec<u8>>| {
+                    let (sender_ctx, receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                    let records: Vec<Vec<u8>> = sender_ctx
+                        .seal_async_stream(stream::iter(chunks), aad)
+                        .map(Result::unwrap)
+                        .collect::<Vec<_>>()
+                        .now_or_never()
+                        .unwrap();
+                    (records, receiver_ctx)
+                };
+                let open_all =
+                    |records: Vec<Vec<u8>>, receiver_ctx: crate::aead::AeadCtxR<A, Kdf, Kem>| {
+                        receiver_ctx
+                            .open_async_stream(stream::iter(records), aad)
+                            .collect::<Vec<_>>()
+                            .now_or_never()
+                            .unwrap()
+                    };
+
+                let (records, receiver_ctx) = seal_all(chunks.clone());
+                assert_eq!(records.len(), chunks.len());
+                let opened: Vec<Vec<u8>> = open_all(records.clone(), receiver_ctx)
+                    .into_iter()
+                    .map(Result::unwrap)
+                    .collect();
+                assert_eq!(opened, chunks);
+
+                // The records concatenate to something open_stream accepts
+                let (records, mut receiver_ctx) = seal_all(chunks.clone());
+                let mut opened = Vec::new();
+                receiver_ctx
+                    .open_stream(&mut &records.concat()[..], &mut opened, aad, 1000)
+                    .unwrap();
+                assert_eq!(opened, chunks.concat());
+
+                // An empty stream is one final record
+                let (records, receiver_ctx) = seal_all(Vec::new());
+                assert_eq!(records.len(), 1);
+                let opened = open_all(records, receiver_ctx);
+                assert_eq!(opened, vec![Ok(Vec::new())]);
+
+                // Swapping two records fails at the first of them, and ends the stream
+                let (mut records, receiver_ctx) = seal_all(chunks.clone());
+                records.swap(1, 2);
+                let opened = open_all(records, receiver_ctx);
+                assert_eq!(
+                    opened,
+                    vec![Ok(chunks[0].clone()), Err(HpkeError::OpenError)]
+                );
+
+                // Dropping the final record is detected
+                let (mut records, receiver_ctx) = seal_all(chunks.clone());
+                records.pop();
+                let opened = open_all(records, receiver_ctx);
+                assert_eq!(opened.last(), Some(&Err(HpkeError::OpenError)));
+                assert_eq!(opened.len(), chunks.len());
+
+                // Anything after the final record is rejected
+                let (mut records, receiver_ctx) = seal_all(chunks.clone());
+                let extra = records[0].clone();
+                records.push(extra);
+                let opened = open_all(records, receiver_ctx);
+                assert_eq!(opened.last(), Some(&Err(HpkeError::ValidationError)));
+            }
+        };
+    }
+
+    #[cfg(feature = "x25519-dalek")]
+    test_async_stream_roundtrip!(
+        test_async_stream_roundtrip_x25519,
+        crate::kem::X25519HkdfSha256
+    );
+
+    #[cfg(feature = "p256")]
+    test_async_stream_roundtrip!(
+        test_async_stream_roundtrip_p256,
+        crate::kem::DhP256HkdfSha256
+    );
+}
@@ -0,0 +1,251 @@
+//! A sender-side cache for authenticated modes. In `Auth` and `AuthPsk` modes, the encapsulation
+//! includes the static-static DH `DH(skS, pkR)`, which is the same every time a sender talks to
+//! the same recipient. An `AuthSendCache` computes it once and reuses it, so only the ephemeral DH
+//! is done per session.
+
+use crate::{
+    aead::{Aead, AeadCtxS, AeadTag},
+    kdf::Kdf as KdfTrait,
+    kem::{self, EncappedKey, Kem as KemTrait},
+    kex::{KeyExchange, Serializable},
+    op_mode::{OpModeS, PskBundle},
+    setup::derive_enc_ctx,
+    trace, HpkeError,
+};
+
+use generic_array::GenericArray;
+use rand_core::{CryptoRng, RngCore};
+use zeroize::Zeroize;
+
+type KexResultBytes<Kem> = GenericArray<
+    u8,
+    <<<Kem as KemTrait>::Kex as KeyExchange>::KexResult as Serializable>::OutputSize,
+>;
+
+/// Holds a sender identity keypair, a recipient public key, and the precomputed static DH result
+/// between the two. The static DH result is zeroed on drop.
+pub struct AuthSendCache<Kem: KemTrait> {
+    /// The sender's identity keypair
+    sk_sender_id: <Kem::Kex as KeyExchange>::PrivateKey,
+    pk_sender_id: <Kem::Kex as KeyExchange>::PublicKey,
+    /// The recipient's public key
+    pk_recip: <Kem::Kex as KeyExchange>::PublicKey,
+    /// The serialized result of `DH(sk_sender_id, pk_recip)`
+    kex_res_identity: KexResultBytes<Kem>,
+}
+
+// Zero the static DH result on drop
+impl<Kem: KemTrait> Drop for AuthSendCache<Kem> {
+    fn drop(&mut self) {
+        self.kex_res_identity.zeroize();
+    }
+}
+
+impl<Kem: KemTrait> AuthSendCache<Kem> {
+    /// Precomputes the static DH result between the sender identity key `sk_sender_id` and the
+    /// recipient public key `pk_recip`
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(cache)` on success. If an error happened during key exchange, returns
+    /// `Err(HpkeError::EncapError)`.
+    pub fn new(
+        sk_sender_id: &<Kem::Kex as KeyExchange>::PrivateKey,
+        pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
+    ) -> Result<AuthSendCache<Kem>, HpkeError> {
+        let kex_res_identity =
+            Kem::Kex::kex(sk_sender_id, pk_recip).map_err(|_| HpkeError::EncapError)?;
+
+        Ok(AuthSendCache {
+            sk_sender_id: sk_sender_id.clone(),
+            pk_sender_id: Kem::Kex::sk_to_pk(sk_sender_id),
+            pk_recip: pk_recip.clone(),
+            kex_res_identity: kex_res_identity.to_bytes(),
+        })
+    }
+
+    /// Does `setup_sender` to the cached recipient, in `Auth` mode if `psk` is `None`, and
+    /// `AuthPsk` mode otherwise. The output is identical to what `setup_sender` would give with
+    /// the same mode and RNG.
+    ///
+    /// Return Value
+    /// ============
+    /// On success, returns an encapsulated public key (intended to be sent to the recipient), and
+    /// an encryption context. If an error happened during key encapsulation, returns
+    /// `Err(HpkeError::EncapError)`. This is the only possible error.
+    pub fn setup_sender<A, Kdf, R>(
+        &self,
+        psk: Option<PskBundle>,
+        info: &[u8],
+        csprng: &mut R,
+    ) -> Result<(EncappedKey<Kem::Kex>, AeadCtxS<A, Kdf, Kem>), HpkeError>
+    where
+        A: Aead,
+        Kdf: KdfTrait,
+        R: CryptoRng + RngCore,
+    {
+        // The mode is only used for its mode ID and PSK here. The identity keypair in it is not
+        // used, since the DH involving it is already cached.
+        let keypair = (&self.sk_sender_id, &self.pk_sender_id);
+        let mode = match psk {
+            Some(bundle) => OpModeS::AuthPskBorrowed(keypair, bundle),
+            None => OpModeS::AuthBorrowed(keypair),
+        };
+
+        let suite_ids = (Kem::KEM_ID, Kdf::KDF_ID, A::AEAD_ID);
+        trace::setup_start("sender", suite_ids);
+
+        // Do the encapsulation with a fresh ephemeral key and the cached static DH result
+        let encap_res = kem::gen_eph_privkey::<Kem, R>(csprng).and_then(|sk_eph| {
+            kem::encap_with_eph_and_static::<Kem>(
+                &self.pk_recip,
+                Some((&self.pk_sender_id, self.kex_res_identity.as_slice())),
+                sk_eph,
+                &[],
+            )
+        });
+        trace::setup_end("sender", suite_ids, &encap_res);
+        let (shared_secret, encapped_key) = encap_res?;
+        // Use everything to derive an encryption context
+        let mut enc_ctx = derive_enc_ctx::<_, _, Kem, _>(&mode, shared_secret, info);
+        enc_ctx.bind_enc(&encapped_key);
+
+        Ok((encapped_key, enc_ctx.into()))
+    }
+
+    /// Does `setup_sender` and `AeadCtxS::seal` in one shot, like `single_shot_seal`
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok((encapped_key, auth_tag))` on success. If an error happened during key
+    /// encapsulation, returns `Err(HpkeError::EncapError)`. If an error happened during
+    /// encryption, returns `Err(HpkeError::SealError)`. In this case, the contents of `plaintext`
+    /// is undefined.
+    pub fn seal_to<A, Kdf, R>(
+        &self,
+        psk: Option<PskBundle>,
+        info: &[u8],
+        plaintext: &mut [u8],
+        aad: &[u8],
+        csprng: &mut R,
+    ) -> Result<(EncappedKey<Kem::Kex>, AeadTag<A>), HpkeError>
+    where
+        A: Aead,
+        Kdf: KdfTrait,
+        R: CryptoRng + RngCore,
+    {
+        // Encap a key
+        let (encapped_key, mut aead_ctx) = self.setup_sender::<A, Kdf, R>(psk, info, csprng)?;
+        // Encrypt
+        let tag = aead_ctx.seal(plaintext, aad)?;
+
+        Ok((encapped_key, tag))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AuthSendCache;
+    use crate::{
+        aead::ChaCha20Poly1305,
+        kdf::HkdfSha256,
+        kem::Kem as KemTrait,
+        kex::Serializable,
+        op_mode::{OpModeR, OpModeS, PskBundle},
+        setup::setup_sender,
+        single_shot::single_shot_open,
+        test_util::gen_rand_buf,
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// Tests that the cached auth path gives exactly the same output as the uncached one, and that
+    /// the recipient can open what it seals
+    macro_rules! test_auth_send_cache {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+
+                let info = b"the usual";
+                let (psk, psk_id) = (gen_rand_buf(), gen_rand_buf());
+                let psk_bundle = PskBundle {
+                    psk: &psk,
+                    psk_id: &psk_id,
+                };
+
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let (sk_sender, pk_sender) = Kem::gen_keypair(&mut csprng);
+                let cache = AuthSendCache::<Kem>::new(&sk_sender, &pk_recip).unwrap();
+
+                let uncached_modes = [
+                    (None, OpModeS::AuthBorrowed((&sk_sender, &pk_sender))),
+                    (
+                        Some(psk_bundle),
+                        OpModeS::AuthPskBorrowed((&sk_sender, &pk_sender), psk_bundle),
+                    ),
+                ];
+                for (psk, uncached_mode) in uncached_modes.iter() {
+                    // Use identically seeded RNGs so that the ephemeral keys are the same
+                    let (cached_encapped_key, mut cached_ctx) = cache
+                        .setup_sender::<A, Kdf, _>(
+                            *psk,
+                            &info[..],
+                            &mut StdRng::seed_from_u64(0xcafe),
+                        )
+                        .unwrap();
+                    let (uncached_encapped_key, mut uncached_ctx) = setup_sender::<A, Kdf, Kem, _>(
+                        uncached_mode,
+                        &pk_recip,
+                        &info[..],
+                        &mut StdRng::seed_from_u64(0xcafe),
+                    )
+                    .unwrap();
+                    assert_eq!(
+                        cached_encapped_key.to_bytes(),
+                        uncached_encapped_key.to_bytes()
+                    );
+
+                    // Identical contexts give identical ciphertexts and tags
+                    let mut cached_msg = *b"same as it ever was";
+                    let mut uncached_msg = cached_msg;
+                    let cached_tag = cached_ctx.seal(&mut cached_msg, b"").unwrap();
+                    let uncached_tag = uncached_ctx.seal(&mut uncached_msg, b"").unwrap();
+                    assert_eq!(cached_msg, uncached_msg);
+                    assert_eq!(cached_tag.to_bytes(), uncached_tag.to_bytes());
+
+                    // And the receiver can open a one-shot message from the cache
+                    let receiver_mode = match psk {
+                        Some(bundle) => OpModeR::AuthPsk(pk_sender.clone(), *bundle),
+                        None => OpModeR::Auth(pk_sender.clone()),
+                    };
+                    let mut msg = *b"open sesame";
+                    let (encapped_key, tag) = cache
+                        .seal_to::<A, Kdf, _>(*psk, &info[..], &mut msg, b"aad", &mut csprng)
+                        .unwrap();
+                    single_shot_open::<A, Kdf, Kem>(
+                        &receiver_mode,
+                        &sk_recip,
+                        &encapped_key,
+                        &info[..],
+                        &mut msg,
+                        b"aad",
+                        &tag,
+                    )
+                    .unwrap();
+                    assert_eq!(&msg, b"open sesame");
+                }
+            }
+        };
+    }
+
+    #[cfg(feature = "x25519-dalek")]
+    test_auth_send_cache!(test_auth_send_cache_x25519, crate::kem::X25519HkdfSha256);
+
+    #[cfg(feature = "p256")]
+    test_auth_send_cache!(test_auth_send_cache_p256, crate::kem::DhP256HkdfSha256);
+}
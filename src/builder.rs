@@ -0,0 +1,403 @@
+//! Builders for `setup_sender` and `setup_receiver`. These take the same parameters as the setup
+//! functions, but by name, and check that the parameters given are consistent with the mode.
+//!
+//! ```
+//! # #[cfg(feature = "x25519")]
+//! # {
+//! # use rand::{rngs::StdRng, SeedableRng};
+//! # use hpke::{
+//! #     aead::ChaCha20Poly1305, kdf::HkdfSha256, kem::X25519HkdfSha256, Kem as KemTrait,
+//! #     ReceiverBuilder, SenderBuilder, SetupMode,
+//! # };
+//! # let mut csprng = StdRng::from_entropy();
+//! let (sk_recip, pk_recip) = X25519HkdfSha256::gen_keypair(&mut csprng);
+//! let (encapped_key, mut sender_ctx) = SenderBuilder::<X25519HkdfSha256, _>::new()
+//!     .mode(SetupMode::Base)
+//!     .recipient(&pk_recip)
+//!     .info(b"my protocol v1")
+//!     .rng(&mut csprng)
+//!     .build::<ChaCha20Poly1305, HkdfSha256>()
+//!     .unwrap();
+//! let mut receiver_ctx = ReceiverBuilder::<X25519HkdfSha256>::new()
+//!     .mode(SetupMode::Base)
+//!     .secret_key(&sk_recip)
+//!     .encapped_key(&encapped_key)
+//!     .info(b"my protocol v1")
+//!     .build::<ChaCha20Poly1305, HkdfSha256>()
+//!     .unwrap();
+//! # }
+//! ```
+
+use crate::{
+    aead::{Aead, AeadCtxR, AeadCtxS},
+    kdf::Kdf as KdfTrait,
+    kem::{EncappedKey, Kem as KemTrait},
+    kex::KeyExchange,
+    op_mode::{OpModeR, OpModeS, PskBundle},
+    setup::{setup_receiver, setup_sender},
+    HpkeError,
+};
+
+use rand_core::{CryptoRng, RngCore};
+
+type KemPubkey<Kem> = <<Kem as KemTrait>::Kex as KeyExchange>::PublicKey;
+type KemPrivkey<Kem> = <<Kem as KemTrait>::Kex as KeyExchange>::PrivateKey;
+
+/// The mode to set up a context in. Unlike `OpModeS` and `OpModeR`, this carries no keys. Those
+/// are given to the builders separately.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SetupMode {
+    /// No PSK and no sender identity
+    Base,
+    /// A PSK and no sender identity
+    Psk,
+    /// A sender identity and no PSK
+    Auth,
+    /// A PSK and a sender identity
+    AuthPsk,
+}
+
+impl SetupMode {
+    /// Checks that a PSK and sender identity are given iff this mode calls for them
+    pub(crate) fn check(self, has_psk: bool, has_sender_id: bool) -> Result<(), HpkeError> {
+        let (wants_psk, wants_sender_id) = match self {
+            SetupMode::Base => (false, false),
+            SetupMode::Psk => (true, false),
+            SetupMode::Auth => (false, true),
+            SetupMode::AuthPsk => (true, true),
+        };
+
+        match (wants_psk, has_psk, wants_sender_id, has_sender_id) {
+            (true, false, _, _) => Err(HpkeError::InvalidSetupParameters("mode requires a PSK")),
+            (false, true, _, _) => Err(HpkeError::InvalidSetupParameters(
+                "mode does not take a PSK",
+            )),
+            (_, _, true, false) => Err(HpkeError::InvalidSetupParameters(
+                "mode requires a sender identity",
+            )),
+            (_, _, false, true) => Err(HpkeError::InvalidSetupParameters(
+                "mode does not take a sender identity",
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Collects the parameters of `setup_sender`. The mode, recipient, and RNG are required. The info
+/// string defaults to empty. For more information on the parameters, see `setup_sender`.
+pub struct SenderBuilder<'a, Kem: KemTrait, R: CryptoRng + RngCore> {
+    mode: Option<SetupMode>,
+    pk_recip: Option<&'a KemPubkey<Kem>>,
+    info: &'a [u8],
+    psk: Option<PskBundle<'a>>,
+    sender_id_keypair: Option<(&'a KemPrivkey<Kem>, &'a KemPubkey<Kem>)>,
+    csprng: Option<&'a mut R>,
+}
+
+impl<'a, Kem: KemTrait, R: CryptoRng + RngCore> Default for SenderBuilder<'a, Kem, R> {
+    fn default() -> SenderBuilder<'a, Kem, R> {
+        SenderBuilder::new()
+    }
+}
+
+impl<'a, Kem: KemTrait, R: CryptoRng + RngCore> SenderBuilder<'a, Kem, R> {
+    /// Makes a builder with no parameters set
+    pub fn new() -> SenderBuilder<'a, Kem, R> {
+        SenderBuilder {
+            mode: None,
+            pk_recip: None,
+            info: &[],
+            psk: None,
+            sender_id_keypair: None,
+            csprng: None,
+        }
+    }
+
+    /// Sets the mode. This is required.
+    pub fn mode(mut self, mode: SetupMode) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Sets the recipient public key. This is required.
+    pub fn recipient(mut self, pk_recip: &'a KemPubkey<Kem>) -> Self {
+        self.pk_recip = Some(pk_recip);
+        self
+    }
+
+    /// Sets the info string. This defaults to the empty string.
+    pub fn info(mut self, info: &'a [u8]) -> Self {
+        self.info = info;
+        self
+    }
+
+    /// Sets the PSK. This is required in `Psk` and `AuthPsk` modes, and forbidden otherwise.
+    pub fn psk(mut self, psk: PskBundle<'a>) -> Self {
+        self.psk = Some(psk);
+        self
+    }
+
+    /// Sets the sender identity keypair. This is required in `Auth` and `AuthPsk` modes, and
+    /// forbidden otherwise.
+    pub fn sender_id(
+        mut self,
+        sk_sender_id: &'a KemPrivkey<Kem>,
+        pk_sender_id: &'a KemPubkey<Kem>,
+    ) -> Self {
+        self.sender_id_keypair = Some((sk_sender_id, pk_sender_id));
+        self
+    }
+
+    /// Sets the RNG that the ephemeral key is generated with. This is required.
+    pub fn rng(mut self, csprng: &'a mut R) -> Self {
+        self.csprng = Some(csprng);
+        self
+    }
+
+    /// Does `setup_sender` with the collected parameters
+    ///
+    /// Return Value
+    /// ============
+    /// On success, returns an encapsulated public key (intended to be sent to the recipient), and
+    /// an encryption context. If a required parameter is missing, or the PSK or sender identity
+    /// don't match the mode, returns `Err(HpkeError::InvalidSetupParameters(reason))`. Otherwise,
+    /// errors are the same as in `setup_sender`.
+    pub fn build<A: Aead, Kdf: KdfTrait>(
+        self,
+    ) -> Result<(EncappedKey<Kem::Kex>, AeadCtxS<A, Kdf, Kem>), HpkeError> {
+        let mode = self
+            .mode
+            .ok_or(HpkeError::InvalidSetupParameters("mode is missing"))?;
+        let pk_recip = self
+            .pk_recip
+            .ok_or(HpkeError::InvalidSetupParameters("recipient is missing"))?;
+        let csprng = self
+            .csprng
+            .ok_or(HpkeError::InvalidSetupParameters("rng is missing"))?;
+        mode.check(self.psk.is_some(), self.sender_id_keypair.is_some())?;
+
+        // The check above makes sure the unwraps succeed
+        let op_mode = match mode {
+            SetupMode::Base => OpModeS::Base,
+            SetupMode::Psk => OpModeS::Psk(self.psk.unwrap()),
+            SetupMode::Auth => OpModeS::AuthBorrowed(self.sender_id_keypair.unwrap()),
+            SetupMode::AuthPsk => {
+                OpModeS::AuthPskBorrowed(self.sender_id_keypair.unwrap(), self.psk.unwrap())
+            }
+        };
+
+        setup_sender::<A, Kdf, Kem, R>(&op_mode, pk_recip, self.info, csprng)
+    }
+}
+
+/// Collects the parameters of `setup_receiver`. The mode, secret key, and encapsulated key are
+/// required. The info string defaults to empty. For more information on the parameters, see
+/// `setup_receiver`.
+pub struct ReceiverBuilder<'a, Kem: KemTrait> {
+    mode: Option<SetupMode>,
+    sk_recip: Option<&'a KemPrivkey<Kem>>,
+    encapped_key: Option<&'a EncappedKey<Kem::Kex>>,
+    info: &'a [u8],
+    psk: Option<PskBundle<'a>>,
+    pk_sender_id: Option<&'a KemPubkey<Kem>>,
+}
+
+impl<'a, Kem: KemTrait> Default for ReceiverBuilder<'a, Kem> {
+    fn default() -> ReceiverBuilder<'a, Kem> {
+        ReceiverBuilder::new()
+    }
+}
+
+impl<'a, Kem: KemTrait> ReceiverBuilder<'a, Kem> {
+    /// Makes a builder with no parameters set
+    pub fn new() -> ReceiverBuilder<'a, Kem> {
+        ReceiverBuilder {
+            mode: None,
+            sk_recip: None,
+            encapped_key: None,
+            info: &[],
+            psk: None,
+            pk_sender_id: None,
+        }
+    }
+
+    /// Sets the mode. This is required.
+    pub fn mode(mut self, mode: SetupMode) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Sets the recipient secret key. This is required.
+    pub fn secret_key(mut self, sk_recip: &'a KemPrivkey<Kem>) -> Self {
+        self.sk_recip = Some(sk_recip);
+        self
+    }
+
+    /// Sets the encapsulated key that the sender sent. This is required.
+    pub fn encapped_key(mut self, encapped_key: &'a EncappedKey<Kem::Kex>) -> Self {
+        self.encapped_key = Some(encapped_key);
+        self
+    }
+
+    /// Sets the info string. This defaults to the empty string.
+    pub fn info(mut self, info: &'a [u8]) -> Self {
+        self.info = info;
+        self
+    }
+
+    /// Sets the PSK. This is required in `Psk` and `AuthPsk` modes, and forbidden otherwise.
+    pub fn psk(mut self, psk: PskBundle<'a>) -> Self {
+        self.psk = Some(psk);
+        self
+    }
+
+    /// Sets the sender identity public key. This is required in `Auth` and `AuthPsk` modes, and
+    /// forbidden otherwise.
+    pub fn sender_id(mut self, pk_sender_id: &'a KemPubkey<Kem>) -> Self {
+        self.pk_sender_id = Some(pk_sender_id);
+        self
+    }
+
+    /// Does `setup_receiver` with the collected parameters
+    ///
+    /// Return Value
+    /// ============
+    /// On success, returns a decryption context. If a required parameter is missing, or the PSK
+    /// or sender identity don't match the mode, returns
+    /// `Err(HpkeError::InvalidSetupParameters(reason))`. Otherwise, errors are the same as in
+    /// `setup_receiver`.
+    pub fn build<A: Aead, Kdf: KdfTrait>(self) -> Result<AeadCtxR<A, Kdf, Kem>, HpkeError> {
+        let mode = self
+            .mode
+            .ok_or(HpkeError::InvalidSetupParameters("mode is missing"))?;
+        let sk_recip = self
+            .sk_recip
+            .ok_or(HpkeError::InvalidSetupParameters("secret key is missing"))?;
+        let encapped_key = self
+            .encapped_key
+            .ok_or(HpkeError::InvalidSetupParameters("encapped key is missing"))?;
+        mode.check(self.psk.is_some(), self.pk_sender_id.is_some())?;
+
+        // The check above makes sure the unwraps succeed
+        let op_mode = match mode {
+            SetupMode::Base => OpModeR::Base,
+            SetupMode::Psk => OpModeR::Psk(self.psk.unwrap()),
+            SetupMode::Auth => OpModeR::Auth(self.pk_sender_id.unwrap().clone()),
+            SetupMode::AuthPsk => {
+                OpModeR::AuthPsk(self.pk_sender_id.unwrap().clone(), self.psk.unwrap())
+            }
+        };
+
+        setup_receiver::<A, Kdf, Kem>(&op_mode, sk_recip, encapped_key, self.info)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ReceiverBuilder, SenderBuilder, SetupMode};
+    use crate::{
+        aead::ChaCha20Poly1305, kdf::HkdfSha256, kem::Kem as KemTrait, op_mode::PskBundle,
+        test_util::aead_ctx_eq, HpkeError,
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// Tests that base-mode and auth-psk-mode builds give agreeing contexts, and that inconsistent
+    /// or missing parameters are caught
+    macro_rules! test_builders {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let info = b"built, not bought";
+                let psk_bundle = PskBundle::new(&[0xab; 32], b"the id").unwrap();
+
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let (sk_sender, pk_sender) = Kem::gen_keypair(&mut csprng);
+
+                // Base mode
+                let (encapped_key, mut sender_ctx) = SenderBuilder::<Kem, _>::new()
+                    .mode(SetupMode::Base)
+                    .recipient(&pk_recip)
+                    .info(info)
+                    .rng(&mut csprng)
+                    .build::<A, Kdf>()
+                    .unwrap();
+                let mut receiver_ctx = ReceiverBuilder::<Kem>::new()
+                    .mode(SetupMode::Base)
+                    .secret_key(&sk_recip)
+                    .encapped_key(&encapped_key)
+                    .info(info)
+                    .build::<A, Kdf>()
+                    .unwrap();
+                assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+
+                // AuthPsk mode
+                let (encapped_key, mut sender_ctx) = SenderBuilder::<Kem, _>::new()
+                    .mode(SetupMode::AuthPsk)
+                    .recipient(&pk_recip)
+                    .info(info)
+                    .psk(psk_bundle)
+                    .sender_id(&sk_sender, &pk_sender)
+                    .rng(&mut csprng)
+                    .build::<A, Kdf>()
+                    .unwrap();
+                let mut receiver_ctx = ReceiverBuilder::<Kem>::new()
+                    .mode(SetupMode::AuthPsk)
+                    .secret_key(&sk_recip)
+                    .encapped_key(&encapped_key)
+                    .info(info)
+                    .psk(psk_bundle)
+                    .sender_id(&pk_sender)
+                    .build::<A, Kdf>()
+                    .unwrap();
+                assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+
+                // Psk mode without a PSK
+                let res = SenderBuilder::<Kem, _>::new()
+                    .mode(SetupMode::Psk)
+                    .recipient(&pk_recip)
+                    .rng(&mut csprng)
+                    .build::<A, Kdf>();
+                assert_eq!(
+                    res.err(),
+                    Some(HpkeError::InvalidSetupParameters("mode requires a PSK"))
+                );
+
+                // Base mode with a sender identity
+                let res = ReceiverBuilder::<Kem>::new()
+                    .mode(SetupMode::Base)
+                    .secret_key(&sk_recip)
+                    .encapped_key(&encapped_key)
+                    .sender_id(&pk_sender)
+                    .build::<A, Kdf>();
+                assert_eq!(
+                    res.err(),
+                    Some(HpkeError::InvalidSetupParameters(
+                        "mode does not take a sender identity"
+                    ))
+                );
+
+                // No recipient
+                let res = SenderBuilder::<Kem, _>::new()
+                    .mode(SetupMode::Base)
+                    .rng(&mut csprng)
+                    .build::<A, Kdf>();
+                assert_eq!(
+                    res.err(),
+                    Some(HpkeError::InvalidSetupParameters("recipient is missing"))
+                );
+            }
+        };
+    }
+
+    #[cfg(feature = "x25519-dalek")]
+    test_builders!(test_builders_x25519, crate::kem::X25519HkdfSha256);
+
+    #[cfg(feature = "p256")]
+    test_builders!(test_builders_p256, crate::kem::DhP256HkdfSha256);
+}
@@ -0,0 +1,390 @@
+//! Combining the exporter secrets of several HPKE contexts into a single key. This is for schemes
+//! where one party has independent sessions with several peers, and wants a session key that
+//! depends on all of them. This also has `ContextDeriver`, which goes the other way, and splits
+//! one context into many child contexts.
+
+use crate::{
+    aead::{Aead, AeadCtx, AeadCtxR, AeadCtxS, AeadKey, AeadNonce},
+    kdf::{labeled_extract, Kdf as KdfTrait, LabeledExpand},
+    kem::Kem as KemTrait,
+    setup::{AuthExporterCtx, ExporterSecret},
+    HpkeError,
+};
+
+use alloc::vec::Vec;
+use byteorder::{BigEndian, ByteOrder};
+use digest::FixedOutput;
+use generic_array::typenum::Unsigned;
+use zeroize::Zeroize;
+
+/// Anything that can export secrets. This is implemented by `AeadCtxS`, `AeadCtxR`, and
+/// `AuthExporterCtx`, so that sender and receiver contexts can be combined together.
+pub trait ExporterContext {
+    /// Fills `out_buf` with secret bytes derived from this context and `exporter_ctx`
+    fn export(&self, exporter_ctx: &[u8], out_buf: &mut [u8]) -> Result<(), HpkeError>;
+
+    /// Derives an `out_len`-byte key for `label` in the domain of a different KDF, `OtherKdf`.
+    /// This is for a protocol layered on top of HPKE that uses its own KDF. This context exports
+    /// `Nh` bytes of `OtherKdf`, under an exporter context that includes `OtherKdf`'s ID and
+    /// `label`. The result is then `LabeledExtract`ed and `LabeledExpand`ed with `OtherKdf`, under
+    /// the suite ID `"XKDF" || I2OSP(kdf_id, 2)`.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(key)` on success. If `out_len` is more than 255x the digest size of
+    /// `OtherKdf`'s hash function, returns `Err(HpkeError::KdfOutputTooLong)`. If this context
+    /// fails to export, returns that error.
+    fn export_into_kdf<OtherKdf: KdfTrait>(
+        &self,
+        label: &[u8],
+        out_len: usize,
+    ) -> Result<Vec<u8>, HpkeError>
+    where
+        Self: Sized,
+    {
+        let suite_id = kdf_upgrade_suite_id::<OtherKdf>();
+
+        // The exporter context is suite_id || label. The suite ID is fixed-length, so this is
+        // unambiguous.
+        let mut exporter_ctx = Vec::with_capacity(suite_id.len() + label.len());
+        exporter_ctx.extend_from_slice(&suite_id);
+        exporter_ctx.extend_from_slice(label);
+        let mut ikm = vec![0u8; OtherKdf::N_H];
+        self.export(&exporter_ctx, &mut ikm)?;
+
+        let (mut prk, hkdf_ctx) =
+            labeled_extract::<OtherKdf>(&[], &suite_id, b"upgraded_prk", &ikm);
+        ikm.zeroize();
+        prk.zeroize();
+        let mut out = vec![0u8; out_len];
+        hkdf_ctx
+            .labeled_expand(&suite_id, b"upgraded", label, &mut out)
+            .map_err(|_| HpkeError::KdfOutputTooLong)?;
+
+        Ok(out)
+    }
+}
+
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> ExporterContext for AeadCtxS<A, Kdf, Kem> {
+    fn export(&self, exporter_ctx: &[u8], out_buf: &mut [u8]) -> Result<(), HpkeError> {
+        AeadCtxS::export(self, exporter_ctx, out_buf)
+    }
+}
+
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> ExporterContext for AeadCtxR<A, Kdf, Kem> {
+    fn export(&self, exporter_ctx: &[u8], out_buf: &mut [u8]) -> Result<(), HpkeError> {
+        AeadCtxR::export(self, exporter_ctx, out_buf)
+    }
+}
+
+impl<Kdf: KdfTrait, Kem: KemTrait> ExporterContext for AuthExporterCtx<Kdf, Kem> {
+    fn export(&self, exporter_ctx: &[u8], out_buf: &mut [u8]) -> Result<(), HpkeError> {
+        AuthExporterCtx::export(self, exporter_ctx, out_buf)
+    }
+}
+
+// The suite ID of the combiner is "COMB" || I2OSP(kdf_id, 2). This keeps its KDF invocations
+// separate from every HPKE suite.
+fn combiner_suite_id<Kdf: KdfTrait>() -> [u8; 6] {
+    let mut suite_id = *b"COMB\0\0";
+    BigEndian::write_u16(&mut suite_id[4..], Kdf::KDF_ID);
+    suite_id
+}
+
+// The suite ID of export_into_kdf is "XKDF" || I2OSP(kdf_id, 2), where kdf_id is that of the
+// destination KDF
+fn kdf_upgrade_suite_id<Kdf: KdfTrait>() -> [u8; 6] {
+    let mut suite_id = *b"XKDF\0\0";
+    BigEndian::write_u16(&mut suite_id[4..], Kdf::KDF_ID);
+    suite_id
+}
+
+/// Combines the exporter secrets of `ctxs` into a single `out_len`-byte key, using the KDF `Kdf`.
+/// Each context exports `Nh` bytes under the exporter context `label`. The exported secrets are
+/// concatenated in the order given, and the result is `LabeledExtract`ed and `LabeledExpand`ed
+/// with `label`.
+///
+/// The output is order-dependent: the same contexts in a different order give a different key.
+/// All parties must agree on the order, e.g., by sorting their peers' public keys.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(key)` on success. If `out_len` is more than 255x the digest size of `Kdf`'s hash
+/// function, returns `Err(HpkeError::KdfOutputTooLong)`. If any context fails to export, returns
+/// that error.
+pub fn combine_exporters<Kdf: KdfTrait>(
+    ctxs: &[&dyn ExporterContext],
+    label: &[u8],
+    out_len: usize,
+) -> Result<Vec<u8>, HpkeError> {
+    let suite_id = combiner_suite_id::<Kdf>();
+    let nh = <<Kdf::HashImpl as FixedOutput>::OutputSize as Unsigned>::USIZE;
+
+    // Concatenate all the exported secrets. Since they're all the same length, this is
+    // unambiguous.
+    let mut ikm = vec![0u8; nh * ctxs.len()];
+    for (ctx, buf) in ctxs.iter().zip(ikm.chunks_mut(nh)) {
+        if let Err(e) = ctx.export(label, buf) {
+            ikm.zeroize();
+            return Err(e);
+        }
+    }
+
+    // Extract and expand to the output length
+    let (mut prk, hkdf_ctx) = labeled_extract::<Kdf>(&[], &suite_id, b"combined_prk", &ikm);
+    ikm.zeroize();
+    prk.zeroize();
+    let mut out = vec![0u8; out_len];
+    hkdf_ctx
+        .labeled_expand(&suite_id, b"combined", label, &mut out)
+        .map_err(|_| HpkeError::KdfOutputTooLong)?;
+
+    Ok(out)
+}
+
+/// The sender and receiver halves of a child context
+pub type ChildContext<A, Kdf, Kem> = (AeadCtxS<A, Kdf, Kem>, AeadCtxR<A, Kdf, Kem>);
+
+/// Derives child encryption contexts from the exporter secret of a parent context. Each child is
+/// identified by a `u64` index, which is folded into the exporter contexts its keys are derived
+/// with. Two peers with linked parents derive identical children for the same index.
+///
+/// Indices must be used in strictly increasing order, so that no index, and thus no child key, is
+/// ever derived twice. This is checked with a `debug_assert`, i.e., only in debug builds.
+pub struct ContextDeriver<'a, E: ExporterContext + ?Sized> {
+    parent: &'a E,
+    /// The last index a child was derived with. Only tracked in debug builds.
+    #[cfg(debug_assertions)]
+    last_index: Option<u64>,
+}
+
+impl<'a, E: ExporterContext + ?Sized> ContextDeriver<'a, E> {
+    /// Makes a deriver for children of `parent`
+    pub fn new(parent: &'a E) -> ContextDeriver<'a, E> {
+        ContextDeriver {
+            parent,
+            #[cfg(debug_assertions)]
+            last_index: None,
+        }
+    }
+
+    /// Derives the child context with the given index, using the ciphersuite `(A, Kdf, Kem)`. The
+    /// child's key, base nonce, and exporter secret are each exported from the parent. The sender
+    /// and receiver halves of the child are returned together. Only one peer should ever seal
+    /// with a given child. If both peers need to send, give each direction its own index.
+    ///
+    /// Panics
+    /// ======
+    /// In debug builds, panics if `index` is not greater than every index previously given to this
+    /// deriver.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok((sender_ctx, receiver_ctx))` on success. If the parent fails to export, returns
+    /// that error.
+    pub fn derive_context<A, Kdf, Kem>(
+        &mut self,
+        index: u64,
+    ) -> Result<ChildContext<A, Kdf, Kem>, HpkeError>
+    where
+        A: Aead,
+        Kdf: KdfTrait,
+        Kem: KemTrait,
+    {
+        #[cfg(debug_assertions)]
+        {
+            if let Some(last) = self.last_index {
+                debug_assert!(
+                    index > last,
+                    "child context index {} is not greater than the last index {}",
+                    index,
+                    last
+                );
+            }
+            self.last_index = Some(index);
+        }
+
+        // The exporter context of each secret is label || I2OSP(index, 8). The labels are distinct,
+        // so the secrets are independent, even when they're the same length.
+        let export_child_secret = |label: &[u8], out_buf: &mut [u8]| {
+            let mut exporter_ctx = Vec::with_capacity(label.len() + 8);
+            exporter_ctx.extend_from_slice(label);
+            exporter_ctx.extend_from_slice(&index.to_be_bytes());
+            self.parent.export(&exporter_ctx, out_buf)
+        };
+
+        // An AeadCtx isn't Clone, so make it twice from the same secrets
+        let make_ctx = || -> Result<AeadCtx<A, Kdf, Kem>, HpkeError> {
+            let mut key = AeadKey::<A>::default();
+            let mut base_nonce = AeadNonce::<A>::default();
+            let mut exporter_secret = ExporterSecret::<Kdf>::default();
+            export_child_secret(b"child key", key.0.as_mut_slice())?;
+            export_child_secret(b"child base_nonce", base_nonce.0.as_mut_slice())?;
+            export_child_secret(b"child exp", exporter_secret.0.as_mut_slice())?;
+            Ok(AeadCtx::new(&key, base_nonce, exporter_secret))
+        };
+
+        Ok((make_ctx()?.into(), make_ctx()?.into()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{combine_exporters, ContextDeriver, ExporterContext};
+    use crate::{
+        aead::ChaCha20Poly1305,
+        kdf::{HkdfSha256, HkdfSha384, HkdfSha512},
+        test_util::{aead_ctx_eq, gen_ctx_simple_pair},
+        HpkeError,
+    };
+
+    /// Tests that combining is stable, that both sides of the sessions get the same key, that the
+    /// order matters, and that changing any input changes the output
+    macro_rules! test_combine_exporters {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+
+                let label = b"group session key";
+                let (sender1, receiver1) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let (sender2, receiver2) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let (sender3, _) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+
+                let combine = |ctxs: &[&dyn ExporterContext], label: &[u8]| {
+                    combine_exporters::<Kdf>(ctxs, label, 32).unwrap()
+                };
+
+                // The same inputs always give the same key, and senders and receivers agree
+                let key = combine(&[&sender1, &sender2], label);
+                assert_eq!(key.len(), 32);
+                assert_eq!(key, combine(&[&sender1, &sender2], label));
+                assert_eq!(key, combine(&[&receiver1, &receiver2], label));
+                assert_eq!(key, combine(&[&sender1, &receiver2], label));
+
+                // Order matters
+                assert_ne!(key, combine(&[&sender2, &sender1], label));
+                // Changing a context or the label changes the key
+                assert_ne!(key, combine(&[&sender1, &sender3], label));
+                assert_ne!(key, combine(&[&sender1, &sender2], b"another label"));
+            }
+        };
+    }
+
+    /// Tests that both peers derive the same child for the same index, and that different indices
+    /// give different children
+    macro_rules! test_derive_context {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+
+                let (parent_sender, parent_receiver) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let mut sender_deriver = ContextDeriver::new(&parent_sender);
+                let mut receiver_deriver = ContextDeriver::new(&parent_receiver);
+
+                let (mut sender0, _) = sender_deriver.derive_context::<A, Kdf, Kem>(0).unwrap();
+                let (_, mut receiver0) = receiver_deriver.derive_context::<A, Kdf, Kem>(0).unwrap();
+                assert!(aead_ctx_eq(&mut sender0, &mut receiver0));
+
+                // Indices can be skipped, as long as they increase
+                let (mut sender5, mut receiver5) =
+                    sender_deriver.derive_context::<A, Kdf, Kem>(5).unwrap();
+                assert!(aead_ctx_eq(&mut sender5, &mut receiver5));
+                assert!(!aead_ctx_eq(&mut sender5, &mut receiver0));
+
+                // The child's exports differ from the parent's
+                let mut child_export = [0u8; 32];
+                let mut parent_export = [0u8; 32];
+                sender5.export(b"", &mut child_export).unwrap();
+                parent_sender.export(b"", &mut parent_export).unwrap();
+                assert_ne!(child_export, parent_export);
+            }
+        };
+    }
+
+    /// Tests that export_into_kdf is stable, that both sides agree, that the destination KDF and
+    /// the label both change the output, and that the output isn't a plain export
+    macro_rules! test_export_into_kdf {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+
+                let label = b"layered protocol key";
+                let (sender, receiver) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+
+                let key = sender.export_into_kdf::<HkdfSha384>(label, 48).unwrap();
+                assert_eq!(key.len(), 48);
+                assert_eq!(
+                    key,
+                    sender.export_into_kdf::<HkdfSha384>(label, 48).unwrap()
+                );
+                assert_eq!(
+                    key,
+                    receiver.export_into_kdf::<HkdfSha384>(label, 48).unwrap()
+                );
+
+                // Different KDFs and labels give different keys
+                assert_ne!(
+                    key,
+                    sender.export_into_kdf::<HkdfSha512>(label, 48).unwrap()
+                );
+                assert_ne!(key, sender.export_into_kdf::<Kdf>(label, 48).unwrap());
+                assert_ne!(
+                    key,
+                    sender.export_into_kdf::<HkdfSha384>(b"other", 48).unwrap()
+                );
+
+                // It's not just an export of the label
+                let mut plain_export = [0u8; 48];
+                sender.export(label, &mut plain_export).unwrap();
+                assert_ne!(key, plain_export.to_vec());
+
+                assert_eq!(
+                    sender.export_into_kdf::<HkdfSha384>(label, 255 * 48 + 1),
+                    Err(HpkeError::KdfOutputTooLong)
+                );
+            }
+        };
+    }
+
+    /// Tests that reusing an index panics in debug builds
+    #[cfg(all(debug_assertions, feature = "x25519-dalek"))]
+    #[test]
+    #[should_panic(expected = "is not greater than the last index")]
+    fn test_derive_context_repeated_index() {
+        type A = ChaCha20Poly1305;
+        type Kdf = HkdfSha256;
+        type Kem = crate::kem::X25519HkdfSha256;
+
+        let (parent, _) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+        let mut deriver = ContextDeriver::new(&parent);
+        deriver.derive_context::<A, Kdf, Kem>(3).unwrap();
+        deriver.derive_context::<A, Kdf, Kem>(3).unwrap();
+    }
+
+    #[cfg(feature = "x25519-dalek")]
+    test_combine_exporters!(test_combine_exporters_x25519, crate::kem::X25519HkdfSha256);
+
+    #[cfg(feature = "p256")]
+    test_combine_exporters!(test_combine_exporters_p256, crate::kem::DhP256HkdfSha256);
+
+    #[cfg(feature = "x25519-dalek")]
+    test_derive_context!(test_derive_context_x25519, crate::kem::X25519HkdfSha256);
+
+    #[cfg(feature = "p256")]
+    test_derive_context!(test_derive_context_p256, crate::kem::DhP256HkdfSha256);
+
+    #[cfg(feature = "x25519-dalek")]
+    test_export_into_kdf!(test_export_into_kdf_x25519, crate::kem::X25519HkdfSha256);
+
+    #[cfg(feature = "p256")]
+    test_export_into_kdf!(test_export_into_kdf_p256, crate::kem::DhP256HkdfSha256);
+}
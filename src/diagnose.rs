@@ -0,0 +1,246 @@
+//! Diagnostics for receivers that can't open a message. The op-mode isn't encoded anywhere in an
+//! encapsulated key or a ciphertext, so a receiver that's configured for the wrong mode just sees
+//! `HpkeError::OpenError`, the same as for a corrupted message. `diagnose_open_failure` tries
+//! every mode it can and reports which ones open the message.
+//!
+//! **WARNING:** This is for debugging misconfigurations in interop testing only. Never use it to
+//! decide how to decrypt real traffic. Accepting whichever mode works lets an attacker choose the
+//! mode, e.g., strip sender authentication by sending a `Base` mode message to a receiver that
+//! expects `Auth`.
+
+use crate::{
+    aead::{Aead, AeadTag},
+    builder::SetupMode,
+    kdf::Kdf as KdfTrait,
+    kem::{EncappedKey, Kem as KemTrait},
+    kex::KeyExchange,
+    op_mode::{OpModeR, PskBundle},
+    setup::setup_receiver,
+    HpkeError,
+};
+
+use alloc::vec::Vec;
+
+/// What happened when `diagnose_open_failure` tried a mode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModeOutcome {
+    /// The message opened in this mode
+    Opened,
+    /// Setting up or opening failed with the given error
+    Failed(HpkeError),
+    /// The mode wasn't tried, because it needs a PSK or sender public key that wasn't given
+    NotTried,
+}
+
+/// The outcome of trying to open a message in each op-mode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DiagnosisReport {
+    /// The outcome in `Base` mode
+    pub base: ModeOutcome,
+    /// The outcome in `Psk` mode
+    pub psk: ModeOutcome,
+    /// The outcome in `Auth` mode
+    pub auth: ModeOutcome,
+    /// The outcome in `AuthPsk` mode
+    pub auth_psk: ModeOutcome,
+}
+
+impl DiagnosisReport {
+    /// Returns the modes the message opened in. Unless the same keys were used in more than one
+    /// mode, this has at most one element.
+    pub fn opened_modes(&self) -> Vec<SetupMode> {
+        let outcomes = [
+            (SetupMode::Base, self.base),
+            (SetupMode::Psk, self.psk),
+            (SetupMode::Auth, self.auth),
+            (SetupMode::AuthPsk, self.auth_psk),
+        ];
+        outcomes
+            .iter()
+            .filter(|(_, outcome)| *outcome == ModeOutcome::Opened)
+            .map(|(mode, _)| *mode)
+            .collect()
+    }
+}
+
+/// Tries to open `ciphertext` in every op-mode that the given keys allow, and reports how each
+/// attempt went. `psk` is used for `Psk` and `AuthPsk`, and `pk_sender_id` for `Auth` and
+/// `AuthPsk`. Modes whose keys are missing aren't tried. `ciphertext` isn't modified, and the
+/// plaintext is never returned.
+///
+/// **WARNING:** This is a debugging aid. It must never be used to make decryption decisions in
+/// production. See the module documentation.
+#[allow(clippy::too_many_arguments)]
+pub fn diagnose_open_failure<A, Kdf, Kem>(
+    sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
+    encapped_key: &EncappedKey<Kem::Kex>,
+    info: &[u8],
+    ciphertext: &[u8],
+    aad: &[u8],
+    tag: &AeadTag<A>,
+    psk: Option<PskBundle>,
+    pk_sender_id: Option<&<Kem::Kex as KeyExchange>::PublicKey>,
+) -> DiagnosisReport
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    let try_mode = |mode: Option<OpModeR<Kem::Kex>>| {
+        let mode = match mode {
+            Some(mode) => mode,
+            None => return ModeOutcome::NotTried,
+        };
+        let mut buf = ciphertext.to_vec();
+        let res = setup_receiver::<A, Kdf, Kem>(&mode, sk_recip, encapped_key, info)
+            .and_then(|mut ctx| ctx.open(&mut buf, aad, tag));
+        match res {
+            Ok(()) => ModeOutcome::Opened,
+            Err(e) => ModeOutcome::Failed(e),
+        }
+    };
+
+    DiagnosisReport {
+        base: try_mode(Some(OpModeR::Base)),
+        psk: try_mode(psk.map(OpModeR::Psk)),
+        auth: try_mode(pk_sender_id.map(|pk| OpModeR::Auth(pk.clone()))),
+        auth_psk: try_mode(
+            psk.and_then(|psk| pk_sender_id.map(|pk| OpModeR::AuthPsk(pk.clone(), psk))),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{diagnose_open_failure, DiagnosisReport, ModeOutcome};
+    use crate::{
+        aead::ChaCha20Poly1305,
+        builder::SetupMode,
+        kdf::HkdfSha256,
+        kem::Kem as KemTrait,
+        op_mode::{OpModeS, PskBundle},
+        single_shot::single_shot_seal,
+        test_util::gen_rand_buf,
+        HpkeError,
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// Tests that a Base mode message and a Psk mode message are each reported as opening in
+    /// exactly the mode they were sealed in
+    macro_rules! test_diagnose_open_failure {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let info = b"which mode was it";
+                let (psk, psk_id) = (gen_rand_buf(), gen_rand_buf());
+                let psk_bundle = PskBundle {
+                    psk: &psk,
+                    psk_id: &psk_id,
+                };
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let (_, pk_sender) = Kem::gen_keypair(&mut csprng);
+
+                for (mode_s, expected_mode) in &[
+                    (OpModeS::Base, SetupMode::Base),
+                    (OpModeS::Psk(psk_bundle), SetupMode::Psk),
+                ] {
+                    let mut msg = *b"misconfigured";
+                    let (encapped_key, tag) = single_shot_seal::<A, Kdf, Kem, _>(
+                        mode_s,
+                        &pk_recip,
+                        info,
+                        &mut msg,
+                        b"",
+                        &mut csprng,
+                    )
+                    .unwrap();
+
+                    let report = diagnose_open_failure::<A, Kdf, Kem>(
+                        &sk_recip,
+                        &encapped_key,
+                        info,
+                        &msg,
+                        b"",
+                        &tag,
+                        Some(psk_bundle),
+                        Some(&pk_sender),
+                    );
+                    assert_eq!(report.opened_modes(), vec![*expected_mode]);
+
+                    // Without the PSK, the PSK modes aren't tried
+                    let report = diagnose_open_failure::<A, Kdf, Kem>(
+                        &sk_recip,
+                        &encapped_key,
+                        info,
+                        &msg,
+                        b"",
+                        &tag,
+                        None,
+                        Some(&pk_sender),
+                    );
+                    assert_eq!(report.psk, ModeOutcome::NotTried);
+                    assert_eq!(report.auth_psk, ModeOutcome::NotTried);
+                    assert_eq!(report.auth, ModeOutcome::Failed(HpkeError::OpenError));
+                }
+
+                // A message with the wrong tag opens in no mode
+                let mut msg = *b"corrupted";
+                let (encapped_key, _) = single_shot_seal::<A, Kdf, Kem, _>(
+                    &OpModeS::Base,
+                    &pk_recip,
+                    info,
+                    &mut msg,
+                    b"",
+                    &mut csprng,
+                )
+                .unwrap();
+                let (_, wrong_tag) = single_shot_seal::<A, Kdf, Kem, _>(
+                    &OpModeS::Base,
+                    &pk_recip,
+                    info,
+                    &mut b"corrupted".clone(),
+                    b"",
+                    &mut csprng,
+                )
+                .unwrap();
+                let report = diagnose_open_failure::<A, Kdf, Kem>(
+                    &sk_recip,
+                    &encapped_key,
+                    info,
+                    &msg,
+                    b"",
+                    &wrong_tag,
+                    Some(psk_bundle),
+                    None,
+                );
+                assert_eq!(
+                    report,
+                    DiagnosisReport {
+                        base: ModeOutcome::Failed(HpkeError::OpenError),
+                        psk: ModeOutcome::Failed(HpkeError::OpenError),
+                        auth: ModeOutcome::NotTried,
+                        auth_psk: ModeOutcome::NotTried,
+                    }
+                );
+            }
+        };
+    }
+
+    #[cfg(feature = "x25519-dalek")]
+    test_diagnose_open_failure!(
+        test_diagnose_open_failure_x25519,
+        crate::kem::X25519HkdfSha256
+    );
+
+    #[cfg(feature = "p256")]
+    test_diagnose_open_failure!(
+        test_diagnose_open_failure_p256,
+        crate::kem::DhP256HkdfSha256
+    );
+}
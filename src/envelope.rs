@@ -0,0 +1,532 @@
+//! A self-describing envelope format for single-shot messages. An envelope is
+//!
+//! ```text
+//! version || kem_id || kdf_id || aead_id || enc || ciphertext || tag
+//! ```
+//!
+//! where `version` is 1 byte and the algorithm IDs are 2-byte big-endian integers. Since the
+//! envelope says which ciphersuite it was sealed with, a reader only needs their private key (and
+//! the `info` and `aad` strings, if any) to open it. The 7-byte header is authenticated as part of
+//! the AAD. This is gated under the `alloc` feature.
+//!
+//! `negotiate_and_seal` and `negotiate_and_open` pick the suite for an envelope from the sender's
+//! preferences and the recipient's advertised capabilities, for recipients that hold a different
+//! key for each suite they support.
+
+use crate::{
+    aead::{Aead, AeadTag, AesGcm128, AesGcm256, ChaCha20Poly1305},
+    kdf::{HkdfSha256, HkdfSha384, HkdfSha512, Kdf as KdfTrait},
+    kem::{EncappedKey, Kem as KemTrait},
+    kex::{Deserializable, KeyExchange, Serializable},
+    op_mode::{OpModeR, OpModeS},
+    single_shot::{single_shot_open, single_shot_seal},
+    suite::{AeadId, CiphersuiteId, KdfId, KemId},
+    HpkeError,
+};
+
+use alloc::vec::Vec;
+use byteorder::{BigEndian, ByteOrder};
+use rand_core::{CryptoRng, RngCore};
+
+/// The version of the envelope format that `Envelope::seal` produces
+pub const ENVELOPE_VERSION: u8 = 1;
+
+// The length in bytes of version || kem_id || kdf_id || aead_id
+const HEADER_LEN: usize = 7;
+
+/// Evaluates `$body` with `$aead` and `$kdf` bound to the AEAD and KDF named by `$suite`. If
+/// `$suite` doesn't name an AEAD and KDF that envelopes support, evaluates `$unsupported` instead.
+macro_rules! dispatch_suite {
+    ($suite:expr, $unsupported:expr, |$aead:ident, $kdf:ident| $body:expr) => {
+        dispatch_suite!(
+            @arms $suite, $unsupported, $aead, $kdf, $body,
+            (AesGcm128, HkdfSha256), (AesGcm128, HkdfSha384), (AesGcm128, HkdfSha512),
+            (AesGcm256, HkdfSha256), (AesGcm256, HkdfSha384), (AesGcm256, HkdfSha512),
+            (ChaCha20Poly1305, HkdfSha256), (ChaCha20Poly1305, HkdfSha384),
+            (ChaCha20Poly1305, HkdfSha512)
+        )
+    };
+    (@arms $suite:expr, $unsupported:expr, $aead:ident, $kdf:ident, $body:expr,
+        $(($aead_ty:ty, $kdf_ty:ty)),*) => {{
+        let suite: CiphersuiteId = $suite;
+        $(
+            if suite.aead_id == AeadId::of::<$aead_ty>() && suite.kdf_id == KdfId::of::<$kdf_ty>()
+            {
+                type $aead = $aead_ty;
+                type $kdf = $kdf_ty;
+                $body
+            } else
+        )*
+        {
+            $unsupported
+        }
+    }};
+}
+
+/// The error for a ciphersuite that `Envelope::seal` doesn't support
+const UNSUPPORTED_SUITE: HpkeError =
+    HpkeError::InvalidSetupParameters("Envelope does not support this ciphersuite");
+
+/// Seals and opens self-describing envelopes. See the module documentation for the format.
+///
+/// Envelopes support every AEAD and KDF in this crate, except the export-only AEAD. The KEM is
+/// whichever one the recipient's key belongs to.
+pub struct Envelope;
+
+impl Envelope {
+    /// Seals `plaintext` to `pk_recip` in `Base` mode with the AEAD and KDF named by `suite`, and
+    /// packs the result into an envelope.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(envelope)` on success. If `suite.kem_id` isn't `Kem::KEM_ID`, or `suite`
+    /// names an AEAD or KDF that envelopes don't support, returns
+    /// `Err(HpkeError::InvalidSetupParameters(..))`. Otherwise, errors are the same as in
+    /// `single_shot_seal`.
+    pub fn seal<Kem, R>(
+        pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
+        suite: CiphersuiteId,
+        info: &[u8],
+        plaintext: &[u8],
+        aad: &[u8],
+        csprng: &mut R,
+    ) -> Result<Vec<u8>, HpkeError>
+    where
+        Kem: KemTrait,
+        R: CryptoRng + RngCore,
+    {
+        if suite.kem_id != KemId::of::<Kem>() {
+            return Err(UNSUPPORTED_SUITE);
+        }
+
+        dispatch_suite!(suite, Err(UNSUPPORTED_SUITE), |A, Kdf| {
+            seal_suite::<A, Kdf, Kem, R>(pk_recip, info, plaintext, aad, csprng)
+        })
+    }
+
+    /// Opens an envelope made by `Envelope::seal` with the recipient's private key. `info` and
+    /// `aad` must be the same as the sender's.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(plaintext)` on success. If the envelope's version isn't `ENVELOPE_VERSION`,
+    /// returns `Err(HpkeError::VersionMismatch(ENVELOPE_VERSION, version))`. If the envelope is
+    /// too short, names a KEM other than `Kem`, or names an AEAD or KDF that envelopes don't
+    /// support, returns `Err(HpkeError::ValidationError)`. Otherwise, errors are the same as in
+    /// `single_shot_open`.
+    pub fn open<Kem: KemTrait>(
+        sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
+        info: &[u8],
+        aad: &[u8],
+        envelope: &[u8],
+    ) -> Result<Vec<u8>, HpkeError> {
+        let suite = read_suite(envelope)?;
+        if suite.kem_id != KemId::of::<Kem>() {
+            return Err(HpkeError::ValidationError);
+        }
+
+        dispatch_suite!(suite, Err(HpkeError::ValidationError), |A, Kdf| {
+            open_suite::<A, Kdf, Kem>(sk_recip, info, aad, envelope)
+        })
+    }
+}
+
+/// Picks the first suite in `my_prefs` that is also in `their_supported`, that envelopes support
+/// with `Kem`, and that has a public key in `pk_r_by_suite`. Then seals `plaintext` to that key
+/// with `Envelope::seal`, so the chosen suite is in the envelope's header. The order of
+/// `their_supported` doesn't matter; the sender's preferences win.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(envelope)` on success. If no suite meets all of the above, returns
+/// `Err(HpkeError::NoMutualSuite)`. Otherwise, errors are the same as in `Envelope::seal`.
+pub fn negotiate_and_seal<Kem, R>(
+    my_prefs: &[CiphersuiteId],
+    their_supported: &[CiphersuiteId],
+    pk_r_by_suite: &[(CiphersuiteId, <Kem::Kex as KeyExchange>::PublicKey)],
+    info: &[u8],
+    plaintext: &[u8],
+    aad: &[u8],
+    csprng: &mut R,
+) -> Result<Vec<u8>, HpkeError>
+where
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    let (suite, pk_recip) = my_prefs
+        .iter()
+        .filter(|suite| their_supported.contains(suite) && is_supported::<Kem>(**suite))
+        .find_map(|suite| {
+            pk_r_by_suite
+                .iter()
+                .find(|(key_suite, _)| key_suite == suite)
+        })
+        .ok_or(HpkeError::NoMutualSuite)?;
+
+    Envelope::seal::<Kem, R>(pk_recip, *suite, info, plaintext, aad, csprng)
+}
+
+/// Opens an envelope made by `negotiate_and_seal`. The suite is read from the envelope's header,
+/// and the envelope is opened with that suite's key in `sk_r_by_suite`.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(plaintext)` on success. If the header names a suite that has no key in
+/// `sk_r_by_suite`, returns `Err(HpkeError::NoMutualSuite)`. Otherwise, errors are the same as in
+/// `Envelope::open`.
+pub fn negotiate_and_open<Kem: KemTrait>(
+    sk_r_by_suite: &[(CiphersuiteId, <Kem::Kex as KeyExchange>::PrivateKey)],
+    info: &[u8],
+    aad: &[u8],
+    envelope: &[u8],
+) -> Result<Vec<u8>, HpkeError> {
+    let suite = read_suite(envelope)?;
+    let (_, sk_recip) = sk_r_by_suite
+        .iter()
+        .find(|(key_suite, _)| *key_suite == suite)
+        .ok_or(HpkeError::NoMutualSuite)?;
+
+    Envelope::open::<Kem>(sk_recip, info, aad, envelope)
+}
+
+/// Returns whether envelopes can be sealed in `suite` with `Kem`
+fn is_supported<Kem: KemTrait>(suite: CiphersuiteId) -> bool {
+    suite.kem_id == KemId::of::<Kem>() && dispatch_suite!(suite, false, |_A, _Kdf| true)
+}
+
+/// Reads the ciphersuite out of an envelope's header
+fn read_suite(envelope: &[u8]) -> Result<CiphersuiteId, HpkeError> {
+    if envelope.len() < HEADER_LEN {
+        return Err(HpkeError::ValidationError);
+    }
+
+    // Check the version before anything else, so that future formats get a clear error
+    let version = envelope[0];
+    if version != ENVELOPE_VERSION {
+        return Err(HpkeError::VersionMismatch(ENVELOPE_VERSION, version));
+    }
+    // Unregistered IDs are just unsupported, so there's nothing to do with the warnings
+    Ok(CiphersuiteId::new(
+        KemId::new_lenient(BigEndian::read_u16(&envelope[1..3])).0,
+        KdfId::new_lenient(BigEndian::read_u16(&envelope[3..5])).0,
+        AeadId::new_lenient(BigEndian::read_u16(&envelope[5..7])).0,
+    ))
+}
+
+/// Returns the envelope header for the given ciphersuite
+fn header<A: Aead, Kdf: KdfTrait, Kem: KemTrait>() -> [u8; HEADER_LEN] {
+    let mut header = [0u8; HEADER_LEN];
+    header[0] = ENVELOPE_VERSION;
+    BigEndian::write_u16(&mut header[1..3], Kem::KEM_ID);
+    BigEndian::write_u16(&mut header[3..5], Kdf::KDF_ID);
+    BigEndian::write_u16(&mut header[5..7], A::AEAD_ID);
+    header
+}
+
+/// Computes the AAD of an envelope, i.e., `header || aad`
+fn envelope_aad(header: &[u8], aad: &[u8]) -> Vec<u8> {
+    let mut full_aad = Vec::with_capacity(header.len() + aad.len());
+    full_aad.extend_from_slice(header);
+    full_aad.extend_from_slice(aad);
+    full_aad
+}
+
+// Envelope::seal, once the ciphersuite is known
+fn seal_suite<A, Kdf, Kem, R>(
+    pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
+    info: &[u8],
+    plaintext: &[u8],
+    aad: &[u8],
+    csprng: &mut R,
+) -> Result<Vec<u8>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    let header = header::<A, Kdf, Kem>();
+    let full_aad = envelope_aad(&header, aad);
+
+    let mut ciphertext = plaintext.to_vec();
+    let (encapped_key, tag) = single_shot_seal::<A, Kdf, Kem, R>(
+        &OpModeS::Base,
+        pk_recip,
+        info,
+        &mut ciphertext,
+        &full_aad,
+        csprng,
+    )?;
+
+    let enc_len = EncappedKey::<Kem::Kex>::size();
+    let tag_len = AeadTag::<A>::size();
+    let mut envelope = Vec::with_capacity(HEADER_LEN + enc_len + ciphertext.len() + tag_len);
+    envelope.extend_from_slice(&header);
+    envelope.extend_from_slice(&encapped_key.to_bytes());
+    envelope.extend_from_slice(&ciphertext);
+    envelope.extend_from_slice(&tag.to_bytes());
+    Ok(envelope)
+}
+
+// Envelope::open, once the ciphersuite is known. The header has already been checked.
+fn open_suite<A, Kdf, Kem>(
+    sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
+    info: &[u8],
+    aad: &[u8],
+    envelope: &[u8],
+) -> Result<Vec<u8>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    let enc_len = EncappedKey::<Kem::Kex>::size();
+    let tag_len = AeadTag::<A>::size();
+    if envelope.len() < HEADER_LEN + enc_len + tag_len {
+        return Err(HpkeError::ValidationError);
+    }
+
+    let (header, rest) = envelope.split_at(HEADER_LEN);
+    let (enc_bytes, rest) = rest.split_at(enc_len);
+    let (ciphertext, tag_bytes) = rest.split_at(rest.len() - tag_len);
+    let encapped_key = EncappedKey::<Kem::Kex>::from_bytes(enc_bytes)?;
+    let tag = AeadTag::<A>::from_bytes(tag_bytes)?;
+    let full_aad = envelope_aad(header, aad);
+
+    let mut plaintext = ciphertext.to_vec();
+    single_shot_open::<A, Kdf, Kem>(
+        &OpModeR::Base,
+        sk_recip,
+        &encapped_key,
+        info,
+        &mut plaintext,
+        &full_aad,
+        &tag,
+    )?;
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{negotiate_and_open, negotiate_and_seal, Envelope, ENVELOPE_VERSION, HEADER_LEN};
+    use crate::{
+        aead::{Aead, AesGcm128, AesGcm256, ChaCha20Poly1305, ExportOnlyAead},
+        kdf::{HkdfSha256, HkdfSha512, Kdf as KdfTrait},
+        kem::Kem as KemTrait,
+        suite::{AeadId, CiphersuiteId, KemId},
+        HpkeError,
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// Tests that envelopes round-trip in two different suites, that the header can't be
+    /// tampered with, and that truncated and garbage envelopes are rejected
+    macro_rules! test_envelope {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let (info, aad) = (b"archive 2021", b"box 7");
+                let msg = b"open in 2071";
+
+                let suites = [
+                    CiphersuiteId::of::<ChaCha20Poly1305, HkdfSha256, Kem>(),
+                    CiphersuiteId::of::<AesGcm256, HkdfSha512, Kem>(),
+                ];
+                let mut envelopes = suites.iter().map(|suite| {
+                    Envelope::seal::<Kem, _>(&pk_recip, *suite, info, msg, aad, &mut csprng)
+                        .unwrap()
+                });
+                let (envelope_a, envelope_b) =
+                    (envelopes.next().unwrap(), envelopes.next().unwrap());
+
+                // The reader only needs their key to open either one
+                for envelope in [&envelope_a, &envelope_b].iter() {
+                    assert_eq!(envelope[0], ENVELOPE_VERSION);
+                    let plaintext = Envelope::open::<Kem>(&sk_recip, info, aad, envelope).unwrap();
+                    assert_eq!(&plaintext, msg);
+                }
+                assert_eq!(&envelope_a[1..3], &Kem::KEM_ID.to_be_bytes());
+                assert_eq!(&envelope_b[3..5], &HkdfSha512::KDF_ID.to_be_bytes());
+                assert_eq!(&envelope_b[5..7], &AesGcm256::AEAD_ID.to_be_bytes());
+
+                // Swapping the AEAD for another supported one fails to open rather than
+                // misinterpreting the ciphertext
+                let mut tampered = envelope_a.clone();
+                tampered[5..7].copy_from_slice(&AesGcm256::AEAD_ID.to_be_bytes());
+                assert_eq!(
+                    Envelope::open::<Kem>(&sk_recip, info, aad, &tampered),
+                    Err(HpkeError::OpenError)
+                );
+
+                // A future version is reported as such
+                let mut tampered = envelope_a.clone();
+                tampered[0] = ENVELOPE_VERSION + 1;
+                assert_eq!(
+                    Envelope::open::<Kem>(&sk_recip, info, aad, &tampered),
+                    Err(HpkeError::VersionMismatch(
+                        ENVELOPE_VERSION,
+                        ENVELOPE_VERSION + 1
+                    ))
+                );
+
+                // Unknown algorithm IDs and the wrong KEM are rejected
+                for range in [1..3, 3..5, 5..7].iter() {
+                    let mut garbage = envelope_a.clone();
+                    garbage[range.clone()].copy_from_slice(&[0xff, 0xff]);
+                    assert_eq!(
+                        Envelope::open::<Kem>(&sk_recip, info, aad, &garbage),
+                        Err(HpkeError::ValidationError)
+                    );
+                }
+
+                // Every truncation fails, and none of them panic
+                for len in 0..envelope_a.len() {
+                    assert!(
+                        Envelope::open::<Kem>(&sk_recip, info, aad, &envelope_a[..len]).is_err()
+                    );
+                }
+                for len in 0..HEADER_LEN {
+                    assert_eq!(
+                        Envelope::open::<Kem>(&sk_recip, info, aad, &envelope_a[..len]),
+                        Err(HpkeError::ValidationError)
+                    );
+                }
+
+                // Sealing with the export-only AEAD or another KEM is refused
+                let bad_suites = [
+                    CiphersuiteId {
+                        aead_id: AeadId::of::<ExportOnlyAead>(),
+                        ..suites[0]
+                    },
+                    CiphersuiteId {
+                        kem_id: KemId::new_lenient(0xffff).0,
+                        ..suites[0]
+                    },
+                ];
+                for suite in bad_suites.iter() {
+                    assert!(matches!(
+                        Envelope::seal::<Kem, _>(&pk_recip, *suite, info, msg, aad, &mut csprng),
+                        Err(HpkeError::InvalidSetupParameters(_))
+                    ));
+                }
+            }
+        };
+    }
+
+    /// Tests that negotiation picks the sender's most preferred suite that the recipient supports
+    /// and the sender can use, that the recipient opens it with the right key, and that lists with
+    /// nothing in common are an error
+    macro_rules! test_negotiate {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let (info, aad) = (b"negotiated", b"header");
+                let msg = b"whatever we both speak";
+
+                let chacha = CiphersuiteId::of::<ChaCha20Poly1305, HkdfSha256, Kem>();
+                let aes128 = CiphersuiteId::of::<AesGcm128, HkdfSha256, Kem>();
+                let aes256 = CiphersuiteId::of::<AesGcm256, HkdfSha512, Kem>();
+                let export_only = CiphersuiteId::of::<ExportOnlyAead, HkdfSha256, Kem>();
+
+                // The recipient has a key for each suite they support
+                let their_supported = [aes128, export_only, aes256];
+                let keypairs = [
+                    Kem::gen_keypair(&mut csprng),
+                    Kem::gen_keypair(&mut csprng),
+                    Kem::gen_keypair(&mut csprng),
+                ];
+                let pk_r_by_suite = [
+                    (aes128, keypairs[0].1.clone()),
+                    (export_only, keypairs[1].1.clone()),
+                    (aes256, keypairs[2].1.clone()),
+                ];
+                let sk_r_by_suite = [
+                    (aes128, keypairs[0].0.clone()),
+                    (export_only, keypairs[1].0.clone()),
+                    (aes256, keypairs[2].0.clone()),
+                ];
+
+                // ChaCha isn't supported, and the export-only AEAD can't seal, so the sender's
+                // next preference wins, even though the recipient lists AES-GCM-128 first
+                let my_prefs = [chacha, export_only, aes256, aes128];
+                let envelope = negotiate_and_seal::<Kem, _>(
+                    &my_prefs,
+                    &their_supported,
+                    &pk_r_by_suite,
+                    info,
+                    msg,
+                    aad,
+                    &mut csprng,
+                )
+                .unwrap();
+                assert_eq!(&envelope[5..7], &AesGcm256::AEAD_ID.to_be_bytes());
+                assert_eq!(&envelope[3..5], &HkdfSha512::KDF_ID.to_be_bytes());
+                let plaintext =
+                    negotiate_and_open::<Kem>(&sk_r_by_suite, info, aad, &envelope).unwrap();
+                assert_eq!(&plaintext, msg);
+
+                // It was sealed to that suite's key, and no other
+                assert_eq!(
+                    Envelope::open::<Kem>(&keypairs[2].0, info, aad, &envelope).unwrap(),
+                    msg.to_vec()
+                );
+                assert!(Envelope::open::<Kem>(&keypairs[0].0, info, aad, &envelope).is_err());
+
+                // A recipient without a key for the chosen suite can't open it
+                assert_eq!(
+                    negotiate_and_open::<Kem>(&sk_r_by_suite[..2], info, aad, &envelope),
+                    Err(HpkeError::NoMutualSuite)
+                );
+
+                // No overlap, or overlap only on suites that can't be used, is an error
+                for my_prefs in [&[chacha][..], &[export_only][..], &[][..]].iter() {
+                    assert_eq!(
+                        negotiate_and_seal::<Kem, _>(
+                            my_prefs,
+                            &their_supported,
+                            &pk_r_by_suite,
+                            info,
+                            msg,
+                            aad,
+                            &mut csprng,
+                        ),
+                        Err(HpkeError::NoMutualSuite)
+                    );
+                }
+
+                // So is a mutual suite with no key to seal to
+                assert_eq!(
+                    negotiate_and_seal::<Kem, _>(
+                        &[aes128],
+                        &their_supported,
+                        &pk_r_by_suite[1..],
+                        info,
+                        msg,
+                        aad,
+                        &mut csprng,
+                    ),
+                    Err(HpkeError::NoMutualSuite)
+                );
+            }
+        };
+    }
+
+    #[cfg(feature = "x25519-dalek")]
+    test_envelope!(test_envelope_x25519, crate::kem::X25519HkdfSha256);
+
+    #[cfg(feature = "p256")]
+    test_envelope!(test_envelope_p256, crate::kem::DhP256HkdfSha256);
+
+    #[cfg(feature = "x25519-dalek")]
+    test_negotiate!(test_negotiate_x25519, crate::kem::X25519HkdfSha256);
+
+    #[cfg(feature = "p256")]
+    test_negotiate!(test_negotiate_p256, crate::kem::DhP256HkdfSha256);
+}
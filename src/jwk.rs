@@ -0,0 +1,352 @@
+//! Conversions between keys and JSON Web Keys. X25519 keys are `OKP` keys with curve `X25519`
+//! ([RFC 8037](https://tools.ietf.org/html/rfc8037)). P-256 keys are `EC` keys with curve `P-256`
+//! ([RFC 7518 §6.2](https://tools.ietf.org/html/rfc7518#section-6.2)). All byte strings are
+//! unpadded base64url.
+
+use crate::{
+    kex::{Deserializable, KeyExchange, Serializable},
+    HpkeError,
+};
+
+use alloc::{
+    string::{String, ToString},
+    vec::Vec,
+};
+use serde_json::{Map, Value};
+use zeroize::{Zeroize, Zeroizing};
+
+/// Encodes bytes as unpadded base64url
+fn b64_encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Writes the given fields out as a JSON object. The names and values must not need escaping,
+/// which holds for the constant names and the base64url values used here. The output is written
+/// into a single allocation of the right size, so that no copies of a private key are left
+/// behind in memory by reallocations.
+fn jwk_to_string(fields: &[(&str, &str)]) -> Zeroizing<String> {
+    // Each field is "name":"value", plus a comma or brace
+    let len = fields
+        .iter()
+        .map(|(name, val)| name.len() + val.len() + 6)
+        .sum::<usize>()
+        + 1;
+    let mut out = Zeroizing::new(String::with_capacity(len));
+    for (i, (name, val)) in fields.iter().enumerate() {
+        out.push_str(if i == 0 { "{\"" } else { ",\"" });
+        out.push_str(name);
+        out.push_str("\":\"");
+        out.push_str(val);
+        out.push('"');
+    }
+    out.push('}');
+    out
+}
+
+/// Parses `jwk` as a JSON object and checks that its `kty` and `crv` are the given ones
+fn parse_jwk(jwk: &str, kty: &str, crv: &str) -> Result<Map<String, Value>, HpkeError> {
+    let mut obj = match serde_json::from_str(jwk) {
+        Ok(Value::Object(obj)) => obj,
+        _ => return Err(HpkeError::ValidationError),
+    };
+
+    if obj.get("kty").and_then(Value::as_str) != Some(kty)
+        || obj.get("crv").and_then(Value::as_str) != Some(crv)
+    {
+        // Don't leave a private key lying around in the rejected object
+        if let Some(Value::String(d)) = obj.get_mut("d") {
+            d.zeroize();
+        }
+        return Err(HpkeError::ValidationError);
+    }
+
+    Ok(obj)
+}
+
+/// Decodes the unpadded base64url field `name` of a JWK
+fn get_b64_field(obj: &Map<String, Value>, name: &str) -> Result<Vec<u8>, HpkeError> {
+    let encoded = obj
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or(HpkeError::ValidationError)?;
+    base64::decode_config(encoded, base64::URL_SAFE_NO_PAD).map_err(|_| HpkeError::ValidationError)
+}
+
+/// Removes the private key field `d` from a JWK and decodes it. Both the base64url string and the
+/// decoded bytes are zeroized when they're dropped, whether or not decoding succeeds.
+fn take_private_field(obj: &mut Map<String, Value>) -> Result<Zeroizing<Vec<u8>>, HpkeError> {
+    let encoded = match obj.remove("d") {
+        Some(Value::String(d)) => Zeroizing::new(d),
+        _ => return Err(HpkeError::ValidationError),
+    };
+
+    // Decode into a buffer that's big enough up front, so it's never reallocated
+    let mut decoded = Zeroizing::new(Vec::with_capacity(encoded.len() * 3 / 4 + 3));
+    base64::decode_config_buf(encoded.as_str(), base64::URL_SAFE_NO_PAD, &mut decoded)
+        .map_err(|_| HpkeError::ValidationError)?;
+    Ok(decoded)
+}
+
+/// Checks that the `PublicKey` in a private JWK is the pubkey of its private key
+fn check_pubkey<Kex: KeyExchange>(
+    sk: &Kex::PrivateKey,
+    given_pk: &Kex::PublicKey,
+) -> Result<(), HpkeError> {
+    if Kex::sk_to_pk(sk).to_bytes() == given_pk.to_bytes() {
+        Ok(())
+    } else {
+        Err(HpkeError::ValidationError)
+    }
+}
+
+#[cfg(feature = "x25519-dalek")]
+mod x25519_jwk {
+    use super::*;
+    use crate::kex::x25519::{PrivateKey, PublicKey, X25519};
+
+    use serde_json::json;
+
+    const KTY: &str = "OKP";
+    const CRV: &str = "X25519";
+
+    // The public part of an X25519 JWK is just the key, in field x
+    fn pubkey_from_obj(obj: &Map<String, Value>) -> Result<PublicKey, HpkeError> {
+        PublicKey::from_bytes(&get_b64_field(obj, "x")?)
+    }
+
+    impl PublicKey {
+        /// Encodes this key as an `OKP` JWK with curve `X25519`
+        pub fn to_jwk(&self) -> String {
+            json!({"kty": KTY, "crv": CRV, "x": b64_encode(&self.to_bytes())}).to_string()
+        }
+
+        /// Decodes an `OKP` JWK with curve `X25519`
+        ///
+        /// Return Value
+        /// ============
+        /// Returns `Ok(pk)` on success. If the JWK is malformed, or its key type or curve are not
+        /// `OKP` and `X25519`, returns `Err(HpkeError::ValidationError)`. If the key is the wrong
+        /// length, returns `Err(HpkeError::IncorrectInputLength)`.
+        pub fn from_jwk(jwk: &str) -> Result<PublicKey, HpkeError> {
+            pubkey_from_obj(&parse_jwk(jwk, KTY, CRV)?)
+        }
+    }
+
+    impl PrivateKey {
+        /// Encodes this key, along with its public key, as an `OKP` JWK with curve `X25519`. The
+        /// output contains secret material, and is zeroized when it's dropped.
+        pub fn to_jwk(&self) -> Zeroizing<String> {
+            let x = b64_encode(&X25519::sk_to_pk(self).to_bytes());
+            let mut sk_bytes = self.to_bytes();
+            let d = Zeroizing::new(b64_encode(&sk_bytes));
+            sk_bytes.zeroize();
+            jwk_to_string(&[("crv", CRV), ("d", &d), ("kty", KTY), ("x", &x)])
+        }
+
+        /// Decodes an `OKP` JWK with curve `X25519` that contains a private key
+        ///
+        /// Return Value
+        /// ============
+        /// Returns `Ok(sk)` on success. If the JWK is malformed, its key type or curve are not
+        /// `OKP` and `X25519`, or its public key does not match its private key, returns
+        /// `Err(HpkeError::ValidationError)`. If a key is the wrong length, returns
+        /// `Err(HpkeError::IncorrectInputLength)`.
+        pub fn from_jwk(jwk: &str) -> Result<PrivateKey, HpkeError> {
+            let mut obj = parse_jwk(jwk, KTY, CRV)?;
+            let sk = PrivateKey::from_bytes(&take_private_field(&mut obj)?)?;
+            check_pubkey::<X25519>(&sk, &pubkey_from_obj(&obj)?)?;
+            Ok(sk)
+        }
+    }
+}
+
+#[cfg(feature = "p256")]
+mod p256_jwk {
+    use super::*;
+    use crate::kex::ecdh_nistp::{DhP256, PrivateKey, PublicKey};
+
+    use serde_json::json;
+
+    const KTY: &str = "EC";
+    const CRV: &str = "P-256";
+
+    // Splits a pubkey into its x and y coordinates. The uncompressed encoding is 0x04 || x || y.
+    fn pubkey_coords(pk: &PublicKey) -> (String, String) {
+        let bytes = pk.to_bytes();
+        (b64_encode(&bytes[1..33]), b64_encode(&bytes[33..]))
+    }
+
+    // Puts the x and y coordinates back together into an uncompressed point
+    fn pubkey_from_obj(obj: &Map<String, Value>) -> Result<PublicKey, HpkeError> {
+        let x = get_b64_field(obj, "x")?;
+        let y = get_b64_field(obj, "y")?;
+        if x.len() != 32 || y.len() != 32 {
+            return Err(HpkeError::ValidationError);
+        }
+
+        let mut encoded = Vec::with_capacity(65);
+        encoded.push(0x04);
+        encoded.extend_from_slice(&x);
+        encoded.extend_from_slice(&y);
+        PublicKey::from_bytes(&encoded)
+    }
+
+    impl PublicKey {
+        /// Encodes this key as an `EC` JWK with curve `P-256`
+        pub fn to_jwk(&self) -> String {
+            let (x, y) = pubkey_coords(self);
+            json!({"kty": KTY, "crv": CRV, "x": x, "y": y}).to_string()
+        }
+
+        /// Decodes an `EC` JWK with curve `P-256`
+        ///
+        /// Return Value
+        /// ============
+        /// Returns `Ok(pk)` on success. If the JWK is malformed, its key type or curve are not
+        /// `EC` and `P-256`, or its coordinates are not a valid point, returns
+        /// `Err(HpkeError::ValidationError)`.
+        pub fn from_jwk(jwk: &str) -> Result<PublicKey, HpkeError> {
+            pubkey_from_obj(&parse_jwk(jwk, KTY, CRV)?)
+        }
+    }
+
+    impl PrivateKey {
+        /// Encodes this key, along with its public key, as an `EC` JWK with curve `P-256`. The
+        /// output contains secret material, and is zeroized when it's dropped.
+        pub fn to_jwk(&self) -> Zeroizing<String> {
+            let (x, y) = pubkey_coords(&DhP256::sk_to_pk(self));
+            let mut sk_bytes = self.to_bytes();
+            let d = Zeroizing::new(b64_encode(&sk_bytes));
+            sk_bytes.zeroize();
+            jwk_to_string(&[("crv", CRV), ("d", &d), ("kty", KTY), ("x", &x), ("y", &y)])
+        }
+
+        /// Decodes an `EC` JWK with curve `P-256` that contains a private key
+        ///
+        /// Return Value
+        /// ============
+        /// Returns `Ok(sk)` on success. If the JWK is malformed, its key type or curve are not
+        /// `EC` and `P-256`, its private key is invalid, or its public key does not match its
+        /// private key, returns `Err(HpkeError::ValidationError)`. If the private key is the
+        /// wrong length, returns `Err(HpkeError::IncorrectInputLength)`.
+        pub fn from_jwk(jwk: &str) -> Result<PrivateKey, HpkeError> {
+            let mut obj = parse_jwk(jwk, KTY, CRV)?;
+            let sk = PrivateKey::from_bytes(&take_private_field(&mut obj)?)?;
+            check_pubkey::<DhP256>(&sk, &pubkey_from_obj(&obj)?)?;
+            Ok(sk)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        kem::Kem as KemTrait,
+        kex::{KeyExchange, Serializable},
+        HpkeError,
+    };
+
+    use alloc::string::ToString;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// Tests that public and private keys survive a JWK round trip, that the JWKs have the right
+    /// key type and curve, and that tampering with the pubkey of a private JWK is caught
+    macro_rules! test_jwk_round_trip {
+        ($test_name:ident, $kem_ty:ty, $kty:expr, $crv:expr) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kex = <Kem as KemTrait>::Kex;
+                type PublicKey = <Kex as KeyExchange>::PublicKey;
+                type PrivateKey = <Kex as KeyExchange>::PrivateKey;
+
+                let mut csprng = StdRng::from_entropy();
+                let (sk, pk) = Kem::gen_keypair(&mut csprng);
+                let (_, other_pk) = Kem::gen_keypair(&mut csprng);
+
+                let pk_jwk = pk.to_jwk();
+                let sk_jwk = sk.to_jwk();
+                for jwk in &[pk_jwk.as_str(), sk_jwk.as_str()] {
+                    let parsed: serde_json::Value = serde_json::from_str(jwk).unwrap();
+                    assert_eq!(parsed["kty"], $kty);
+                    assert_eq!(parsed["crv"], $crv);
+                }
+
+                let new_pk = PublicKey::from_jwk(&pk_jwk).unwrap();
+                let new_sk = PrivateKey::from_jwk(&sk_jwk).unwrap();
+                assert_eq!(new_pk.to_bytes(), pk.to_bytes());
+                assert_eq!(new_sk.to_bytes(), sk.to_bytes());
+
+                // A public JWK has no private key in it
+                assert_eq!(
+                    PrivateKey::from_jwk(&pk_jwk).err(),
+                    Some(HpkeError::ValidationError)
+                );
+
+                // Swap the pubkey of the private JWK with someone else's
+                let mut tampered: serde_json::Value = serde_json::from_str(&sk_jwk).unwrap();
+                let other: serde_json::Value = serde_json::from_str(&other_pk.to_jwk()).unwrap();
+                for coord in &["x", "y"] {
+                    if let Some(val) = other.get(coord) {
+                        tampered[coord] = val.clone();
+                    }
+                }
+                assert_eq!(
+                    PrivateKey::from_jwk(&tampered.to_string()).err(),
+                    Some(HpkeError::ValidationError)
+                );
+            }
+        };
+    }
+
+    #[cfg(feature = "x25519-dalek")]
+    test_jwk_round_trip!(
+        test_jwk_round_trip_x25519,
+        crate::kem::X25519HkdfSha256,
+        "OKP",
+        "X25519"
+    );
+
+    #[cfg(feature = "p256")]
+    test_jwk_round_trip!(
+        test_jwk_round_trip_p256,
+        crate::kem::DhP256HkdfSha256,
+        "EC",
+        "P-256"
+    );
+
+    /// Tests that a JWK for one curve can't be decoded as a key on the other
+    #[cfg(all(feature = "x25519-dalek", feature = "p256"))]
+    #[test]
+    fn test_jwk_wrong_curve() {
+        use crate::kex::{ecdh_nistp, x25519};
+
+        let mut csprng = StdRng::from_entropy();
+        let (p256_sk, p256_pk) = crate::kem::DhP256HkdfSha256::gen_keypair(&mut csprng);
+        let (x25519_sk, x25519_pk) = crate::kem::X25519HkdfSha256::gen_keypair(&mut csprng);
+
+        assert_eq!(
+            x25519::PublicKey::from_jwk(&p256_pk.to_jwk()).err(),
+            Some(HpkeError::ValidationError)
+        );
+        assert_eq!(
+            x25519::PrivateKey::from_jwk(&p256_sk.to_jwk()).err(),
+            Some(HpkeError::ValidationError)
+        );
+        assert_eq!(
+            ecdh_nistp::PublicKey::from_jwk(&x25519_pk.to_jwk()).err(),
+            Some(HpkeError::ValidationError)
+        );
+        assert_eq!(
+            ecdh_nistp::PrivateKey::from_jwk(&x25519_sk.to_jwk()).err(),
+            Some(HpkeError::ValidationError)
+        );
+
+        // Even with the curve name changed, the OKP key type gives it away
+        let relabeled = x25519_pk.to_jwk().replace("X25519", "P-256");
+        assert_eq!(
+            ecdh_nistp::PublicKey::from_jwk(&relabeled).err(),
+            Some(HpkeError::ValidationError)
+        );
+    }
+}
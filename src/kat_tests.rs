@@ -3,16 +3,15 @@ use crate::{
     kdf::{HkdfSha256, HkdfSha384, HkdfSha512, Kdf as KdfTrait},
     kem::{encap_with_eph, DhP256HkdfSha256, EncappedKey, Kem as KemTrait, X25519HkdfSha256},
     kex::{Deserializable, KeyExchange, Serializable},
-    op_mode::{OpModeR, PskBundle},
-    setup::setup_receiver,
+    op_mode::{OpModeR, OpModeS, PskBundle},
+    setup::{setup_receiver, setup_sender},
+    test_util::ReplayRng,
 };
 
 extern crate std;
 use std::{fs::File, string::String, vec::Vec};
 
-use hex;
 use serde::{de::Error as SError, Deserialize, Deserializer};
-use serde_json;
 
 /// Asserts that the given serializable values are equal
 macro_rules! assert_serializable_eq {
@@ -39,7 +38,7 @@ fn bytes_from_hex_opt<'de, D>(deserializer: D) -> Result<Option<Vec<u8>>, D::Err
 where
     D: Deserializer<'de>,
 {
-    bytes_from_hex(deserializer).map(|v| Some(v))
+    bytes_from_hex(deserializer).map(Some)
 }
 
 // Each individual test case looks like this
@@ -167,8 +166,83 @@ fn make_op_mode_r<'a, Kex: KeyExchange>(
     }
 }
 
+/// Constructs an `OpModeS` from the given components. Like `make_op_mode_r`, the variant is
+/// determined solely by `mode_id`, and this panics if there is insufficient data for it.
+fn make_op_mode_s<'a, Kex: KeyExchange>(
+    mode_id: u8,
+    sender_keypair: Option<(Kex::PrivateKey, Kex::PublicKey)>,
+    psk: Option<&'a [u8]>,
+    psk_id: Option<&'a [u8]>,
+) -> OpModeS<'a, Kex> {
+    let bundle = psk.map(|bytes| PskBundle {
+        psk: bytes,
+        psk_id: psk_id.unwrap(),
+    });
+
+    match mode_id {
+        0 => OpModeS::Base,
+        1 => OpModeS::Psk(bundle.unwrap()),
+        2 => OpModeS::Auth(sender_keypair.unwrap()),
+        3 => OpModeS::AuthPsk(sender_keypair.unwrap(), bundle.unwrap()),
+        _ => panic!("Invalid mode ID: {}", mode_id),
+    }
+}
+
+/// Does the sender side of a test vector. This runs `setup_sender` with the test vector's
+/// ephemeral IKM, and checks that the encapped key, ciphertexts, and exports match.
+fn test_case_sender<A: Aead, Kdf: KdfTrait, Kem: KemTrait>(tv: &MainTestVector) {
+    let (_, pk_recip) = get_and_validate_keypair::<Kem::Kex>(&tv.sk_recip, &tv.pk_recip);
+    let sender_keypair = tv
+        .sk_sender
+        .as_ref()
+        .map(|sk| get_and_validate_keypair::<Kem::Kex>(sk, tv.pk_sender.as_ref().unwrap()));
+    let mode = make_op_mode_s(
+        tv.mode,
+        sender_keypair,
+        tv.psk.as_deref(),
+        tv.psk_id.as_deref(),
+    );
+
+    let mut csprng = ReplayRng(&tv.ikm_eph);
+    let (encapped_key, mut aead_ctx) =
+        setup_sender::<A, Kdf, Kem, _>(&mode, &pk_recip, &tv.info, &mut csprng)
+            .expect("setup_sender failed");
+    assert_eq!(
+        encapped_key.to_bytes().as_slice(),
+        tv.encapped_key.as_slice(),
+        "sender's encapped keys don't match"
+    );
+
+    // Seal every plaintext in sequence and compare against ciphertext || tag
+    for enc_packet in tv.encryptions.iter() {
+        let mut ciphertext = enc_packet.plaintext.clone();
+        let tag = aead_ctx
+            .seal(&mut ciphertext, &enc_packet.aad)
+            .expect("seal failed");
+        ciphertext.extend_from_slice(&tag.to_bytes());
+        assert_eq!(
+            ciphertext, enc_packet.ciphertext,
+            "sender's ciphertexts don't match"
+        );
+    }
+
+    for export in tv.exports.iter() {
+        let mut exported_val = vec![0u8; export.export_len];
+        aead_ctx
+            .export(&export.export_ctx, &mut exported_val)
+            .unwrap();
+        assert_eq!(
+            exported_val, export.export_val,
+            "sender's export values don't match"
+        );
+    }
+}
+
 // This does all the legwork
 fn test_case<A: Aead, Kdf: KdfTrait, Kem: KemTrait>(tv: MainTestVector) {
+    // Do the sender side first, since the receiver side consumes parts of the test vector
+    test_case_sender::<A, Kdf, Kem>(&tv);
+
     // First, deserialize all the relevant keys so we can reconstruct the encapped key
     let recip_keypair = get_and_validate_keypair::<Kem::Kex>(&tv.sk_recip, &tv.pk_recip);
     let eph_keypair = get_and_validate_keypair::<Kem::Kex>(&tv.sk_eph, &tv.pk_eph);
@@ -181,17 +255,17 @@ fn test_case<A: Aead, Kdf: KdfTrait, Kem: KemTrait>(tv: MainTestVector) {
 
     // Make sure the keys match what we would've gotten had we used DeriveKeyPair
     {
-        let derived_kp = Kem::derive_keypair(&tv.ikm_recip);
+        let derived_kp = Kem::derive_keypair(&tv.ikm_recip).unwrap();
         assert_serializable_eq!(recip_keypair.0, derived_kp.0, "sk recip doesn't match");
         assert_serializable_eq!(recip_keypair.1, derived_kp.1, "pk recip doesn't match");
     }
     {
-        let derived_kp = Kem::derive_keypair(&tv.ikm_eph);
+        let derived_kp = Kem::derive_keypair(&tv.ikm_eph).unwrap();
         assert_serializable_eq!(eph_keypair.0, derived_kp.0, "sk eph doesn't match");
         assert_serializable_eq!(eph_keypair.1, derived_kp.1, "pk eph doesn't match");
     }
     if let Some(sks) = sender_keypair.as_ref() {
-        let derived_kp = Kem::derive_keypair(&tv.ikm_sender.unwrap());
+        let derived_kp = Kem::derive_keypair(&tv.ikm_sender.unwrap()).unwrap();
         assert_serializable_eq!(sks.0, derived_kp.0, "sk sender doesn't match");
         assert_serializable_eq!(sks.1, derived_kp.1, "pk sender doesn't match");
     }
@@ -201,13 +275,16 @@ fn test_case<A: Aead, Kdf: KdfTrait, Kem: KemTrait>(tv: MainTestVector) {
 
     // Now derive the encapped key with the deterministic encap function, using all the inputs
     // above
-    let (shared_secret, encapped_key) =
-        encap_with_eph::<Kem>(&pk_recip, sender_keypair.as_ref(), sk_eph.clone())
-            .expect("encap failed");
+    let (shared_secret, encapped_key) = encap_with_eph::<Kem>(
+        &pk_recip,
+        sender_keypair.as_ref().map(|(sk, pk)| (sk, pk)),
+        sk_eph.clone(),
+    )
+    .expect("encap failed");
 
     // Assert that the derived shared secret key is identical to the one provided
     assert_eq!(
-        shared_secret.as_slice(),
+        shared_secret.as_ref(),
         tv.shared_secret.as_slice(),
         "shared_secret doesn't match"
     );
@@ -226,8 +303,8 @@ fn test_case<A: Aead, Kdf: KdfTrait, Kem: KemTrait>(tv: MainTestVector) {
     let mode = make_op_mode_r(
         tv.mode,
         sender_keypair.map(|(_, pk)| pk),
-        tv.psk.as_ref().map(Vec::as_slice),
-        tv.psk_id.as_ref().map(Vec::as_slice),
+        tv.psk.as_deref(),
+        tv.psk_id.as_deref(),
     );
     let mut aead_ctx = setup_receiver::<A, Kdf, Kem>(&mode, &sk_recip, &encapped_key, &tv.info)
         .expect("setup_receiver failed");
@@ -331,6 +408,16 @@ fn kat_test() {
     let file = File::open("test-vectors-5f503c5.json").unwrap();
     let tvs: Vec<MainTestVector> = serde_json::from_reader(file).unwrap();
 
+    // AuthPSK is the only mode that has both a sender identity and a PSK in the key schedule.
+    // Make sure it's actually covered for every KEM we support.
+    let count_auth_psk = |kem_id| {
+        tvs.iter()
+            .filter(|tv| tv.mode == 0x03 && tv.kem_id == kem_id)
+            .count()
+    };
+    assert!(count_auth_psk(X25519HkdfSha256::KEM_ID) > 0);
+    assert!(count_auth_psk(DhP256HkdfSha256::KEM_ID) > 0);
+
     for tv in tvs.into_iter() {
         // Ignore everything that doesn't use X25519 or P256, since that's all we support right now
         if tv.kem_id != DhP256HkdfSha256::KEM_ID && tv.kem_id != X25519HkdfSha256::KEM_ID {
@@ -353,3 +440,65 @@ fn kat_test() {
         );
     }
 }
+
+/// Tests that vectors made by `generate_test_vector` pass the same known-answer test as the RFC
+/// vectors, in every mode
+#[cfg(feature = "test-internals")]
+#[test]
+fn generated_vector_kat() {
+    use crate::test_vector::{generate_test_vector, TestVectorParams};
+
+    fn run<A: Aead, Kdf: KdfTrait, Kem: KemTrait>() {
+        let psk = PskBundle {
+            psk: b"a pre-shared key that's long enough",
+            psk_id: b"psk id",
+        };
+        let ikm_eph = vec![7u8; <<Kem::Kex as KeyExchange>::PrivateKey as Serializable>::size()];
+        let encryptions: &[(&[u8], &[u8])] = &[(b"first", b"aad 0"), (b"second", b""), (b"", b"x")];
+        let exports: &[(&[u8], usize)] = &[(b"", 32), (b"ctx", 7)];
+
+        for (ikm_sender, psk) in [
+            (None, None),
+            (None, Some(psk)),
+            (Some(&b"sender ikm"[..]), None),
+            (Some(&b"sender ikm"[..]), Some(psk)),
+        ]
+        .iter()
+        {
+            let params = TestVectorParams {
+                info: b"generated vector",
+                ikm_recip: b"recipient ikm",
+                ikm_eph: &ikm_eph,
+                ikm_sender: *ikm_sender,
+                psk: *psk,
+                encryptions,
+                exports,
+            };
+            let tv = generate_test_vector::<A, Kdf, Kem>(&params).unwrap();
+            let parsed: MainTestVector = serde_json::from_str(&tv.to_json()).unwrap();
+            assert_eq!(parsed.mode, tv.mode);
+            test_case::<A, Kdf, Kem>(parsed);
+        }
+
+        // The ephemeral IKM has to be exactly Nsk bytes
+        let params = TestVectorParams {
+            info: b"",
+            ikm_recip: b"",
+            ikm_eph: b"short",
+            ikm_sender: None,
+            psk: None,
+            encryptions,
+            exports,
+        };
+        assert_eq!(
+            generate_test_vector::<A, Kdf, Kem>(&params),
+            Err(crate::HpkeError::IncorrectInputLength {
+                expected: ikm_eph.len(),
+                got: 5
+            })
+        );
+    }
+
+    run::<ChaCha20Poly1305, HkdfSha256, X25519HkdfSha256>();
+    run::<AesGcm128, HkdfSha384, DhP256HkdfSha256>();
+}
@@ -1,11 +1,15 @@
-use crate::kem::Kem as KemTrait;
+use crate::{kem::Kem as KemTrait, HpkeError};
 
 use byteorder::{BigEndian, ByteOrder};
+use core::marker::PhantomData;
 use digest::{BlockInput, Digest, FixedOutput, Reset, Update};
-use generic_array::GenericArray;
+use generic_array::{
+    typenum::{IsLessOrEqual, True, Unsigned, U64},
+    GenericArray,
+};
 use sha2::{Sha256, Sha384, Sha512};
 
-const VERSION_LABEL: &[u8] = b"HPKE-v1";
+pub(crate) const VERSION_LABEL: &[u8] = b"HPKE-v1";
 
 // This is currently the maximum value of Nh. It is achieved by HKDF-SHA512 in draft11 §7.2.
 pub(crate) const MAX_DIGEST_SIZE: usize = 64;
@@ -20,43 +24,66 @@ pub trait Kdf {
 
     /// The algorithm identifier for a KDF implementation
     const KDF_ID: u16;
+
+    /// `Nh`, the output size of the underlying hash function, in bytes. This is the length of an
+    /// exporter secret, and `255 * N_H` is the most that `export` can output.
+    const N_H: usize = <<Self::HashImpl as FixedOutput>::OutputSize as Unsigned>::USIZE;
 }
 
 // We use Kdf as a type parameter, so this is to avoid ambiguity.
 use Kdf as KdfTrait;
 
-/// The implementation of HKDF-SHA256
-pub struct HkdfSha256 {}
+/// HKDF over an arbitrary hash function `D`, with the algorithm identifier `ID`. This is for
+/// experimenting with hash functions that HPKE doesn't define a KDF for. `D`'s output must be at
+/// most 64 bytes, the largest `Nh` in draft11 §7.2. This is checked at compile time.
+///
+/// **WARNING:** An `ID` that isn't in the IANA registry makes a ciphersuite that isn't HPKE, and
+/// that won't interoperate with any other implementation. Don't reuse a registered ID for a
+/// different hash function.
+pub struct HkdfGeneric<D, const ID: u16> {
+    _marker: PhantomData<D>,
+}
 
-impl KdfTrait for HkdfSha256 {
+impl<D, const ID: u16> KdfTrait for HkdfGeneric<D, ID>
+where
+    D: Digest + Update + BlockInput + FixedOutput + Reset + Default + Clone,
+    // Buffers throughout the crate are MAX_DIGEST_SIZE bytes
+    <D as FixedOutput>::OutputSize: IsLessOrEqual<U64, Output = True>,
+{
     #[doc(hidden)]
-    type HashImpl = Sha256;
+    type HashImpl = D;
 
-    // draft11 §7.2: HKDF-SHA256
-    const KDF_ID: u16 = 0x0001;
+    const KDF_ID: u16 = ID;
 }
 
+/// The implementation of HKDF-SHA256
+// draft11 §7.2: HKDF-SHA256
+pub type HkdfSha256 = HkdfGeneric<Sha256, 0x0001>;
+
 /// The implementation of HKDF-SHA384
-pub struct HkdfSha384 {}
+// draft11 §7.2: HKDF-SHA384
+pub type HkdfSha384 = HkdfGeneric<Sha384, 0x0002>;
 
-impl KdfTrait for HkdfSha384 {
-    #[doc(hidden)]
-    type HashImpl = Sha384;
+/// The implementation of HKDF-SHA512
+// draft11 §7.2: HKDF-SHA512
+pub type HkdfSha512 = HkdfGeneric<Sha512, 0x0003>;
 
-    // draft11 §7.2: HKDF-SHA384
-    const KDF_ID: u16 = 0x0002;
+/// Checks at compile time that the given KDF's digest size is `nh` and that it fits within
+/// `MAX_DIGEST_SIZE`
+macro_rules! assert_kdf_sizes {
+    ($kdf:ty, nh = $nh:expr) => {
+        const_assert!(<$kdf as KdfTrait>::N_H == $nh);
+        const_assert!(<$kdf as KdfTrait>::N_H <= MAX_DIGEST_SIZE);
+    };
 }
 
-/// The implementation of HKDF-SHA512
-pub struct HkdfSha512 {}
-
-impl KdfTrait for HkdfSha512 {
-    #[doc(hidden)]
-    type HashImpl = Sha512;
+// The bound on HkdfGeneric's digest size is MAX_DIGEST_SIZE
+const_assert!(U64::USIZE == MAX_DIGEST_SIZE);
 
-    // draft11 §7.2: HKDF-SHA512
-    const KDF_ID: u16 = 0x0003;
-}
+// draft11 §7.2: Nh of HKDF-SHA256, HKDF-SHA384, and HKDF-SHA512 is 32, 48, and 64
+assert_kdf_sizes!(HkdfSha256, nh = 32);
+assert_kdf_sizes!(HkdfSha384, nh = 48);
+assert_kdf_sizes!(HkdfSha512, nh = 64);
 
 // draft11 §4.1
 // def ExtractAndExpand(dh, kem_context):
@@ -66,17 +93,26 @@ impl KdfTrait for HkdfSha512 {
 //   return shared_secret
 
 /// Uses the given IKM to extract a secret, and then uses that secret, plus the given suite ID and
-/// info string, to expand to the output buffer
+/// info string followed by `info_suffix`, to expand to the output buffer. In HPKE, `info_suffix`
+/// is always empty.
 pub(crate) fn extract_and_expand<Kem: KemTrait>(
     ikm: &[u8],
     suite_id: &[u8],
     info: &[u8],
+    info_suffix: &[u8],
     out: &mut [u8],
 ) -> Result<(), hkdf::InvalidLength> {
     // Extract using given IKM
     let (_, hkdf_ctx) = labeled_extract::<Kem::Kdf>(&[], suite_id, b"eae_prk", ikm);
     // Expand using given info string
-    hkdf_ctx.labeled_expand(suite_id, b"shared_secret", info, out)
+    hkdf_ctx.labeled_expand_split(
+        VERSION_LABEL,
+        suite_id,
+        b"shared_secret",
+        info,
+        info_suffix,
+        out,
+    )
 }
 
 // draft11 §4.0
@@ -93,16 +129,81 @@ pub(crate) fn labeled_extract<Kdf: KdfTrait>(
 ) -> (
     GenericArray<u8, <<Kdf as KdfTrait>::HashImpl as FixedOutput>::OutputSize>,
     hkdf::Hkdf<Kdf::HashImpl>,
+) {
+    labeled_extract_with_version::<Kdf>(VERSION_LABEL, salt, suite_id, label, ikm)
+}
+
+/// Does `labeled_extract` with `version_label` in place of `"HPKE-v1"`
+pub(crate) fn labeled_extract_with_version<Kdf: KdfTrait>(
+    version_label: &[u8],
+    salt: &[u8],
+    suite_id: &[u8],
+    label: &[u8],
+    ikm: &[u8],
+) -> (
+    GenericArray<u8, <<Kdf as KdfTrait>::HashImpl as FixedOutput>::OutputSize>,
+    hkdf::Hkdf<Kdf::HashImpl>,
 ) {
     // Call HKDF-Extract with the IKM being the concatenation of all of the above
     let mut extract_ctx = hkdf::HkdfExtract::<Kdf::HashImpl>::new(Some(salt));
-    extract_ctx.input_ikm(VERSION_LABEL);
+    extract_ctx.input_ikm(version_label);
     extract_ctx.input_ikm(suite_id);
     extract_ctx.input_ikm(label);
     extract_ctx.input_ikm(ikm);
     extract_ctx.finalize()
 }
 
+/// The pseudorandom key output by HKDF-Extract. This is secret, so treat it like a key.
+pub type Prk<Kdf> = GenericArray<u8, <<Kdf as KdfTrait>::HashImpl as FixedOutput>::OutputSize>;
+
+/// An HKDF-Expand context for a fixed PRK, made by `labeled_extract_prk`
+pub struct Expander<Kdf: KdfTrait>(hkdf::Hkdf<Kdf::HashImpl>);
+
+impl<Kdf: KdfTrait> Expander<Kdf> {
+    /// Does HPKE's `LabeledExpand(prk, label, info, L)` with this context's PRK and the given
+    /// suite ID, where `L` is the length of `out`. The output is written to `out`.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If `out` is longer than 255x the digest size of the
+    /// underlying hash function, returns `Err(HpkeError::KdfOutputTooLong)`.
+    pub fn labeled_expand(
+        &self,
+        suite_id: &[u8],
+        label: &[u8],
+        info: &[u8],
+        out: &mut [u8],
+    ) -> Result<(), HpkeError> {
+        // Don't let the length assert in LabeledExpand get hit. Anything this long is too long for
+        // HKDF anyway.
+        if out.len() > u16::MAX as usize {
+            return Err(HpkeError::KdfOutputTooLong);
+        }
+
+        self.0
+            .labeled_expand(suite_id, label, info, out)
+            .map_err(|_| HpkeError::KdfOutputTooLong)
+    }
+}
+
+/// Does HPKE's `LabeledExtract(salt, label, ikm)` with the given suite ID. This is for building
+/// custom derivations out of HPKE's labeled KDF. Note that HPKE's own suite IDs are
+/// `"KEM"||kem_id` in the KEM and `"HPKE"||kem_id||kdf_id||aead_id` everywhere else, so if you
+/// use your own derivations alongside HPKE, pick a suite ID or label that HPKE doesn't use.
+///
+/// Return Value
+/// ============
+/// Returns the PRK, and an `Expander` for doing `LabeledExpand` with that PRK
+pub fn labeled_extract_prk<Kdf: KdfTrait>(
+    salt: &[u8],
+    suite_id: &[u8],
+    label: &[u8],
+    ikm: &[u8],
+) -> (Prk<Kdf>, Expander<Kdf>) {
+    let (prk, hkdf_ctx) = labeled_extract::<Kdf>(salt, suite_id, label, ikm);
+    (prk, Expander(hkdf_ctx))
+}
+
 // This trait only exists so I can implement it for hkdf::Hkdf
 pub(crate) trait LabeledExpand {
     fn labeled_expand(
@@ -111,6 +212,32 @@ pub(crate) trait LabeledExpand {
         label: &[u8],
         info: &[u8],
         out: &mut [u8],
+    ) -> Result<(), hkdf::InvalidLength> {
+        self.labeled_expand_with_version(VERSION_LABEL, suite_id, label, info, out)
+    }
+
+    /// Does `labeled_expand` with `version_label` in place of `"HPKE-v1"`
+    fn labeled_expand_with_version(
+        &self,
+        version_label: &[u8],
+        suite_id: &[u8],
+        label: &[u8],
+        info: &[u8],
+        out: &mut [u8],
+    ) -> Result<(), hkdf::InvalidLength> {
+        self.labeled_expand_split(version_label, suite_id, label, info, &[], out)
+    }
+
+    /// Does `labeled_expand_with_version` with `info || info_suffix` as the info string, without
+    /// copying the two into one buffer
+    fn labeled_expand_split(
+        &self,
+        version_label: &[u8],
+        suite_id: &[u8],
+        label: &[u8],
+        info: &[u8],
+        info_suffix: &[u8],
+        out: &mut [u8],
     ) -> Result<(), hkdf::InvalidLength>;
 }
 
@@ -122,11 +249,13 @@ impl<D: Update + BlockInput + FixedOutput + Reset + Default + Clone> LabeledExpa
     //   labeled_info = concat(I2OSP(L, 2), "HPKE-v1", suite_id,
     //                         label, info)
     //   return Expand(prk, labeled_info, L)
-    fn labeled_expand(
+    fn labeled_expand_split(
         &self,
+        version_label: &[u8],
         suite_id: &[u8],
         label: &[u8],
         info: &[u8],
+        info_suffix: &[u8],
         out: &mut [u8],
     ) -> Result<(), hkdf::InvalidLength> {
         // We need to write the length as a u16, so that's the de-facto upper bound on length
@@ -137,7 +266,163 @@ impl<D: Update + BlockInput + FixedOutput + Reset + Default + Clone> LabeledExpa
         BigEndian::write_u16(&mut len_buf, out.len() as u16);
 
         // Call HKDF-Expand() with the info string set to the concatenation of all of the above
-        let labeled_info = [&len_buf, VERSION_LABEL, suite_id, label, info];
+        let labeled_info = [&len_buf, version_label, suite_id, label, info, info_suffix];
         self.expand_multi_info(&labeled_info, out)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        labeled_extract_prk, HkdfGeneric, HkdfSha256, HkdfSha384, HkdfSha512, Kdf as KdfTrait, Prk,
+    };
+    use crate::HpkeError;
+
+    use sha2::Sha256;
+
+    /// Tests the public labeled KDF functions against the key schedule of draft11 §A.1.1
+    /// (DHKEM(X25519, HKDF-SHA256), HKDF-SHA256, AES-128-GCM, Base mode)
+    #[test]
+    fn test_labeled_extract_prk_vector() {
+        type Kdf = HkdfSha256;
+
+        let unhex = |s: &str| hex::decode(s).unwrap();
+        // suite_id = "HPKE" || kem_id || kdf_id || aead_id
+        let suite_id = b"HPKE\x00\x20\x00\x01\x00\x01";
+        let info = unhex("4f6465206f6e2061204772656369616e2055726e");
+        let shared_secret =
+            unhex("fe0e18c9f024ce43799ae393c7e8fe8fce9d218875e8227b0187c04e7d2ea1fc");
+
+        // key_schedule_context = mode || psk_id_hash || info_hash, where the hashes are the PRKs
+        // of LabeledExtract("", label, ikm)
+        let (psk_id_hash, _) = labeled_extract_prk::<Kdf>(b"", suite_id, b"psk_id_hash", b"");
+        let (info_hash, _) = labeled_extract_prk::<Kdf>(b"", suite_id, b"info_hash", &info);
+        let mut key_schedule_context = vec![0x00];
+        key_schedule_context.extend_from_slice(&psk_id_hash);
+        key_schedule_context.extend_from_slice(&info_hash);
+        assert_eq!(
+            key_schedule_context,
+            unhex(
+                "00725611c9d98c07c03f60095cd32d400d8347d45ed67097bbad50fc56da742d07cb6cffde367bb0\
+                 565ba28bb02c90744a20f5ef37f30523526106f637abb05449"
+            )
+        );
+
+        // secret = LabeledExtract(shared_secret, "secret", psk)
+        let (secret, expander) =
+            labeled_extract_prk::<Kdf>(&shared_secret, suite_id, b"secret", b"");
+        assert_eq!(
+            secret.as_slice(),
+            unhex("12fff91991e93b48de37e7daddb52981084bd8aa64289c3788471d9a9712f397")
+        );
+
+        // key = LabeledExpand(secret, "key", key_schedule_context, Nk)
+        let mut key = [0u8; 16];
+        expander
+            .labeled_expand(suite_id, b"key", &key_schedule_context, &mut key)
+            .unwrap();
+        assert_eq!(key.to_vec(), unhex("4531685d41d65f03dc48f6b8302c05b0"));
+
+        // HKDF-SHA256 can't output more than 255 * Nh bytes
+        let mut too_long = vec![0u8; 255 * Kdf::N_H + 1];
+        assert_eq!(
+            expander.labeled_expand(suite_id, b"key", b"", &mut too_long),
+            Err(HpkeError::KdfOutputTooLong)
+        );
+    }
+
+    /// Tests that every KDF reports the `Nh` from draft11 §7.2
+    #[test]
+    fn test_n_h() {
+        assert_eq!(HkdfSha256::N_H, 32);
+        assert_eq!(HkdfSha384::N_H, 48);
+        assert_eq!(HkdfSha512::N_H, 64);
+    }
+
+    /// Tests that `HkdfGeneric<Sha256, 0x0001>` is HKDF-SHA256, and that a custom ID only changes
+    /// the KDF ID
+    #[test]
+    fn test_hkdf_generic() {
+        type Generic = HkdfGeneric<Sha256, 0x0001>;
+        type Custom = HkdfGeneric<Sha256, 0x7F01>;
+
+        assert_eq!(Generic::KDF_ID, HkdfSha256::KDF_ID);
+        assert_eq!(Generic::N_H, HkdfSha256::N_H);
+        assert_eq!(Custom::KDF_ID, 0x7F01);
+        assert_eq!(Custom::N_H, 32);
+
+        // With the same suite ID, the labeled KDF functions give the same outputs
+        fn kdf_outputs<Kdf: KdfTrait>() -> (Prk<Kdf>, [u8; 40]) {
+            let suite_id = b"HPKE\x00\x20\x00\x01\x00\x01";
+            let (prk, expander) = labeled_extract_prk::<Kdf>(b"salt", suite_id, b"label", b"ikm");
+            let mut out = [0u8; 40];
+            expander
+                .labeled_expand(suite_id, b"key", b"info", &mut out)
+                .unwrap();
+            (prk, out)
+        }
+        assert_eq!(kdf_outputs::<Generic>(), kdf_outputs::<HkdfSha256>());
+        assert_eq!(kdf_outputs::<Custom>(), kdf_outputs::<HkdfSha256>());
+    }
+
+    /// Tests that a ciphersuite with a custom KDF ID works end to end, and that the ID is bound
+    /// into the key schedule
+    #[cfg(feature = "x25519-dalek")]
+    #[test]
+    fn test_hkdf_generic_custom_id() {
+        use crate::{
+            aead::ChaCha20Poly1305,
+            kem::{Kem as KemTrait, X25519HkdfSha256},
+            op_mode::{OpModeR, OpModeS},
+            single_shot::{single_shot_open, single_shot_seal},
+        };
+        use rand::{rngs::StdRng, SeedableRng};
+
+        type A = ChaCha20Poly1305;
+        type Custom = HkdfGeneric<Sha256, 0x7F01>;
+        type Kem = X25519HkdfSha256;
+
+        let mut csprng = StdRng::from_entropy();
+        let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+        let msg = b"custom kdf";
+
+        let mut ciphertext = *msg;
+        let (encapped_key, tag) = single_shot_seal::<A, Custom, Kem, _>(
+            &OpModeS::Base,
+            &pk_recip,
+            b"info",
+            &mut ciphertext,
+            b"",
+            &mut csprng,
+        )
+        .unwrap();
+
+        // HKDF-SHA256 with the registered ID derives a different key
+        let mut buf = ciphertext;
+        assert_eq!(
+            single_shot_open::<A, HkdfSha256, Kem>(
+                &OpModeR::Base,
+                &sk_recip,
+                &encapped_key,
+                b"info",
+                &mut buf,
+                b"",
+                &tag,
+            ),
+            Err(HpkeError::OpenError)
+        );
+
+        let mut buf = ciphertext;
+        single_shot_open::<A, Custom, Kem>(
+            &OpModeR::Base,
+            &sk_recip,
+            &encapped_key,
+            b"info",
+            &mut buf,
+            b"",
+            &tag,
+        )
+        .unwrap();
+        assert_eq!(&buf, msg);
+    }
+}
@@ -5,9 +5,24 @@ use crate::{
     HpkeError,
 };
 
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
 use digest::FixedOutput;
-use generic_array::GenericArray;
+use generic_array::{typenum::Unsigned, GenericArray};
 use rand_core::{CryptoRng, RngCore};
+#[cfg(any(feature = "x25519-dalek", feature = "p256"))]
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+/// Where `Kem::keypair` gets its keying material from
+pub enum KeySource<'a, R: CryptoRng + RngCore> {
+    /// Generate a random keypair with this RNG, as `gen_keypair` does
+    Random(&'a mut R),
+    /// Derive a keypair from this input keying material, as `derive_keypair` does. Since there's
+    /// no RNG here, `R` has to be given explicitly, e.g., `KeySource::<OsRng>::Ikm(ikm)`.
+    Ikm(&'a [u8]),
+}
 
 /// Defines a combination of key exchange mechanism and a KDF, which together form a KEM
 pub trait Kem: Sized {
@@ -24,17 +39,52 @@ pub trait Kem: Sized {
     /// This keying material SHOULD have as many bits of entropy as the bit length of a secret key,
     /// i.e., `8 * Self::Kex::PrivateKey::size()`. For X25519 and P-256, this is 256 bits of
     /// entropy.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok((sk, pk))` on success. If the KEM rejection-samples private keys, as P-256
+    /// does, and every candidate is rejected, returns `Err(HpkeError::DeriveKeyPairError)`. For
+    /// P-256, this happens with probability 2^-8192.
     fn derive_keypair(
         ikm: &[u8],
-    ) -> (
-        <Self::Kex as KeyExchange>::PrivateKey,
-        <Self::Kex as KeyExchange>::PublicKey,
-    ) {
+    ) -> Result<
+        (
+            <Self::Kex as KeyExchange>::PrivateKey,
+            <Self::Kex as KeyExchange>::PublicKey,
+        ),
+        HpkeError,
+    > {
         let suite_id = kem_suite_id::<Self>();
         Self::Kex::derive_keypair::<Self::Kdf>(&suite_id, ikm)
     }
 
+    /// Does `derive_keypair` and also returns the value of the DeriveKeyPair rejection sampling
+    /// counter that produced the returned keypair, i.e., the number of candidate private keys that
+    /// were rejected. KEMs that don't do rejection sampling always return 0. This is only meant
+    /// for testing and interop diagnostics.
+    #[cfg(feature = "test-internals")]
+    fn derive_keypair_counted(
+        ikm: &[u8],
+    ) -> Result<
+        (
+            <Self::Kex as KeyExchange>::PrivateKey,
+            <Self::Kex as KeyExchange>::PublicKey,
+            u8,
+        ),
+        HpkeError,
+    > {
+        let suite_id = kem_suite_id::<Self>();
+        Self::Kex::derive_keypair_counted::<Self::Kdf>(&suite_id, ikm)
+    }
+
     /// Generates a random keypair using the given RNG
+    ///
+    /// Panics
+    /// ======
+    /// Panics if the RNG fails, since `RngCore::fill_bytes` does. Functions which do an
+    /// encapsulation, like `setup_sender`, return `Err(HpkeError::EncapError)` on an RNG failure
+    /// instead. Also panics if `derive_keypair` fails on the random keying material, which for
+    /// P-256 happens with probability 2^-8192.
     fn gen_keypair<R: CryptoRng + RngCore>(
         csprng: &mut R,
     ) -> (
@@ -49,10 +99,82 @@ pub trait Kem: Sized {
         // Fill it with randomness
         csprng.fill_bytes(&mut ikm);
         // Run derive_keypair using the KEM's KDF
-        Self::derive_keypair(&ikm)
+        Self::derive_keypair(&ikm).expect("DeriveKeyPair failed on random keying material")
+    }
+
+    /// Makes a keypair from the given source. `KeySource::Random(csprng)` is exactly
+    /// `gen_keypair(csprng)`, and `KeySource::Ikm(ikm)` is exactly `derive_keypair(ikm)`. This is
+    /// for generic code that needs either one.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok((sk, pk))` on success. If the source is `KeySource::Ikm`, errors are the same as
+    /// in `derive_keypair`.
+    ///
+    /// Panics
+    /// ======
+    /// Panics if the source is `KeySource::Random` and the RNG fails. See `gen_keypair`.
+    fn keypair<R: CryptoRng + RngCore>(
+        source: KeySource<R>,
+    ) -> Result<
+        (
+            <Self::Kex as KeyExchange>::PrivateKey,
+            <Self::Kex as KeyExchange>::PublicKey,
+        ),
+        HpkeError,
+    > {
+        match source {
+            KeySource::Random(csprng) => Ok(Self::gen_keypair(csprng)),
+            KeySource::Ikm(ikm) => Self::derive_keypair(ikm),
+        }
+    }
+
+    /// Generates a random keypair, runs `f` on it, and returns what `f` returns. The private key
+    /// is zeroized and dropped as soon as `f` returns, so the ephemeral private key can't be kept
+    /// around by accident.
+    ///
+    /// Panics
+    /// ======
+    /// Panics if the RNG fails. See `gen_keypair`.
+    fn with_ephemeral_keypair<R, T, F>(csprng: &mut R, f: F) -> T
+    where
+        R: CryptoRng + RngCore,
+        F: FnOnce(
+            &<Self::Kex as KeyExchange>::PrivateKey,
+            &<Self::Kex as KeyExchange>::PublicKey,
+        ) -> T,
+        <Self::Kex as KeyExchange>::PrivateKey: Zeroize,
+    {
+        let (mut sk, pk) = Self::gen_keypair(csprng);
+        let out = f(&sk, &pk);
+        // sk isn't moved, so this wipes the same memory that f saw
+        sk.zeroize();
+        out
+    }
+
+    /// Generates a random keypair using the OS RNG. This is a convenience for when you don't need
+    /// a specific RNG. Use `gen_keypair` if you need determinism or a custom RNG.
+    #[cfg(feature = "getrandom")]
+    fn gen_keypair_os() -> (
+        <Self::Kex as KeyExchange>::PrivateKey,
+        <Self::Kex as KeyExchange>::PublicKey,
+    ) {
+        Self::gen_keypair(&mut rand_core::OsRng)
     }
 }
 
+/// The sizes that follow from a KEM's types. This is implemented for every `Kem`, and can't be
+/// implemented any other way, so the sizes always agree with the types.
+pub trait KemSizes: Kem {
+    /// `Nsecret`, the length of the KEM's shared secret, in bytes. This is the length of the
+    /// array in a `SharedSecret`.
+    const N_SECRET: usize;
+}
+
+impl<K: Kem> KemSizes for K {
+    const N_SECRET: usize = <SharedSecretSize<K> as Unsigned>::USIZE;
+}
+
 // Kem is also used as a type parameter everywhere. To avoid confusion, alias it
 use Kem as KemTrait;
 
@@ -69,6 +191,22 @@ impl KemTrait for X25519HkdfSha256 {
     const KEM_ID: u16 = 0x0020;
 }
 
+#[cfg(feature = "x25519-dalek")]
+impl crate::kex::x25519::PublicKey {
+    /// Returns a short, stable identifier for this public key under DHKEM(X25519, HKDF-SHA256).
+    /// This is useful for routing, but is NOT a substitute for comparing the full key.
+    pub fn key_id(&self) -> [u8; 8] {
+        key_id::<X25519HkdfSha256>(self)
+    }
+
+    /// Serializes this public key, prefixed with the one-byte KEM ID of DHKEM(X25519,
+    /// HKDF-SHA256). Parse with `public_key_from_tagged_bytes`.
+    #[cfg(feature = "alloc")]
+    pub fn to_tagged_bytes(&self) -> Vec<u8> {
+        tagged_bytes::<X25519HkdfSha256>(self)
+    }
+}
+
 #[cfg(feature = "p256")]
 /// Represents DHKEM(P-256, HKDF-SHA256)
 pub struct DhP256HkdfSha256 {}
@@ -82,12 +220,285 @@ impl KemTrait for DhP256HkdfSha256 {
     const KEM_ID: u16 = 0x0010;
 }
 
+#[cfg(feature = "p256")]
+impl crate::kex::ecdh_nistp::PublicKey {
+    /// Returns a short, stable identifier for this public key under DHKEM(P-256, HKDF-SHA256).
+    /// This is useful for routing, but is NOT a substitute for comparing the full key.
+    pub fn key_id(&self) -> [u8; 8] {
+        key_id::<DhP256HkdfSha256>(self)
+    }
+
+    /// Serializes this public key, prefixed with the one-byte KEM ID of DHKEM(P-256,
+    /// HKDF-SHA256). Parse with `public_key_from_tagged_bytes`.
+    #[cfg(feature = "alloc")]
+    pub fn to_tagged_bytes(&self) -> Vec<u8> {
+        tagged_bytes::<DhP256HkdfSha256>(self)
+    }
+}
+
+/// Checks at compile time that the given KEM's pubkey, privkey, and shared secret lengths are
+/// `npk`, `nsk`, and `nsecret`, respectively, and that pubkeys and DH results fit within
+/// `MAX_PUBKEY_SIZE`. The encapped key length `Nenc` is always `Npk`, since encapped keys are
+/// pubkeys.
+#[allow(unused_macros)]
+macro_rules! assert_kem_sizes {
+    ($kem:ty, npk = $npk:expr, nsk = $nsk:expr, nsecret = $nsecret:expr) => {
+        const_assert!(
+            <<KemPubkey<$kem> as Serializable>::OutputSize as Unsigned>::USIZE == $npk
+        );
+        const_assert!(
+            <<KemPrivkey<$kem> as Serializable>::OutputSize as Unsigned>::USIZE == $nsk
+        );
+        const_assert!(
+            <$kem as KemSizes>::N_SECRET == $nsecret
+        );
+        const_assert!(
+            <<KemPubkey<$kem> as Serializable>::OutputSize as Unsigned>::USIZE
+                <= MAX_PUBKEY_SIZE
+        );
+        const_assert!(
+            <<<<$kem as KemTrait>::Kex as KeyExchange>::KexResult as Serializable>::OutputSize as
+                Unsigned>::USIZE
+                <= MAX_PUBKEY_SIZE
+        );
+    };
+}
+
+// draft11 §7.1: Npk, Nsk, and Nsecret of DHKEM(X25519, HKDF-SHA256) are 32, 32, and 32
+#[cfg(feature = "x25519-dalek")]
+assert_kem_sizes!(X25519HkdfSha256, npk = 32, nsk = 32, nsecret = 32);
+
+// draft11 §7.1: Npk, Nsk, and Nsecret of DHKEM(P-256, HKDF-SHA256) are 65, 32, and 32
+#[cfg(feature = "p256")]
+assert_kem_sizes!(DhP256HkdfSha256, npk = 65, nsk = 32, nsecret = 32);
+
 /// Convenience types representing public/private keys corresponding to a KEM's underlying DH alg
 type KemPubkey<Kem> = <<Kem as KemTrait>::Kex as KeyExchange>::PublicKey;
 type KemPrivkey<Kem> = <<Kem as KemTrait>::Kex as KeyExchange>::PrivateKey;
 
+/// Computes the key ID of a public key. This is the first 8 bytes of
+/// `SHA-256(I2OSP(KEM_ID, 2) || pk)`. The KEM ID is included so that the same bytes under
+/// different KEMs get different IDs.
+#[cfg(any(feature = "x25519-dalek", feature = "p256"))]
+pub(crate) fn key_id<Kem: KemTrait>(pk: &KemPubkey<Kem>) -> [u8; 8] {
+    let digest = Sha256::new()
+        .chain(Kem::KEM_ID.to_be_bytes())
+        .chain(pk.to_bytes())
+        .finalize();
+
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&digest[..8]);
+    id
+}
+
+/// A public key of any supported KEM, as parsed by `public_key_from_tagged_bytes`
+#[cfg(any(feature = "x25519-dalek", feature = "p256"))]
+#[derive(Clone)]
+pub enum TaggedPublicKey {
+    /// A DHKEM(X25519, HKDF-SHA256) public key
+    #[cfg(feature = "x25519-dalek")]
+    X25519HkdfSha256(KemPubkey<X25519HkdfSha256>),
+    /// A DHKEM(P-256, HKDF-SHA256) public key
+    #[cfg(feature = "p256")]
+    DhP256HkdfSha256(KemPubkey<DhP256HkdfSha256>),
+}
+
+// The tagged format stores KEM IDs in one byte. Make sure they fit.
+#[cfg(feature = "x25519-dalek")]
+const_assert!(X25519HkdfSha256::KEM_ID <= 0xff);
+#[cfg(feature = "p256")]
+const_assert!(DhP256HkdfSha256::KEM_ID <= 0xff);
+
+/// Serializes a public key as `I2OSP(KEM_ID, 1) || pk`
+#[cfg(all(feature = "alloc", any(feature = "x25519-dalek", feature = "p256")))]
+fn tagged_bytes<Kem: KemTrait>(pk: &KemPubkey<Kem>) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(1 + KemPubkey::<Kem>::size());
+    buf.push(Kem::KEM_ID as u8);
+    buf.extend_from_slice(&pk.to_bytes());
+    buf
+}
+
+/// Parses a public key of the form `I2OSP(KEM_ID, 1) || pk`, as output by `to_tagged_bytes`. The
+/// KEM ID says which KEM's `from_bytes` is used to parse the rest.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(pk)` on success. If `buf` is empty, returns
+/// `Err(HpkeError::IncorrectInputLength { expected: 1, got: 0 })`. If the KEM ID isn't that of a
+/// KEM enabled in this build, returns `Err(HpkeError::ValidationError)`. If the key fails to
+/// deserialize, returns the error from that KEM's `from_bytes`.
+#[cfg(any(feature = "x25519-dalek", feature = "p256"))]
+pub fn public_key_from_tagged_bytes(buf: &[u8]) -> Result<TaggedPublicKey, HpkeError> {
+    let (tag, pk_bytes) = buf.split_first().ok_or(HpkeError::IncorrectInputLength {
+        expected: 1,
+        got: 0,
+    })?;
+
+    match u16::from(*tag) {
+        #[cfg(feature = "x25519-dalek")]
+        X25519HkdfSha256::KEM_ID => KemPubkey::<X25519HkdfSha256>::from_bytes(pk_bytes)
+            .map(TaggedPublicKey::X25519HkdfSha256),
+        #[cfg(feature = "p256")]
+        DhP256HkdfSha256::KEM_ID => KemPubkey::<DhP256HkdfSha256>::from_bytes(pk_bytes)
+            .map(TaggedPublicKey::DhP256HkdfSha256),
+        _ => Err(HpkeError::ValidationError),
+    }
+}
+
+/// A public key in the framing of the HPKE key field of an Encrypted ClientHello config, which is
+/// `I2OSP(KEM_ID, 2) || I2OSP(Npk, 2) || pk`
+#[cfg(any(feature = "x25519-dalek", feature = "p256"))]
+#[derive(Clone)]
+pub struct EchKeyConfig(pub TaggedPublicKey);
+
+/// Parses the key of an `EchKeyConfig`, checking the length field against the KEM's `Npk`
+#[cfg(any(feature = "x25519-dalek", feature = "p256"))]
+fn ech_public_key<Kem: KemTrait>(pk_bytes: &[u8]) -> Result<KemPubkey<Kem>, HpkeError> {
+    let npk = KemPubkey::<Kem>::size();
+    if pk_bytes.len() != npk {
+        return Err(HpkeError::IncorrectInputLength {
+            expected: npk,
+            got: pk_bytes.len(),
+        });
+    }
+    KemPubkey::<Kem>::from_bytes(pk_bytes)
+}
+
+/// Serializes a public key as `I2OSP(KEM_ID, 2) || I2OSP(Npk, 2) || pk`
+#[cfg(all(feature = "alloc", any(feature = "x25519-dalek", feature = "p256")))]
+fn ech_key_config_bytes<Kem: KemTrait>(pk: &KemPubkey<Kem>) -> Vec<u8> {
+    let npk = KemPubkey::<Kem>::size();
+    let mut buf = Vec::with_capacity(4 + npk);
+    buf.extend_from_slice(&Kem::KEM_ID.to_be_bytes());
+    // Every supported KEM's Npk fits in 2 bytes. See assert_kem_sizes
+    buf.extend_from_slice(&(npk as u16).to_be_bytes());
+    buf.extend_from_slice(&pk.to_bytes());
+    buf
+}
+
+#[cfg(any(feature = "x25519-dalek", feature = "p256"))]
+impl EchKeyConfig {
+    /// Returns the KEM ID of the key
+    pub fn kem_id(&self) -> u16 {
+        match self.0 {
+            #[cfg(feature = "x25519-dalek")]
+            TaggedPublicKey::X25519HkdfSha256(_) => X25519HkdfSha256::KEM_ID,
+            #[cfg(feature = "p256")]
+            TaggedPublicKey::DhP256HkdfSha256(_) => DhP256HkdfSha256::KEM_ID,
+        }
+    }
+
+    /// Parses a key of the form `I2OSP(KEM_ID, 2) || I2OSP(pk_len, 2) || pk` from the start of
+    /// `buf`. The KEM ID says which KEM's `from_bytes` is used to parse `pk`. Since the key field
+    /// is followed by the rest of the ECH config, whatever comes after the key is returned.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok((key_config, rest))` on success. If `buf` is too short for the KEM ID and the
+    /// length field, returns
+    /// `Err(HpkeError::IncorrectInputLength { expected: 4, got: buf.len() })`. If `buf` is shorter
+    /// than the length field says, returns
+    /// `Err(HpkeError::IncorrectInputLength { expected: pk_len, got: remaining })`. If the KEM ID
+    /// isn't that of a KEM enabled in this build, returns `Err(HpkeError::ValidationError)`. If the
+    /// length field isn't the KEM's `Npk`, returns
+    /// `Err(HpkeError::IncorrectInputLength { expected: npk, got: pk_len })`. If the key fails to
+    /// deserialize, returns the error from that KEM's `from_bytes`.
+    pub fn parse(buf: &[u8]) -> Result<(EchKeyConfig, &[u8]), HpkeError> {
+        if buf.len() < 4 {
+            return Err(HpkeError::IncorrectInputLength {
+                expected: 4,
+                got: buf.len(),
+            });
+        }
+        let kem_id = u16::from_be_bytes([buf[0], buf[1]]);
+        let pk_len = usize::from(u16::from_be_bytes([buf[2], buf[3]]));
+        let rest = &buf[4..];
+        if rest.len() < pk_len {
+            return Err(HpkeError::IncorrectInputLength {
+                expected: pk_len,
+                got: rest.len(),
+            });
+        }
+        let (pk_bytes, rest) = rest.split_at(pk_len);
+
+        let pk =
+            match kem_id {
+                #[cfg(feature = "x25519-dalek")]
+                X25519HkdfSha256::KEM_ID => ech_public_key::<X25519HkdfSha256>(pk_bytes)
+                    .map(TaggedPublicKey::X25519HkdfSha256),
+                #[cfg(feature = "p256")]
+                DhP256HkdfSha256::KEM_ID => ech_public_key::<DhP256HkdfSha256>(pk_bytes)
+                    .map(TaggedPublicKey::DhP256HkdfSha256),
+                _ => Err(HpkeError::ValidationError),
+            }?;
+        Ok((EchKeyConfig(pk), rest))
+    }
+
+    /// Serializes the key as `I2OSP(KEM_ID, 2) || I2OSP(Npk, 2) || pk`, which is what `parse`
+    /// reads
+    #[cfg(feature = "alloc")]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match &self.0 {
+            #[cfg(feature = "x25519-dalek")]
+            TaggedPublicKey::X25519HkdfSha256(pk) => ech_key_config_bytes::<X25519HkdfSha256>(pk),
+            #[cfg(feature = "p256")]
+            TaggedPublicKey::DhP256HkdfSha256(pk) => ech_key_config_bytes::<DhP256HkdfSha256>(pk),
+        }
+    }
+}
+
+/// Validates each of `keys` as a public key of `Kem`, with the same checks as `from_bytes`, i.e.,
+/// the encoding and, for NIST curves, curve membership. With the `rayon` feature, the keys are
+/// validated in parallel on rayon's global thread pool, which speeds up loading a large directory
+/// of keys.
+///
+/// Return Value
+/// ============
+/// Returns one result per key, in the same order as `keys`. The `i`-th result is `Ok(())` if
+/// `keys[i]` is a valid public key, and otherwise the error from `from_bytes`.
+#[cfg(feature = "alloc")]
+pub fn validate_public_keys_batch<Kem: KemTrait>(keys: &[&[u8]]) -> Vec<Result<(), HpkeError>> {
+    let validate = |key: &&[u8]| KemPubkey::<Kem>::from_bytes(key).map(|_| ());
+
+    #[cfg(feature = "rayon")]
+    {
+        use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+        // par_iter().map().collect() keeps the order of the input
+        keys.par_iter().map(validate).collect()
+    }
+    #[cfg(not(feature = "rayon"))]
+    {
+        keys.iter().map(validate).collect()
+    }
+}
+
 /// Holds the content of an encapsulated secret. This is what the receiver uses to derive the
 /// shared secret.
+///
+/// An `EncappedKey` only comes from a sender-side setup, or from deserializing the bytes a sender
+/// transmitted. It is a separate type from `PublicKey`, even though it wraps one, so a receiver
+/// can't be handed some other public key by mistake:
+///
+#[cfg_attr(feature = "x25519", doc = "```compile_fail")]
+#[cfg_attr(not(feature = "x25519"), doc = "```ignore")]
+/// use hpke::{
+///     aead::ChaCha20Poly1305, kdf::HkdfSha256, kem::X25519HkdfSha256, setup_receiver, Kem,
+///     OpModeR,
+/// };
+/// use rand::{rngs::StdRng, SeedableRng};
+///
+/// let mut csprng = StdRng::from_entropy();
+/// let (sk_recip, _) = X25519HkdfSha256::gen_keypair(&mut csprng);
+/// let (_, pk_sender_id) = X25519HkdfSha256::gen_keypair(&mut csprng);
+///
+/// // The sender's identity key is not an encapsulated key
+/// setup_receiver::<ChaCha20Poly1305, HkdfSha256, X25519HkdfSha256>(
+///     &OpModeR::Auth(pk_sender_id.clone()),
+///     &sk_recip,
+///     &pk_sender_id,
+///     b"info",
+/// );
+/// ```
 // This just wraps a pubkey, because that's all an encapsulated key is in a DH-KEM
 pub struct EncappedKey<Kex: KeyExchange>(Kex::PublicKey);
 
@@ -110,10 +521,117 @@ impl<Kex: KeyExchange> Deserializable for EncappedKey<Kex> {
     }
 }
 
-/// A convenience type representing the fixed-size byte array of the same length as a serialized
-/// `KexResult`
-pub(crate) type SharedSecret<Kem> =
-    GenericArray<u8, <<<Kem as KemTrait>::Kdf as KdfTrait>::HashImpl as FixedOutput>::OutputSize>;
+// Returns the same bytes as to_bytes()
+impl<Kex: KeyExchange> AsRef<[u8]> for EncappedKey<Kex> {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+/// Packs the sender's identity public key and an encapsulated key into a single blob
+/// `pk_sender || enc`. In `Auth` and `AuthPsk` modes, these two travel together. Both have a fixed
+/// length for a given KEM, so there is no length prefix. `unpack_auth_keys` undoes this.
+#[cfg(feature = "alloc")]
+pub fn pack_auth_keys<Kem: KemTrait>(
+    pk_sender_id: &KemPubkey<Kem>,
+    encapped_key: &EncappedKey<Kem::Kex>,
+) -> Vec<u8> {
+    let mut blob = Vec::with_capacity(KemPubkey::<Kem>::size() + EncappedKey::<Kem::Kex>::size());
+    blob.extend_from_slice(&pk_sender_id.to_bytes());
+    blob.extend_from_slice(&encapped_key.to_bytes());
+    blob
+}
+
+/// Unpacks a blob of the form `pk_sender || enc`, as output by `pack_auth_keys`, into the
+/// sender's identity public key and the encapsulated key. Both are deserialized and validated.
+///
+/// Return Value
+/// ============
+/// Returns `Ok((pk_sender_id, encapped_key))` on success. If `blob` isn't exactly `Npk + Nenc`
+/// bytes long, returns
+/// `Err(HpkeError::IncorrectInputLength { expected: Npk + Nenc, got: blob.len() })`. If either
+/// component fails to deserialize, returns the error from its `from_bytes`.
+pub fn unpack_auth_keys<Kem: KemTrait>(
+    blob: &[u8],
+) -> Result<(KemPubkey<Kem>, EncappedKey<Kem::Kex>), HpkeError> {
+    let pk_len = KemPubkey::<Kem>::size();
+    let expected_len = pk_len + EncappedKey::<Kem::Kex>::size();
+    if blob.len() != expected_len {
+        return Err(HpkeError::IncorrectInputLength {
+            expected: expected_len,
+            got: blob.len(),
+        });
+    }
+
+    let (pk_bytes, enc_bytes) = blob.split_at(pk_len);
+    let pk_sender_id = KemPubkey::<Kem>::from_bytes(pk_bytes)?;
+    let encapped_key = EncappedKey::<Kem::Kex>::from_bytes(enc_bytes)?;
+    Ok((pk_sender_id, encapped_key))
+}
+
+/// Returns `Err(err)` if `bytes` is all zeros, in constant time. The backends already reject DH
+/// results that come from low-order points. This is a last line of defense against a buggy
+/// backend, and against a DH or KDF failure that zeroes its output.
+fn reject_all_zero(bytes: &[u8], err: HpkeError) -> Result<(), HpkeError> {
+    let acc = bytes.iter().fold(0u8, |acc, b| acc | b);
+    if bool::from(acc.ct_eq(&0)) {
+        Err(err)
+    } else {
+        Ok(())
+    }
+}
+
+// The length of a shared secret, as a type-level number
+type SharedSecretSize<Kem> =
+    <<<Kem as KemTrait>::Kdf as KdfTrait>::HashImpl as FixedOutput>::OutputSize;
+
+/// The shared secret a KEM encapsulates. This is `Kem::N_SECRET` bytes long, and is zeroed on
+/// drop.
+pub struct SharedSecret<Kem: KemTrait>(pub(crate) GenericArray<u8, SharedSecretSize<Kem>>);
+
+impl<Kem: KemTrait> SharedSecret<Kem> {
+    /// The length of a shared secret, in bytes. This is the same as `Kem::N_SECRET`.
+    pub const LEN: usize = Kem::N_SECRET;
+
+    /// Returns the length of the shared secret, in bytes. This is always `Self::LEN`.
+    // A shared secret is never empty, so there's no is_empty
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        Self::LEN
+    }
+}
+
+// We use this to get an empty buffer we can read secret bytes into
+impl<Kem: KemTrait> Default for SharedSecret<Kem> {
+    fn default() -> SharedSecret<Kem> {
+        SharedSecret(GenericArray::default())
+    }
+}
+
+impl<Kem: KemTrait> Clone for SharedSecret<Kem> {
+    fn clone(&self) -> SharedSecret<Kem> {
+        SharedSecret(self.0.clone())
+    }
+}
+
+impl<Kem: KemTrait> AsRef<[u8]> for SharedSecret<Kem> {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl<Kem: KemTrait> Zeroize for SharedSecret<Kem> {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+// Zero out secrets on drop
+impl<Kem: KemTrait> Drop for SharedSecret<Kem> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
 
 // draft11 §4.1
 // def Encap(pkR):
@@ -149,14 +667,57 @@ pub(crate) type SharedSecret<Kem> =
 /// returns `Err(HpkeError::EncapError)`.
 pub(crate) fn encap_with_eph<Kem: KemTrait>(
     pk_recip: &KemPubkey<Kem>,
-    sender_id_keypair: Option<&(KemPrivkey<Kem>, KemPubkey<Kem>)>,
+    sender_id_keypair: Option<(&KemPrivkey<Kem>, &KemPubkey<Kem>)>,
+    sk_eph: KemPrivkey<Kem>,
+) -> Result<(SharedSecret<Kem>, EncappedKey<Kem::Kex>), HpkeError> {
+    encap_with_eph_and_extra::<Kem>(pk_recip, sender_id_keypair, sk_eph, &[])
+}
+
+/// Does `encap_with_eph`, with `extra_kem_context` appended to `kem_context`. In HPKE,
+/// `extra_kem_context` is always empty.
+pub(crate) fn encap_with_eph_and_extra<Kem: KemTrait>(
+    pk_recip: &KemPubkey<Kem>,
+    sender_id_keypair: Option<(&KemPrivkey<Kem>, &KemPubkey<Kem>)>,
     sk_eph: KemPrivkey<Kem>,
+    extra_kem_context: &[u8],
+) -> Result<(SharedSecret<Kem>, EncappedKey<Kem::Kex>), HpkeError> {
+    match sender_id_keypair {
+        Some((sk_sender_id, pk_sender_id)) => {
+            // We want to do an authed encap. Do KEX between the sender identity secret key and
+            // the recipient's pubkey
+            let kex_res_identity =
+                Kem::Kex::kex(sk_sender_id, pk_recip).map_err(|_| HpkeError::EncapError)?;
+            encap_with_eph_and_static::<Kem>(
+                pk_recip,
+                Some((pk_sender_id, &kex_res_identity.to_bytes())),
+                sk_eph,
+                extra_kem_context,
+            )
+        }
+        None => encap_with_eph_and_static::<Kem>(pk_recip, None, sk_eph, extra_kem_context),
+    }
+}
+
+/// Does `encap_with_eph`, except, in auth mode, the static DH result `DH(skS, pkR)` is given
+/// instead of computed. `sender_id` is the sender's identity pubkey along with the serialized
+/// static DH result. `extra_kem_context` is appended to `kem_context`, and is empty in HPKE.
+///
+/// Return Value
+/// ============
+/// Returns a shared secret and encapped key on success. If an error happened during key exchange,
+/// returns `Err(HpkeError::EncapError)`.
+pub(crate) fn encap_with_eph_and_static<Kem: KemTrait>(
+    pk_recip: &KemPubkey<Kem>,
+    sender_id: Option<(&KemPubkey<Kem>, &[u8])>,
+    sk_eph: KemPrivkey<Kem>,
+    extra_kem_context: &[u8],
 ) -> Result<(SharedSecret<Kem>, EncappedKey<Kem::Kex>), HpkeError> {
     // Put together the binding context used for all KDF operations
     let suite_id = kem_suite_id::<Kem>();
 
     // Compute the shared secret from the ephemeral inputs
     let kex_res_eph = Kem::Kex::kex(&sk_eph, pk_recip).map_err(|_| HpkeError::EncapError)?;
+    reject_all_zero(&kex_res_eph.to_bytes(), HpkeError::EncapError)?;
 
     // The encapped key is the ephemeral pubkey
     let encapped_key = {
@@ -166,7 +727,9 @@ pub(crate) fn encap_with_eph<Kem: KemTrait>(
 
     // The shared secret is either gonna be kex_res_eph, or that along with another shared secret
     // that's tied to the sender's identity.
-    let shared_secret = if let Some((sk_sender_id, pk_sender_id)) = sender_id_keypair {
+    let shared_secret = if let Some((pk_sender_id, kex_res_identity)) = sender_id {
+        reject_all_zero(kex_res_identity, HpkeError::EncapError)?;
+
         // kem_context = encapped_key || pk_recip || pk_sender_id
         // We concat without allocation by making a buffer of the maximum possible size, then
         // taking the appropriately sized slice.
@@ -178,18 +741,10 @@ pub(crate) fn encap_with_eph<Kem: KemTrait>(
         );
         let kem_context = &kem_context_buf[..kem_context_size];
 
-        // We want to do an authed encap. Do KEX between the sender identity secret key and the
-        // recipient's pubkey
-        let kex_res_identity =
-            Kem::Kex::kex(sk_sender_id, pk_recip).map_err(|_| HpkeError::EncapError)?;
-
         // concatted_secrets = kex_res_eph || kex_res_identity
         // Same no-alloc concat trick as above
-        let (concatted_secrets_buf, concatted_secret_size) = concat_with_known_maxlen!(
-            MAX_PUBKEY_SIZE,
-            &kex_res_eph.to_bytes(),
-            &kex_res_identity.to_bytes()
-        );
+        let (concatted_secrets_buf, concatted_secret_size) =
+            concat_with_known_maxlen!(MAX_PUBKEY_SIZE, &kex_res_eph.to_bytes(), kex_res_identity);
         let concatted_secrets = &concatted_secrets_buf[..concatted_secret_size];
 
         // The "authed shared secret" is derived from the KEX of the ephemeral input with the
@@ -197,8 +752,14 @@ pub(crate) fn encap_with_eph<Kem: KemTrait>(
         // HKDF-Expand call only errors if the output values are 255x the digest size of the hash
         // function. Since these values are fixed at compile time, we don't worry about it.
         let mut buf = <SharedSecret<Kem> as Default>::default();
-        extract_and_expand::<Kem>(concatted_secrets, &suite_id, kem_context, &mut buf)
-            .expect("shared secret is way too big");
+        extract_and_expand::<Kem>(
+            concatted_secrets,
+            &suite_id,
+            kem_context,
+            extra_kem_context,
+            &mut buf.0,
+        )
+        .expect("shared secret is way too big");
         buf
     } else {
         // kem_context = encapped_key || pk_recip
@@ -216,14 +777,42 @@ pub(crate) fn encap_with_eph<Kem: KemTrait>(
         // digest size of the hash function. Since these values are fixed at compile time, we don't
         // worry about it.
         let mut buf = <SharedSecret<Kem> as Default>::default();
-        extract_and_expand::<Kem>(&kex_res_eph.to_bytes(), &suite_id, kem_context, &mut buf)
-            .expect("shared secret is way too big");
+        extract_and_expand::<Kem>(
+            &kex_res_eph.to_bytes(),
+            &suite_id,
+            kem_context,
+            extra_kem_context,
+            &mut buf.0,
+        )
+        .expect("shared secret is way too big");
         buf
     };
+    reject_all_zero(&shared_secret.0, HpkeError::EncapError)?;
 
     Ok((shared_secret, encapped_key))
 }
 
+/// Generates an ephemeral private key for an encapsulation. This is the same as
+/// `Kem::gen_keypair`, except that an RNG failure is returned as `Err(HpkeError::EncapError)`
+/// instead of a panic.
+pub(crate) fn gen_eph_privkey<Kem, R>(csprng: &mut R) -> Result<KemPrivkey<Kem>, HpkeError>
+where
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    // Make some keying material that's the size of a private key, and fill it with randomness
+    let mut ikm: GenericArray<u8, <KemPrivkey<Kem> as Serializable>::OutputSize> =
+        GenericArray::default();
+    csprng
+        .try_fill_bytes(&mut ikm)
+        .map_err(|_| HpkeError::EncapError)?;
+
+    let derived = Kem::derive_keypair(&ikm);
+    ikm.zeroize();
+    let (sk_eph, _) = derived.map_err(|_| HpkeError::EncapError)?;
+    Ok(sk_eph)
+}
+
 /// Derives a shared secret and an ephemeral pubkey that the owner of the reciepint's pubkey can
 /// use to derive the same shared secret. If `sk_sender_id` is given, the sender's identity will be
 /// tied to the shared secret.
@@ -232,10 +821,10 @@ pub(crate) fn encap_with_eph<Kem: KemTrait>(
 /// Return Value
 /// ============
 /// Returns a shared secret and encapped key on success. If an error happened during key exchange,
-/// returns `Err(HpkeError::EncapError)`.
-pub(crate) fn encap<Kem: KemTrait, R>(
+/// or the RNG failed, returns `Err(HpkeError::EncapError)`.
+pub(crate) fn encap<Kem, R>(
     pk_recip: &KemPubkey<Kem>,
-    sender_id_keypair: Option<&(KemPrivkey<Kem>, KemPubkey<Kem>)>,
+    sender_id_keypair: Option<(&KemPrivkey<Kem>, &KemPubkey<Kem>)>,
     csprng: &mut R,
 ) -> Result<(SharedSecret<Kem>, EncappedKey<Kem::Kex>), HpkeError>
 where
@@ -243,11 +832,56 @@ where
     R: CryptoRng + RngCore,
 {
     // Generate a new ephemeral keypair
-    let (sk_eph, _) = Kem::gen_keypair(csprng);
+    let sk_eph = gen_eph_privkey::<Kem, R>(csprng)?;
     // Now pass to encap_with_eph
     encap_with_eph::<Kem>(pk_recip, sender_id_keypair, sk_eph)
 }
 
+/// Does `Encap(pk_recip)`, and also returns the ephemeral private key it used, so that it can be
+/// escrowed.
+///
+/// **WARNING:** This defeats forward secrecy. Anyone who gets the returned ephemeral private key
+/// can recompute the shared secret, and thus decrypt every message of the session, for as long as
+/// the key is kept around. It does not matter whether the sender or the recipient have since
+/// deleted their keys. Only use this if you are legally or contractually required to escrow
+/// session keys, and store the ephemeral keys at least as carefully as long-term secret keys.
+///
+/// Return Value
+/// ============
+/// Returns the shared secret, the encapped key, and the ephemeral private key on success. If an
+/// error happened during key exchange, returns `Err(HpkeError::EncapError)`.
+#[cfg(feature = "escrow")]
+pub fn encap_with_escrow<Kem, R>(
+    pk_recip: &KemPubkey<Kem>,
+    csprng: &mut R,
+) -> Result<(SharedSecret<Kem>, EncappedKey<Kem::Kex>, KemPrivkey<Kem>), HpkeError>
+where
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    // Same as encap, except we keep a copy of the ephemeral private key
+    let sk_eph = gen_eph_privkey::<Kem, R>(csprng)?;
+    let (shared_secret, encapped_key) = encap_with_eph::<Kem>(pk_recip, None, sk_eph.clone())?;
+    Ok((shared_secret, encapped_key, sk_eph))
+}
+
+/// Recomputes the shared secret of an encapsulation made by `encap_with_escrow`, given the
+/// escrowed ephemeral private key and the recipient's public key. This is what the escrow agent
+/// runs.
+///
+/// Return Value
+/// ============
+/// Returns the shared secret and the encapped key on success. These are identical to what
+/// `encap_with_escrow` returned. If an error happened during key exchange, returns
+/// `Err(HpkeError::EncapError)`.
+#[cfg(feature = "escrow")]
+pub fn recompute_escrowed_shared_secret<Kem: KemTrait>(
+    pk_recip: &KemPubkey<Kem>,
+    sk_eph: &KemPrivkey<Kem>,
+) -> Result<(SharedSecret<Kem>, EncappedKey<Kem::Kex>), HpkeError> {
+    encap_with_eph::<Kem>(pk_recip, None, sk_eph.clone())
+}
+
 // draft11 §4.1
 // def Decap(enc, skR):
 //   pkE = DeserializePublicKey(enc)
@@ -281,6 +915,17 @@ pub(crate) fn decap<Kem: KemTrait>(
     sk_recip: &KemPrivkey<Kem>,
     pk_sender_id: Option<&KemPubkey<Kem>>,
     encapped_key: &EncappedKey<Kem::Kex>,
+) -> Result<SharedSecret<Kem>, HpkeError> {
+    decap_with_extra::<Kem>(sk_recip, pk_sender_id, encapped_key, &[])
+}
+
+/// Does `decap`, with `extra_kem_context` appended to `kem_context`. In HPKE, `extra_kem_context`
+/// is always empty.
+pub(crate) fn decap_with_extra<Kem: KemTrait>(
+    sk_recip: &KemPrivkey<Kem>,
+    pk_sender_id: Option<&KemPubkey<Kem>>,
+    encapped_key: &EncappedKey<Kem::Kex>,
+    extra_kem_context: &[u8],
 ) -> Result<SharedSecret<Kem>, HpkeError> {
     // Put together the binding context used for all KDF operations
     let suite_id = kem_suite_id::<Kem>();
@@ -288,6 +933,7 @@ pub(crate) fn decap<Kem: KemTrait>(
     // Compute the shared secret from the ephemeral inputs
     let kex_res_eph =
         Kem::Kex::kex(sk_recip, &encapped_key.0).map_err(|_| HpkeError::DecapError)?;
+    reject_all_zero(&kex_res_eph.to_bytes(), HpkeError::DecapError)?;
 
     // Compute the sender's pubkey from their privkey
     let pk_recip = Kem::Kex::sk_to_pk(sk_recip);
@@ -310,6 +956,7 @@ pub(crate) fn decap<Kem: KemTrait>(
         // recipient's pubkey
         let kex_res_identity =
             Kem::Kex::kex(sk_recip, pk_sender_id).map_err(|_| HpkeError::DecapError)?;
+        reject_all_zero(&kex_res_identity.to_bytes(), HpkeError::DecapError)?;
 
         // concatted_secrets = kex_res_eph || kex_res_identity
         // Same no-alloc concat trick as above
@@ -329,9 +976,11 @@ pub(crate) fn decap<Kem: KemTrait>(
             concatted_secrets,
             &suite_id,
             kem_context,
-            &mut shared_secret,
+            extra_kem_context,
+            &mut shared_secret.0,
         )
         .expect("shared secret is way too big");
+        reject_all_zero(&shared_secret.0, HpkeError::DecapError)?;
         Ok(shared_secret)
     } else {
         // kem_context = encapped_key || pk_recip || pk_sender_id
@@ -353,77 +1002,398 @@ pub(crate) fn decap<Kem: KemTrait>(
             &kex_res_eph.to_bytes(),
             &suite_id,
             kem_context,
-            &mut shared_secret,
+            extra_kem_context,
+            &mut shared_secret.0,
         )
         .expect("shared secret is way too big");
+        reject_all_zero(&shared_secret.0, HpkeError::DecapError)?;
         Ok(shared_secret)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::kem::{decap, encap, Deserializable, EncappedKey, Kem as KemTrait, Serializable};
-
-    use rand::{rngs::StdRng, SeedableRng};
-
-    macro_rules! test_encap_correctness {
-        ($test_name:ident, $kem_ty:ty) => {
-            /// Tests that encap and decap produce the same shared secret when composed
-            #[test]
-            fn $test_name() {
-                type Kem = $kem_ty;
-
-                let mut csprng = StdRng::from_entropy();
-                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+    use crate::{
+        kem::{
+            decap, encap, Deserializable, EncappedKey, Kem as KemTrait, KemSizes, Serializable,
+            SharedSecret,
+        },
+        kex::KeyExchange,
+        HpkeError,
+    };
 
-                // Encapsulate a random shared secret
-                let (auth_shared_secret, encapped_key) =
-                    encap::<Kem, _>(&pk_recip, None, &mut csprng).unwrap();
+    use rand::{rngs::StdRng, CryptoRng, RngCore, SeedableRng};
+    use sha2::{Digest, Sha256};
 
-                // Decap it
-                let decapped_auth_shared_secret =
-                    decap::<Kem>(&sk_recip, None, &encapped_key).unwrap();
+    // We need this in our encap-decap tests
+    impl<Kem: KemTrait> PartialEq for SharedSecret<Kem> {
+        fn eq(&self, other: &SharedSecret<Kem>) -> bool {
+            self.0 == other.0
+        }
+    }
 
-                // Ensure that the encapsulated secret is what decap() derives
-                assert_eq!(auth_shared_secret, decapped_auth_shared_secret);
+    impl<Kem: KemTrait> core::fmt::Debug for SharedSecret<Kem> {
+        fn fmt(&self, f: &mut core::fmt::Formatter) -> Result<(), core::fmt::Error> {
+            write!(f, "SharedSecret({:?})", self.0)
+        }
+    }
 
-                //
-                // Now do it with the auth, i.e., using the sender's identity keys
-                //
+    /// An "RNG" that only outputs zeros
+    struct ZeroRng;
 
-                // Make a sender identity keypair
-                let (sk_sender_id, pk_sender_id) = Kem::gen_keypair(&mut csprng);
+    impl RngCore for ZeroRng {
+        fn next_u32(&mut self) -> u32 {
+            0
+        }
+        fn next_u64(&mut self) -> u64 {
+            0
+        }
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            dest.iter_mut().for_each(|b| *b = 0);
+        }
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
 
-                // Encapsulate a random shared secret
-                let (auth_shared_secret, encapped_key) = encap::<Kem, _>(
-                    &pk_recip,
-                    Some(&(sk_sender_id, pk_sender_id.clone())),
-                    &mut csprng,
-                )
-                .unwrap();
+    impl CryptoRng for ZeroRng {}
 
-                // Decap it
-                let decapped_auth_shared_secret =
-                    decap::<Kem>(&sk_recip, Some(&pk_sender_id), &encapped_key).unwrap();
+    /// An RNG that always fails. Like every `RngCore`, it panics when it fails in an infallible
+    /// method.
+    struct FailingRng;
 
-                // Ensure that the encapsulated secret is what decap() derives
-                assert_eq!(auth_shared_secret, decapped_auth_shared_secret);
-            }
-        };
+    impl RngCore for FailingRng {
+        fn next_u32(&mut self) -> u32 {
+            panic!("RNG failure")
+        }
+        fn next_u64(&mut self) -> u64 {
+            panic!("RNG failure")
+        }
+        fn fill_bytes(&mut self, _: &mut [u8]) {
+            panic!("RNG failure")
+        }
+        fn try_fill_bytes(&mut self, _: &mut [u8]) -> Result<(), rand::Error> {
+            let code = core::num::NonZeroU32::new(rand::Error::CUSTOM_START).unwrap();
+            Err(rand::Error::from(code))
+        }
     }
 
-    /// Tests that an deserialize-serialize round trip on an encapped key ends up at the same value
-    macro_rules! test_encapped_serialize {
+    impl CryptoRng for FailingRng {}
+
+    /// Tests that a degenerate RNG still gives a working keypair and encapsulation, and that a
+    /// failing RNG makes encapsulation return a clean error instead of panicking
+    macro_rules! test_degenerate_rng {
         ($test_name:ident, $kem_ty:ty) => {
             #[test]
             fn $test_name() {
+                use crate::{
+                    aead::ChaCha20Poly1305, kdf::HkdfSha256, op_mode::OpModeS, setup::setup_sender,
+                };
+
                 type Kem = $kem_ty;
+                type Kex = <Kem as KemTrait>::Kex;
 
-                // Encapsulate a random shared secret
-                let encapped_key = {
-                    let mut csprng = StdRng::from_entropy();
-                    let (_, pk_recip) = Kem::gen_keypair(&mut csprng);
-                    encap::<Kem, _>(&pk_recip, None, &mut csprng).unwrap().1
+                // The RNG output only goes into DeriveKeyPair as IKM, so all zeros is a perfectly
+                // fine (if predictable) key
+                let (sk, pk) = Kem::gen_keypair(&mut ZeroRng);
+                let sk_size = <<Kex as KeyExchange>::PrivateKey as Serializable>::size();
+                let (expected_sk, _) = Kem::derive_keypair(&vec![0u8; sk_size]).unwrap();
+                assert_eq!(sk.to_bytes(), expected_sk.to_bytes());
+                assert_eq!(pk.to_bytes(), Kex::sk_to_pk(&sk).to_bytes());
+
+                let (shared_secret, encapped_key) =
+                    encap::<Kem, _>(&pk, None, &mut ZeroRng).unwrap();
+                assert_eq!(
+                    decap::<Kem>(&sk, None, &encapped_key).unwrap(),
+                    shared_secret
+                );
+
+                let mut csprng = StdRng::from_entropy();
+                let (_, pk_recip) = Kem::gen_keypair(&mut csprng);
+                assert_eq!(
+                    encap::<Kem, _>(&pk_recip, None, &mut FailingRng).err(),
+                    Some(HpkeError::EncapError)
+                );
+                assert_eq!(
+                    setup_sender::<ChaCha20Poly1305, HkdfSha256, Kem, _>(
+                        &OpModeS::Base,
+                        &pk_recip,
+                        b"info",
+                        &mut FailingRng,
+                    )
+                    .err(),
+                    Some(HpkeError::EncapError)
+                );
+            }
+        };
+    }
+
+    /// Tests that a public key round-trips through the tagged format, and that a bad tag, a bad
+    /// key, and an empty buffer are rejected
+    #[cfg(feature = "alloc")]
+    macro_rules! test_tagged_bytes {
+        ($test_name:ident, $kem_ty:ty, $variant:ident) => {
+            #[test]
+            fn $test_name() {
+                use crate::kem::{public_key_from_tagged_bytes, TaggedPublicKey};
+
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let (_, pk) = Kem::gen_keypair(&mut csprng);
+
+                let tagged = pk.to_tagged_bytes();
+                assert_eq!(tagged[0] as u16, Kem::KEM_ID);
+                assert_eq!(&tagged[1..], pk.to_bytes().as_slice());
+
+                match public_key_from_tagged_bytes(&tagged).unwrap() {
+                    TaggedPublicKey::$variant(parsed) => {
+                        assert_eq!(parsed.to_bytes(), pk.to_bytes())
+                    }
+                    #[allow(unreachable_patterns)]
+                    _ => panic!("parsed as the wrong KEM"),
+                }
+
+                // An unknown tag
+                let mut bad_tag = tagged.clone();
+                bad_tag[0] = 0xff;
+                assert_eq!(
+                    public_key_from_tagged_bytes(&bad_tag).err(),
+                    Some(HpkeError::ValidationError)
+                );
+                // A truncated key
+                let key_len = tagged.len() - 1;
+                assert_eq!(
+                    public_key_from_tagged_bytes(&tagged[..key_len]).err(),
+                    Some(HpkeError::IncorrectInputLength {
+                        expected: key_len,
+                        got: key_len - 1
+                    })
+                );
+                // Nothing at all
+                assert_eq!(
+                    public_key_from_tagged_bytes(&[]).err(),
+                    Some(HpkeError::IncorrectInputLength {
+                        expected: 1,
+                        got: 0
+                    })
+                );
+            }
+        };
+    }
+
+    /// Tests that a public key round-trips through the ECH key config format, that whatever
+    /// follows the key is returned, and that a length field which doesn't match the KEM is
+    /// rejected
+    #[cfg(feature = "alloc")]
+    macro_rules! test_ech_key_config {
+        ($test_name:ident, $kem_ty:ty, $variant:ident) => {
+            #[test]
+            fn $test_name() {
+                use crate::kem::{EchKeyConfig, TaggedPublicKey};
+
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let (_, pk) = Kem::gen_keypair(&mut csprng);
+                let npk = pk.to_bytes().len();
+
+                let encoded = EchKeyConfig(TaggedPublicKey::$variant(pk.clone())).to_bytes();
+                assert_eq!(&encoded[..2], &Kem::KEM_ID.to_be_bytes());
+                assert_eq!(&encoded[2..4], &(npk as u16).to_be_bytes());
+                assert_eq!(&encoded[4..], pk.to_bytes().as_slice());
+
+                let mut with_trailer = encoded.clone();
+                with_trailer.extend_from_slice(b"cipher suites");
+                let (parsed, rest) = EchKeyConfig::parse(&with_trailer).unwrap();
+                assert_eq!(parsed.kem_id(), Kem::KEM_ID);
+                assert_eq!(parsed.to_bytes(), encoded);
+                assert_eq!(rest, b"cipher suites");
+                match parsed.0 {
+                    TaggedPublicKey::$variant(parsed) => {
+                        assert_eq!(parsed.to_bytes(), pk.to_bytes())
+                    }
+                    #[allow(unreachable_patterns)]
+                    _ => panic!("parsed as the wrong KEM"),
+                }
+
+                // A length field one shorter than Npk, followed by a shortened key
+                let mut bad_len = encoded.clone();
+                bad_len[2..4].copy_from_slice(&(npk as u16 - 1).to_be_bytes());
+                bad_len.pop();
+                assert_eq!(
+                    EchKeyConfig::parse(&bad_len).err(),
+                    Some(HpkeError::IncorrectInputLength {
+                        expected: npk,
+                        got: npk - 1
+                    })
+                );
+                // A length field longer than the buffer
+                let mut bad_len = encoded.clone();
+                bad_len[2..4].copy_from_slice(&(npk as u16 + 1).to_be_bytes());
+                assert_eq!(
+                    EchKeyConfig::parse(&bad_len).err(),
+                    Some(HpkeError::IncorrectInputLength {
+                        expected: npk + 1,
+                        got: npk
+                    })
+                );
+                // An unknown KEM
+                let mut bad_kem = encoded.clone();
+                bad_kem[..2].copy_from_slice(&[0xff, 0xff]);
+                assert_eq!(
+                    EchKeyConfig::parse(&bad_kem).err(),
+                    Some(HpkeError::ValidationError)
+                );
+                // Not even a header
+                assert_eq!(
+                    EchKeyConfig::parse(&encoded[..3]).err(),
+                    Some(HpkeError::IncorrectInputLength {
+                        expected: 4,
+                        got: 3
+                    })
+                );
+            }
+        };
+    }
+
+    /// Tests that batch validation gives one result per key, in order, for a mix of valid keys,
+    /// keys of the wrong length, and `invalid_key`, which fails with `invalid_key_err`
+    #[cfg(feature = "alloc")]
+    macro_rules! test_validate_public_keys_batch {
+        ($test_name:ident, $kem_ty:ty, $invalid_key:expr, $invalid_key_err:expr) => {
+            #[test]
+            fn $test_name() {
+                use crate::kem::validate_public_keys_batch;
+                use alloc::vec::Vec;
+
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let valid_keys: Vec<_> = (0..3)
+                    .map(|_| Kem::gen_keypair(&mut csprng).1.to_bytes())
+                    .collect();
+                let npk = valid_keys[0].len();
+                let invalid_key: &[u8] = &$invalid_key;
+
+                let keys: Vec<&[u8]> = vec![
+                    &valid_keys[0],
+                    &valid_keys[1][..npk - 1],
+                    &[],
+                    invalid_key,
+                    &valid_keys[2],
+                ];
+                let results = validate_public_keys_batch::<Kem>(&keys);
+                assert_eq!(
+                    results,
+                    vec![
+                        Ok(()),
+                        Err(HpkeError::IncorrectInputLength {
+                            expected: npk,
+                            got: npk - 1
+                        }),
+                        Err(HpkeError::IncorrectInputLength {
+                            expected: npk,
+                            got: 0
+                        }),
+                        Err($invalid_key_err),
+                        Ok(()),
+                    ]
+                );
+
+                assert!(validate_public_keys_batch::<Kem>(&[]).is_empty());
+            }
+        };
+    }
+
+    /// Tests that a shared secret is `Kem::N_SECRET` bytes long, and that its bytes are zeroed
+    /// when it's dropped
+    macro_rules! test_shared_secret_len_and_zeroize {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                use core::mem::ManuallyDrop;
+
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let (_, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let (shared_secret, _) = encap::<Kem, _>(&pk_recip, None, &mut csprng).unwrap();
+
+                assert_eq!(SharedSecret::<Kem>::LEN, Kem::N_SECRET);
+                assert_eq!(shared_secret.len(), Kem::N_SECRET);
+                assert_eq!(shared_secret.as_ref().len(), Kem::N_SECRET);
+                assert!(shared_secret.as_ref().iter().any(|&b| b != 0));
+
+                // Run the destructor without giving up the memory, then look at what it left there
+                let mut shared_secret = ManuallyDrop::new(shared_secret);
+                let ptr = shared_secret.0.as_ptr();
+                unsafe {
+                    ManuallyDrop::drop(&mut shared_secret);
+                    for i in 0..Kem::N_SECRET {
+                        assert_eq!(core::ptr::read_volatile(ptr.add(i)), 0);
+                    }
+                }
+            }
+        };
+    }
+
+    macro_rules! test_encap_correctness {
+        ($test_name:ident, $kem_ty:ty) => {
+            /// Tests that encap and decap produce the same shared secret when composed
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+
+                // Encapsulate a random shared secret
+                let (auth_shared_secret, encapped_key) =
+                    encap::<Kem, _>(&pk_recip, None, &mut csprng).unwrap();
+
+                // Decap it
+                let decapped_auth_shared_secret =
+                    decap::<Kem>(&sk_recip, None, &encapped_key).unwrap();
+
+                // Ensure that the encapsulated secret is what decap() derives
+                assert_eq!(auth_shared_secret, decapped_auth_shared_secret);
+
+                //
+                // Now do it with the auth, i.e., using the sender's identity keys
+                //
+
+                // Make a sender identity keypair
+                let (sk_sender_id, pk_sender_id) = Kem::gen_keypair(&mut csprng);
+
+                // Encapsulate a random shared secret
+                let (auth_shared_secret, encapped_key) =
+                    encap::<Kem, _>(&pk_recip, Some((&sk_sender_id, &pk_sender_id)), &mut csprng)
+                        .unwrap();
+
+                // Decap it
+                let decapped_auth_shared_secret =
+                    decap::<Kem>(&sk_recip, Some(&pk_sender_id), &encapped_key).unwrap();
+
+                // Ensure that the encapsulated secret is what decap() derives
+                assert_eq!(auth_shared_secret, decapped_auth_shared_secret);
+            }
+        };
+    }
+
+    /// Tests that an deserialize-serialize round trip on an encapped key ends up at the same value
+    macro_rules! test_encapped_serialize {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+
+                // Encapsulate a random shared secret
+                let encapped_key = {
+                    let mut csprng = StdRng::from_entropy();
+                    let (_, pk_recip) = Kem::gen_keypair(&mut csprng);
+                    encap::<Kem, _>(&pk_recip, None, &mut csprng).unwrap().1
                 };
                 // Serialize it
                 let encapped_key_bytes = encapped_key.to_bytes();
@@ -439,19 +1409,775 @@ mod tests {
         };
     }
 
+    /// Tests that deserializing an encapped key of the wrong length reports the expected and
+    /// given lengths
+    macro_rules! test_encapped_incorrect_len {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kex = <Kem as KemTrait>::Kex;
+
+                // Make a buffer that's one byte too short
+                let expected_len = EncappedKey::<Kex>::size();
+                let short_bytes = vec![0u8; expected_len - 1];
+
+                match EncappedKey::<Kex>::from_bytes(&short_bytes) {
+                    Err(e) => assert_eq!(
+                        e,
+                        HpkeError::IncorrectInputLength {
+                            expected: expected_len,
+                            got: expected_len - 1
+                        }
+                    ),
+                    Ok(_) => panic!("short encapped key was unexpectedly valid"),
+                }
+            }
+        };
+    }
+
+    /// Tests that a sender pubkey and encapped key round-trip through `pack_auth_keys` and
+    /// `unpack_auth_keys`, and that a blob of the wrong length is rejected
+    #[cfg(feature = "alloc")]
+    macro_rules! test_pack_auth_keys {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                use crate::kem::{pack_auth_keys, unpack_auth_keys};
+                type Kem = $kem_ty;
+                type Kex = <Kem as KemTrait>::Kex;
+
+                let mut csprng = StdRng::from_entropy();
+                let (_, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let (_, pk_sender_id) = Kem::gen_keypair(&mut csprng);
+                let (_, encapped_key) = encap::<Kem, _>(&pk_recip, None, &mut csprng).unwrap();
+
+                let blob = pack_auth_keys::<Kem>(&pk_sender_id, &encapped_key);
+                let expected_len =
+                    <Kex as KeyExchange>::PublicKey::size() + EncappedKey::<Kex>::size();
+                assert_eq!(blob.len(), expected_len);
+
+                let (new_pk_sender_id, new_encapped_key) = unpack_auth_keys::<Kem>(&blob).unwrap();
+                assert!(new_pk_sender_id == pk_sender_id);
+                assert!(new_encapped_key.0 == encapped_key.0);
+
+                // Truncated and extended blobs are both rejected
+                assert_eq!(
+                    unpack_auth_keys::<Kem>(&blob[..expected_len - 1]).err(),
+                    Some(HpkeError::IncorrectInputLength {
+                        expected: expected_len,
+                        got: expected_len - 1
+                    })
+                );
+                let mut long_blob = blob.clone();
+                long_blob.push(0);
+                assert_eq!(
+                    unpack_auth_keys::<Kem>(&long_blob).err(),
+                    Some(HpkeError::IncorrectInputLength {
+                        expected: expected_len,
+                        got: expected_len + 1
+                    })
+                );
+            }
+        };
+    }
+
+    /// Tests that `as_ref()` on public keys and encapped keys gives the same bytes as `to_bytes()`,
+    /// so the two can be used interchangeably when hashing
+    macro_rules! test_as_ref_matches_to_bytes {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let (_, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let (_, encapped_key) = encap::<Kem, _>(&pk_recip, None, &mut csprng).unwrap();
+
+                assert_eq!(
+                    Sha256::digest(pk_recip.as_ref()),
+                    Sha256::digest(&pk_recip.to_bytes())
+                );
+                assert_eq!(
+                    Sha256::digest(encapped_key.as_ref()),
+                    Sha256::digest(&encapped_key.to_bytes())
+                );
+            }
+        };
+    }
+
+    /// Tests that distinct keys have distinct key IDs, and that a key's ID survives a
+    /// serialize-deserialize round trip
+    macro_rules! test_key_id {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kex = <Kem as KemTrait>::Kex;
+
+                let mut csprng = StdRng::from_entropy();
+                let (_, pk1) = Kem::gen_keypair(&mut csprng);
+                let (_, pk2) = Kem::gen_keypair(&mut csprng);
+                assert_ne!(pk1.key_id(), pk2.key_id());
+
+                let pk1_bytes = pk1.to_bytes();
+                let new_pk1 = <Kex as KeyExchange>::PublicKey::from_bytes(&pk1_bytes).unwrap();
+                assert_eq!(pk1.key_id(), new_pk1.key_id());
+            }
+        };
+    }
+
+    /// Tests that `gen_keypair_os` makes valid keypairs, and doesn't make the same one twice
+    #[cfg(feature = "getrandom")]
+    macro_rules! test_gen_keypair_os {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kex = <Kem as KemTrait>::Kex;
+
+                let (sk1, pk1) = Kem::gen_keypair_os();
+                let (sk2, pk2) = Kem::gen_keypair_os();
+
+                // The pubkeys are the pubkeys of the privkeys
+                assert_eq!(Kex::sk_to_pk(&sk1).to_bytes(), pk1.to_bytes());
+                assert_eq!(Kex::sk_to_pk(&sk2).to_bytes(), pk2.to_bytes());
+
+                // And they're different every time
+                assert_ne!(sk1.to_bytes(), sk2.to_bytes());
+                assert_ne!(pk1.to_bytes(), pk2.to_bytes());
+            }
+        };
+    }
+
+    /// Tests that `keypair` makes valid keypairs from both sources, that the IKM source agrees with
+    /// `derive_keypair`, and that the random source agrees with `gen_keypair`
+    macro_rules! test_keypair_source {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                use crate::kem::KeySource;
+
+                type Kem = $kem_ty;
+                type Kex = <Kem as KemTrait>::Kex;
+
+                let ikm = [0x17u8; 32];
+                let (sk1, pk1) = Kem::keypair(KeySource::<StdRng>::Ikm(&ikm)).unwrap();
+                let (sk2, pk2) = Kem::keypair(KeySource::<StdRng>::Ikm(&ikm)).unwrap();
+                let (expected_sk, expected_pk) = Kem::derive_keypair(&ikm).unwrap();
+                assert_eq!(Kex::sk_to_pk(&sk1).to_bytes(), pk1.to_bytes());
+                assert_eq!(sk1.to_bytes(), sk2.to_bytes());
+                assert_eq!(pk1.to_bytes(), pk2.to_bytes());
+                assert_eq!(sk1.to_bytes(), expected_sk.to_bytes());
+                assert_eq!(pk1.to_bytes(), expected_pk.to_bytes());
+
+                // Same seed, same keypair as gen_keypair
+                let (sk, pk) =
+                    Kem::keypair(KeySource::Random(&mut StdRng::seed_from_u64(7))).unwrap();
+                let (expected_sk, _) = Kem::gen_keypair(&mut StdRng::seed_from_u64(7));
+                assert_eq!(Kex::sk_to_pk(&sk).to_bytes(), pk.to_bytes());
+                assert_eq!(sk.to_bytes(), expected_sk.to_bytes());
+
+                // And a fresh RNG gives a fresh keypair
+                let mut csprng = StdRng::from_entropy();
+                let (other_sk, _) = Kem::keypair(KeySource::Random(&mut csprng)).unwrap();
+                assert_ne!(other_sk.to_bytes(), sk.to_bytes());
+            }
+        };
+    }
+
+    /// Tests that `with_ephemeral_keypair` hands the closure a fresh, valid keypair and returns
+    /// what the closure returns
+    macro_rules! test_with_ephemeral_keypair {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kex = <Kem as KemTrait>::Kex;
+
+                let mut csprng = StdRng::from_entropy();
+                let pk1 = Kem::with_ephemeral_keypair(&mut csprng, |sk, pk| {
+                    assert_eq!(Kex::sk_to_pk(sk).to_bytes(), pk.to_bytes());
+                    pk.clone()
+                });
+                let pk2 = Kem::with_ephemeral_keypair(&mut csprng, |_, pk| pk.clone());
+                assert_ne!(pk1.to_bytes(), pk2.to_bytes());
+            }
+        };
+    }
+
+    /// Tests that `derive_keypair_counted` agrees with `derive_keypair`, and that the counter is 0
+    /// on inputs that are accepted on the first try
+    #[cfg(feature = "test-internals")]
+    macro_rules! test_derive_keypair_counted {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+
+                // The probability that a P-256 candidate is rejected is about 2^-32, so no
+                // reasonable IKM will need a second iteration
+                let ikm = [0x42u8; 32];
+                let (sk, pk) = Kem::derive_keypair(&ikm).unwrap();
+                let (counted_sk, counted_pk, counter) = Kem::derive_keypair_counted(&ikm).unwrap();
+
+                assert_eq!(sk.to_bytes(), counted_sk.to_bytes());
+                assert_eq!(pk.to_bytes(), counted_pk.to_bytes());
+                assert_eq!(counter, 0);
+            }
+        };
+    }
+
+    /// Tests that `derive_keypair_counted` reports a nonzero counter when the first candidate is
+    /// rejected. No known P-256 IKM needs a second candidate, so this uses a KDF whose "hash"
+    /// outputs the second-to-last byte of its input, repeated. In HKDF-Expand's HMAC, that byte is
+    /// the DeriveKeyPair counter for the inner hash, and then the inner hash's output for the
+    /// outer one. So each candidate is its counter repeated, and candidate 0, which is zero, is
+    /// rejected, while candidate 1 is accepted.
+    #[cfg(all(feature = "test-internals", feature = "p256"))]
+    #[test]
+    fn test_derive_keypair_counted_nonzero() {
+        use crate::{kdf::Kdf as KdfTrait, kex::DhP256};
+        use digest::{BlockInput, FixedOutput, Reset, Update};
+        use generic_array::{
+            typenum::{U32, U64},
+            GenericArray,
+        };
+
+        // The last two bytes of the input so far, oldest first
+        #[derive(Clone, Default)]
+        struct PenultimateHash([u8; 2]);
+        impl Update for PenultimateHash {
+            fn update(&mut self, data: impl AsRef<[u8]>) {
+                for b in data.as_ref() {
+                    self.0 = [self.0[1], *b];
+                }
+            }
+        }
+        impl BlockInput for PenultimateHash {
+            type BlockSize = U64;
+        }
+        impl FixedOutput for PenultimateHash {
+            type OutputSize = U32;
+            fn finalize_into(self, out: &mut GenericArray<u8, U32>) {
+                out.iter_mut().for_each(|b| *b = self.0[0]);
+            }
+            fn finalize_into_reset(&mut self, out: &mut GenericArray<u8, U32>) {
+                out.iter_mut().for_each(|b| *b = self.0[0]);
+                self.0 = [0; 2];
+            }
+        }
+        impl Reset for PenultimateHash {
+            fn reset(&mut self) {
+                self.0 = [0; 2];
+            }
+        }
+
+        struct PenultimateKdf;
+        impl KdfTrait for PenultimateKdf {
+            type HashImpl = PenultimateHash;
+            // Not a real algorithm ID
+            const KDF_ID: u16 = 0xFFFE;
+        }
+
+        struct PenultimateKem;
+        impl KemTrait for PenultimateKem {
+            type Kex = DhP256;
+            type Kdf = PenultimateKdf;
+            // Not a real KEM ID
+            const KEM_ID: u16 = 0xFFFF;
+        }
+
+        let ikm = [0x42u8; 32];
+        let (counted_sk, counted_pk, counter) =
+            PenultimateKem::derive_keypair_counted(&ikm).unwrap();
+        assert_eq!(counter, 1);
+        assert_eq!(counted_sk.to_bytes().as_slice(), &[1u8; 32]);
+
+        // derive_keypair takes the same candidate
+        let (sk, pk) = PenultimateKem::derive_keypair(&ikm).unwrap();
+        assert_eq!(sk.to_bytes(), counted_sk.to_bytes());
+        assert_eq!(pk.to_bytes(), counted_pk.to_bytes());
+    }
+
+    /// Tests that the escrowed ephemeral key, along with the recipient pubkey, recomputes the
+    /// shared secret that the recipient derives
+    #[cfg(feature = "escrow")]
+    macro_rules! test_encap_with_escrow {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                use crate::kem::{encap_with_escrow, recompute_escrowed_shared_secret};
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+
+                let (shared_secret, encapped_key, sk_eph) =
+                    encap_with_escrow::<Kem, _>(&pk_recip, &mut csprng).unwrap();
+                let decapped_shared_secret = decap::<Kem>(&sk_recip, None, &encapped_key).unwrap();
+                assert_eq!(shared_secret, decapped_shared_secret);
+
+                // The escrow agent gets the same thing
+                let (escrowed_shared_secret, escrowed_encapped_key) =
+                    recompute_escrowed_shared_secret::<Kem>(&pk_recip, &sk_eph).unwrap();
+                assert_eq!(escrowed_shared_secret, decapped_shared_secret);
+                assert_eq!(escrowed_encapped_key.to_bytes(), encapped_key.to_bytes());
+            }
+        };
+    }
+
+    /// Tests that decapping with the sender's identity pubkey in place of an encapped key doesn't
+    /// panic. The encapped key comes from the peer, so anyone can send this.
+    macro_rules! test_decap_sender_id_as_encapped_key {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let (sk_recip, _) = Kem::gen_keypair(&mut csprng);
+                let (_, pk_sender_id) = Kem::gen_keypair(&mut csprng);
+
+                let mixed_up =
+                    EncappedKey::<<Kem as KemTrait>::Kex>::from_bytes(&pk_sender_id.to_bytes())
+                        .unwrap();
+                assert!(decap::<Kem>(&sk_recip, Some(&pk_sender_id), &mixed_up).is_ok());
+            }
+        };
+    }
+
+    /// Tests that keys, encapped keys, and shared secrets serialize to exactly the bytes in a
+    /// draft11 test vector. The vectors are byte strings, so this holds regardless of host
+    /// endianness. The fields are `ikmR`, `skRm`, `pkRm`, `ikmE`, `enc`, and `shared_secret` of
+    /// the Base mode vector with HKDF-SHA256 and AES-128-GCM.
+    macro_rules! test_known_serialization {
+        (
+            $test_name:ident,
+            $kem_ty:ty,
+            $ikm_recip:expr,
+            $sk_recip:expr,
+            $pk_recip:expr,
+            $ikm_eph:expr,
+            $encapped_key:expr,
+            $shared_secret:expr
+        ) => {
+            #[test]
+            fn $test_name() {
+                use crate::kem::encap_with_eph;
+
+                type Kem = $kem_ty;
+                type Kex = <Kem as KemTrait>::Kex;
+
+                let unhex = |s: &str| hex::decode(s).unwrap();
+                let sk_recip_bytes = unhex($sk_recip);
+                let pk_recip_bytes = unhex($pk_recip);
+                let encapped_key_bytes = unhex($encapped_key);
+
+                // Derived keys serialize to the expected bytes
+                let (sk_recip, pk_recip) = Kem::derive_keypair(&unhex($ikm_recip)).unwrap();
+                assert_eq!(sk_recip.to_bytes().as_slice(), sk_recip_bytes.as_slice());
+                assert_eq!(pk_recip.to_bytes().as_slice(), pk_recip_bytes.as_slice());
+
+                // Deserializing and reserializing is the identity
+                let sk = <Kex as KeyExchange>::PrivateKey::from_bytes(&sk_recip_bytes).unwrap();
+                let pk = <Kex as KeyExchange>::PublicKey::from_bytes(&pk_recip_bytes).unwrap();
+                assert_eq!(sk.to_bytes().as_slice(), sk_recip_bytes.as_slice());
+                assert_eq!(pk.to_bytes().as_slice(), pk_recip_bytes.as_slice());
+                assert_eq!(
+                    Kex::sk_to_pk(&sk).to_bytes().as_slice(),
+                    pk_recip_bytes.as_slice()
+                );
+
+                // Encap and decap give the expected encapped key and shared secret
+                let (sk_eph, _) = Kem::derive_keypair(&unhex($ikm_eph)).unwrap();
+                let (shared_secret, encapped_key) =
+                    encap_with_eph::<Kem>(&pk, None, sk_eph).unwrap();
+                assert_eq!(
+                    encapped_key.to_bytes().as_slice(),
+                    encapped_key_bytes.as_slice()
+                );
+                assert_eq!(shared_secret.as_ref(), unhex($shared_secret).as_slice());
+
+                let encapped_key = EncappedKey::<Kex>::from_bytes(&encapped_key_bytes).unwrap();
+                assert_eq!(
+                    encapped_key.to_bytes().as_slice(),
+                    encapped_key_bytes.as_slice()
+                );
+                let decapped_secret = decap::<Kem>(&sk, None, &encapped_key).unwrap();
+                assert_eq!(decapped_secret, shared_secret);
+            }
+        };
+    }
+
+    /// Tests that an all-zero byte string is rejected, and that anything else goes through
+    #[test]
+    fn test_reject_all_zero() {
+        use super::reject_all_zero;
+
+        assert_eq!(
+            reject_all_zero(&[0u8; 32], HpkeError::DecapError),
+            Err(HpkeError::DecapError)
+        );
+        assert_eq!(
+            reject_all_zero(&[], HpkeError::EncapError),
+            Err(HpkeError::EncapError)
+        );
+
+        let mut almost_zero = [0u8; 32];
+        almost_zero[31] = 1;
+        assert_eq!(reject_all_zero(&almost_zero, HpkeError::DecapError), Ok(()));
+    }
+
+    /// Tests that encap and decap fail if the DH returns all zeros, even when the backend doesn't
+    /// catch it. This uses X25519 keys with a broken DH.
+    #[cfg(feature = "x25519-dalek")]
+    #[test]
+    fn test_zero_dh_rejected() {
+        use crate::{
+            kdf::{HkdfSha256, Kdf as KdfTrait},
+            kex::{x25519, KexError, X25519},
+            util::KemSuiteId,
+        };
+        use generic_array::{typenum, GenericArray};
+
+        /// The all-zero DH result
+        struct ZeroKexResult;
+        impl Serializable for ZeroKexResult {
+            type OutputSize = typenum::U32;
+            fn to_bytes(&self) -> GenericArray<u8, typenum::U32> {
+                GenericArray::default()
+            }
+        }
+
+        /// X25519, except every DH outputs zeros
+        struct ZeroDh;
+        impl KeyExchange for ZeroDh {
+            type PublicKey = x25519::PublicKey;
+            type PrivateKey = x25519::PrivateKey;
+            type KexResult = ZeroKexResult;
+
+            fn sk_to_pk(sk: &Self::PrivateKey) -> Self::PublicKey {
+                X25519::sk_to_pk(sk)
+            }
+            fn kex(_: &Self::PrivateKey, _: &Self::PublicKey) -> Result<ZeroKexResult, KexError> {
+                Ok(ZeroKexResult)
+            }
+            fn derive_keypair<Kdf: KdfTrait>(
+                suite_id: &KemSuiteId,
+                ikm: &[u8],
+            ) -> Result<(Self::PrivateKey, Self::PublicKey), HpkeError> {
+                X25519::derive_keypair::<Kdf>(suite_id, ikm)
+            }
+            #[cfg(feature = "test-internals")]
+            fn derive_keypair_counted<Kdf: KdfTrait>(
+                suite_id: &KemSuiteId,
+                ikm: &[u8],
+            ) -> Result<(Self::PrivateKey, Self::PublicKey, u8), HpkeError> {
+                X25519::derive_keypair_counted::<Kdf>(suite_id, ikm)
+            }
+        }
+
+        struct ZeroDhKem;
+        impl KemTrait for ZeroDhKem {
+            type Kex = ZeroDh;
+            type Kdf = HkdfSha256;
+            // Not a real KEM ID
+            const KEM_ID: u16 = 0xffff;
+        }
+
+        let mut csprng = StdRng::from_entropy();
+        let (sk_recip, pk_recip) = ZeroDhKem::gen_keypair(&mut csprng);
+        let (sk_sender, pk_sender) = ZeroDhKem::gen_keypair(&mut csprng);
+
+        // Both unauthed and authed encap fail
+        assert_eq!(
+            encap::<ZeroDhKem, _>(&pk_recip, None, &mut csprng).err(),
+            Some(HpkeError::EncapError)
+        );
+        assert_eq!(
+            encap::<ZeroDhKem, _>(&pk_recip, Some((&sk_sender, &pk_sender)), &mut csprng).err(),
+            Some(HpkeError::EncapError)
+        );
+
+        // So do unauthed and authed decap, on an otherwise fine encapped key
+        let encapped_key = EncappedKey::<ZeroDh>::from_bytes(&pk_sender.to_bytes()).unwrap();
+        assert_eq!(
+            decap::<ZeroDhKem>(&sk_recip, None, &encapped_key).err(),
+            Some(HpkeError::DecapError)
+        );
+        let (_, pk_other) = ZeroDhKem::gen_keypair(&mut csprng);
+        assert_eq!(
+            decap::<ZeroDhKem>(&sk_recip, Some(&pk_other), &encapped_key).err(),
+            Some(HpkeError::DecapError)
+        );
+    }
+
+    /// Tests that `with_ephemeral_keypair` zeroizes the private key in the same place the closure
+    /// saw it. This uses a private key that doesn't zeroize itself on drop, and records where it
+    /// was dropped and what it held at that point.
+    #[cfg(feature = "x25519-dalek")]
+    #[test]
+    fn test_with_ephemeral_keypair_zeroizes() {
+        use crate::{
+            kdf::{HkdfSha256, Kdf as KdfTrait},
+            kex::{x25519, Deserializable, KexError, X25519},
+            util::KemSuiteId,
+        };
+        use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+        use generic_array::{typenum, GenericArray};
+        use zeroize::Zeroize;
+
+        // How many RecordingSks have been dropped, the address of the last one, and whether every
+        // one was all zeros when it was dropped. Nothing else uses RecordingSk, so these aren't
+        // shared with any other test.
+        static DROP_COUNT: AtomicUsize = AtomicUsize::new(0);
+        static DROP_ADDR: AtomicUsize = AtomicUsize::new(0);
+        static DROPPED_ZEROED: AtomicBool = AtomicBool::new(true);
+
+        /// An X25519 private key that records its address and whether it's zero when it's
+        /// dropped. It only gets zeroed if someone calls zeroize() on it.
+        #[derive(Clone)]
+        #[cfg_attr(
+            feature = "serde_impls",
+            derive(serde_derive::Serialize, serde_derive::Deserialize)
+        )]
+        struct RecordingSk([u8; 32]);
+        impl Zeroize for RecordingSk {
+            fn zeroize(&mut self) {
+                self.0.zeroize();
+            }
+        }
+        impl Drop for RecordingSk {
+            fn drop(&mut self) {
+                DROP_COUNT.fetch_add(1, Ordering::SeqCst);
+                DROP_ADDR.store(self as *const RecordingSk as usize, Ordering::SeqCst);
+                if self.0 != [0u8; 32] {
+                    DROPPED_ZEROED.store(false, Ordering::SeqCst);
+                }
+            }
+        }
+        impl Serializable for RecordingSk {
+            type OutputSize = typenum::U32;
+            fn to_bytes(&self) -> GenericArray<u8, typenum::U32> {
+                self.0.into()
+            }
+        }
+        impl Deserializable for RecordingSk {
+            fn from_bytes(encoded: &[u8]) -> Result<Self, HpkeError> {
+                let mut buf = [0u8; 32];
+                buf.copy_from_slice(encoded);
+                Ok(RecordingSk(buf))
+            }
+        }
+
+        /// X25519 with a RecordingSk private key
+        struct RecordingKex;
+        impl KeyExchange for RecordingKex {
+            type PublicKey = x25519::PublicKey;
+            type PrivateKey = RecordingSk;
+            type KexResult = x25519::KexResult;
+
+            fn sk_to_pk(sk: &RecordingSk) -> x25519::PublicKey {
+                X25519::sk_to_pk(&x25519::PrivateKey::from_bytes(&sk.0).unwrap())
+            }
+            fn kex(_: &RecordingSk, _: &x25519::PublicKey) -> Result<x25519::KexResult, KexError> {
+                Err(KexError)
+            }
+            fn derive_keypair<Kdf: KdfTrait>(
+                suite_id: &KemSuiteId,
+                ikm: &[u8],
+            ) -> Result<(RecordingSk, x25519::PublicKey), HpkeError> {
+                let (sk, pk) = X25519::derive_keypair::<Kdf>(suite_id, ikm)?;
+                Ok((RecordingSk(sk.to_bytes().into()), pk))
+            }
+            #[cfg(feature = "test-internals")]
+            fn derive_keypair_counted<Kdf: KdfTrait>(
+                suite_id: &KemSuiteId,
+                ikm: &[u8],
+            ) -> Result<(RecordingSk, x25519::PublicKey, u8), HpkeError> {
+                let (sk, pk) = Self::derive_keypair::<Kdf>(suite_id, ikm)?;
+                Ok((sk, pk, 0))
+            }
+        }
+
+        struct RecordingKem;
+        impl KemTrait for RecordingKem {
+            type Kex = RecordingKex;
+            type Kdf = HkdfSha256;
+            // Not a real KEM ID
+            const KEM_ID: u16 = 0xffff;
+        }
+
+        let mut csprng = StdRng::from_entropy();
+        let (sk_addr, sk_bytes) = RecordingKem::with_ephemeral_keypair(&mut csprng, |sk, pk| {
+            assert_eq!(RecordingKex::sk_to_pk(sk).to_bytes(), pk.to_bytes());
+            (sk as *const RecordingSk as usize, sk.0)
+        });
+        assert_ne!(sk_bytes, [0u8; 32]);
+
+        // The key was dropped exactly once, where the closure saw it, and was zero by then
+        assert_eq!(DROP_COUNT.load(Ordering::SeqCst), 1);
+        assert_eq!(DROP_ADDR.load(Ordering::SeqCst), sk_addr);
+        assert!(DROPPED_ZEROED.load(Ordering::SeqCst));
+    }
+
     #[cfg(feature = "x25519-dalek")]
     mod x25519_tests {
         use super::*;
 
+        test_decap_sender_id_as_encapped_key!(
+            test_decap_sender_id_as_encapped_key_x25519,
+            crate::kem::X25519HkdfSha256
+        );
+
+        #[cfg(feature = "test-internals")]
+        test_derive_keypair_counted!(
+            test_derive_keypair_counted_x25519,
+            crate::kem::X25519HkdfSha256
+        );
+        test_as_ref_matches_to_bytes!(
+            test_as_ref_matches_to_bytes_x25519,
+            crate::kem::X25519HkdfSha256
+        );
         test_encap_correctness!(test_encap_correctness_x25519, crate::kem::X25519HkdfSha256);
+        test_shared_secret_len_and_zeroize!(
+            test_shared_secret_len_and_zeroize_x25519,
+            crate::kem::X25519HkdfSha256
+        );
+        test_with_ephemeral_keypair!(
+            test_with_ephemeral_keypair_x25519,
+            crate::kem::X25519HkdfSha256
+        );
+        test_keypair_source!(test_keypair_source_x25519, crate::kem::X25519HkdfSha256);
+        test_degenerate_rng!(test_degenerate_rng_x25519, crate::kem::X25519HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_tagged_bytes!(
+            test_tagged_bytes_x25519,
+            crate::kem::X25519HkdfSha256,
+            X25519HkdfSha256
+        );
+        #[cfg(feature = "alloc")]
+        test_ech_key_config!(
+            test_ech_key_config_x25519,
+            crate::kem::X25519HkdfSha256,
+            X25519HkdfSha256
+        );
+        // Every 32-byte string is an X25519 pubkey, so the invalid key is the wrong length too
+        #[cfg(feature = "alloc")]
+        test_validate_public_keys_batch!(
+            test_validate_public_keys_batch_x25519,
+            crate::kem::X25519HkdfSha256,
+            [0u8; 33],
+            HpkeError::IncorrectInputLength {
+                expected: 32,
+                got: 33
+            }
+        );
+        #[cfg(feature = "escrow")]
+        test_encap_with_escrow!(test_encap_with_escrow_x25519, crate::kem::X25519HkdfSha256);
+        #[cfg(feature = "getrandom")]
+        test_gen_keypair_os!(test_gen_keypair_os_x25519, crate::kem::X25519HkdfSha256);
+        test_key_id!(test_key_id_x25519, crate::kem::X25519HkdfSha256);
+        // draft11 §A.1.1. X25519 private keys are clamped when serialized, so skRm is given
+        // clamped here. The unclamped skRm is 4612c5...4e8ac8.
+        test_known_serialization!(
+            test_known_serialization_x25519,
+            crate::kem::X25519HkdfSha256,
+            "6db9df30aa07dd42ee5e8181afdb977e538f5e1fec8a06223f33f7013e525037",
+            "4012c550263fc8ad58375df3f557aac531d26850903e55a9f23f21d8534e8a48",
+            "3948cfe0ad1ddb695d780e59077195da6c56506b027329794ab02bca80815c4d",
+            "7268600d403fce431561aef583ee1613527cff655c1343f29812e66706df3234",
+            "37fda3567bdbd628e88668c3c8d7e97d1d1253b6d4ea6d44c150f741f1bf4431",
+            "fe0e18c9f024ce43799ae393c7e8fe8fce9d218875e8227b0187c04e7d2ea1fc"
+        );
         test_encapped_serialize!(test_encapped_serialize_x25519, crate::kem::X25519HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_pack_auth_keys!(test_pack_auth_keys_x25519, crate::kem::X25519HkdfSha256);
+        test_encapped_incorrect_len!(
+            test_encapped_incorrect_len_x25519,
+            crate::kem::X25519HkdfSha256
+        );
     }
 
     #[cfg(feature = "p256")]
     mod p256_tests {
         use super::*;
 
+        test_decap_sender_id_as_encapped_key!(
+            test_decap_sender_id_as_encapped_key_p256,
+            crate::kem::DhP256HkdfSha256
+        );
+
+        #[cfg(feature = "test-internals")]
+        test_derive_keypair_counted!(
+            test_derive_keypair_counted_p256,
+            crate::kem::DhP256HkdfSha256
+        );
+
+        test_as_ref_matches_to_bytes!(
+            test_as_ref_matches_to_bytes_p256,
+            crate::kem::DhP256HkdfSha256
+        );
         test_encap_correctness!(test_encap_correctness_p256, crate::kem::DhP256HkdfSha256);
+        test_shared_secret_len_and_zeroize!(
+            test_shared_secret_len_and_zeroize_p256,
+            crate::kem::DhP256HkdfSha256
+        );
+        test_with_ephemeral_keypair!(
+            test_with_ephemeral_keypair_p256,
+            crate::kem::DhP256HkdfSha256
+        );
+        test_keypair_source!(test_keypair_source_p256, crate::kem::DhP256HkdfSha256);
+        test_degenerate_rng!(test_degenerate_rng_p256, crate::kem::DhP256HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_tagged_bytes!(
+            test_tagged_bytes_p256,
+            crate::kem::DhP256HkdfSha256,
+            DhP256HkdfSha256
+        );
+        #[cfg(feature = "alloc")]
+        test_ech_key_config!(
+            test_ech_key_config_p256,
+            crate::kem::DhP256HkdfSha256,
+            DhP256HkdfSha256
+        );
+        // (0, 0) is not on the curve
+        #[cfg(feature = "alloc")]
+        test_validate_public_keys_batch!(
+            test_validate_public_keys_batch_p256,
+            crate::kem::DhP256HkdfSha256,
+            {
+                let mut off_curve = [0u8; 65];
+                off_curve[0] = 0x04;
+                off_curve
+            },
+            HpkeError::ValidationError
+        );
+        #[cfg(feature = "escrow")]
+        test_encap_with_escrow!(test_encap_with_escrow_p256, crate::kem::DhP256HkdfSha256);
+        #[cfg(feature = "getrandom")]
+        test_gen_keypair_os!(test_gen_keypair_os_p256, crate::kem::DhP256HkdfSha256);
+        test_key_id!(test_key_id_p256, crate::kem::DhP256HkdfSha256);
+        // draft11 §A.3.1
+        test_known_serialization!(
+            test_known_serialization_p256,
+            crate::kem::DhP256HkdfSha256,
+            "668b37171f1072f3cf12ea8a236a45df23fc13b82af3609ad1e354f6ef817550",
+            "f3ce7fdae57e1a310d87f1ebbde6f328be0a99cdbcadf4d6589cf29de4b8ffd2",
+            "04fe8c19ce0905191ebc298a9245792531f26f0cece2460639e8bc39cb7f706a826a779b4cf969b8a0e539c7\
+             f62fb3d30ad6aa8f80e30f1d128aafd68a2ce72ea0",
+            "4270e54ffd08d79d5928020af4686d8f6b7d35dbe470265f1f5aa22816ce860e",
+            "04a92719c6195d5085104f469a8b9814d5838ff72b60501e2c4466e5e67b325ac98536d7b61a1af4b78e5b7f\
+             951c0900be863c403ce65c9bfcb9382657222d18c4",
+            "c0d26aeab536609a572b07695d933b589dcf363ff9d93c93adea537aeabb8cb8"
+        );
         test_encapped_serialize!(test_encapped_serialize_p256, crate::kem::DhP256HkdfSha256);
+        #[cfg(feature = "alloc")]
+        test_pack_auth_keys!(test_pack_auth_keys_p256, crate::kem::DhP256HkdfSha256);
+        test_encapped_incorrect_len!(
+            test_encapped_incorrect_len_p256,
+            crate::kem::DhP256HkdfSha256
+        );
     }
 }
@@ -43,6 +43,7 @@ pub trait KeyExchange {
     /// `Kem::gen_keypair` or `Kem::derive_keypair`
     #[cfg(feature = "serde_impls")]
     type PublicKey: Clone
+        + AsRef<[u8]>
         + Serializable
         + Deserializable
         + SerdeSerialize
@@ -50,10 +51,11 @@ pub trait KeyExchange {
     /// The key exchange's public key type. If you want to generate a keypair, see
     /// `Kem::gen_keypair` or `Kem::derive_keypair`
     #[cfg(not(feature = "serde_impls"))]
-    type PublicKey: Clone + Serializable + Deserializable;
+    type PublicKey: Clone + AsRef<[u8]> + Serializable + Deserializable;
 
     /// The key exchange's private key type. If you want to generate a keypair, see
-    /// `Kem::gen_keypair` or `Kem::derive_keypair`
+    /// `Kem::gen_keypair` or `Kem::derive_keypair`. Unlike public keys, this deliberately does
+    /// not implement `AsRef<[u8]>`, so that secret bytes aren't handed out by accident.
     #[cfg(feature = "serde_impls")]
     type PrivateKey: Clone
         + Serializable
@@ -62,7 +64,8 @@ pub trait KeyExchange {
         + for<'a> SerdeDeserialize<'a>;
 
     /// The key exchange's private key type. If you want to generate a keypair, see
-    /// `Kem::gen_keypair` or `Kem::derive_keypair`
+    /// `Kem::gen_keypair` or `Kem::derive_keypair`. Unlike public keys, this deliberately does
+    /// not implement `AsRef<[u8]>`, so that secret bytes aren't handed out by accident.
     #[cfg(not(feature = "serde_impls"))]
     type PrivateKey: Clone + Serializable + Deserializable;
 
@@ -79,7 +82,14 @@ pub trait KeyExchange {
     fn derive_keypair<Kdf: KdfTrait>(
         suite_id: &KemSuiteId,
         ikm: &[u8],
-    ) -> (Self::PrivateKey, Self::PublicKey);
+    ) -> Result<(Self::PrivateKey, Self::PublicKey), HpkeError>;
+
+    #[doc(hidden)]
+    #[cfg(feature = "test-internals")]
+    fn derive_keypair_counted<Kdf: KdfTrait>(
+        suite_id: &KemSuiteId,
+        ikm: &[u8],
+    ) -> Result<(Self::PrivateKey, Self::PublicKey, u8), HpkeError>;
 }
 
 #[cfg(feature = "p256")]
@@ -1,7 +1,7 @@
 use crate::{
     kdf::{labeled_extract, Kdf as KdfTrait, LabeledExpand},
     kex::{Deserializable, KexError, KeyExchange, Serializable},
-    util::{enforce_equal_len, KemSuiteId},
+    util::{enforce_equal_len, replace_and_zeroize, KemSuiteId},
     HpkeError,
 };
 
@@ -9,15 +9,26 @@ use generic_array::{
     typenum::{self, Unsigned},
     GenericArray,
 };
+#[cfg(feature = "compressed")]
+use p256::elliptic_curve::sec1::CompressedPointSize;
 use p256::{
     elliptic_curve::{ecdh::diffie_hellman, sec1::UncompressedPointSize, FieldSize},
     NistP256,
 };
+use subtle::{ConstantTimeEq, CtOption};
 use zeroize::Zeroize;
 
 /// An ECDH-P256 public key. This is never the point at infinity.
+// We keep the uncompressed encoding around so that we can hand out a reference to it in AsRef
 #[derive(Clone)]
-pub struct PublicKey(p256::PublicKey);
+pub struct PublicKey(p256::PublicKey, p256::EncodedPoint);
+
+impl PublicKey {
+    fn new(pk: p256::PublicKey) -> PublicKey {
+        let encoded = p256::EncodedPoint::encode(pk, false);
+        PublicKey(pk, encoded)
+    }
+}
 
 // p256::SecretKey is just a newtype for an elliptic_curve::NonZeroScalar as long as
 // feature="arithmetic" is set in elliptic_curve.
@@ -37,9 +48,15 @@ impl Serializable for PublicKey {
     type OutputSize = UncompressedPointSize<NistP256>;
 
     fn to_bytes(&self) -> GenericArray<u8, Self::OutputSize> {
-        // Get the uncompressed pubkey encoding
-        let encoded = p256::EncodedPoint::encode(self.0, false);
-        GenericArray::clone_from_slice(encoded.as_bytes())
+        // The uncompressed pubkey encoding was computed when the key was made
+        GenericArray::clone_from_slice(self.1.as_bytes())
+    }
+}
+
+// Returns the same bytes as to_bytes()
+impl AsRef<[u8]> for PublicKey {
+    fn as_ref(&self) -> &[u8] {
+        self.1.as_bytes()
     }
 }
 
@@ -54,7 +71,38 @@ impl Deserializable for PublicKey {
         // PublicKey::from_sec1_bytes will error if it receives the point at infinity.
         let parsed =
             p256::PublicKey::from_sec1_bytes(encoded).map_err(|_| HpkeError::ValidationError)?;
-        Ok(PublicKey(parsed))
+        Ok(PublicKey::new(parsed))
+    }
+}
+
+impl PublicKey {
+    /// Serializes this public key as a compressed SEC1 point, which is 33 bytes instead of 65.
+    /// **This is not the encoding RFC 9180 uses.** Only use it on links where both ends agree to,
+    /// and use `to_bytes` everywhere else. This is gated under the `compressed` feature.
+    #[cfg(feature = "compressed")]
+    pub fn to_bytes_compressed(&self) -> GenericArray<u8, CompressedPointSize<NistP256>> {
+        let encoded = p256::EncodedPoint::encode(self.0, true);
+        GenericArray::clone_from_slice(encoded.as_bytes())
+    }
+
+    /// Deserializes a public key from a compressed SEC1 point, as output by
+    /// `to_bytes_compressed`. The point is decompressed and checked to be on the curve. This is
+    /// gated under the `compressed` feature.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(pk)` on success. If `encoded` isn't 33 bytes long, returns
+    /// `Err(HpkeError::IncorrectInputLength { expected: 33, got: encoded.len() })`. If `encoded`
+    /// isn't a compressed point on the curve, returns `Err(HpkeError::ValidationError)`.
+    #[cfg(feature = "compressed")]
+    pub fn from_bytes_compressed(encoded: &[u8]) -> Result<PublicKey, HpkeError> {
+        // The length check rules out the uncompressed and identity encodings
+        enforce_equal_len(CompressedPointSize::<NistP256>::to_usize(), encoded.len())?;
+
+        // Decompression fails if the x-coordinate isn't that of a point on the curve
+        let parsed =
+            p256::PublicKey::from_sec1_bytes(encoded).map_err(|_| HpkeError::ValidationError)?;
+        Ok(PublicKey::new(parsed))
     }
 }
 
@@ -74,15 +122,37 @@ impl Deserializable for PrivateKey {
         // Check the length
         enforce_equal_len(Self::OutputSize::to_usize(), encoded.len())?;
 
-        // Recall PrivateKeys aren't allowed to be 0 mod the curve order. Since p256::SecretKeys
-        // are actually NonZeroScalars whenever feature="arithmetic", this invariant is checked for
-        // us in NonZeroScalar::new()
-        let sk = p256::SecretKey::from_bytes(encoded).map_err(|_| HpkeError::ValidationError)?;
+        // Recall PrivateKeys aren't allowed to be 0 mod the curve order
+        let sk = secret_key_from_bytes(encoded).ok_or(HpkeError::ValidationError)?;
 
         Ok(PrivateKey(sk))
     }
 }
 
+// Zero isn't a valid secret key, so this can't zero the key in place. Instead, the key is replaced
+// with the scalar 1, and the memory of the old key is zeroed.
+impl Zeroize for PrivateKey {
+    fn zeroize(&mut self) {
+        let mut one = [0u8; 32];
+        one[31] = 1;
+        let placeholder = secret_key_from_bytes(&one).expect("1 is a valid secret key");
+        replace_and_zeroize(&mut self.0, placeholder);
+    }
+}
+
+/// Parses a scalar in `[1, order)` as a secret key, in constant time. `encoded` MUST be 32 bytes
+/// long. We don't use `p256::SecretKey::from_bytes`, since its zero check never fires, and it
+/// returns early on an out-of-range scalar. Instead, the range check and the zero check are both
+/// constant-time, and they're combined before anything branches on them. So the running time
+/// reveals only whether the scalar was accepted, which the return value reveals anyway. A zero
+/// secret key would make `sk_to_pk` panic.
+fn secret_key_from_bytes(encoded: &[u8]) -> Option<p256::SecretKey> {
+    let is_nonzero = !encoded.ct_eq(&[0u8; 32][..]);
+    let scalar = p256::ScalarBytes::new(GenericArray::clone_from_slice(encoded))
+        .and_then(|s| CtOption::new(s, is_nonzero));
+    Option::from(scalar).map(p256::SecretKey::new)
+}
+
 // DH results are serialized in the same way as public keys
 impl Serializable for KexResult {
     // draft11 §4.1: Nsecret of DHKEM(P-256, HKDF-SHA256) is 32
@@ -111,7 +181,7 @@ impl KeyExchange for DhP256 {
         // pk = sk·G where G is the generator. This maintains the invariant of the public key not
         // being the point at infinity, since ord(G) = p, and sk is not 0 mod p (by the invariant
         // we keep on PrivateKeys)
-        PublicKey(sk.0.public_key())
+        PublicKey::new(sk.0.public_key())
     }
 
     /// Does the DH operation. This function is infallible, thanks to invariants on its inputs.
@@ -148,45 +218,84 @@ impl KeyExchange for DhP256 {
 
     /// Deterministically derives a keypair from the given input keying material and ciphersuite
     /// ID. The keying material SHOULD have as many bits of entropy as the bit length of a secret
-    /// key, i.e., 256.
+    /// key, i.e., 256. Returns `Err(HpkeError::DeriveKeyPairError)` if every candidate private key
+    /// is rejected.
     #[doc(hidden)]
-    fn derive_keypair<Kdf: KdfTrait>(suite_id: &KemSuiteId, ikm: &[u8]) -> (PrivateKey, PublicKey) {
-        // Write the label into a byte buffer and extract from the IKM
-        let (_, hkdf_ctx) = labeled_extract::<Kdf>(&[], suite_id, b"dkp_prk", ikm);
-
-        // The buffer we hold the candidate scalar bytes in. This is the size of a private key.
-        let mut buf = GenericArray::<u8, <PrivateKey as Serializable>::OutputSize>::default();
-
-        // Try to generate a key 256 times. Practically, this will succeed and return early on the
-        // first iteration.
-        for counter in 0u8..=255 {
-            // This unwrap is fine. It only triggers if buf is way too big. It's only 32 bytes.
-            hkdf_ctx
-                .labeled_expand(suite_id, b"candidate", &[counter], &mut buf)
-                .unwrap();
+    fn derive_keypair<Kdf: KdfTrait>(
+        suite_id: &KemSuiteId,
+        ikm: &[u8],
+    ) -> Result<(PrivateKey, PublicKey), HpkeError> {
+        let (sk, pk, _) = derive_keypair_with_counter::<Kdf>(suite_id, ikm)?;
+        Ok((sk, pk))
+    }
 
-            // Try to convert to a nonzero scalar. If the conversion succeeded, return the keypair
-            if let Ok(s) = p256::SecretKey::from_bytes(buf) {
-                let sk = PrivateKey(s);
-                let pk = Self::sk_to_pk(&sk);
-                return (sk, pk);
-            }
-        }
+    /// Does `derive_keypair` and also returns the value of `counter` that produced the accepted
+    /// candidate, i.e., the number of candidates that were rejected
+    #[doc(hidden)]
+    #[cfg(feature = "test-internals")]
+    fn derive_keypair_counted<Kdf: KdfTrait>(
+        suite_id: &KemSuiteId,
+        ikm: &[u8],
+    ) -> Result<(PrivateKey, PublicKey, u8), HpkeError> {
+        derive_keypair_with_counter::<Kdf>(suite_id, ikm)
+    }
+}
 
-        // The code should never ever get here. The likelihood that we get 256 bad samples
-        // in a row for p256 is 2^-8192.
-        panic!("DeriveKeyPair failed all attempts");
+/// The DeriveKeyPair rejection sampling loop. Returns the keypair along with the value of
+/// `counter` that produced it.
+fn derive_keypair_with_counter<Kdf: KdfTrait>(
+    suite_id: &KemSuiteId,
+    ikm: &[u8],
+) -> Result<(PrivateKey, PublicKey, u8), HpkeError> {
+    // Write the label into a byte buffer and extract from the IKM
+    let (_, hkdf_ctx) = labeled_extract::<Kdf>(&[], suite_id, b"dkp_prk", ikm);
+
+    sample_keypair(|counter, buf| {
+        // This unwrap is fine. It only triggers if buf is way too big. It's only 32 bytes.
+        hkdf_ctx
+            .labeled_expand(suite_id, b"candidate", &[counter], buf)
+            .unwrap();
+    })
+}
+
+/// Runs the rejection sampling part of DeriveKeyPair, where `candidate(counter, buf)` fills `buf`
+/// with the candidate scalar bytes for the given counter. Returns the keypair along with the value
+/// of `counter` that produced it. This takes at most 256 candidates. If all of them are rejected,
+/// returns `Err(HpkeError::DeriveKeyPairError)`.
+fn sample_keypair<F>(mut candidate: F) -> Result<(PrivateKey, PublicKey, u8), HpkeError>
+where
+    F: FnMut(u8, &mut [u8]),
+{
+    // The buffer we hold the candidate scalar bytes in. This is the size of a private key.
+    let mut buf = GenericArray::<u8, <PrivateKey as Serializable>::OutputSize>::default();
+
+    // Try to generate a key 256 times. Practically, this will succeed and return early on the
+    // first iteration.
+    for counter in 0u8..=255 {
+        candidate(counter, &mut buf);
+
+        // Try to convert to a nonzero scalar. If the conversion succeeded, return the keypair
+        if let Some(s) = secret_key_from_bytes(&buf) {
+            let sk = PrivateKey(s);
+            let pk = DhP256::sk_to_pk(&sk);
+            return Ok((sk, pk, counter));
+        }
     }
+
+    // The code should never ever get here. The likelihood that we get 256 bad samples
+    // in a row for p256 is 2^-8192.
+    Err(HpkeError::DeriveKeyPairError)
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{
         kex::{
-            ecdh_nistp::{DhP256, PrivateKey, PublicKey},
+            ecdh_nistp::{sample_keypair, DhP256, PrivateKey, PublicKey},
             Deserializable, KeyExchange, Serializable,
         },
         test_util::kex_gen_keypair,
+        HpkeError,
     };
 
     use rand::{rngs::StdRng, SeedableRng};
@@ -302,6 +411,86 @@ mod tests {
         assert_eq!(pubkey, rederived_pubkey);
     }
 
+    /// Tests that from_bytes only accepts the 65-byte uncompressed encoding
+    #[test]
+    fn test_pubkey_from_bytes_rejects_other_encodings() {
+        type Kex = DhP256;
+
+        let mut csprng = StdRng::from_entropy();
+        let (_, pk) = kex_gen_keypair::<Kex, _>(&mut csprng);
+        let pk_bytes = pk.to_bytes();
+
+        // The compressed encoding of the same point is the wrong length
+        let mut compressed = [0u8; 33];
+        compressed[0] = 0x02 | (pk_bytes[64] & 1);
+        compressed[1..].copy_from_slice(&pk_bytes[1..33]);
+        assert_eq!(
+            PublicKey::from_bytes(&compressed).err(),
+            Some(HpkeError::IncorrectInputLength {
+                expected: 65,
+                got: 33
+            })
+        );
+        // So is a truncated uncompressed encoding
+        assert_eq!(
+            PublicKey::from_bytes(&pk_bytes[..64]).err(),
+            Some(HpkeError::IncorrectInputLength {
+                expected: 65,
+                got: 64
+            })
+        );
+        // A 65-byte input with a compressed tag isn't an uncompressed point
+        let mut bad_tag = pk_bytes;
+        bad_tag[0] = 0x02;
+        assert_eq!(
+            PublicKey::from_bytes(&bad_tag).err(),
+            Some(HpkeError::ValidationError)
+        );
+    }
+
+    /// Tests that compressing then decompressing a pubkey gives back the same pubkey, and that
+    /// uncompressed and invalid points are rejected
+    #[cfg(feature = "compressed")]
+    #[test]
+    fn test_pubkey_compressed_roundtrip() {
+        type Kex = DhP256;
+
+        let mut csprng = StdRng::from_entropy();
+        let (_, pk) = kex_gen_keypair::<Kex, _>(&mut csprng);
+
+        let compressed = pk.to_bytes_compressed();
+        assert_eq!(compressed.len(), 33);
+        assert!(compressed[0] == 0x02 || compressed[0] == 0x03);
+        assert_eq!(&compressed[1..], &pk.to_bytes()[1..33]);
+
+        let decompressed = PublicKey::from_bytes_compressed(&compressed).unwrap();
+        assert_eq!(decompressed, pk);
+        // The standard encoding is still uncompressed
+        assert_eq!(decompressed.to_bytes(), pk.to_bytes());
+
+        assert_eq!(
+            PublicKey::from_bytes_compressed(&pk.to_bytes()).err(),
+            Some(HpkeError::IncorrectInputLength {
+                expected: 33,
+                got: 65
+            })
+        );
+        let mut bad_tag = compressed;
+        bad_tag[0] = 0x04;
+        assert_eq!(
+            PublicKey::from_bytes_compressed(&bad_tag).err(),
+            Some(HpkeError::ValidationError)
+        );
+        // There is no point on P-256 with x = 1
+        let mut off_curve = [0u8; 33];
+        off_curve[0] = 0x02;
+        off_curve[32] = 0x01;
+        assert_eq!(
+            PublicKey::from_bytes_compressed(&off_curve).err(),
+            Some(HpkeError::ValidationError)
+        );
+    }
+
     /// Tests that an deserialize-serialize round-trip on a DH keypair ends up at the same values
     #[test]
     fn test_dh_serialize_correctness() {
@@ -321,4 +510,100 @@ mod tests {
         assert!(new_sk == sk, "private key doesn't serialize correctly");
         assert!(new_pk == pk, "public key doesn't serialize correctly");
     }
+
+    /// Tests that the DeriveKeyPair rejection loop skips a zero scalar and scalars that are at
+    /// least the group order, and accepts the first valid one
+    #[test]
+    fn test_sample_keypair_rejects_invalid_scalars() {
+        let unhex = |s: &str| hex::decode(s).unwrap();
+        let candidates = [
+            // Zero
+            unhex("0000000000000000000000000000000000000000000000000000000000000000"),
+            // The group order
+            unhex("ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632551"),
+            // Way bigger than the group order
+            unhex("ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"),
+            // One
+            unhex("0000000000000000000000000000000000000000000000000000000000000001"),
+        ];
+
+        let (sk, pk, counter) = sample_keypair(|counter, buf| {
+            buf.copy_from_slice(&candidates[counter as usize]);
+        })
+        .unwrap();
+        assert_eq!(counter, 3);
+        assert_eq!(sk.to_bytes().as_slice(), &candidates[3][..]);
+        assert_eq!(pk, DhP256::sk_to_pk(&sk));
+    }
+
+    /// Tests that the DeriveKeyPair rejection loop gives up with an error after 256 bad
+    /// candidates, rather than spinning forever
+    #[test]
+    fn test_sample_keypair_gives_up() {
+        let mut num_candidates = 0;
+        let res = sample_keypair(|_, buf| {
+            num_candidates += 1;
+            assert!(num_candidates <= 256, "more than 256 candidates");
+            buf.iter_mut().for_each(|b| *b = 0);
+        });
+        assert!(matches!(res, Err(HpkeError::DeriveKeyPairError)));
+        assert_eq!(num_candidates, 256);
+    }
+
+    /// Tests that a zero private key is rejected
+    #[test]
+    fn test_zero_privkey_rejected() {
+        assert!(PrivateKey::from_bytes(&[0u8; 32]).is_err());
+    }
+
+    /// Tests that private keys outside `[1, order)` are rejected with a ValidationError, rather
+    /// than reduced mod the order, and that the ones on either edge of the range are accepted
+    #[test]
+    fn test_out_of_range_privkey_rejected() {
+        let unhex = |s: &str| hex::decode(s).unwrap();
+        let rejected = [
+            // Zero
+            unhex("0000000000000000000000000000000000000000000000000000000000000000"),
+            // The group order
+            unhex("ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632551"),
+            // The group order plus one, which reduces to one
+            unhex("ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632552"),
+            // The largest 256-bit number
+            unhex("ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"),
+        ];
+        for sk_bytes in rejected.iter() {
+            assert_eq!(
+                PrivateKey::from_bytes(sk_bytes).err(),
+                Some(HpkeError::ValidationError)
+            );
+        }
+
+        let accepted = [
+            // One
+            unhex("0000000000000000000000000000000000000000000000000000000000000001"),
+            // The group order minus one
+            unhex("ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632550"),
+        ];
+        for sk_bytes in accepted.iter() {
+            let sk = PrivateKey::from_bytes(sk_bytes).unwrap();
+            assert_eq!(sk.to_bytes().as_slice(), &sk_bytes[..]);
+        }
+    }
+
+    /// Tests that zeroizing a private key replaces it with the scalar 1, which is the closest
+    /// thing to zero that's still a valid key
+    #[test]
+    fn test_privkey_zeroize() {
+        use zeroize::Zeroize;
+
+        let mut csprng = StdRng::from_entropy();
+        let (mut sk, _) = kex_gen_keypair::<DhP256, _>(&mut csprng);
+        sk.zeroize();
+
+        let mut one = [0u8; 32];
+        one[31] = 1;
+        assert_eq!(sk.to_bytes().as_slice(), &one[..]);
+        // It's still usable, so code that touches it after zeroizing doesn't panic
+        let _ = DhP256::sk_to_pk(&sk);
+    }
 }
@@ -10,6 +10,7 @@ use generic_array::{
     GenericArray,
 };
 use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
 
 // We wrap the types in order to abstract away the dalek dep
 
@@ -34,6 +35,13 @@ impl Serializable for PublicKey {
     }
 }
 
+// Returns the same bytes as to_bytes()
+impl AsRef<[u8]> for PublicKey {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_bytes()
+    }
+}
+
 impl Deserializable for PublicKey {
     // Dalek lets us convert [u8; 32] to pubkeys. Assuming the input length is correct, this
     // conversion is infallible, so no ValidationErrors are raised.
@@ -48,6 +56,20 @@ impl Deserializable for PublicKey {
     }
 }
 
+impl PublicKey {
+    /// Deserializes a public key that is already known to be valid, e.g., because it was
+    /// already validated with `from_bytes`. X25519 public keys have no validation beyond a length
+    /// check, so this only saves the length check and the `Result`.
+    ///
+    /// # Panics
+    /// Panics if `encoded` is not exactly 32 bytes long.
+    pub fn from_trusted_bytes(encoded: &[u8]) -> PublicKey {
+        let mut arr = [0u8; 32];
+        arr.copy_from_slice(encoded);
+        PublicKey(x25519_dalek::PublicKey::from(arr))
+    }
+}
+
 impl Serializable for PrivateKey {
     // draft11 §7.1: Nsk of DHKEM(X25519, HKDF-SHA256) is 32
     type OutputSize = typenum::U32;
@@ -57,6 +79,12 @@ impl Serializable for PrivateKey {
         GenericArray::clone_from_slice(&self.0.to_bytes())
     }
 }
+impl Zeroize for PrivateKey {
+    fn zeroize(&mut self) {
+        self.0.zeroize();
+    }
+}
+
 impl Deserializable for PrivateKey {
     // Dalek lets us convert [u8; 32] to scalars. Assuming the input length is correct, this
     // conversion is infallible, so no ValidationErrors are raised.
@@ -134,7 +162,10 @@ impl KeyExchange for X25519 {
     /// ID. The keying material SHOULD have as many bits of entropy as the bit length of a secret
     /// key, i.e., 256.
     #[doc(hidden)]
-    fn derive_keypair<Kdf: KdfTrait>(suite_id: &KemSuiteId, ikm: &[u8]) -> (PrivateKey, PublicKey) {
+    fn derive_keypair<Kdf: KdfTrait>(
+        suite_id: &KemSuiteId,
+        ikm: &[u8],
+    ) -> Result<(PrivateKey, PublicKey), HpkeError> {
         // Write the label into a byte buffer and extract from the IKM
         let (_, hkdf_ctx) = labeled_extract::<Kdf>(&[], suite_id, b"dkp_prk", ikm);
         // The buffer we hold the candidate scalar bytes in. This is the size of a private key.
@@ -146,7 +177,19 @@ impl KeyExchange for X25519 {
         let sk = x25519_dalek::StaticSecret::from(buf);
         let pk = x25519_dalek::PublicKey::from(&sk);
 
-        (PrivateKey(sk), PublicKey(pk))
+        Ok((PrivateKey(sk), PublicKey(pk)))
+    }
+
+    /// Does `derive_keypair`. X25519 does no rejection sampling, so the counter is always 0, and
+    /// this never fails.
+    #[doc(hidden)]
+    #[cfg(feature = "test-internals")]
+    fn derive_keypair_counted<Kdf: KdfTrait>(
+        suite_id: &KemSuiteId,
+        ikm: &[u8],
+    ) -> Result<(PrivateKey, PublicKey, u8), HpkeError> {
+        let (sk, pk) = Self::derive_keypair::<Kdf>(suite_id, ikm)?;
+        Ok((sk, pk, 0))
     }
 }
 
@@ -158,6 +201,7 @@ mod tests {
             Deserializable, KeyExchange, Serializable,
         },
         test_util::kex_gen_keypair,
+        HpkeError,
     };
     use rand::{rngs::StdRng, RngCore, SeedableRng};
 
@@ -217,4 +261,55 @@ mod tests {
         assert!(new_sk == sk, "private key doesn't serialize correctly");
         assert!(new_pk == pk, "public key doesn't serialize correctly");
     }
+
+    /// Tests that from_trusted_bytes on valid bytes gives the same pubkey as from_bytes
+    #[test]
+    fn test_pubkey_from_trusted_bytes() {
+        type Kex = X25519;
+
+        let mut csprng = StdRng::from_entropy();
+        let (_, pk) = kex_gen_keypair::<Kex, _>(&mut csprng);
+        let pk_bytes = pk.to_bytes();
+
+        let checked_pk = <Kex as KeyExchange>::PublicKey::from_bytes(&pk_bytes).unwrap();
+        let trusted_pk = PublicKey::from_trusted_bytes(&pk_bytes);
+        assert!(checked_pk == trusted_pk);
+    }
+
+    /// Tests that from_trusted_bytes panics on a wrong-length input instead of making a pubkey
+    #[test]
+    #[should_panic]
+    fn test_pubkey_from_trusted_bytes_wrong_length() {
+        let _ = PublicKey::from_trusted_bytes(&[9u8; 33]);
+    }
+
+    /// Tests that deserializing a wrong-length input reports both the expected and given lengths
+    #[test]
+    fn test_incorrect_input_length() {
+        type Kex = X25519;
+
+        // One byte short of a valid pubkey or privkey
+        let short_bytes = [0u8; 31];
+
+        match <Kex as KeyExchange>::PublicKey::from_bytes(&short_bytes) {
+            Err(e) => assert_eq!(
+                e,
+                HpkeError::IncorrectInputLength {
+                    expected: 32,
+                    got: 31
+                }
+            ),
+            Ok(_) => panic!("31-byte pubkey was unexpectedly valid"),
+        }
+        match <Kex as KeyExchange>::PrivateKey::from_bytes(&short_bytes) {
+            Err(e) => assert_eq!(
+                e,
+                HpkeError::IncorrectInputLength {
+                    expected: 32,
+                    got: 31
+                }
+            ),
+            Ok(_) => panic!("31-byte privkey was unexpectedly valid"),
+        }
+    }
 }
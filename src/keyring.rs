@@ -0,0 +1,225 @@
+//! Recipient key rotation. A `KeyRing` holds the current recipient private key along with older
+//! ones that haven't been retired yet, so that messages encapsulated to an old public key can
+//! still be opened during a grace period. Every key is tagged with a generation number, which
+//! counts the rotations that came before it.
+
+use crate::{
+    aead::{Aead, AeadTag},
+    kdf::Kdf as KdfTrait,
+    kem::{EncappedKey, Kem as KemTrait},
+    kex::KeyExchange,
+    op_mode::OpModeR,
+    single_shot::try_open_with_keys,
+    HpkeError,
+};
+
+use alloc::vec::Vec;
+
+/// The generation of a key in a `KeyRing`. The first key is generation 0, and every rotation
+/// increments it by 1.
+pub type Generation = u64;
+
+/// A set of recipient private keys, each tagged with its generation
+pub struct KeyRing<Kem: KemTrait> {
+    /// The keys, oldest first
+    sks: Vec<<Kem::Kex as KeyExchange>::PrivateKey>,
+    /// `generations[i]` is the generation of `sks[i]`
+    generations: Vec<Generation>,
+    /// The generation of the newest key
+    newest: Generation,
+}
+
+impl<Kem: KemTrait> KeyRing<Kem> {
+    /// Makes a keyring whose only key is `sk_recip`, at generation 0
+    pub fn new(sk_recip: <Kem::Kex as KeyExchange>::PrivateKey) -> KeyRing<Kem> {
+        KeyRing {
+            sks: vec![sk_recip],
+            generations: vec![0],
+            newest: 0,
+        }
+    }
+
+    /// Adds `sk_recip` as the newest key. Older keys are kept until they're retired.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns the generation of the new key
+    pub fn rotate(&mut self, sk_recip: <Kem::Kex as KeyExchange>::PrivateKey) -> Generation {
+        self.newest += 1;
+        self.sks.push(sk_recip);
+        self.generations.push(self.newest);
+        self.newest
+    }
+
+    /// Removes every key older than `generation`, i.e., ends the grace period for those keys.
+    /// The newest key is never removed.
+    pub fn retire_before(&mut self, generation: Generation) {
+        let generation = generation.min(self.newest);
+        let num_retired = self
+            .generations
+            .iter()
+            .take_while(|&&g| g < generation)
+            .count();
+        self.sks.drain(..num_retired);
+        self.generations.drain(..num_retired);
+    }
+
+    /// Returns the generation of the newest key
+    pub fn newest_generation(&self) -> Generation {
+        self.newest
+    }
+
+    /// Returns the generations of all the keys in this keyring, oldest first
+    pub fn generations(&self) -> &[Generation] {
+        &self.generations
+    }
+
+    /// Does a `single_shot_open` with every key in this keyring, like `try_open_with_keys`. Every
+    /// key is tried, even after one succeeds, so that the running time doesn't reveal which
+    /// generation matched.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok((generation, plaintext))` on success, where `generation` is the generation of
+    /// the key that opened the ciphertext. This is useful for tracking how many senders have
+    /// migrated to the newest key. If no key succeeds, returns `Err(HpkeError::OpenError)`.
+    pub fn open_with_keyring<A, Kdf>(
+        &self,
+        mode: &OpModeR<Kem::Kex>,
+        encapped_key: &EncappedKey<Kem::Kex>,
+        info: &[u8],
+        ciphertext: &[u8],
+        aad: &[u8],
+        tag: &AeadTag<A>,
+    ) -> Result<(Generation, Vec<u8>), HpkeError>
+    where
+        A: Aead,
+        Kdf: KdfTrait,
+    {
+        let (idx, plaintext) = try_open_with_keys::<A, Kdf, Kem>(
+            mode,
+            &self.sks,
+            encapped_key,
+            info,
+            ciphertext,
+            aad,
+            tag,
+        )?;
+        Ok((self.generations[idx], plaintext))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::KeyRing;
+    use crate::{
+        aead::ChaCha20Poly1305,
+        kdf::HkdfSha256,
+        kem::Kem as KemTrait,
+        op_mode::{OpModeR, OpModeS},
+        single_shot::single_shot_seal,
+        HpkeError,
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// Tests that a message to the old key opens under the old generation, a message to the new
+    /// key opens under the new generation, and that retired keys no longer open anything
+    macro_rules! test_keyring_rotation {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let info = b"rotation";
+
+                let (sk_old, pk_old) = Kem::gen_keypair(&mut csprng);
+                let mut keyring = KeyRing::<Kem>::new(sk_old);
+
+                // A legacy sender encrypts to the old key
+                let mut legacy_msg = *b"still on the old key";
+                let (legacy_enc, legacy_tag) = single_shot_seal::<A, Kdf, Kem, _>(
+                    &OpModeS::Base,
+                    &pk_old,
+                    info,
+                    &mut legacy_msg,
+                    b"",
+                    &mut csprng,
+                )
+                .unwrap();
+
+                // Rotate. An up-to-date sender encrypts to the new key
+                let (sk_new, pk_new) = Kem::gen_keypair(&mut csprng);
+                assert_eq!(keyring.rotate(sk_new), 1);
+                assert_eq!(keyring.generations(), &[0, 1]);
+                let mut new_msg = *b"migrated";
+                let (new_enc, new_tag) = single_shot_seal::<A, Kdf, Kem, _>(
+                    &OpModeS::Base,
+                    &pk_new,
+                    info,
+                    &mut new_msg,
+                    b"",
+                    &mut csprng,
+                )
+                .unwrap();
+
+                // Only the old key can open the legacy message, so it's reported as generation 0
+                let (generation, plaintext) = keyring
+                    .open_with_keyring::<A, Kdf>(
+                        &OpModeR::Base,
+                        &legacy_enc,
+                        info,
+                        &legacy_msg,
+                        b"",
+                        &legacy_tag,
+                    )
+                    .unwrap();
+                assert_eq!(generation, 0);
+                assert_eq!(&plaintext, b"still on the old key");
+
+                let (generation, plaintext) = keyring
+                    .open_with_keyring::<A, Kdf>(
+                        &OpModeR::Base,
+                        &new_enc,
+                        info,
+                        &new_msg,
+                        b"",
+                        &new_tag,
+                    )
+                    .unwrap();
+                assert_eq!(generation, 1);
+                assert_eq!(&plaintext, b"migrated");
+
+                // End the grace period. The legacy message doesn't open anymore.
+                keyring.retire_before(1);
+                assert_eq!(keyring.generations(), &[1]);
+                assert_eq!(
+                    keyring
+                        .open_with_keyring::<A, Kdf>(
+                            &OpModeR::Base,
+                            &legacy_enc,
+                            info,
+                            &legacy_msg,
+                            b"",
+                            &legacy_tag,
+                        )
+                        .err(),
+                    Some(HpkeError::OpenError)
+                );
+
+                // The newest key is never retired
+                keyring.retire_before(100);
+                assert_eq!(keyring.generations(), &[1]);
+            }
+        };
+    }
+
+    #[cfg(feature = "x25519-dalek")]
+    test_keyring_rotation!(test_keyring_rotation_x25519, crate::kem::X25519HkdfSha256);
+
+    #[cfg(feature = "p256")]
+    test_keyring_rotation!(test_keyring_rotation_p256, crate::kem::DhP256HkdfSha256);
+}
@@ -0,0 +1,53 @@
+//! Domain separation labels for the key schedule. Every `LabeledExtract` and `LabeledExpand` in
+//! HPKE prepends the version label `"HPKE-v1"` and a `suite_id` of `"HPKE"||kem_id||kdf_id||aead_id`
+//! to its input. A `LabelConfig` swaps both out, so that a protocol which isn't HPKE, but is built
+//! the same way, can reuse this crate's primitives without its keys colliding with HPKE's.
+//!
+//! This is only public with the `experimental-labels` feature. Anything set up with a non-default
+//! `LabelConfig` is **not HPKE**, and won't interoperate with any HPKE implementation.
+
+use crate::{
+    aead::Aead,
+    kdf::{Kdf as KdfTrait, VERSION_LABEL},
+    kem::Kem as KemTrait,
+    util::{full_suite_id, FullSuiteId},
+};
+
+/// The version label and `suite_id` prefix used in the key schedule and in the context it makes,
+/// i.e., for the key, base nonce, exporter secret, and everything derived from the exporter
+/// secret. The KEM is a separate primitive with its own `"KEM"||kem_id` suite ID, and it is not
+/// affected by this.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LabelConfig {
+    /// The version label that starts every labeled KDF input. This is `"HPKE-v1"` in HPKE.
+    pub version_label: &'static [u8],
+    /// The first 4 bytes of `suite_id`. This is `"HPKE"` in HPKE. The algorithm IDs that follow
+    /// it are unchanged.
+    pub suite_id_prefix: [u8; 4],
+}
+
+impl LabelConfig {
+    /// The labels defined in draft11 §5.1. Setting up with these is exactly HPKE.
+    pub const HPKE: LabelConfig = LabelConfig {
+        version_label: VERSION_LABEL,
+        suite_id_prefix: *b"HPKE",
+    };
+
+    /// Constructs the `suite_id` for the given ciphersuite under these labels
+    pub(crate) fn full_suite_id<A, Kdf, Kem>(&self) -> FullSuiteId
+    where
+        A: Aead,
+        Kdf: KdfTrait,
+        Kem: KemTrait,
+    {
+        let mut suite_id = full_suite_id::<A, Kdf, Kem>();
+        suite_id[..4].copy_from_slice(&self.suite_id_prefix);
+        suite_id
+    }
+}
+
+impl Default for LabelConfig {
+    fn default() -> LabelConfig {
+        LabelConfig::HPKE
+    }
+}
@@ -77,9 +77,7 @@
 #[macro_use]
 extern crate std;
 
-#[cfg(not(feature = "std"))]
-#[allow(unused_imports)]
-#[macro_use]
+#[cfg_attr(not(feature = "std"), allow(unused_imports), macro_use)]
 extern crate alloc;
 
 //-------- Testing stuff --------//
@@ -106,28 +104,149 @@ pub use generic_array;
 mod util;
 
 pub mod aead;
+#[cfg(feature = "async")]
+pub mod async_stream;
+pub mod auth_cache;
+pub mod builder;
+#[cfg(feature = "alloc")]
+pub mod combine;
+#[cfg(feature = "test-internals")]
+pub mod diagnose;
+#[cfg(feature = "alloc")]
+pub mod envelope;
+#[cfg(all(feature = "jwk", any(feature = "x25519-dalek", feature = "p256")))]
+mod jwk;
 pub mod kdf;
 pub mod kem;
 pub mod kex;
+#[cfg(feature = "alloc")]
+pub mod keyring;
+mod labels;
+pub mod local;
+#[cfg(all(feature = "serde_impls", feature = "alloc"))]
+pub mod managed;
+#[cfg(feature = "alloc")]
+pub mod manifest;
+#[cfg(all(feature = "serde_impls", feature = "alloc"))]
+pub mod mode_config;
+#[cfg(all(feature = "alloc", any(feature = "x25519-dalek", feature = "p256")))]
+pub mod multi_recipient;
 pub mod op_mode;
+#[cfg(all(feature = "alloc", feature = "x25519-dalek"))]
+pub mod sealed_box;
+pub mod self_test;
 pub mod setup;
 pub mod single_shot;
+#[cfg(feature = "std")]
+pub mod stream;
+pub mod strict;
+pub mod suite;
+#[cfg(feature = "test-internals")]
+pub mod test_vector;
+mod trace;
+pub mod typed;
+#[cfg(feature = "alloc")]
+pub mod versioned;
 
 #[cfg(feature = "serde_impls")]
 mod serde_impls;
 
 #[doc(inline)]
-pub use crate::aead::{AeadCtxR, AeadCtxS};
+pub use crate::aead::{ct_ciphertext_eq, AeadCtxR, AeadCtxS, ContextStats};
+#[doc(inline)]
+pub use auth_cache::AuthSendCache;
+#[doc(inline)]
+pub use builder::{ReceiverBuilder, SenderBuilder, SetupMode};
+#[cfg(feature = "alloc")]
+#[doc(inline)]
+pub use combine::{combine_exporters, ChildContext, ContextDeriver, ExporterContext};
+#[cfg(feature = "test-internals")]
 #[doc(inline)]
-pub use kem::{EncappedKey, Kem};
+pub use diagnose::{diagnose_open_failure, DiagnosisReport, ModeOutcome};
+#[cfg(feature = "alloc")]
+#[doc(inline)]
+pub use envelope::{negotiate_and_open, negotiate_and_seal, Envelope, ENVELOPE_VERSION};
+#[cfg(feature = "alloc")]
+#[doc(inline)]
+pub use kem::{pack_auth_keys, validate_public_keys_batch};
+#[cfg(any(feature = "x25519-dalek", feature = "p256"))]
+#[doc(inline)]
+pub use kem::{public_key_from_tagged_bytes, EchKeyConfig, TaggedPublicKey};
+#[doc(inline)]
+pub use kem::{unpack_auth_keys, EncappedKey, Kem, KemSizes, KeySource, SharedSecret};
 #[doc(inline)]
 pub use kex::{Deserializable, Serializable};
+#[cfg(feature = "alloc")]
+#[doc(inline)]
+pub use keyring::{Generation, KeyRing};
+#[cfg(feature = "experimental-labels")]
+#[doc(inline)]
+pub use labels::LabelConfig;
+#[cfg(all(feature = "serde_impls", feature = "alloc"))]
+#[doc(inline)]
+pub use managed::{KeypairMetadata, ManagedKeypair};
+#[cfg(feature = "std")]
+#[doc(inline)]
+pub use manifest::verify_payload;
+#[cfg(feature = "alloc")]
+#[doc(inline)]
+pub use manifest::{open_manifest, seal_manifest, Manifest};
+#[cfg(all(feature = "serde_impls", feature = "alloc"))]
+#[doc(inline)]
+pub use mode_config::{ReceiverModeConfig, SenderModeConfig};
+#[cfg(all(feature = "alloc", any(feature = "x25519-dalek", feature = "p256")))]
+#[doc(inline)]
+pub use multi_recipient::MultiRecipientMessage;
+#[cfg(feature = "alloc")]
+#[doc(inline)]
+pub use op_mode::PskTable;
 #[doc(inline)]
 pub use op_mode::{OpModeR, OpModeS, PskBundle};
+#[cfg(all(feature = "alloc", feature = "x25519-dalek"))]
+#[doc(inline)]
+pub use sealed_box::{open_box, seal_box};
+#[doc(inline)]
+pub use self_test::{run_self_tests, SelfTestError};
+#[cfg(feature = "raw-shared-secret")]
+#[doc(inline)]
+pub use setup::setup_receiver_from_shared_secret;
+#[doc(inline)]
+pub use setup::{
+    complete_setup, encap_only, recover_receiver, setup_receiver, setup_receiver_auth_export_only,
+    setup_receiver_hashed_info, setup_receiver_with_info, setup_sender,
+    setup_sender_auth_export_only, setup_sender_hashed_info, setup_sender_idempotent,
+    setup_sender_with_info, AuthExporterCtx, EncapSecret, IntoInfo,
+};
+#[cfg(feature = "test-internals")]
+#[doc(hidden)]
+pub use setup::{key_schedule_inputs, setup_sender_debug, KeyScheduleDebug, KeyScheduleInputs};
+#[cfg(feature = "alloc")]
 #[doc(inline)]
-pub use setup::{setup_receiver, setup_sender};
+pub use setup::{setup_receiver_bind_recipient, setup_sender_bind_recipient};
+#[cfg(feature = "experimental-kem-context")]
 #[doc(inline)]
-pub use single_shot::{single_shot_open, single_shot_seal};
+pub use setup::{setup_receiver_with_kem_context, setup_sender_with_kem_context};
+#[cfg(feature = "experimental-labels")]
+#[doc(inline)]
+pub use setup::{setup_receiver_with_labels, setup_sender_with_labels};
+#[cfg(feature = "alloc")]
+#[doc(inline)]
+pub use single_shot::{
+    open_data_key, reencrypt, seal_data_key, single_shot_export_only,
+    single_shot_export_only_receiver, try_open_with_keys,
+};
+#[doc(inline)]
+pub use single_shot::{parse_received, single_shot_open, single_shot_seal, validate_message};
+#[doc(inline)]
+pub use strict::StrictAeadCtx;
+#[cfg(feature = "alloc")]
+#[doc(inline)]
+pub use suite::registry;
+#[doc(inline)]
+pub use suite::{
+    setup_preflight, supported_suites, AeadId, AlgorithmEntry, AlgorithmKind, CiphersuiteId, KdfId,
+    KemId, SuitePreflight, UnregisteredId,
+};
 
 //-------- Top-level types --------//
 
@@ -148,9 +267,44 @@ pub enum HpkeError {
     EncapError,
     /// Decapsulation failed
     DecapError,
-    /// An input isn't the right length. First value is the expected length, second is the given
-    /// length.
-    IncorrectInputLength(usize, usize),
+    /// An input isn't the right length. This is what every `Deserializable::from_bytes` impl
+    /// returns on a length mismatch.
+    IncorrectInputLength {
+        /// The length the input should have been
+        expected: usize,
+        /// The length the input actually was
+        got: usize,
+    },
+    /// A versioned frame has the wrong version. First value is the expected version, second is
+    /// the given version.
+    VersionMismatch(u8, u8),
+    /// A strict context was used after one of its operations failed
+    Poisoned,
+    /// A PSK is too short, or its PSK ID is empty
+    InvalidPsk,
+    /// An operation was cancelled before it finished
+    Cancelled,
+    /// A setup builder is missing a required parameter, or was given parameters that don't match
+    /// its mode. The value says which.
+    InvalidSetupParameters(&'static str),
+    /// The padding of a padded message is malformed, or the padding block size is 0
+    InvalidPadding,
+    /// `seal` or `open` was called on a context whose AEAD is `ExportOnlyAead`
+    ExportOnly,
+    /// `seal_binding_enc` or `open_binding_enc` was called on a context that wasn't set up from
+    /// an encapsulated key, e.g., one made by `complete_setup` or `ContextDeriver`
+    NoEncappedKey,
+    /// A timestamped message is older than the freshness window it was opened with
+    StaleMessage,
+    /// A context reached the seal limit set with `AeadCtxS::with_seal_limit`
+    PolicyLimitReached,
+    /// A sequence number given to `AeadCtxR::open_at_seq` was already used, or was too far ahead
+    SeqOutOfWindow,
+    /// The sender's and recipient's ciphersuite lists given to `negotiate_and_seal` have no usable
+    /// suite in common, or the recipient has no key for the suite in a negotiated envelope
+    NoMutualSuite,
+    /// DeriveKeyPair rejected every candidate private key it made from the input keying material
+    DeriveKeyPairError,
 }
 
 impl core::fmt::Display for HpkeError {
@@ -163,11 +317,85 @@ impl core::fmt::Display for HpkeError {
             HpkeError::ValidationError => write!(f, "Input value is invalid"),
             HpkeError::EncapError => write!(f, "Encapsulation failed"),
             HpkeError::DecapError => write!(f, "Decapsulation failed"),
-            HpkeError::IncorrectInputLength(expected, given) => write!(
+            HpkeError::IncorrectInputLength { expected, got } => write!(
                 f,
                 "Incorrect input length. Expected {} bytes. Got {}.",
+                expected, got
+            ),
+            HpkeError::VersionMismatch(expected, given) => write!(
+                f,
+                "Frame version mismatch. Expected version {}. Got {}.",
                 expected, given
             ),
+            HpkeError::Poisoned => write!(f, "Context is poisoned by an earlier failure"),
+            HpkeError::InvalidPsk => write!(f, "PSK is too short or PSK ID is empty"),
+            HpkeError::Cancelled => write!(f, "Operation was cancelled"),
+            HpkeError::InvalidSetupParameters(reason) => {
+                write!(f, "Invalid setup parameters: {}", reason)
+            }
+            HpkeError::InvalidPadding => write!(f, "Padding is malformed or block size is 0"),
+            HpkeError::ExportOnly => write!(f, "Cannot seal or open with an export-only context"),
+            HpkeError::NoEncappedKey => write!(f, "Context has no encapsulated key to bind to"),
+            HpkeError::StaleMessage => write!(f, "Message is older than the freshness window"),
+            HpkeError::PolicyLimitReached => write!(f, "Seal limit of context reached"),
+            HpkeError::SeqOutOfWindow => write!(f, "Sequence number outside of receive window"),
+            HpkeError::NoMutualSuite => write!(f, "No ciphersuite in common with the peer"),
+            HpkeError::DeriveKeyPairError => write!(f, "Could not derive a keypair from the IKM"),
+        }
+    }
+}
+
+impl HpkeError {
+    /// Returns a numeric code for this error, for reporting errors compactly, e.g., over a
+    /// constrained link. Data carried by a variant is not part of the code. Codes are stable
+    /// across releases within a major version, and are never reused.
+    ///
+    /// | Code | Variant                  |
+    /// |------|--------------------------|
+    /// | 1    | `MessageLimitReached`    |
+    /// | 2    | `OpenError`              |
+    /// | 3    | `SealError`              |
+    /// | 4    | `KdfOutputTooLong`       |
+    /// | 5    | `ValidationError`        |
+    /// | 6    | `EncapError`             |
+    /// | 7    | `DecapError`             |
+    /// | 8    | `IncorrectInputLength`   |
+    /// | 9    | `VersionMismatch`        |
+    /// | 10   | `Poisoned`               |
+    /// | 11   | `InvalidPsk`             |
+    /// | 12   | `Cancelled`              |
+    /// | 13   | `InvalidSetupParameters` |
+    /// | 14   | `InvalidPadding`         |
+    /// | 15   | `ExportOnly`             |
+    /// | 16   | `NoEncappedKey`          |
+    /// | 17   | `StaleMessage`           |
+    /// | 18   | `PolicyLimitReached`     |
+    /// | 19   | `SeqOutOfWindow`         |
+    /// | 20   | `NoMutualSuite`          |
+    /// | 21   | `DeriveKeyPairError`     |
+    pub fn code(&self) -> u16 {
+        match self {
+            HpkeError::MessageLimitReached => 1,
+            HpkeError::OpenError => 2,
+            HpkeError::SealError => 3,
+            HpkeError::KdfOutputTooLong => 4,
+            HpkeError::ValidationError => 5,
+            HpkeError::EncapError => 6,
+            HpkeError::DecapError => 7,
+            HpkeError::IncorrectInputLength { .. } => 8,
+            HpkeError::VersionMismatch(..) => 9,
+            HpkeError::Poisoned => 10,
+            HpkeError::InvalidPsk => 11,
+            HpkeError::Cancelled => 12,
+            HpkeError::InvalidSetupParameters(_) => 13,
+            HpkeError::InvalidPadding => 14,
+            HpkeError::ExportOnly => 15,
+            HpkeError::NoEncappedKey => 16,
+            HpkeError::StaleMessage => 17,
+            HpkeError::PolicyLimitReached => 18,
+            HpkeError::SeqOutOfWindow => 19,
+            HpkeError::NoMutualSuite => 20,
+            HpkeError::DeriveKeyPairError => 21,
         }
     }
 }
@@ -175,3 +403,64 @@ impl core::fmt::Display for HpkeError {
 // An Error type is just something that's Debug and Display
 #[cfg(feature = "std")]
 impl std::error::Error for HpkeError {}
+
+#[cfg(test)]
+mod test {
+    use super::HpkeError;
+
+    /// Tests that every error variant maps to the code in the table in `HpkeError::code`'s doc,
+    /// and that no two variants share a code
+    #[test]
+    fn test_error_codes() {
+        let table = [
+            (HpkeError::MessageLimitReached, 1),
+            (HpkeError::OpenError, 2),
+            (HpkeError::SealError, 3),
+            (HpkeError::KdfOutputTooLong, 4),
+            (HpkeError::ValidationError, 5),
+            (HpkeError::EncapError, 6),
+            (HpkeError::DecapError, 7),
+            (
+                HpkeError::IncorrectInputLength {
+                    expected: 32,
+                    got: 31,
+                },
+                8,
+            ),
+            (HpkeError::VersionMismatch(1, 2), 9),
+            (HpkeError::Poisoned, 10),
+            (HpkeError::InvalidPsk, 11),
+            (HpkeError::Cancelled, 12),
+            (HpkeError::InvalidSetupParameters("missing psk"), 13),
+            (HpkeError::InvalidPadding, 14),
+            (HpkeError::ExportOnly, 15),
+            (HpkeError::NoEncappedKey, 16),
+            (HpkeError::StaleMessage, 17),
+            (HpkeError::PolicyLimitReached, 18),
+            (HpkeError::SeqOutOfWindow, 19),
+            (HpkeError::NoMutualSuite, 20),
+            (HpkeError::DeriveKeyPairError, 21),
+        ];
+
+        for (i, (err, code)) in table.iter().enumerate() {
+            assert_eq!(err.code(), *code, "wrong code for {:?}", err);
+            for (other, _) in &table[i + 1..] {
+                assert_ne!(err.code(), other.code());
+            }
+        }
+
+        // The data carried by a variant doesn't affect its code
+        assert_eq!(
+            HpkeError::IncorrectInputLength {
+                expected: 1,
+                got: 2
+            }
+            .code(),
+            HpkeError::IncorrectInputLength {
+                expected: 3,
+                got: 4
+            }
+            .code()
+        );
+    }
+}
@@ -0,0 +1,124 @@
+//! A wrapper that keeps a context on the thread that made it. `AeadCtxS` and `AeadCtxR` are
+//! `Send`, so that callers who synchronize access themselves can move them between threads. A
+//! `LocalContext` is neither `Send` nor `Sync`, so the compiler rejects any attempt to move it to,
+//! or share it with, another thread. This is purely a compile-time aid. The wrapper derefs to
+//! the underlying context, and `into_inner` gives the context back.
+//!
+//! A `LocalContext` can't be moved into a thread:
+//!
+#![cfg_attr(feature = "x25519", doc = "```compile_fail")]
+#![cfg_attr(not(feature = "x25519"), doc = "```ignore")]
+//! use hpke::{
+//!     aead::ChaCha20Poly1305, kdf::HkdfSha256, kem::X25519HkdfSha256, local::LocalContext,
+//!     setup_sender, Kem, OpModeS,
+//! };
+//! use rand::{rngs::StdRng, SeedableRng};
+//!
+//! let mut csprng = StdRng::from_entropy();
+//! let (_, pk_recip) = X25519HkdfSha256::gen_keypair(&mut csprng);
+//! let (_, ctx) = setup_sender::<ChaCha20Poly1305, HkdfSha256, X25519HkdfSha256, _>(
+//!     &OpModeS::Base,
+//!     &pk_recip,
+//!     b"info",
+//!     &mut csprng,
+//! )
+//! .unwrap();
+//! let mut ctx = LocalContext::new(ctx);
+//!
+//! std::thread::spawn(move || {
+//!     ctx.seal(&mut [], b"").unwrap();
+//! });
+//! ```
+
+use core::{
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+
+/// A context `C`, usually an `AeadCtxS` or `AeadCtxR`, that can't leave the thread it's on. This
+/// derefs to the context.
+pub struct LocalContext<C> {
+    ctx: C,
+    // Raw pointers are neither Send nor Sync, so neither is this
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<C> LocalContext<C> {
+    /// Pins `ctx` to the current thread
+    pub fn new(ctx: C) -> LocalContext<C> {
+        LocalContext {
+            ctx,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Returns the underlying context, which can be moved between threads again
+    pub fn into_inner(self) -> C {
+        self.ctx
+    }
+}
+
+impl<C> Deref for LocalContext<C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.ctx
+    }
+}
+
+impl<C> DerefMut for LocalContext<C> {
+    fn deref_mut(&mut self) -> &mut C {
+        &mut self.ctx
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LocalContext;
+    use crate::{
+        aead::{AeadCtxR, AeadCtxS, ChaCha20Poly1305},
+        kdf::HkdfSha256,
+        test_util::gen_ctx_simple_pair,
+    };
+
+    /// Only compiles if `T` is `Send`
+    fn assert_send<T: Send>() {}
+
+    /// Tests that a `LocalContext` works like the context it wraps, and that the unwrapped
+    /// contexts are still `Send`
+    macro_rules! test_local_context {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+                type Kdf = HkdfSha256;
+                type A = ChaCha20Poly1305;
+
+                assert_send::<AeadCtxS<A, Kdf, Kem>>();
+                assert_send::<AeadCtxR<A, Kdf, Kem>>();
+
+                let (sender_ctx, receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let mut sender_ctx = LocalContext::new(sender_ctx);
+                let mut receiver_ctx = LocalContext::new(receiver_ctx);
+
+                let mut msg = *b"stay here";
+                let tag = sender_ctx.seal(&mut msg, b"").unwrap();
+                receiver_ctx.open(&mut msg, b"", &tag).unwrap();
+                assert_eq!(&msg, b"stay here");
+
+                // Unwrapping keeps the sequence number
+                let mut sender_ctx = sender_ctx.into_inner();
+                let mut receiver_ctx = receiver_ctx.into_inner();
+                let tag = sender_ctx.seal(&mut msg, b"").unwrap();
+                receiver_ctx.open(&mut msg, b"", &tag).unwrap();
+                assert_eq!(sender_ctx.stats().seals, 2);
+            }
+        };
+    }
+
+    #[cfg(feature = "x25519-dalek")]
+    test_local_context!(test_local_context_x25519, crate::kem::X25519HkdfSha256);
+
+    #[cfg(feature = "p256")]
+    test_local_context!(test_local_context_p256, crate::kem::DhP256HkdfSha256);
+}
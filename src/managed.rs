@@ -0,0 +1,361 @@
+//! Keypairs with lifecycle metadata. A `ManagedKeypair` bundles a keypair with its creation time
+//! and an optional label, so that a key store can track rotation without keeping a separate
+//! database. It serializes with serde. By default, only the public metadata is written out. The
+//! private key is only written if `set_serialize_private_key(true)` was called.
+
+use crate::{
+    kem::Kem as KemTrait,
+    kex::{KeyExchange, Serializable},
+};
+
+use alloc::string::String;
+use core::{fmt, marker::PhantomData};
+
+use rand_core::{CryptoRng, RngCore};
+use serde::{
+    de::{self, Error, MapAccess, SeqAccess, Visitor},
+    ser::SerializeStruct,
+    Deserialize as SerdeDeserialize, Deserializer, Serialize as SerdeSerialize, Serializer,
+};
+
+type PrivateKey<Kem> = <<Kem as KemTrait>::Kex as KeyExchange>::PrivateKey;
+type PublicKey<Kem> = <<Kem as KemTrait>::Kex as KeyExchange>::PublicKey;
+
+// The serialized field names, in order. ManagedKeypair writes all four. KeypairMetadata only
+// reads the first three.
+const FIELDS: &[&str] = &["public_key", "created_at", "label", "private_key"];
+
+/// A keypair, along with when it was created and an optional label. The private key is zeroized
+/// on drop, like every `PrivateKey` in this crate.
+pub struct ManagedKeypair<Kem: KemTrait> {
+    sk: PrivateKey<Kem>,
+    pk: PublicKey<Kem>,
+    /// Creation time, in seconds since the UNIX epoch
+    created_at: u64,
+    label: Option<String>,
+    /// Whether `serialize` writes out `sk`
+    serialize_private_key: bool,
+}
+
+/// The public part of a `ManagedKeypair`. This is what a serialized `ManagedKeypair` deserializes
+/// to when it doesn't include the private key.
+pub struct KeypairMetadata<Kem: KemTrait> {
+    /// The public key
+    pub public_key: PublicKey<Kem>,
+    /// Creation time, in seconds since the UNIX epoch
+    pub created_at: u64,
+    /// The label, if one was set
+    pub label: Option<String>,
+}
+
+impl<Kem: KemTrait> ManagedKeypair<Kem> {
+    /// Generates a fresh keypair with `Kem::gen_keypair`. `created_at` is in seconds since the
+    /// UNIX epoch. This crate has no clock, so it's up to the caller to say what time it is.
+    pub fn generate<R: CryptoRng + RngCore>(
+        created_at: u64,
+        label: Option<String>,
+        csprng: &mut R,
+    ) -> ManagedKeypair<Kem> {
+        let (sk, _) = Kem::gen_keypair(csprng);
+        ManagedKeypair::from_private_key(sk, created_at, label)
+    }
+
+    /// Wraps an existing private key. The public key is computed from it.
+    pub fn from_private_key(
+        sk: PrivateKey<Kem>,
+        created_at: u64,
+        label: Option<String>,
+    ) -> ManagedKeypair<Kem> {
+        let pk = Kem::Kex::sk_to_pk(&sk);
+        ManagedKeypair {
+            sk,
+            pk,
+            created_at,
+            label,
+            serialize_private_key: false,
+        }
+    }
+
+    /// Returns the private key
+    pub fn private_key(&self) -> &PrivateKey<Kem> {
+        &self.sk
+    }
+
+    /// Returns the public key
+    pub fn public_key(&self) -> &PublicKey<Kem> {
+        &self.pk
+    }
+
+    /// Returns the creation time, in seconds since the UNIX epoch
+    pub fn created_at(&self) -> u64 {
+        self.created_at
+    }
+
+    /// Returns the label, if one was set
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Sets whether serializing this keypair includes the private key. This is `false` by
+    /// default, in which case the private key is written as null. Only set this when the
+    /// serialized output is going somewhere that is as trusted as the key itself.
+    pub fn set_serialize_private_key(&mut self, serialize_private_key: bool) {
+        self.serialize_private_key = serialize_private_key;
+    }
+
+    /// Returns a copy of the public metadata of this keypair
+    pub fn metadata(&self) -> KeypairMetadata<Kem> {
+        KeypairMetadata {
+            public_key: self.pk.clone(),
+            created_at: self.created_at,
+            label: self.label.clone(),
+        }
+    }
+}
+
+/// Implements `serde::Serialize`. The private key is written as null unless
+/// `set_serialize_private_key(true)` was called.
+impl<Kem: KemTrait> SerdeSerialize for ManagedKeypair<Kem> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let sk = if self.serialize_private_key {
+            Some(&self.sk)
+        } else {
+            None
+        };
+
+        let mut state = serializer.serialize_struct("ManagedKeypair", FIELDS.len())?;
+        state.serialize_field("public_key", &self.pk)?;
+        state.serialize_field("created_at", &self.created_at)?;
+        state.serialize_field("label", &self.label.as_deref())?;
+        state.serialize_field("private_key", &sk)?;
+        state.end()
+    }
+}
+
+/// Implements `serde::Deserialize`. This fails if the private key is missing, or if it doesn't
+/// match the public key. A keypair deserialized this way will serialize its private key again.
+impl<'de, Kem: KemTrait> SerdeDeserialize<'de> for ManagedKeypair<Kem> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let fields: Fields<Kem> = deserializer.deserialize_struct(
+            "ManagedKeypair",
+            FIELDS,
+            FieldsVisitor(PhantomData),
+        )?;
+        let sk = fields
+            .sk
+            .ok_or_else(|| D::Error::custom("ManagedKeypair is missing its private key"))?;
+
+        let mut keypair =
+            ManagedKeypair::<Kem>::from_private_key(sk, fields.created_at, fields.label);
+        if keypair.pk.to_bytes() != fields.pk.to_bytes() {
+            return Err(D::Error::custom(
+                "ManagedKeypair public key does not match its private key",
+            ));
+        }
+        keypair.serialize_private_key = true;
+        Ok(keypair)
+    }
+}
+
+/// Implements `serde::Serialize`, in the same format as a `ManagedKeypair` without its private
+/// key
+impl<Kem: KemTrait> SerdeSerialize for KeypairMetadata<Kem> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("KeypairMetadata", 3)?;
+        state.serialize_field("public_key", &self.public_key)?;
+        state.serialize_field("created_at", &self.created_at)?;
+        state.serialize_field("label", &self.label.as_deref())?;
+        state.end()
+    }
+}
+
+/// Implements `serde::Deserialize`. This accepts a serialized `ManagedKeypair` too, and ignores
+/// its private key.
+impl<'de, Kem: KemTrait> SerdeDeserialize<'de> for KeypairMetadata<Kem> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let fields: Fields<Kem> = deserializer.deserialize_struct(
+            "KeypairMetadata",
+            &FIELDS[..3],
+            FieldsVisitor(PhantomData),
+        )?;
+        Ok(KeypairMetadata {
+            public_key: fields.pk,
+            created_at: fields.created_at,
+            label: fields.label,
+        })
+    }
+}
+
+/// Everything that can be in a serialized `ManagedKeypair` or `KeypairMetadata`
+struct Fields<Kem: KemTrait> {
+    pk: PublicKey<Kem>,
+    created_at: u64,
+    label: Option<String>,
+    sk: Option<PrivateKey<Kem>>,
+}
+
+/// A label. serde's `String` impls need its `alloc` feature, which this crate doesn't enable, so
+/// we copy out of a `&str` ourselves.
+struct Label(String);
+
+impl<'de> SerdeDeserialize<'de> for Label {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct LabelVisitor;
+
+        impl<'de> Visitor<'de> for LabelVisitor {
+            type Value = Label;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a string")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Label, E> {
+                Ok(Label(String::from(v)))
+            }
+        }
+
+        deserializer.deserialize_str(LabelVisitor)
+    }
+}
+
+struct FieldsVisitor<Kem: KemTrait>(PhantomData<Kem>);
+
+impl<'de, Kem: KemTrait> Visitor<'de> for FieldsVisitor<Kem> {
+    type Value = Fields<Kem>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a managed keypair")
+    }
+
+    // Non-self-describing formats give us the fields in order. The private key is last, and it's
+    // only there if the input is a ManagedKeypair.
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Fields<Kem>, A::Error> {
+        let pk = seq
+            .next_element()?
+            .ok_or_else(|| A::Error::invalid_length(0, &self))?;
+        let created_at = seq
+            .next_element()?
+            .ok_or_else(|| A::Error::invalid_length(1, &self))?;
+        let label: Option<Label> = seq
+            .next_element()?
+            .ok_or_else(|| A::Error::invalid_length(2, &self))?;
+        let sk = seq.next_element()?.flatten();
+
+        Ok(Fields {
+            pk,
+            created_at,
+            label: label.map(|l| l.0),
+            sk,
+        })
+    }
+
+    // Self-describing formats give us the fields by name. Unknown fields are ignored.
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Fields<Kem>, A::Error> {
+        let mut pk = None;
+        let mut created_at = None;
+        let mut label: Option<Option<Label>> = None;
+        let mut sk = None;
+
+        while let Some(key) = map.next_key::<Label>()? {
+            match key.0.as_str() {
+                "public_key" => pk = Some(map.next_value()?),
+                "created_at" => created_at = Some(map.next_value()?),
+                "label" => label = Some(map.next_value()?),
+                "private_key" => sk = map.next_value()?,
+                _ => {
+                    map.next_value::<de::IgnoredAny>()?;
+                }
+            }
+        }
+
+        Ok(Fields {
+            pk: pk.ok_or_else(|| A::Error::missing_field("public_key"))?,
+            created_at: created_at.ok_or_else(|| A::Error::missing_field("created_at"))?,
+            label: label.flatten().map(|l| l.0),
+            sk,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{KeypairMetadata, ManagedKeypair};
+    use crate::{kem::Kem as KemTrait, kex::Serializable};
+
+    use alloc::string::String;
+    use rand::{rngs::StdRng, SeedableRng};
+    use serde_json::Value;
+
+    /// Tests that the metadata round-trips, that the private key is left out unless it's asked
+    /// for, and that a keypair only deserializes if its private key is present and matches
+    macro_rules! test_managed_keypair {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let mut keypair = ManagedKeypair::<Kem>::generate(
+                    1_600_000_000,
+                    Some(String::from("signing-2020")),
+                    &mut csprng,
+                );
+
+                // By default, the private key is null, and the metadata round-trips
+                let json = serde_json::to_string(&keypair).unwrap();
+                let value: Value = serde_json::from_str(&json).unwrap();
+                assert!(value["private_key"].is_null());
+                let metadata: KeypairMetadata<Kem> = serde_json::from_str(&json).unwrap();
+                assert_eq!(
+                    metadata.public_key.to_bytes(),
+                    keypair.public_key().to_bytes()
+                );
+                assert_eq!(metadata.created_at, 1_600_000_000);
+                assert_eq!(metadata.label.as_deref(), Some("signing-2020"));
+                // A keypair can't be rebuilt from its metadata
+                assert!(serde_json::from_str::<ManagedKeypair<Kem>>(&json).is_err());
+
+                // Metadata serializes the same way, minus the private key field
+                let metadata_json = serde_json::to_string(&keypair.metadata()).unwrap();
+                let metadata_value: Value = serde_json::from_str(&metadata_json).unwrap();
+                let mut expected = value.clone();
+                expected.as_object_mut().unwrap().remove("private_key");
+                assert_eq!(metadata_value, expected);
+
+                // Once asked for, the private key is included, and the whole keypair round-trips
+                keypair.set_serialize_private_key(true);
+                let json = serde_json::to_string(&keypair).unwrap();
+                let reconstructed: ManagedKeypair<Kem> = serde_json::from_str(&json).unwrap();
+                assert_eq!(
+                    reconstructed.private_key().to_bytes(),
+                    keypair.private_key().to_bytes()
+                );
+                assert_eq!(
+                    reconstructed.public_key().to_bytes(),
+                    keypair.public_key().to_bytes()
+                );
+                assert_eq!(reconstructed.created_at(), 1_600_000_000);
+                assert_eq!(reconstructed.label(), Some("signing-2020"));
+
+                // A public key that doesn't match the private key is rejected
+                let (_, other_pk) = Kem::gen_keypair(&mut csprng);
+                let mut value: Value = serde_json::from_str(&json).unwrap();
+                value["public_key"] = serde_json::to_value(&other_pk).unwrap();
+                assert!(serde_json::from_value::<ManagedKeypair<Kem>>(value).is_err());
+
+                // No label is fine too
+                let keypair = ManagedKeypair::<Kem>::generate(0, None, &mut csprng);
+                let json = serde_json::to_string(&keypair).unwrap();
+                let metadata: KeypairMetadata<Kem> = serde_json::from_str(&json).unwrap();
+                assert_eq!(metadata.label, None);
+                assert_eq!(metadata.created_at, 0);
+            }
+        };
+    }
+
+    #[cfg(feature = "x25519-dalek")]
+    test_managed_keypair!(test_managed_keypair_x25519, crate::kem::X25519HkdfSha256);
+
+    #[cfg(feature = "p256")]
+    test_managed_keypair!(test_managed_keypair_p256, crate::kem::DhP256HkdfSha256);
+}
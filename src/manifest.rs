@@ -0,0 +1,219 @@
+//! Sealed manifests for payloads that are too large to seal in one piece. Instead of encrypting
+//! the payload itself, the sender seals a small manifest holding the SHA-256 hash of the payload
+//! and some metadata, and sends the payload alongside it through whatever integrity layer it
+//! likes. The receiver opens the manifest, then streams the payload through `verify_payload` to
+//! check that it matches the hash.
+//!
+//! The manifest plaintext is `payload_hash || metadata`. It's sealed in `Base` mode with an empty
+//! AAD, so it has the same format as `seal_data_key`.
+//!
+//! Note that the payload is not encrypted by any of this. Its confidentiality, if needed, is up to
+//! the caller.
+
+use crate::{
+    aead::Aead,
+    kdf::Kdf as KdfTrait,
+    kem::{EncappedKey, Kem as KemTrait},
+    kex::KeyExchange,
+    single_shot::{open_data_key, seal_data_key},
+    HpkeError,
+};
+
+use alloc::vec::Vec;
+use rand_core::{CryptoRng, RngCore};
+#[cfg(feature = "std")]
+use std::io::{self, Read};
+
+/// The length of the payload hash in a manifest. This is the output length of SHA-256.
+pub const PAYLOAD_HASH_LEN: usize = 32;
+
+/// An opened manifest
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    /// The SHA-256 hash of the payload
+    pub payload_hash: [u8; PAYLOAD_HASH_LEN],
+    /// The metadata the sender attached to the manifest
+    pub metadata: Vec<u8>,
+}
+
+/// Seals a manifest for the payload with the given SHA-256 hash to the given recipient, in `Base`
+/// mode. See the module documentation for the manifest format.
+///
+/// Return Value
+/// ============
+/// Returns `Ok((encapped_key, sealed_manifest))` on success, where `sealed_manifest` is
+/// `ciphertext || tag`. If an error happened during key encapsulation, returns
+/// `Err(HpkeError::EncapError)`. If an error happened during encryption, returns
+/// `Err(HpkeError::SealError)`.
+pub fn seal_manifest<A, Kdf, Kem, R>(
+    pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
+    info: &[u8],
+    payload_hash: &[u8; PAYLOAD_HASH_LEN],
+    metadata: &[u8],
+    csprng: &mut R,
+) -> Result<(EncappedKey<Kem::Kex>, Vec<u8>), HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    let mut plaintext = Vec::with_capacity(PAYLOAD_HASH_LEN + metadata.len());
+    plaintext.extend_from_slice(payload_hash);
+    plaintext.extend_from_slice(metadata);
+    seal_data_key::<A, Kdf, Kem, R>(pk_recip, info, &plaintext, csprng)
+}
+
+/// Opens a manifest sealed by `seal_manifest`. This checks the manifest, but not the payload. Use
+/// `verify_payload` for that.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(manifest)` on success. If `sealed_manifest` is shorter than a tag, returns
+/// `Err(HpkeError::IncorrectInputLength { expected: tag_len, got: sealed_manifest.len() })`. If an
+/// error happened during key decapsulation, returns `Err(HpkeError::DecapError)`. If an error
+/// happened during decryption, returns `Err(HpkeError::OpenError)`. If the opened manifest is too
+/// short to hold a payload hash, returns
+/// `Err(HpkeError::IncorrectInputLength { expected: PAYLOAD_HASH_LEN, got: len })`.
+pub fn open_manifest<A, Kdf, Kem>(
+    sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
+    encapped_key: &EncappedKey<Kem::Kex>,
+    info: &[u8],
+    sealed_manifest: &[u8],
+) -> Result<Manifest, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    let mut plaintext =
+        open_data_key::<A, Kdf, Kem>(sk_recip, encapped_key, info, sealed_manifest)?;
+    if plaintext.len() < PAYLOAD_HASH_LEN {
+        return Err(HpkeError::IncorrectInputLength {
+            expected: PAYLOAD_HASH_LEN,
+            got: plaintext.len(),
+        });
+    }
+
+    let metadata = plaintext.split_off(PAYLOAD_HASH_LEN);
+    let mut payload_hash = [0u8; PAYLOAD_HASH_LEN];
+    payload_hash.copy_from_slice(&plaintext);
+    Ok(Manifest {
+        payload_hash,
+        metadata,
+    })
+}
+
+/// Reads all of `payload_reader`, hashes it with SHA-256, and checks the hash against the one in
+/// `manifest`. The payload is never buffered in full. This is gated under the `std` feature.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(())` if the payload matches the manifest. If the hashes differ, returns an
+/// `io::Error` of kind `InvalidData` wrapping `HpkeError::ValidationError`. If reading fails,
+/// returns the reader's error.
+#[cfg(feature = "std")]
+pub fn verify_payload<Rd: Read>(manifest: &Manifest, mut payload_reader: Rd) -> io::Result<()> {
+    use sha2::{Digest, Sha256};
+    use subtle::ConstantTimeEq;
+
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        match payload_reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => hasher.update(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    let computed_hash = hasher.finalize();
+    if bool::from(computed_hash.ct_eq(&manifest.payload_hash)) {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            HpkeError::ValidationError,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{open_manifest, seal_manifest, PAYLOAD_HASH_LEN};
+    use crate::{aead::ChaCha20Poly1305, kdf::HkdfSha256, kem::Kem as KemTrait, HpkeError};
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// Tests that a manifest round-trips, and that a payload which doesn't match the manifest
+    /// fails verification even though the manifest itself opens fine
+    macro_rules! test_manifest {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let info = b"manifest test";
+                let payload = vec![0x5au8; 10_000];
+                let metadata = b"content-type: application/octet-stream";
+
+                let payload_hash: [u8; PAYLOAD_HASH_LEN] = {
+                    use sha2::{Digest, Sha256};
+                    Sha256::digest(&payload).into()
+                };
+                let (encapped_key, sealed_manifest) = seal_manifest::<A, Kdf, Kem, _>(
+                    &pk_recip,
+                    info,
+                    &payload_hash,
+                    metadata,
+                    &mut csprng,
+                )
+                .unwrap();
+
+                let manifest =
+                    open_manifest::<A, Kdf, Kem>(&sk_recip, &encapped_key, info, &sealed_manifest)
+                        .unwrap();
+                assert_eq!(manifest.payload_hash, payload_hash);
+                assert_eq!(manifest.metadata, metadata.to_vec());
+
+                #[cfg(feature = "std")]
+                {
+                    use super::verify_payload;
+
+                    verify_payload(&manifest, payload.as_slice()).unwrap();
+
+                    let mut tampered_payload = payload.clone();
+                    tampered_payload[1234] ^= 1;
+                    let err = verify_payload(&manifest, tampered_payload.as_slice()).unwrap_err();
+                    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+                    let truncated_payload = &payload[..payload.len() - 1];
+                    assert!(verify_payload(&manifest, truncated_payload).is_err());
+                }
+
+                // A tampered manifest doesn't open
+                let mut tampered_manifest = sealed_manifest.clone();
+                tampered_manifest[0] ^= 1;
+                assert_eq!(
+                    open_manifest::<A, Kdf, Kem>(
+                        &sk_recip,
+                        &encapped_key,
+                        info,
+                        &tampered_manifest
+                    ),
+                    Err(HpkeError::OpenError)
+                );
+            }
+        };
+    }
+
+    #[cfg(feature = "x25519-dalek")]
+    test_manifest!(test_manifest_x25519, crate::kem::X25519HkdfSha256);
+
+    #[cfg(feature = "p256")]
+    test_manifest!(test_manifest_p256, crate::kem::DhP256HkdfSha256);
+}
@@ -0,0 +1,579 @@
+//! Op-modes that can be stored. `OpModeS` and `OpModeR` borrow their PSKs, so they can't be
+//! deserialized into. A `SenderModeConfig` or `ReceiverModeConfig` owns everything an op-mode
+//! holds, serializes with serde, and lends out an `OpModeS` or `OpModeR` on demand. This is
+//! gated under the `serde_impls` and `alloc` features.
+//!
+//! A serialized config has the fields `mode`, which is one of `"base"`, `"psk"`, `"auth"`, and
+//! `"auth_psk"`, then `psk`, `psk_id`, and `pk_sender_id`, each of which is null if the mode
+//! doesn't use it. A sender config also has `sk_sender_id`, which is null unless
+//! `set_serialize_private_key(true)` was called. The PSK is always included, so a serialized config
+//! in a PSK mode is as secret as the PSK.
+
+use crate::{
+    builder::SetupMode,
+    kex::{KeyExchange, Serializable},
+    op_mode::{OpModeR, OpModeS, PskBundle},
+};
+
+use alloc::{string::String, vec::Vec};
+use core::{fmt, marker::PhantomData};
+
+use serde::{
+    de::{self, Error, MapAccess, SeqAccess, Visitor},
+    ser::SerializeStruct,
+    Deserialize as SerdeDeserialize, Deserializer, Serialize as SerdeSerialize, Serializer,
+};
+use zeroize::Zeroizing;
+
+// The serialized field names, in order. SenderModeConfig writes all five. ReceiverModeConfig
+// writes the first four.
+const FIELDS: &[&str] = &["mode", "psk", "psk_id", "pk_sender_id", "sk_sender_id"];
+
+/// An owned PSK and PSK ID. Both are zeroized on drop.
+struct OwnedPsk {
+    psk: Zeroizing<Vec<u8>>,
+    psk_id: Zeroizing<Vec<u8>>,
+}
+
+impl OwnedPsk {
+    fn from_bundle(bundle: &PskBundle) -> OwnedPsk {
+        OwnedPsk {
+            psk: Zeroizing::new(bundle.psk.to_vec()),
+            psk_id: Zeroizing::new(bundle.psk_id.to_vec()),
+        }
+    }
+
+    fn bundle(&self) -> PskBundle<'_> {
+        PskBundle {
+            psk: &self.psk,
+            psk_id: &self.psk_id,
+        }
+    }
+}
+
+/// The op-mode a receiver is set up with, in a form that can be stored
+pub struct ReceiverModeConfig<Kex: KeyExchange> {
+    mode: SetupMode,
+    psk: Option<OwnedPsk>,
+    pk_sender_id: Option<Kex::PublicKey>,
+}
+
+impl<Kex: KeyExchange> ReceiverModeConfig<Kex> {
+    /// Copies everything in `op_mode` into a new config
+    pub fn from_op_mode(op_mode: &OpModeR<Kex>) -> ReceiverModeConfig<Kex> {
+        let (mode, psk) = match op_mode {
+            OpModeR::Base => (SetupMode::Base, None),
+            OpModeR::Psk(psk) => (SetupMode::Psk, Some(psk)),
+            OpModeR::Auth(_) => (SetupMode::Auth, None),
+            OpModeR::AuthPsk(_, psk) => (SetupMode::AuthPsk, Some(psk)),
+        };
+        ReceiverModeConfig {
+            mode,
+            psk: psk.map(OwnedPsk::from_bundle),
+            pk_sender_id: op_mode.get_pk_sender_id().cloned(),
+        }
+    }
+
+    /// Returns the mode of this config
+    pub fn mode(&self) -> SetupMode {
+        self.mode
+    }
+
+    /// Returns the op-mode this config describes, borrowing the PSK from it
+    pub fn op_mode(&self) -> OpModeR<'_, Kex> {
+        // Construction and deserialization both make sure the PSK and sender key are there iff
+        // the mode needs them, so the unwraps can't panic
+        match self.mode {
+            SetupMode::Base => OpModeR::Base,
+            SetupMode::Psk => OpModeR::Psk(self.psk.as_ref().unwrap().bundle()),
+            SetupMode::Auth => OpModeR::Auth(self.pk_sender_id.clone().unwrap()),
+            SetupMode::AuthPsk => OpModeR::AuthPsk(
+                self.pk_sender_id.clone().unwrap(),
+                self.psk.as_ref().unwrap().bundle(),
+            ),
+        }
+    }
+}
+
+/// The op-mode a sender is set up with, in a form that can be stored. The sender's identity
+/// private key is zeroized on drop, like every `PrivateKey` in this crate.
+pub struct SenderModeConfig<Kex: KeyExchange> {
+    mode: SetupMode,
+    psk: Option<OwnedPsk>,
+    sender_id_keypair: Option<(Kex::PrivateKey, Kex::PublicKey)>,
+    /// Whether `serialize` writes out the sender's identity private key
+    serialize_private_key: bool,
+}
+
+impl<Kex: KeyExchange> SenderModeConfig<Kex> {
+    /// Copies everything in `op_mode` into a new config
+    pub fn from_op_mode(op_mode: &OpModeS<Kex>) -> SenderModeConfig<Kex> {
+        let (mode, psk) = match op_mode {
+            OpModeS::Base => (SetupMode::Base, None),
+            OpModeS::Psk(psk) => (SetupMode::Psk, Some(psk)),
+            OpModeS::Auth(_) | OpModeS::AuthBorrowed(_) => (SetupMode::Auth, None),
+            OpModeS::AuthPsk(_, psk) | OpModeS::AuthPskBorrowed(_, psk) => {
+                (SetupMode::AuthPsk, Some(psk))
+            }
+        };
+        SenderModeConfig {
+            mode,
+            psk: psk.map(OwnedPsk::from_bundle),
+            sender_id_keypair: op_mode
+                .get_sender_id_keypair()
+                .map(|(sk, pk)| (sk.clone(), pk.clone())),
+            serialize_private_key: false,
+        }
+    }
+
+    /// Returns the mode of this config
+    pub fn mode(&self) -> SetupMode {
+        self.mode
+    }
+
+    /// Returns the op-mode this config describes, borrowing the PSK and sender identity keypair
+    /// from it
+    pub fn op_mode(&self) -> OpModeS<'_, Kex> {
+        // Construction and deserialization both make sure the PSK and keypair are there iff the
+        // mode needs them, so the unwraps can't panic
+        let keypair = || {
+            let (sk, pk) = self.sender_id_keypair.as_ref().unwrap();
+            (sk, pk)
+        };
+        match self.mode {
+            SetupMode::Base => OpModeS::Base,
+            SetupMode::Psk => OpModeS::Psk(self.psk.as_ref().unwrap().bundle()),
+            SetupMode::Auth => OpModeS::AuthBorrowed(keypair()),
+            SetupMode::AuthPsk => {
+                OpModeS::AuthPskBorrowed(keypair(), self.psk.as_ref().unwrap().bundle())
+            }
+        }
+    }
+
+    /// Sets whether serializing this config includes the sender's identity private key. This is
+    /// `false` by default, in which case the private key is written as null, and the result can't
+    /// be deserialized into a `SenderModeConfig` in an authenticated mode. Only set this when the
+    /// serialized output is going somewhere that is as trusted as the key itself.
+    pub fn set_serialize_private_key(&mut self, serialize_private_key: bool) {
+        self.serialize_private_key = serialize_private_key;
+    }
+}
+
+/// Returns the serialized name of `mode`
+fn mode_name(mode: SetupMode) -> &'static str {
+    match mode {
+        SetupMode::Base => "base",
+        SetupMode::Psk => "psk",
+        SetupMode::Auth => "auth",
+        SetupMode::AuthPsk => "auth_psk",
+    }
+}
+
+/// Serializes the fields that both configs have
+fn serialize_common<S: SerializeStruct, Kex: KeyExchange>(
+    state: &mut S,
+    mode: SetupMode,
+    psk: &Option<OwnedPsk>,
+    pk_sender_id: Option<&Kex::PublicKey>,
+) -> Result<(), S::Error> {
+    state.serialize_field("mode", mode_name(mode))?;
+    state.serialize_field("psk", &psk.as_ref().map(|p| Bytes(&p.psk)))?;
+    state.serialize_field("psk_id", &psk.as_ref().map(|p| Bytes(&p.psk_id)))?;
+    state.serialize_field("pk_sender_id", &pk_sender_id)
+}
+
+/// Implements `serde::Serialize`. See the module documentation for the format.
+impl<Kex: KeyExchange> SerdeSerialize for ReceiverModeConfig<Kex> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("ReceiverModeConfig", 4)?;
+        serialize_common::<_, Kex>(&mut state, self.mode, &self.psk, self.pk_sender_id.as_ref())?;
+        state.end()
+    }
+}
+
+/// Implements `serde::Serialize`. See the module documentation for the format. The private key is
+/// written as null unless `set_serialize_private_key(true)` was called.
+impl<Kex: KeyExchange> SerdeSerialize for SenderModeConfig<Kex> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let pk = self.sender_id_keypair.as_ref().map(|(_, pk)| pk);
+        let sk = self
+            .sender_id_keypair
+            .as_ref()
+            .filter(|_| self.serialize_private_key)
+            .map(|(sk, _)| sk);
+
+        let mut state = serializer.serialize_struct("SenderModeConfig", FIELDS.len())?;
+        serialize_common::<_, Kex>(&mut state, self.mode, &self.psk, pk)?;
+        state.serialize_field("sk_sender_id", &sk)?;
+        state.end()
+    }
+}
+
+/// Implements `serde::Deserialize`. This fails if the PSK or sender public key is missing when the
+/// mode needs it, or present when it doesn't. A sender private key is ignored.
+impl<'de, Kex: KeyExchange> SerdeDeserialize<'de> for ReceiverModeConfig<Kex> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut fields: Fields<Kex> = deserializer.deserialize_struct(
+            "ReceiverModeConfig",
+            FIELDS,
+            FieldsVisitor(PhantomData),
+        )?;
+        let psk = fields.psk::<D::Error>()?;
+        fields
+            .mode
+            .check(psk.is_some(), fields.pk_sender_id.is_some())
+            .map_err(D::Error::custom)?;
+
+        Ok(ReceiverModeConfig {
+            mode: fields.mode,
+            psk,
+            pk_sender_id: fields.pk_sender_id,
+        })
+    }
+}
+
+/// Implements `serde::Deserialize`. This fails if the PSK or sender keypair is missing when the
+/// mode needs it, or present when it doesn't, and if the sender private key doesn't match the
+/// public key. A config deserialized with a private key will serialize it again.
+impl<'de, Kex: KeyExchange> SerdeDeserialize<'de> for SenderModeConfig<Kex> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut fields: Fields<Kex> = deserializer.deserialize_struct(
+            "SenderModeConfig",
+            FIELDS,
+            FieldsVisitor(PhantomData),
+        )?;
+        let psk = fields.psk::<D::Error>()?;
+        let sender_id_keypair = match (fields.sk_sender_id.take(), fields.pk_sender_id.take()) {
+            (Some(sk), Some(pk)) => {
+                if Kex::sk_to_pk(&sk).to_bytes() != pk.to_bytes() {
+                    return Err(D::Error::custom(
+                        "sender public key does not match its private key",
+                    ));
+                }
+                Some((sk, pk))
+            }
+            (None, Some(_)) => {
+                return Err(D::Error::custom("sender private key is missing"));
+            }
+            (Some(_), None) => {
+                return Err(D::Error::custom("sender public key is missing"));
+            }
+            (None, None) => None,
+        };
+        fields
+            .mode
+            .check(psk.is_some(), sender_id_keypair.is_some())
+            .map_err(D::Error::custom)?;
+
+        Ok(SenderModeConfig {
+            mode: fields.mode,
+            psk,
+            serialize_private_key: sender_id_keypair.is_some(),
+            sender_id_keypair,
+        })
+    }
+}
+
+/// A byte string. serde's `Vec<u8>` impls need its `alloc` feature, which this crate doesn't
+/// enable, so we do the copying ourselves.
+struct Bytes<'a>(&'a [u8]);
+
+impl<'a> SerdeSerialize for Bytes<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+/// The owned counterpart of `Bytes`. This holds PSKs, so it's zeroized on drop, including when
+/// deserialization fails partway through.
+struct ByteBuf(Zeroizing<Vec<u8>>);
+
+impl<'de> SerdeDeserialize<'de> for ByteBuf {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ByteBufVisitor;
+
+        impl<'de> Visitor<'de> for ByteBufVisitor {
+            type Value = ByteBuf;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a byte string")
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<ByteBuf, E> {
+                Ok(ByteBuf(Zeroizing::new(v.to_vec())))
+            }
+
+            // Formats without a byte string type, like JSON, write bytes as a sequence. Letting
+            // push() grow the buffer would free the old allocations without zeroizing them, so we
+            // grow it by hand and let the old buffer zeroize itself.
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<ByteBuf, A::Error> {
+                let mut buf = Zeroizing::new(Vec::with_capacity(seq.size_hint().unwrap_or(0)));
+                while let Some(b) = seq.next_element()? {
+                    if buf.len() == buf.capacity() {
+                        let mut bigger = Zeroizing::new(Vec::with_capacity(2 * buf.len() + 32));
+                        bigger.extend_from_slice(&buf);
+                        buf = bigger;
+                    }
+                    buf.push(b);
+                }
+                Ok(ByteBuf(buf))
+            }
+        }
+
+        deserializer.deserialize_bytes(ByteBufVisitor)
+    }
+}
+
+/// A string. serde's `String` impls need its `alloc` feature too.
+struct Name(String);
+
+impl<'de> SerdeDeserialize<'de> for Name {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct NameVisitor;
+
+        impl<'de> Visitor<'de> for NameVisitor {
+            type Value = Name;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "a string")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Name, E> {
+                Ok(Name(String::from(v)))
+            }
+        }
+
+        deserializer.deserialize_str(NameVisitor)
+    }
+}
+
+/// Everything that can be in a serialized `SenderModeConfig` or `ReceiverModeConfig`
+struct Fields<Kex: KeyExchange> {
+    mode: SetupMode,
+    psk: Option<ByteBuf>,
+    psk_id: Option<ByteBuf>,
+    pk_sender_id: Option<Kex::PublicKey>,
+    sk_sender_id: Option<Kex::PrivateKey>,
+}
+
+impl<Kex: KeyExchange> Fields<Kex> {
+    /// Takes the PSK and PSK ID, which must be both present or both absent, and must make a valid
+    /// `PskBundle`
+    fn psk<E: de::Error>(&mut self) -> Result<Option<OwnedPsk>, E> {
+        match (self.psk.take(), self.psk_id.take()) {
+            (Some(psk), Some(psk_id)) => {
+                PskBundle::new(&psk.0, &psk_id.0).map_err(E::custom)?;
+                Ok(Some(OwnedPsk {
+                    psk: psk.0,
+                    psk_id: psk_id.0,
+                }))
+            }
+            (None, None) => Ok(None),
+            (Some(_), None) => Err(E::custom("PSK is missing its PSK ID")),
+            (None, Some(_)) => Err(E::custom("PSK ID is missing its PSK")),
+        }
+    }
+}
+
+/// Parses a serialized mode name
+fn parse_mode<E: de::Error>(name: &str) -> Result<SetupMode, E> {
+    match name {
+        "base" => Ok(SetupMode::Base),
+        "psk" => Ok(SetupMode::Psk),
+        "auth" => Ok(SetupMode::Auth),
+        "auth_psk" => Ok(SetupMode::AuthPsk),
+        _ => Err(E::unknown_variant(
+            name,
+            &["base", "psk", "auth", "auth_psk"],
+        )),
+    }
+}
+
+struct FieldsVisitor<Kex: KeyExchange>(PhantomData<Kex>);
+
+impl<'de, Kex: KeyExchange> Visitor<'de> for FieldsVisitor<Kex> {
+    type Value = Fields<Kex>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an op-mode config")
+    }
+
+    // Non-self-describing formats give us the fields in order. The private key is last, and it's
+    // only there if the input is a SenderModeConfig.
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Fields<Kex>, A::Error> {
+        let mode: Name = seq
+            .next_element()?
+            .ok_or_else(|| A::Error::invalid_length(0, &self))?;
+        let psk = seq
+            .next_element()?
+            .ok_or_else(|| A::Error::invalid_length(1, &self))?;
+        let psk_id = seq
+            .next_element()?
+            .ok_or_else(|| A::Error::invalid_length(2, &self))?;
+        let pk_sender_id = seq
+            .next_element()?
+            .ok_or_else(|| A::Error::invalid_length(3, &self))?;
+        let sk_sender_id = seq.next_element()?.flatten();
+
+        Ok(Fields {
+            mode: parse_mode(&mode.0)?,
+            psk,
+            psk_id,
+            pk_sender_id,
+            sk_sender_id,
+        })
+    }
+
+    // Self-describing formats give us the fields by name. Unknown fields are ignored.
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Fields<Kex>, A::Error> {
+        let mut mode = None;
+        let mut psk = None;
+        let mut psk_id = None;
+        let mut pk_sender_id = None;
+        let mut sk_sender_id = None;
+
+        while let Some(key) = map.next_key::<Name>()? {
+            match key.0.as_str() {
+                "mode" => mode = Some(parse_mode(&map.next_value::<Name>()?.0)?),
+                "psk" => psk = map.next_value()?,
+                "psk_id" => psk_id = map.next_value()?,
+                "pk_sender_id" => pk_sender_id = map.next_value()?,
+                "sk_sender_id" => sk_sender_id = map.next_value()?,
+                _ => {
+                    map.next_value::<de::IgnoredAny>()?;
+                }
+            }
+        }
+
+        Ok(Fields {
+            mode: mode.ok_or_else(|| A::Error::missing_field("mode"))?,
+            psk,
+            psk_id,
+            pk_sender_id,
+            sk_sender_id,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ReceiverModeConfig, SenderModeConfig};
+    use crate::{
+        aead::ChaCha20Poly1305,
+        builder::SetupMode,
+        kdf::HkdfSha256,
+        kem::Kem as KemTrait,
+        op_mode::{OpModeR, OpModeS, PskBundle, MIN_PSK_LEN},
+        setup::{setup_receiver, setup_sender},
+        test_util::gen_rand_buf,
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+    use serde_json::Value;
+
+    /// Tests that PSK and auth mode configs round-trip through JSON and still set up contexts that
+    /// talk to each other, that the sender private key is only written when asked for, and that
+    /// inconsistent configs are rejected
+    macro_rules! test_mode_config {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+                type Kex = <Kem as KemTrait>::Kex;
+
+                let mut csprng = StdRng::from_entropy();
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let (sk_sender_id, pk_sender_id) = Kem::gen_keypair(&mut csprng);
+                let (psk, psk_id) = (gen_rand_buf(), gen_rand_buf());
+                let psk_bundle = PskBundle {
+                    psk: &psk,
+                    psk_id: &psk_id,
+                };
+
+                // Returns whether the given modes set up contexts that talk to each other
+                let mut linked = |sender_mode: &OpModeS<Kex>, receiver_mode: &OpModeR<Kex>| {
+                    let (encapped_key, mut sender_ctx) =
+                        setup_sender::<A, Kdf, Kem, _>(sender_mode, &pk_recip, b"", &mut csprng)
+                            .unwrap();
+                    let mut receiver_ctx =
+                        setup_receiver::<A, Kdf, Kem>(receiver_mode, &sk_recip, &encapped_key, b"")
+                            .unwrap();
+                    let mut msg = *b"configured";
+                    let tag = sender_ctx.seal(&mut msg, b"").unwrap();
+                    receiver_ctx.open(&mut msg, b"", &tag).is_ok()
+                };
+
+                // PSK mode round-trips on both sides
+                let sender_json = serde_json::to_string(&SenderModeConfig::from_op_mode(
+                    &OpModeS::<Kex>::Psk(psk_bundle),
+                ))
+                .unwrap();
+                let receiver_json = serde_json::to_string(&ReceiverModeConfig::from_op_mode(
+                    &OpModeR::<Kex>::Psk(psk_bundle),
+                ))
+                .unwrap();
+                let sender_config: SenderModeConfig<Kex> =
+                    serde_json::from_str(&sender_json).unwrap();
+                let receiver_config: ReceiverModeConfig<Kex> =
+                    serde_json::from_str(&receiver_json).unwrap();
+                assert_eq!(sender_config.mode(), SetupMode::Psk);
+                assert_eq!(receiver_config.mode(), SetupMode::Psk);
+                assert!(linked(&sender_config.op_mode(), &receiver_config.op_mode()));
+
+                // In auth mode, the sender private key is left out by default, and then the sender
+                // config doesn't deserialize
+                let mut sender_config = SenderModeConfig::from_op_mode(&OpModeS::<Kex>::Auth((
+                    sk_sender_id.clone(),
+                    pk_sender_id.clone(),
+                )));
+                let json = serde_json::to_string(&sender_config).unwrap();
+                let value: Value = serde_json::from_str(&json).unwrap();
+                assert_eq!(value["mode"], "auth");
+                assert!(value["psk"].is_null());
+                assert!(value["sk_sender_id"].is_null());
+                assert!(serde_json::from_str::<SenderModeConfig<Kex>>(&json).is_err());
+                // It's a fine receiver config though
+                let receiver_config: ReceiverModeConfig<Kex> = serde_json::from_str(&json).unwrap();
+                assert_eq!(receiver_config.mode(), SetupMode::Auth);
+
+                sender_config.set_serialize_private_key(true);
+                let json = serde_json::to_string(&sender_config).unwrap();
+                let sender_config: SenderModeConfig<Kex> = serde_json::from_str(&json).unwrap();
+                assert!(linked(&sender_config.op_mode(), &receiver_config.op_mode()));
+                // A deserialized private key is written out again
+                assert_eq!(serde_json::to_string(&sender_config).unwrap(), json);
+
+                // A sender key that doesn't match is rejected
+                let (_, other_pk) = Kem::gen_keypair(&mut csprng);
+                let mut value: Value = serde_json::from_str(&json).unwrap();
+                value["pk_sender_id"] = serde_json::to_value(&other_pk).unwrap();
+                assert!(serde_json::from_value::<SenderModeConfig<Kex>>(value).is_err());
+
+                // So is a mode that doesn't match the keys, or a mode that doesn't exist
+                let mut value: Value = serde_json::from_str(&receiver_json).unwrap();
+                value["mode"] = "auth_psk".into();
+                assert!(serde_json::from_value::<ReceiverModeConfig<Kex>>(value.clone()).is_err());
+                value["mode"] = "base".into();
+                assert!(serde_json::from_value::<ReceiverModeConfig<Kex>>(value.clone()).is_err());
+                value["mode"] = "psk_auth".into();
+                assert!(serde_json::from_value::<ReceiverModeConfig<Kex>>(value.clone()).is_err());
+                // Or a PSK that's too short
+                value["mode"] = "psk".into();
+                value["psk"] = serde_json::to_value(&psk[..MIN_PSK_LEN - 1]).unwrap();
+                assert!(serde_json::from_value::<ReceiverModeConfig<Kex>>(value.clone()).is_err());
+                // Or a PSK without its ID
+                value["psk"] = serde_json::to_value(&psk[..]).unwrap();
+                value["psk_id"] = Value::Null;
+                assert!(serde_json::from_value::<ReceiverModeConfig<Kex>>(value).is_err());
+            }
+        };
+    }
+
+    #[cfg(feature = "x25519-dalek")]
+    test_mode_config!(test_mode_config_x25519, crate::kem::X25519HkdfSha256);
+
+    #[cfg(feature = "p256")]
+    test_mode_config!(test_mode_config_p256, crate::kem::DhP256HkdfSha256);
+}
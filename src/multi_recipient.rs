@@ -0,0 +1,305 @@
+//! A message format for sending one plaintext to several recipients. The plaintext is encrypted
+//! once, under a fresh data key, and the data key is wrapped for each recipient with
+//! `seal_data_key`. A message is
+//!
+//! ```text
+//! n_recipients || (recipient_tag || enc || wrapped_key)* || ciphertext || tag
+//! ```
+//!
+//! where `n_recipients` is a 2-byte big-endian integer, and `recipient_tag` is the 8-byte
+//! `key_id` of the recipient's public key, so a recipient can find their entry without trying
+//! every one. `enc || wrapped_key` is the output of `seal_data_key` with an empty info string.
+//! `ciphertext || tag` is the plaintext encrypted with the data key, under the same AEAD, with an
+//! all-zero nonce and everything before it as the AAD. The zero nonce is fine, since every data
+//! key only ever encrypts one message. This is gated under the `alloc` feature.
+//!
+//! Note that every recipient learns the data key, so any recipient can make a new message that
+//! the others will accept. This is confidentiality, not authentication.
+
+use crate::{
+    aead::{Aead, AeadTag},
+    kdf::Kdf as KdfTrait,
+    kem::{key_id, EncappedKey, Kem as KemTrait},
+    kex::{Deserializable, KeyExchange, Serializable},
+    single_shot::{open_data_key, seal_data_key},
+    HpkeError,
+};
+
+use aead::{AeadCore, AeadInPlace, NewAead};
+use alloc::vec::Vec;
+use byteorder::{BigEndian, ByteOrder};
+use generic_array::{typenum::Unsigned, GenericArray};
+use rand_core::{CryptoRng, RngCore};
+use zeroize::Zeroize;
+
+/// The length in bytes of a recipient tag
+pub const RECIPIENT_TAG_LEN: usize = 8;
+
+/// Seals and opens multi-recipient messages. See the module documentation for the format.
+pub struct MultiRecipientMessage;
+
+impl MultiRecipientMessage {
+    /// Encrypts `plaintext` once, and wraps the data key for every public key in `pks_recip`.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(message)` on success. If `pks_recip` is empty or has more than `u16::MAX`
+    /// keys, returns `Err(HpkeError::InvalidSetupParameters(..))`. Otherwise, errors are the
+    /// same as in `seal_data_key`.
+    pub fn seal<A, Kdf, Kem, R>(
+        pks_recip: &[&<Kem::Kex as KeyExchange>::PublicKey],
+        plaintext: &[u8],
+        csprng: &mut R,
+    ) -> Result<Vec<u8>, HpkeError>
+    where
+        A: Aead,
+        Kdf: KdfTrait,
+        Kem: KemTrait,
+        R: CryptoRng + RngCore,
+    {
+        if pks_recip.is_empty() || pks_recip.len() > u16::MAX as usize {
+            return Err(HpkeError::InvalidSetupParameters(
+                "a multi-recipient message needs between 1 and 65535 recipients",
+            ));
+        }
+
+        let mut data_key = GenericArray::<u8, <A::AeadImpl as NewAead>::KeySize>::default();
+        csprng.fill_bytes(&mut data_key);
+
+        let mut message = Vec::with_capacity(
+            2 + pks_recip.len() * entry_len::<A, Kem>() + plaintext.len() + AeadTag::<A>::size(),
+        );
+        message.extend_from_slice(&(pks_recip.len() as u16).to_be_bytes());
+        for pk_recip in pks_recip {
+            let wrapped = seal_data_key::<A, Kdf, Kem, R>(pk_recip, b"", &data_key, csprng);
+            let (encapped_key, wrapped_key) = match wrapped {
+                Ok(wrapped) => wrapped,
+                Err(e) => {
+                    data_key.zeroize();
+                    return Err(e);
+                }
+            };
+            message.extend_from_slice(&key_id::<Kem>(pk_recip));
+            message.extend_from_slice(&encapped_key.to_bytes());
+            message.extend_from_slice(&wrapped_key);
+        }
+
+        // The AAD is everything so far, so the recipient list can't be changed
+        let header_len = message.len();
+        message.extend_from_slice(plaintext);
+        let (header, ciphertext) = message.split_at_mut(header_len);
+        let dem = A::AeadImpl::new(&data_key);
+        data_key.zeroize();
+        let tag = dem
+            .encrypt_in_place_detached(&Default::default(), header, ciphertext)
+            .map_err(|_| HpkeError::SealError)?;
+        message.extend_from_slice(&tag);
+
+        Ok(message)
+    }
+
+    /// Finds the entry tagged `my_tag` in `message`, unwraps the data key with `sk_recip`, and
+    /// decrypts the shared ciphertext. `my_tag` is the `key_id` of the recipient's public key. If
+    /// several entries have that tag, each one is tried in turn.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(plaintext)` on success. If the message is truncated, returns
+    /// `Err(HpkeError::ValidationError)`. If no entry has the tag `my_tag`, or none of the entries
+    /// with it unwrap with `sk_recip`, or the ciphertext fails to decrypt, returns
+    /// `Err(HpkeError::OpenError)`. If an entry's encapped key fails to deserialize, returns the
+    /// error from `EncappedKey::from_bytes`.
+    pub fn open<A, Kdf, Kem>(
+        sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
+        my_tag: &[u8; RECIPIENT_TAG_LEN],
+        message: &[u8],
+    ) -> Result<Vec<u8>, HpkeError>
+    where
+        A: Aead,
+        Kdf: KdfTrait,
+        Kem: KemTrait,
+    {
+        if message.len() < 2 {
+            return Err(HpkeError::ValidationError);
+        }
+        let n_recipients = BigEndian::read_u16(&message[..2]) as usize;
+        let header_len = 2 + n_recipients * entry_len::<A, Kem>();
+        let tag_len = AeadTag::<A>::size();
+        if message.len() < header_len + tag_len {
+            return Err(HpkeError::ValidationError);
+        }
+
+        let (header, rest) = message.split_at(header_len);
+        let (ciphertext, tag_bytes) = rest.split_at(rest.len() - tag_len);
+        let tag = AeadTag::<A>::from_bytes(tag_bytes)?;
+
+        let enc_len = EncappedKey::<Kem::Kex>::size();
+        let mut data_key = None;
+        for entry in header[2..].chunks_exact(entry_len::<A, Kem>()) {
+            let (recipient_tag, rest) = entry.split_at(RECIPIENT_TAG_LEN);
+            if recipient_tag != my_tag {
+                continue;
+            }
+
+            let (enc_bytes, wrapped_key) = rest.split_at(enc_len);
+            let encapped_key = EncappedKey::<Kem::Kex>::from_bytes(enc_bytes)?;
+            match open_data_key::<A, Kdf, Kem>(sk_recip, &encapped_key, b"", wrapped_key) {
+                Ok(key) => {
+                    data_key = Some(key);
+                    break;
+                }
+                // A different recipient with the same tag. Keep looking.
+                Err(HpkeError::OpenError) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        let mut data_key = data_key.ok_or(HpkeError::OpenError)?;
+
+        let dem = A::AeadImpl::new(GenericArray::from_slice(&data_key));
+        data_key.zeroize();
+        let mut plaintext = ciphertext.to_vec();
+        dem.decrypt_in_place_detached(
+            &Default::default(),
+            header,
+            &mut plaintext,
+            tag.as_ref().into(),
+        )
+        .map_err(|_| HpkeError::OpenError)?;
+        Ok(plaintext)
+    }
+}
+
+/// The length of a recipient entry, i.e., `recipient_tag || enc || wrapped_key`
+fn entry_len<A: Aead, Kem: KemTrait>() -> usize {
+    RECIPIENT_TAG_LEN
+        + EncappedKey::<Kem::Kex>::size()
+        + <A::AeadImpl as NewAead>::KeySize::USIZE
+        + <A::AeadImpl as AeadCore>::TagSize::USIZE
+}
+
+#[cfg(test)]
+mod test {
+    use super::{entry_len, MultiRecipientMessage, RECIPIENT_TAG_LEN};
+    use crate::{aead::ChaCha20Poly1305, kdf::HkdfSha256, kem::Kem as KemTrait, HpkeError};
+
+    use alloc::vec::Vec;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// Tests that every recipient of a three-recipient message recovers the plaintext, that a
+    /// non-recipient doesn't, even by claiming a recipient's tag, and that tampering with the
+    /// recipient list or truncating the message is caught
+    macro_rules! test_multi_recipient {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let recipients = [
+                    Kem::gen_keypair(&mut csprng),
+                    Kem::gen_keypair(&mut csprng),
+                    Kem::gen_keypair(&mut csprng),
+                ];
+                let pks_recip: Vec<_> = recipients.iter().map(|(_, pk)| pk).collect();
+                let msg = b"meeting moved to 3pm";
+
+                let message =
+                    MultiRecipientMessage::seal::<A, Kdf, Kem, _>(&pks_recip, msg, &mut csprng)
+                        .unwrap();
+                assert_eq!(&message[..2], &3u16.to_be_bytes());
+                assert_eq!(
+                    message.len(),
+                    2 + 3 * entry_len::<A, Kem>() + msg.len() + 16
+                );
+
+                for (sk_recip, pk_recip) in recipients.iter() {
+                    let plaintext = MultiRecipientMessage::open::<A, Kdf, Kem>(
+                        sk_recip,
+                        &pk_recip.key_id(),
+                        &message,
+                    )
+                    .unwrap();
+                    assert_eq!(&plaintext, msg);
+                }
+
+                // A non-recipient has no entry, and can't use someone else's either
+                let (other_sk, other_pk) = Kem::gen_keypair(&mut csprng);
+                assert_eq!(
+                    MultiRecipientMessage::open::<A, Kdf, Kem>(
+                        &other_sk,
+                        &other_pk.key_id(),
+                        &message
+                    ),
+                    Err(HpkeError::OpenError)
+                );
+                assert_eq!(
+                    MultiRecipientMessage::open::<A, Kdf, Kem>(
+                        &other_sk,
+                        &recipients[0].1.key_id(),
+                        &message
+                    ),
+                    Err(HpkeError::OpenError)
+                );
+
+                // Dropping a recipient from the list breaks the shared ciphertext
+                let (sk_recip, pk_recip) = &recipients[0];
+                let mut tampered = message.clone();
+                tampered[1] = 2;
+                tampered.drain(2 + 2 * entry_len::<A, Kem>()..2 + 3 * entry_len::<A, Kem>());
+                assert_eq!(
+                    MultiRecipientMessage::open::<A, Kdf, Kem>(
+                        sk_recip,
+                        &pk_recip.key_id(),
+                        &tampered
+                    ),
+                    Err(HpkeError::OpenError)
+                );
+
+                // So does corrupting the ciphertext
+                let mut tampered = message.clone();
+                *tampered.last_mut().unwrap() ^= 1;
+                assert_eq!(
+                    MultiRecipientMessage::open::<A, Kdf, Kem>(
+                        sk_recip,
+                        &pk_recip.key_id(),
+                        &tampered
+                    ),
+                    Err(HpkeError::OpenError)
+                );
+
+                // A message too short for its recipient count is malformed
+                let truncated = &message[..2 + 3 * entry_len::<A, Kem>() + 15];
+                assert_eq!(
+                    MultiRecipientMessage::open::<A, Kdf, Kem>(
+                        sk_recip,
+                        &pk_recip.key_id(),
+                        truncated
+                    ),
+                    Err(HpkeError::ValidationError)
+                );
+                assert_eq!(
+                    MultiRecipientMessage::open::<A, Kdf, Kem>(
+                        sk_recip,
+                        &[0u8; RECIPIENT_TAG_LEN],
+                        &message[..1]
+                    ),
+                    Err(HpkeError::ValidationError)
+                );
+
+                // There has to be someone to send to
+                assert!(matches!(
+                    MultiRecipientMessage::seal::<A, Kdf, Kem, _>(&[], msg, &mut csprng),
+                    Err(HpkeError::InvalidSetupParameters(_))
+                ));
+            }
+        };
+    }
+
+    #[cfg(feature = "x25519-dalek")]
+    test_multi_recipient!(test_multi_recipient_x25519, crate::kem::X25519HkdfSha256);
+
+    #[cfg(feature = "p256")]
+    test_multi_recipient!(test_multi_recipient_p256, crate::kem::DhP256HkdfSha256);
+}
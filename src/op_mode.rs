@@ -1,4 +1,13 @@
-use crate::kex::KeyExchange;
+use crate::{kex::KeyExchange, HpkeError};
+
+use subtle::{Choice, ConstantTimeEq};
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+/// The minimum PSK length that `PskBundle::new` accepts. draft11 §5.1.2 says the PSK MUST have at
+/// least 32 bytes of entropy.
+pub const MIN_PSK_LEN: usize = 32;
 
 /// Contains preshared key bytes and an identifier. This is intended to go inside an `OpModeR` or
 /// `OpModeS` struct.
@@ -10,10 +19,108 @@ pub struct PskBundle<'a> {
     pub psk_id: &'a [u8],
 }
 
+impl<'a> PskBundle<'a> {
+    /// Makes a `PskBundle` after checking that `psk` is at least `MIN_PSK_LEN` bytes long and
+    /// that `psk_id` is nonempty.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(bundle)` on success. If either check fails, returns
+    /// `Err(HpkeError::InvalidPsk)`.
+    pub fn new(psk: &'a [u8], psk_id: &'a [u8]) -> Result<PskBundle<'a>, HpkeError> {
+        // draft11 §5.1: the PSK and PSK ID have to be both empty or both nonempty. We rule out the
+        // former, since that's just not a PSK mode.
+        if psk.len() < MIN_PSK_LEN || psk_id.is_empty() {
+            Err(HpkeError::InvalidPsk)
+        } else {
+            Ok(PskBundle { psk, psk_id })
+        }
+    }
+}
+
+// Compares both the PSK and the PSK ID in constant time. Only the lengths leak. This is for
+// picking among several PSKs without revealing which one matched.
+impl<'a> ConstantTimeEq for PskBundle<'a> {
+    fn ct_eq(&self, other: &PskBundle<'a>) -> Choice {
+        self.psk.ct_eq(other.psk) & self.psk_id.ct_eq(other.psk_id)
+    }
+}
+
+/// A set of PSKs, looked up by `psk_id`. This is for receivers that accept several PSKs and learn
+/// which one a message uses from its framing.
+#[cfg(feature = "alloc")]
+#[derive(Clone, Default)]
+pub struct PskTable<'a> {
+    entries: Vec<PskBundle<'a>>,
+}
+
+#[cfg(feature = "alloc")]
+impl<'a> PskTable<'a> {
+    /// Makes an empty table
+    pub fn new() -> PskTable<'a> {
+        PskTable::default()
+    }
+
+    /// Adds `bundle` to the table, replacing any bundle with the same `psk_id`
+    pub fn insert(&mut self, bundle: PskBundle<'a>) {
+        match self.entries.iter_mut().find(|e| e.psk_id == bundle.psk_id) {
+            Some(entry) => *entry = bundle,
+            None => self.entries.push(bundle),
+        }
+    }
+
+    /// Returns the number of bundles in the table
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns whether the table is empty
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Looks up the bundle with the given `psk_id`. Every entry is compared, in constant time, so
+    /// the time this takes doesn't depend on which entry matched, or whether any did. Only the
+    /// lengths of the PSK IDs leak.
+    pub fn get_ct(&self, psk_id: &[u8]) -> Option<&PskBundle<'a>> {
+        self.get_ct_counted(psk_id, || ())
+    }
+
+    /// Does `get_ct`, calling `on_compare` once for every PSK ID it compares. This is so tests can
+    /// check that lookups don't exit early.
+    fn get_ct_counted(
+        &self,
+        psk_id: &[u8],
+        mut on_compare: impl FnMut(),
+    ) -> Option<&PskBundle<'a>> {
+        use subtle::ConditionallySelectable;
+
+        let mut found = Choice::from(0);
+        let mut idx = 0u64;
+        for (i, entry) in self.entries.iter().enumerate() {
+            let is_match = entry.psk_id.ct_eq(psk_id);
+            on_compare();
+            idx.conditional_assign(&(i as u64), is_match);
+            found |= is_match;
+        }
+
+        if bool::from(found) {
+            Some(&self.entries[idx as usize])
+        } else {
+            None
+        }
+    }
+}
+
 /// The operation mode of the HPKE session (receiver's view). This is how the sender authenticates
 /// their identity to the receiver. This authentication information can include a preshared key,
 /// the identity key of the sender, both, or neither. `Base` is the only mode that does not provide
 /// any kind of sender identity authentication.
+///
+/// The mode is not sent on the wire. Nothing in an encapsulated key or ciphertext says which mode
+/// it was made in, so a receiver in a different mode from the sender just fails to open, with
+/// `HpkeError::OpenError`. When debugging this, `diagnose_open_failure` (with the
+/// `test-internals` feature) reports which modes a message opens in.
 pub enum OpModeR<'a, Kex: KeyExchange> {
     /// No extra information included
     Base,
@@ -50,15 +157,22 @@ pub enum OpModeS<'a, Kex: KeyExchange> {
     Auth((Kex::PrivateKey, Kex::PublicKey)),
     /// Both of the above
     AuthPsk((Kex::PrivateKey, Kex::PublicKey), PskBundle<'a>),
+    /// Same as `Auth`, but borrows the identity keypair of the sender. This is useful when the
+    /// same long-term keypair is used for many sessions.
+    AuthBorrowed((&'a Kex::PrivateKey, &'a Kex::PublicKey)),
+    /// Same as `AuthPsk`, but borrows the identity keypair of the sender
+    AuthPskBorrowed((&'a Kex::PrivateKey, &'a Kex::PublicKey), PskBundle<'a>),
 }
 
 // Helpers functions for setup_sender and testing
 impl<'a, Kex: KeyExchange> OpModeS<'a, Kex> {
     /// Returns the sender's identity pubkey if it's specified
-    pub(crate) fn get_sender_id_keypair(&self) -> Option<&(Kex::PrivateKey, Kex::PublicKey)> {
+    pub(crate) fn get_sender_id_keypair(&self) -> Option<(&Kex::PrivateKey, &Kex::PublicKey)> {
         match self {
-            OpModeS::Auth(keypair) => Some(keypair),
-            OpModeS::AuthPsk(keypair, _) => Some(keypair),
+            OpModeS::Auth((sk, pk)) => Some((sk, pk)),
+            OpModeS::AuthPsk((sk, pk), _) => Some((sk, pk)),
+            OpModeS::AuthBorrowed((sk, pk)) => Some((sk, pk)),
+            OpModeS::AuthPskBorrowed((sk, pk), _) => Some((sk, pk)),
             _ => None,
         }
     }
@@ -116,8 +230,8 @@ impl<'a, Kex: KeyExchange> OpMode<Kex> for OpModeS<'a, Kex> {
         match self {
             OpModeS::Base => 0x00,
             OpModeS::Psk(..) => 0x01,
-            OpModeS::Auth(..) => 0x02,
-            OpModeS::AuthPsk(..) => 0x03,
+            OpModeS::Auth(..) | OpModeS::AuthBorrowed(..) => 0x02,
+            OpModeS::AuthPsk(..) | OpModeS::AuthPskBorrowed(..) => 0x03,
         }
     }
 
@@ -128,6 +242,7 @@ impl<'a, Kex: KeyExchange> OpMode<Kex> for OpModeS<'a, Kex> {
         match self {
             OpModeS::Psk(bundle) => bundle.psk,
             OpModeS::AuthPsk(_, bundle) => bundle.psk,
+            OpModeS::AuthPskBorrowed(_, bundle) => bundle.psk,
             _ => &[],
         }
     }
@@ -138,7 +253,109 @@ impl<'a, Kex: KeyExchange> OpMode<Kex> for OpModeS<'a, Kex> {
         match self {
             OpModeS::Psk(p) => p.psk_id,
             OpModeS::AuthPsk(_, p) => p.psk_id,
+            OpModeS::AuthPskBorrowed(_, p) => p.psk_id,
             _ => &[],
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{PskBundle, MIN_PSK_LEN};
+    use crate::HpkeError;
+
+    use subtle::ConstantTimeEq;
+
+    /// Tests that `PskTable::get_ct` finds the bundle with the given ID, and that hits and misses
+    /// alike compare every entry
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn test_psk_table() {
+        use super::PskTable;
+
+        let psks = [[1u8; 32], [2u8; 32], [3u8; 32]];
+        let psk_ids: [&[u8]; 3] = [b"first", b"second", b"third"];
+        let mut table = PskTable::new();
+        assert!(table.is_empty());
+        for (psk, psk_id) in psks.iter().zip(psk_ids.iter()) {
+            table.insert(PskBundle::new(psk, psk_id).unwrap());
+        }
+        assert_eq!(table.len(), 3);
+
+        // Returns the result of a lookup, and how many PSK IDs it compared
+        let lookup = |psk_id: &[u8]| {
+            let mut comparisons = 0;
+            let res = table.get_ct_counted(psk_id, || comparisons += 1).cloned();
+            (res, comparisons)
+        };
+
+        for (psk, psk_id) in psks.iter().zip(psk_ids.iter()) {
+            let (bundle, comparisons) = lookup(psk_id);
+            assert_eq!(bundle.unwrap().psk, psk);
+            assert_eq!(table.get_ct(psk_id).unwrap().psk, psk);
+            assert_eq!(comparisons, 3);
+        }
+
+        let (bundle, comparisons) = lookup(b"fourth");
+        assert!(bundle.is_none());
+        assert_eq!(comparisons, 3);
+        let (bundle, comparisons) = lookup(b"");
+        assert!(bundle.is_none());
+        assert_eq!(comparisons, 3);
+
+        // Inserting an existing ID replaces its PSK
+        let new_psk = [4u8; 32];
+        table.insert(PskBundle::new(&new_psk, b"second").unwrap());
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.get_ct(b"second").unwrap().psk, &new_psk);
+    }
+
+    /// Tests that `PskBundle::new` accepts a PSK of exactly `MIN_PSK_LEN` bytes, and rejects
+    /// anything shorter
+    #[test]
+    fn test_psk_bundle_min_len() {
+        let psk = [0x0fu8; MIN_PSK_LEN];
+        let psk_id = b"my psk";
+
+        assert!(PskBundle::new(&psk, psk_id).is_ok());
+        assert_eq!(
+            PskBundle::new(&psk[..MIN_PSK_LEN - 1], psk_id).err(),
+            Some(HpkeError::InvalidPsk)
+        );
+    }
+
+    /// Tests that `PskBundle::new` rejects an empty PSK ID when the PSK is nonempty
+    #[test]
+    fn test_psk_bundle_empty_id() {
+        let psk = [0x0fu8; MIN_PSK_LEN];
+        assert_eq!(PskBundle::new(&psk, b"").err(), Some(HpkeError::InvalidPsk));
+    }
+
+    /// Tests that `ct_eq` is true iff both the PSK and the PSK ID match
+    #[test]
+    fn test_psk_bundle_ct_eq() {
+        let psk = [0x0fu8; MIN_PSK_LEN];
+        let other_psk = [0xf0u8; MIN_PSK_LEN];
+        let bundle = PskBundle::new(&psk, b"id 1").unwrap();
+
+        // Copying the contents gives an equal bundle
+        let psk_copy = psk;
+        let id_copy = *b"id 1";
+        assert!(bool::from(
+            bundle.ct_eq(&PskBundle::new(&psk_copy, &id_copy).unwrap())
+        ));
+
+        // Changing either part, or the length of either part, gives an unequal bundle
+        for unequal in &[
+            PskBundle::new(&other_psk, b"id 1").unwrap(),
+            PskBundle::new(&psk, b"id 2").unwrap(),
+            PskBundle::new(&psk, b"id 12").unwrap(),
+        ] {
+            assert!(!bool::from(bundle.ct_eq(unequal)));
+        }
+        let long_psk = [0x0fu8; MIN_PSK_LEN + 1];
+        assert!(!bool::from(
+            bundle.ct_eq(&PskBundle::new(&long_psk, b"id 1").unwrap())
+        ));
+    }
+}
@@ -0,0 +1,129 @@
+//! An API in the style of libsodium's `crypto_box_seal` and `crypto_box_seal_open`, for code that's
+//! moving off of libsodium. A sealed box is an anonymous message to a recipient's public key. Here
+//! it's made with HPKE in `Base` mode, with DHKEM(X25519, HKDF-SHA256), HKDF-SHA256, and
+//! ChaCha20Poly1305, and an empty info string. The format is `enc || ciphertext || tag`.
+//!
+//! **This is API-compatible with libsodium, but not wire-compatible.** libsodium derives the nonce
+//! from a BLAKE2b hash of the public keys, and encrypts with XSalsa20Poly1305. Boxes sealed here
+//! can't be opened by libsodium, and vice versa. This is gated under the `alloc` and `x25519`
+//! features.
+
+use crate::{
+    aead::ChaCha20Poly1305,
+    kdf::HkdfSha256,
+    kem::{EncappedKey, Kem as KemTrait, X25519HkdfSha256},
+    kex::{Deserializable, KeyExchange, Serializable},
+    single_shot::{open_data_key, seal_data_key},
+    HpkeError,
+};
+
+use alloc::vec::Vec;
+use rand_core::{CryptoRng, RngCore};
+
+type Kem = X25519HkdfSha256;
+type PublicKey = <<Kem as KemTrait>::Kex as KeyExchange>::PublicKey;
+type PrivateKey = <<Kem as KemTrait>::Kex as KeyExchange>::PrivateKey;
+
+/// The number of bytes a sealed box adds to the message it holds
+pub const SEALED_BOX_OVERHEAD: usize = 32 + 16;
+
+/// Seals `message` to `pk_recip`. This is the counterpart of libsodium's `crypto_box_seal`.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(sealed_box)` on success, which is `SEALED_BOX_OVERHEAD` bytes longer than
+/// `message`. If an error happened during key encapsulation, returns `Err(HpkeError::EncapError)`.
+/// If an error happened during encryption, returns `Err(HpkeError::SealError)`.
+pub fn seal_box<R: CryptoRng + RngCore>(
+    pk_recip: &PublicKey,
+    message: &[u8],
+    csprng: &mut R,
+) -> Result<Vec<u8>, HpkeError> {
+    let (encapped_key, ciphertext) =
+        seal_data_key::<ChaCha20Poly1305, HkdfSha256, Kem, R>(pk_recip, b"", message, csprng)?;
+
+    let mut sealed_box = Vec::with_capacity(SEALED_BOX_OVERHEAD + message.len());
+    sealed_box.extend_from_slice(&encapped_key.to_bytes());
+    sealed_box.extend_from_slice(&ciphertext);
+    Ok(sealed_box)
+}
+
+/// Opens a box sealed by `seal_box`. This is the counterpart of libsodium's
+/// `crypto_box_seal_open`.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(message)` on success. If `sealed_box` is shorter than `SEALED_BOX_OVERHEAD`, returns
+/// `Err(HpkeError::IncorrectInputLength { expected: SEALED_BOX_OVERHEAD, got: sealed_box.len() })`.
+/// If the encapsulated key is invalid, or if an error happened during key decapsulation, returns
+/// `Err(HpkeError::ValidationError)` or `Err(HpkeError::DecapError)`. If the box was corrupted,
+/// returns `Err(HpkeError::OpenError)`.
+pub fn open_box(sk_recip: &PrivateKey, sealed_box: &[u8]) -> Result<Vec<u8>, HpkeError> {
+    if sealed_box.len() < SEALED_BOX_OVERHEAD {
+        return Err(HpkeError::IncorrectInputLength {
+            expected: SEALED_BOX_OVERHEAD,
+            got: sealed_box.len(),
+        });
+    }
+
+    let (enc, ciphertext) = sealed_box.split_at(EncappedKey::<<Kem as KemTrait>::Kex>::size());
+    let encapped_key = EncappedKey::from_bytes(enc)?;
+    open_data_key::<ChaCha20Poly1305, HkdfSha256, Kem>(sk_recip, &encapped_key, b"", ciphertext)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{open_box, seal_box, Kem, SEALED_BOX_OVERHEAD};
+    use crate::{
+        aead::{AeadTag, ChaCha20Poly1305},
+        kem::{EncappedKey, Kem as KemTrait},
+        kex::Serializable,
+        HpkeError,
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// Tests that a sealed box opens, is `SEALED_BOX_OVERHEAD` bytes longer than its message, and
+    /// doesn't open if it's corrupted, truncated, or opened with the wrong key
+    #[test]
+    fn test_sealed_box() {
+        assert_eq!(
+            SEALED_BOX_OVERHEAD,
+            EncappedKey::<<Kem as KemTrait>::Kex>::size() + AeadTag::<ChaCha20Poly1305>::size()
+        );
+
+        let mut csprng = StdRng::from_entropy();
+        let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+        let msg = b"anonymous tip";
+
+        let sealed_box = seal_box(&pk_recip, msg, &mut csprng).unwrap();
+        assert_eq!(sealed_box.len(), msg.len() + SEALED_BOX_OVERHEAD);
+        assert_eq!(open_box(&sk_recip, &sealed_box).unwrap(), msg.to_vec());
+
+        // Corrupting the ciphertext or the encapsulated key breaks it
+        for &i in &[0, 40, sealed_box.len() - 1] {
+            let mut corrupted = sealed_box.clone();
+            corrupted[i] ^= 1;
+            assert!(open_box(&sk_recip, &corrupted).is_err());
+        }
+        let mut corrupted = sealed_box.clone();
+        corrupted[40] ^= 1;
+        assert_eq!(open_box(&sk_recip, &corrupted), Err(HpkeError::OpenError));
+
+        assert_eq!(
+            open_box(&sk_recip, &sealed_box[..SEALED_BOX_OVERHEAD - 1]),
+            Err(HpkeError::IncorrectInputLength {
+                expected: SEALED_BOX_OVERHEAD,
+                got: SEALED_BOX_OVERHEAD - 1
+            })
+        );
+
+        let (other_sk, _) = Kem::gen_keypair(&mut csprng);
+        assert!(open_box(&other_sk, &sealed_box).is_err());
+
+        // An empty message is fine
+        let sealed_box = seal_box(&pk_recip, b"", &mut csprng).unwrap();
+        assert_eq!(sealed_box.len(), SEALED_BOX_OVERHEAD);
+        assert!(open_box(&sk_recip, &sealed_box).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,401 @@
+//! A power-on self-test. `run_self_tests` runs a known-answer test for every ciphersuite that's
+//! compiled in, so that an application can refuse to start if the build is broken. The known
+//! answers are the Base mode test vectors from draft11 Appendix A. There are no vectors for
+//! HKDF-SHA384, so it is not tested here.
+
+#[cfg(any(feature = "x25519-dalek", feature = "p256"))]
+use crate::{
+    aead::{Aead, AeadCtxS, AesGcm128, AesGcm256, ChaCha20Poly1305},
+    kdf::{HkdfSha256, HkdfSha512, Kdf as KdfTrait},
+    kem::{self, Kem as KemTrait},
+    kex::{Deserializable, KeyExchange, Serializable},
+    op_mode::OpModeS,
+    setup::derive_enc_ctx,
+};
+
+#[cfg(feature = "p256")]
+use crate::kem::DhP256HkdfSha256;
+#[cfg(feature = "x25519-dalek")]
+use crate::kem::X25519HkdfSha256;
+
+/// Describes the ciphersuite whose known-answer test failed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SelfTestError {
+    /// The algorithm identifier of the KEM
+    pub kem_id: u16,
+    /// The algorithm identifier of the KDF
+    pub kdf_id: u16,
+    /// The algorithm identifier of the AEAD
+    pub aead_id: u16,
+}
+
+impl core::fmt::Display for SelfTestError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "Self-test failed for suite (KEM 0x{:04x}, KDF 0x{:04x}, AEAD 0x{:04x})",
+            self.kem_id, self.kdf_id, self.aead_id
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for SelfTestError {}
+
+// The inputs common to all the test vectors
+#[cfg(any(feature = "x25519-dalek", feature = "p256"))]
+const INFO: &[u8] = b"Ode on a Grecian Urn";
+#[cfg(any(feature = "x25519-dalek", feature = "p256"))]
+const PLAINTEXT: &[u8; 29] = b"Beauty is truth, truth beauty";
+#[cfg(any(feature = "x25519-dalek", feature = "p256"))]
+const AAD: &[u8] = b"Count-0";
+
+/// The inputs and expected output of a single known-answer test
+#[cfg(any(feature = "x25519-dalek", feature = "p256"))]
+struct Kat<'a> {
+    /// The IKM that the ephemeral keypair is derived from
+    ikm_eph: &'a [u8],
+    /// The serialized recipient public key
+    pk_recip: &'a [u8],
+    /// The expected ciphertext, with the tag appended
+    ciphertext: &'a [u8],
+}
+
+#[cfg(feature = "x25519-dalek")]
+const X25519_SHA256_AES128: Kat = Kat {
+    ikm_eph: &[
+        0x72, 0x68, 0x60, 0x0d, 0x40, 0x3f, 0xce, 0x43, 0x15, 0x61, 0xae, 0xf5, 0x83, 0xee, 0x16,
+        0x13, 0x52, 0x7c, 0xff, 0x65, 0x5c, 0x13, 0x43, 0xf2, 0x98, 0x12, 0xe6, 0x67, 0x06, 0xdf,
+        0x32, 0x34,
+    ],
+    pk_recip: &[
+        0x39, 0x48, 0xcf, 0xe0, 0xad, 0x1d, 0xdb, 0x69, 0x5d, 0x78, 0x0e, 0x59, 0x07, 0x71, 0x95,
+        0xda, 0x6c, 0x56, 0x50, 0x6b, 0x02, 0x73, 0x29, 0x79, 0x4a, 0xb0, 0x2b, 0xca, 0x80, 0x81,
+        0x5c, 0x4d,
+    ],
+    ciphertext: &[
+        0xf9, 0x38, 0x55, 0x8b, 0x5d, 0x72, 0xf1, 0xa2, 0x38, 0x10, 0xb4, 0xbe, 0x2a, 0xb4, 0xf8,
+        0x43, 0x31, 0xac, 0xc0, 0x2f, 0xc9, 0x7b, 0xab, 0xc5, 0x3a, 0x52, 0xae, 0x82, 0x18, 0xa3,
+        0x55, 0xa9, 0x6d, 0x87, 0x70, 0xac, 0x83, 0xd0, 0x7b, 0xea, 0x87, 0xe1, 0x3c, 0x51, 0x2a,
+    ],
+};
+
+#[cfg(feature = "x25519-dalek")]
+const X25519_SHA256_AES256: Kat = Kat {
+    ikm_eph: &[
+        0x2c, 0xd7, 0xc6, 0x01, 0xce, 0xfb, 0x3d, 0x42, 0xa6, 0x2b, 0x04, 0xb7, 0xa9, 0x04, 0x14,
+        0x94, 0xc0, 0x6c, 0x78, 0x43, 0x81, 0x8e, 0x0c, 0xe2, 0x8a, 0x8f, 0x70, 0x4a, 0xe7, 0xab,
+        0x20, 0xf9,
+    ],
+    pk_recip: &[
+        0x43, 0x0f, 0x4b, 0x98, 0x59, 0x66, 0x51, 0x45, 0xa6, 0xb1, 0xba, 0x27, 0x40, 0x24, 0x48,
+        0x7b, 0xd6, 0x6f, 0x03, 0xa2, 0xdd, 0x57, 0x7d, 0x77, 0x53, 0xc6, 0x8d, 0x7d, 0x7d, 0x00,
+        0xc0, 0x0c,
+    ],
+    ciphertext: &[
+        0xe5, 0xd8, 0x4c, 0xd5, 0x31, 0xcf, 0xb5, 0x83, 0x09, 0x6e, 0x7c, 0xfa, 0x96, 0x41, 0xbd,
+        0x30, 0x79, 0xcf, 0x3a, 0x91, 0xcd, 0xa8, 0x13, 0xc5, 0x2d, 0xeb, 0x5f, 0x51, 0x2b, 0xe9,
+        0x93, 0x19, 0x80, 0xa4, 0x1d, 0xe1, 0x25, 0xa9, 0x25, 0xcd, 0xad, 0x85, 0x9d, 0x5b, 0x7a,
+    ],
+};
+
+#[cfg(feature = "x25519-dalek")]
+const X25519_SHA256_CHACHA: Kat = Kat {
+    ikm_eph: &[
+        0x90, 0x9a, 0x9b, 0x35, 0xd3, 0xdc, 0x47, 0x13, 0xa5, 0xe7, 0x2a, 0x4d, 0xa2, 0x74, 0xb5,
+        0x5d, 0x3d, 0x38, 0x21, 0xa3, 0x7e, 0x5d, 0x09, 0x9e, 0x74, 0xa6, 0x47, 0xdb, 0x58, 0x3a,
+        0x90, 0x4b,
+    ],
+    pk_recip: &[
+        0x43, 0x10, 0xee, 0x97, 0xd8, 0x8c, 0xc1, 0xf0, 0x88, 0xa5, 0x57, 0x6c, 0x77, 0xab, 0x0c,
+        0xf5, 0xc3, 0xac, 0x79, 0x7f, 0x3d, 0x95, 0x13, 0x9c, 0x6c, 0x84, 0xb5, 0x42, 0x9c, 0x59,
+        0x66, 0x2a,
+    ],
+    ciphertext: &[
+        0x1c, 0x52, 0x50, 0xd8, 0x03, 0x4e, 0xc2, 0xb7, 0x84, 0xba, 0x2c, 0xfd, 0x69, 0xdb, 0xdb,
+        0x8a, 0xf4, 0x06, 0xcf, 0xe3, 0xff, 0x93, 0x8e, 0x13, 0x1f, 0x0d, 0xef, 0x8c, 0x8b, 0x60,
+        0xb4, 0xdb, 0x21, 0x99, 0x3c, 0x62, 0xce, 0x81, 0x88, 0x3d, 0x2d, 0xd1, 0xb5, 0x1a, 0x28,
+    ],
+};
+
+#[cfg(feature = "x25519-dalek")]
+const X25519_SHA512_AES128: Kat = Kat {
+    ikm_eph: &[
+        0x89, 0x52, 0x21, 0xae, 0x20, 0xf3, 0x9c, 0xbf, 0x46, 0x87, 0x1d, 0x6e, 0xa1, 0x62, 0xd4,
+        0x4b, 0x84, 0xdd, 0x7b, 0xa9, 0xcc, 0x7a, 0x3c, 0x80, 0xf1, 0x6d, 0x6e, 0xa4, 0x24, 0x2c,
+        0xd6, 0xd4,
+    ],
+    pk_recip: &[
+        0xad, 0xf1, 0x6c, 0x69, 0x6b, 0x87, 0x99, 0x58, 0x79, 0xb2, 0x7d, 0x47, 0x0d, 0x37, 0x21,
+        0x2f, 0x38, 0xa5, 0x8b, 0xfe, 0x7f, 0x84, 0xe6, 0xd5, 0x0d, 0xb6, 0x38, 0xb8, 0xf2, 0xc2,
+        0x23, 0x40,
+    ],
+    ciphertext: &[
+        0xd3, 0xa6, 0x76, 0x35, 0x9d, 0x7d, 0xb8, 0x14, 0xf1, 0xf7, 0xa1, 0x2c, 0xbe, 0x98, 0xab,
+        0x33, 0x4c, 0x83, 0x4e, 0x14, 0xd6, 0x1d, 0xef, 0x40, 0x61, 0x6d, 0xfc, 0x7e, 0x53, 0xdc,
+        0x5f, 0xc9, 0x2e, 0x1e, 0x05, 0xd8, 0xc8, 0x13, 0x95, 0x96, 0xdc, 0x8e, 0x7b, 0x04, 0xf5,
+    ],
+};
+
+#[cfg(feature = "x25519-dalek")]
+const X25519_SHA512_AES256: Kat = Kat {
+    ikm_eph: &[
+        0xe7, 0x2b, 0x39, 0x23, 0x2e, 0xe9, 0xef, 0x9f, 0x65, 0x37, 0xa7, 0x2a, 0xfe, 0x28, 0xf5,
+        0x51, 0xdb, 0xe6, 0x32, 0x00, 0x6a, 0xa1, 0xb3, 0x00, 0xa0, 0x05, 0x18, 0x88, 0x3a, 0x3f,
+        0x2d, 0xc1,
+    ],
+    pk_recip: &[
+        0xaa, 0x7b, 0xdd, 0xcf, 0x5c, 0xa0, 0xb2, 0xc0, 0xcf, 0x76, 0x0b, 0x5d, 0xff, 0xc6, 0x27,
+        0x40, 0xa8, 0xe7, 0x61, 0xec, 0x57, 0x20, 0x32, 0xa8, 0x09, 0xbe, 0xbc, 0x87, 0xaa, 0xf7,
+        0x57, 0x5e,
+    ],
+    ciphertext: &[
+        0x18, 0x6c, 0xbe, 0xff, 0xd8, 0x0f, 0xd6, 0x88, 0x62, 0xb0, 0x9d, 0x96, 0x8a, 0x94, 0x4c,
+        0x9f, 0x1e, 0xcc, 0x1c, 0x3f, 0x5d, 0xbc, 0xd1, 0xe2, 0x69, 0x73, 0xec, 0x30, 0xa9, 0x85,
+        0x6f, 0x00, 0x6f, 0x7b, 0xb4, 0x72, 0xc3, 0xe3, 0x0f, 0xff, 0x57, 0xce, 0xd6, 0x69, 0xfc,
+    ],
+};
+
+#[cfg(feature = "x25519-dalek")]
+const X25519_SHA512_CHACHA: Kat = Kat {
+    ikm_eph: &[
+        0x63, 0x6d, 0x12, 0x37, 0xa5, 0xae, 0x67, 0x4c, 0x24, 0xca, 0xa0, 0xc3, 0x2a, 0x98, 0x0d,
+        0x32, 0x18, 0xd8, 0x4f, 0x91, 0x6b, 0xa3, 0x1e, 0x16, 0x69, 0x98, 0x92, 0xd2, 0x71, 0x03,
+        0xa2, 0xa9,
+    ],
+    pk_recip: &[
+        0x06, 0xaa, 0x19, 0x3a, 0x56, 0x12, 0xd8, 0x9a, 0x19, 0x35, 0xc3, 0x3f, 0x1f, 0xda, 0x31,
+        0x09, 0xfc, 0xdf, 0x4b, 0x86, 0x7d, 0xa4, 0xc4, 0x50, 0x78, 0x79, 0xf1, 0x84, 0x34, 0x0b,
+        0x0e, 0x0e,
+    ],
+    ciphertext: &[
+        0x72, 0xda, 0x96, 0x27, 0xfd, 0x7e, 0xb3, 0xa8, 0xb7, 0x16, 0x9c, 0x6d, 0x97, 0x41, 0x9b,
+        0x80, 0xad, 0xef, 0xca, 0x75, 0x1c, 0x6b, 0x52, 0xb3, 0x9a, 0x2e, 0x08, 0x4d, 0x35, 0xce,
+        0x3e, 0xb4, 0x48, 0x7a, 0xad, 0xac, 0xa5, 0xa9, 0xc5, 0x90, 0xe0, 0x93, 0x8c, 0x48, 0xb9,
+    ],
+};
+
+#[cfg(feature = "p256")]
+const P256_SHA256_AES128: Kat = Kat {
+    ikm_eph: &[
+        0x42, 0x70, 0xe5, 0x4f, 0xfd, 0x08, 0xd7, 0x9d, 0x59, 0x28, 0x02, 0x0a, 0xf4, 0x68, 0x6d,
+        0x8f, 0x6b, 0x7d, 0x35, 0xdb, 0xe4, 0x70, 0x26, 0x5f, 0x1f, 0x5a, 0xa2, 0x28, 0x16, 0xce,
+        0x86, 0x0e,
+    ],
+    pk_recip: &[
+        0x04, 0xfe, 0x8c, 0x19, 0xce, 0x09, 0x05, 0x19, 0x1e, 0xbc, 0x29, 0x8a, 0x92, 0x45, 0x79,
+        0x25, 0x31, 0xf2, 0x6f, 0x0c, 0xec, 0xe2, 0x46, 0x06, 0x39, 0xe8, 0xbc, 0x39, 0xcb, 0x7f,
+        0x70, 0x6a, 0x82, 0x6a, 0x77, 0x9b, 0x4c, 0xf9, 0x69, 0xb8, 0xa0, 0xe5, 0x39, 0xc7, 0xf6,
+        0x2f, 0xb3, 0xd3, 0x0a, 0xd6, 0xaa, 0x8f, 0x80, 0xe3, 0x0f, 0x1d, 0x12, 0x8a, 0xaf, 0xd6,
+        0x8a, 0x2c, 0xe7, 0x2e, 0xa0,
+    ],
+    ciphertext: &[
+        0x5a, 0xd5, 0x90, 0xbb, 0x8b, 0xaa, 0x57, 0x7f, 0x86, 0x19, 0xdb, 0x35, 0xa3, 0x63, 0x11,
+        0x22, 0x6a, 0x89, 0x6e, 0x73, 0x42, 0xa6, 0xd8, 0x36, 0xd8, 0xb7, 0xbc, 0xd2, 0xf2, 0x0b,
+        0x6c, 0x7f, 0x90, 0x76, 0xac, 0x23, 0x2e, 0x3a, 0xb2, 0x52, 0x3f, 0x39, 0x51, 0x34, 0x34,
+    ],
+};
+
+#[cfg(feature = "p256")]
+const P256_SHA256_AES256: Kat = Kat {
+    ikm_eph: &[
+        0xa9, 0x0d, 0x34, 0x17, 0xc3, 0xda, 0x9c, 0xb6, 0xc6, 0xae, 0x19, 0xb4, 0xb5, 0xdd, 0x6c,
+        0xc9, 0x52, 0x9a, 0x4c, 0xc2, 0x4e, 0xfb, 0x7a, 0xe0, 0xac, 0xe1, 0xf3, 0x18, 0x87, 0xa8,
+        0xcd, 0x6c,
+    ],
+    pk_recip: &[
+        0x04, 0xab, 0xc7, 0xe4, 0x9a, 0x4c, 0x6b, 0x35, 0x66, 0xd7, 0x7d, 0x03, 0x04, 0xad, 0xdc,
+        0x6e, 0xd0, 0xe9, 0x85, 0x12, 0xff, 0xcc, 0xf5, 0x05, 0xe6, 0xa8, 0xe3, 0xeb, 0x25, 0xc6,
+        0x85, 0x13, 0x6f, 0x85, 0x31, 0x48, 0x54, 0x48, 0x76, 0xde, 0x76, 0xc0, 0xf2, 0xef, 0x99,
+        0xcd, 0xc3, 0xa0, 0x5c, 0xcf, 0x5d, 0xed, 0x78, 0x60, 0xc7, 0xc0, 0x21, 0x23, 0x8f, 0x9e,
+        0x20, 0x73, 0xd2, 0x35, 0x6c,
+    ],
+    ciphertext: &[
+        0x58, 0xc6, 0x1a, 0x45, 0x05, 0x9d, 0x0c, 0x57, 0x04, 0x56, 0x0e, 0x9d, 0x88, 0xb5, 0x64,
+        0xa8, 0xb6, 0x3f, 0x13, 0x64, 0xb8, 0xd1, 0xfc, 0xb3, 0xc4, 0xc6, 0xdd, 0xc1, 0xd2, 0x91,
+        0x74, 0x24, 0x65, 0xe9, 0x02, 0xcd, 0x21, 0x6f, 0x89, 0x08, 0xda, 0x49, 0xf8, 0xf9, 0x6f,
+    ],
+};
+
+#[cfg(feature = "p256")]
+const P256_SHA256_CHACHA: Kat = Kat {
+    ikm_eph: &[
+        0xf1, 0xf1, 0xa3, 0xbc, 0x95, 0x41, 0x68, 0x71, 0x53, 0x9e, 0xcb, 0x51, 0xc3, 0xa8, 0xf0,
+        0xcf, 0x60, 0x8a, 0xfb, 0x40, 0xfb, 0xbe, 0x30, 0x5c, 0x0a, 0x72, 0x81, 0x9d, 0x35, 0xc3,
+        0x3f, 0x1f,
+    ],
+    pk_recip: &[
+        0x04, 0xa6, 0x97, 0xbf, 0xfd, 0xe9, 0x40, 0x5c, 0x99, 0x28, 0x83, 0xc5, 0xc4, 0x39, 0xd6,
+        0xcc, 0x35, 0x81, 0x70, 0xb5, 0x1a, 0xf7, 0x28, 0x12, 0x33, 0x3b, 0x01, 0x56, 0x21, 0xdc,
+        0x0f, 0x40, 0xba, 0xd9, 0xbb, 0x72, 0x6f, 0x68, 0xa5, 0xc0, 0x13, 0x80, 0x6a, 0x79, 0x0e,
+        0xc7, 0x16, 0xab, 0x86, 0x69, 0xf8, 0x4f, 0x6b, 0x69, 0x45, 0x96, 0xc2, 0x98, 0x7c, 0xf3,
+        0x5b, 0xab, 0xa2, 0xa0, 0x06,
+    ],
+    ciphertext: &[
+        0x64, 0x69, 0xc4, 0x1c, 0x5c, 0x81, 0xd3, 0xaa, 0x85, 0x43, 0x25, 0x31, 0xec, 0xf6, 0x46,
+        0x0e, 0xc9, 0x45, 0xbd, 0xe1, 0xeb, 0x42, 0x8c, 0xb2, 0xfe, 0xdf, 0x7a, 0x29, 0xf5, 0xa6,
+        0x85, 0xb4, 0xcc, 0xb0, 0xd0, 0x57, 0xf0, 0x3e, 0xa2, 0x95, 0x2a, 0x27, 0xbb, 0x45, 0x8b,
+    ],
+};
+
+#[cfg(feature = "p256")]
+const P256_SHA512_AES128: Kat = Kat {
+    ikm_eph: &[
+        0x4a, 0xb1, 0x1a, 0x9d, 0xd7, 0x8c, 0x39, 0x66, 0x8f, 0x70, 0x38, 0xf9, 0x21, 0xff, 0xc0,
+        0x99, 0x3b, 0x36, 0x81, 0x71, 0xd3, 0xdd, 0xde, 0x80, 0x31, 0x50, 0x1e, 0xe1, 0xe0, 0x8c,
+        0x4c, 0x9a,
+    ],
+    pk_recip: &[
+        0x04, 0x08, 0x5a, 0xa5, 0xb6, 0x65, 0xdc, 0x38, 0x26, 0xf9, 0x65, 0x0c, 0xcb, 0xcc, 0x47,
+        0x1b, 0xe2, 0x68, 0xc8, 0xad, 0xa8, 0x66, 0x42, 0x2f, 0x73, 0x9e, 0x2d, 0x53, 0x1d, 0x4a,
+        0x88, 0x18, 0xa9, 0x46, 0x6b, 0xc6, 0xb4, 0x49, 0x35, 0x70, 0x96, 0x23, 0x29, 0x19, 0xec,
+        0x4f, 0xe9, 0x07, 0x0c, 0xcb, 0xac, 0x4a, 0xac, 0x30, 0xf4, 0xa1, 0xa5, 0x3e, 0xfc, 0xf7,
+        0xaf, 0x90, 0x61, 0x0e, 0xdd,
+    ],
+    ciphertext: &[
+        0xd3, 0xcf, 0x49, 0x84, 0x93, 0x14, 0x84, 0xa0, 0x80, 0xf7, 0x4c, 0x1b, 0xb2, 0xa6, 0x78,
+        0x27, 0x00, 0xdc, 0x1f, 0xef, 0x9a, 0xbe, 0x84, 0x42, 0xe4, 0x4a, 0x6f, 0x09, 0x04, 0x4c,
+        0x88, 0x90, 0x72, 0x00, 0xb3, 0x32, 0x00, 0x35, 0x43, 0x75, 0x4e, 0xb5, 0x19, 0x17, 0xba,
+    ],
+};
+
+#[cfg(feature = "p256")]
+const P256_SHA512_AES256: Kat = Kat {
+    ikm_eph: &[
+        0x0c, 0x4b, 0x7c, 0x80, 0x90, 0xd9, 0x99, 0x5e, 0x29, 0x8d, 0x6f, 0xd6, 0x1c, 0x7a, 0x0a,
+        0x66, 0xbb, 0x76, 0x5a, 0x12, 0x21, 0x9a, 0xf1, 0xaa, 0xcf, 0xaa, 0xc9, 0x9b, 0x4d, 0xea,
+        0xf8, 0xad,
+    ],
+    pk_recip: &[
+        0x04, 0x00, 0xf2, 0x09, 0xb1, 0xbf, 0x3b, 0x35, 0xb4, 0x05, 0xd7, 0x50, 0xef, 0x57, 0x7d,
+        0x0b, 0x2d, 0xc8, 0x17, 0x84, 0x00, 0x5d, 0x1c, 0x67, 0xff, 0x4f, 0x6d, 0x28, 0x60, 0xd7,
+        0x64, 0x0c, 0xa3, 0x79, 0xe2, 0x2a, 0xc7, 0xfa, 0x10, 0x5d, 0x94, 0xbc, 0x19, 0x57, 0x58,
+        0xf4, 0xdf, 0xc0, 0xb8, 0x22, 0x52, 0x09, 0x8a, 0x83, 0x50, 0xc1, 0xbf, 0xed, 0xa8, 0x27,
+        0x5c, 0xe4, 0xdd, 0x42, 0x62,
+    ],
+    ciphertext: &[
+        0x94, 0x9f, 0x58, 0xe8, 0x7c, 0x39, 0xb3, 0xf5, 0x53, 0x90, 0xb6, 0xa9, 0x70, 0xde, 0x27,
+        0xdf, 0xac, 0x44, 0xaa, 0xdc, 0x2f, 0xbc, 0x9d, 0x62, 0x3d, 0xcd, 0xe1, 0xa0, 0x8b, 0x62,
+        0x8c, 0x83, 0xad, 0x07, 0xdb, 0xbe, 0xe6, 0xae, 0xde, 0x7f, 0xcf, 0xbf, 0x95, 0x56, 0x70,
+    ],
+};
+
+#[cfg(feature = "p256")]
+const P256_SHA512_CHACHA: Kat = Kat {
+    ikm_eph: &[
+        0x02, 0xbd, 0x2b, 0xdb, 0xb4, 0x30, 0xc0, 0x30, 0x0c, 0xea, 0x89, 0xb3, 0x7a, 0xda, 0x70,
+        0x62, 0x06, 0xa9, 0xa7, 0x4e, 0x48, 0x81, 0x62, 0x67, 0x1d, 0x1f, 0xf6, 0x8b, 0x24, 0xde,
+        0xeb, 0x5f,
+    ],
+    pk_recip: &[
+        0x04, 0x8f, 0xed, 0x80, 0x8e, 0x94, 0x8d, 0x46, 0xd9, 0x5f, 0x77, 0x8b, 0xd4, 0x52, 0x36,
+        0xce, 0x0c, 0x46, 0x45, 0x67, 0xa1, 0xdc, 0x6f, 0x14, 0x8b, 0xa7, 0x1d, 0xc5, 0xae, 0xff,
+        0x2a, 0xd5, 0x2a, 0x43, 0xc7, 0x18, 0x51, 0xb9, 0x9a, 0x2c, 0xdb, 0xf1, 0xda, 0xd6, 0x8d,
+        0x00, 0xba, 0xad, 0x45, 0x00, 0x7e, 0x0a, 0xf4, 0x43, 0xff, 0x80, 0xad, 0x1b, 0x55, 0x32,
+        0x2c, 0x65, 0x8b, 0x73, 0x72,
+    ],
+    ciphertext: &[
+        0x81, 0xa1, 0xf5, 0x43, 0x72, 0x91, 0x3f, 0x6d, 0xd8, 0x8f, 0x45, 0xd7, 0x88, 0x9d, 0xab,
+        0x17, 0x49, 0x42, 0xba, 0xef, 0x7b, 0x1f, 0x3a, 0x32, 0xee, 0x42, 0x05, 0x8b, 0xd4, 0xb5,
+        0xca, 0x5e, 0x83, 0x23, 0x30, 0x14, 0x20, 0xb9, 0xe3, 0xf3, 0xc7, 0xb5, 0x6f, 0xa8, 0xb4,
+    ],
+};
+
+/// Seals `PLAINTEXT` in Base mode with the given ephemeral key and recipient, and checks that the
+/// output is the expected ciphertext
+#[cfg(any(feature = "x25519-dalek", feature = "p256"))]
+fn run_kat<A: Aead, Kdf: KdfTrait, Kem: KemTrait>(kat: &Kat) -> Result<(), SelfTestError> {
+    let err = SelfTestError {
+        kem_id: Kem::KEM_ID,
+        kdf_id: Kdf::KDF_ID,
+        aead_id: A::AEAD_ID,
+    };
+
+    // Do the setup deterministically, by deriving the ephemeral keypair from the given IKM
+    let pk_recip =
+        <Kem::Kex as KeyExchange>::PublicKey::from_bytes(kat.pk_recip).map_err(|_| err)?;
+    let (sk_eph, _) = Kem::derive_keypair(kat.ikm_eph).map_err(|_| err)?;
+    let (shared_secret, _) =
+        kem::encap_with_eph::<Kem>(&pk_recip, None, sk_eph).map_err(|_| err)?;
+    let mut ctx: AeadCtxS<A, Kdf, Kem> =
+        derive_enc_ctx::<A, Kdf, Kem, _>(&OpModeS::Base, shared_secret, INFO).into();
+
+    // Seal and compare to the expected ciphertext || tag
+    let mut msg = *PLAINTEXT;
+    let tag = ctx.seal(&mut msg, AAD).map_err(|_| err)?;
+    let tag_bytes = tag.to_bytes();
+    let (expected_msg, expected_tag) = kat.ciphertext.split_at(msg.len().min(kat.ciphertext.len()));
+    if expected_msg == msg && expected_tag == tag_bytes.as_slice() {
+        Ok(())
+    } else {
+        Err(err)
+    }
+}
+
+/// Runs a known-answer test for every ciphersuite that's compiled in
+///
+/// Return Value
+/// ============
+/// Returns `Ok(())` if every test passes. Otherwise, returns `Err(e)`, where `e` identifies the
+/// first suite that failed.
+pub fn run_self_tests() -> Result<(), SelfTestError> {
+    #[cfg(feature = "x25519-dalek")]
+    {
+        run_kat::<AesGcm128, HkdfSha256, X25519HkdfSha256>(&X25519_SHA256_AES128)?;
+        run_kat::<AesGcm256, HkdfSha256, X25519HkdfSha256>(&X25519_SHA256_AES256)?;
+        run_kat::<ChaCha20Poly1305, HkdfSha256, X25519HkdfSha256>(&X25519_SHA256_CHACHA)?;
+        run_kat::<AesGcm128, HkdfSha512, X25519HkdfSha256>(&X25519_SHA512_AES128)?;
+        run_kat::<AesGcm256, HkdfSha512, X25519HkdfSha256>(&X25519_SHA512_AES256)?;
+        run_kat::<ChaCha20Poly1305, HkdfSha512, X25519HkdfSha256>(&X25519_SHA512_CHACHA)?;
+    }
+    #[cfg(feature = "p256")]
+    {
+        run_kat::<AesGcm128, HkdfSha256, DhP256HkdfSha256>(&P256_SHA256_AES128)?;
+        run_kat::<AesGcm256, HkdfSha256, DhP256HkdfSha256>(&P256_SHA256_AES256)?;
+        run_kat::<ChaCha20Poly1305, HkdfSha256, DhP256HkdfSha256>(&P256_SHA256_CHACHA)?;
+        run_kat::<AesGcm128, HkdfSha512, DhP256HkdfSha256>(&P256_SHA512_AES128)?;
+        run_kat::<AesGcm256, HkdfSha512, DhP256HkdfSha256>(&P256_SHA512_AES256)?;
+        run_kat::<ChaCha20Poly1305, HkdfSha512, DhP256HkdfSha256>(&P256_SHA512_CHACHA)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::run_self_tests;
+
+    /// Tests that the self-tests pass on this build
+    #[test]
+    fn test_self_tests_pass() {
+        assert_eq!(run_self_tests(), Ok(()));
+    }
+
+    /// Tests that a tampered expected ciphertext is detected, and that the error names the suite
+    #[cfg(feature = "x25519-dalek")]
+    #[test]
+    fn test_self_test_detects_tampering() {
+        use super::{run_kat, Kat, SelfTestError, X25519_SHA256_AES128};
+        use crate::{aead::AesGcm128, kdf::HkdfSha256, kem::X25519HkdfSha256};
+
+        // Flip a bit in the ciphertext, and then in the tag
+        for &idx in &[0, X25519_SHA256_AES128.ciphertext.len() - 1] {
+            let mut tampered_ct = [0u8; 45];
+            tampered_ct.copy_from_slice(X25519_SHA256_AES128.ciphertext);
+            tampered_ct[idx] ^= 1;
+            let tampered_kat = Kat {
+                ciphertext: &tampered_ct,
+                ..X25519_SHA256_AES128
+            };
+
+            assert_eq!(
+                run_kat::<AesGcm128, HkdfSha256, X25519HkdfSha256>(&tampered_kat),
+                Err(SelfTestError {
+                    kem_id: 0x0020,
+                    kdf_id: 0x0001,
+                    aead_id: 0x0001,
+                })
+            );
+        }
+    }
+}
@@ -1,18 +1,48 @@
 use crate::{
-    aead::{Aead, AeadCtx, AeadCtxR, AeadCtxS},
-    kdf::{labeled_extract, Kdf as KdfTrait, LabeledExpand, MAX_DIGEST_SIZE},
+    aead::{Aead, AeadCtx, AeadCtxR, AeadCtxS, AeadKey, AeadNonce, ExportOnlyAead},
+    kdf::{
+        labeled_extract, labeled_extract_with_version, Kdf as KdfTrait, LabeledExpand, Prk,
+        MAX_DIGEST_SIZE,
+    },
     kem::{self, EncappedKey, Kem as KemTrait, SharedSecret},
     kex::KeyExchange,
-    op_mode::{OpMode, OpModeR, OpModeS},
-    util::full_suite_id,
-    HpkeError,
+    labels::LabelConfig,
+    op_mode::{OpMode, OpModeR, OpModeS, PskBundle},
+    trace, HpkeError,
 };
 
-use digest::Digest;
+#[cfg(feature = "raw-shared-secret")]
+use crate::util::enforce_equal_len;
+
+#[cfg(feature = "test-internals")]
+use crate::kdf::VERSION_LABEL;
+#[cfg(feature = "test-internals")]
+use aead::{AeadCore, NewAead};
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use digest::{Digest, FixedOutput};
 use generic_array::GenericArray;
 use rand_core::{CryptoRng, RngCore};
 use zeroize::Zeroize;
 
+/// The maximum length of the `info` string that `setup_sender` and `setup_receiver` will accept. A
+/// huge `info` is almost certainly a logic error, and makes the key schedule slow. To bind a longer
+/// string, use `setup_sender_hashed_info` and `setup_receiver_hashed_info`.
+pub const MAX_INFO_LEN: usize = 64 * 1024;
+
+/// Returns `Err(HpkeError::IncorrectInputLength { expected: MAX_INFO_LEN, got: info.len() })` if
+/// `info` is longer than `MAX_INFO_LEN`
+fn check_info_len(info: &[u8]) -> Result<(), HpkeError> {
+    if info.len() > MAX_INFO_LEN {
+        Err(HpkeError::IncorrectInputLength {
+            expected: MAX_INFO_LEN,
+            got: info.len(),
+        })
+    } else {
+        Ok(())
+    }
+}
+
 /// Secret generated in `derive_enc_ctx` and stored in `AeadCtx`
 pub(crate) struct ExporterSecret<K: KdfTrait>(
     pub(crate) GenericArray<u8, <K::HashImpl as Digest>::OutputSize>,
@@ -56,9 +86,30 @@ impl<K: KdfTrait> Drop for ExporterSecret<K> {
 //
 //   return Context<ROLE>(key, base_nonce, 0, exporter_secret)
 
+/// The intermediate values of the key schedule, as named in draft11 §5.1. This is only for
+/// cross-checking against test vectors.
+#[cfg(feature = "test-internals")]
+#[doc(hidden)]
+pub struct KeyScheduleDebug<A: Aead, Kdf: KdfTrait, Kem: KemTrait> {
+    pub shared_secret: SharedSecret<Kem>,
+    pub key_schedule_context: Vec<u8>,
+    pub secret: GenericArray<u8, <Kdf::HashImpl as FixedOutput>::OutputSize>,
+    pub key: GenericArray<u8, <A::AeadImpl as NewAead>::KeySize>,
+    pub base_nonce: GenericArray<u8, <A::AeadImpl as AeadCore>::NonceSize>,
+    pub exporter_secret: GenericArray<u8, <Kdf::HashImpl as Digest>::OutputSize>,
+}
+
+/// The output of the key schedule, along with the intermediate `secret`
+type KeyScheduleOutput<A, Kdf> = (
+    GenericArray<u8, <<Kdf as KdfTrait>::HashImpl as FixedOutput>::OutputSize>,
+    AeadKey<A>,
+    AeadNonce<A>,
+    ExporterSecret<Kdf>,
+);
+
 // This is the KeySchedule function. It runs a KDF over all the parameters, inputs, and secrets,
 // and spits out a key-nonce pair to be used for symmetric encryption.
-fn derive_enc_ctx<A, Kdf, Kem, O>(
+pub(crate) fn derive_enc_ctx<A, Kdf, Kem, O>(
     mode: &O,
     shared_secret: SharedSecret<Kem>,
     info: &[u8],
@@ -69,30 +120,104 @@ where
     Kem: KemTrait,
     O: OpMode<Kem::Kex>,
 {
-    // Put together the binding context used for all KDF operations
-    let suite_id = full_suite_id::<A, Kdf, Kem>();
+    derive_enc_ctx_with_labels(mode, shared_secret, info, &LabelConfig::HPKE)
+}
+
+// Does derive_enc_ctx with the given labels in place of HPKE's
+pub(crate) fn derive_enc_ctx_with_labels<A, Kdf, Kem, O>(
+    mode: &O,
+    shared_secret: SharedSecret<Kem>,
+    info: &[u8],
+    labels: &LabelConfig,
+) -> AeadCtx<A, Kdf, Kem>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    O: OpMode<Kem::Kex>,
+{
+    let (mut secret, key, base_nonce, exporter_secret) =
+        key_schedule::<A, Kdf, Kem, O>(mode, &shared_secret, info, labels);
+    secret.zeroize();
+
+    // An all-zero key or base nonce can only come out of a broken key schedule. Catch that in
+    // tests rather than quietly encrypting under it. Export-only contexts leave both zeroed on
+    // purpose, so they're exempt.
+    if A::AEAD_ID != ExportOnlyAead::AEAD_ID {
+        debug_assert!(
+            key.0.iter().any(|&b| b != 0),
+            "key schedule derived an all-zero AEAD key"
+        );
+        debug_assert!(
+            base_nonce.0.iter().any(|&b| b != 0),
+            "key schedule derived an all-zero base nonce"
+        );
+    }
+
+    AeadCtx::new_with_labels(&key, base_nonce, exporter_secret, labels)
+}
 
+/// The longest a `key_schedule_context` can be. Each of its 3 parts fits in `MAX_DIGEST_SIZE`.
+const MAX_SCHED_CONTEXT_SIZE: usize = 3 * MAX_DIGEST_SIZE;
+
+/// Computes `key_schedule_context`. Returns a buffer and the length of the context in that
+/// buffer.
+fn key_schedule_context<Kdf, Kem, O>(
+    mode: &O,
+    info: &[u8],
+    version_label: &[u8],
+    suite_id: &[u8],
+) -> ([u8; MAX_SCHED_CONTEXT_SIZE], usize)
+where
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    O: OpMode<Kem::Kex>,
+{
     // In KeySchedule(),
     //   psk_id_hash = LabeledExtract("", "psk_id_hash", psk_id)
     //   info_hash = LabeledExtract("", "info_hash", info)
     //   key_schedule_context = concat(mode, psk_id_hash, info_hash)
 
+    let (psk_id_hash, _) = labeled_extract_with_version::<Kdf>(
+        version_label,
+        &[],
+        suite_id,
+        b"psk_id_hash",
+        mode.get_psk_id(),
+    );
+    let (info_hash, _) =
+        labeled_extract_with_version::<Kdf>(version_label, &[], suite_id, b"info_hash", info);
+
     // We concat without allocation by making a buffer of the maximum possible size, then
-    // taking the appropriately sized slice.
-    let (sched_context_buf, sched_context_size) = {
-        let (psk_id_hash, _) =
-            labeled_extract::<Kdf>(&[], &suite_id, b"psk_id_hash", mode.get_psk_id());
-        let (info_hash, _) = labeled_extract::<Kdf>(&[], &suite_id, b"info_hash", info);
-
-        // Yes it's overkill to bound the first input by MAX_DIGEST_SIZE, since it's only 1 byte.
-        // But whatever, this is pretty clean.
-        concat_with_known_maxlen!(
-            MAX_DIGEST_SIZE,
-            &[mode.mode_id()],
-            psk_id_hash.as_slice(),
-            info_hash.as_slice()
-        )
-    };
+    // taking the appropriately sized slice. Yes it's overkill to bound the first input by
+    // MAX_DIGEST_SIZE, since it's only 1 byte. But whatever, this is pretty clean.
+    concat_with_known_maxlen!(
+        MAX_DIGEST_SIZE,
+        &[mode.mode_id()],
+        psk_id_hash.as_slice(),
+        info_hash.as_slice()
+    )
+}
+
+// Does the work of derive_enc_ctx, and also returns the intermediate `secret`
+fn key_schedule<A, Kdf, Kem, O>(
+    mode: &O,
+    shared_secret: &SharedSecret<Kem>,
+    info: &[u8],
+    labels: &LabelConfig,
+) -> KeyScheduleOutput<A, Kdf>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    O: OpMode<Kem::Kex>,
+{
+    // Put together the binding context used for all KDF operations
+    let suite_id = labels.full_suite_id::<A, Kdf, Kem>();
+    let version_label = labels.version_label;
+
+    let (sched_context_buf, sched_context_size) =
+        key_schedule_context::<Kdf, Kem, O>(mode, info, version_label, &suite_id);
     let sched_context = &sched_context_buf[..sched_context_size];
 
     // In KeySchedule(),
@@ -100,32 +225,49 @@ where
     //   key = LabeledExpand(secret, "key", key_schedule_context, Nk)
     //   base_nonce = LabeledExpand(secret, "base_nonce", key_schedule_context, Nn)
     //   exporter_secret = LabeledExpand(secret, "exp", key_schedule_context, Nh)
-    // Instead of `secret` we derive an HKDF context which we run .expand() on to derive the
+    // Along with `secret` we derive an HKDF context which we run .expand() on to derive the
     // key-nonce pair.
-    let (_, secret_ctx) =
-        labeled_extract::<Kdf>(&shared_secret, &suite_id, b"secret", mode.get_psk_bytes());
+    let (secret, secret_ctx) = labeled_extract_with_version::<Kdf>(
+        version_label,
+        &shared_secret.0,
+        &suite_id,
+        b"secret",
+        mode.get_psk_bytes(),
+    );
 
     // Empty fixed-size buffers
-    let mut key = crate::aead::AeadKey::<A>::default();
-    let mut base_nonce = crate::aead::AeadNonce::<A>::default();
+    let mut key = AeadKey::<A>::default();
+    let mut base_nonce = AeadNonce::<A>::default();
     let mut exporter_secret = <ExporterSecret<Kdf> as Default>::default();
 
     // Fill the key, base nonce, and exporter secret. This only errors if the output values are
     // 255x the digest size of the hash function. Since these values are fixed at compile time, we
     // don't worry about it.
+    // An export-only context never uses its key or base nonce, so we skip deriving them and leave
+    // them zeroed. The exporter secret doesn't depend on them, so exports are unaffected.
+    if A::AEAD_ID != ExportOnlyAead::AEAD_ID {
+        secret_ctx
+            .labeled_expand_with_version(
+                version_label,
+                &suite_id,
+                b"key",
+                sched_context,
+                key.0.as_mut_slice(),
+            )
+            .expect("aead key len is way too big");
+        secret_ctx
+            .labeled_expand_with_version(
+                version_label,
+                &suite_id,
+                b"base_nonce",
+                sched_context,
+                base_nonce.0.as_mut_slice(),
+            )
+            .expect("nonce len is way too big");
+    }
     secret_ctx
-        .labeled_expand(&suite_id, b"key", sched_context, key.0.as_mut_slice())
-        .expect("aead key len is way too big");
-    secret_ctx
-        .labeled_expand(
-            &suite_id,
-            b"base_nonce",
-            sched_context,
-            base_nonce.0.as_mut_slice(),
-        )
-        .expect("nonce len is way too big");
-    secret_ctx
-        .labeled_expand(
+        .labeled_expand_with_version(
+            version_label,
             &suite_id,
             b"exp",
             sched_context,
@@ -133,7 +275,7 @@ where
         )
         .expect("exporter secret len is way too big");
 
-    AeadCtx::new(&key, base_nonce, exporter_secret)
+    (secret, key, base_nonce, exporter_secret)
 }
 
 // draft11 §5.1.4:
@@ -147,8 +289,9 @@ where
 /// Return Value
 /// ============
 /// On success, returns an encapsulated public key (intended to be sent to the recipient), and an
-/// encryption context. If an error happened during key encapsulation, returns
-/// `Err(HpkeError::EncapError)`. This is the only possible error.
+/// encryption context. If `info` is longer than `MAX_INFO_LEN`, returns
+/// `Err(HpkeError::IncorrectInputLength { expected: MAX_INFO_LEN, got: info.len() })`. If an error
+/// happened during key encapsulation, returns `Err(HpkeError::EncapError)`.
 pub fn setup_sender<A, Kdf, Kem, R>(
     mode: &OpModeS<Kem::Kex>,
     pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
@@ -161,185 +304,2183 @@ where
     Kem: KemTrait,
     R: CryptoRng + RngCore,
 {
-    // If the identity key is set, use it
-    let sender_id_keypair = mode.get_sender_id_keypair();
-    // Do the encapsulation
-    let (shared_secret, encapped_key) = kem::encap::<Kem, _>(pk_recip, sender_id_keypair, csprng)?;
-    // Use everything to derive an encryption context
-    let enc_ctx = derive_enc_ctx::<_, _, Kem, _>(mode, shared_secret, info);
-
+    let sk_eph = kem::gen_eph_privkey::<Kem, R>(csprng)?;
+    let (encapped_key, enc_ctx) =
+        setup_sender_inner::<A, Kdf, Kem>(mode, pk_recip, info, &LabelConfig::HPKE, &[], sk_eph)?;
     Ok((encapped_key, enc_ctx.into()))
 }
 
-// draft11 §5.1.4
-// def SetupAuthPSKR(enc, skR, info, psk, psk_id, pkS):
-//   shared_secret = AuthDecap(enc, skR, pkS)
-//   return KeyScheduleR(mode_auth_psk, shared_secret, info,
-//                       psk, psk_id)
-
-/// Initiates a decryption context given a private key `sk_recip` and an encapsulated key which
-/// was encapsulated to `sk_recip`'s corresponding public key
+/// Does `setup_sender`, but with the version label and `suite_id` prefix of `labels` in place of
+/// HPKE's, in the key schedule and in the returned context. With `LabelConfig::HPKE`, this is
+/// exactly `setup_sender`.
+///
+/// **WARNING:** With any other labels, this is not HPKE. Only a receiver using
+/// `setup_receiver_with_labels` with the same labels can talk to this sender.
 ///
 /// Return Value
 /// ============
-/// On success, returns a decryption context. If an error happened during key decapsulation,
-/// returns `Err(HpkeError::DecapError)`. This is the only possible error.
-pub fn setup_receiver<A, Kdf, Kem>(
-    mode: &OpModeR<Kem::Kex>,
-    sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
-    encapped_key: &EncappedKey<Kem::Kex>,
+/// Same as `setup_sender`
+#[cfg(feature = "experimental-labels")]
+pub fn setup_sender_with_labels<A, Kdf, Kem, R>(
+    labels: &LabelConfig,
+    mode: &OpModeS<Kem::Kex>,
+    pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
     info: &[u8],
-) -> Result<AeadCtxR<A, Kdf, Kem>, HpkeError>
+    csprng: &mut R,
+) -> Result<(EncappedKey<Kem::Kex>, AeadCtxS<A, Kdf, Kem>), HpkeError>
 where
     A: Aead,
     Kdf: KdfTrait,
     Kem: KemTrait,
+    R: CryptoRng + RngCore,
 {
-    // If the identity key is set, use it
-    let pk_sender_id: Option<&<Kem::Kex as KeyExchange>::PublicKey> = mode.get_pk_sender_id();
-    // Do the decapsulation
-    let shared_secret = kem::decap::<Kem>(sk_recip, pk_sender_id, encapped_key)?;
+    let sk_eph = kem::gen_eph_privkey::<Kem, R>(csprng)?;
+    let (encapped_key, enc_ctx) =
+        setup_sender_inner::<A, Kdf, Kem>(mode, pk_recip, info, labels, &[], sk_eph)?;
+    Ok((encapped_key, enc_ctx.into()))
+}
 
-    // Use everything to derive an encryption context
-    let enc_ctx = derive_enc_ctx::<_, _, Kem, _>(mode, shared_secret, info);
-    Ok(enc_ctx.into())
+/// Does `setup_sender`, but with `extra_kem_context` appended to the `kem_context` that the KEM
+/// derives the shared secret from. This binds the encapsulation itself to some external context,
+/// such as the group state of a layered protocol. It is separate from the AEAD's AAD. With an
+/// empty `extra_kem_context`, this is exactly `setup_sender`.
+///
+/// **WARNING:** With a non-empty `extra_kem_context`, this is not HPKE. Only a receiver using
+/// `setup_receiver_with_kem_context` with the same context can talk to this sender.
+///
+/// Return Value
+/// ============
+/// Same as `setup_sender`
+#[cfg(feature = "experimental-kem-context")]
+pub fn setup_sender_with_kem_context<A, Kdf, Kem, R>(
+    extra_kem_context: &[u8],
+    mode: &OpModeS<Kem::Kex>,
+    pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
+    info: &[u8],
+    csprng: &mut R,
+) -> Result<(EncappedKey<Kem::Kex>, AeadCtxS<A, Kdf, Kem>), HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    let sk_eph = kem::gen_eph_privkey::<Kem, R>(csprng)?;
+    let (encapped_key, enc_ctx) = setup_sender_inner::<A, Kdf, Kem>(
+        mode,
+        pk_recip,
+        info,
+        &LabelConfig::HPKE,
+        extra_kem_context,
+        sk_eph,
+    )?;
+    Ok((encapped_key, enc_ctx.into()))
 }
 
-#[cfg(test)]
-mod test {
-    use super::{setup_receiver, setup_sender};
-    use crate::test_util::{aead_ctx_eq, gen_rand_buf, new_op_mode_pair, OpModeKind};
-    use crate::{aead::ChaCha20Poly1305, kdf::HkdfSha256, kem::Kem as KemTrait};
+/// Hashes `info` for `setup_sender_hashed_info` and `setup_receiver_hashed_info`. This is
+/// `LabeledExtract("", "info_hash", info)`, which is how the key schedule itself hashes `info`.
+fn hash_info<A: Aead, Kdf: KdfTrait, Kem: KemTrait>(info: &[u8]) -> Prk<Kdf> {
+    let suite_id = LabelConfig::HPKE.full_suite_id::<A, Kdf, Kem>();
+    labeled_extract::<Kdf>(&[], &suite_id, b"info_hash", info).0
+}
 
-    use rand::{rngs::StdRng, SeedableRng};
+/// Does `setup_sender` with `LabeledExtract("", "info_hash", info)` in place of `info` itself.
+/// This is for an `info` too big to run through the key schedule or to send along, such as a
+/// whole policy document. Both sides then only need the hash.
+///
+/// **WARNING:** This diverges from RFC 9180, which puts `info` into the key schedule unhashed.
+/// The receiver must use `setup_receiver_hashed_info`, or equivalently `setup_receiver` with the
+/// hash as its `info`. Both sides have to agree beforehand to do this.
+///
+/// Return Value
+/// ============
+/// Same as `setup_sender`, except that `info` can be any length
+pub fn setup_sender_hashed_info<A, Kdf, Kem, R>(
+    mode: &OpModeS<Kem::Kex>,
+    pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
+    info: &[u8],
+    csprng: &mut R,
+) -> Result<(EncappedKey<Kem::Kex>, AeadCtxS<A, Kdf, Kem>), HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    let info_hash = hash_info::<A, Kdf, Kem>(info);
+    setup_sender::<A, Kdf, Kem, R>(mode, pk_recip, &info_hash, csprng)
+}
 
-    /// This tests that `setup_sender` and `setup_receiver` derive the same context. We do this by
-    /// testing that `gen_ctx_kem_pair` returns identical encryption contexts
-    macro_rules! test_setup_correctness {
-        ($test_name:ident, $aead_ty:ty, $kdf_ty:ty, $kem_ty:ty) => {
-            #[test]
-            fn $test_name() {
-                type A = $aead_ty;
-                type Kdf = $kdf_ty;
-                type Kem = $kem_ty;
-                type Kex = <Kem as KemTrait>::Kex;
+/// A value that stands for an `info` string. Implement this for an enum of a protocol's labels,
+/// so that the sender and receiver get their `info` bytes from the same place, and then use
+/// `setup_sender_with_info` and `setup_receiver_with_info`. This is also implemented for `&[u8]`
+/// and `&[u8; N]`, so raw bytes and byte string literals work too.
+///
+/// ```
+/// # #[cfg(feature = "x25519")]
+/// # {
+/// # use rand::{rngs::StdRng, SeedableRng};
+/// use hpke::{
+///     aead::ChaCha20Poly1305, kdf::HkdfSha256, kem::X25519HkdfSha256, setup_receiver_with_info,
+///     setup_sender_with_info, IntoInfo, Kem as KemTrait, OpModeR, OpModeS,
+/// };
+///
+/// enum Label {
+///     Upload,
+///     Download,
+/// }
+///
+/// impl IntoInfo for Label {
+///     fn info_bytes(&self) -> &[u8] {
+///         match self {
+///             Label::Upload => b"example.com upload v1",
+///             Label::Download => b"example.com download v1",
+///         }
+///     }
+/// }
+///
+/// type Kem = X25519HkdfSha256;
+/// # let mut csprng = StdRng::from_entropy();
+/// let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+/// let (encapped_key, _) = setup_sender_with_info::<ChaCha20Poly1305, HkdfSha256, Kem, _>(
+///     &OpModeS::Base,
+///     &pk_recip,
+///     Label::Upload,
+///     &mut csprng,
+/// )
+/// .unwrap();
+/// let _ = setup_receiver_with_info::<ChaCha20Poly1305, HkdfSha256, Kem>(
+///     &OpModeR::Base,
+///     &sk_recip,
+///     &encapped_key,
+///     Label::Upload,
+/// )
+/// .unwrap();
+/// # }
+/// ```
+pub trait IntoInfo {
+    /// Returns the `info` string this value stands for
+    fn info_bytes(&self) -> &[u8];
+}
 
-                let mut csprng = StdRng::from_entropy();
+impl IntoInfo for &[u8] {
+    fn info_bytes(&self) -> &[u8] {
+        self
+    }
+}
 
-                let info = b"why would you think in a million years that that would actually work";
+// So that byte string literals like b"info" work without a cast
+impl<const N: usize> IntoInfo for &[u8; N] {
+    fn info_bytes(&self) -> &[u8] {
+        &self[..]
+    }
+}
 
-                // Generate the receiver's long-term keypair
-                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+/// Does `setup_sender` with `info.info_bytes()` as the `info` string
+///
+/// Return Value
+/// ============
+/// Same as `setup_sender`
+pub fn setup_sender_with_info<A, Kdf, Kem, R>(
+    mode: &OpModeS<Kem::Kex>,
+    pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
+    info: impl IntoInfo,
+    csprng: &mut R,
+) -> Result<(EncappedKey<Kem::Kex>, AeadCtxS<A, Kdf, Kem>), HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    setup_sender::<A, Kdf, Kem, R>(mode, pk_recip, info.info_bytes(), csprng)
+}
 
-                // Try a full setup for all the op modes
-                for op_mode_kind in &[
-                    OpModeKind::Base,
-                    OpModeKind::Auth,
-                    OpModeKind::Psk,
-                    OpModeKind::AuthPsk,
-                ] {
-                    // Generate a mutually agreeing op mode pair
-                    let (psk, psk_id) = (gen_rand_buf(), gen_rand_buf());
-                    let (sender_mode, receiver_mode) =
-                        new_op_mode_pair::<Kex, Kdf>(*op_mode_kind, &psk, &psk_id);
+/// Does `setup_sender` with `info || pk_recip` in place of `info`, where `pk_recip` is serialized
+/// with `to_bytes`. This is a hardening variant that binds the recipient's identity into the key
+/// schedule, as well as into the KEM, so that a context can't be reinterpreted as being for some
+/// other recipient. This matters for KEMs that don't bind the recipient's public key themselves,
+/// and for protocols that carry `info` around separately. The DHKEMs in this crate already put
+/// `pk_recip` into their `kem_context`.
+///
+/// **WARNING:** This diverges from RFC 9180. The receiver must use
+/// `setup_receiver_bind_recipient`, or equivalently `setup_receiver` with `info || pk_recip` as
+/// its `info`.
+///
+/// Return Value
+/// ============
+/// Same as `setup_sender`, except that the length limit applies to `info || pk_recip`
+#[cfg(feature = "alloc")]
+pub fn setup_sender_bind_recipient<A, Kdf, Kem, R>(
+    mode: &OpModeS<Kem::Kex>,
+    pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
+    info: &[u8],
+    csprng: &mut R,
+) -> Result<(EncappedKey<Kem::Kex>, AeadCtxS<A, Kdf, Kem>), HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    let bound_info = info_with_recipient::<Kem::Kex>(info, pk_recip);
+    setup_sender::<A, Kdf, Kem, R>(mode, pk_recip, &bound_info, csprng)
+}
 
-                    // Construct the sender's encryption context, and get an encapped key
-                    let (encapped_key, mut aead_ctx1) = setup_sender::<A, Kdf, Kem, _>(
-                        &sender_mode,
-                        &pk_recip,
-                        &info[..],
-                        &mut csprng,
-                    )
-                    .unwrap();
+/// Does `setup_sender` with an ephemeral keypair derived from `ephemeral_ikm` by the KEM's
+/// `DeriveKeyPair`, instead of one generated from an RNG. Running this twice with the same inputs
+/// gives the same encapsulated key and the same context, so a retried send produces byte-identical
+/// ciphertexts, and a receiver can spot duplicates. `ephemeral_ikm` should have as much entropy as
+/// `Kem::derive_keypair` asks for, and must be kept as secret as a private key.
+///
+/// **WARNING:** The context's nonces only depend on the sequence number. If the same
+/// `ephemeral_ikm`, recipient, mode, and `info` are ever used to seal a *different* sequence of
+/// messages, the same key and nonce encrypt two different plaintexts, which breaks both the
+/// confidentiality and the integrity of the AEAD. Only reuse `ephemeral_ikm` to re-send exactly
+/// the same messages, in the same order, with the same AADs. This also gives up the forward
+/// secrecy that a fresh ephemeral key provides.
+///
+/// Return Value
+/// ============
+/// Same as `setup_sender`
+pub fn setup_sender_idempotent<A, Kdf, Kem>(
+    mode: &OpModeS<Kem::Kex>,
+    pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
+    info: &[u8],
+    ephemeral_ikm: &[u8],
+) -> Result<(EncappedKey<Kem::Kex>, AeadCtxS<A, Kdf, Kem>), HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    let (sk_eph, _) = Kem::derive_keypair(ephemeral_ikm)?;
+    let (encapped_key, enc_ctx) =
+        setup_sender_inner::<A, Kdf, Kem>(mode, pk_recip, info, &LabelConfig::HPKE, &[], sk_eph)?;
+    Ok((encapped_key, enc_ctx.into()))
+}
 
-                    // Use the encapped key to derive the reciever's encryption context
-                    let mut aead_ctx2 = setup_receiver::<A, Kdf, Kem>(
-                        &receiver_mode,
-                        &sk_recip,
-                        &encapped_key,
-                        &info[..],
-                    )
-                    .unwrap();
+// The body of setup_sender, with the ephemeral private key already picked. This returns the bare
+// AeadCtx, so it can be wrapped in something other than an AeadCtxS. extra_kem_context is appended
+// to the KEM's kem_context, and is empty in HPKE.
+fn setup_sender_inner<A, Kdf, Kem>(
+    mode: &OpModeS<Kem::Kex>,
+    pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
+    info: &[u8],
+    labels: &LabelConfig,
+    extra_kem_context: &[u8],
+    sk_eph: <Kem::Kex as KeyExchange>::PrivateKey,
+) -> Result<(EncappedKey<Kem::Kex>, AeadCtx<A, Kdf, Kem>), HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    check_info_len(info)?;
 
-                    // Ensure that the two derived contexts are equivalent
-                    assert!(aead_ctx_eq(&mut aead_ctx1, &mut aead_ctx2));
-                }
-            }
-        };
-    }
+    let suite_ids = (Kem::KEM_ID, Kdf::KDF_ID, A::AEAD_ID);
+    trace::setup_start("sender", suite_ids);
 
-    /// Tests that using different input data gives you different encryption contexts
-    macro_rules! test_setup_soundness {
-        ($test_name:ident, $aead:ty, $kdf:ty, $kem:ty) => {
-            #[test]
-            fn $test_name() {
-                type A = $aead;
-                type Kdf = $kdf;
-                type Kem = $kem;
-                type Kex = <Kem as KemTrait>::Kex;
+    // If the identity key is set, use it
+    let sender_id_keypair = mode.get_sender_id_keypair();
+    // Do the encapsulation
+    let encap_res = kem::encap_with_eph_and_extra::<Kem>(
+        pk_recip,
+        sender_id_keypair,
+        sk_eph,
+        extra_kem_context,
+    );
+    trace::setup_end("sender", suite_ids, &encap_res);
+    let (shared_secret, encapped_key) = encap_res?;
+    // Use everything to derive an encryption context
+    let mut enc_ctx = derive_enc_ctx_with_labels::<_, _, Kem, _>(mode, shared_secret, info, labels);
+    enc_ctx.bind_enc(&encapped_key);
 
-                let mut csprng = StdRng::from_entropy();
+    Ok((encapped_key, enc_ctx))
+}
 
-                let info = b"why would you think in a million years that that would actually work";
+/// The shared secret of an encapsulation done by `encap_only`, waiting to be turned into an
+/// encryption context by `complete_setup`. This is zeroed on drop.
+pub struct EncapSecret<Kem: KemTrait>(SharedSecret<Kem>);
 
-                // Generate the receiver's long-term keypair
-                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+impl<Kem: KemTrait> Drop for EncapSecret<Kem> {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
 
-                // Generate a mutually agreeing op mode pair
+/// Does the key encapsulation half of `setup_sender`, without the key schedule. This is the
+/// expensive part of setup, so it can be done before the mode and `info` string are known. Finish
+/// the setup with `complete_setup`.
+///
+/// Since the encapsulation is not authenticated, it can only be completed in `Base` or `Psk`
+/// mode.
+///
+/// Return Value
+/// ============
+/// On success, returns the shared secret and an encapsulated public key (intended to be sent to
+/// the recipient). If an error happened during key encapsulation, returns
+/// `Err(HpkeError::EncapError)`.
+pub fn encap_only<Kem, R>(
+    pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
+    csprng: &mut R,
+) -> Result<(EncapSecret<Kem>, EncappedKey<Kem::Kex>), HpkeError>
+where
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    let (shared_secret, encapped_key) = kem::encap::<Kem, _>(pk_recip, None, csprng)?;
+    Ok((EncapSecret(shared_secret), encapped_key))
+}
+
+/// Does the key schedule half of `setup_sender`, using the shared secret from `encap_only`. The
+/// output is the same as if `setup_sender` had been called with the same recipient, mode, info,
+/// and RNG.
+///
+/// Return Value
+/// ============
+/// On success, returns an encryption context. If `mode` is `Auth` or `AuthPsk`, returns
+/// `Err(HpkeError::InvalidSetupParameters(..))`, since the encapsulation was not authenticated.
+/// If `info` is longer than `MAX_INFO_LEN`, returns
+/// `Err(HpkeError::IncorrectInputLength { expected: MAX_INFO_LEN, got: info.len() })`.
+pub fn complete_setup<A, Kdf, Kem>(
+    shared_secret: EncapSecret<Kem>,
+    mode: &OpModeS<Kem::Kex>,
+    info: &[u8],
+) -> Result<AeadCtxS<A, Kdf, Kem>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    if mode.get_sender_id_keypair().is_some() {
+        return Err(HpkeError::InvalidSetupParameters(
+            "encap_only does not support authenticated modes",
+        ));
+    }
+    check_info_len(info)?;
+
+    let enc_ctx = derive_enc_ctx::<_, _, Kem, _>(mode, shared_secret.0.clone(), info);
+    trace::setup("sender", (Kem::KEM_ID, Kdf::KDF_ID, A::AEAD_ID));
+    Ok(enc_ctx.into())
+}
+
+/// The successful output of `setup_sender_debug`
+#[cfg(feature = "test-internals")]
+type SetupSenderDebugOutput<A, Kdf, Kem> = (
+    EncappedKey<<Kem as KemTrait>::Kex>,
+    AeadCtxS<A, Kdf, Kem>,
+    KeyScheduleDebug<A, Kdf, Kem>,
+);
+
+/// Does the same thing as `setup_sender`, and also returns all the intermediate values of the key
+/// schedule. This is only for debugging interop and cross-checking against test vectors.
+///
+/// Return Value
+/// ============
+/// Same as `setup_sender`, with a `KeyScheduleDebug` appended to the successful output
+#[cfg(feature = "test-internals")]
+#[doc(hidden)]
+pub fn setup_sender_debug<A, Kdf, Kem, R>(
+    mode: &OpModeS<Kem::Kex>,
+    pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
+    info: &[u8],
+    csprng: &mut R,
+) -> Result<SetupSenderDebugOutput<A, Kdf, Kem>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    check_info_len(info)?;
+
+    let sk_eph = kem::gen_eph_privkey::<Kem, R>(csprng)?;
+    setup_sender_debug_with_eph::<A, Kdf, Kem>(mode, pk_recip, info, sk_eph)
+}
+
+/// Does `setup_sender_debug` with the given ephemeral private key, instead of one generated from
+/// an RNG. This is for reproducing test vectors, which fix the ephemeral key.
+#[cfg(feature = "test-internals")]
+pub(crate) fn setup_sender_debug_with_eph<A, Kdf, Kem>(
+    mode: &OpModeS<Kem::Kex>,
+    pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
+    info: &[u8],
+    sk_eph: <Kem::Kex as KeyExchange>::PrivateKey,
+) -> Result<SetupSenderDebugOutput<A, Kdf, Kem>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    check_info_len(info)?;
+
+    // Do the encapsulation
+    let sender_id_keypair = mode.get_sender_id_keypair();
+    let (shared_secret, encapped_key) =
+        kem::encap_with_eph::<Kem>(pk_recip, sender_id_keypair, sk_eph)?;
+    // Run the key schedule and copy out everything
+    let (secret, key, base_nonce, exporter_secret) =
+        key_schedule::<A, Kdf, Kem, _>(mode, &shared_secret, info, &LabelConfig::HPKE);
+    let (sched_context_buf, sched_context_size) = key_schedule_context::<Kdf, Kem, _>(
+        mode,
+        info,
+        VERSION_LABEL,
+        &LabelConfig::HPKE.full_suite_id::<A, Kdf, Kem>(),
+    );
+    let debug = KeyScheduleDebug {
+        shared_secret: shared_secret.clone(),
+        key_schedule_context: sched_context_buf[..sched_context_size].to_vec(),
+        secret,
+        key: key.0.clone(),
+        base_nonce: base_nonce.0.clone(),
+        exporter_secret: exporter_secret.0.clone(),
+    };
+    let mut enc_ctx = AeadCtx::new(&key, base_nonce, exporter_secret);
+    enc_ctx.bind_enc(&encapped_key);
+
+    Ok((encapped_key, enc_ctx.into(), debug))
+}
+
+/// The exact byte strings that the key schedule feeds to the KDF, as returned by
+/// `key_schedule_inputs`. In draft11 §4, `LabeledExtract(salt, label, ikm)` is HKDF-Extract with
+/// the IKM `"HPKE-v1" || suite_id || label || ikm`, and `LabeledExpand(prk, label, info, L)` is
+/// HKDF-Expand with the info `I2OSP(L, 2) || "HPKE-v1" || suite_id || label || info`. These are
+/// those IKMs and infos, for diffing byte by byte against another implementation.
+#[cfg(feature = "test-internals")]
+#[doc(hidden)]
+pub struct KeyScheduleInputs {
+    /// `suite_id = concat("HPKE", I2OSP(kem_id, 2), I2OSP(kdf_id, 2), I2OSP(aead_id, 2))`
+    pub suite_id: Vec<u8>,
+    /// The IKM of `psk_id_hash = LabeledExtract("", "psk_id_hash", psk_id)`
+    pub psk_id_hash_ikm: Vec<u8>,
+    /// The IKM of `info_hash = LabeledExtract("", "info_hash", info)`
+    pub info_hash_ikm: Vec<u8>,
+    /// `key_schedule_context = concat(mode, psk_id_hash, info_hash)`
+    pub key_schedule_context: Vec<u8>,
+    /// The IKM of `secret = LabeledExtract(shared_secret, "secret", psk)`. This includes the PSK.
+    /// The salt is the shared secret, which isn't known here.
+    pub secret_ikm: Vec<u8>,
+    /// The info of `key = LabeledExpand(secret, "key", key_schedule_context, Nk)`
+    pub key_info: Vec<u8>,
+    /// The info of `base_nonce = LabeledExpand(secret, "base_nonce", key_schedule_context, Nn)`
+    pub base_nonce_info: Vec<u8>,
+    /// The info of `exporter_secret = LabeledExpand(secret, "exp", key_schedule_context, Nh)`
+    pub exporter_secret_info: Vec<u8>,
+}
+
+/// Returns the byte strings the key schedule feeds to the KDF when setting up a context in the
+/// given mode with the given `info`. A sender in the matching `OpModeS` feeds the KDF the same
+/// bytes. This is only for debugging interop. Since `secret_ikm` contains the PSK, treat the
+/// output as secret in PSK modes.
+#[cfg(feature = "test-internals")]
+#[doc(hidden)]
+pub fn key_schedule_inputs<A, Kdf, Kem>(mode: &OpModeR<Kem::Kex>, info: &[u8]) -> KeyScheduleInputs
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    use generic_array::typenum::Unsigned;
+
+    let suite_id = LabelConfig::HPKE.full_suite_id::<A, Kdf, Kem>();
+    let (sched_context_buf, sched_context_size) =
+        key_schedule_context::<Kdf, Kem, _>(mode, info, VERSION_LABEL, &suite_id);
+    let sched_context = &sched_context_buf[..sched_context_size];
+
+    // concat(version_label, suite_id, label, ikm)
+    let labeled_ikm = |label: &[u8], ikm: &[u8]| [VERSION_LABEL, &suite_id, label, ikm].concat();
+    // concat(I2OSP(len, 2), version_label, suite_id, label, key_schedule_context)
+    let labeled_info = |label: &[u8], len: usize| {
+        [
+            &(len as u16).to_be_bytes(),
+            VERSION_LABEL,
+            &suite_id,
+            label,
+            sched_context,
+        ]
+        .concat()
+    };
+
+    KeyScheduleInputs {
+        suite_id: suite_id.to_vec(),
+        psk_id_hash_ikm: labeled_ikm(b"psk_id_hash", mode.get_psk_id()),
+        info_hash_ikm: labeled_ikm(b"info_hash", info),
+        key_schedule_context: sched_context.to_vec(),
+        secret_ikm: labeled_ikm(b"secret", mode.get_psk_bytes()),
+        key_info: labeled_info(b"key", <A::AeadImpl as NewAead>::KeySize::USIZE),
+        base_nonce_info: labeled_info(b"base_nonce", <A::AeadImpl as AeadCore>::NonceSize::USIZE),
+        exporter_secret_info: labeled_info(b"exp", Kdf::N_H),
+    }
+}
+
+// draft11 §5.1.4
+// def SetupAuthPSKR(enc, skR, info, psk, psk_id, pkS):
+//   shared_secret = AuthDecap(enc, skR, pkS)
+//   return KeyScheduleR(mode_auth_psk, shared_secret, info,
+//                       psk, psk_id)
+
+/// Initiates a decryption context given a private key `sk_recip` and an encapsulated key which
+/// was encapsulated to `sk_recip`'s corresponding public key
+///
+/// Return Value
+/// ============
+/// On success, returns a decryption context. If `info` is longer than `MAX_INFO_LEN`, returns
+/// `Err(HpkeError::IncorrectInputLength { expected: MAX_INFO_LEN, got: info.len() })`. If an error
+/// happened during key decapsulation, returns `Err(HpkeError::DecapError)`.
+///
+/// Note that there's no error for a malformed `encapped_key`. It was validated when it was
+/// deserialized, so a bad length or an invalid curve point is rejected by
+/// `EncappedKey::from_bytes`, before `sk_recip` is ever involved.
+pub fn setup_receiver<A, Kdf, Kem>(
+    mode: &OpModeR<Kem::Kex>,
+    sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
+    encapped_key: &EncappedKey<Kem::Kex>,
+    info: &[u8],
+) -> Result<AeadCtxR<A, Kdf, Kem>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    setup_receiver_inner::<A, Kdf, Kem>(mode, sk_recip, encapped_key, info, &LabelConfig::HPKE, &[])
+        .map(Into::into)
+}
+
+/// Does `setup_receiver`, but with the version label and `suite_id` prefix of `labels` in place
+/// of HPKE's, in the key schedule and in the returned context. With `LabelConfig::HPKE`, this is
+/// exactly `setup_receiver`.
+///
+/// **WARNING:** With any other labels, this is not HPKE. It can only open messages from a sender
+/// using `setup_sender_with_labels` with the same labels.
+///
+/// Return Value
+/// ============
+/// Same as `setup_receiver`
+#[cfg(feature = "experimental-labels")]
+pub fn setup_receiver_with_labels<A, Kdf, Kem>(
+    labels: &LabelConfig,
+    mode: &OpModeR<Kem::Kex>,
+    sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
+    encapped_key: &EncappedKey<Kem::Kex>,
+    info: &[u8],
+) -> Result<AeadCtxR<A, Kdf, Kem>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    setup_receiver_inner::<A, Kdf, Kem>(mode, sk_recip, encapped_key, info, labels, &[])
+        .map(Into::into)
+}
+
+/// Does `setup_receiver`, but with `extra_kem_context` appended to the `kem_context` that the KEM
+/// derives the shared secret from. With an empty `extra_kem_context`, this is exactly
+/// `setup_receiver`.
+///
+/// **WARNING:** With a non-empty `extra_kem_context`, this is not HPKE. It can only open messages
+/// from a sender using `setup_sender_with_kem_context` with the same context. With any other
+/// context, setup succeeds but every `open` fails.
+///
+/// Return Value
+/// ============
+/// Same as `setup_receiver`
+#[cfg(feature = "experimental-kem-context")]
+pub fn setup_receiver_with_kem_context<A, Kdf, Kem>(
+    extra_kem_context: &[u8],
+    mode: &OpModeR<Kem::Kex>,
+    sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
+    encapped_key: &EncappedKey<Kem::Kex>,
+    info: &[u8],
+) -> Result<AeadCtxR<A, Kdf, Kem>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    setup_receiver_inner::<A, Kdf, Kem>(
+        mode,
+        sk_recip,
+        encapped_key,
+        info,
+        &LabelConfig::HPKE,
+        extra_kem_context,
+    )
+    .map(Into::into)
+}
+
+/// Does `setup_receiver` with `LabeledExtract("", "info_hash", info)` in place of `info` itself.
+/// This opens messages from `setup_sender_hashed_info`.
+///
+/// **WARNING:** This diverges from RFC 9180. See `setup_sender_hashed_info`.
+///
+/// Return Value
+/// ============
+/// Same as `setup_receiver`, except that `info` can be any length
+pub fn setup_receiver_hashed_info<A, Kdf, Kem>(
+    mode: &OpModeR<Kem::Kex>,
+    sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
+    encapped_key: &EncappedKey<Kem::Kex>,
+    info: &[u8],
+) -> Result<AeadCtxR<A, Kdf, Kem>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    let info_hash = hash_info::<A, Kdf, Kem>(info);
+    setup_receiver::<A, Kdf, Kem>(mode, sk_recip, encapped_key, &info_hash)
+}
+
+/// Does `setup_receiver` with `info.info_bytes()` as the `info` string. This opens messages from
+/// `setup_sender_with_info`, or from `setup_sender` with the same bytes.
+///
+/// Return Value
+/// ============
+/// Same as `setup_receiver`
+pub fn setup_receiver_with_info<A, Kdf, Kem>(
+    mode: &OpModeR<Kem::Kex>,
+    sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
+    encapped_key: &EncappedKey<Kem::Kex>,
+    info: impl IntoInfo,
+) -> Result<AeadCtxR<A, Kdf, Kem>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    setup_receiver::<A, Kdf, Kem>(mode, sk_recip, encapped_key, info.info_bytes())
+}
+
+/// Does `setup_receiver` with `info || pk_recip` in place of `info`, where `pk_recip` is the
+/// public key of `sk_recip`. This opens messages from `setup_sender_bind_recipient`.
+///
+/// **WARNING:** This diverges from RFC 9180. See `setup_sender_bind_recipient`.
+///
+/// Return Value
+/// ============
+/// Same as `setup_receiver`, except that the length limit applies to `info || pk_recip`
+#[cfg(feature = "alloc")]
+pub fn setup_receiver_bind_recipient<A, Kdf, Kem>(
+    mode: &OpModeR<Kem::Kex>,
+    sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
+    encapped_key: &EncappedKey<Kem::Kex>,
+    info: &[u8],
+) -> Result<AeadCtxR<A, Kdf, Kem>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    let pk_recip = Kem::Kex::sk_to_pk(sk_recip);
+    let bound_info = info_with_recipient::<Kem::Kex>(info, &pk_recip);
+    setup_receiver::<A, Kdf, Kem>(mode, sk_recip, encapped_key, &bound_info)
+}
+
+// Returns info || pk_recip, the info string of setup_{sender,receiver}_bind_recipient
+#[cfg(feature = "alloc")]
+fn info_with_recipient<Kex: KeyExchange>(info: &[u8], pk_recip: &Kex::PublicKey) -> Vec<u8> {
+    use crate::kex::Serializable;
+
+    let pk_bytes = pk_recip.to_bytes();
+    let mut bound_info = Vec::with_capacity(info.len() + pk_bytes.len());
+    bound_info.extend_from_slice(info);
+    bound_info.extend_from_slice(&pk_bytes);
+    bound_info
+}
+
+// The body of setup_receiver. This returns the bare AeadCtx, so it can be wrapped in something
+// other than an AeadCtxR. extra_kem_context is appended to the KEM's kem_context, and is empty in
+// HPKE.
+fn setup_receiver_inner<A, Kdf, Kem>(
+    mode: &OpModeR<Kem::Kex>,
+    sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
+    encapped_key: &EncappedKey<Kem::Kex>,
+    info: &[u8],
+    labels: &LabelConfig,
+    extra_kem_context: &[u8],
+) -> Result<AeadCtx<A, Kdf, Kem>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    check_info_len(info)?;
+
+    let suite_ids = (Kem::KEM_ID, Kdf::KDF_ID, A::AEAD_ID);
+    trace::setup_start("receiver", suite_ids);
+
+    // If the identity key is set, use it
+    let pk_sender_id: Option<&<Kem::Kex as KeyExchange>::PublicKey> = mode.get_pk_sender_id();
+    // Do the decapsulation. This is the first thing that touches sk_recip. The encapped key is
+    // already known to be well-formed: unlike a bare public key, which can come from
+    // from_trusted_bytes, an EncappedKey only comes from encap or the validating from_bytes.
+    let decap_res =
+        kem::decap_with_extra::<Kem>(sk_recip, pk_sender_id, encapped_key, extra_kem_context);
+    trace::setup_end("receiver", suite_ids, &decap_res);
+    let shared_secret = decap_res?;
+
+    // Use everything to derive an encryption context
+    let mut enc_ctx = derive_enc_ctx_with_labels::<_, _, Kem, _>(mode, shared_secret, info, labels);
+    enc_ctx.bind_enc(encapped_key);
+    enc_ctx.bind_sender_id(pk_sender_id);
+
+    Ok(enc_ctx)
+}
+
+/// An export-only context from an authenticated setup. Exported secrets are bound to the
+/// sender's identity key. There is no `seal` or `open`, so this can't be used for encryption by
+/// accident. Make one with `setup_sender_auth_export_only` or `setup_receiver_auth_export_only`.
+pub struct AuthExporterCtx<Kdf: KdfTrait, Kem: KemTrait>(AeadCtx<ExportOnlyAead, Kdf, Kem>);
+
+impl<Kdf: KdfTrait, Kem: KemTrait> AuthExporterCtx<Kdf, Kem> {
+    /// Fills a given buffer with secret bytes derived from this context. This is the same as
+    /// `AeadCtxS::export`.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If the buffer length is more than about 255x the digest size
+    /// of the underlying hash function, returns an `Err(HpkeError::KdfOutputTooLong)`.
+    pub fn export(&self, exporter_ctx: &[u8], out_buf: &mut [u8]) -> Result<(), HpkeError> {
+        self.0.export(exporter_ctx, out_buf)
+    }
+
+    /// Returns the `(KEM ID, KDF ID, AEAD ID)` of the ciphersuite this context belongs to. The
+    /// AEAD ID is always that of `ExportOnlyAead`.
+    pub fn suite_ids(&self) -> (u16, u16, u16) {
+        self.0.suite_ids()
+    }
+}
+
+/// Does `setup_sender` with the export-only AEAD, in `Auth` mode if `psk` is `None`, and
+/// `AuthPsk` mode otherwise. This is for using HPKE only to agree on authenticated secrets.
+///
+/// Return Value
+/// ============
+/// On success, returns an encapsulated public key (intended to be sent to the recipient), and an
+/// export-only context. Errors are the same as in `setup_sender`.
+pub fn setup_sender_auth_export_only<Kdf, Kem, R>(
+    sender_id_keypair: (
+        &<Kem::Kex as KeyExchange>::PrivateKey,
+        &<Kem::Kex as KeyExchange>::PublicKey,
+    ),
+    psk: Option<PskBundle>,
+    pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
+    info: &[u8],
+    csprng: &mut R,
+) -> Result<(EncappedKey<Kem::Kex>, AuthExporterCtx<Kdf, Kem>), HpkeError>
+where
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    let mode = match psk {
+        Some(bundle) => OpModeS::AuthPskBorrowed(sender_id_keypair, bundle),
+        None => OpModeS::AuthBorrowed(sender_id_keypair),
+    };
+    let sk_eph = kem::gen_eph_privkey::<Kem, R>(csprng)?;
+    let (encapped_key, ctx) = setup_sender_inner::<ExportOnlyAead, Kdf, Kem>(
+        &mode,
+        pk_recip,
+        info,
+        &LabelConfig::HPKE,
+        &[],
+        sk_eph,
+    )?;
+    Ok((encapped_key, AuthExporterCtx(ctx)))
+}
+
+/// Does `setup_receiver` with the export-only AEAD, in `Auth` mode if `psk` is `None`, and
+/// `AuthPsk` mode otherwise. Note that using the wrong `pk_sender_id` is not an error. It gives a
+/// context whose exports don't match the sender's.
+///
+/// Return Value
+/// ============
+/// On success, returns an export-only context. Errors are the same as in `setup_receiver`.
+pub fn setup_receiver_auth_export_only<Kdf, Kem>(
+    pk_sender_id: &<Kem::Kex as KeyExchange>::PublicKey,
+    psk: Option<PskBundle>,
+    sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
+    encapped_key: &EncappedKey<Kem::Kex>,
+    info: &[u8],
+) -> Result<AuthExporterCtx<Kdf, Kem>, HpkeError>
+where
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    let mode = match psk {
+        Some(bundle) => OpModeR::AuthPsk(pk_sender_id.clone(), bundle),
+        None => OpModeR::Auth(pk_sender_id.clone()),
+    };
+    let ctx = setup_receiver_inner::<ExportOnlyAead, Kdf, Kem>(
+        &mode,
+        sk_recip,
+        encapped_key,
+        info,
+        &LabelConfig::HPKE,
+        &[],
+    )?;
+    Ok(AuthExporterCtx(ctx))
+}
+
+/// Makes a decryption context from the shared secret of a decapsulation that was done elsewhere,
+/// e.g., in an HSM that holds the recipient's private key. This does the key schedule half of
+/// `setup_receiver`. `shared_secret` is the serialized output of `Decap` (or `AuthDecap`, in the
+/// authenticated modes), and `mode` must be the mode the decapsulation was done in.
+///
+/// The shared secret is as sensitive as the private key, for the one session it belongs to.
+/// Anyone who holds it can open and forge every message in that session, so move it between
+/// components only over an authenticated and encrypted channel, and zero it once you're done.
+///
+/// Return Value
+/// ============
+/// On success, returns a decryption context. If `shared_secret` is not the length of a shared
+/// secret of `Kem`, returns
+/// `Err(HpkeError::IncorrectInputLength { expected, got: shared_secret.len() })`. If `info` is
+/// longer than `MAX_INFO_LEN`, returns
+/// `Err(HpkeError::IncorrectInputLength { expected: MAX_INFO_LEN, got: info.len() })`.
+#[cfg(feature = "raw-shared-secret")]
+pub fn setup_receiver_from_shared_secret<A, Kdf, Kem>(
+    shared_secret: &[u8],
+    mode: &OpModeR<Kem::Kex>,
+    info: &[u8],
+) -> Result<AeadCtxR<A, Kdf, Kem>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    enforce_equal_len(SharedSecret::<Kem>::LEN, shared_secret.len())?;
+    check_info_len(info)?;
+
+    let shared_secret = SharedSecret::<Kem>(GenericArray::clone_from_slice(shared_secret));
+    let mut enc_ctx = derive_enc_ctx::<_, _, Kem, _>(mode, shared_secret, info);
+    enc_ctx.bind_sender_id(mode.get_pk_sender_id());
+    trace::setup("receiver", (Kem::KEM_ID, Kdf::KDF_ID, A::AEAD_ID));
+    Ok(enc_ctx.into())
+}
+
+/// Rebuilds the decryption context of a session from the recipient's private key and the stored
+/// encapsulated key, e.g., so that a recovery agent holding `sk_recip` can decrypt archived
+/// messages. This is the same as `setup_receiver`, with the arguments in the order a recovery
+/// workflow has them. `mode` and `info` must be the ones the sender used.
+///
+/// The context starts at sequence number 0, so archived messages must be opened in the order
+/// they were sealed.
+///
+/// Return Value
+/// ============
+/// Same as `setup_receiver`.
+pub fn recover_receiver<A, Kdf, Kem>(
+    sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
+    encapped_key: &EncappedKey<Kem::Kex>,
+    mode: &OpModeR<Kem::Kex>,
+    info: &[u8],
+) -> Result<AeadCtxR<A, Kdf, Kem>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    setup_receiver::<A, Kdf, Kem>(mode, sk_recip, encapped_key, info)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        complete_setup, encap_only, recover_receiver, setup_receiver, setup_receiver_hashed_info,
+        setup_sender, setup_sender_hashed_info, setup_sender_idempotent, MAX_INFO_LEN,
+    };
+    use crate::test_util::{aead_ctx_eq, gen_rand_buf, new_op_mode_pair, OpModeKind};
+    use crate::{
+        aead::{AesGcm128, AesGcm256, ChaCha20Poly1305},
+        kdf::{HkdfSha256, HkdfSha384, HkdfSha512},
+        kem::Kem as KemTrait,
+        kex::{Deserializable, Serializable},
+        op_mode::{OpModeR, OpModeS, PskBundle},
+        EncappedKey, HpkeError,
+    };
+
+    use alloc::vec::Vec;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// This tests that `setup_sender` and `setup_receiver` derive the same context. We do this by
+    /// testing that `gen_ctx_kem_pair` returns identical encryption contexts
+    macro_rules! test_setup_correctness {
+        ($test_name:ident, $aead_ty:ty, $kdf_ty:ty, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = $aead_ty;
+                type Kdf = $kdf_ty;
+                type Kem = $kem_ty;
+                type Kex = <Kem as KemTrait>::Kex;
+
+                let mut csprng = StdRng::from_entropy();
+
+                let info = b"why would you think in a million years that that would actually work";
+
+                // Generate the receiver's long-term keypair
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+
+                // Try a full setup for all the op modes
+                for op_mode_kind in &[
+                    OpModeKind::Base,
+                    OpModeKind::Auth,
+                    OpModeKind::Psk,
+                    OpModeKind::AuthPsk,
+                ] {
+                    // Generate a mutually agreeing op mode pair
+                    let (psk, psk_id) = (gen_rand_buf(), gen_rand_buf());
+                    let (sender_mode, receiver_mode) =
+                        new_op_mode_pair::<Kex, Kdf>(*op_mode_kind, &psk, &psk_id);
+
+                    // Construct the sender's encryption context, and get an encapped key
+                    let (encapped_key, mut aead_ctx1) = setup_sender::<A, Kdf, Kem, _>(
+                        &sender_mode,
+                        &pk_recip,
+                        &info[..],
+                        &mut csprng,
+                    )
+                    .unwrap();
+
+                    // Use the encapped key to derive the reciever's encryption context
+                    let mut aead_ctx2 = setup_receiver::<A, Kdf, Kem>(
+                        &receiver_mode,
+                        &sk_recip,
+                        &encapped_key,
+                        &info[..],
+                    )
+                    .unwrap();
+
+                    // Ensure that the two derived contexts are equivalent
+                    assert!(aead_ctx_eq(&mut aead_ctx1, &mut aead_ctx2));
+                }
+            }
+        };
+    }
+
+    /// Tests that using different input data gives you different encryption contexts
+    macro_rules! test_setup_soundness {
+        ($test_name:ident, $aead:ty, $kdf:ty, $kem:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = $aead;
+                type Kdf = $kdf;
+                type Kem = $kem;
+                type Kex = <Kem as KemTrait>::Kex;
+
+                let mut csprng = StdRng::from_entropy();
+
+                let info = b"why would you think in a million years that that would actually work";
+
+                // Generate the receiver's long-term keypair
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+
+                // Generate a mutually agreeing op mode pair
+                let (psk, psk_id) = (gen_rand_buf(), gen_rand_buf());
+                let (sender_mode, receiver_mode) =
+                    new_op_mode_pair::<Kex, Kdf>(OpModeKind::Base, &psk, &psk_id);
+
+                // Construct the sender's encryption context normally
+                let (encapped_key, sender_ctx) =
+                    setup_sender::<A, Kdf, Kem, _>(&sender_mode, &pk_recip, &info[..], &mut csprng)
+                        .unwrap();
+
+                // Now make a receiver with the wrong info string and ensure it doesn't match the
+                // sender
+                let bad_info = b"something else";
+                let mut receiver_ctx = setup_receiver::<_, _, Kem>(
+                    &receiver_mode,
+                    &sk_recip,
+                    &encapped_key,
+                    &bad_info[..],
+                )
+                .unwrap();
+                assert!(!aead_ctx_eq(&mut sender_ctx.clone(), &mut receiver_ctx));
+
+                // Now make a receiver with the wrong secret key and ensure it doesn't match the
+                // sender
+                let (bad_sk, _) = Kem::gen_keypair(&mut csprng);
+                let mut aead_ctx2 =
+                    setup_receiver::<_, _, Kem>(&receiver_mode, &bad_sk, &encapped_key, &info[..])
+                        .unwrap();
+                assert!(!aead_ctx_eq(&mut sender_ctx.clone(), &mut aead_ctx2));
+
+                // Now make a receiver with the wrong encapped key and ensure it doesn't match the
+                // sender. The reason `bad_encapped_key` is bad is because its underlying key is
+                // uniformly random, and therefore different from the key that the sender sent.
+                let (bad_encapped_key, _) =
+                    setup_sender::<A, Kdf, Kem, _>(&sender_mode, &pk_recip, &info[..], &mut csprng)
+                        .unwrap();
+                let mut aead_ctx2 = setup_receiver::<_, _, Kem>(
+                    &receiver_mode,
+                    &sk_recip,
+                    &bad_encapped_key,
+                    &info[..],
+                )
+                .unwrap();
+                assert!(!aead_ctx_eq(&mut sender_ctx.clone(), &mut aead_ctx2));
+
+                // Now make sure that this test was a valid test by ensuring that doing everything
+                // the right way makes it pass
+                let mut aead_ctx2 = setup_receiver::<_, _, Kem>(
+                    &receiver_mode,
+                    &sk_recip,
+                    &encapped_key,
+                    &info[..],
+                )
+                .unwrap();
+                assert!(aead_ctx_eq(&mut sender_ctx.clone(), &mut aead_ctx2));
+            }
+        };
+    }
+
+    /// Tests that AuthPSK mode round-trips, and that both the sender's identity and the PSK go into
+    /// its key schedule. That is, AuthPSK differs from Auth and PSK with the same inputs, and the
+    /// receiver gets a different context if either its sender pubkey or its PSK is wrong.
+    macro_rules! test_auth_psk {
+        ($test_name:ident, $aead:ty, $kdf:ty, $kem:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = $aead;
+                type Kdf = $kdf;
+                type Kem = $kem;
+
+                let mut csprng = StdRng::from_entropy();
+                let info = b"both at once";
+
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let (sk_sender, pk_sender) = Kem::gen_keypair(&mut csprng);
+                let (_, pk_other) = Kem::gen_keypair(&mut csprng);
+                let (psk, psk_id) = (gen_rand_buf(), gen_rand_buf());
+                let psk_bundle = PskBundle {
+                    psk: &psk,
+                    psk_id: &psk_id,
+                };
+
+                // Set up with identically seeded RNGs, so that every mode gets the same
+                // ephemeral key
+                let setup = |mode: &OpModeS<<Kem as KemTrait>::Kex>| {
+                    setup_sender::<A, Kdf, Kem, _>(
+                        mode,
+                        &pk_recip,
+                        &info[..],
+                        &mut StdRng::seed_from_u64(0x03),
+                    )
+                    .unwrap()
+                };
+                let sender_id = (sk_sender.clone(), pk_sender.clone());
+                let (encapped_key, sender_ctx) =
+                    setup(&OpModeS::AuthPsk(sender_id.clone(), psk_bundle));
+                let (auth_encapped_key, auth_ctx) = setup(&OpModeS::Auth(sender_id));
+                let (_, psk_ctx) = setup(&OpModeS::Psk(psk_bundle));
+                assert_eq!(encapped_key.to_bytes(), auth_encapped_key.to_bytes());
+
+                // AuthPSK is neither Auth nor PSK
+                let export = |ctx: &crate::aead::AeadCtxS<A, Kdf, Kem>| {
+                    let mut buf = [0u8; 32];
+                    ctx.export(b"", &mut buf).unwrap();
+                    buf
+                };
+                assert_ne!(export(&sender_ctx), export(&auth_ctx));
+                assert_ne!(export(&sender_ctx), export(&psk_ctx));
+
+                // The receiver with everything right agrees with the sender
+                let receive = |mode: &OpModeR<<Kem as KemTrait>::Kex>| {
+                    setup_receiver::<A, Kdf, Kem>(mode, &sk_recip, &encapped_key, &info[..])
+                        .unwrap()
+                };
+                let mut receiver_ctx = receive(&OpModeR::AuthPsk(pk_sender.clone(), psk_bundle));
+                assert!(aead_ctx_eq(&mut sender_ctx.clone(), &mut receiver_ctx));
+
+                // A receiver that's missing either input, or has the wrong one, disagrees
+                let bad_psk = gen_rand_buf();
+                let bad_psk_bundle = PskBundle {
+                    psk: &bad_psk,
+                    psk_id: &psk_id,
+                };
+                let bad_psk_id_bundle = PskBundle {
+                    psk: &psk,
+                    psk_id: b"another id",
+                };
+                let bad_modes = [
+                    OpModeR::Auth(pk_sender.clone()),
+                    OpModeR::Psk(psk_bundle),
+                    OpModeR::AuthPsk(pk_other.clone(), psk_bundle),
+                    OpModeR::AuthPsk(pk_sender.clone(), bad_psk_bundle),
+                    OpModeR::AuthPsk(pk_sender.clone(), bad_psk_id_bundle),
+                ];
+                for bad_mode in bad_modes.iter() {
+                    let mut bad_receiver_ctx = receive(bad_mode);
+                    assert!(!aead_ctx_eq(&mut sender_ctx.clone(), &mut bad_receiver_ctx));
+                }
+            }
+        };
+    }
+
+    /// Tests that a single recipient public key can be used for several setups in every mode,
+    /// without being cloned
+    macro_rules! test_setup_reuses_pk_recip {
+        ($test_name:ident, $aead:ty, $kdf:ty, $kem:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = $aead;
+                type Kdf = $kdf;
+                type Kem = $kem;
+                type Kex = <Kem as KemTrait>::Kex;
+
+                let mut csprng = StdRng::from_entropy();
+
+                let info = b"one key, many sessions";
+                let (psk, psk_id) = (gen_rand_buf(), gen_rand_buf());
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+
+                for op_mode_kind in &[
+                    OpModeKind::Base,
+                    OpModeKind::Auth,
+                    OpModeKind::Psk,
+                    OpModeKind::AuthPsk,
+                ] {
+                    let (sender_mode, receiver_mode) =
+                        new_op_mode_pair::<Kex, Kdf>(*op_mode_kind, &psk, &psk_id);
+
+                    // Two consecutive setups with the same pk_recip value
+                    for _ in 0..2 {
+                        let (encapped_key, mut sender_ctx) = setup_sender::<A, Kdf, Kem, _>(
+                            &sender_mode,
+                            &pk_recip,
+                            &info[..],
+                            &mut csprng,
+                        )
+                        .unwrap();
+                        let mut receiver_ctx = setup_receiver::<A, Kdf, Kem>(
+                            &receiver_mode,
+                            &sk_recip,
+                            &encapped_key,
+                            &info[..],
+                        )
+                        .unwrap();
+                        assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+                    }
+                }
+            }
+        };
+    }
+
+    /// Tests that the borrowing auth modes produce exactly the same output as the owning ones
+    macro_rules! test_setup_borrowed_auth {
+        ($test_name:ident, $aead:ty, $kdf:ty, $kem:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = $aead;
+                type Kdf = $kdf;
+                type Kem = $kem;
+
+                let mut csprng = StdRng::from_entropy();
+
+                let info = b"the same old thing";
+                let (psk, psk_id) = (gen_rand_buf(), gen_rand_buf());
+                let psk_bundle = PskBundle {
+                    psk: &psk,
+                    psk_id: &psk_id,
+                };
+
+                // Generate the receiver's and sender's long-term keypairs
+                let (_, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let (sk_sender, pk_sender) = Kem::gen_keypair(&mut csprng);
+
+                let mode_pairs = [
+                    (
+                        OpModeS::Auth((sk_sender.clone(), pk_sender.clone())),
+                        OpModeS::AuthBorrowed((&sk_sender, &pk_sender)),
+                    ),
+                    (
+                        OpModeS::AuthPsk((sk_sender.clone(), pk_sender.clone()), psk_bundle),
+                        OpModeS::AuthPskBorrowed((&sk_sender, &pk_sender), psk_bundle),
+                    ),
+                ];
+
+                for (owned_mode, borrowed_mode) in mode_pairs.iter() {
+                    // Use identically seeded RNGs so that the ephemeral keys are the same
+                    let (owned_encapped_key, mut owned_ctx) = setup_sender::<A, Kdf, Kem, _>(
+                        owned_mode,
+                        &pk_recip,
+                        &info[..],
+                        &mut StdRng::seed_from_u64(0xb0bb1e),
+                    )
+                    .unwrap();
+                    let (borrowed_encapped_key, mut borrowed_ctx) = setup_sender::<A, Kdf, Kem, _>(
+                        borrowed_mode,
+                        &pk_recip,
+                        &info[..],
+                        &mut StdRng::seed_from_u64(0xb0bb1e),
+                    )
+                    .unwrap();
+                    assert_eq!(
+                        owned_encapped_key.to_bytes(),
+                        borrowed_encapped_key.to_bytes()
+                    );
+
+                    // Identical contexts give identical ciphertexts and tags
+                    let mut owned_msg = *b"same as it ever was";
+                    let mut borrowed_msg = owned_msg;
+                    let owned_tag = owned_ctx.seal(&mut owned_msg, b"").unwrap();
+                    let borrowed_tag = borrowed_ctx.seal(&mut borrowed_msg, b"").unwrap();
+                    assert_eq!(owned_msg, borrowed_msg);
+                    assert_eq!(owned_tag.to_bytes(), borrowed_tag.to_bytes());
+                }
+            }
+        };
+    }
+
+    /// Tests that setup rejects an info string over the limit and accepts one at the limit
+    macro_rules! test_setup_max_info_len {
+        ($test_name:ident, $aead:ty, $kdf:ty, $kem:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = $aead;
+                type Kdf = $kdf;
+                type Kem = $kem;
+
+                let mut csprng = StdRng::from_entropy();
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+
+                let info = vec![0u8; MAX_INFO_LEN];
+                let long_info = vec![0u8; MAX_INFO_LEN + 1];
+
+                // An info of exactly the limit is fine
+                let (encapped_key, _) =
+                    setup_sender::<A, Kdf, Kem, _>(&OpModeS::Base, &pk_recip, &info, &mut csprng)
+                        .unwrap();
+                assert!(setup_receiver::<A, Kdf, Kem>(
+                    &OpModeR::Base,
+                    &sk_recip,
+                    &encapped_key,
+                    &info
+                )
+                .is_ok());
+
+                // One more byte is too many, for both sender and receiver
+                let expected_err = HpkeError::IncorrectInputLength {
+                    expected: MAX_INFO_LEN,
+                    got: MAX_INFO_LEN + 1,
+                };
+                assert_eq!(
+                    setup_sender::<A, Kdf, Kem, _>(
+                        &OpModeS::Base,
+                        &pk_recip,
+                        &long_info,
+                        &mut csprng
+                    )
+                    .err(),
+                    Some(expected_err)
+                );
+                assert_eq!(
+                    setup_receiver::<A, Kdf, Kem>(
+                        &OpModeR::Base,
+                        &sk_recip,
+                        &encapped_key,
+                        &long_info
+                    )
+                    .err(),
+                    Some(expected_err)
+                );
+            }
+        };
+    }
+
+    /// Tests that `sender_public_key` returns the sender identity key in the authenticated modes,
+    /// and nothing otherwise
+    macro_rules! test_sender_public_key {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let info = b"audit trail";
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+
+                for op_mode_kind in &[
+                    OpModeKind::Base,
+                    OpModeKind::Auth,
+                    OpModeKind::Psk,
+                    OpModeKind::AuthPsk,
+                ] {
+                    let (psk, psk_id) = (gen_rand_buf(), gen_rand_buf());
+                    let (sender_mode, receiver_mode) = new_op_mode_pair::<
+                        <Kem as KemTrait>::Kex,
+                        Kdf,
+                    >(*op_mode_kind, &psk, &psk_id);
+                    let (encapped_key, _) =
+                        setup_sender::<A, Kdf, Kem, _>(&sender_mode, &pk_recip, info, &mut csprng)
+                            .unwrap();
+                    let receiver_ctx = setup_receiver::<A, Kdf, Kem>(
+                        &receiver_mode,
+                        &sk_recip,
+                        &encapped_key,
+                        info,
+                    )
+                    .unwrap();
+
+                    let expected = receiver_mode.get_pk_sender_id().map(|pk| pk.to_bytes());
+                    let got = receiver_ctx.sender_public_key().map(|pk| pk.to_bytes());
+                    match op_mode_kind {
+                        OpModeKind::Auth | OpModeKind::AuthPsk => assert!(got.is_some()),
+                        OpModeKind::Base | OpModeKind::Psk => assert!(got.is_none()),
+                    }
+                    assert_eq!(got, expected);
+                }
+            }
+        };
+    }
+
+    /// Tests that the hashed-info setups agree on identical infos too long for the key schedule,
+    /// disagree on different ones, and match the plain setups with the digest as the info
+    macro_rules! test_setup_hashed_info {
+        ($test_name:ident, $aead:ty, $kdf:ty, $kem:ty) => {
+            #[test]
+            fn $test_name() {
+                use crate::{kdf::labeled_extract, util::full_suite_id};
+
+                type A = $aead;
+                type Kdf = $kdf;
+                type Kem = $kem;
+
+                let mut csprng = StdRng::from_entropy();
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+
+                let info = vec![0x42u8; 16 * MAX_INFO_LEN];
+                let same_info = info.clone();
+                let mut other_info = info.clone();
+                *other_info.last_mut().unwrap() ^= 1;
+
+                let (encapped_key, mut sender_ctx) = setup_sender_hashed_info::<A, Kdf, Kem, _>(
+                    &OpModeS::Base,
+                    &pk_recip,
+                    &info,
+                    &mut csprng,
+                )
+                .unwrap();
+                let msg = b"see attached policy";
+                let mut ciphertext = *msg;
+                let tag = sender_ctx.seal(&mut ciphertext, b"").unwrap();
+
+                // Returns whether the given receiver context can open the message
+                let opens = |receiver_ctx: Result<crate::AeadCtxR<A, Kdf, Kem>, HpkeError>| {
+                    let mut buf = ciphertext;
+                    receiver_ctx.unwrap().open(&mut buf, b"", &tag).is_ok() && &buf == msg
+                };
+
+                assert!(opens(setup_receiver_hashed_info::<A, Kdf, Kem>(
+                    &OpModeR::Base,
+                    &sk_recip,
+                    &encapped_key,
+                    &same_info,
+                )));
+                assert!(!opens(setup_receiver_hashed_info::<A, Kdf, Kem>(
+                    &OpModeR::Base,
+                    &sk_recip,
+                    &encapped_key,
+                    &other_info,
+                )));
+
+                // A receiver that hashes the info itself gets the same context
+                let suite_id = full_suite_id::<A, Kdf, Kem>();
+                let (info_hash, _) = labeled_extract::<Kdf>(&[], &suite_id, b"info_hash", &info);
+                assert!(opens(setup_receiver::<A, Kdf, Kem>(
+                    &OpModeR::Base,
+                    &sk_recip,
+                    &encapped_key,
+                    &info_hash,
+                )));
+            }
+        };
+    }
+
+    /// Tests that two idempotent setups with the same ikm give the same encapped key and the same
+    /// sequence-0 ciphertext, which the receiver opens, and that a different ikm gives a different
+    /// encapped key
+    macro_rules! test_setup_idempotent {
+        ($test_name:ident, $aead:ty, $kdf:ty, $kem:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = $aead;
+                type Kdf = $kdf;
+                type Kem = $kem;
+
+                let mut csprng = StdRng::from_entropy();
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let info = b"at-least-once delivery";
+                let ikm = gen_rand_buf();
+                let msg = b"retry me";
+
+                // Returns the encapped key and the sequence-0 ciphertext and tag
+                let run = |ikm: &[u8]| {
+                    let (encapped_key, mut sender_ctx) = setup_sender_idempotent::<A, Kdf, Kem>(
+                        &OpModeS::Base,
+                        &pk_recip,
+                        info,
+                        ikm,
+                    )
+                    .unwrap();
+                    let mut ciphertext = *msg;
+                    let tag = sender_ctx.seal(&mut ciphertext, b"aad").unwrap();
+                    (encapped_key, ciphertext, tag.to_bytes())
+                };
+
+                let (encapped_key, ciphertext, tag) = run(&ikm);
+                let (retry_encapped_key, retry_ciphertext, retry_tag) = run(&ikm);
+                assert_eq!(encapped_key.to_bytes(), retry_encapped_key.to_bytes());
+                assert_eq!(ciphertext, retry_ciphertext);
+                assert_eq!(tag, retry_tag);
+
+                let mut receiver_ctx =
+                    setup_receiver::<A, Kdf, Kem>(&OpModeR::Base, &sk_recip, &encapped_key, info)
+                        .unwrap();
+                let mut plaintext = ciphertext;
+                let tag = crate::aead::AeadTag::<A>::from_bytes(&tag).unwrap();
+                receiver_ctx.open(&mut plaintext, b"aad", &tag).unwrap();
+                assert_eq!(&plaintext, msg);
+
+                let mut other_ikm = ikm;
+                other_ikm[0] ^= 1;
+                let (other_encapped_key, _, _) = run(&other_ikm);
+                assert_ne!(encapped_key.to_bytes(), other_encapped_key.to_bytes());
+            }
+        };
+    }
+
+    /// A protocol's fixed set of info strings
+    enum TestLabel {
+        Request,
+        Response,
+    }
+
+    impl super::IntoInfo for TestLabel {
+        fn info_bytes(&self) -> &[u8] {
+            match self {
+                TestLabel::Request => b"test protocol request",
+                TestLabel::Response => b"test protocol response",
+            }
+        }
+    }
+
+    /// Tests that setting up with an `IntoInfo` value gives the same contexts as setting up with
+    /// its bytes, and that different labels give different contexts
+    macro_rules! test_setup_with_info {
+        ($test_name:ident, $aead:ty, $kdf:ty, $kem:ty) => {
+            #[test]
+            fn $test_name() {
+                use crate::setup::{setup_receiver_with_info, setup_sender_with_info};
+
+                type A = $aead;
+                type Kdf = $kdf;
+                type Kem = $kem;
+
+                let mut csprng = StdRng::from_entropy();
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let raw_info: &[u8] = b"test protocol request";
+
+                // A labeled sender and a raw receiver agree, and so do a raw sender and a labeled
+                // receiver
+                let (encapped_key, mut sender_ctx) = setup_sender_with_info::<A, Kdf, Kem, _>(
+                    &OpModeS::Base,
+                    &pk_recip,
+                    TestLabel::Request,
+                    &mut csprng,
+                )
+                .unwrap();
+                let mut receiver_ctx = setup_receiver::<A, Kdf, Kem>(
+                    &OpModeR::Base,
+                    &sk_recip,
+                    &encapped_key,
+                    raw_info,
+                )
+                .unwrap();
+                assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+
+                let (encapped_key, mut sender_ctx) = setup_sender::<A, Kdf, Kem, _>(
+                    &OpModeS::Base,
+                    &pk_recip,
+                    raw_info,
+                    &mut csprng,
+                )
+                .unwrap();
+                let mut receiver_ctx = setup_receiver_with_info::<A, Kdf, Kem>(
+                    &OpModeR::Base,
+                    &sk_recip,
+                    &encapped_key,
+                    TestLabel::Request,
+                )
+                .unwrap();
+                assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+
+                // Raw bytes work as an IntoInfo too, and a different label gives a different
+                // context. The contexts above have already sealed, so compare exports instead.
+                let export = |ctx: &crate::aead::AeadCtxR<A, Kdf, Kem>| {
+                    let mut secret = [0u8; 32];
+                    ctx.export(b"", &mut secret).unwrap();
+                    secret
+                };
+                let raw_receiver_ctx = setup_receiver_with_info::<A, Kdf, Kem>(
+                    &OpModeR::Base,
+                    &sk_recip,
+                    &encapped_key,
+                    raw_info,
+                )
+                .unwrap();
+                assert_eq!(export(&raw_receiver_ctx), export(&receiver_ctx));
+                // So does a byte string literal, without a cast
+                let literal_receiver_ctx = setup_receiver_with_info::<A, Kdf, Kem>(
+                    &OpModeR::Base,
+                    &sk_recip,
+                    &encapped_key,
+                    b"test protocol request",
+                )
+                .unwrap();
+                assert_eq!(export(&literal_receiver_ctx), export(&receiver_ctx));
+                let other_receiver_ctx = setup_receiver_with_info::<A, Kdf, Kem>(
+                    &OpModeR::Base,
+                    &sk_recip,
+                    &encapped_key,
+                    TestLabel::Response,
+                )
+                .unwrap();
+                assert_ne!(export(&other_receiver_ctx), export(&receiver_ctx));
+            }
+        };
+    }
+
+    /// Tests that a context bound to the recipient opens for that recipient, that it's the same
+    /// as an unbound context with `info || pk_recip`, and that binding to any other key, or not
+    /// binding at all, doesn't open
+    #[cfg(feature = "alloc")]
+    macro_rules! test_setup_bind_recipient {
+        ($test_name:ident, $aead:ty, $kdf:ty, $kem:ty) => {
+            #[test]
+            fn $test_name() {
+                use crate::setup::{setup_receiver_bind_recipient, setup_sender_bind_recipient};
+
+                type A = $aead;
+                type Kdf = $kdf;
+                type Kem = $kem;
+
+                let mut csprng = StdRng::from_entropy();
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let (_, pk_other) = Kem::gen_keypair(&mut csprng);
+                let info = b"for your eyes only";
+
+                let (encapped_key, mut sender_ctx) = setup_sender_bind_recipient::<A, Kdf, Kem, _>(
+                    &OpModeS::Base,
+                    &pk_recip,
+                    info,
+                    &mut csprng,
+                )
+                .unwrap();
+                let msg = b"bound to one recipient";
+                let mut ciphertext = *msg;
+                let tag = sender_ctx.seal(&mut ciphertext, b"").unwrap();
+
+                // Returns whether the given receiver context can open the message
+                let opens = |receiver_ctx: Result<crate::AeadCtxR<A, Kdf, Kem>, HpkeError>| {
+                    let mut buf = ciphertext;
+                    receiver_ctx.unwrap().open(&mut buf, b"", &tag).is_ok() && &buf == msg
+                };
+                // Returns info || pk
+                let bound_info =
+                    |pk: &<<Kem as KemTrait>::Kex as crate::kex::KeyExchange>::PublicKey| {
+                        let mut bound_info = info.to_vec();
+                        bound_info.extend_from_slice(&pk.to_bytes());
+                        bound_info
+                    };
+
+                assert!(opens(setup_receiver_bind_recipient::<A, Kdf, Kem>(
+                    &OpModeR::Base,
+                    &sk_recip,
+                    &encapped_key,
+                    info,
+                )));
+                assert!(opens(setup_receiver::<A, Kdf, Kem>(
+                    &OpModeR::Base,
+                    &sk_recip,
+                    &encapped_key,
+                    &bound_info(&pk_recip),
+                )));
+                assert!(!opens(setup_receiver::<A, Kdf, Kem>(
+                    &OpModeR::Base,
+                    &sk_recip,
+                    &encapped_key,
+                    &bound_info(&pk_other),
+                )));
+                assert!(!opens(setup_receiver::<A, Kdf, Kem>(
+                    &OpModeR::Base,
+                    &sk_recip,
+                    &encapped_key,
+                    info,
+                )));
+            }
+        };
+    }
+
+    /// Tests that encap_only followed by complete_setup gives the same encapped key and context as
+    /// setup_sender, and that the authenticated modes are refused
+    macro_rules! test_split_setup {
+        ($test_name:ident, $aead:ty, $kdf:ty, $kem:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = $aead;
+                type Kdf = $kdf;
+                type Kem = $kem;
+                type Kex = <Kem as KemTrait>::Kex;
+
+                let mut csprng = StdRng::from_entropy();
+
+                let info = b"decided at the last minute";
                 let (psk, psk_id) = (gen_rand_buf(), gen_rand_buf());
-                let (sender_mode, receiver_mode) =
-                    new_op_mode_pair::<Kex, Kdf>(OpModeKind::Base, &psk, &psk_id);
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+
+                for op_mode_kind in &[OpModeKind::Base, OpModeKind::Psk] {
+                    let (sender_mode, receiver_mode) =
+                        new_op_mode_pair::<Kex, Kdf>(*op_mode_kind, &psk, &psk_id);
+
+                    // Use identically seeded RNGs so that the ephemeral keys are the same
+                    let (shared_secret, split_encapped_key) =
+                        encap_only::<Kem, _>(&pk_recip, &mut StdRng::seed_from_u64(0xbeef))
+                            .unwrap();
+                    let mut split_ctx =
+                        complete_setup::<A, Kdf, Kem>(shared_secret, &sender_mode, &info[..])
+                            .unwrap();
+                    let (encapped_key, mut sender_ctx) = setup_sender::<A, Kdf, Kem, _>(
+                        &sender_mode,
+                        &pk_recip,
+                        &info[..],
+                        &mut StdRng::seed_from_u64(0xbeef),
+                    )
+                    .unwrap();
+                    assert_eq!(split_encapped_key.to_bytes(), encapped_key.to_bytes());
+
+                    // Identical contexts give identical ciphertexts, tags, and exports
+                    let mut split_msg = *b"hurry up and wait";
+                    let mut msg = split_msg;
+                    let split_tag = split_ctx.seal(&mut split_msg, b"").unwrap();
+                    let tag = sender_ctx.seal(&mut msg, b"").unwrap();
+                    assert_eq!(split_msg, msg);
+                    assert_eq!(split_tag.to_bytes(), tag.to_bytes());
+
+                    let mut split_export = [0u8; 32];
+                    let mut export = [0u8; 32];
+                    split_ctx.export(b"ctx", &mut split_export).unwrap();
+                    sender_ctx.export(b"ctx", &mut export).unwrap();
+                    assert_eq!(split_export, export);
+
+                    // And the receiver agrees with the split context
+                    let mut receiver_ctx = setup_receiver::<A, Kdf, Kem>(
+                        &receiver_mode,
+                        &sk_recip,
+                        &split_encapped_key,
+                        &info[..],
+                    )
+                    .unwrap();
+                    receiver_ctx.open(&mut split_msg, b"", &split_tag).unwrap();
+                    assert!(aead_ctx_eq(&mut split_ctx, &mut receiver_ctx));
+                }
+
+                // An unauthenticated encapsulation can't be completed in an authenticated mode
+                for op_mode_kind in &[OpModeKind::Auth, OpModeKind::AuthPsk] {
+                    let (sender_mode, _) =
+                        new_op_mode_pair::<Kex, Kdf>(*op_mode_kind, &psk, &psk_id);
+                    let (shared_secret, _) = encap_only::<Kem, _>(&pk_recip, &mut csprng).unwrap();
+                    assert!(matches!(
+                        complete_setup::<A, Kdf, Kem>(shared_secret, &sender_mode, &info[..]),
+                        Err(HpkeError::InvalidSetupParameters(_))
+                    ));
+                }
+            }
+        };
+    }
+
+    /// Tests that a session sealed long ago can be recovered from only the recipient's private key
+    /// and the stored encapsulated key, and that a different stored key recovers nothing
+    macro_rules! test_recover_receiver {
+        ($test_name:ident, $aead:ty, $kdf:ty, $kem:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = $aead;
+                type Kdf = $kdf;
+                type Kem = $kem;
+
+                let mut csprng = StdRng::from_entropy();
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let info = b"archive";
+
+                // Seal a few messages, and keep only the serialized encapsulated key and the
+                // ciphertexts. The sender's context is gone.
+                let (stored_enc, archive) = {
+                    let (encapped_key, mut sender_ctx) = setup_sender::<A, Kdf, Kem, _>(
+                        &OpModeS::Base,
+                        &pk_recip,
+                        info,
+                        &mut csprng,
+                    )
+                    .unwrap();
+                    let archive: Vec<_> = [&b"first"[..], b"second", b"third"]
+                        .iter()
+                        .map(|msg| {
+                            let mut ciphertext = msg.to_vec();
+                            let tag = sender_ctx.seal(&mut ciphertext, b"").unwrap();
+                            (ciphertext, tag)
+                        })
+                        .collect();
+                    (encapped_key.to_bytes(), archive)
+                };
+
+                // The recovery agent rebuilds the context and opens everything in order
+                let encapped_key =
+                    EncappedKey::<<Kem as KemTrait>::Kex>::from_bytes(&stored_enc).unwrap();
+                let mut receiver_ctx =
+                    recover_receiver::<A, Kdf, Kem>(&sk_recip, &encapped_key, &OpModeR::Base, info)
+                        .unwrap();
+                let recovered: Vec<_> = archive
+                    .into_iter()
+                    .map(|(mut ciphertext, tag)| {
+                        receiver_ctx.open(&mut ciphertext, b"", &tag).unwrap();
+                        ciphertext
+                    })
+                    .collect();
+                assert_eq!(recovered, [&b"first"[..], b"second", b"third"]);
+
+                // The stored key from some other session recovers a context that opens nothing
+                let (other_encapped_key, mut other_sender_ctx) =
+                    setup_sender::<A, Kdf, Kem, _>(&OpModeS::Base, &pk_recip, info, &mut csprng)
+                        .unwrap();
+                let mut msg = *b"first";
+                let tag = other_sender_ctx.seal(&mut msg, b"").unwrap();
+                let mut wrong_ctx =
+                    recover_receiver::<A, Kdf, Kem>(&sk_recip, &encapped_key, &OpModeR::Base, info)
+                        .unwrap();
+                assert_eq!(
+                    wrong_ctx.open(&mut msg, b"", &tag),
+                    Err(HpkeError::OpenError)
+                );
+                let mut right_ctx = recover_receiver::<A, Kdf, Kem>(
+                    &sk_recip,
+                    &other_encapped_key,
+                    &OpModeR::Base,
+                    info,
+                )
+                .unwrap();
+                right_ctx.open(&mut msg, b"", &tag).unwrap();
+            }
+        };
+    }
+
+    /// Tests that a receiver context made from the shared secret of a separate decap agrees with
+    /// the sender in every mode, and that a shared secret of the wrong length is rejected
+    #[cfg(feature = "raw-shared-secret")]
+    macro_rules! test_setup_receiver_from_shared_secret {
+        ($test_name:ident, $aead:ty, $kdf:ty, $kem:ty) => {
+            #[test]
+            fn $test_name() {
+                use crate::kem::decap;
+                use crate::setup::setup_receiver_from_shared_secret;
+
+                type A = $aead;
+                type Kdf = $kdf;
+                type Kem = $kem;
+                type Kex = <Kem as KemTrait>::Kex;
+
+                let mut csprng = StdRng::from_entropy();
+
+                let info = b"offloaded";
+                let (psk, psk_id) = (gen_rand_buf(), gen_rand_buf());
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+
+                for op_mode_kind in &[
+                    OpModeKind::Base,
+                    OpModeKind::Auth,
+                    OpModeKind::Psk,
+                    OpModeKind::AuthPsk,
+                ] {
+                    let (sender_mode, receiver_mode) =
+                        new_op_mode_pair::<Kex, Kdf>(*op_mode_kind, &psk, &psk_id);
+                    let (encapped_key, mut sender_ctx) = setup_sender::<A, Kdf, Kem, _>(
+                        &sender_mode,
+                        &pk_recip,
+                        &info[..],
+                        &mut csprng,
+                    )
+                    .unwrap();
+
+                    // This is the part the HSM does
+                    let shared_secret =
+                        decap::<Kem>(&sk_recip, receiver_mode.get_pk_sender_id(), &encapped_key)
+                            .unwrap();
+
+                    // And this is the part the backend does
+                    let mut receiver_ctx = setup_receiver_from_shared_secret::<A, Kdf, Kem>(
+                        shared_secret.as_ref(),
+                        &receiver_mode,
+                        &info[..],
+                    )
+                    .unwrap();
+
+                    let mut msg = *b"from the frontend";
+                    let tag = sender_ctx.seal(&mut msg, b"aad").unwrap();
+                    receiver_ctx.open(&mut msg, b"aad", &tag).unwrap();
+                    assert_eq!(&msg, b"from the frontend");
+                    assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+
+                    let short = &shared_secret.as_ref()[1..];
+                    assert_eq!(
+                        setup_receiver_from_shared_secret::<A, Kdf, Kem>(
+                            short,
+                            &receiver_mode,
+                            &info[..]
+                        )
+                        .err(),
+                        Some(HpkeError::IncorrectInputLength {
+                            expected: shared_secret.len(),
+                            got: short.len()
+                        })
+                    );
+                }
+            }
+        };
+    }
+
+    /// Tests that the authenticated export-only contexts agree, with and without a PSK, that a
+    /// wrong sender key gives different exports, and that an all-zero encapped key is a decap
+    /// error, or fails to deserialize with `$zero_enc_parse_err` if that's given
+    macro_rules! test_auth_export_only {
+        ($test_name:ident, $kdf:ty, $kem:ty, $zero_enc_parse_err:expr) => {
+            #[test]
+            fn $test_name() {
+                use crate::{
+                    kem::EncappedKey,
+                    kex::Deserializable,
+                    setup::{setup_receiver_auth_export_only, setup_sender_auth_export_only},
+                };
+
+                type Kdf = $kdf;
+                type Kem = $kem;
+
+                let mut csprng = StdRng::from_entropy();
+
+                let info = b"just the secrets";
+                let (psk, psk_id) = (gen_rand_buf(), gen_rand_buf());
+                let psk_bundle = PskBundle {
+                    psk: &psk,
+                    psk_id: &psk_id,
+                };
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let (sk_sender, pk_sender) = Kem::gen_keypair(&mut csprng);
+                let (_, pk_impostor) = Kem::gen_keypair(&mut csprng);
+
+                for psk in &[None, Some(psk_bundle)] {
+                    let (encapped_key, sender_ctx) = setup_sender_auth_export_only::<Kdf, Kem, _>(
+                        (&sk_sender, &pk_sender),
+                        *psk,
+                        &pk_recip,
+                        &info[..],
+                        &mut csprng,
+                    )
+                    .unwrap();
+                    let receiver_ctx = setup_receiver_auth_export_only::<Kdf, Kem>(
+                        &pk_sender,
+                        *psk,
+                        &sk_recip,
+                        &encapped_key,
+                        &info[..],
+                    )
+                    .unwrap();
+
+                    let mut sender_secret = [0u8; 32];
+                    let mut receiver_secret = [0u8; 32];
+                    sender_ctx.export(b"key", &mut sender_secret).unwrap();
+                    receiver_ctx.export(b"key", &mut receiver_secret).unwrap();
+                    assert_eq!(sender_secret, receiver_secret);
+
+                    // Someone claiming to be someone else gets a different secret
+                    let impostor_ctx = setup_receiver_auth_export_only::<Kdf, Kem>(
+                        &pk_impostor,
+                        *psk,
+                        &sk_recip,
+                        &encapped_key,
+                        &info[..],
+                    )
+                    .unwrap();
+                    let mut impostor_secret = [0u8; 32];
+                    impostor_ctx.export(b"key", &mut impostor_secret).unwrap();
+                    assert_ne!(sender_secret, impostor_secret);
+                }
 
-                // Construct the sender's encryption context normally
-                let (encapped_key, sender_ctx) =
-                    setup_sender::<A, Kdf, Kem, _>(&sender_mode, &pk_recip, &info[..], &mut csprng)
+                // An encapped key that the DH rejects is a decap error. The all-zero X25519 point
+                // has low order, so it deserializes and the DH rejects it. The all-zero P-256
+                // encoding is the point at infinity, which doesn't even deserialize.
+                let zeros = [0u8; 65];
+                let enc_len = <EncappedKey<<Kem as KemTrait>::Kex> as Serializable>::size();
+                let parsed = EncappedKey::<<Kem as KemTrait>::Kex>::from_bytes(&zeros[..enc_len]);
+                match $zero_enc_parse_err {
+                    None => {
+                        let bad_encapped_key = parsed.unwrap();
+                        assert!(matches!(
+                            setup_receiver_auth_export_only::<Kdf, Kem>(
+                                &pk_sender,
+                                None,
+                                &sk_recip,
+                                &bad_encapped_key,
+                                &info[..],
+                            ),
+                            Err(HpkeError::DecapError)
+                        ));
+                    }
+                    Some(expected_err) => assert_eq!(parsed.err(), Some(expected_err)),
+                }
+            }
+        };
+    }
+
+    /// Tests that `setup_receiver` with the export-only AEAD, which skips deriving an AEAD key and
+    /// nonce, exports exactly what the full key schedule from draft11 §5.1 says it should
+    macro_rules! test_export_only_fast_path {
+        ($test_name:ident, $kdf:ty, $kem:ty) => {
+            #[test]
+            fn $test_name() {
+                use crate::{
+                    aead::{AeadCtx, AeadKey, AeadNonce, ExportOnlyAead},
+                    kdf::{labeled_extract, LabeledExpand},
+                    kem::encap,
+                    setup::ExporterSecret,
+                    util::full_suite_id,
+                };
+
+                type A = ExportOnlyAead;
+                type Kdf = $kdf;
+                type Kem = $kem;
+
+                let mut csprng = StdRng::from_entropy();
+                let info = b"only here for the exports";
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let (shared_secret, encapped_key) =
+                    encap::<Kem, _>(&pk_recip, None, &mut csprng).unwrap();
+
+                // Run the whole Base mode key schedule by hand, up to the exporter secret
+                let suite_id = full_suite_id::<A, Kdf, Kem>();
+                let (psk_id_hash, _) = labeled_extract::<Kdf>(&[], &suite_id, b"psk_id_hash", &[]);
+                let (info_hash, _) = labeled_extract::<Kdf>(&[], &suite_id, b"info_hash", info);
+                let mut sched_context = vec![0u8];
+                sched_context.extend_from_slice(&psk_id_hash);
+                sched_context.extend_from_slice(&info_hash);
+                let (_, secret_ctx) =
+                    labeled_extract::<Kdf>(shared_secret.as_ref(), &suite_id, b"secret", &[]);
+                let mut exporter_secret = ExporterSecret::<Kdf>::default();
+                secret_ctx
+                    .labeled_expand(&suite_id, b"exp", &sched_context, &mut exporter_secret.0)
+                    .unwrap();
+                let expected_ctx = AeadCtx::<A, Kdf, Kem>::new(
+                    &AeadKey::default(),
+                    AeadNonce::default(),
+                    exporter_secret,
+                );
+
+                let receiver_ctx =
+                    setup_receiver::<A, Kdf, Kem>(&OpModeR::Base, &sk_recip, &encapped_key, info)
                         .unwrap();
+                for exporter_ctx in &[&b""[..], b"first", b"second"] {
+                    let mut expected = [0u8; 32];
+                    let mut got = [0u8; 32];
+                    expected_ctx.export(exporter_ctx, &mut expected).unwrap();
+                    receiver_ctx.export(exporter_ctx, &mut got).unwrap();
+                    assert_eq!(got, expected);
+                }
+            }
+        };
+    }
 
-                // Now make a receiver with the wrong info string and ensure it doesn't match the
-                // sender
-                let bad_info = b"something else";
-                let mut receiver_ctx = setup_receiver::<_, _, Kem>(
-                    &receiver_mode,
-                    &sk_recip,
-                    &encapped_key,
-                    &bad_info[..],
+    /// Tests that the key schedule under `LabelConfig::HPKE` makes exactly the context that the
+    /// standard key schedule makes
+    macro_rules! test_default_labels {
+        ($test_name:ident, $kem:ty) => {
+            #[test]
+            fn $test_name() {
+                use crate::{
+                    aead::{AeadCtxR, AeadCtxS},
+                    kem::encap,
+                    labels::LabelConfig,
+                    setup::{derive_enc_ctx, derive_enc_ctx_with_labels},
+                };
+
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem;
+
+                assert_eq!(LabelConfig::default(), LabelConfig::HPKE);
+
+                let mut csprng = StdRng::from_entropy();
+                let info = b"nothing new here";
+                let (_, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let (shared_secret, _) = encap::<Kem, _>(&pk_recip, None, &mut csprng).unwrap();
+
+                let mut sender_ctx: AeadCtxS<A, Kdf, Kem> =
+                    derive_enc_ctx(&OpModeS::Base, shared_secret.clone(), info).into();
+                let mut receiver_ctx: AeadCtxR<A, Kdf, Kem> = derive_enc_ctx_with_labels(
+                    &OpModeR::<<Kem as KemTrait>::Kex>::Base,
+                    shared_secret,
+                    info,
+                    &LabelConfig::HPKE,
                 )
-                .unwrap();
-                assert!(!aead_ctx_eq(&mut sender_ctx.clone(), &mut receiver_ctx));
+                .into();
 
-                // Now make a receiver with the wrong secret key and ensure it doesn't match the
-                // sender
-                let (bad_sk, _) = Kem::gen_keypair(&mut csprng);
-                let mut aead_ctx2 =
-                    setup_receiver::<_, _, Kem>(&receiver_mode, &bad_sk, &encapped_key, &info[..])
-                        .unwrap();
-                assert!(!aead_ctx_eq(&mut sender_ctx.clone(), &mut aead_ctx2));
+                let mut sender_secret = [0u8; 32];
+                let mut receiver_secret = [0u8; 32];
+                sender_ctx.export(b"exp", &mut sender_secret).unwrap();
+                receiver_ctx.export(b"exp", &mut receiver_secret).unwrap();
+                assert_eq!(sender_secret, receiver_secret);
+                assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+            }
+        };
+    }
 
-                // Now make a receiver with the wrong encapped key and ensure it doesn't match the
-                // sender. The reason `bad_encapped_key` is bad is because its underlying key is
-                // uniformly random, and therefore different from the key that the sender sent.
-                let (bad_encapped_key, _) =
-                    setup_sender::<A, Kdf, Kem, _>(&sender_mode, &pk_recip, &info[..], &mut csprng)
-                        .unwrap();
-                let mut aead_ctx2 = setup_receiver::<_, _, Kem>(
-                    &receiver_mode,
-                    &sk_recip,
-                    &bad_encapped_key,
-                    &info[..],
+    /// Tests that a sender and receiver with the same custom labels agree, and that their context
+    /// differs from HPKE's
+    #[cfg(feature = "experimental-labels")]
+    macro_rules! test_custom_labels {
+        ($test_name:ident, $kem:ty) => {
+            #[test]
+            fn $test_name() {
+                use crate::{
+                    labels::LabelConfig,
+                    setup::{setup_receiver_with_labels, setup_sender_with_labels},
+                };
+
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem;
+
+                let labels = LabelConfig {
+                    version_label: b"NOT-HPKE-v1",
+                    suite_id_prefix: *b"NOPE",
+                };
+
+                let mut csprng = StdRng::from_entropy();
+                let info = b"something else entirely";
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let (encapped_key, mut sender_ctx) = setup_sender_with_labels::<A, Kdf, Kem, _>(
+                    &labels,
+                    &OpModeS::Base,
+                    &pk_recip,
+                    info,
+                    &mut csprng,
                 )
                 .unwrap();
-                assert!(!aead_ctx_eq(&mut sender_ctx.clone(), &mut aead_ctx2));
-
-                // Now make sure that this test was a valid test by ensuring that doing everything
-                // the right way makes it pass
-                let mut aead_ctx2 = setup_receiver::<_, _, Kem>(
-                    &receiver_mode,
+                let mut receiver_ctx = setup_receiver_with_labels::<A, Kdf, Kem>(
+                    &labels,
+                    &OpModeR::Base,
                     &sk_recip,
                     &encapped_key,
-                    &info[..],
+                    info,
                 )
                 .unwrap();
-                assert!(aead_ctx_eq(&mut sender_ctx.clone(), &mut aead_ctx2));
+                let mut sender_secret = [0u8; 32];
+                let mut receiver_secret = [0u8; 32];
+                sender_ctx.export(b"exp", &mut sender_secret).unwrap();
+                receiver_ctx.export(b"exp", &mut receiver_secret).unwrap();
+                assert_eq!(sender_secret, receiver_secret);
+                assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+
+                // A standard HPKE receiver decaps the same shared secret, but derives a different
+                // context from it
+                let hpke_ctx =
+                    setup_receiver::<A, Kdf, Kem>(&OpModeR::Base, &sk_recip, &encapped_key, info)
+                        .unwrap();
+                let mut hpke_secret = [0u8; 32];
+                hpke_ctx.export(b"exp", &mut hpke_secret).unwrap();
+                assert_ne!(sender_secret, hpke_secret);
+            }
+        };
+    }
+
+    /// Tests that a sender and receiver with the same extra KEM context agree, in both the
+    /// unauthenticated and authenticated KEM, and that a different context makes open fail
+    #[cfg(feature = "experimental-kem-context")]
+    macro_rules! test_kem_context {
+        ($test_name:ident, $kem:ty) => {
+            #[test]
+            fn $test_name() {
+                use crate::setup::{
+                    setup_receiver_with_kem_context, setup_sender_with_kem_context,
+                };
+
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem;
+
+                let mut csprng = StdRng::from_entropy();
+                let info = b"layered";
+                let group_context = b"epoch 7 of group 42";
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let (sk_sender, pk_sender) = Kem::gen_keypair(&mut csprng);
+
+                let modes = [
+                    (OpModeS::Base, OpModeR::Base),
+                    (
+                        OpModeS::Auth((sk_sender.clone(), pk_sender.clone())),
+                        OpModeR::Auth(pk_sender.clone()),
+                    ),
+                ];
+                for (sender_mode, receiver_mode) in modes.iter() {
+                    let (encapped_key, mut sender_ctx) =
+                        setup_sender_with_kem_context::<A, Kdf, Kem, _>(
+                            group_context,
+                            sender_mode,
+                            &pk_recip,
+                            info,
+                            &mut csprng,
+                        )
+                        .unwrap();
+                    let msg = b"for the group";
+                    let mut ciphertext = *msg;
+                    let tag = sender_ctx.seal(&mut ciphertext, b"").unwrap();
+
+                    // Returns whether a receiver with the given extra context can open the message
+                    let opens = |extra_kem_context: &[u8]| {
+                        let mut receiver_ctx = setup_receiver_with_kem_context::<A, Kdf, Kem>(
+                            extra_kem_context,
+                            receiver_mode,
+                            &sk_recip,
+                            &encapped_key,
+                            info,
+                        )
+                        .unwrap();
+                        let mut buf = ciphertext;
+                        receiver_ctx.open(&mut buf, b"", &tag).is_ok()
+                    };
+                    assert!(opens(group_context));
+                    assert!(!opens(b"epoch 8 of group 42"));
+                    assert!(!opens(b""));
+
+                    // An empty extra context is plain HPKE
+                    let (encapped_key, mut sender_ctx) =
+                        setup_sender_with_kem_context::<A, Kdf, Kem, _>(
+                            b"",
+                            sender_mode,
+                            &pk_recip,
+                            info,
+                            &mut csprng,
+                        )
+                        .unwrap();
+                    let mut receiver_ctx = setup_receiver::<A, Kdf, Kem>(
+                        receiver_mode,
+                        &sk_recip,
+                        &encapped_key,
+                        info,
+                    )
+                    .unwrap();
+                    assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+                }
             }
         };
     }
@@ -348,6 +2489,17 @@ mod test {
     mod x25519_tests {
         use super::*;
 
+        test_export_only_fast_path!(
+            test_export_only_fast_path_x25519,
+            HkdfSha384,
+            crate::kem::X25519HkdfSha256
+        );
+        test_default_labels!(test_default_labels_x25519, crate::kem::X25519HkdfSha256);
+        test_sender_public_key!(test_sender_public_key_x25519, crate::kem::X25519HkdfSha256);
+        #[cfg(feature = "experimental-labels")]
+        test_custom_labels!(test_custom_labels_x25519, crate::kem::X25519HkdfSha256);
+        #[cfg(feature = "experimental-kem-context")]
+        test_kem_context!(test_kem_context_x25519, crate::kem::X25519HkdfSha256);
         test_setup_correctness!(
             test_setup_correctness_x25519,
             ChaCha20Poly1305,
@@ -360,12 +2512,109 @@ mod test {
             HkdfSha256,
             crate::kem::X25519HkdfSha256
         );
+        test_auth_psk!(
+            test_auth_psk_x25519_aes128_sha256,
+            AesGcm128,
+            HkdfSha256,
+            crate::kem::X25519HkdfSha256
+        );
+        test_auth_psk!(
+            test_auth_psk_x25519_aes256_sha384,
+            AesGcm256,
+            HkdfSha384,
+            crate::kem::X25519HkdfSha256
+        );
+        test_auth_psk!(
+            test_auth_psk_x25519_chacha_sha512,
+            ChaCha20Poly1305,
+            HkdfSha512,
+            crate::kem::X25519HkdfSha256
+        );
+        test_setup_borrowed_auth!(
+            test_setup_borrowed_auth_x25519,
+            ChaCha20Poly1305,
+            HkdfSha256,
+            crate::kem::X25519HkdfSha256
+        );
+        test_setup_reuses_pk_recip!(
+            test_setup_reuses_pk_recip_x25519,
+            ChaCha20Poly1305,
+            HkdfSha256,
+            crate::kem::X25519HkdfSha256
+        );
+        test_setup_idempotent!(
+            test_setup_idempotent_x25519,
+            ChaCha20Poly1305,
+            HkdfSha256,
+            crate::kem::X25519HkdfSha256
+        );
+        test_setup_with_info!(
+            test_setup_with_info_x25519,
+            ChaCha20Poly1305,
+            HkdfSha256,
+            crate::kem::X25519HkdfSha256
+        );
+        #[cfg(feature = "alloc")]
+        test_setup_bind_recipient!(
+            test_setup_bind_recipient_x25519,
+            ChaCha20Poly1305,
+            HkdfSha256,
+            crate::kem::X25519HkdfSha256
+        );
+        test_setup_hashed_info!(
+            test_setup_hashed_info_x25519,
+            ChaCha20Poly1305,
+            HkdfSha384,
+            crate::kem::X25519HkdfSha256
+        );
+        test_split_setup!(
+            test_split_setup_x25519,
+            ChaCha20Poly1305,
+            HkdfSha256,
+            crate::kem::X25519HkdfSha256
+        );
+        test_auth_export_only!(
+            test_auth_export_only_x25519,
+            HkdfSha256,
+            crate::kem::X25519HkdfSha256,
+            None
+        );
+        #[cfg(feature = "raw-shared-secret")]
+        test_setup_receiver_from_shared_secret!(
+            test_setup_receiver_from_shared_secret_x25519,
+            ChaCha20Poly1305,
+            HkdfSha256,
+            crate::kem::X25519HkdfSha256
+        );
+        test_recover_receiver!(
+            test_recover_receiver_x25519,
+            ChaCha20Poly1305,
+            HkdfSha256,
+            crate::kem::X25519HkdfSha256
+        );
+        test_setup_max_info_len!(
+            test_setup_max_info_len_x25519,
+            ChaCha20Poly1305,
+            HkdfSha256,
+            crate::kem::X25519HkdfSha256
+        );
     }
 
     #[cfg(feature = "p256")]
     mod p256_tests {
         use super::*;
 
+        test_export_only_fast_path!(
+            test_export_only_fast_path_p256,
+            HkdfSha384,
+            crate::kem::DhP256HkdfSha256
+        );
+        test_default_labels!(test_default_labels_p256, crate::kem::DhP256HkdfSha256);
+        test_sender_public_key!(test_sender_public_key_p256, crate::kem::DhP256HkdfSha256);
+        #[cfg(feature = "experimental-labels")]
+        test_custom_labels!(test_custom_labels_p256, crate::kem::DhP256HkdfSha256);
+        #[cfg(feature = "experimental-kem-context")]
+        test_kem_context!(test_kem_context_p256, crate::kem::DhP256HkdfSha256);
         test_setup_correctness!(
             test_setup_correctness_p256,
             ChaCha20Poly1305,
@@ -378,5 +2627,376 @@ mod test {
             HkdfSha256,
             crate::kem::DhP256HkdfSha256
         );
+        test_auth_psk!(
+            test_auth_psk_p256_aes128_sha256,
+            AesGcm128,
+            HkdfSha256,
+            crate::kem::DhP256HkdfSha256
+        );
+        test_auth_psk!(
+            test_auth_psk_p256_aes256_sha384,
+            AesGcm256,
+            HkdfSha384,
+            crate::kem::DhP256HkdfSha256
+        );
+        test_auth_psk!(
+            test_auth_psk_p256_chacha_sha512,
+            ChaCha20Poly1305,
+            HkdfSha512,
+            crate::kem::DhP256HkdfSha256
+        );
+        test_setup_borrowed_auth!(
+            test_setup_borrowed_auth_p256,
+            ChaCha20Poly1305,
+            HkdfSha256,
+            crate::kem::DhP256HkdfSha256
+        );
+        test_setup_reuses_pk_recip!(
+            test_setup_reuses_pk_recip_p256,
+            ChaCha20Poly1305,
+            HkdfSha256,
+            crate::kem::DhP256HkdfSha256
+        );
+        test_setup_idempotent!(
+            test_setup_idempotent_p256,
+            ChaCha20Poly1305,
+            HkdfSha384,
+            crate::kem::DhP256HkdfSha256
+        );
+        test_setup_with_info!(
+            test_setup_with_info_p256,
+            AesGcm256,
+            HkdfSha256,
+            crate::kem::DhP256HkdfSha256
+        );
+        #[cfg(feature = "alloc")]
+        test_setup_bind_recipient!(
+            test_setup_bind_recipient_p256,
+            ChaCha20Poly1305,
+            HkdfSha384,
+            crate::kem::DhP256HkdfSha256
+        );
+        test_setup_hashed_info!(
+            test_setup_hashed_info_p256,
+            ChaCha20Poly1305,
+            HkdfSha384,
+            crate::kem::DhP256HkdfSha256
+        );
+        test_split_setup!(
+            test_split_setup_p256,
+            ChaCha20Poly1305,
+            HkdfSha256,
+            crate::kem::DhP256HkdfSha256
+        );
+        test_auth_export_only!(
+            test_auth_export_only_p256,
+            HkdfSha256,
+            crate::kem::DhP256HkdfSha256,
+            Some(HpkeError::ValidationError)
+        );
+        test_recover_receiver!(
+            test_recover_receiver_p256,
+            ChaCha20Poly1305,
+            HkdfSha256,
+            crate::kem::DhP256HkdfSha256
+        );
+        #[cfg(feature = "raw-shared-secret")]
+        test_setup_receiver_from_shared_secret!(
+            test_setup_receiver_from_shared_secret_p256,
+            ChaCha20Poly1305,
+            HkdfSha256,
+            crate::kem::DhP256HkdfSha256
+        );
+        test_setup_max_info_len!(
+            test_setup_max_info_len_p256,
+            AesGcm128,
+            HkdfSha256,
+            crate::kem::DhP256HkdfSha256
+        );
+    }
+
+    /// Tests every intermediate value of the key schedule against the draft11 test vector for
+    /// Base mode with DHKEM(X25519, HKDF-SHA256), HKDF-SHA256, and AES-128-GCM
+    #[cfg(all(feature = "test-internals", feature = "x25519-dalek"))]
+    #[test]
+    fn test_setup_sender_debug_vector() {
+        use super::setup_sender_debug;
+        use crate::{
+            aead::AesGcm128,
+            kem::X25519HkdfSha256,
+            kex::{Deserializable, KeyExchange},
+            test_util::ReplayRng,
+        };
+
+        type Kem = X25519HkdfSha256;
+        type Kex = <Kem as KemTrait>::Kex;
+
+        let unhex = |s: &str| hex::decode(s).unwrap();
+        let info = unhex("4f6465206f6e2061204772656369616e2055726e");
+        let ikm_eph = unhex("7268600d403fce431561aef583ee1613527cff655c1343f29812e66706df3234");
+        let pk_recip = <Kex as KeyExchange>::PublicKey::from_bytes(&unhex(
+            "3948cfe0ad1ddb695d780e59077195da6c56506b027329794ab02bca80815c4d",
+        ))
+        .unwrap();
+
+        let (encapped_key, _, debug) = setup_sender_debug::<AesGcm128, HkdfSha256, Kem, _>(
+            &OpModeS::Base,
+            &pk_recip,
+            &info,
+            &mut ReplayRng(&ikm_eph),
+        )
+        .unwrap();
+
+        assert_eq!(
+            encapped_key.to_bytes().as_slice(),
+            unhex("37fda3567bdbd628e88668c3c8d7e97d1d1253b6d4ea6d44c150f741f1bf4431")
+        );
+        assert_eq!(
+            debug.shared_secret.as_ref(),
+            unhex("fe0e18c9f024ce43799ae393c7e8fe8fce9d218875e8227b0187c04e7d2ea1fc")
+        );
+        assert_eq!(
+            debug.key_schedule_context,
+            unhex(
+                "00725611c9d98c07c03f60095cd32d400d8347d45ed67097bbad50fc56da742d07cb6cffde367bb05\
+                 65ba28bb02c90744a20f5ef37f30523526106f637abb05449"
+            )
+        );
+        assert_eq!(
+            debug.secret.as_slice(),
+            unhex("12fff91991e93b48de37e7daddb52981084bd8aa64289c3788471d9a9712f397")
+        );
+        assert_eq!(
+            debug.key.as_slice(),
+            unhex("4531685d41d65f03dc48f6b8302c05b0")
+        );
+        assert_eq!(
+            debug.base_nonce.as_slice(),
+            unhex("56d890e5accaaf011cff4b7d")
+        );
+        assert_eq!(
+            debug.exporter_secret.as_slice(),
+            unhex("45ff1c2e220db587171952c0592d5f5ebe103f1561a2614e38f2ffd47e99e3f8")
+        );
+    }
+
+    /// Tests the KDF inputs reported by key_schedule_inputs against the draft11 test vector for
+    /// Base mode with DHKEM(X25519, HKDF-SHA256), HKDF-SHA256, and AES-128-GCM. Running HKDF on
+    /// them directly has to give the vector's secret, key, base nonce, and exporter secret.
+    #[cfg(all(feature = "test-internals", feature = "x25519-dalek"))]
+    #[test]
+    fn test_key_schedule_inputs_vector() {
+        use super::key_schedule_inputs;
+        use crate::{aead::AesGcm128, kem::X25519HkdfSha256};
+        use sha2::Sha256;
+
+        type Kem = X25519HkdfSha256;
+
+        let unhex = |s: &str| hex::decode(s).unwrap();
+        let info = unhex("4f6465206f6e2061204772656369616e2055726e");
+        let inputs = key_schedule_inputs::<AesGcm128, HkdfSha256, Kem>(&OpModeR::Base, &info);
+
+        assert_eq!(inputs.suite_id, b"HPKE\x00\x20\x00\x01\x00\x01");
+        assert_eq!(
+            inputs.key_schedule_context,
+            unhex(
+                "00725611c9d98c07c03f60095cd32d400d8347d45ed67097bbad50fc56da742d07cb6cffde367bb05\
+                 65ba28bb02c90744a20f5ef37f30523526106f637abb05449"
+            )
+        );
+        assert_eq!(
+            inputs.psk_id_hash_ikm,
+            b"HPKE-v1HPKE\x00\x20\x00\x01\x00\x01psk_id_hash"
+        );
+        assert_eq!(
+            inputs.info_hash_ikm,
+            [&b"HPKE-v1HPKE\x00\x20\x00\x01\x00\x01info_hash"[..], &info].concat()
+        );
+        assert_eq!(
+            &inputs.key_info[..],
+            [
+                &b"\x00\x10HPKE-v1HPKE\x00\x20\x00\x01\x00\x01key"[..],
+                &inputs.key_schedule_context
+            ]
+            .concat()
+        );
+
+        // The hashes in key_schedule_context come from extracting the IKMs with an empty salt
+        let (psk_id_hash, _) = hkdf::Hkdf::<Sha256>::extract(Some(&[]), &inputs.psk_id_hash_ikm);
+        let (info_hash, _) = hkdf::Hkdf::<Sha256>::extract(Some(&[]), &inputs.info_hash_ikm);
+        assert_eq!(&inputs.key_schedule_context[1..33], psk_id_hash.as_slice());
+        assert_eq!(&inputs.key_schedule_context[33..], info_hash.as_slice());
+
+        // Now run the rest of the key schedule on the reported inputs
+        let shared_secret =
+            unhex("fe0e18c9f024ce43799ae393c7e8fe8fce9d218875e8227b0187c04e7d2ea1fc");
+        let (secret, secret_ctx) =
+            hkdf::Hkdf::<Sha256>::extract(Some(&shared_secret), &inputs.secret_ikm);
+        assert_eq!(
+            secret.as_slice(),
+            unhex("12fff91991e93b48de37e7daddb52981084bd8aa64289c3788471d9a9712f397")
+        );
+        let mut key = [0u8; 16];
+        secret_ctx.expand(&inputs.key_info, &mut key).unwrap();
+        assert_eq!(key.to_vec(), unhex("4531685d41d65f03dc48f6b8302c05b0"));
+        let mut base_nonce = [0u8; 12];
+        secret_ctx
+            .expand(&inputs.base_nonce_info, &mut base_nonce)
+            .unwrap();
+        assert_eq!(base_nonce.to_vec(), unhex("56d890e5accaaf011cff4b7d"));
+        let mut exporter_secret = [0u8; 32];
+        secret_ctx
+            .expand(&inputs.exporter_secret_info, &mut exporter_secret)
+            .unwrap();
+        assert_eq!(
+            exporter_secret.to_vec(),
+            unhex("45ff1c2e220db587171952c0592d5f5ebe103f1561a2614e38f2ffd47e99e3f8")
+        );
+    }
+
+    /// Tests that a message with a malformed encapped key is rejected with a `ValidationError`
+    /// before any DH with the recipient's private key happens. This uses P-256 keys with a DH
+    /// that counts how often it's called.
+    #[cfg(feature = "p256")]
+    #[test]
+    fn test_malformed_enc_rejected_before_decap() {
+        use crate::{
+            kex::{ecdh_nistp, DhP256, KexError, KeyExchange},
+            single_shot::parse_received,
+            util::KemSuiteId,
+        };
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        static KEX_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        /// P-256, except every DH is counted
+        struct CountingDh;
+        impl KeyExchange for CountingDh {
+            type PublicKey = ecdh_nistp::PublicKey;
+            type PrivateKey = ecdh_nistp::PrivateKey;
+            type KexResult = <DhP256 as KeyExchange>::KexResult;
+
+            fn sk_to_pk(sk: &Self::PrivateKey) -> Self::PublicKey {
+                DhP256::sk_to_pk(sk)
+            }
+            fn kex(
+                sk: &Self::PrivateKey,
+                pk: &Self::PublicKey,
+            ) -> Result<Self::KexResult, KexError> {
+                KEX_CALLS.fetch_add(1, Ordering::SeqCst);
+                DhP256::kex(sk, pk)
+            }
+            fn derive_keypair<Kdf: crate::kdf::Kdf>(
+                suite_id: &KemSuiteId,
+                ikm: &[u8],
+            ) -> Result<(Self::PrivateKey, Self::PublicKey), HpkeError> {
+                DhP256::derive_keypair::<Kdf>(suite_id, ikm)
+            }
+            #[cfg(feature = "test-internals")]
+            fn derive_keypair_counted<Kdf: crate::kdf::Kdf>(
+                suite_id: &KemSuiteId,
+                ikm: &[u8],
+            ) -> Result<(Self::PrivateKey, Self::PublicKey, u8), HpkeError> {
+                DhP256::derive_keypair_counted::<Kdf>(suite_id, ikm)
+            }
+        }
+
+        struct CountingKem;
+        impl KemTrait for CountingKem {
+            type Kex = CountingDh;
+            type Kdf = HkdfSha256;
+            // Not a real KEM ID
+            const KEM_ID: u16 = 0xffff;
+        }
+
+        type A = ChaCha20Poly1305;
+        type Kdf = HkdfSha256;
+        type Kem = CountingKem;
+
+        let mut csprng = StdRng::from_entropy();
+        let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+        let info = b"malformed enc";
+
+        // A well-formed message of the form enc || ciphertext || tag
+        let (encapped_key, mut sender_ctx) =
+            setup_sender::<A, Kdf, Kem, _>(&OpModeS::Base, &pk_recip, info, &mut csprng).unwrap();
+        let mut msg = [0u8; 65 + 5 + 16];
+        msg[..65].copy_from_slice(&encapped_key.to_bytes());
+        msg[65..70].copy_from_slice(b"hello");
+        let tag = sender_ctx.seal(&mut msg[65..70], b"").unwrap();
+        msg[70..].copy_from_slice(&tag.to_bytes());
+
+        // Replace enc with (1, 1), which isn't on the curve. Nothing gets to the DH.
+        let mut malformed_msg = msg;
+        malformed_msg[1..65].iter_mut().for_each(|b| *b = 0);
+        malformed_msg[32] = 1;
+        malformed_msg[64] = 1;
+        let kex_calls_before = KEX_CALLS.load(Ordering::SeqCst);
+        assert!(matches!(
+            parse_received::<A, Kem>(&mut malformed_msg),
+            Err(HpkeError::ValidationError)
+        ));
+        assert_eq!(KEX_CALLS.load(Ordering::SeqCst), kex_calls_before);
+
+        // The well-formed message makes it to exactly one DH
+        let (encapped_key, ciphertext, tag) = parse_received::<A, Kem>(&mut msg).unwrap();
+        let mut receiver_ctx =
+            setup_receiver::<A, Kdf, Kem>(&OpModeR::Base, &sk_recip, &encapped_key, info).unwrap();
+        assert_eq!(KEX_CALLS.load(Ordering::SeqCst), kex_calls_before + 1);
+        receiver_ctx.open(ciphertext, b"", &tag).unwrap();
+        assert_eq!(ciphertext, b"hello");
+    }
+
+    /// Tests that the key schedule's sanity check fires when the derived AEAD key is all zeros.
+    /// The KDF here uses a "hash" that always outputs zeros, so HMAC, and hence every HKDF
+    /// output, is all zeros too.
+    #[cfg(all(feature = "test-internals", feature = "x25519-dalek", debug_assertions))]
+    #[test]
+    #[should_panic(expected = "key schedule derived an all-zero AEAD key")]
+    fn test_all_zero_key_asserts() {
+        use crate::{kdf::Kdf as KdfTrait, kem::X25519HkdfSha256};
+        use digest::{BlockInput, FixedOutput, Reset, Update};
+        use generic_array::{
+            typenum::{U32, U64},
+            GenericArray,
+        };
+
+        #[derive(Clone, Default)]
+        struct ZeroHash;
+        impl Update for ZeroHash {
+            fn update(&mut self, _: impl AsRef<[u8]>) {}
+        }
+        impl BlockInput for ZeroHash {
+            type BlockSize = U64;
+        }
+        impl FixedOutput for ZeroHash {
+            type OutputSize = U32;
+            fn finalize_into(self, out: &mut GenericArray<u8, U32>) {
+                out.iter_mut().for_each(|b| *b = 0);
+            }
+            fn finalize_into_reset(&mut self, out: &mut GenericArray<u8, U32>) {
+                out.iter_mut().for_each(|b| *b = 0);
+            }
+        }
+        impl Reset for ZeroHash {
+            fn reset(&mut self) {}
+        }
+
+        struct ZeroKdf;
+        impl KdfTrait for ZeroKdf {
+            type HashImpl = ZeroHash;
+            // Not a real algorithm ID
+            const KDF_ID: u16 = 0xFFFE;
+        }
+
+        type Kem = X25519HkdfSha256;
+        let mut csprng = StdRng::from_entropy();
+        let (_, pk_recip) = Kem::gen_keypair(&mut csprng);
+        let _ = setup_sender::<ChaCha20Poly1305, ZeroKdf, Kem, _>(
+            &OpModeS::Base,
+            &pk_recip,
+            b"degenerate",
+            &mut csprng,
+        );
     }
 }
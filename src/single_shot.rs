@@ -2,7 +2,7 @@ use crate::{
     aead::{Aead, AeadTag},
     kdf::Kdf as KdfTrait,
     kem::{EncappedKey, Kem as KemTrait},
-    kex::KeyExchange,
+    kex::{Deserializable, KeyExchange, Serializable},
     op_mode::{OpModeR, OpModeS},
     setup::{setup_receiver, setup_sender},
     HpkeError,
@@ -10,6 +10,13 @@ use crate::{
 
 use rand_core::{CryptoRng, RngCore};
 
+#[cfg(feature = "alloc")]
+use crate::aead::ExportOnlyAead;
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+#[cfg(feature = "alloc")]
+use zeroize::Zeroize;
+
 // draft11 §6.1
 // def SealAuthPSK(pkR, info, aad, pt, psk, psk_id, skS):
 //   enc, ctx = SetupAuthPSKS(pkR, info, psk, psk_id, skS)
@@ -82,6 +89,468 @@ where
     aead_ctx.open(ciphertext, aad, tag)
 }
 
+/// Splits a received message of the form `encapped_key || ciphertext || tag` into its parts. The
+/// ciphertext is returned as a subslice of `buf`, so it can be passed straight to `open` or
+/// `single_shot_open`.
+///
+/// Return Value
+/// ============
+/// Returns `Ok((encapped_key, ciphertext, tag))` on success. If `buf` is shorter than an encapped
+/// key plus a tag, returns
+/// `Err(HpkeError::IncorrectInputLength { expected: min_len, got: buf.len() })`. If the encapped
+/// key fails to deserialize, returns the error from `EncappedKey::from_bytes`.
+pub fn parse_received<A, Kem>(
+    buf: &mut [u8],
+) -> Result<(EncappedKey<Kem::Kex>, &mut [u8], AeadTag<A>), HpkeError>
+where
+    A: Aead,
+    Kem: KemTrait,
+{
+    let enc_len = EncappedKey::<Kem::Kex>::size();
+    let tag_len = AeadTag::<A>::size();
+    let min_len = enc_len + tag_len;
+    if buf.len() < min_len {
+        return Err(HpkeError::IncorrectInputLength {
+            expected: min_len,
+            got: buf.len(),
+        });
+    }
+
+    let ct_end = buf.len() - tag_len;
+    let (enc_bytes, rest) = buf.split_at_mut(enc_len);
+    let (ciphertext, tag_bytes) = rest.split_at_mut(ct_end - enc_len);
+
+    let encapped_key = EncappedKey::<Kem::Kex>::from_bytes(enc_bytes)?;
+    let tag = AeadTag::<A>::from_bytes(tag_bytes)?;
+    Ok((encapped_key, ciphertext, tag))
+}
+
+/// Checks that a received message of the form `encapped_key || ciphertext || tag` is well-formed,
+/// without doing any decapsulation or decryption. This is meant for cheaply shedding malformed
+/// traffic before spending effort on it.
+///
+/// The encapped key is deserialized the same way `EncappedKey::from_bytes` does it. For P-256, this
+/// checks that the key is a valid curve point, which costs a field square root, but is much
+/// cheaper than the scalar multiplications in a decap. For X25519, every 32-byte string is a valid
+/// public key, so only the length is checked. Passing this check does not mean the message will
+/// decrypt.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(())` if the message is well-formed. If `enc_and_ct` is shorter than an encapped key
+/// plus a tag, returns
+/// `Err(HpkeError::IncorrectInputLength { expected: min_len, got: enc_and_ct.len() })`. If the
+/// encapped key fails to deserialize, returns the error from `EncappedKey::from_bytes`.
+pub fn validate_message<Kem, A>(enc_and_ct: &[u8]) -> Result<(), HpkeError>
+where
+    Kem: KemTrait,
+    A: Aead,
+{
+    let enc_len = EncappedKey::<Kem::Kex>::size();
+    let min_len = enc_len + AeadTag::<A>::size();
+    if enc_and_ct.len() < min_len {
+        return Err(HpkeError::IncorrectInputLength {
+            expected: min_len,
+            got: enc_and_ct.len(),
+        });
+    }
+
+    EncappedKey::<Kem::Kex>::from_bytes(&enc_and_ct[..enc_len]).map(|_| ())
+}
+
+/// Does a `single_shot_open` with every one of the given candidate recipient secret keys, for
+/// when the sender doesn't say which of the recipient's keys they encapsulated to. Every key is
+/// tried, even after one succeeds, so that the running time doesn't reveal which key matched.
+///
+/// Return Value
+/// ============
+/// Returns `Ok((idx, plaintext))` on success, where `idx` is the index in `sks_recip` of the first
+/// key that opened the ciphertext. If no key succeeds, returns `Err(HpkeError::OpenError)`.
+#[cfg(feature = "alloc")]
+pub fn try_open_with_keys<A, Kdf, Kem>(
+    mode: &OpModeR<Kem::Kex>,
+    sks_recip: &[<Kem::Kex as KeyExchange>::PrivateKey],
+    encapped_key: &EncappedKey<Kem::Kex>,
+    info: &[u8],
+    ciphertext: &[u8],
+    aad: &[u8],
+    tag: &AeadTag<A>,
+) -> Result<(usize, Vec<u8>), HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    let mut opened: Option<(usize, Vec<u8>)> = None;
+
+    for (idx, sk_recip) in sks_recip.iter().enumerate() {
+        // Decrypt a fresh copy, since a failed open leaves its buffer in an undefined state
+        let mut buf = ciphertext.to_vec();
+        let res =
+            single_shot_open::<A, Kdf, Kem>(mode, sk_recip, encapped_key, info, &mut buf, aad, tag);
+
+        // Only remember the first success, and don't break early
+        if res.is_ok() && opened.is_none() {
+            opened = Some((idx, buf));
+        }
+    }
+
+    opened.ok_or(HpkeError::OpenError)
+}
+
+/// Does a `setup_sender` with the `ExportOnlyAead` and an `export` in one shot. Since no
+/// encryption context is returned, there is no way to reach the `seal()` and `open()` methods
+/// that return `HpkeError::ExportOnly` on export-only contexts.
+///
+/// ```
+/// # #[cfg(feature = "x25519")]
+/// # {
+/// # use rand::{rngs::StdRng, SeedableRng};
+/// # use hpke::{
+/// #     kdf::HkdfSha256, kem::X25519HkdfSha256, single_shot_export_only,
+/// #     single_shot_export_only_receiver, Kem as KemTrait, OpModeR, OpModeS,
+/// # };
+/// # let mut csprng = StdRng::from_entropy();
+/// let (sk_recip, pk_recip) = X25519HkdfSha256::gen_keypair(&mut csprng);
+/// let (encapped_key, secret) = single_shot_export_only::<HkdfSha256, X25519HkdfSha256, _>(
+///     &OpModeS::Base,
+///     &pk_recip,
+///     b"info",
+///     b"label",
+///     32,
+///     &mut csprng,
+/// )
+/// .unwrap();
+/// let recv_secret = single_shot_export_only_receiver::<HkdfSha256, X25519HkdfSha256>(
+///     &OpModeR::Base,
+///     &sk_recip,
+///     &encapped_key,
+///     b"info",
+///     b"label",
+///     32,
+/// )
+/// .unwrap();
+/// assert_eq!(secret, recv_secret);
+/// # }
+/// ```
+///
+/// What comes back is a secret, not an export-only context, so there is nothing to call `seal()`
+/// on:
+///
+#[cfg_attr(feature = "x25519", doc = "```compile_fail")]
+#[cfg_attr(not(feature = "x25519"), doc = "```ignore")]
+/// # use rand::{rngs::StdRng, SeedableRng};
+/// # use hpke::{
+/// #     aead::{AeadCtxS, ExportOnlyAead},
+/// #     kdf::HkdfSha256,
+/// #     kem::X25519HkdfSha256,
+/// #     single_shot_export_only, Kem, OpModeS,
+/// # };
+/// # let mut csprng = StdRng::from_entropy();
+/// # let (_, pk_recip) = X25519HkdfSha256::gen_keypair(&mut csprng);
+/// let (_, mut ctx): (_, AeadCtxS<ExportOnlyAead, HkdfSha256, X25519HkdfSha256>) =
+///     single_shot_export_only::<HkdfSha256, X25519HkdfSha256, _>(
+///         &OpModeS::Base,
+///         &pk_recip,
+///         b"info",
+///         b"label",
+///         32,
+///         &mut csprng,
+///     )
+///     .unwrap();
+/// ctx.seal(&mut [], b"");
+/// ```
+///
+/// Return Value
+/// ============
+/// Returns `Ok((encapped_key, secret))` on success, where `secret` is `out_len` bytes long. If an
+/// error happened during key encapsulation, returns `Err(HpkeError::EncapError)`. If `out_len` is
+/// more than 255x the digest size of the KDF's hash function, returns
+/// `Err(HpkeError::KdfOutputTooLong)`.
+#[cfg(feature = "alloc")]
+pub fn single_shot_export_only<Kdf, Kem, R>(
+    mode: &OpModeS<Kem::Kex>,
+    pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
+    info: &[u8],
+    exporter_ctx: &[u8],
+    out_len: usize,
+    csprng: &mut R,
+) -> Result<(EncappedKey<Kem::Kex>, Vec<u8>), HpkeError>
+where
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    // Encap a key
+    let (encapped_key, aead_ctx) =
+        setup_sender::<ExportOnlyAead, Kdf, Kem, R>(mode, pk_recip, info, csprng)?;
+    // Export
+    let mut secret = vec![0u8; out_len];
+    aead_ctx.export(exporter_ctx, &mut secret)?;
+
+    Ok((encapped_key, secret))
+}
+
+/// Does a `setup_receiver` with the `ExportOnlyAead` and an `export` in one shot. This is the
+/// receiver's side of `single_shot_export_only`.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(secret)` on success, where `secret` is `out_len` bytes long. If an error happened
+/// during key decapsulation, returns `Err(HpkeError::DecapError)`. If `out_len` is more than 255x
+/// the digest size of the KDF's hash function, returns `Err(HpkeError::KdfOutputTooLong)`.
+#[cfg(feature = "alloc")]
+pub fn single_shot_export_only_receiver<Kdf, Kem>(
+    mode: &OpModeR<Kem::Kex>,
+    sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
+    encapped_key: &EncappedKey<Kem::Kex>,
+    info: &[u8],
+    exporter_ctx: &[u8],
+    out_len: usize,
+) -> Result<Vec<u8>, HpkeError>
+where
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    // Decap the key
+    let aead_ctx = setup_receiver::<ExportOnlyAead, Kdf, Kem>(mode, sk_recip, encapped_key, info)?;
+    // Export
+    let mut secret = vec![0u8; out_len];
+    aead_ctx.export(exporter_ctx, &mut secret)?;
+
+    Ok(secret)
+}
+
+/// Seals a symmetric data key to `pk_recip` in `Base` mode. This is the KEM half of the KEM-DEM
+/// pattern, a.k.a. envelope encryption: HPKE encrypts only the short data key, and the bulk data
+/// is encrypted under the data key with a separate AEAD (the DEM). Do this rather than HPKE-seal a
+/// large object directly. The object is then encrypted once, with an AEAD of your choosing, and
+/// rewrapping it for a new recipient only means sealing the data key again.
+///
+/// The recommended flow is:
+///
+/// 1. Generate a fresh random data key for every object.
+/// 2. Encrypt the object with the data key and your AEAD. Bind the object's identity in the AAD.
+/// 3. Call `seal_data_key`, and store the encapped key and sealed data key next to the object.
+/// 4. To decrypt, call `open_data_key`, then decrypt the object with the data key.
+///
+/// ```
+/// # #[cfg(feature = "x25519")]
+/// # {
+/// # use rand::{rngs::StdRng, RngCore, SeedableRng};
+/// use chacha20poly1305::aead::{Aead as _, NewAead as _, Payload};
+/// use hpke::{
+///     aead::ChaCha20Poly1305, kdf::HkdfSha256, kem::X25519HkdfSha256, open_data_key,
+///     seal_data_key, Kem as KemTrait,
+/// };
+/// # let mut csprng = StdRng::from_entropy();
+/// # let object = vec![0x42; 1 << 20];
+/// let (sk_recip, pk_recip) = X25519HkdfSha256::gen_keypair(&mut csprng);
+///
+/// // Encrypt the object under a fresh data key. A random nonce is fine here, since every data
+/// // key is only ever used once.
+/// let mut data_key = [0u8; 32];
+/// csprng.fill_bytes(&mut data_key);
+/// let object_aad = b"bucket/object-1";
+/// let dem = chacha20poly1305::ChaCha20Poly1305::new(&data_key.into());
+/// let nonce = chacha20poly1305::Nonce::default();
+/// let object_ct = dem
+///     .encrypt(&nonce, Payload { msg: &object, aad: object_aad })
+///     .unwrap();
+///
+/// // Wrap the data key for the recipient
+/// let (encapped_key, sealed_key) = seal_data_key::<ChaCha20Poly1305, HkdfSha256, X25519HkdfSha256, _>(
+///     &pk_recip,
+///     object_aad,
+///     &data_key,
+///     &mut csprng,
+/// )
+/// .unwrap();
+///
+/// // The recipient unwraps the data key and decrypts the object
+/// let opened_key = open_data_key::<ChaCha20Poly1305, HkdfSha256, X25519HkdfSha256>(
+///     &sk_recip,
+///     &encapped_key,
+///     object_aad,
+///     &sealed_key,
+/// )
+/// .unwrap();
+/// let dem = chacha20poly1305::ChaCha20Poly1305::new(opened_key.as_slice().into());
+/// let opened_object = dem
+///     .decrypt(&nonce, Payload { msg: &object_ct, aad: object_aad })
+///     .unwrap();
+/// assert_eq!(opened_object, object);
+/// # }
+/// ```
+///
+/// Return Value
+/// ============
+/// Returns `Ok((encapped_key, sealed_key))` on success, where `sealed_key` is
+/// `ciphertext || tag`. If an error happened during key encapsulation, returns
+/// `Err(HpkeError::EncapError)`. If an error happened during encryption, returns
+/// `Err(HpkeError::SealError)`.
+#[cfg(feature = "alloc")]
+pub fn seal_data_key<A, Kdf, Kem, R>(
+    pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
+    info: &[u8],
+    data_key: &[u8],
+    csprng: &mut R,
+) -> Result<(EncappedKey<Kem::Kex>, Vec<u8>), HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    let mut sealed_key = data_key.to_vec();
+    let res = single_shot_seal::<A, Kdf, Kem, R>(
+        &OpModeS::Base,
+        pk_recip,
+        info,
+        &mut sealed_key,
+        b"",
+        csprng,
+    );
+    match res {
+        Ok((encapped_key, tag)) => {
+            sealed_key.extend_from_slice(&tag.to_bytes());
+            Ok((encapped_key, sealed_key))
+        }
+        Err(e) => {
+            // Don't leave the data key lying around
+            sealed_key.zeroize();
+            Err(e)
+        }
+    }
+}
+
+/// Opens a data key sealed by `seal_data_key`. This is the receiver's side of `seal_data_key`.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(data_key)` on success. If `sealed_key` is shorter than a tag, returns
+/// `Err(HpkeError::IncorrectInputLength { expected: tag_len, got: sealed_key.len() })`. If an error
+/// happened during key decapsulation, returns `Err(HpkeError::DecapError)`. If an error happened
+/// during decryption, returns `Err(HpkeError::OpenError)`.
+#[cfg(feature = "alloc")]
+pub fn open_data_key<A, Kdf, Kem>(
+    sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
+    encapped_key: &EncappedKey<Kem::Kex>,
+    info: &[u8],
+    sealed_key: &[u8],
+) -> Result<Vec<u8>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    let tag_len = AeadTag::<A>::size();
+    if sealed_key.len() < tag_len {
+        return Err(HpkeError::IncorrectInputLength {
+            expected: tag_len,
+            got: sealed_key.len(),
+        });
+    }
+    let (ciphertext, tag_bytes) = sealed_key.split_at(sealed_key.len() - tag_len);
+    let tag = AeadTag::<A>::from_bytes(tag_bytes)?;
+
+    let mut data_key = ciphertext.to_vec();
+    let res = single_shot_open::<A, Kdf, Kem>(
+        &OpModeR::Base,
+        sk_recip,
+        encapped_key,
+        info,
+        &mut data_key,
+        b"",
+        &tag,
+    );
+    match res {
+        Ok(()) => Ok(data_key),
+        Err(e) => {
+            data_key.zeroize();
+            Err(e)
+        }
+    }
+}
+
+/// Opens a message sealed to `old_sk_recip` under one ciphersuite, and seals its plaintext to
+/// `new_pk_recip` under another. This is for migrating stored messages to a new ciphersuite. Both
+/// messages are `ciphertext || tag`, in `Base` mode. The plaintext only ever exists in a buffer
+/// that is zeroized before returning.
+///
+/// Return Value
+/// ============
+/// Returns `Ok((new_encapped_key, new_ciphertext))` on success. If `old_ciphertext` is shorter than
+/// a tag of `OldA`, returns
+/// `Err(HpkeError::IncorrectInputLength { expected: tag_len, got: old_ciphertext.len() })`.
+/// Otherwise, errors are the same as in `single_shot_open` with the old suite, and in
+/// `single_shot_seal` with the new one.
+#[cfg(feature = "alloc")]
+#[allow(clippy::too_many_arguments)]
+pub fn reencrypt<OldA, OldKdf, OldKem, NewA, NewKdf, NewKem, R>(
+    old_sk_recip: &<OldKem::Kex as KeyExchange>::PrivateKey,
+    old_encapped_key: &EncappedKey<OldKem::Kex>,
+    old_info: &[u8],
+    old_ciphertext: &[u8],
+    old_aad: &[u8],
+    new_pk_recip: &<NewKem::Kex as KeyExchange>::PublicKey,
+    new_info: &[u8],
+    new_aad: &[u8],
+    csprng: &mut R,
+) -> Result<(EncappedKey<NewKem::Kex>, Vec<u8>), HpkeError>
+where
+    OldA: Aead,
+    OldKdf: KdfTrait,
+    OldKem: KemTrait,
+    NewA: Aead,
+    NewKdf: KdfTrait,
+    NewKem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    let tag_len = AeadTag::<OldA>::size();
+    if old_ciphertext.len() < tag_len {
+        return Err(HpkeError::IncorrectInputLength {
+            expected: tag_len,
+            got: old_ciphertext.len(),
+        });
+    }
+    let (ciphertext, tag_bytes) = old_ciphertext.split_at(old_ciphertext.len() - tag_len);
+    let tag = AeadTag::<OldA>::from_bytes(tag_bytes)?;
+
+    // Open in place, then seal the plaintext in place. The buffer holds plaintext in between, so
+    // it's wiped on every path out of here.
+    let mut buf = ciphertext.to_vec();
+    let res = single_shot_open::<OldA, OldKdf, OldKem>(
+        &OpModeR::Base,
+        old_sk_recip,
+        old_encapped_key,
+        old_info,
+        &mut buf,
+        old_aad,
+        &tag,
+    )
+    .and_then(|()| {
+        single_shot_seal::<NewA, NewKdf, NewKem, R>(
+            &OpModeS::Base,
+            new_pk_recip,
+            new_info,
+            &mut buf,
+            new_aad,
+            csprng,
+        )
+    });
+    match res {
+        Ok((new_encapped_key, new_tag)) => {
+            buf.extend_from_slice(&new_tag.to_bytes());
+            Ok((new_encapped_key, buf))
+        }
+        Err(e) => {
+            buf.zeroize();
+            Err(e)
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::{single_shot_open, single_shot_seal};
@@ -93,8 +562,159 @@ mod test {
         test_util::{gen_rand_buf, kex_gen_keypair},
     };
 
+    #[cfg(feature = "alloc")]
+    use super::{
+        open_data_key, seal_data_key, single_shot_export_only, single_shot_export_only_receiver,
+        try_open_with_keys,
+    };
+    #[cfg(feature = "alloc")]
+    use crate::{kem::EncappedKey, kex::KeyExchange, HpkeError};
+    #[cfg(feature = "alloc")]
+    use alloc::vec::Vec;
+
     use rand::{rngs::StdRng, SeedableRng};
 
+    /// Tests that parse_received splits a sealed message back into what single_shot_seal output,
+    /// also when the ciphertext is empty, and that a buffer one byte too short is rejected
+    #[cfg(feature = "alloc")]
+    macro_rules! test_parse_received {
+        ($test_name:ident, $aead:ty, $kdf:ty, $kem:ty) => {
+            #[test]
+            fn $test_name() {
+                use super::parse_received;
+                use crate::{aead::AeadTag, kem::EncappedKey, kex::Serializable, HpkeError};
+
+                type A = $aead;
+                type Kdf = $kdf;
+                type Kem = $kem;
+                type Kex = <Kem as KemTrait>::Kex;
+
+                let mut csprng = StdRng::from_entropy();
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let info = b"wire format";
+                let enc_len = EncappedKey::<Kex>::size();
+                let tag_len = AeadTag::<A>::size();
+
+                for msg in &[&b"some message"[..], &b""[..]] {
+                    let mut ciphertext = msg.to_vec();
+                    let (encapped_key, tag) = single_shot_seal::<A, Kdf, Kem, _>(
+                        &OpModeS::Base,
+                        &pk_recip,
+                        &info[..],
+                        &mut ciphertext,
+                        b"",
+                        &mut csprng,
+                    )
+                    .unwrap();
+
+                    // Lay it out the usual way: encapped_key || ciphertext || tag
+                    let mut received = encapped_key.to_bytes().to_vec();
+                    received.extend_from_slice(&ciphertext);
+                    received.extend_from_slice(&tag.to_bytes());
+                    assert_eq!(received.len(), enc_len + msg.len() + tag_len);
+
+                    let (parsed_key, parsed_ct, parsed_tag) =
+                        parse_received::<A, Kem>(&mut received).unwrap();
+                    assert_eq!(parsed_key.to_bytes(), encapped_key.to_bytes());
+                    assert_eq!(parsed_ct, &ciphertext[..]);
+                    assert_eq!(parsed_tag.to_bytes(), tag.to_bytes());
+
+                    // And the parts open in place
+                    single_shot_open::<A, Kdf, Kem>(
+                        &OpModeR::Base,
+                        &sk_recip,
+                        &parsed_key,
+                        &info[..],
+                        parsed_ct,
+                        b"",
+                        &parsed_tag,
+                    )
+                    .unwrap();
+                    assert_eq!(parsed_ct, *msg);
+                }
+
+                let mut too_short = vec![0u8; enc_len + tag_len - 1];
+                assert_eq!(
+                    parse_received::<A, Kem>(&mut too_short).err(),
+                    Some(HpkeError::IncorrectInputLength {
+                        expected: enc_len + tag_len,
+                        got: enc_len + tag_len - 1
+                    })
+                );
+            }
+        };
+    }
+
+    /// Tests that validate_message accepts a real message and rejects a truncated one
+    #[cfg(feature = "alloc")]
+    macro_rules! test_validate_message {
+        ($test_name:ident, $aead:ty, $kdf:ty, $kem:ty) => {
+            #[test]
+            fn $test_name() {
+                use super::validate_message;
+                use crate::{aead::AeadTag, kem::EncappedKey, kex::Serializable, HpkeError};
+
+                type A = $aead;
+                type Kdf = $kdf;
+                type Kem = $kem;
+                type Kex = <Kem as KemTrait>::Kex;
+
+                let mut csprng = StdRng::from_entropy();
+                let (_, pk_recip) = Kem::gen_keypair(&mut csprng);
+
+                let mut ciphertext = b"nothing to see here".to_vec();
+                let (encapped_key, tag) = single_shot_seal::<A, Kdf, Kem, _>(
+                    &OpModeS::Base,
+                    &pk_recip,
+                    b"info",
+                    &mut ciphertext,
+                    b"",
+                    &mut csprng,
+                )
+                .unwrap();
+                let mut received = encapped_key.to_bytes().to_vec();
+                received.extend_from_slice(&ciphertext);
+                received.extend_from_slice(&tag.to_bytes());
+                validate_message::<Kem, A>(&received).unwrap();
+
+                // An empty ciphertext is fine, but anything shorter isn't
+                let min_len = EncappedKey::<Kex>::size() + AeadTag::<A>::size();
+                validate_message::<Kem, A>(&received[..min_len]).unwrap();
+                assert_eq!(
+                    validate_message::<Kem, A>(&received[..min_len - 1]),
+                    Err(HpkeError::IncorrectInputLength {
+                        expected: min_len,
+                        got: min_len - 1
+                    })
+                );
+            }
+        };
+    }
+
+    /// Tests that validate_message rejects a P-256 encapped key that isn't on the curve
+    #[cfg(all(feature = "alloc", feature = "p256"))]
+    #[test]
+    fn test_validate_message_bad_point_p256() {
+        use super::validate_message;
+        use crate::{kem::DhP256HkdfSha256, HpkeError};
+
+        type A = ChaCha20Poly1305;
+        type Kem = DhP256HkdfSha256;
+
+        // An uncompressed point with x = y = 1. 1 != 1 - 3 + b mod p, so it's not on the curve.
+        let mut received = vec![0u8; 65];
+        received[0] = 0x04;
+        received[32] = 1;
+        received[64] = 1;
+        received.extend_from_slice(b"ciphertext");
+        received.extend_from_slice(&[0u8; 16]);
+
+        assert_eq!(
+            validate_message::<Kem, A>(&received),
+            Err(HpkeError::ValidationError)
+        );
+    }
+
     macro_rules! test_single_shot_correctness {
         ($test_name:ident, $aead:ty, $kdf:ty, $kem:ty) => {
             /// Tests that `single_shot_open` can open a `single_shot_seal` ciphertext. This
@@ -166,6 +786,291 @@ mod test {
         };
     }
 
+    /// Tests that `try_open_with_keys` finds the one key out of several that the ciphertext was
+    /// encapsulated to
+    #[cfg(feature = "alloc")]
+    macro_rules! test_try_open_with_keys {
+        ($test_name:ident, $aead:ty, $kdf:ty, $kem:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = $aead;
+                type Kdf = $kdf;
+                type Kem = $kem;
+
+                let msg = b"which one of you is it";
+                let aad = b"line up";
+                let info = b"the usual suspects";
+
+                let mut csprng = StdRng::from_entropy();
+
+                // Make three recipient keypairs and encrypt to the second one
+                let sks_recip = [
+                    Kem::gen_keypair(&mut csprng).0,
+                    Kem::gen_keypair(&mut csprng).0,
+                    Kem::gen_keypair(&mut csprng).0,
+                ];
+                let pk_recip = <Kem as KemTrait>::Kex::sk_to_pk(&sks_recip[1]);
+
+                let mut ciphertext = msg.clone();
+                let (encapped_key, tag) = single_shot_seal::<A, Kdf, Kem, _>(
+                    &OpModeS::Base,
+                    &pk_recip,
+                    info,
+                    &mut ciphertext[..],
+                    aad,
+                    &mut csprng,
+                )
+                .unwrap();
+
+                // Trying all three keys should find the second
+                let (idx, plaintext) = try_open_with_keys::<A, Kdf, Kem>(
+                    &OpModeR::Base,
+                    &sks_recip,
+                    &encapped_key,
+                    info,
+                    &ciphertext,
+                    aad,
+                    &tag,
+                )
+                .expect("try_open_with_keys() failed");
+                assert_eq!(idx, 1);
+                assert_eq!(&plaintext[..], &msg[..]);
+
+                // Without the second key, nothing should work
+                let wrong_sks = [sks_recip[0].clone(), sks_recip[2].clone()];
+                let res = try_open_with_keys::<A, Kdf, Kem>(
+                    &OpModeR::Base,
+                    &wrong_sks,
+                    &encapped_key,
+                    info,
+                    &ciphertext,
+                    aad,
+                    &tag,
+                );
+                assert_eq!(res.err(), Some(HpkeError::OpenError));
+            }
+        };
+    }
+
+    /// Tests that both sides of a single-shot export-only session derive the same secret, and that
+    /// the returned types are just an encapped key and bytes
+    #[cfg(feature = "alloc")]
+    macro_rules! test_single_shot_export_only {
+        ($test_name:ident, $kdf:ty, $kem:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kdf = $kdf;
+                type Kem = $kem;
+                type Kex = <Kem as KemTrait>::Kex;
+
+                let info = b"no seal for you";
+                let exporter_ctx = b"the only thing this is good for";
+
+                let mut csprng = StdRng::from_entropy();
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+
+                // Spell out the types, so that this fails to compile if anything with a seal()
+                // or open() ever gets returned
+                let (encapped_key, secret): (EncappedKey<Kex>, Vec<u8>) =
+                    single_shot_export_only::<Kdf, Kem, _>(
+                        &OpModeS::Base,
+                        &pk_recip,
+                        info,
+                        exporter_ctx,
+                        64,
+                        &mut csprng,
+                    )
+                    .unwrap();
+                let recv_secret: Vec<u8> = single_shot_export_only_receiver::<Kdf, Kem>(
+                    &OpModeR::Base,
+                    &sk_recip,
+                    &encapped_key,
+                    info,
+                    exporter_ctx,
+                    64,
+                )
+                .unwrap();
+
+                assert_eq!(secret.len(), 64);
+                assert_eq!(secret, recv_secret);
+            }
+        };
+    }
+
+    /// Tests that a 32-byte data key round-trips through seal_data_key and open_data_key, and
+    /// that a tampered or truncated sealed key is rejected
+    #[cfg(feature = "alloc")]
+    macro_rules! test_data_key_roundtrip {
+        ($test_name:ident, $kem:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem;
+
+                let info = b"bucket/object-1";
+                let mut csprng = StdRng::from_entropy();
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+
+                let data_key = [0x5au8; 32];
+                let (encapped_key, sealed_key) =
+                    seal_data_key::<A, Kdf, Kem, _>(&pk_recip, info, &data_key, &mut csprng)
+                        .unwrap();
+                // The sealed key is the encrypted data key followed by the tag
+                assert_eq!(sealed_key.len(), 32 + 16);
+                assert_ne!(&sealed_key[..32], &data_key[..]);
+
+                let opened_key =
+                    open_data_key::<A, Kdf, Kem>(&sk_recip, &encapped_key, info, &sealed_key)
+                        .unwrap();
+                assert_eq!(opened_key, data_key.to_vec());
+
+                // A flipped bit anywhere fails to open
+                let mut tampered = sealed_key.clone();
+                tampered[0] ^= 1;
+                assert_eq!(
+                    open_data_key::<A, Kdf, Kem>(&sk_recip, &encapped_key, info, &tampered),
+                    Err(HpkeError::OpenError)
+                );
+                // So does the wrong info string
+                assert_eq!(
+                    open_data_key::<A, Kdf, Kem>(&sk_recip, &encapped_key, b"other", &sealed_key),
+                    Err(HpkeError::OpenError)
+                );
+                // Anything shorter than a tag is a length error
+                assert_eq!(
+                    open_data_key::<A, Kdf, Kem>(&sk_recip, &encapped_key, info, &sealed_key[..15]),
+                    Err(HpkeError::IncorrectInputLength {
+                        expected: 16,
+                        got: 15
+                    })
+                );
+            }
+        };
+    }
+
+    #[cfg(all(feature = "alloc", feature = "x25519-dalek"))]
+    test_data_key_roundtrip!(test_data_key_roundtrip_x25519, crate::kem::X25519HkdfSha256);
+
+    #[cfg(all(feature = "alloc", feature = "p256"))]
+    test_data_key_roundtrip!(test_data_key_roundtrip_p256, crate::kem::DhP256HkdfSha256);
+
+    #[cfg(all(feature = "alloc", feature = "x25519-dalek"))]
+    test_single_shot_export_only!(
+        test_single_shot_export_only_x25519,
+        HkdfSha256,
+        crate::kem::X25519HkdfSha256
+    );
+
+    #[cfg(all(feature = "alloc", feature = "p256"))]
+    test_single_shot_export_only!(
+        test_single_shot_export_only_p256,
+        HkdfSha256,
+        crate::kem::DhP256HkdfSha256
+    );
+
+    #[cfg(all(feature = "alloc", feature = "x25519-dalek"))]
+    test_try_open_with_keys!(
+        test_try_open_with_keys_x25519,
+        ChaCha20Poly1305,
+        HkdfSha256,
+        crate::kem::X25519HkdfSha256
+    );
+
+    #[cfg(all(feature = "alloc", feature = "p256"))]
+    test_try_open_with_keys!(
+        test_try_open_with_keys_p256,
+        ChaCha20Poly1305,
+        HkdfSha256,
+        crate::kem::DhP256HkdfSha256
+    );
+
+    /// Tests that a message sealed under an X25519 suite can be moved to a P-256 suite with
+    /// `reencrypt`, and then only opens under the P-256 suite
+    #[cfg(all(feature = "alloc", feature = "x25519-dalek", feature = "p256"))]
+    #[test]
+    fn test_reencrypt() {
+        use super::reencrypt;
+        use crate::{
+            aead::{AeadTag, AesGcm128},
+            kem::{DhP256HkdfSha256, X25519HkdfSha256},
+            kex::{Deserializable, Serializable},
+            HpkeError,
+        };
+
+        type OldA = ChaCha20Poly1305;
+        type OldKdf = HkdfSha256;
+        type OldKem = X25519HkdfSha256;
+        type NewA = AesGcm128;
+        type NewKdf = crate::kdf::HkdfSha384;
+        type NewKem = DhP256HkdfSha256;
+
+        let mut csprng = StdRng::from_entropy();
+        let (old_sk_recip, old_pk_recip) = OldKem::gen_keypair(&mut csprng);
+        let (new_sk_recip, new_pk_recip) = NewKem::gen_keypair(&mut csprng);
+        let (old_info, old_aad) = (b"old suite", b"old aad");
+        let (new_info, new_aad) = (b"new suite", b"new aad");
+        let msg = b"this has to be migrated";
+
+        let mut old_ciphertext = msg.to_vec();
+        let (old_encapped_key, old_tag) = single_shot_seal::<OldA, OldKdf, OldKem, _>(
+            &OpModeS::Base,
+            &old_pk_recip,
+            old_info,
+            &mut old_ciphertext,
+            old_aad,
+            &mut csprng,
+        )
+        .unwrap();
+        old_ciphertext.extend_from_slice(&old_tag.to_bytes());
+
+        let (new_encapped_key, new_ciphertext) =
+            reencrypt::<OldA, OldKdf, OldKem, NewA, NewKdf, NewKem, _>(
+                &old_sk_recip,
+                &old_encapped_key,
+                old_info,
+                &old_ciphertext,
+                old_aad,
+                &new_pk_recip,
+                new_info,
+                new_aad,
+                &mut csprng,
+            )
+            .unwrap();
+
+        let tag_len = AeadTag::<NewA>::size();
+        let (ciphertext, tag_bytes) = new_ciphertext.split_at(new_ciphertext.len() - tag_len);
+        let mut plaintext = ciphertext.to_vec();
+        single_shot_open::<NewA, NewKdf, NewKem>(
+            &OpModeR::Base,
+            &new_sk_recip,
+            &new_encapped_key,
+            new_info,
+            &mut plaintext,
+            new_aad,
+            &AeadTag::from_bytes(tag_bytes).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(plaintext, msg.to_vec());
+
+        // The wrong old AAD means nothing gets re-encrypted
+        assert_eq!(
+            reencrypt::<OldA, OldKdf, OldKem, NewA, NewKdf, NewKem, _>(
+                &old_sk_recip,
+                &old_encapped_key,
+                old_info,
+                &old_ciphertext,
+                b"wrong aad",
+                &new_pk_recip,
+                new_info,
+                new_aad,
+                &mut csprng,
+            )
+            .map(|(_, ct)| ct),
+            Err(HpkeError::OpenError)
+        );
+    }
+
     #[cfg(feature = "x25519-dalek")]
     test_single_shot_correctness!(
         test_single_shot_correctness_x25519,
@@ -181,4 +1086,36 @@ mod test {
         HkdfSha256,
         crate::kem::DhP256HkdfSha256
     );
+
+    #[cfg(all(feature = "alloc", feature = "x25519-dalek"))]
+    test_parse_received!(
+        test_parse_received_x25519,
+        ChaCha20Poly1305,
+        HkdfSha256,
+        crate::kem::X25519HkdfSha256
+    );
+
+    #[cfg(all(feature = "alloc", feature = "p256"))]
+    test_parse_received!(
+        test_parse_received_p256,
+        ChaCha20Poly1305,
+        HkdfSha256,
+        crate::kem::DhP256HkdfSha256
+    );
+
+    #[cfg(all(feature = "alloc", feature = "x25519-dalek"))]
+    test_validate_message!(
+        test_validate_message_x25519,
+        ChaCha20Poly1305,
+        HkdfSha256,
+        crate::kem::X25519HkdfSha256
+    );
+
+    #[cfg(all(feature = "alloc", feature = "p256"))]
+    test_validate_message!(
+        test_validate_message_p256,
+        ChaCha20Poly1305,
+        HkdfSha256,
+        crate::kem::DhP256HkdfSha256
+    );
 }
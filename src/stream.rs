@@ -0,0 +1,500 @@
+//! Chunked encryption of `Read`ers into `Write`rs. This is gated under the `std` feature.
+//!
+//! A sealed stream is a sequence of records. Each record is
+//! `is_last || I2OSP(len(ciphertext), 4) || ciphertext || tag`, where `is_last` is a single byte
+//! which is 1 for the final record and 0 otherwise. Every record is sealed with the next sequence
+//! number of the context, and its AAD is `is_last || I2OSP(len(ciphertext), 4) || aad`. So
+//! records cannot be reordered, dropped, or modified, and since the stream must end with a record
+//! marked final, truncation is detected too. A stream always has at least one record, even if the
+//! input is empty.
+
+use crate::{
+    aead::{Aead, AeadCtxR, AeadCtxS, AeadTag},
+    kdf::Kdf as KdfTrait,
+    kem::Kem as KemTrait,
+    kex::{Deserializable, Serializable},
+    HpkeError,
+};
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use std::{
+    io::{self, Read, Write},
+    vec::Vec,
+};
+
+/// The length of a record header, i.e., `is_last || I2OSP(len(ciphertext), 4)`
+pub(crate) const HEADER_LEN: usize = 5;
+
+/// Makes a record header
+pub(crate) fn header(is_last: bool, len: u32) -> [u8; HEADER_LEN] {
+    let mut buf = [0u8; HEADER_LEN];
+    buf[0] = is_last as u8;
+    buf[1..].copy_from_slice(&len.to_be_bytes());
+    buf
+}
+
+/// Computes the AAD of a record, i.e., `header || aad`
+pub(crate) fn record_aad(header: &[u8; HEADER_LEN], aad: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + aad.len());
+    buf.extend_from_slice(header);
+    buf.extend_from_slice(aad);
+    buf
+}
+
+/// Reads from `reader` until `buf` is full or the reader is exhausted. Returns the number of bytes
+/// read.
+fn read_up_to<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Checks that `chunk_size` is nonzero and fits in a record header
+fn check_chunk_size(chunk_size: usize) -> io::Result<()> {
+    if chunk_size == 0 || chunk_size > u32::MAX as usize {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "chunk size must be in the range [1, 2^32)",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Wraps an `HpkeError` in an `io::Error`
+fn hpke_to_io(kind: io::ErrorKind, e: HpkeError) -> io::Error {
+    io::Error::new(kind, e)
+}
+
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AeadCtxS<A, Kdf, Kem> {
+    /// Reads all of `reader` in chunks of `chunk_size` bytes, seals each chunk, and writes the
+    /// resulting records to `writer`. See the module documentation for the record format.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If `chunk_size` is 0 or at least 2^32, returns an error of
+    /// kind `InvalidInput`. If sealing fails, returns an error of kind `Other` wrapping the
+    /// `HpkeError` that `AeadCtxS::seal` returned. I/O errors are passed through.
+    pub fn seal_stream<R: Read, W: Write>(
+        &mut self,
+        reader: &mut R,
+        writer: &mut W,
+        aad: &[u8],
+        chunk_size: usize,
+    ) -> io::Result<()> {
+        check_chunk_size(chunk_size)?;
+
+        // We can't tell if a chunk is the last one until we try to read the next one. So always
+        // keep one chunk of lookahead.
+        let mut cur = vec![0u8; chunk_size];
+        let mut next = vec![0u8; chunk_size];
+        let mut cur_len = read_up_to(reader, &mut cur)?;
+        loop {
+            let next_len = if cur_len < chunk_size {
+                // A short read means the reader is exhausted
+                0
+            } else {
+                read_up_to(reader, &mut next)?
+            };
+            let is_last = next_len == 0;
+
+            // Seal the current chunk and write out the record. The cast is fine because we
+            // checked the chunk size above.
+            let header = header(is_last, cur_len as u32);
+            let tag = self
+                .seal(&mut cur[..cur_len], &record_aad(&header, aad))
+                .map_err(|e| hpke_to_io(io::ErrorKind::Other, e))?;
+            writer.write_all(&header)?;
+            writer.write_all(&cur[..cur_len])?;
+            writer.write_all(&tag.to_bytes())?;
+
+            if is_last {
+                return Ok(());
+            }
+            core::mem::swap(&mut cur, &mut next);
+            cur_len = next_len;
+        }
+    }
+}
+
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AeadCtxR<A, Kdf, Kem> {
+    /// Reads records made by `AeadCtxS::seal_stream` from `reader`, opens them, and writes the
+    /// plaintext to `writer`. `chunk_size` is the maximum chunk size to accept, and should be the
+    /// same as the sender's.
+    ///
+    /// Each chunk is authenticated before it's written, but the stream as a whole is not
+    /// authenticated until this function returns `Ok(())`. If it returns an error, the output so
+    /// far MUST be discarded.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If `chunk_size` is 0 or at least 2^32, returns an error of
+    /// kind `InvalidInput`. If the stream ends before the final record, i.e., it was truncated,
+    /// returns an error of kind `UnexpectedEof`. If a record is bigger than `chunk_size`, fails to
+    /// open, or is followed by more data after the final record, returns an error of kind
+    /// `InvalidData` wrapping an `HpkeError`. I/O errors are passed through.
+    pub fn open_stream<R: Read, W: Write>(
+        &mut self,
+        reader: &mut R,
+        writer: &mut W,
+        aad: &[u8],
+        chunk_size: usize,
+    ) -> io::Result<()> {
+        // Nobody can cancel this
+        let cancel = AtomicBool::new(false);
+        self.open_stream_cancellable(reader, writer, aad, chunk_size, &cancel)
+    }
+
+    /// Does `open_stream`, except `cancel` is checked before every record. If it is set, this
+    /// stops early. As with any other error, the output so far MUST be discarded.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If `cancel` was set, returns an error of kind `Other` wrapping
+    /// `HpkeError::Cancelled`. Otherwise, errors are the same as in `open_stream`.
+    pub fn open_stream_cancellable<R: Read, W: Write>(
+        &mut self,
+        reader: &mut R,
+        writer: &mut W,
+        aad: &[u8],
+        chunk_size: usize,
+        cancel: &AtomicBool,
+    ) -> io::Result<()> {
+        self.open_records(
+            reader,
+            |plaintext| writer.write_all(plaintext),
+            aad,
+            chunk_size,
+            cancel,
+        )
+    }
+
+    /// Does `open_stream`, except each chunk of plaintext is given to `on_plaintext`, in order,
+    /// instead of being written out. At most one chunk is held in memory at a time. As with
+    /// `open_stream`, everything `on_plaintext` was given MUST be discarded if this returns an
+    /// error.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If `on_plaintext` returns an error, stops and returns an error
+    /// of kind `Other` wrapping that `HpkeError`. Otherwise, errors are the same as in
+    /// `open_stream`.
+    pub fn open_streaming<R, F>(
+        &mut self,
+        mut ct_reader: R,
+        mut on_plaintext: F,
+        aad: &[u8],
+        chunk_size: usize,
+    ) -> io::Result<()>
+    where
+        R: Read,
+        F: FnMut(&[u8]) -> Result<(), HpkeError>,
+    {
+        // Nobody can cancel this
+        let cancel = AtomicBool::new(false);
+        self.open_records(
+            &mut ct_reader,
+            |plaintext| on_plaintext(plaintext).map_err(|e| hpke_to_io(io::ErrorKind::Other, e)),
+            aad,
+            chunk_size,
+            &cancel,
+        )
+    }
+
+    /// Opens the records in `reader`, and gives each chunk of plaintext to `on_plaintext`. This is
+    /// the body of `open_stream_cancellable` and `open_streaming`.
+    fn open_records<R, F>(
+        &mut self,
+        reader: &mut R,
+        mut on_plaintext: F,
+        aad: &[u8],
+        chunk_size: usize,
+        cancel: &AtomicBool,
+    ) -> io::Result<()>
+    where
+        R: Read,
+        F: FnMut(&[u8]) -> io::Result<()>,
+    {
+        check_chunk_size(chunk_size)?;
+
+        let tag_size = AeadTag::<A>::size();
+        let mut buf = vec![0u8; chunk_size];
+        let mut tag_buf = vec![0u8; tag_size];
+        loop {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(hpke_to_io(io::ErrorKind::Other, HpkeError::Cancelled));
+            }
+
+            // Read the header. If the stream ends here, it was truncated.
+            let mut header = [0u8; HEADER_LEN];
+            reader.read_exact(&mut header)?;
+            let is_last = match header[0] {
+                0 => false,
+                1 => true,
+                _ => {
+                    return Err(hpke_to_io(
+                        io::ErrorKind::InvalidData,
+                        HpkeError::ValidationError,
+                    ))
+                }
+            };
+            let mut len_bytes = [0u8; 4];
+            len_bytes.copy_from_slice(&header[1..]);
+            let len = u32::from_be_bytes(len_bytes) as usize;
+            if len > chunk_size {
+                return Err(hpke_to_io(
+                    io::ErrorKind::InvalidData,
+                    HpkeError::IncorrectInputLength {
+                        expected: chunk_size,
+                        got: len,
+                    },
+                ));
+            }
+
+            // Read the ciphertext and tag, and open the ciphertext in place
+            let ciphertext = &mut buf[..len];
+            reader.read_exact(ciphertext)?;
+            reader.read_exact(&mut tag_buf)?;
+            let tag = AeadTag::<A>::from_bytes(&tag_buf)
+                .map_err(|e| hpke_to_io(io::ErrorKind::InvalidData, e))?;
+            self.open(ciphertext, &record_aad(&header, aad), &tag)
+                .map_err(|e| hpke_to_io(io::ErrorKind::InvalidData, e))?;
+            on_plaintext(ciphertext)?;
+
+            if is_last {
+                break;
+            }
+        }
+
+        // There must be nothing after the final record
+        if read_up_to(reader, &mut [0u8; 1])? != 0 {
+            return Err(hpke_to_io(
+                io::ErrorKind::InvalidData,
+                HpkeError::ValidationError,
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        aead::ChaCha20Poly1305, kdf::HkdfSha256, test_util::gen_ctx_simple_pair, HpkeError,
+    };
+
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use rand::{rngs::StdRng, RngCore, SeedableRng};
+    use std::{
+        io::{Cursor, Write},
+        vec::Vec,
+    };
+
+    /// Tests that streams of various lengths round-trip, and that truncating a stream is detected
+    macro_rules! test_stream_roundtrip {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let chunk_size = 4096;
+                let aad = b"file header";
+
+                // Try an empty file, a file that's an exact multiple of the chunk size, and a
+                // file-sized one that isn't
+                for &len in &[0, 4 * chunk_size, 1024 * 1024 + 17] {
+                    let mut plaintext = vec![0u8; len];
+                    csprng.fill_bytes(&mut plaintext);
+
+                    let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                    let mut sealed = Vec::new();
+                    sender_ctx
+                        .seal_stream(&mut Cursor::new(&plaintext), &mut sealed, aad, chunk_size)
+                        .unwrap();
+
+                    let mut opened = Vec::new();
+                    receiver_ctx
+                        .open_stream(&mut Cursor::new(&sealed), &mut opened, aad, chunk_size)
+                        .unwrap();
+                    assert_eq!(opened, plaintext);
+                }
+
+                // Now chop the final record off a stream. This must be detected.
+                let plaintext = vec![0xaau8; 3 * chunk_size];
+                let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let mut sealed = Vec::new();
+                sender_ctx
+                    .seal_stream(&mut Cursor::new(&plaintext), &mut sealed, aad, chunk_size)
+                    .unwrap();
+                let record_len = sealed.len() / 3;
+                sealed.truncate(2 * record_len);
+
+                let err = receiver_ctx
+                    .open_stream(&mut Cursor::new(&sealed), &mut Vec::new(), aad, chunk_size)
+                    .unwrap_err();
+                assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+            }
+        };
+    }
+
+    /// Tests that open_streaming hands over a multi-chunk message in order and one chunk at a
+    /// time, that a truncated stream errors, and that a callback error stops it
+    macro_rules! test_open_streaming {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let chunk_size = 1000;
+                let aad = b"big message";
+                let mut plaintext = vec![0u8; 5 * chunk_size + 123];
+                csprng.fill_bytes(&mut plaintext);
+
+                // Seals the plaintext under a fresh context pair, and returns the records along
+                // with the receiver
+                let seal_fresh = || {
+                    let (mut sender_ctx, receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                    let mut sealed = Vec::new();
+                    sender_ctx
+                        .seal_stream(&mut Cursor::new(&plaintext), &mut sealed, aad, chunk_size)
+                        .unwrap();
+                    (sealed, receiver_ctx)
+                };
+
+                // Concatenating the chunks gives back the plaintext
+                let (sealed, mut receiver_ctx) = seal_fresh();
+                let mut chunks: Vec<Vec<u8>> = Vec::new();
+                receiver_ctx
+                    .open_streaming(
+                        Cursor::new(&sealed),
+                        |chunk| {
+                            chunks.push(chunk.to_vec());
+                            Ok(())
+                        },
+                        aad,
+                        chunk_size,
+                    )
+                    .unwrap();
+                assert_eq!(chunks.len(), 6);
+                assert!(chunks[..5].iter().all(|c| c.len() == chunk_size));
+                assert_eq!(chunks.concat(), plaintext);
+
+                // Cut off part of the final tag
+                let (sealed, mut receiver_ctx) = seal_fresh();
+                let truncated = &sealed[..sealed.len() - 1];
+                let err = receiver_ctx
+                    .open_streaming(Cursor::new(truncated), |_| Ok(()), aad, chunk_size)
+                    .unwrap_err();
+                assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+
+                // A callback error stops everything, and comes back out
+                let (sealed, mut receiver_ctx) = seal_fresh();
+                let mut num_calls = 0;
+                let err = receiver_ctx
+                    .open_streaming(
+                        Cursor::new(&sealed),
+                        |_| {
+                            num_calls += 1;
+                            Err(HpkeError::Cancelled)
+                        },
+                        aad,
+                        chunk_size,
+                    )
+                    .unwrap_err();
+                assert_eq!(num_calls, 1);
+                let inner = err.into_inner().unwrap().downcast::<HpkeError>().unwrap();
+                assert_eq!(*inner, HpkeError::Cancelled);
+            }
+        };
+    }
+
+    /// A writer that sets a flag the first time it's written to
+    struct FlaggingWriter<'a> {
+        out: Vec<u8>,
+        flag: &'a AtomicBool,
+    }
+
+    impl<'a> Write for FlaggingWriter<'a> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.flag.store(true, Ordering::Relaxed);
+            self.out.write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Tests that cancelling after the first chunk stops `open_stream_cancellable` before it
+    /// processes the rest
+    macro_rules! test_stream_cancel {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+
+                let chunk_size = 1024;
+                let plaintext = vec![0x55u8; 10 * chunk_size];
+
+                let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let mut sealed = Vec::new();
+                sender_ctx
+                    .seal_stream(&mut Cursor::new(&plaintext), &mut sealed, b"", chunk_size)
+                    .unwrap();
+
+                // The flag gets set as soon as the first chunk is written out
+                let cancel = AtomicBool::new(false);
+                let mut writer = FlaggingWriter {
+                    out: Vec::new(),
+                    flag: &cancel,
+                };
+                let err = receiver_ctx
+                    .open_stream_cancellable(
+                        &mut Cursor::new(&sealed),
+                        &mut writer,
+                        b"",
+                        chunk_size,
+                        &cancel,
+                    )
+                    .unwrap_err();
+
+                let inner = err.into_inner().unwrap().downcast::<HpkeError>().unwrap();
+                assert_eq!(*inner, HpkeError::Cancelled);
+                assert_eq!(writer.out.len(), chunk_size);
+            }
+        };
+    }
+
+    #[cfg(feature = "x25519-dalek")]
+    test_stream_cancel!(test_stream_cancel_x25519, crate::kem::X25519HkdfSha256);
+
+    #[cfg(feature = "p256")]
+    test_stream_cancel!(test_stream_cancel_p256, crate::kem::DhP256HkdfSha256);
+
+    #[cfg(feature = "x25519-dalek")]
+    test_stream_roundtrip!(test_stream_roundtrip_x25519, crate::kem::X25519HkdfSha256);
+
+    #[cfg(feature = "p256")]
+    test_stream_roundtrip!(test_stream_roundtrip_p256, crate::kem::DhP256HkdfSha256);
+
+    #[cfg(feature = "x25519-dalek")]
+    test_open_streaming!(test_open_streaming_x25519, crate::kem::X25519HkdfSha256);
+
+    #[cfg(feature = "p256")]
+    test_open_streaming!(test_open_streaming_p256, crate::kem::DhP256HkdfSha256);
+}
@@ -0,0 +1,143 @@
+//! A strict bidirectional encryption context. In protocols where both peers send and receive, a
+//! failed `open` or `seal` almost always means the peers have fallen out of sync. A
+//! `StrictAeadCtx` refuses to continue after any such failure. Once poisoned, every subsequent
+//! operation returns `HpkeError::Poisoned`, and the only way forward is to tear down the session
+//! and set up a new one.
+
+use crate::{
+    aead::{Aead, AeadCtxR, AeadCtxS, AeadTag},
+    kdf::Kdf as KdfTrait,
+    kem::Kem as KemTrait,
+    HpkeError,
+};
+
+/// A sender context and a receiver context which are poisoned together. If any `seal` or `open`
+/// fails, the whole thing is poisoned.
+pub struct StrictAeadCtx<A: Aead, Kdf: KdfTrait, Kem: KemTrait> {
+    /// The context we use to seal outgoing messages
+    sender: AeadCtxS<A, Kdf, Kem>,
+    /// The context we use to open incoming messages
+    receiver: AeadCtxR<A, Kdf, Kem>,
+    /// Records whether a `seal` or `open` has ever failed
+    poisoned: bool,
+}
+
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> StrictAeadCtx<A, Kdf, Kem> {
+    /// Makes a strict context out of the context for the outgoing direction and the context for
+    /// the incoming direction
+    pub fn new(
+        sender: AeadCtxS<A, Kdf, Kem>,
+        receiver: AeadCtxR<A, Kdf, Kem>,
+    ) -> StrictAeadCtx<A, Kdf, Kem> {
+        StrictAeadCtx {
+            sender,
+            receiver,
+            poisoned: false,
+        }
+    }
+
+    /// Returns whether a `seal` or `open` on this context has ever failed
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    /// Does `AeadCtxS::seal` with the outgoing context
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(tag)` on success. If this context is poisoned, returns
+    /// `Err(HpkeError::Poisoned)`, and `plaintext` will be unmodified. Otherwise, errors are the
+    /// same as in `AeadCtxS::seal`, and any error poisons this context.
+    pub fn seal(&mut self, plaintext: &mut [u8], aad: &[u8]) -> Result<AeadTag<A>, HpkeError> {
+        if self.poisoned {
+            return Err(HpkeError::Poisoned);
+        }
+
+        let res = self.sender.seal(plaintext, aad);
+        self.poisoned = res.is_err();
+        res
+    }
+
+    /// Does `AeadCtxR::open` with the incoming context
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If this context is poisoned, returns
+    /// `Err(HpkeError::Poisoned)`, and `ciphertext` will be unmodified. Otherwise, errors are the
+    /// same as in `AeadCtxR::open`, and any error poisons this context.
+    pub fn open(
+        &mut self,
+        ciphertext: &mut [u8],
+        aad: &[u8],
+        tag: &AeadTag<A>,
+    ) -> Result<(), HpkeError> {
+        if self.poisoned {
+            return Err(HpkeError::Poisoned);
+        }
+
+        let res = self.receiver.open(ciphertext, aad, tag);
+        self.poisoned = res.is_err();
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::StrictAeadCtx;
+    use crate::{
+        aead::{AeadTag, ChaCha20Poly1305},
+        kdf::HkdfSha256,
+        kex::Deserializable,
+        test_util::gen_ctx_simple_pair,
+        HpkeError,
+    };
+
+    /// Tests that a failed open poisons the context, so that every operation afterwards, in
+    /// either direction, returns Poisoned
+    macro_rules! test_poisoned_after_open_error {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+
+                // Alice -> Bob and Bob -> Alice
+                let (mut alice_sender, bob_receiver) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let (bob_sender, _) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let mut bob = StrictAeadCtx::new(bob_sender, bob_receiver);
+
+                // A normal round trip works fine
+                let mut msg = *b"how's it going";
+                let tag = alice_sender.seal(&mut msg, b"").unwrap();
+                bob.open(&mut msg, b"", &tag).unwrap();
+                assert!(!bob.is_poisoned());
+
+                // Force an OpenError with a bogus tag
+                let mut msg = *b"you up?";
+                alice_sender.seal(&mut msg, b"").unwrap();
+                let bad_tag = AeadTag::<A>::from_bytes(&[0u8; 16]).unwrap();
+                assert_eq!(bob.open(&mut msg, b"", &bad_tag), Err(HpkeError::OpenError));
+                assert!(bob.is_poisoned());
+
+                // Now Bob can't seal anything, and can't open anything either
+                let mut reply = *b"nope";
+                assert_eq!(bob.seal(&mut reply, b"").err(), Some(HpkeError::Poisoned));
+                assert_eq!(&reply, b"nope");
+                assert_eq!(bob.open(&mut msg, b"", &tag), Err(HpkeError::Poisoned));
+            }
+        };
+    }
+
+    #[cfg(feature = "x25519-dalek")]
+    test_poisoned_after_open_error!(
+        test_poisoned_after_open_error_x25519,
+        crate::kem::X25519HkdfSha256
+    );
+
+    #[cfg(feature = "p256")]
+    test_poisoned_after_open_error!(
+        test_poisoned_after_open_error_p256,
+        crate::kem::DhP256HkdfSha256
+    );
+}
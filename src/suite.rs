@@ -0,0 +1,569 @@
+use crate::{
+    aead::{Aead, AesGcm128, AesGcm256, ChaCha20Poly1305, ExportOnlyAead},
+    kdf::{HkdfSha256, HkdfSha384, HkdfSha512, Kdf as KdfTrait},
+    kem::Kem as KemTrait,
+    kex::{KeyExchange, Serializable},
+    HpkeError,
+};
+
+#[cfg(feature = "p256")]
+use crate::kem::DhP256HkdfSha256;
+#[cfg(feature = "x25519-dalek")]
+use crate::kem::X25519HkdfSha256;
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use aead::{AeadCore as BaseAeadCore, NewAead as BaseNewAead};
+use generic_array::typenum::Unsigned;
+
+/// Returned alongside an algorithm ID made by `new_lenient` when the ID isn't registered in
+/// draft11 §7. The value is the unregistered ID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnregisteredId(pub u16);
+
+/// Defines a newtype for the algorithm IDs of one kind of primitive, whose registered values are
+/// `$registered`
+macro_rules! algorithm_id {
+    ($(#[$meta:meta])* $name:ident, $section:literal, $registered:expr) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        pub struct $name(u16);
+
+        impl $name {
+            #[doc = concat!("Makes an ID from its value, if it's registered in draft11 ", $section)]
+            ///
+            /// Return Value
+            /// ============
+            /// Returns `Ok(id)` on success. If `id` isn't registered, returns
+            /// `Err(HpkeError::ValidationError)`.
+            pub fn new(id: u16) -> Result<$name, HpkeError> {
+                match $name::new_lenient(id) {
+                    (id, None) => Ok(id),
+                    (_, Some(_)) => Err(HpkeError::ValidationError),
+                }
+            }
+
+            /// Makes an ID from any value. This is for IDs that are private-use, or newer than
+            /// this crate. If `id` isn't registered, this also returns an `UnregisteredId`.
+            pub fn new_lenient(id: u16) -> ($name, Option<UnregisteredId>) {
+                let warning = if $registered.contains(&id) {
+                    None
+                } else {
+                    Some(UnregisteredId(id))
+                };
+                ($name(id), warning)
+            }
+
+            /// Makes an ID without checking it. This is for the IDs of implemented algorithms,
+            /// which are registered by construction.
+            pub(crate) const fn from_raw(id: u16) -> $name {
+                $name(id)
+            }
+
+            /// Returns the value of this ID
+            pub const fn get(self) -> u16 {
+                self.0
+            }
+        }
+
+        impl From<$name> for u16 {
+            fn from(id: $name) -> u16 {
+                id.0
+            }
+        }
+    };
+}
+
+algorithm_id!(
+    /// A KEM ID, as defined in draft11 §7.1
+    KemId,
+    "§7.1",
+    [0x0010u16, 0x0011, 0x0012, 0x0020, 0x0021]
+);
+algorithm_id!(
+    /// A KDF ID, as defined in draft11 §7.2
+    KdfId,
+    "§7.2",
+    [0x0001u16, 0x0002, 0x0003]
+);
+algorithm_id!(
+    /// An AEAD ID, as defined in draft11 §7.3
+    AeadId,
+    "§7.3",
+    [0x0001u16, 0x0002, 0x0003, 0xFFFF]
+);
+
+impl KemId {
+    /// Returns the ID of `Kem`
+    pub fn of<Kem: KemTrait>() -> KemId {
+        KemId(Kem::KEM_ID)
+    }
+}
+
+impl KdfId {
+    /// Returns the ID of `Kdf`
+    pub fn of<Kdf: KdfTrait>() -> KdfId {
+        KdfId(Kdf::KDF_ID)
+    }
+}
+
+impl AeadId {
+    /// Returns the ID of `A`
+    pub fn of<A: Aead>() -> AeadId {
+        AeadId(A::AEAD_ID)
+    }
+}
+
+/// Identifies an HPKE ciphersuite by the algorithm IDs of its KEM, KDF, and AEAD. Each ID has its
+/// own type, so they can't be mixed up:
+///
+/// ```compile_fail
+/// use hpke::{AeadId, CiphersuiteId, KdfId, KemId};
+///
+/// let kem_id = KemId::new(0x0020).unwrap();
+/// let kdf_id = KdfId::new(0x0001).unwrap();
+/// let aead_id = AeadId::new(0x0003).unwrap();
+/// // The KEM and KDF IDs are swapped
+/// let suite = CiphersuiteId::new(kdf_id, kem_id, aead_id);
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CiphersuiteId {
+    /// The KEM ID, as defined in draft11 §7.1
+    pub kem_id: KemId,
+    /// The KDF ID, as defined in draft11 §7.2
+    pub kdf_id: KdfId,
+    /// The AEAD ID, as defined in draft11 §7.3
+    pub aead_id: AeadId,
+}
+
+impl CiphersuiteId {
+    /// Makes a ciphersuite ID from the IDs of its parts
+    pub const fn new(kem_id: KemId, kdf_id: KdfId, aead_id: AeadId) -> CiphersuiteId {
+        CiphersuiteId {
+            kem_id,
+            kdf_id,
+            aead_id,
+        }
+    }
+
+    /// Returns the ID of the ciphersuite `(A, Kdf, Kem)`
+    pub fn of<A: Aead, Kdf: KdfTrait, Kem: KemTrait>() -> CiphersuiteId {
+        CiphersuiteId::new(KemId::of::<Kem>(), KdfId::of::<Kdf>(), AeadId::of::<A>())
+    }
+}
+
+// The IDs of every KEM this crate was compiled with. This depends on which features are set.
+const KEM_IDS: &[u16] = &[
+    #[cfg(feature = "x25519-dalek")]
+    X25519HkdfSha256::KEM_ID,
+    #[cfg(feature = "p256")]
+    DhP256HkdfSha256::KEM_ID,
+];
+
+// KDFs and AEADs are not feature-gated, so these are always all present
+const KDF_IDS: &[u16] = &[HkdfSha256::KDF_ID, HkdfSha384::KDF_ID, HkdfSha512::KDF_ID];
+const AEAD_IDS: &[u16] = &[
+    AesGcm128::AEAD_ID,
+    AesGcm256::AEAD_ID,
+    ChaCha20Poly1305::AEAD_ID,
+    ExportOnlyAead::AEAD_ID,
+];
+
+const NUM_SUITES: usize = KEM_IDS.len() * KDF_IDS.len() * AEAD_IDS.len();
+
+/// Computes the cartesian product of `KEM_IDS`, `KDF_IDS`, and `AEAD_IDS` at compile time
+const fn all_suites() -> [CiphersuiteId; NUM_SUITES] {
+    let mut suites =
+        [CiphersuiteId::new(KemId::from_raw(0), KdfId::from_raw(0), AeadId::from_raw(0));
+            NUM_SUITES];
+
+    // No for loops in const fns, so we do it by hand
+    let mut i = 0;
+    let mut kem_idx = 0;
+    while kem_idx < KEM_IDS.len() {
+        let mut kdf_idx = 0;
+        while kdf_idx < KDF_IDS.len() {
+            let mut aead_idx = 0;
+            while aead_idx < AEAD_IDS.len() {
+                suites[i] = CiphersuiteId::new(
+                    KemId::from_raw(KEM_IDS[kem_idx]),
+                    KdfId::from_raw(KDF_IDS[kdf_idx]),
+                    AeadId::from_raw(AEAD_IDS[aead_idx]),
+                );
+                i += 1;
+                aead_idx += 1;
+            }
+            kdf_idx += 1;
+        }
+        kem_idx += 1;
+    }
+
+    suites
+}
+
+static SUPPORTED_SUITES: [CiphersuiteId; NUM_SUITES] = all_suites();
+
+/// Returns every ciphersuite this build of the crate supports. The KEMs that appear in this list
+/// depend on which feature flags are enabled, e.g., if the `p256` feature is disabled, no suite
+/// will use DHKEM(P-256, HKDF-SHA256).
+pub fn supported_suites() -> &'static [CiphersuiteId] {
+    &SUPPORTED_SUITES
+}
+
+/// The kind of primitive an `AlgorithmEntry` describes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AlgorithmKind {
+    /// A KEM, with an ID from draft11 §7.1
+    Kem,
+    /// A KDF, with an ID from draft11 §7.2
+    Kdf,
+    /// An AEAD, with an ID from draft11 §7.3
+    Aead,
+}
+
+/// An algorithm that this crate implements, as listed by `registry`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AlgorithmEntry {
+    /// What kind of primitive this is
+    pub kind: AlgorithmKind,
+    /// The algorithm ID
+    pub id: u16,
+    /// The name of the algorithm, as written in draft11 §7
+    pub name: &'static str,
+    /// The Cargo feature that gates this algorithm, if any
+    pub feature: Option<&'static str>,
+    /// Whether this build of the crate was compiled with the algorithm
+    pub enabled: bool,
+}
+
+/// Returns every algorithm that this crate implements, including the ones that are compiled out
+/// of this build. The `enabled` field of an entry says whether it's compiled in. Unlike
+/// `supported_suites`, this lists each KEM, KDF, and AEAD once, rather than every combination.
+#[cfg(feature = "alloc")]
+pub fn registry() -> Vec<AlgorithmEntry> {
+    let entry = |kind, id, name, feature, enabled| AlgorithmEntry {
+        kind,
+        id,
+        name,
+        feature,
+        enabled,
+    };
+
+    // The KEM IDs aren't taken from the KEM types, since those don't exist when the KEM's feature
+    // is disabled
+    vec![
+        entry(
+            AlgorithmKind::Kem,
+            0x0010,
+            "DHKEM(P-256, HKDF-SHA256)",
+            Some("p256"),
+            cfg!(feature = "p256"),
+        ),
+        entry(
+            AlgorithmKind::Kem,
+            0x0020,
+            "DHKEM(X25519, HKDF-SHA256)",
+            Some("x25519"),
+            cfg!(feature = "x25519-dalek"),
+        ),
+        entry(
+            AlgorithmKind::Kdf,
+            HkdfSha256::KDF_ID,
+            "HKDF-SHA256",
+            None,
+            true,
+        ),
+        entry(
+            AlgorithmKind::Kdf,
+            HkdfSha384::KDF_ID,
+            "HKDF-SHA384",
+            None,
+            true,
+        ),
+        entry(
+            AlgorithmKind::Kdf,
+            HkdfSha512::KDF_ID,
+            "HKDF-SHA512",
+            None,
+            true,
+        ),
+        entry(
+            AlgorithmKind::Aead,
+            AesGcm128::AEAD_ID,
+            "AES-128-GCM",
+            None,
+            true,
+        ),
+        entry(
+            AlgorithmKind::Aead,
+            AesGcm256::AEAD_ID,
+            "AES-256-GCM",
+            None,
+            true,
+        ),
+        entry(
+            AlgorithmKind::Aead,
+            ChaCha20Poly1305::AEAD_ID,
+            "ChaCha20Poly1305",
+            None,
+            true,
+        ),
+        entry(
+            AlgorithmKind::Aead,
+            ExportOnlyAead::AEAD_ID,
+            "Export-only",
+            None,
+            true,
+        ),
+    ]
+}
+
+/// The ID and parameter lengths of a ciphersuite, as named in draft11 §7. All lengths are in
+/// bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SuitePreflight {
+    /// The IDs of the suite's KEM, KDF, and AEAD
+    pub id: CiphersuiteId,
+    /// The length of a KEM shared secret
+    pub n_secret: usize,
+    /// The length of an encapsulated key
+    pub n_enc: usize,
+    /// The length of an encoded public key
+    pub n_pk: usize,
+    /// The length of an encoded private key
+    pub n_sk: usize,
+    /// The output size of the KDF's hash function
+    pub n_h: usize,
+    /// The length of an AEAD key. This is `None` for the export-only AEAD.
+    pub n_k: Option<usize>,
+    /// The length of an AEAD nonce. This is `None` for the export-only AEAD.
+    pub n_n: Option<usize>,
+    /// The length of an AEAD tag. This is `None` for the export-only AEAD.
+    pub n_t: Option<usize>,
+}
+
+/// Returns the ID and parameter lengths of the given ciphersuite. This is for validating
+/// configuration and sizing buffers ahead of time. It does no cryptography and uses no
+/// randomness. Every value is known at compile time.
+pub fn setup_preflight<A: Aead, Kdf: KdfTrait, Kem: KemTrait>() -> SuitePreflight {
+    type AeadImpl<A> = <A as Aead>::AeadImpl;
+    type Kex<Kem> = <Kem as KemTrait>::Kex;
+
+    // draft11 §7.3 doesn't define any lengths for export-only
+    let aead_len = |len: usize| {
+        if A::AEAD_ID == ExportOnlyAead::AEAD_ID {
+            None
+        } else {
+            Some(len)
+        }
+    };
+
+    SuitePreflight {
+        id: CiphersuiteId::of::<A, Kdf, Kem>(),
+        // The KEM's shared secret is the output of its own KDF
+        n_secret: <Kem::Kdf as KdfTrait>::N_H,
+        // In a DHKEM, the encapsulated key is just a public key
+        n_enc: <<Kex<Kem> as KeyExchange>::PublicKey as Serializable>::size(),
+        n_pk: <<Kex<Kem> as KeyExchange>::PublicKey as Serializable>::size(),
+        n_sk: <<Kex<Kem> as KeyExchange>::PrivateKey as Serializable>::size(),
+        n_h: Kdf::N_H,
+        n_k: aead_len(<AeadImpl<A> as BaseNewAead>::KeySize::USIZE),
+        n_n: aead_len(<AeadImpl<A> as BaseAeadCore>::NonceSize::USIZE),
+        n_t: aead_len(<AeadImpl<A> as BaseAeadCore>::TagSize::USIZE),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{supported_suites, AeadId, CiphersuiteId, KdfId, KemId, UnregisteredId};
+    use crate::{aead::ChaCha20Poly1305, kdf::HkdfSha256, HpkeError};
+
+    #[cfg(any(feature = "x25519-dalek", feature = "p256"))]
+    use crate::kem::Kem as KemTrait;
+
+    /// Tests the preflight lengths against the tables in draft11 §7. There's no RNG to check for
+    /// draws, since `setup_preflight` doesn't take one and has no way to get one.
+    #[cfg(feature = "x25519-dalek")]
+    #[test]
+    fn test_setup_preflight_x25519() {
+        use super::{setup_preflight, SuitePreflight};
+        use crate::{aead::ExportOnlyAead, kdf::HkdfSha384, kem::X25519HkdfSha256};
+
+        assert_eq!(
+            setup_preflight::<ChaCha20Poly1305, HkdfSha256, X25519HkdfSha256>(),
+            SuitePreflight {
+                id: CiphersuiteId::new(
+                    KemId::new(0x0020).unwrap(),
+                    KdfId::new(0x0001).unwrap(),
+                    AeadId::new(0x0003).unwrap(),
+                ),
+                n_secret: 32,
+                n_enc: 32,
+                n_pk: 32,
+                n_sk: 32,
+                n_h: 32,
+                n_k: Some(32),
+                n_n: Some(12),
+                n_t: Some(16),
+            }
+        );
+
+        // Export-only has no AEAD lengths, and the KDF doesn't change Nsecret
+        let preflight = setup_preflight::<ExportOnlyAead, HkdfSha384, X25519HkdfSha256>();
+        assert_eq!(preflight.id.aead_id.get(), 0xFFFF);
+        assert_eq!((preflight.n_secret, preflight.n_h), (32, 48));
+        assert_eq!(
+            (preflight.n_k, preflight.n_n, preflight.n_t),
+            (None, None, None)
+        );
+    }
+
+    /// Tests the preflight lengths against the tables in draft11 §7
+    #[cfg(feature = "p256")]
+    #[test]
+    fn test_setup_preflight_p256() {
+        use super::{setup_preflight, SuitePreflight};
+        use crate::{aead::AesGcm128, kdf::HkdfSha512, kem::DhP256HkdfSha256};
+
+        assert_eq!(
+            setup_preflight::<AesGcm128, HkdfSha512, DhP256HkdfSha256>(),
+            SuitePreflight {
+                id: CiphersuiteId::new(
+                    KemId::new(0x0010).unwrap(),
+                    KdfId::new(0x0003).unwrap(),
+                    AeadId::new(0x0001).unwrap(),
+                ),
+                n_secret: 32,
+                n_enc: 65,
+                n_pk: 65,
+                n_sk: 32,
+                n_h: 64,
+                n_k: Some(16),
+                n_n: Some(12),
+                n_t: Some(16),
+            }
+        );
+    }
+
+    /// Tests that there are no duplicate suites, and that every KEM appears with all 12 KDF-AEAD
+    /// combinations
+    #[test]
+    fn test_supported_suites_complete() {
+        let suites = supported_suites();
+        for (i, s1) in suites.iter().enumerate() {
+            assert!(suites[i + 1..].iter().all(|s2| s1 != s2), "duplicate suite");
+
+            let num_with_kem = suites.iter().filter(|s2| s2.kem_id == s1.kem_id).count();
+            assert_eq!(num_with_kem, 3 * 4);
+        }
+    }
+
+    /// Tests that X25519 suites appear iff the x25519 feature is enabled
+    #[test]
+    fn test_supported_suites_x25519() {
+        #[cfg(feature = "x25519-dalek")]
+        let kem_id = crate::kem::X25519HkdfSha256::KEM_ID;
+        // draft11 §7.1: DHKEM(X25519, HKDF-SHA256)
+        #[cfg(not(feature = "x25519-dalek"))]
+        let kem_id = 0x0020;
+
+        let suite = CiphersuiteId::new(
+            KemId::new(kem_id).unwrap(),
+            KdfId::of::<HkdfSha256>(),
+            AeadId::of::<ChaCha20Poly1305>(),
+        );
+        assert_eq!(
+            supported_suites().contains(&suite),
+            cfg!(feature = "x25519-dalek")
+        );
+    }
+
+    /// Tests that P-256 suites appear iff the p256 feature is enabled
+    #[test]
+    fn test_supported_suites_p256() {
+        #[cfg(feature = "p256")]
+        let kem_id = crate::kem::DhP256HkdfSha256::KEM_ID;
+        // draft11 §7.1: DHKEM(P-256, HKDF-SHA256)
+        #[cfg(not(feature = "p256"))]
+        let kem_id = 0x0010;
+
+        let suite = CiphersuiteId::new(
+            KemId::new(kem_id).unwrap(),
+            KdfId::of::<HkdfSha256>(),
+            AeadId::of::<ChaCha20Poly1305>(),
+        );
+        assert_eq!(supported_suites().contains(&suite), cfg!(feature = "p256"));
+    }
+
+    /// Tests that registered IDs are accepted, that unregistered ones are rejected by `new` and
+    /// flagged by `new_lenient`, and that the IDs of implemented algorithms are registered
+    #[test]
+    fn test_algorithm_ids() {
+        assert_eq!(KemId::new(0x0021).unwrap().get(), 0x0021);
+        assert_eq!(KdfId::new(0x0002).map(u16::from), Ok(0x0002));
+        assert_eq!(AeadId::new(0xFFFF).unwrap().get(), 0xFFFF);
+
+        assert_eq!(KemId::new(0x0000), Err(HpkeError::ValidationError));
+        assert_eq!(KdfId::new(0x0004), Err(HpkeError::ValidationError));
+        // Registered AEAD IDs aren't necessarily registered KDF IDs, and vice versa
+        assert_eq!(KdfId::new(0xFFFF), Err(HpkeError::ValidationError));
+
+        let (id, warning) = KemId::new_lenient(0xFF01);
+        assert_eq!(id.get(), 0xFF01);
+        assert_eq!(warning, Some(UnregisteredId(0xFF01)));
+        assert_eq!(AeadId::new_lenient(0x0001).1, None);
+
+        for suite in supported_suites() {
+            assert_eq!(KemId::new(suite.kem_id.get()), Ok(suite.kem_id));
+            assert_eq!(KdfId::new(suite.kdf_id.get()), Ok(suite.kdf_id));
+            assert_eq!(AeadId::new(suite.aead_id.get()), Ok(suite.aead_id));
+        }
+    }
+
+    /// Tests that the registry lists each implemented algorithm once, that its KEM IDs match the
+    /// KEM types, and that a KEM is enabled iff its feature is
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn test_registry() {
+        use super::{registry, AlgorithmEntry, AlgorithmKind};
+
+        let entries = registry();
+        let find = |kind, id| {
+            entries
+                .iter()
+                .find(|e: &&AlgorithmEntry| e.kind == kind && e.id == id)
+                .copied()
+                .unwrap()
+        };
+        assert_eq!(entries.len(), 2 + 3 + 4);
+
+        let x25519 = find(AlgorithmKind::Kem, 0x0020);
+        assert_eq!(x25519.name, "DHKEM(X25519, HKDF-SHA256)");
+        assert_eq!(x25519.feature, Some("x25519"));
+        assert_eq!(x25519.enabled, cfg!(feature = "x25519-dalek"));
+        let p256 = find(AlgorithmKind::Kem, 0x0010);
+        assert_eq!(p256.enabled, cfg!(feature = "p256"));
+        #[cfg(feature = "x25519-dalek")]
+        assert_eq!(crate::kem::X25519HkdfSha256::KEM_ID, x25519.id);
+        #[cfg(feature = "p256")]
+        assert_eq!(crate::kem::DhP256HkdfSha256::KEM_ID, p256.id);
+
+        assert_eq!(find(AlgorithmKind::Kdf, 0x0002).name, "HKDF-SHA384");
+        assert_eq!(find(AlgorithmKind::Aead, 0xFFFF).name, "Export-only");
+        // KDF and AEAD IDs overlap, so the kind matters
+        assert_eq!(find(AlgorithmKind::Aead, 0x0001).name, "AES-128-GCM");
+        assert!(entries
+            .iter()
+            .filter(|e| e.kind != AlgorithmKind::Kem)
+            .all(|e| e.enabled && e.feature.is_none()));
+
+        // Every enabled algorithm is in some supported suite
+        for e in entries.iter().filter(|e| e.enabled) {
+            assert!(supported_suites().iter().any(|s| match e.kind {
+                AlgorithmKind::Kem => s.kem_id.get() == e.id,
+                AlgorithmKind::Kdf => s.kdf_id.get() == e.id,
+                AlgorithmKind::Aead => s.aead_id.get() == e.id,
+            }));
+        }
+    }
+}
@@ -18,6 +18,44 @@ pub(crate) fn gen_rand_buf() -> [u8; 32] {
     buf
 }
 
+/// An "RNG" that outputs the given bytes, in order, and then panics. Since `gen_keypair` is just
+/// `derive_keypair` on random IKM, giving `setup_sender` a `ReplayRng` of some IKM makes it use
+/// the ephemeral keypair derived from that IKM.
+#[cfg(any(
+    all(feature = "test-internals", feature = "x25519-dalek"),
+    all(feature = "std", feature = "x25519", feature = "p256")
+))]
+pub(crate) struct ReplayRng<'a>(pub(crate) &'a [u8]);
+
+#[cfg(any(
+    all(feature = "test-internals", feature = "x25519-dalek"),
+    all(feature = "std", feature = "x25519", feature = "p256")
+))]
+impl<'a> RngCore for ReplayRng<'a> {
+    fn next_u32(&mut self) -> u32 {
+        rand_core::impls::next_u32_via_fill(self)
+    }
+    fn next_u64(&mut self) -> u64 {
+        rand_core::impls::next_u64_via_fill(self)
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        assert!(dest.len() <= self.0.len(), "ReplayRng ran out of bytes");
+        let (out, rest) = self.0.split_at(dest.len());
+        dest.copy_from_slice(out);
+        self.0 = rest;
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(any(
+    all(feature = "test-internals", feature = "x25519-dalek"),
+    all(feature = "std", feature = "x25519", feature = "p256")
+))]
+impl<'a> CryptoRng for ReplayRng<'a> {}
+
 /// Generates a keypair without the need of a KEM
 pub(crate) fn kex_gen_keypair<Kex: KeyExchange, R: CryptoRng + RngCore>(
     csprng: &mut R,
@@ -28,7 +66,7 @@ pub(crate) fn kex_gen_keypair<Kex: KeyExchange, R: CryptoRng + RngCore>(
     // Fill it with randomness
     csprng.fill_bytes(&mut ikm);
     // Run derive_keypair with a nonsense ciphersuite. We use SHA-512 to satisfy any security level
-    Kex::derive_keypair::<crate::kdf::HkdfSha512>(b"31337", &ikm)
+    Kex::derive_keypair::<crate::kdf::HkdfSha512>(b"31337", &ikm).unwrap()
 }
 
 /// Creates a pair of `AeadCtx`s without doing a key exchange
@@ -72,6 +110,7 @@ pub(crate) enum OpModeKind {
 }
 
 /// Makes an agreeing pair of `OpMode`s of the specified variant
+#[allow(clippy::extra_unused_type_parameters)]
 pub(crate) fn new_op_mode_pair<'a, Kex: KeyExchange, Kdf: KdfTrait>(
     kind: OpModeKind,
     psk: &'a [u8],
@@ -134,12 +173,12 @@ pub(crate) fn aead_ctx_eq<A: Aead, Kdf: KdfTrait, Kem: KemTrait>(
         // Encrypt the plaintext
         let tag = sender
             .seal(&mut plaintext[..], &aad)
-            .expect(&format!("seal() #{} failed", i));
+            .unwrap_or_else(|_| panic!("seal() #{} failed", i));
         // Rename for clarity
         let mut ciphertext = plaintext;
 
         // Now to decrypt on the other side
-        if let Err(_) = receiver.open(&mut ciphertext[..], &aad, &tag) {
+        if receiver.open(&mut ciphertext[..], &aad, &tag).is_err() {
             // An error occurred in decryption. These encryption contexts are not identical.
             return false;
         }
@@ -0,0 +1,274 @@
+//! Generating test vectors. `generate_test_vector` runs a deterministic setup and records every
+//! intermediate value, in the same JSON schema as the draft11 test vectors. This is the inverse of
+//! the known-answer tests, for authoring new vectors or checking a fork against this crate.
+//!
+//! **WARNING:** A test vector contains every secret of the session it describes. Never generate
+//! one from keys that protect real data.
+
+use crate::{
+    aead::Aead,
+    kdf::Kdf as KdfTrait,
+    kem::Kem as KemTrait,
+    kex::{KeyExchange, Serializable},
+    op_mode::{OpMode, OpModeS, PskBundle},
+    setup::setup_sender_debug_with_eph,
+    HpkeError,
+};
+
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+
+/// The inputs to `generate_test_vector`. The mode is determined by which of `ikm_sender` and
+/// `psk` are given.
+#[derive(Clone, Copy)]
+pub struct TestVectorParams<'a> {
+    /// The info string
+    pub info: &'a [u8],
+    /// The IKM that the recipient's keypair is derived from
+    pub ikm_recip: &'a [u8],
+    /// The IKM that the ephemeral keypair is derived from. This must be `Nsk` bytes long.
+    pub ikm_eph: &'a [u8],
+    /// The IKM that the sender's identity keypair is derived from, in the `Auth` modes
+    pub ikm_sender: Option<&'a [u8]>,
+    /// The PSK and PSK ID, in the PSK modes
+    pub psk: Option<PskBundle<'a>>,
+    /// The `(plaintext, aad)` of each message to seal, in order
+    pub encryptions: &'a [(&'a [u8], &'a [u8])],
+    /// The `(exporter_context, L)` of each secret to export
+    pub exports: &'a [(&'a [u8], usize)],
+}
+
+/// One encryption in a `TestVector`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EncryptionVector {
+    /// The plaintext
+    pub pt: Vec<u8>,
+    /// The AAD
+    pub aad: Vec<u8>,
+    /// The nonce this message was sealed with
+    pub nonce: Vec<u8>,
+    /// The ciphertext, followed by the tag
+    pub ct: Vec<u8>,
+}
+
+/// One export in a `TestVector`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExportVector {
+    /// The exporter context
+    pub exporter_context: Vec<u8>,
+    /// The length of the exported secret
+    pub len: usize,
+    /// The exported secret
+    pub exported_value: Vec<u8>,
+}
+
+/// A test vector, with every value named in draft11 Appendix A. The fields are named after the
+/// vector's JSON keys. The sender fields are `None` unless the mode is `Auth` or `AuthPsk`, and
+/// the PSK fields are `None` unless the mode is `Psk` or `AuthPsk`.
+#[allow(missing_docs)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TestVector {
+    pub mode: u8,
+    pub kem_id: u16,
+    pub kdf_id: u16,
+    pub aead_id: u16,
+    pub info: Vec<u8>,
+    pub ikm_r: Vec<u8>,
+    pub ikm_s: Option<Vec<u8>>,
+    pub ikm_e: Vec<u8>,
+    pub sk_rm: Vec<u8>,
+    pub sk_sm: Option<Vec<u8>>,
+    pub sk_em: Vec<u8>,
+    pub psk: Option<Vec<u8>>,
+    pub psk_id: Option<Vec<u8>>,
+    pub pk_rm: Vec<u8>,
+    pub pk_sm: Option<Vec<u8>>,
+    pub pk_em: Vec<u8>,
+    pub enc: Vec<u8>,
+    pub shared_secret: Vec<u8>,
+    pub key_schedule_context: Vec<u8>,
+    pub secret: Vec<u8>,
+    pub key: Vec<u8>,
+    pub base_nonce: Vec<u8>,
+    pub exporter_secret: Vec<u8>,
+    pub encryptions: Vec<EncryptionVector>,
+    pub exports: Vec<ExportVector>,
+}
+
+/// Writes `"key":"hex(bytes)"` to `out`
+fn write_hex_field(out: &mut String, key: &str, bytes: &[u8]) {
+    // Writing to a String can't fail
+    write!(out, "\"{}\":\"", key).unwrap();
+    for b in bytes {
+        write!(out, "{:02x}", b).unwrap();
+    }
+    out.push('"');
+}
+
+impl TestVector {
+    /// Serializes this vector as a JSON object, in the schema of the draft11 test vectors. Byte
+    /// strings are hex-encoded, and optional fields that are `None` are left out.
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{");
+        write!(
+            out,
+            "\"mode\":{},\"kem_id\":{},\"kdf_id\":{},\"aead_id\":{},",
+            self.mode, self.kem_id, self.kdf_id, self.aead_id
+        )
+        .unwrap();
+
+        let fields: [(&str, Option<&Vec<u8>>); 19] = [
+            ("info", Some(&self.info)),
+            ("ikmR", Some(&self.ikm_r)),
+            ("ikmS", self.ikm_s.as_ref()),
+            ("ikmE", Some(&self.ikm_e)),
+            ("skRm", Some(&self.sk_rm)),
+            ("skSm", self.sk_sm.as_ref()),
+            ("skEm", Some(&self.sk_em)),
+            ("psk", self.psk.as_ref()),
+            ("psk_id", self.psk_id.as_ref()),
+            ("pkRm", Some(&self.pk_rm)),
+            ("pkSm", self.pk_sm.as_ref()),
+            ("pkEm", Some(&self.pk_em)),
+            ("enc", Some(&self.enc)),
+            ("shared_secret", Some(&self.shared_secret)),
+            ("key_schedule_context", Some(&self.key_schedule_context)),
+            ("secret", Some(&self.secret)),
+            ("key", Some(&self.key)),
+            ("base_nonce", Some(&self.base_nonce)),
+            ("exporter_secret", Some(&self.exporter_secret)),
+        ];
+        for (key, val) in fields.iter() {
+            if let Some(val) = val {
+                write_hex_field(&mut out, key, val);
+                out.push(',');
+            }
+        }
+
+        out.push_str("\"encryptions\":[");
+        for (i, e) in self.encryptions.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out.push('{');
+            write_hex_field(&mut out, "aad", &e.aad);
+            out.push(',');
+            write_hex_field(&mut out, "ct", &e.ct);
+            out.push(',');
+            write_hex_field(&mut out, "nonce", &e.nonce);
+            out.push(',');
+            write_hex_field(&mut out, "pt", &e.pt);
+            out.push('}');
+        }
+        out.push_str("],\"exports\":[");
+        for (i, e) in self.exports.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out.push('{');
+            write_hex_field(&mut out, "exporter_context", &e.exporter_context);
+            write!(out, ",\"L\":{},", e.len).unwrap();
+            write_hex_field(&mut out, "exported_value", &e.exported_value);
+            out.push('}');
+        }
+        out.push_str("]}");
+
+        out
+    }
+}
+
+/// Runs a deterministic sender setup in the ciphersuite `(A, Kdf, Kem)` with the given inputs, and
+/// records every intermediate value, every encryption, and every export
+///
+/// Return Value
+/// ============
+/// Returns `Ok(test_vector)` on success. If `params.ikm_eph` isn't `Nsk` bytes long, returns
+/// `Err(HpkeError::IncorrectInputLength { expected: Nsk, got: params.ikm_eph.len() })`. Otherwise,
+/// errors are the same as in `Kem::derive_keypair`, `setup_sender`, `AeadCtxS::seal`, and
+/// `AeadCtxS::export`.
+pub fn generate_test_vector<A, Kdf, Kem>(params: &TestVectorParams) -> Result<TestVector, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    // The KATs replay ikmE as setup_sender's RNG output, which is exactly Nsk bytes
+    let nsk = <<Kem::Kex as KeyExchange>::PrivateKey as Serializable>::size();
+    if params.ikm_eph.len() != nsk {
+        return Err(HpkeError::IncorrectInputLength {
+            expected: nsk,
+            got: params.ikm_eph.len(),
+        });
+    }
+
+    let (sk_recip, pk_recip) = Kem::derive_keypair(params.ikm_recip)?;
+    let (sk_eph, pk_eph) = Kem::derive_keypair(params.ikm_eph)?;
+    let sender_keypair = params.ikm_sender.map(Kem::derive_keypair).transpose()?;
+    let (sk_sender, pk_sender) = match sender_keypair.as_ref() {
+        Some((sk, pk)) => (Some(sk.to_bytes().to_vec()), Some(pk.to_bytes().to_vec())),
+        None => (None, None),
+    };
+
+    let mode = match (sender_keypair, params.psk) {
+        (None, None) => OpModeS::Base,
+        (None, Some(psk)) => OpModeS::Psk(psk),
+        (Some(keypair), None) => OpModeS::Auth(keypair),
+        (Some(keypair), Some(psk)) => OpModeS::AuthPsk(keypair, psk),
+    };
+    let (encapped_key, mut ctx, debug) =
+        setup_sender_debug_with_eph::<A, Kdf, Kem>(&mode, &pk_recip, params.info, sk_eph.clone())?;
+
+    let mut encryptions = Vec::with_capacity(params.encryptions.len());
+    for (pt, aad) in params.encryptions.iter() {
+        let mut ct = pt.to_vec();
+        let tag = ctx.seal(&mut ct, aad)?;
+        ct.extend_from_slice(&tag.to_bytes());
+        // seal just logged the nonce it used
+        let nonce = ctx.nonce_log().last().unwrap().to_vec();
+        encryptions.push(EncryptionVector {
+            pt: pt.to_vec(),
+            aad: aad.to_vec(),
+            nonce,
+            ct,
+        });
+    }
+
+    let mut exports = Vec::with_capacity(params.exports.len());
+    for (exporter_context, len) in params.exports.iter() {
+        let mut exported_value = vec![0u8; *len];
+        ctx.export(exporter_context, &mut exported_value)?;
+        exports.push(ExportVector {
+            exporter_context: exporter_context.to_vec(),
+            len: *len,
+            exported_value,
+        });
+    }
+
+    Ok(TestVector {
+        mode: mode.mode_id(),
+        kem_id: Kem::KEM_ID,
+        kdf_id: Kdf::KDF_ID,
+        aead_id: A::AEAD_ID,
+        info: params.info.to_vec(),
+        ikm_r: params.ikm_recip.to_vec(),
+        ikm_s: params.ikm_sender.map(|ikm| ikm.to_vec()),
+        ikm_e: params.ikm_eph.to_vec(),
+        sk_rm: sk_recip.to_bytes().to_vec(),
+        sk_sm: sk_sender,
+        sk_em: sk_eph.to_bytes().to_vec(),
+        psk: params.psk.map(|bundle| bundle.psk.to_vec()),
+        psk_id: params.psk.map(|bundle| bundle.psk_id.to_vec()),
+        pk_rm: pk_recip.to_bytes().to_vec(),
+        pk_sm: pk_sender,
+        pk_em: pk_eph.to_bytes().to_vec(),
+        enc: encapped_key.to_bytes().to_vec(),
+        shared_secret: debug.shared_secret.as_ref().to_vec(),
+        key_schedule_context: debug.key_schedule_context,
+        secret: debug.secret.to_vec(),
+        key: debug.key.to_vec(),
+        base_nonce: debug.base_nonce.to_vec(),
+        exporter_secret: debug.exporter_secret.to_vec(),
+        encryptions,
+        exports,
+    })
+}
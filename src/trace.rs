@@ -0,0 +1,352 @@
+//! Observability hooks. If the `tracing` feature is set, these emit `tracing` events. Otherwise,
+//! they do nothing. None of these functions take secret material as input, so there is no way
+//! for it to end up in a log.
+//!
+//! If the `metrics` feature is set, these also increment counters with the `metrics` crate. Every
+//! counter is labeled with the `kem_id`, `kdf_id`, and `aead_id` of the ciphersuite, and the setup
+//! counter is also labeled with the `role`.
+
+use crate::HpkeError;
+
+#[cfg(feature = "metrics")]
+use alloc::{string::ToString, vec};
+#[cfg(feature = "tracing")]
+use tracing::{debug, warn};
+
+/// The `(KEM ID, KDF ID, AEAD ID)` of a ciphersuite
+pub(crate) type SuiteIds = (u16, u16, u16);
+
+/// Increments the counter `name`, labeled with the suite IDs and, if given, the role
+#[cfg(feature = "metrics")]
+fn count(name: &'static str, role: Option<&'static str>, suite_ids: SuiteIds) {
+    let mut labels = vec![
+        metrics::Label::new("kem_id", suite_ids.0.to_string()),
+        metrics::Label::new("kdf_id", suite_ids.1.to_string()),
+        metrics::Label::new("aead_id", suite_ids.2.to_string()),
+    ];
+    if let Some(role) = role {
+        labels.push(metrics::Label::new("role", role));
+    }
+    metrics::counter!(name, labels).increment(1);
+}
+
+/// Records the start of a setup. `role` is either `"sender"` or `"receiver"`.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn setup_start(role: &'static str, suite_ids: SuiteIds) {
+    #[cfg(feature = "tracing")]
+    debug!(
+        role,
+        kem_id = suite_ids.0,
+        kdf_id = suite_ids.1,
+        aead_id = suite_ids.2,
+        "HPKE setup started"
+    );
+}
+
+/// Counts a successful seal
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn seal(suite_ids: SuiteIds) {
+    #[cfg(feature = "metrics")]
+    count("hpke.seal.count", None, suite_ids);
+}
+
+/// Counts an open that failed because the ciphertext didn't authenticate
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn open_failure(suite_ids: SuiteIds) {
+    #[cfg(feature = "metrics")]
+    count("hpke.open.failure.count", None, suite_ids);
+}
+
+/// Counts a successful setup. `setup_end` already does this, so this is only for the setups that
+/// don't do an encap or decap of their own. `role` is either `"sender"` or `"receiver"`.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn setup(role: &'static str, suite_ids: SuiteIds) {
+    #[cfg(feature = "metrics")]
+    count("hpke.setup.count", Some(role), suite_ids);
+}
+
+/// Records the end of a setup, along with the error, if there was one. A failed setup is either
+/// an encap failure (for senders) or a decap failure (for receivers). Only successful setups are
+/// counted.
+pub(crate) fn setup_end<T>(role: &'static str, suite_ids: SuiteIds, res: &Result<T, HpkeError>) {
+    if res.is_ok() {
+        setup(role, suite_ids);
+    }
+    #[cfg(feature = "tracing")]
+    match res {
+        Ok(_) => debug!(
+            role,
+            kem_id = suite_ids.0,
+            kdf_id = suite_ids.1,
+            aead_id = suite_ids.2,
+            "HPKE setup finished"
+        ),
+        Err(e) => warn!(
+            role,
+            kem_id = suite_ids.0,
+            kdf_id = suite_ids.1,
+            aead_id = suite_ids.2,
+            error = ?e,
+            "HPKE setup failed"
+        ),
+    }
+}
+
+/// Records that a context's sequence counter overflowed. `seq` is the sequence number of the last
+/// message the context will ever process.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn seq_overflow(role: &'static str, suite_ids: SuiteIds, seq: u64) {
+    #[cfg(feature = "tracing")]
+    warn!(
+        role,
+        kem_id = suite_ids.0,
+        kdf_id = suite_ids.1,
+        aead_id = suite_ids.2,
+        seq,
+        error = ?HpkeError::MessageLimitReached,
+        "HPKE sequence counter overflowed"
+    );
+}
+
+#[cfg(all(test, feature = "tracing", feature = "x25519-dalek"))]
+mod test {
+    extern crate std;
+
+    use crate::{
+        aead::ChaCha20Poly1305,
+        kdf::HkdfSha256,
+        kem::{EncappedKey, Kem as KemTrait, X25519HkdfSha256},
+        kex::{Deserializable, Serializable},
+        setup::setup_receiver,
+        HpkeError, OpModeR,
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::{
+        fmt::Debug,
+        format,
+        string::{String, ToString},
+        sync::{Arc, Mutex},
+        vec::Vec,
+    };
+    use tracing::{
+        field::{Field, Visit},
+        span, Event, Metadata, Subscriber,
+    };
+
+    /// The fields of a single event, as `(name, value)` pairs
+    type EventFields = Vec<(String, String)>;
+
+    /// A subscriber that records the fields of every event it sees
+    #[derive(Clone, Default)]
+    struct CaptureSubscriber(Arc<Mutex<Vec<EventFields>>>);
+
+    struct FieldRecorder<'a>(&'a mut EventFields);
+
+    impl<'a> Visit for FieldRecorder<'a> {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0
+                .push((field.name().to_string(), format!("{:?}", value)));
+        }
+    }
+
+    impl Subscriber for CaptureSubscriber {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+            span::Id::from_u64(1)
+        }
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = Vec::new();
+            event.record(&mut FieldRecorder(&mut fields));
+            self.0.lock().unwrap().push(fields);
+        }
+        fn enter(&self, _: &span::Id) {}
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    /// Tests that a decap failure emits an event with the suite IDs and error kind, and that no
+    /// event contains any key bytes
+    #[test]
+    fn test_decap_failure_event() {
+        type A = ChaCha20Poly1305;
+        type Kdf = HkdfSha256;
+        type Kem = X25519HkdfSha256;
+
+        let mut csprng = StdRng::from_entropy();
+        let (sk_recip, _) = Kem::gen_keypair(&mut csprng);
+
+        // The all-zero X25519 point has low order, so the DH result is 0 and decap fails
+        let bad_encapped_key =
+            EncappedKey::<<Kem as KemTrait>::Kex>::from_bytes(&[0u8; 32]).unwrap();
+
+        let subscriber = CaptureSubscriber::default();
+        let res = tracing::subscriber::with_default(subscriber.clone(), || {
+            setup_receiver::<A, Kdf, Kem>(&OpModeR::Base, &sk_recip, &bad_encapped_key, b"info")
+        });
+        assert_eq!(res.err(), Some(HpkeError::DecapError));
+
+        let events = subscriber.0.lock().unwrap();
+        let failure = events
+            .iter()
+            .find(|fields| fields.iter().any(|(name, _)| name == "error"))
+            .expect("no failure event was emitted");
+
+        // draft11 §7: X25519HkdfSha256 is 0x0020, HkdfSha256 is 0x0001, ChaCha20Poly1305 is
+        // 0x0003
+        let mut expected: EventFields = [
+            ("message", "HPKE setup failed"),
+            ("role", "\"receiver\""),
+            ("kem_id", "32"),
+            ("kdf_id", "1"),
+            ("aead_id", "3"),
+            ("error", "DecapError"),
+        ]
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+        let mut got = failure.clone();
+        expected.sort();
+        got.sort();
+        assert_eq!(got, expected);
+
+        // Make sure the secret key doesn't show up anywhere, in any common format
+        let sk_bytes = sk_recip.to_bytes();
+        let sk_hex = hex::encode(sk_bytes);
+        let sk_debug = format!("{:?}", sk_bytes.as_slice());
+        for (_, value) in events.iter().flatten() {
+            assert!(!value.contains(&sk_hex));
+            assert!(!value.contains(&sk_debug));
+        }
+    }
+}
+
+#[cfg(all(test, feature = "metrics", feature = "x25519-dalek"))]
+mod metrics_test {
+    extern crate std;
+
+    use crate::{
+        aead::{AeadTag, ChaCha20Poly1305},
+        kdf::HkdfSha256,
+        kem::{EncappedKey, Kem as KemTrait, X25519HkdfSha256},
+        kex::Deserializable,
+        setup::{complete_setup, encap_only, setup_receiver, setup_sender},
+        HpkeError, OpModeR, OpModeS,
+    };
+
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::{
+        string::{String, ToString},
+        vec::Vec,
+    };
+
+    /// Tests that every successful setup, every seal, and every failed open increments its counter,
+    /// with the suite IDs as labels, and that a failed setup and a successful open don't
+    #[test]
+    fn test_metrics_counters() {
+        type A = ChaCha20Poly1305;
+        type Kdf = HkdfSha256;
+        type Kem = X25519HkdfSha256;
+
+        let mut csprng = StdRng::from_entropy();
+        let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        metrics::with_local_recorder(&recorder, || {
+            let (encapped_key, mut sender_ctx) =
+                setup_sender::<A, Kdf, Kem, _>(&OpModeS::Base, &pk_recip, b"info", &mut csprng)
+                    .unwrap();
+            let mut receiver_ctx =
+                setup_receiver::<A, Kdf, Kem>(&OpModeR::Base, &sk_recip, &encapped_key, b"info")
+                    .unwrap();
+
+            let mut msg = *b"ping";
+            let tag = sender_ctx.seal(&mut msg, b"").unwrap();
+            let bad_tag = AeadTag::<A>::from_bytes(&[0u8; 16]).unwrap();
+            assert_eq!(
+                receiver_ctx.open(&mut msg.clone(), b"", &bad_tag),
+                Err(HpkeError::OpenError)
+            );
+            receiver_ctx.open(&mut msg, b"", &tag).unwrap();
+
+            // The all-zero X25519 point makes decap fail
+            let bad_encapped_key =
+                EncappedKey::<<Kem as KemTrait>::Kex>::from_bytes(&[0u8; 32]).unwrap();
+            assert!(setup_receiver::<A, Kdf, Kem>(
+                &OpModeR::Base,
+                &sk_recip,
+                &bad_encapped_key,
+                b"info"
+            )
+            .is_err());
+
+            // The setups that don't encap or decap themselves count too
+            let (shared_secret, _) = encap_only::<Kem, _>(&pk_recip, &mut csprng).unwrap();
+            complete_setup::<A, Kdf, Kem>(shared_secret, &OpModeS::Base, b"info").unwrap();
+            #[cfg(feature = "raw-shared-secret")]
+            {
+                let shared_secret =
+                    crate::kem::decap::<Kem>(&sk_recip, None, &encapped_key).unwrap();
+                crate::setup::setup_receiver_from_shared_secret::<A, Kdf, Kem>(
+                    shared_secret.as_ref(),
+                    &OpModeR::Base,
+                    b"info",
+                )
+                .unwrap();
+            }
+        });
+
+        // Every counter, as (name, sorted labels, value), sorted
+        let mut counters: Vec<(String, Vec<(String, String)>, u64)> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| {
+                let key = key.key();
+                let value = match value {
+                    DebugValue::Counter(value) => value,
+                    value => panic!("{} isn't a counter: {:?}", key.name(), value),
+                };
+                let mut labels: Vec<(String, String)> = key
+                    .labels()
+                    .map(|label| (label.key().to_string(), label.value().to_string()))
+                    .collect();
+                labels.sort();
+                (key.name().to_string(), labels, value)
+            })
+            .collect();
+        counters.sort();
+
+        // draft11 §7: X25519HkdfSha256 is 0x0020, HkdfSha256 is 0x0001, ChaCha20Poly1305 is
+        // 0x0003
+        let counter = |name: &str, role: Option<&str>, value: u64| {
+            let mut labels: Vec<(String, String)> =
+                [("kem_id", "32"), ("kdf_id", "1"), ("aead_id", "3")]
+                    .iter()
+                    .chain(role.map(|role| ("role", role)).iter())
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect();
+            labels.sort();
+            (name.to_string(), labels, value)
+        };
+        let receiver_setups = if cfg!(feature = "raw-shared-secret") {
+            2
+        } else {
+            1
+        };
+        assert_eq!(
+            counters,
+            [
+                counter("hpke.open.failure.count", None, 1),
+                counter("hpke.seal.count", None, 1),
+                counter("hpke.setup.count", Some("receiver"), receiver_setups),
+                counter("hpke.setup.count", Some("sender"), 2),
+            ]
+        );
+    }
+}
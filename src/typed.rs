@@ -0,0 +1,470 @@
+//! Contexts that carry their op-mode in their type. Every `setup_sender` and `setup_receiver`
+//! returns the same `AeadCtxS` or `AeadCtxR`, whatever the mode, so nothing stops an
+//! unauthenticated context from reaching code that expects an authenticated one. The setup
+//! functions here return a `TypedCtxS` or `TypedCtxR` tagged with a mode marker, so a function
+//! can demand, e.g., a `TypedCtxR<A, Kdf, Kem, AuthMode>`, or any context whose mode is
+//! `AuthenticatedMode`.
+//!
+//! This is purely a compile-time distinction. The typed setup functions do exactly what
+//! `setup_sender` and `setup_receiver` do in the corresponding mode, and the wrappers deref to
+//! the underlying contexts.
+//!
+//! A base mode context can't be passed where an authenticated one is required:
+//!
+#![cfg_attr(feature = "x25519", doc = "```compile_fail")]
+#![cfg_attr(not(feature = "x25519"), doc = "```ignore")]
+//! use hpke::{
+//!     aead::ChaCha20Poly1305,
+//!     kdf::HkdfSha256,
+//!     kem::X25519HkdfSha256,
+//!     typed::{setup_sender_base, AuthMode, TypedCtxS},
+//!     Kem,
+//! };
+//! use rand::{rngs::StdRng, SeedableRng};
+//!
+//! type Ctx<M> = TypedCtxS<ChaCha20Poly1305, HkdfSha256, X25519HkdfSha256, M>;
+//!
+//! fn send_authenticated(ctx: &mut Ctx<AuthMode>) {
+//!     ctx.seal(&mut [], b"").unwrap();
+//! }
+//!
+//! let mut csprng = StdRng::from_entropy();
+//! let (_, pk_recip) = X25519HkdfSha256::gen_keypair(&mut csprng);
+//! let (_, mut ctx) = setup_sender_base::<ChaCha20Poly1305, HkdfSha256, X25519HkdfSha256, _>(
+//!     &pk_recip,
+//!     b"info",
+//!     &mut csprng,
+//! )
+//! .unwrap();
+//! send_authenticated(&mut ctx);
+//! ```
+
+use crate::{
+    aead::{Aead, AeadCtxR, AeadCtxS},
+    builder::SetupMode,
+    kdf::Kdf as KdfTrait,
+    kem::{EncappedKey, Kem as KemTrait},
+    kex::KeyExchange,
+    op_mode::{OpModeR, OpModeS, PskBundle},
+    setup::{setup_receiver, setup_sender},
+    HpkeError,
+};
+
+use core::{
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+use rand_core::{CryptoRng, RngCore};
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// A type-level op-mode. This is implemented by `BaseMode`, `PskMode`, `AuthMode`, and
+/// `AuthPskMode`, and can't be implemented outside this crate.
+pub trait ModeMarker: private::Sealed {
+    /// The mode this marker stands for
+    const MODE: SetupMode;
+}
+
+/// A mode that authenticates the sender's identity key, i.e., `AuthMode` or `AuthPskMode`
+pub trait AuthenticatedMode: ModeMarker {}
+
+/// Marks a context set up in `Base` mode
+#[derive(Clone, Copy, Debug)]
+pub enum BaseMode {}
+
+/// Marks a context set up in `Psk` mode
+#[derive(Clone, Copy, Debug)]
+pub enum PskMode {}
+
+/// Marks a context set up in `Auth` mode
+#[derive(Clone, Copy, Debug)]
+pub enum AuthMode {}
+
+/// Marks a context set up in `AuthPsk` mode
+#[derive(Clone, Copy, Debug)]
+pub enum AuthPskMode {}
+
+macro_rules! impl_mode_marker {
+    ($marker:ty, $mode:expr) => {
+        impl private::Sealed for $marker {}
+        impl ModeMarker for $marker {
+            const MODE: SetupMode = $mode;
+        }
+    };
+}
+
+impl_mode_marker!(BaseMode, SetupMode::Base);
+impl_mode_marker!(PskMode, SetupMode::Psk);
+impl_mode_marker!(AuthMode, SetupMode::Auth);
+impl_mode_marker!(AuthPskMode, SetupMode::AuthPsk);
+
+impl AuthenticatedMode for AuthMode {}
+impl AuthenticatedMode for AuthPskMode {}
+
+/// An `AeadCtxS` that was set up in the mode `M`. This derefs to the `AeadCtxS`.
+pub struct TypedCtxS<A: Aead, Kdf: KdfTrait, Kem: KemTrait, M: ModeMarker> {
+    ctx: AeadCtxS<A, Kdf, Kem>,
+    _mode: PhantomData<M>,
+}
+
+/// An `AeadCtxR` that was set up in the mode `M`. This derefs to the `AeadCtxR`.
+pub struct TypedCtxR<A: Aead, Kdf: KdfTrait, Kem: KemTrait, M: ModeMarker> {
+    ctx: AeadCtxR<A, Kdf, Kem>,
+    _mode: PhantomData<M>,
+}
+
+macro_rules! impl_typed_ctx {
+    ($typed_ctx:ident, $ctx:ident) => {
+        impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait, M: ModeMarker> $typed_ctx<A, Kdf, Kem, M> {
+            fn new(ctx: $ctx<A, Kdf, Kem>) -> Self {
+                $typed_ctx {
+                    ctx,
+                    _mode: PhantomData,
+                }
+            }
+
+            /// Returns the mode this context was set up in
+            pub fn mode(&self) -> SetupMode {
+                M::MODE
+            }
+
+            /// Drops the mode marker and returns the underlying context
+            pub fn into_inner(self) -> $ctx<A, Kdf, Kem> {
+                self.ctx
+            }
+        }
+
+        impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait, M: ModeMarker> Deref
+            for $typed_ctx<A, Kdf, Kem, M>
+        {
+            type Target = $ctx<A, Kdf, Kem>;
+
+            fn deref(&self) -> &$ctx<A, Kdf, Kem> {
+                &self.ctx
+            }
+        }
+
+        impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait, M: ModeMarker> DerefMut
+            for $typed_ctx<A, Kdf, Kem, M>
+        {
+            fn deref_mut(&mut self) -> &mut $ctx<A, Kdf, Kem> {
+                &mut self.ctx
+            }
+        }
+    };
+}
+
+impl_typed_ctx!(TypedCtxS, AeadCtxS);
+impl_typed_ctx!(TypedCtxR, AeadCtxR);
+
+/// The result of a typed sender setup
+pub type TypedSetupS<A, Kdf, Kem, M> = Result<
+    (
+        EncappedKey<<Kem as KemTrait>::Kex>,
+        TypedCtxS<A, Kdf, Kem, M>,
+    ),
+    HpkeError,
+>;
+
+/// Does `setup_sender` with `OpModeS::Base`
+///
+/// Return Value
+/// ============
+/// Same as `setup_sender`
+pub fn setup_sender_base<A, Kdf, Kem, R>(
+    pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
+    info: &[u8],
+    csprng: &mut R,
+) -> TypedSetupS<A, Kdf, Kem, BaseMode>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    let (encapped_key, ctx) = setup_sender(&OpModeS::Base, pk_recip, info, csprng)?;
+    Ok((encapped_key, TypedCtxS::new(ctx)))
+}
+
+/// Does `setup_sender` with `OpModeS::Psk`
+///
+/// Return Value
+/// ============
+/// Same as `setup_sender`
+pub fn setup_sender_psk<A, Kdf, Kem, R>(
+    psk: PskBundle,
+    pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
+    info: &[u8],
+    csprng: &mut R,
+) -> TypedSetupS<A, Kdf, Kem, PskMode>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    let (encapped_key, ctx) = setup_sender(&OpModeS::Psk(psk), pk_recip, info, csprng)?;
+    Ok((encapped_key, TypedCtxS::new(ctx)))
+}
+
+/// Does `setup_sender` with `OpModeS::AuthBorrowed`, authenticating as the given identity keypair
+///
+/// Return Value
+/// ============
+/// Same as `setup_sender`
+pub fn setup_sender_auth<A, Kdf, Kem, R>(
+    sender_id_keypair: (
+        &<Kem::Kex as KeyExchange>::PrivateKey,
+        &<Kem::Kex as KeyExchange>::PublicKey,
+    ),
+    pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
+    info: &[u8],
+    csprng: &mut R,
+) -> TypedSetupS<A, Kdf, Kem, AuthMode>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    let mode = OpModeS::AuthBorrowed(sender_id_keypair);
+    let (encapped_key, ctx) = setup_sender(&mode, pk_recip, info, csprng)?;
+    Ok((encapped_key, TypedCtxS::new(ctx)))
+}
+
+/// Does `setup_sender` with `OpModeS::AuthPskBorrowed`, authenticating as the given identity
+/// keypair
+///
+/// Return Value
+/// ============
+/// Same as `setup_sender`
+pub fn setup_sender_auth_psk<A, Kdf, Kem, R>(
+    sender_id_keypair: (
+        &<Kem::Kex as KeyExchange>::PrivateKey,
+        &<Kem::Kex as KeyExchange>::PublicKey,
+    ),
+    psk: PskBundle,
+    pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
+    info: &[u8],
+    csprng: &mut R,
+) -> TypedSetupS<A, Kdf, Kem, AuthPskMode>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    let mode = OpModeS::AuthPskBorrowed(sender_id_keypair, psk);
+    let (encapped_key, ctx) = setup_sender(&mode, pk_recip, info, csprng)?;
+    Ok((encapped_key, TypedCtxS::new(ctx)))
+}
+
+/// Does `setup_receiver` with `OpModeR::Base`
+///
+/// Return Value
+/// ============
+/// Same as `setup_receiver`
+pub fn setup_receiver_base<A, Kdf, Kem>(
+    sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
+    encapped_key: &EncappedKey<Kem::Kex>,
+    info: &[u8],
+) -> Result<TypedCtxR<A, Kdf, Kem, BaseMode>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    setup_receiver(&OpModeR::Base, sk_recip, encapped_key, info).map(TypedCtxR::new)
+}
+
+/// Does `setup_receiver` with `OpModeR::Psk`
+///
+/// Return Value
+/// ============
+/// Same as `setup_receiver`
+pub fn setup_receiver_psk<A, Kdf, Kem>(
+    psk: PskBundle,
+    sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
+    encapped_key: &EncappedKey<Kem::Kex>,
+    info: &[u8],
+) -> Result<TypedCtxR<A, Kdf, Kem, PskMode>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    setup_receiver(&OpModeR::Psk(psk), sk_recip, encapped_key, info).map(TypedCtxR::new)
+}
+
+/// Does `setup_receiver` with `OpModeR::Auth`, expecting the sender to authenticate as
+/// `pk_sender_id`
+///
+/// Return Value
+/// ============
+/// Same as `setup_receiver`
+pub fn setup_receiver_auth<A, Kdf, Kem>(
+    pk_sender_id: &<Kem::Kex as KeyExchange>::PublicKey,
+    sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
+    encapped_key: &EncappedKey<Kem::Kex>,
+    info: &[u8],
+) -> Result<TypedCtxR<A, Kdf, Kem, AuthMode>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    let mode = OpModeR::Auth(pk_sender_id.clone());
+    setup_receiver(&mode, sk_recip, encapped_key, info).map(TypedCtxR::new)
+}
+
+/// Does `setup_receiver` with `OpModeR::AuthPsk`, expecting the sender to authenticate as
+/// `pk_sender_id`
+///
+/// Return Value
+/// ============
+/// Same as `setup_receiver`
+pub fn setup_receiver_auth_psk<A, Kdf, Kem>(
+    pk_sender_id: &<Kem::Kex as KeyExchange>::PublicKey,
+    psk: PskBundle,
+    sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
+    encapped_key: &EncappedKey<Kem::Kex>,
+    info: &[u8],
+) -> Result<TypedCtxR<A, Kdf, Kem, AuthPskMode>, HpkeError>
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    let mode = OpModeR::AuthPsk(pk_sender_id.clone(), psk);
+    setup_receiver(&mode, sk_recip, encapped_key, info).map(TypedCtxR::new)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        setup_receiver_auth, setup_receiver_auth_psk, setup_receiver_base, setup_sender_auth,
+        setup_sender_auth_psk, setup_sender_base, AuthenticatedMode, TypedCtxR, TypedCtxS,
+    };
+    use crate::{
+        aead::ChaCha20Poly1305,
+        builder::SetupMode,
+        kdf::HkdfSha256,
+        kem::Kem as KemTrait,
+        op_mode::{OpModeR, PskBundle},
+        setup::setup_receiver,
+        test_util::gen_rand_buf,
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// Seals a message, but only with an authenticated context
+    fn seal_authenticated<Kem: KemTrait, M: AuthenticatedMode>(
+        ctx: &mut TypedCtxS<ChaCha20Poly1305, HkdfSha256, Kem, M>,
+        msg: &mut [u8],
+    ) -> crate::aead::AeadTag<ChaCha20Poly1305> {
+        ctx.seal(msg, b"").unwrap()
+    }
+
+    /// Opens a message, but only with an authenticated context
+    fn open_authenticated<Kem: KemTrait, M: AuthenticatedMode>(
+        ctx: &mut TypedCtxR<ChaCha20Poly1305, HkdfSha256, Kem, M>,
+        msg: &mut [u8],
+        tag: &crate::aead::AeadTag<ChaCha20Poly1305>,
+    ) {
+        ctx.open(msg, b"", tag).unwrap()
+    }
+
+    /// Tests that typed contexts report their mode, that authenticated ones can be passed to
+    /// functions requiring `AuthenticatedMode`, and that the typed setups are the same as the
+    /// usual ones on the wire
+    macro_rules! test_typed_ctx {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let info = b"typed contexts";
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+                let (sk_sender_id, pk_sender_id) = Kem::gen_keypair(&mut csprng);
+                let (psk, psk_id) = (gen_rand_buf(), gen_rand_buf());
+                let psk_bundle = PskBundle {
+                    psk: &psk,
+                    psk_id: &psk_id,
+                };
+
+                // Auth mode
+                let (encapped_key, mut sender_ctx) = setup_sender_auth::<A, Kdf, Kem, _>(
+                    (&sk_sender_id, &pk_sender_id),
+                    &pk_recip,
+                    info,
+                    &mut csprng,
+                )
+                .unwrap();
+                assert_eq!(sender_ctx.mode(), SetupMode::Auth);
+                let mut receiver_ctx = setup_receiver_auth::<A, Kdf, Kem>(
+                    &pk_sender_id,
+                    &sk_recip,
+                    &encapped_key,
+                    info,
+                )
+                .unwrap();
+                assert_eq!(receiver_ctx.mode(), SetupMode::Auth);
+                let mut msg = *b"authenticated";
+                let tag = seal_authenticated(&mut sender_ctx, &mut msg);
+                open_authenticated(&mut receiver_ctx, &mut msg, &tag);
+                assert_eq!(&msg, b"authenticated");
+
+                // AuthPsk mode
+                let (encapped_key, mut sender_ctx) = setup_sender_auth_psk::<A, Kdf, Kem, _>(
+                    (&sk_sender_id, &pk_sender_id),
+                    psk_bundle,
+                    &pk_recip,
+                    info,
+                    &mut csprng,
+                )
+                .unwrap();
+                assert_eq!(sender_ctx.mode(), SetupMode::AuthPsk);
+                let mut receiver_ctx = setup_receiver_auth_psk::<A, Kdf, Kem>(
+                    &pk_sender_id,
+                    psk_bundle,
+                    &sk_recip,
+                    &encapped_key,
+                    info,
+                )
+                .unwrap();
+                let mut msg = *b"also authenticated";
+                let tag = seal_authenticated(&mut sender_ctx, &mut msg);
+                open_authenticated(&mut receiver_ctx, &mut msg, &tag);
+                assert_eq!(&msg, b"also authenticated");
+
+                // A typed base context is the same as an untyped one on the wire
+                let (encapped_key, mut sender_ctx) =
+                    setup_sender_base::<A, Kdf, Kem, _>(&pk_recip, info, &mut csprng).unwrap();
+                assert_eq!(sender_ctx.mode(), SetupMode::Base);
+                let mut msg = *b"unauthenticated";
+                let tag = sender_ctx.seal(&mut msg, b"").unwrap();
+                let mut typed_receiver_ctx =
+                    setup_receiver_base::<A, Kdf, Kem>(&sk_recip, &encapped_key, info).unwrap();
+                let mut receiver_ctx =
+                    setup_receiver::<A, Kdf, Kem>(&OpModeR::Base, &sk_recip, &encapped_key, info)
+                        .unwrap();
+                let mut typed_msg = msg;
+                typed_receiver_ctx.open(&mut typed_msg, b"", &tag).unwrap();
+                receiver_ctx.open(&mut msg, b"", &tag).unwrap();
+                assert_eq!(&typed_msg, b"unauthenticated");
+                assert_eq!(&msg, b"unauthenticated");
+            }
+        };
+    }
+
+    #[cfg(feature = "x25519-dalek")]
+    test_typed_ctx!(test_typed_ctx_x25519, crate::kem::X25519HkdfSha256);
+
+    #[cfg(feature = "p256")]
+    test_typed_ctx!(test_typed_ctx_p256, crate::kem::DhP256HkdfSha256);
+}
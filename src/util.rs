@@ -49,6 +49,13 @@ pub(crate) fn kem_suite_id<Kem: KemTrait>() -> KemSuiteId {
     suite_id
 }
 
+/// Fails to compile if the given const boolean expression is false
+macro_rules! const_assert {
+    ($cond:expr) => {
+        const _: () = assert!($cond);
+    };
+}
+
 /// Returns a const expression that evaluates to the number of arguments it received
 macro_rules! count {
     () => (0usize);
@@ -85,10 +92,32 @@ pub(crate) fn write_to_buf<'a>(buf: &'a mut [u8], to_write: &[u8]) -> &'a mut [u
     &mut buf[to_write.len()..]
 }
 
+/// Puts `new` in `slot`, then drops the old value and overwrites the memory it was in with zeros.
+/// This wipes the key of a cipher that doesn't zeroize itself on drop, like the AES-GCM ciphers,
+/// without needing the cipher's cooperation.
+pub(crate) fn replace_and_zeroize<T>(slot: &mut T, new: T) {
+    let mut old = core::mem::ManuallyDrop::new(core::mem::replace(slot, new));
+    let old_ptr = (&mut *old as *mut T).cast::<u8>();
+    // SAFETY: `old` is a ManuallyDrop, so it is dropped exactly once, here, even if its destructor
+    // panics. Its memory is on our stack until we return, and nothing reads it after the drop.
+    // Writing zeros over it as bytes is fine, since it no longer has to be a valid T.
+    unsafe {
+        core::mem::ManuallyDrop::drop(&mut old);
+        for i in 0..core::mem::size_of::<T>() {
+            core::ptr::write_volatile(old_ptr.add(i), 0);
+        }
+    }
+    // Keep the zeroing from being reordered or elided
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+}
+
 /// Takes two lengths and returns an `Err(HpkeError::IncorrectInputLength)` iff they don't match
 pub(crate) fn enforce_equal_len(expected_len: usize, given_len: usize) -> Result<(), HpkeError> {
     if given_len != expected_len {
-        Err(HpkeError::IncorrectInputLength(expected_len, given_len))
+        Err(HpkeError::IncorrectInputLength {
+            expected: expected_len,
+            got: given_len,
+        })
     } else {
         Ok(())
     }
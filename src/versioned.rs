@@ -0,0 +1,138 @@
+//! Versioned framing on top of an encryption context. A versioned frame is
+//! `version || ciphertext || tag`, where `version` is a single byte that is authenticated as part
+//! of the AAD. This lets peers speaking different protocol versions detect the mismatch before
+//! attempting decryption. This is gated under the `alloc` feature.
+
+use crate::{
+    aead::{Aead, AeadCtxR, AeadCtxS, AeadTag},
+    kdf::Kdf as KdfTrait,
+    kem::Kem as KemTrait,
+    kex::{Deserializable, Serializable},
+    HpkeError,
+};
+
+use alloc::vec::Vec;
+
+/// Computes the AAD of a versioned frame, i.e., `version || extra_aad`
+fn versioned_aad(version: u8, extra_aad: &[u8]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(1 + extra_aad.len());
+    aad.push(version);
+    aad.extend_from_slice(extra_aad);
+    aad
+}
+
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AeadCtxS<A, Kdf, Kem> {
+    /// Seals `plaintext` into a versioned frame `version || ciphertext || tag`. The version byte
+    /// is authenticated along with `extra_aad`.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(frame)` on success. Errors are the same as in `AeadCtxS::seal`.
+    pub fn seal_versioned(
+        &mut self,
+        version: u8,
+        plaintext: &[u8],
+        extra_aad: &[u8],
+    ) -> Result<Vec<u8>, HpkeError> {
+        let aad = versioned_aad(version, extra_aad);
+
+        // Write the version and plaintext, encrypt the plaintext in place, and append the tag
+        let mut frame = Vec::with_capacity(1 + plaintext.len() + AeadTag::<A>::size());
+        frame.push(version);
+        frame.extend_from_slice(plaintext);
+        let tag = self.seal(&mut frame[1..], &aad)?;
+        frame.extend_from_slice(&tag.to_bytes());
+
+        Ok(frame)
+    }
+}
+
+impl<A: Aead, Kdf: KdfTrait, Kem: KemTrait> AeadCtxR<A, Kdf, Kem> {
+    /// Opens a versioned frame made by `AeadCtxS::seal_versioned`. The version is checked before
+    /// any decryption happens, so a mismatch does not advance the sequence counter.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(plaintext)` on success. If `frame` is too short to contain a version and a
+    /// tag, returns `Err(HpkeError::ValidationError)`. If the frame's version isn't
+    /// `expected_version`, returns `Err(HpkeError::VersionMismatch(expected_version, version))`.
+    /// Otherwise, errors are the same as in `AeadCtxR::open`.
+    pub fn open_versioned(
+        &mut self,
+        expected_version: u8,
+        frame: &[u8],
+        extra_aad: &[u8],
+    ) -> Result<Vec<u8>, HpkeError> {
+        let tag_size = AeadTag::<A>::size();
+        if frame.len() < 1 + tag_size {
+            return Err(HpkeError::ValidationError);
+        }
+
+        // Check the version before doing anything else
+        let version = frame[0];
+        if version != expected_version {
+            return Err(HpkeError::VersionMismatch(expected_version, version));
+        }
+
+        // Split the rest into ciphertext and tag, and decrypt the ciphertext in place
+        let (ciphertext, tag_bytes) = frame[1..].split_at(frame.len() - 1 - tag_size);
+        let tag = AeadTag::<A>::from_bytes(tag_bytes)?;
+        let aad = versioned_aad(version, extra_aad);
+
+        let mut plaintext = ciphertext.to_vec();
+        self.open(&mut plaintext, &aad, &tag)?;
+
+        Ok(plaintext)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        aead::ChaCha20Poly1305, kdf::HkdfSha256, test_util::gen_ctx_simple_pair, HpkeError,
+    };
+
+    /// Tests that versioned frames round-trip, and that opening with the wrong version gives a
+    /// VersionMismatch rather than an OpenError
+    macro_rules! test_versioned_frame {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+
+                let msg = b"version one forever";
+                let extra_aad = b"some header";
+
+                let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<A, Kdf, Kem>();
+                let frame = sender_ctx.seal_versioned(1, msg, extra_aad).unwrap();
+                assert_eq!(frame[0], 1);
+
+                // Opening as the wrong version fails before any decryption happens
+                assert_eq!(
+                    receiver_ctx.open_versioned(2, &frame, extra_aad),
+                    Err(HpkeError::VersionMismatch(2, 1))
+                );
+
+                // Since the mismatch didn't advance the receiver, the right version still works
+                let plaintext = receiver_ctx.open_versioned(1, &frame, extra_aad).unwrap();
+                assert_eq!(&plaintext[..], &msg[..]);
+
+                // Tampering with the version byte is caught by the AAD
+                let mut tampered = sender_ctx.seal_versioned(1, msg, extra_aad).unwrap();
+                tampered[0] = 2;
+                assert_eq!(
+                    receiver_ctx.open_versioned(2, &tampered, extra_aad),
+                    Err(HpkeError::OpenError)
+                );
+            }
+        };
+    }
+
+    #[cfg(feature = "x25519-dalek")]
+    test_versioned_frame!(test_versioned_frame_x25519, crate::kem::X25519HkdfSha256);
+
+    #[cfg(feature = "p256")]
+    test_versioned_frame!(test_versioned_frame_p256, crate::kem::DhP256HkdfSha256);
+}